@@ -1,167 +1,633 @@
-use bfv::{BfvParameters, EvaluationKey, EvaluationKeyProto, Evaluator, SecretKey, SecretKeyProto};
-use crypto_bigint::U256;
-use prost::Message;
+use bfv::{Evaluator, SecretKey};
+use clap::{Parser, Subcommand};
+use crypto_bigint::{Encoding, U256};
+#[cfg(feature = "keyring")]
+use psi::KeyringKeyStore;
 use psi::{
-    construct_query, db, deserialize_query_response, gen_bfv_params, generate_evaluation_key,
-    process_query_response, serialize_query, ItemLabel, PsiParams, SerializedQueryResponse,
+    dedup_response_labels, gen_bfv_params, import_item_labels, seeded_rng, tls_connector, ClientId,
+    FileKeyStore, HttpPsiClient, ImportFormat, ImportOptions, ItemLabel, PotentialResponseLabels,
+    PsiClient, PsiError, PsiParams, QuerySubmission, ResponseLabel, SecretKeyStore, ValueEncoding,
 };
-use rand::thread_rng;
-use std::io::{Read, Write};
+use rand_chacha::ChaCha20Rng;
+use std::io::{BufReader, BufWriter, Write};
 use std::path::{Path, PathBuf};
-use std::{error::Error, io::BufReader};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpStream};
-use traits::TryFromWithParameters;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::{error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use zeroize::Zeroizing;
 
-fn generate_random_client_with_evaluation_key_and_store(
+const CLIENT_SECRET_KEY_FILE: &str = "client_secret_key.bin";
+const CLIENT_ID_FILE: &str = "client_id.bin";
+
+/// Reads passphrase for the sealed secret key from `CLIENT_KEY_PASSPHRASE` env variable. Prompts on stdin if it isn't set.
+fn read_passphrase() -> Result<Zeroizing<String>, PsiError> {
+    if let Ok(passphrase) = std::env::var("CLIENT_KEY_PASSPHRASE") {
+        return Ok(Zeroizing::new(passphrase));
+    }
+
+    // prompt on stderr, since `query` may write results to stdout
+    eprint!("Enter passphrase for client secret key: ");
+    std::io::stderr().flush()?;
+    let mut passphrase = Zeroizing::new(String::new());
+    std::io::stdin().read_line(&mut passphrase)?;
+    Ok(Zeroizing::new(passphrase.trim_end().to_string()))
+}
+
+/// Returns secret key store selected with `CLIENT_KEY_STORAGE` env variable. Set it to `keyring` to store secret key
+/// in platform keyring (requires `keyring` feature). Defaults to passphrase sealed file under `data_dir`.
+fn client_key_store(data_dir: &Path) -> Result<Box<dyn SecretKeyStore>, PsiError> {
+    match std::env::var("CLIENT_KEY_STORAGE").as_deref() {
+        #[cfg(feature = "keyring")]
+        Ok("keyring") => Ok(Box::new(KeyringKeyStore::new("ulpsi", "client")?)),
+        Ok("file") | Err(_) => {
+            let passphrase = read_passphrase()?;
+            Ok(Box::new(FileKeyStore::new(
+                data_dir.join(CLIENT_SECRET_KEY_FILE),
+                passphrase.as_bytes(),
+            )))
+        }
+        Ok(other) => Err(PsiError::ParamsMismatch(format!(
+            "Unsupported client key storage {other}"
+        ))),
+    }
+}
+
+/// Returns client id stored under `data_dir`. Generates and stores a new id if none exists or if `regenerate` is set,
+/// which must be the case whenever a new secret key is generated since server may still have evaluation key of the old
+/// secret key cached under the old id.
+fn load_or_generate_client_id(
+    data_dir: &Path,
+    regenerate: bool,
+    rng: &mut ChaCha20Rng,
+) -> Result<ClientId, PsiError> {
+    let client_id_path = data_dir.join(CLIENT_ID_FILE);
+    if !regenerate {
+        if let Ok(bytes) = std::fs::read(&client_id_path) {
+            if let Ok(id) = bytes.try_into() {
+                return Ok(ClientId(id));
+            }
+            warn!("Malformed client id file. Generating new client id");
+        }
+    }
+
+    let client_id = ClientId::random(rng);
+    std::fs::create_dir_all(data_dir)?;
+    std::fs::write(&client_id_path, client_id.0)?;
+    Ok(client_id)
+}
+
+/// Unlocks existing client secret key from the key store. If the store is empty, generates and stores a new secret key
+/// with `rng`. Returns secret key along with client id under which server caches the corresponding evaluation key.
+fn load_or_generate_client_secret_key(
+    data_dir: &Path,
+    evaluator: &Evaluator,
+    rng: &mut ChaCha20Rng,
+) -> Result<(SecretKey, ClientId), PsiError> {
+    let key_store = client_key_store(data_dir)?;
+
+    info!("Unlocking client secret key");
+    let (sk, is_new) = match key_store.load(evaluator.params())? {
+        Some(sk) => (sk, false),
+        None => {
+            info!("Generating random client secret key");
+            let sk = SecretKey::random_with_params(evaluator.params(), rng);
+            key_store.store(&sk, evaluator.params())?;
+            (sk, true)
+        }
+    };
+
+    Ok((sk, load_or_generate_client_id(data_dir, is_new, rng)?))
+}
+
+/// Generates and stores a new client secret key and client id. Keeps existing secret key unless `force` is set.
+fn keygen(
+    data_dir: &Path,
     evaluator: &Evaluator,
-) -> (SecretKey, EvaluationKey) {
-    let mut rng = thread_rng();
-    let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
-    let ek = generate_evaluation_key(&evaluator, &sk);
-
-    // serliaze keys
-    let sk_serliazed = SecretKeyProto::try_from_with_parameters(&sk, evaluator.params());
-    let mut sk_bytes = sk_serliazed.encode_to_vec();
-
-    let ek_serliazed = EvaluationKeyProto::try_from_with_parameters(&ek, evaluator.params());
-    let mut ek_bytes = ek_serliazed.encode_to_vec();
-
-    // store sk and ek for server
-    let client_dir = "./../data/client";
-    let mut client_sk_path = PathBuf::from(client_dir);
-    client_sk_path.push("client_secret_key.bin");
-    let mut client_ek_path = PathBuf::from(client_dir);
-    client_ek_path.push("client_evaluation_key.bin");
-    std::fs::create_dir_all(client_dir).expect("Create data directory failed");
-    let mut sk_file =
-        std::fs::File::create(client_sk_path).expect("Failed to create client_secret_key.bin");
-    sk_file
-        .write_all(&mut sk_bytes)
-        .expect("Failed to write client_secret_key.bin");
-
-    let mut ek_file =
-        std::fs::File::create(client_ek_path).expect("Failed to create client_evaluation_key.bin");
-    ek_file
-        .write_all(&mut ek_bytes)
-        .expect("Failed to write client_evaluation_key.bin");
-
-    (sk, ek)
-}
-
-pub fn read_client_secret_key(bfv_params: &BfvParameters) -> SecretKey {
-    let mut file = std::fs::File::open("./../data/client_secret_key.bin")
-        .expect("Failed to open client_secret_key.bin");
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)
-        .expect("Unable to read client_secret_key.bin");
-    let proto = SecretKeyProto::decode(&*buffer).expect("Malformed client_secret_key.bin");
-    let secret_key = SecretKey::try_from_with_parameters(&proto, &bfv_params);
-    secret_key
-}
-
-pub async fn simulate_query(client_set_path: &Path) {
-    let psi_params = PsiParams::default();
-    let bfv_params = gen_bfv_params(&psi_params);
-    let evaluator = Evaluator::new(bfv_params);
-
-    println!("Reading Client Set...");
-    let file = std::fs::File::open(client_set_path).expect(&format!(
-        "Failed to open client set at {}",
-        client_set_path.display()
-    ));
-    let reader = BufReader::new(file);
-    let item_labels: Vec<ItemLabel> =
-        bincode::deserialize_from(reader).expect("Invalid client set file");
-
-    println!("Generating random client secret key and evaluation key...");
-    let (client_secret_key, _) = generate_random_client_with_evaluation_key_and_store(&evaluator);
-
-    println!("Constructing query...");
-    let mut rng = thread_rng();
-    let query_set = item_labels
+    force: bool,
+    rng: &mut ChaCha20Rng,
+) -> Result<(), PsiError> {
+    let key_store = client_key_store(data_dir)?;
+    if !force && key_store.load(evaluator.params())?.is_some() {
+        info!("Client secret key already exists. Pass --force to replace it");
+        return Ok(());
+    }
+
+    info!("Generating random client secret key");
+    let sk = SecretKey::random_with_params(evaluator.params(), rng);
+    key_store.store(&sk, evaluator.params())?;
+    load_or_generate_client_id(data_dir, true, rng)?;
+    info!("Stored client secret key and client id");
+    Ok(())
+}
+
+/// Reads client set stored at `client_set_path`
+fn read_client_set(client_set_path: &Path) -> Result<Vec<ItemLabel>, PsiError> {
+    info!("Reading client set");
+    let file = open_file(client_set_path)?;
+    Ok(bincode::deserialize_from(BufReader::new(file))?)
+}
+
+/// Opens file at `path` with its path in the error
+fn open_file(path: &Path) -> Result<std::fs::File, PsiError> {
+    std::fs::File::open(path)
+        .map_err(|e| PsiError::Io(format!("Failed to open {}: {e}", path.display())))
+}
+
+/// Reads items to query from `path`. Client sets are read whole, and only items are read from CSV and JSONL datasets.
+fn read_items(
+    path: &Path,
+    format: ItemsFormat,
+    item_col: &str,
+    encoding: ValueEncoding,
+) -> Result<Vec<U256>, PsiError> {
+    let item_labels = match format.import_format() {
+        None => read_client_set(path)?,
+        Some(import_format) => {
+            let options = ImportOptions::new(import_format, item_col).with_encoding(encoding);
+            import_item_labels(open_file(path)?, &options)?
+        }
+    };
+    Ok(item_labels.iter().map(|il| *il.item()).collect())
+}
+
+/// Checks that `response` contains label of every item in `item_labels`
+fn check_response(item_labels: &[ItemLabel], response: &[PotentialResponseLabels]) {
+    // Items that didn't fit in a query are part of a later query, thus every item is in the response.
+    item_labels.iter().for_each(|il| {
+        // find the item in response and check that label exists as one of the potential response labels
+        let res = response
+            .iter()
+            .find(|res| res.item() == il.item())
+            .expect("Item is missing in response");
+        assert!(res.labels().contains(&il.label()));
+    });
+    info!("Query success");
+}
+
+/// Writes items at the intersection along with their labels to `out`, or to stdout if `out` isn't set. Items are
+/// written as 32 little endian bytes (see `psi::item_from_bytes`), and items and labels are encoded with `encoding`.
+fn write_results(
+    results: &[ResponseLabel],
+    out: Option<&Path>,
+    format: OutputFormat,
+    encoding: ValueEncoding,
+) -> Result<(), PsiError> {
+    let records = results
         .iter()
-        .map(|il| il.item().clone())
-        .collect::<Vec<U256>>();
-    let query_state = construct_query(
-        &query_set,
-        &psi_params,
-        &evaluator,
-        &client_secret_key,
-        &mut rng,
+        .map(|result| {
+            serde_json::json!({
+                "item": encoding.encode(&result.item().to_le_bytes()),
+                "labels": result
+                    .labels()
+                    .iter()
+                    .map(|label| encoding.encode(label.as_bytes()))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut writer: Box<dyn Write> = match out {
+        Some(path) => Box::new(BufWriter::new(std::fs::File::create(path)?)),
+        None => Box::new(std::io::stdout().lock()),
+    };
+    let json_error = |e: serde_json::Error| PsiError::Serialization(e.to_string());
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &records).map_err(json_error)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::Jsonl => {
+            for record in records.iter() {
+                serde_json::to_writer(&mut writer, record).map_err(json_error)?;
+                writeln!(writer)?;
+            }
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Items queried over a connection, along with path they were read from
+struct QuerySet {
+    path: PathBuf,
+    items: Vec<U256>,
+}
+
+/// Queries `items` and logs round trip time and server's time breakdown. Sets larger than a single query are split
+/// across multiple queries sent with `submission`.
+async fn query_items<S: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut PsiClient<S>,
+    items: &[U256],
+    submission: QuerySubmission,
+) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+    info!(items = items.len(), "Constructing query");
+    let query_states = client.construct_queries(items).await?;
+
+    info!(queries = query_states.len(), "Sending query");
+    let now = std::time::Instant::now();
+    let response = match &query_states[..] {
+        [query_state] => client.send_query_streamed(query_state).await?,
+        _ => client.send_queries(&query_states, submission).await?,
+    };
+    info!(
+        round_trip_ms = now.elapsed().as_millis() as u64,
+        "Received query response"
     );
+    if let Some(metadata) = client.last_metadata() {
+        info!(
+            deserialize_ms = metadata.deserialize_time.as_millis() as u64,
+            powers_ms = metadata.powers_time.as_millis() as u64,
+            evaluation_ms = metadata.evaluation_time().as_millis() as u64,
+            serialize_ms = metadata.serialize_time.as_millis() as u64,
+            db_version = metadata.db_version,
+            "Server time breakdown"
+        );
+    }
+    Ok(response)
+}
 
-    // serialize query
-    let mut serialized_query = serialize_query(query_state.query(), evaluator.params());
-
-    println!("Query Size: {} Bytes", serialized_query.len());
-
-    // send request
-    println!("Sending query...");
-    let mut stream = TcpStream::connect("127.0.0.1:6379").await.unwrap();
-
-    stream
-        .write_all(&mut serialized_query)
-        .await
-        .expect("Failed to send query request");
-    stream.flush().await.expect("A");
-
-    // read response
-    let mut response_buffer = Vec::new();
-
-    stream
-        .readable()
-        .await
-        .expect("Failed to read response from server");
-    stream
-        .read_to_end(&mut response_buffer)
-        .await
-        .expect("Failed to read response from server");
-
-    let serialized_query_response: SerializedQueryResponse =
-        bincode::deserialize(&response_buffer).unwrap();
-    let query_response =
-        deserialize_query_response(&serialized_query_response, &psi_params, &evaluator);
-
-    println!("Query Response Size: {} Bytes", response_buffer.len());
-
-    // validate query response
-    let response = process_query_response(
-        &psi_params,
-        query_state.hash_tables(),
-        &evaluator,
-        &client_secret_key,
-        &query_response,
+/// Same as `query_items` but over server's HTTP transport
+async fn query_items_http(
+    client: &mut HttpPsiClient,
+    items: &[U256],
+) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+    info!(items = items.len(), "Sending query");
+    let now = std::time::Instant::now();
+    let response = client.query(items).await?;
+    info!(
+        round_trip_ms = now.elapsed().as_millis() as u64,
+        "Received query response"
     );
+    Ok(response)
+}
 
-    // check all item labels are present
-    item_labels.iter().for_each(|il| {
-        // if item_label is in hash table stack, then ignore it.
-        let mut in_stack_flag = false;
-        query_state.hash_table_stack().iter().for_each(|ht_entry| {
-            if il.item() == ht_entry.entry_value() {
-                in_stack_flag = true;
-            }
-        });
-
-        if !in_stack_flag {
-            // find the item in response and check that label exists as one of the potential response labels
-            response.iter().for_each(|res| {
-                if res.item() == il.item() {
-                    assert!(res.labels().contains(&il.label()));
-                }
+/// Transport client reaches server over
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+enum Transport {
+    /// Framed protocol over TCP, wrapped in TLS if `tls-ca` is set
+    #[default]
+    Tcp,
+    /// Server's HTTP transport at `url`
+    Http,
+    /// Framed protocol over QUIC. Requires `tls-ca`.
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+/// Format of items file of `query`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum ItemsFormat {
+    /// Bincode client set, as stored by server's `gen-client-set`
+    ClientSet,
+    /// Comma separated values with a header row naming the columns
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl ItemsFormat {
+    /// Returns `None` for client sets, which aren't imported
+    fn import_format(&self) -> Option<ImportFormat> {
+        match self {
+            ItemsFormat::ClientSet => None,
+            ItemsFormat::Csv => Some(ImportFormat::Csv),
+            ItemsFormat::Jsonl => Some(ImportFormat::Jsonl),
+        }
+    }
+}
+
+/// Format of results written by `query`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// Array of `{"item": ..., "labels": [...]}` objects
+    Json,
+    /// One `{"item": ..., "labels": [...]}` object per line
+    Jsonl,
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// Directory client's sealed secret key and client id are stored under
+    #[arg(long, global = true, default_value = "./../data/client")]
+    data_dir: PathBuf,
+    /// `PsiParams` config file (.toml, .json or bincode .bin). Defaults to `PsiParams::default`. Must match server's, or
+    /// queried tenant's, params.
+    #[arg(long, global = true, visible_alias = "params-file")]
+    config: Option<PathBuf>,
+    /// Seeds generation of new secret key and randomness of queries, thus runs are reproducible
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+    /// Only log warnings and errors. Overrides `RUST_LOG`.
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    #[command(subcommand)]
+    command: Commands,
+}
+
+/// Flags of connecting to server. API token is read from `CLIENT_API_TOKEN` env variable.
+#[derive(clap::Args, Debug)]
+struct ConnectArgs {
+    /// Address of server, or of coordinator of sharded servers
+    #[arg(long, default_value = "127.0.0.1:6379")]
+    server: String,
+    #[arg(long, value_enum, default_value_t = Transport::Tcp)]
+    transport: Transport,
+    /// URL of server's HTTP transport, or of reverse proxy in front of it. Defaults to `http://{server}`.
+    /// `tls-ca` is trusted for https URLs.
+    #[arg(long)]
+    url: Option<String>,
+    /// PEM encoded certificate of CA that issued server's certificate. Connection is wrapped in TLS if set.
+    #[arg(long)]
+    tls_ca: Option<PathBuf>,
+    /// Domain server's certificate must be valid for
+    #[arg(long, default_value = "localhost")]
+    tls_domain: String,
+    /// Tenant whose dataset is queried. Server's default tenant is queried if not set.
+    #[arg(long)]
+    tenant: Option<String>,
+    /// Sends all queries of a set too large for a single query without waiting for responses
+    #[arg(long, conflicts_with = "batched")]
+    pipelined: bool,
+    /// Sends queries of a set too large for a single query in batches of `DEFAULT_MAX_BATCH_QUERIES` queries per
+    /// request
+    #[arg(long)]
+    batched: bool,
+}
+
+impl ConnectArgs {
+    fn submission(&self) -> QuerySubmission {
+        match (self.pipelined, self.batched) {
+            (true, _) => QuerySubmission::Pipelined,
+            (_, true) => QuerySubmission::Batched,
+            _ => QuerySubmission::Sequential,
+        }
+    }
+
+    fn read_tls_ca(&self) -> Result<Option<Vec<u8>>, PsiError> {
+        self.tls_ca
+            .as_ref()
+            .map(|ca_path| {
+                std::fs::read(ca_path)
+                    .map_err(|e| PsiError::Io(format!("Failed to read {}: {e}", ca_path.display())))
             })
+            .transpose()
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Generates client secret key and client id. Secret key is sealed with passphrase under `data-dir`, or stored in
+    /// platform keyring if `CLIENT_KEY_STORAGE=keyring`. Existing secret key is kept unless `force` is set.
+    Keygen {
+        /// Replaces existing secret key and client id
+        #[arg(long)]
+        force: bool,
+    },
+    /// Queries items in `items` and writes items at the intersection along with their labels to `out`. Secret key is
+    /// generated if none is stored yet.
+    Query {
+        #[arg(long)]
+        items: PathBuf,
+        #[arg(long, value_enum, default_value_t = ItemsFormat::ClientSet)]
+        items_format: ItemsFormat,
+        /// Name of column (CSV) or field (JSONL) of items
+        #[arg(long, default_value = "item")]
+        item_col: String,
+        /// Encoding of items in CSV and JSONL, and of items and labels in results: hex or base64
+        #[arg(long, default_value = "hex")]
+        encoding: ValueEncoding,
+        /// File results are written to. Defaults to stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+        #[arg(long, value_enum, default_value_t = OutputFormat::Json)]
+        format: OutputFormat,
+        #[command(flatten)]
+        connect: ConnectArgs,
+    },
+    /// Queries each client set, as stored by server's `gen-client-set`, in order over a single connection and checks
+    /// that server returned labels of all items
+    Verify {
+        #[arg(required = true)]
+        client_sets: Vec<PathBuf>,
+        #[command(flatten)]
+        connect: ConnectArgs,
+    },
+}
+
+/// Connects to server with `connect` and queries each of `query_sets` in order. Returns response of each set.
+async fn connect_and_query(
+    cli: &Cli,
+    psi_params: &PsiParams,
+    connect: &ConnectArgs,
+    query_sets: &[QuerySet],
+) -> Result<Vec<Vec<PotentialResponseLabels>>, PsiError> {
+    let evaluator = Evaluator::new(gen_bfv_params(psi_params));
+    let mut rng = seeded_rng(cli.seed);
+    let (client_secret_key, client_id) =
+        load_or_generate_client_secret_key(&cli.data_dir, &evaluator, &mut rng)?;
+
+    let addr = connect.server.as_str();
+    match connect.transport {
+        Transport::Tcp => {}
+        Transport::Http => {
+            let url = connect
+                .url
+                .clone()
+                .unwrap_or_else(|| format!("http://{addr}"));
+            let client = HttpPsiClient::new(&url, psi_params, client_secret_key, client_id);
+            return query_sets_http(client, connect, cli.seed, query_sets).await;
         }
-    });
+        #[cfg(feature = "quic")]
+        Transport::Quic => {
+            let ca_pem = connect.read_tls_ca()?.ok_or(PsiError::Tls(
+                "QUIC transport requires --tls-ca".to_string(),
+            ))?;
+            let addr = tokio::net::lookup_host(addr)
+                .await?
+                .next()
+                .ok_or_else(|| PsiError::Io(format!("Failed to resolve {addr}")))?;
+            let client = PsiClient::connect_quic(
+                addr,
+                &connect.tls_domain,
+                &psi::quic_client_config(&ca_pem)?,
+                psi_params,
+                client_secret_key,
+                client_id,
+            )
+            .await?;
+            return query_sets(&mut seed_client(client, cli.seed), connect, query_sets).await;
+        }
+    }
+    match connect.read_tls_ca()? {
+        Some(ca_pem) => {
+            let connector = tls_connector(&ca_pem)?;
+            let client = PsiClient::connect_tls(
+                addr,
+                &connect.tls_domain,
+                &connector,
+                psi_params,
+                client_secret_key,
+                client_id,
+            )
+            .await?;
+            query_sets(&mut seed_client(client, cli.seed), connect, query_sets).await
+        }
+        None => {
+            let client = PsiClient::connect(addr, psi_params, client_secret_key, client_id).await?;
+            query_sets(&mut seed_client(client, cli.seed), connect, query_sets).await
+        }
+    }
+}
+
+/// Seeds randomness of `client` with `seed`, if set
+fn seed_client<S: AsyncRead + AsyncWrite + Unpin>(
+    client: PsiClient<S>,
+    seed: Option<u64>,
+) -> PsiClient<S> {
+    match seed {
+        Some(seed) => client.with_seed(seed),
+        None => client,
+    }
+}
+
+/// Authenticates with API token in `CLIENT_API_TOKEN` env variable, if set, selects tenant, if set, and queries each
+/// of `query_sets` in order
+async fn query_sets<S: AsyncRead + AsyncWrite + Unpin>(
+    client: &mut PsiClient<S>,
+    connect: &ConnectArgs,
+    query_sets: &[QuerySet],
+) -> Result<Vec<Vec<PotentialResponseLabels>>, PsiError> {
+    if let Ok(token) = std::env::var("CLIENT_API_TOKEN") {
+        client.authenticate(&token).await?;
+    }
+    if let Some(tenant) = &connect.tenant {
+        client.select_tenant(tenant).await?;
+    }
+    client.enable_metadata().await?;
+    let mut responses = vec![];
+    for query_set in query_sets.iter() {
+        let response = query_items(client, &query_set.items, connect.submission())
+            .instrument(info_span!("client_set", path = %query_set.path.display()))
+            .await?;
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+/// Same as `query_sets` but over server's HTTP transport. API token and tenant are sent with every request.
+async fn query_sets_http(
+    mut client: HttpPsiClient,
+    connect: &ConnectArgs,
+    seed: Option<u64>,
+    query_sets: &[QuerySet],
+) -> Result<Vec<Vec<PotentialResponseLabels>>, PsiError> {
+    if let Some(ca_pem) = connect.read_tls_ca()? {
+        client = client.with_tls_ca(&ca_pem)?;
+    }
+    if let Ok(token) = std::env::var("CLIENT_API_TOKEN") {
+        client = client.with_token(&token);
+    }
+    if let Some(tenant) = &connect.tenant {
+        client = client.with_tenant(tenant);
+    }
+    if let Some(seed) = seed {
+        client = client.with_seed(seed);
+    }
+    if connect.submission() != QuerySubmission::Sequential {
+        warn!("Queries are sent sequentially over HTTP transport");
+    }
+    if client.fetch_params().await? != *client.psi_params() {
+        return Err(PsiError::ParamsMismatch(
+            "Server's params don't match --config".to_string(),
+        ));
+    }
+    let mut responses = vec![];
+    for query_set in query_sets.iter() {
+        let response = query_items_http(&mut client, &query_set.items)
+            .instrument(info_span!("client_set", path = %query_set.path.display()))
+            .await?;
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+async fn run(cli: &Cli) -> Result<(), PsiError> {
+    let psi_params = match &cli.config {
+        Some(path) => PsiParams::from_file(path)?,
+        None => PsiParams::default(),
+    };
+
+    match &cli.command {
+        Commands::Keygen { force } => {
+            let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+            keygen(&cli.data_dir, &evaluator, *force, &mut seeded_rng(cli.seed))
+        }
+        Commands::Query {
+            items,
+            items_format,
+            item_col,
+            encoding,
+            out,
+            format,
+            connect,
+        } => {
+            let query_set = QuerySet {
+                path: items.clone(),
+                items: read_items(items, *items_format, item_col, *encoding)?,
+            };
+            let responses =
+                connect_and_query(cli, &psi_params, connect, std::slice::from_ref(&query_set))
+                    .await?;
+            let results = dedup_response_labels(&psi_params, responses.concat());
+            info!(intersection = results.len(), "Writing results");
+            write_results(&results, out.as_deref(), *format, *encoding)
+        }
+        Commands::Verify {
+            client_sets,
+            connect,
+        } => {
+            let client_sets = client_sets
+                .iter()
+                .map(|path| Ok((path, read_client_set(path)?)))
+                .collect::<Result<Vec<_>, PsiError>>()?;
+            let query_sets = client_sets
+                .iter()
+                .map(|(path, item_labels)| QuerySet {
+                    path: path.to_path_buf(),
+                    items: item_labels.iter().map(|il| *il.item()).collect(),
+                })
+                .collect::<Vec<_>>();
+            let responses = connect_and_query(cli, &psi_params, connect, &query_sets).await?;
+            for ((path, item_labels), response) in client_sets.iter().zip(responses.iter()) {
+                let _span = info_span!("client_set", path = %path.display()).entered();
+                check_response(item_labels, response);
+            }
+            Ok(())
+        }
+    }
+}
 
-    println!("Query Success!");
+/// Logs to stderr filtered by `RUST_LOG`, which defaults to `info`. `quiet` only logs warnings and errors.
+fn init_tracing(quiet: bool) {
+    let filter = if quiet {
+        EnvFilter::new("warn")
+    } else {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
 }
 
 #[tokio::main]
 async fn main() {
-    let client_set_path = std::env::args()
-        .nth(1)
-        .expect("Pass path to client intersection set");
+    let cli = Cli::parse();
+    init_tracing(cli.quiet);
 
-    simulate_query(Path::new(&client_set_path)).await;
+    if let Err(e) = run(&cli).await {
+        error!("{e}");
+        std::process::exit(1);
+    }
 }
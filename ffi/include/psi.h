@@ -0,0 +1,89 @@
+/*
+ * C interface of PSI client. See `ffi/src/lib.rs` for details.
+ *
+ * The library only constructs queries and processes responses. Application sends them to server itself, as payloads
+ * of `EvaluationKey` and `Query` frames, and passes payload of `QueryResponse` frame back. Compression and OPRF aren't
+ * supported.
+ *
+ * Every function that returns `int32_t` returns `PSI_OK` or an error code, in which case `psi_last_error` describes
+ * the error. Buffers returned by the library are owned by the caller and must be freed with `psi_buffer_free`.
+ */
+
+#ifndef PSI_H
+#define PSI_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PSI_OK 0
+/* Argument is null or malformed, for ex. params that aren't valid TOML */
+#define PSI_ERROR_INVALID_ARGUMENT 1
+/* Operation failed, for ex. response does not match the query */
+#define PSI_ERROR_FAILED 2
+/* Library panicked. Objects passed to the call must not be used afterwards. */
+#define PSI_ERROR_PANIC 3
+
+/* Size of a single item passed to `psi_construct_query` */
+#define PSI_ITEM_BYTES 32
+/* Size of client id written by `psi_client_id` */
+#define PSI_CLIENT_ID_BYTES 16
+
+typedef struct PsiClient PsiClient;
+typedef struct PsiQuery PsiQuery;
+
+/* Bytes owned by the caller. Must be freed with `psi_buffer_free`. */
+typedef struct PsiBuffer {
+    uint8_t *data;
+    size_t len;
+} PsiBuffer;
+
+/* Description of the last error on calling thread, or NULL. Valid until next call that fails on the thread. */
+const char *psi_last_error(void);
+
+/* Creates client with a fresh secret key. `params` is a TOML config, same as client's `--config`, or NULL for default
+ * params. */
+int32_t psi_client_new(const char *params, PsiClient **out);
+
+/* Same as `psi_client_new` but with secret key returned by `psi_client_secret_key` */
+int32_t psi_client_from_secret_key(const char *params, const uint8_t *sk, size_t sk_len, PsiClient **out);
+
+void psi_client_free(PsiClient *client);
+
+/* Copies `PSI_CLIENT_ID_BYTES` bytes long id of `client` to `out` */
+int32_t psi_client_id(const PsiClient *client, uint8_t *out);
+
+/* Serialized secret key of `client`. Wipe the buffer before freeing it. */
+int32_t psi_client_secret_key(const PsiClient *client, PsiBuffer *out);
+
+/* Generates evaluation key and returns payload of `EvaluationKey` frame, ie client id || evaluation key */
+int32_t psi_client_evaluation_key(PsiClient *client, PsiBuffer *out);
+
+/* Constructs queries for `count` items of `PSI_ITEM_BYTES` bytes (little endian) each, stored one after another at
+ * `items`. Items that don't fit in a single query are split across multiple queries. */
+int32_t psi_construct_query(PsiClient *client, const uint8_t *items, size_t count, PsiQuery **out);
+
+/* No. of queries in `query` */
+size_t psi_query_count(const PsiQuery *query);
+
+/* Payload of `Query` frame of query at `index`, ie client id || serialized query */
+int32_t psi_query_payload(const PsiClient *client, const PsiQuery *query, size_t index, PsiBuffer *out);
+
+/* Processes payload of `QueryResponse` frame received in response to query at `index`. Writes potential labels of
+ * queried items to `out` as: no. of items (u32 LE) followed by, for each item, item (`PSI_ITEM_BYTES` bytes LE) ||
+ * no. of labels (u32 LE) || length of each label (u32 LE) followed by the label. */
+int32_t psi_process_response(const PsiClient *client, const PsiQuery *query, size_t index, const uint8_t *response,
+                             size_t response_len, PsiBuffer *out);
+
+void psi_query_free(PsiQuery *query);
+
+void psi_buffer_free(PsiBuffer buffer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PSI_H */
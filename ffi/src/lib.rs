@@ -0,0 +1,637 @@
+//! C interface of PSI client, so that applications that can't link Rust (mobile apps, C++ services) can query the
+//! server. Declarations are in `include/psi.h`.
+//!
+//! The library only constructs queries and processes responses. Application sends them to server itself, as payloads
+//! of `MessageType::EvaluationKey` and `MessageType::Query` frames, and passes payload of `MessageType::QueryResponse`
+//! back. Compression and OPRF aren't supported.
+//!
+//! Every function that can fail returns `PSI_OK` or an error code, in which case `psi_last_error` describes the error.
+//! Buffers returned by the library are owned by the caller and must be freed with `psi_buffer_free`.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+    ptr, slice,
+};
+
+use bfv::{EvaluationKeyProto, Evaluator, SecretKey};
+use crypto_bigint::{Encoding, U256};
+use prost::Message;
+use psi::{
+    construct_queries, deserialize_query_response, deserialize_secret_key, gen_bfv_params,
+    generate_evaluation_key, process_query_response, seeded_rng, serialize_query,
+    serialize_secret_key, ClientId, PotentialResponseLabels, PsiError, PsiParams, QueryState,
+    SerializedQueryResponse, CLIENT_ID_BYTES,
+};
+use rand_chacha::ChaCha20Rng;
+use traits::TryFromWithParameters;
+
+pub const PSI_OK: i32 = 0;
+/// Argument is null or malformed, for ex. params that aren't valid TOML
+pub const PSI_ERROR_INVALID_ARGUMENT: i32 = 1;
+/// Operation failed, for ex. response does not match the query
+pub const PSI_ERROR_FAILED: i32 = 2;
+/// Library panicked. Objects passed to the call must not be used afterwards.
+pub const PSI_ERROR_PANIC: i32 = 3;
+
+/// Size of a single item passed to `psi_construct_query`
+pub const PSI_ITEM_BYTES: usize = 32;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+#[derive(Debug)]
+enum FfiError {
+    InvalidArgument(String),
+    Psi(PsiError),
+}
+
+impl std::fmt::Display for FfiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FfiError::InvalidArgument(e) => write!(f, "Invalid argument: {e}"),
+            FfiError::Psi(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for FfiError {}
+
+impl From<PsiError> for FfiError {
+    fn from(value: PsiError) -> Self {
+        FfiError::Psi(value)
+    }
+}
+
+impl From<bincode::Error> for FfiError {
+    fn from(value: bincode::Error) -> Self {
+        FfiError::Psi(value.into())
+    }
+}
+
+impl FfiError {
+    fn code(&self) -> i32 {
+        match self {
+            FfiError::InvalidArgument(_) => PSI_ERROR_INVALID_ARGUMENT,
+            FfiError::Psi(_) => PSI_ERROR_FAILED,
+        }
+    }
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(message.replace('\0', " ")).ok());
+}
+
+/// Runs `f` and returns its status. Error, or panic, is stored as last error of the thread.
+fn ffi_call(f: impl FnOnce() -> Result<(), FfiError>) -> i32 {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => PSI_OK,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            e.code()
+        }
+        Err(_) => {
+            set_last_error("Library panicked".to_string());
+            PSI_ERROR_PANIC
+        }
+    }
+}
+
+/// Returns reference to `ptr`, or error if it is null
+unsafe fn non_null<'a, T>(ptr: *const T, name: &str) -> Result<&'a T, FfiError> {
+    ptr.as_ref()
+        .ok_or_else(|| FfiError::InvalidArgument(format!("{name} is null")))
+}
+
+/// Returns `len` bytes at `ptr`. Null `ptr` is accepted only if `len` is 0.
+unsafe fn bytes<'a>(ptr: *const u8, len: usize, name: &str) -> Result<&'a [u8], FfiError> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    non_null(ptr, name)?;
+    Ok(slice::from_raw_parts(ptr, len))
+}
+
+/// Writes `value` to `out`
+unsafe fn write_out<T>(out: *mut T, value: T) -> Result<(), FfiError> {
+    if out.is_null() {
+        return Err(FfiError::InvalidArgument(
+            "Output pointer is null".to_string(),
+        ));
+    }
+    out.write(value);
+    Ok(())
+}
+
+/// Moves `value` to heap and writes pointer to it to `out`. Caller frees it with the respective `psi_*_free`.
+unsafe fn write_boxed<T>(out: *mut *mut T, value: T) -> Result<(), FfiError> {
+    non_null(out, "Output pointer")?;
+    out.write(Box::into_raw(Box::new(value)));
+    Ok(())
+}
+
+/// Bytes owned by the caller. Must be freed with `psi_buffer_free`.
+#[repr(C)]
+pub struct PsiBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+impl PsiBuffer {
+    fn from_vec(bytes: Vec<u8>) -> PsiBuffer {
+        let len = bytes.len();
+        let data = Box::into_raw(bytes.into_boxed_slice()) as *mut u8;
+        PsiBuffer { data, len }
+    }
+}
+
+/// PSI client. Holds parameters, secret key and id of the client.
+pub struct PsiClient {
+    psi_params: PsiParams,
+    evaluator: Evaluator,
+    sk: SecretKey,
+    client_id: ClientId,
+    rng: ChaCha20Rng,
+}
+
+impl PsiClient {
+    fn new(params: *const c_char, sk: Option<&[u8]>) -> Result<PsiClient, FfiError> {
+        let psi_params = if params.is_null() {
+            PsiParams::default()
+        } else {
+            let params = unsafe { CStr::from_ptr(params) }
+                .to_str()
+                .map_err(|e| FfiError::InvalidArgument(format!("Params aren't UTF-8: {e}")))?;
+            let psi_params: PsiParams = toml::from_str(params)
+                .map_err(|e| FfiError::InvalidArgument(format!("Malformed params: {e}")))?;
+            psi_params.validate()?;
+            psi_params
+        };
+        if psi_params.oprf() {
+            return Err(FfiError::InvalidArgument(
+                "OPRF isn't supported by C interface".to_string(),
+            ));
+        }
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let mut rng = seeded_rng(None);
+        let sk = match sk {
+            Some(bytes) => deserialize_secret_key(bytes, evaluator.params()).ok_or(
+                FfiError::InvalidArgument("Malformed secret key".to_string()),
+            )?,
+            None => SecretKey::random_with_params(evaluator.params(), &mut rng),
+        };
+        let client_id = ClientId::random(&mut rng);
+        Ok(PsiClient {
+            psi_params,
+            evaluator,
+            sk,
+            client_id,
+            rng,
+        })
+    }
+}
+
+/// Queries constructed by `psi_construct_query`. Items that don't fit in a single query are split across multiple
+/// queries.
+pub struct PsiQuery(Vec<QueryState>);
+
+impl PsiQuery {
+    fn get(&self, index: usize) -> Result<&QueryState, FfiError> {
+        self.0.get(index).ok_or_else(|| {
+            FfiError::InvalidArgument(format!(
+                "Query index {index} out of range, there are {} queries",
+                self.0.len()
+            ))
+        })
+    }
+}
+
+/// Encodes potential labels of each queried item as: no. of items (u32 LE) followed by, for each item, item (32 bytes
+/// LE) || no. of labels (u32 LE) || length of each label (u32 LE) followed by the label.
+fn encode_response_labels(responses: &[PotentialResponseLabels]) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&(responses.len() as u32).to_le_bytes());
+    responses.iter().for_each(|response| {
+        bytes.extend_from_slice(&response.item().to_le_bytes());
+        bytes.extend_from_slice(&(response.labels().len() as u32).to_le_bytes());
+        response.labels().iter().for_each(|label| {
+            bytes.extend_from_slice(&(label.as_bytes().len() as u32).to_le_bytes());
+            bytes.extend_from_slice(label.as_bytes());
+        });
+    });
+    bytes
+}
+
+/// Description of the last error on calling thread, or null. Valid until next call that fails on the thread.
+#[no_mangle]
+pub extern "C" fn psi_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |e| e.as_ptr()))
+}
+
+/// Creates client with a fresh secret key and writes it to `out`. `params` is a TOML config, same as client's
+/// `--config`, or null for default params.
+///
+/// # Safety
+///
+/// `params` must be null or a NUL terminated string. `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_client_new(params: *const c_char, out: *mut *mut PsiClient) -> i32 {
+    ffi_call(|| write_boxed(out, PsiClient::new(params, None)?))
+}
+
+/// Same as `psi_client_new` but with secret key returned by `psi_client_secret_key`, thus evaluation key uploaded
+/// by a previous client stays valid.
+///
+/// # Safety
+///
+/// `params` must be null or a NUL terminated string. `sk` must point to `sk_len` bytes. `out` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_client_from_secret_key(
+    params: *const c_char,
+    sk: *const u8,
+    sk_len: usize,
+    out: *mut *mut PsiClient,
+) -> i32 {
+    ffi_call(|| {
+        let sk = bytes(sk, sk_len, "Secret key")?;
+        write_boxed(out, PsiClient::new(params, Some(sk))?)
+    })
+}
+
+/// # Safety
+///
+/// `client` must be null or returned by `psi_client_new` and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn psi_client_free(client: *mut PsiClient) {
+    if !client.is_null() {
+        drop(Box::from_raw(client));
+    }
+}
+
+/// Copies `CLIENT_ID_BYTES` bytes long id of `client` to `out`
+///
+/// # Safety
+///
+/// `client` must be a live client. `out` must be valid for writes of `CLIENT_ID_BYTES` bytes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_client_id(client: *const PsiClient, out: *mut u8) -> i32 {
+    ffi_call(|| {
+        let client = non_null(client, "Client")?;
+        if out.is_null() {
+            return Err(FfiError::InvalidArgument(
+                "Output pointer is null".to_string(),
+            ));
+        }
+        ptr::copy_nonoverlapping(client.client_id.0.as_ptr(), out, CLIENT_ID_BYTES);
+        Ok(())
+    })
+}
+
+/// Writes serialized secret key of `client` to `out`, so that it can be restored with `psi_client_from_secret_key`.
+/// Caller should wipe the buffer before freeing it.
+///
+/// # Safety
+///
+/// `client` must be a live client. `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_client_secret_key(
+    client: *const PsiClient,
+    out: *mut PsiBuffer,
+) -> i32 {
+    ffi_call(|| {
+        let client = non_null(client, "Client")?;
+        let sk = serialize_secret_key(&client.sk, client.evaluator.params());
+        write_out(out, PsiBuffer::from_vec(sk.to_vec()))
+    })
+}
+
+/// Generates evaluation key of `client` and writes payload of `MessageType::EvaluationKey` frame, ie client id ||
+/// evaluation key, to `out`
+///
+/// # Safety
+///
+/// `client` must be a live client. `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_client_evaluation_key(
+    client: *mut PsiClient,
+    out: *mut PsiBuffer,
+) -> i32 {
+    ffi_call(|| {
+        let client = client
+            .as_mut()
+            .ok_or_else(|| FfiError::InvalidArgument("Client is null".to_string()))?;
+        let ek = generate_evaluation_key(
+            &client.psi_params,
+            &client.evaluator,
+            &client.sk,
+            &mut client.rng,
+        );
+        let ek_bytes = EvaluationKeyProto::try_from_with_parameters(&ek, client.evaluator.params())
+            .encode_to_vec();
+        write_out(out, PsiBuffer::from_vec(client.client_id.prefix(&ek_bytes)))
+    })
+}
+
+/// Constructs queries for `count` items of `PSI_ITEM_BYTES` bytes (LE) each, stored one after another at `items`.
+///
+/// # Safety
+///
+/// `client` must be a live client. `items` must point to `count * PSI_ITEM_BYTES` bytes. `out` must be valid for
+/// writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_construct_query(
+    client: *mut PsiClient,
+    items: *const u8,
+    count: usize,
+    out: *mut *mut PsiQuery,
+) -> i32 {
+    ffi_call(|| {
+        let client = client
+            .as_mut()
+            .ok_or_else(|| FfiError::InvalidArgument("Client is null".to_string()))?;
+        let len = count
+            .checked_mul(PSI_ITEM_BYTES)
+            .ok_or_else(|| FfiError::InvalidArgument(format!("Too many items: {count}")))?;
+        let items = bytes(items, len, "Items")?
+            .chunks_exact(PSI_ITEM_BYTES)
+            .map(|item| U256::from_le_bytes(item.try_into().unwrap()))
+            .collect::<Vec<_>>();
+
+        let query_states = construct_queries(
+            &items,
+            &client.psi_params,
+            &client.evaluator,
+            &client.sk,
+            &mut client.rng,
+        );
+        write_boxed(out, PsiQuery(query_states))
+    })
+}
+
+/// No. of queries in `query`, or 0 if `query` is null
+///
+/// # Safety
+///
+/// `query` must be null or a live query.
+#[no_mangle]
+pub unsafe extern "C" fn psi_query_count(query: *const PsiQuery) -> usize {
+    query.as_ref().map_or(0, |query| query.0.len())
+}
+
+/// Writes payload of `MessageType::Query` frame of query at `index`, ie client id || serialized query, to `out`
+///
+/// # Safety
+///
+/// `client` must be the live client that constructed `query`. `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_query_payload(
+    client: *const PsiClient,
+    query: *const PsiQuery,
+    index: usize,
+    out: *mut PsiBuffer,
+) -> i32 {
+    ffi_call(|| {
+        let client = non_null(client, "Client")?;
+        let query_state = non_null(query, "Query")?.get(index)?;
+        let query_bytes = serialize_query(query_state.query(), client.evaluator.params());
+        write_out(
+            out,
+            PsiBuffer::from_vec(client.client_id.prefix(&query_bytes)),
+        )
+    })
+}
+
+/// Processes payload of `MessageType::QueryResponse` frame, received in response to query at `index`, and writes
+/// potential labels of queried items to `out`. See `encode_response_labels` for the encoding.
+///
+/// # Safety
+///
+/// `client` must be the live client that constructed `query`. `response` must point to `response_len` bytes. `out`
+/// must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn psi_process_response(
+    client: *const PsiClient,
+    query: *const PsiQuery,
+    index: usize,
+    response: *const u8,
+    response_len: usize,
+    out: *mut PsiBuffer,
+) -> i32 {
+    ffi_call(|| {
+        let client = non_null(client, "Client")?;
+        let query_state = non_null(query, "Query")?.get(index)?;
+        let serialized: SerializedQueryResponse =
+            bincode::deserialize(bytes(response, response_len, "Response")?)?;
+        let query_response =
+            deserialize_query_response(&serialized, &client.psi_params, &client.evaluator)?;
+        let responses = process_query_response(
+            &client.psi_params,
+            query_state.hash_tables(),
+            &client.evaluator,
+            &client.sk,
+            &query_response,
+        );
+        write_out(out, PsiBuffer::from_vec(encode_response_labels(&responses)))
+    })
+}
+
+/// # Safety
+///
+/// `query` must be null or returned by `psi_construct_query` and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn psi_query_free(query: *mut PsiQuery) {
+    if !query.is_null() {
+        drop(Box::from_raw(query));
+    }
+}
+
+/// # Safety
+///
+/// `buffer` must be returned by the library and not freed before.
+#[no_mangle]
+pub unsafe extern "C" fn psi_buffer_free(buffer: PsiBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(
+            buffer.data,
+            buffer.len,
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bfv::EvaluationKey;
+    use psi::{deserialize_query, gen_random_item_labels, serialize_query_response, Server};
+
+    use super::*;
+
+    unsafe fn take(buffer: PsiBuffer) -> Vec<u8> {
+        let bytes = slice::from_raw_parts(buffer.data, buffer.len).to_vec();
+        psi_buffer_free(buffer);
+        bytes
+    }
+
+    fn empty_buffer() -> PsiBuffer {
+        PsiBuffer {
+            data: ptr::null_mut(),
+            len: 0,
+        }
+    }
+
+    /// Returns first `len` bytes of `bytes` and advances it past them
+    fn read<'a>(bytes: &mut &'a [u8], len: usize) -> &'a [u8] {
+        let (head, rest) = bytes.split_at(len);
+        *bytes = rest;
+        head
+    }
+
+    fn read_u32(bytes: &mut &[u8]) -> usize {
+        u32::from_le_bytes(read(bytes, 4).try_into().unwrap()) as usize
+    }
+
+    /// Decodes labels encoded with `encode_response_labels`
+    fn decode_response_labels(mut bytes: &[u8]) -> Vec<([u8; PSI_ITEM_BYTES], Vec<Vec<u8>>)> {
+        let bytes = &mut bytes;
+        (0..read_u32(bytes))
+            .map(|_| {
+                let item = read(bytes, PSI_ITEM_BYTES).try_into().unwrap();
+                let labels = (0..read_u32(bytes))
+                    .map(|_| {
+                        let len = read_u32(bytes);
+                        read(bytes, len).to_vec()
+                    })
+                    .collect();
+                (item, labels)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn query_through_ffi_works() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(100, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        unsafe {
+            let mut client = ptr::null_mut();
+            assert_eq!(psi_client_new(ptr::null(), &mut client), PSI_OK);
+
+            let mut buffer = empty_buffer();
+            assert_eq!(psi_client_evaluation_key(client, &mut buffer), PSI_OK);
+            let ek_payload = take(buffer);
+            let (client_id, ek_bytes) = ClientId::split_prefix(&ek_payload).unwrap();
+            let ek = EvaluationKey::try_from_with_parameters(
+                &EvaluationKeyProto::decode(ek_bytes).unwrap(),
+                server.evaluator().params(),
+            );
+
+            // first item is in server set
+            let items = [*item_labels[0].item(), U256::from_u64(7)]
+                .iter()
+                .flat_map(|item| item.to_le_bytes())
+                .collect::<Vec<_>>();
+            let mut query = ptr::null_mut();
+            assert_eq!(
+                psi_construct_query(client, items.as_ptr(), 2, &mut query),
+                PSI_OK
+            );
+            assert_eq!(psi_query_count(query), 1);
+
+            assert_eq!(psi_query_payload(client, query, 0, &mut buffer), PSI_OK);
+            let query_payload = take(buffer);
+            let (query_client_id, query_bytes) = ClientId::split_prefix(&query_payload).unwrap();
+            assert_eq!(query_client_id, client_id);
+            let server_query =
+                deserialize_query(query_bytes, &psi_params, server.evaluator()).unwrap();
+            let response = server.query(&server_query, &ek).unwrap();
+            let response_bytes = bincode::serialize(&serialize_query_response(
+                &response,
+                server.evaluator().params(),
+            ))
+            .unwrap();
+
+            assert_eq!(
+                psi_process_response(
+                    client,
+                    query,
+                    0,
+                    response_bytes.as_ptr(),
+                    response_bytes.len(),
+                    &mut buffer
+                ),
+                PSI_OK
+            );
+            let responses = decode_response_labels(&take(buffer));
+            let (_, labels) = responses
+                .iter()
+                .find(|(item, _)| *item == item_labels[0].item().to_le_bytes())
+                .expect("Item at intersection is missing in response");
+            assert!(labels.contains(&item_labels[0].label().as_bytes().to_vec()));
+
+            // query index out of range
+            assert_eq!(
+                psi_query_payload(client, query, 1, &mut buffer),
+                PSI_ERROR_INVALID_ARGUMENT
+            );
+            assert!(!psi_last_error().is_null());
+
+            psi_query_free(query);
+            psi_client_free(client);
+        }
+    }
+
+    #[test]
+    fn client_rejects_malformed_arguments() {
+        unsafe {
+            let mut client = ptr::null_mut();
+            let params = CString::new("not toml").unwrap();
+            assert_eq!(
+                psi_client_new(params.as_ptr(), &mut client),
+                PSI_ERROR_INVALID_ARGUMENT
+            );
+            assert!(client.is_null());
+            assert_eq!(
+                psi_client_from_secret_key(ptr::null(), [1u8; 4].as_ptr(), 4, &mut client),
+                PSI_ERROR_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                psi_client_new(ptr::null(), ptr::null_mut()),
+                PSI_ERROR_INVALID_ARGUMENT
+            );
+
+            // secret key restores the same client
+            assert_eq!(psi_client_new(ptr::null(), &mut client), PSI_OK);
+            let mut buffer = empty_buffer();
+            assert_eq!(psi_client_secret_key(client, &mut buffer), PSI_OK);
+            let sk = take(buffer);
+            let mut restored = ptr::null_mut();
+            assert_eq!(
+                psi_client_from_secret_key(ptr::null(), sk.as_ptr(), sk.len(), &mut restored),
+                PSI_OK
+            );
+            assert_eq!(psi_client_secret_key(restored, &mut buffer), PSI_OK);
+            assert_eq!(take(buffer), sk);
+
+            // malformed response
+            let item = [1u8; PSI_ITEM_BYTES];
+            let mut query = ptr::null_mut();
+            assert_eq!(
+                psi_construct_query(client, item.as_ptr(), 1, &mut query),
+                PSI_OK
+            );
+            assert_eq!(
+                psi_process_response(client, query, 0, [0u8; 3].as_ptr(), 3, &mut buffer),
+                PSI_ERROR_FAILED
+            );
+
+            psi_query_free(query);
+            psi_client_free(client);
+            psi_client_free(restored);
+        }
+    }
+}
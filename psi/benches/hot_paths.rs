@@ -0,0 +1,270 @@
+//! Benchmarks of hot paths of setup and query, for each of `PsiParams::for_server_size` presets. Run with
+//! `cargo bench -p psi` and compare against a saved baseline (`--save-baseline` / `--baseline`) to catch regressions,
+//! for ex. after bumping BFV.
+
+use bfv::{Encoding, EvaluationKeyProto, Evaluator, Plaintext, PolyCache, SecretKey};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use itertools::Itertools;
+use ndarray::Array2;
+use prost::Message;
+use psi::{
+    construct_query, deserialize_query, deserialize_query_response, gen_bfv_params,
+    gen_random_item_labels, generate_evaluation_key,
+    internals::{
+        calculate_ps_powers_with_dag, calculate_source_powers, newton_interpolate, ps_evaluate_poly,
+    },
+    random_u256, serialize_query, serialize_query_response, Cuckoo, Db, HashBackend, ItemLabel,
+    PsiParams, Server, PRESET_SERVER_SIZES,
+};
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use traits::{TryEncodingWithParameters, TryFromWithParameters};
+
+/// No. of ItemLabels inserted into db by setup benchmarks
+const SET_SIZE: usize = 1 << 12;
+
+/// No. of items hashed by hash backend benchmarks
+const HASHED_SET_SIZE: usize = 1 << 20;
+
+/// Label size of presets
+const LABEL_BYTES: u32 = 32;
+
+/// Returns preset params for each server size in `PRESET_SERVER_SIZES`, named by the server size
+fn presets() -> Vec<(String, PsiParams)> {
+    PRESET_SERVER_SIZES
+        .iter()
+        .map(|log_n| {
+            (
+                format!("2^{log_n}"),
+                PsiParams::for_server_size(1 << log_n, LABEL_BYTES),
+            )
+        })
+        .collect()
+}
+
+fn bench_newton_interpolate(c: &mut Criterion) {
+    let mut rng = thread_rng();
+    let mut group = c.benchmark_group("newton_interpolate");
+    for (name, psi_params) in presets() {
+        let modq = gen_bfv_params(&psi_params).plaintext_modulus as u32;
+        let points = psi_params.ps_params().total_degree() + 1;
+        let x = (0..points as u32).collect_vec();
+        let y = (0..points).map(|_| rng.gen::<u32>() % modq).collect_vec();
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| newton_interpolate(&x, &y, modq).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_insert_many(c: &mut Criterion) {
+    let mut group = c.benchmark_group("db_insert_many");
+    group.sample_size(10);
+    for (name, psi_params) in presets() {
+        let item_labels = gen_random_item_labels(SET_SIZE, None);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || Db::new(&psi_params),
+                |mut db| db.insert_many(&item_labels),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn bench_preprocess(c: &mut Criterion) {
+    let mut group = c.benchmark_group("db_preprocess");
+    group.sample_size(10);
+    for (name, psi_params) in presets() {
+        let item_labels = gen_random_item_labels(SET_SIZE, None);
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter_batched(
+                || {
+                    let mut db = Db::new(&psi_params);
+                    assert!(db.insert_many(&item_labels).is_empty());
+                    db
+                },
+                |mut db| db.preprocess().unwrap(),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// Benchmarks calculating PS powers from encrypted source powers and evaluating a single polynomial on them, which is
+/// what server does for every InnerBox of a query
+fn bench_ps(c: &mut Criterion) {
+    let mut rng = thread_rng();
+    let mut powers_group = c.benchmark_group("calculate_ps_powers_with_dag");
+    powers_group.sample_size(10);
+    let mut inputs = vec![];
+    for (name, psi_params) in presets() {
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let modq = evaluator.params().plaintext_modulus as u32;
+
+        let values = (0..evaluator.params().degree)
+            .map(|_| rng.gen::<u32>() % modq)
+            .collect_vec();
+        let source_powers_cts = calculate_source_powers(&values, psi_params.source_powers(), modq)
+            .iter()
+            .map(|powers| {
+                let pt = Plaintext::try_encoding_with_parameters(
+                    powers.as_slice(),
+                    evaluator.params(),
+                    Encoding::simd(0, PolyCache::None),
+                );
+                evaluator.encrypt(&sk, &pt, &mut rng)
+            })
+            .collect_vec();
+        let dag = psi_params.powers_dag().unwrap().into_nodes();
+
+        let ps_powers = || {
+            calculate_ps_powers_with_dag(
+                &evaluator,
+                &ek,
+                &source_powers_cts,
+                psi_params.source_powers(),
+                psi_params.ps_params().powers(),
+                &dag,
+                psi_params.ps_params(),
+            )
+        };
+        powers_group.bench_function(BenchmarkId::from_parameter(&name), |b| b.iter(&ps_powers));
+        let x_powers = ps_powers();
+        inputs.push((name, psi_params, evaluator, sk, x_powers));
+    }
+    powers_group.finish();
+
+    // evaluation at level 1 trades larger evaluation key, which holds relinearization key of both levels, for faster
+    // evaluation
+    let mut evaluate_group = c.benchmark_group("ps_evaluate_poly");
+    evaluate_group.sample_size(10);
+    for (name, psi_params, evaluator, sk, x_powers) in inputs {
+        let modq = evaluator.params().plaintext_modulus as u32;
+        let coefficients = Array2::from_shape_fn(
+            (
+                evaluator.params().degree,
+                psi_params.ps_params().total_degree() + 1,
+            ),
+            |_| rng.gen::<u32>() % modq,
+        );
+        for level in [0, 1] {
+            let psi_params = psi_params.clone().with_evaluation_level(level);
+            let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+            let ek_bytes = EvaluationKeyProto::try_from_with_parameters(&ek, evaluator.params())
+                .encode_to_vec()
+                .len();
+            eprintln!("{name} level {level}: evaluation key of {ek_bytes} bytes");
+
+            let mut x_powers = x_powers.clone();
+            x_powers
+                .values_mut()
+                .for_each(|ct| evaluator.mod_down_level(ct, level));
+            evaluate_group.bench_function(BenchmarkId::new(&name, format!("level_{level}")), |b| {
+                b.iter(|| {
+                    ps_evaluate_poly(
+                        &evaluator,
+                        &ek,
+                        &x_powers,
+                        psi_params.ps_params(),
+                        coefficients.view(),
+                        level,
+                    )
+                })
+            });
+        }
+    }
+    evaluate_group.finish();
+}
+
+/// Benchmarks serializing and deserializing query of a single item and response to it
+fn bench_serialize(c: &mut Criterion) {
+    let mut rng = thread_rng();
+    let mut group = c.benchmark_group("serialize");
+    group.sample_size(10);
+    for (name, psi_params) in presets() {
+        let item_labels = gen_random_item_labels(SET_SIZE, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let query_state = construct_query(
+            &[*item_labels[0].item()],
+            &psi_params,
+            &evaluator,
+            &sk,
+            &mut rng,
+        );
+        let query = query_state.query();
+        let response = server.query(query, &ek).unwrap();
+
+        let query_bytes = serialize_query(query, evaluator.params());
+        let response_bytes = serialize_query_response(&response, evaluator.params());
+        group.bench_function(BenchmarkId::new("serialize_query", &name), |b| {
+            b.iter(|| serialize_query(query, evaluator.params()))
+        });
+        group.bench_function(BenchmarkId::new("deserialize_query", &name), |b| {
+            b.iter(|| deserialize_query(&query_bytes, &psi_params, &evaluator).unwrap())
+        });
+        group.bench_function(BenchmarkId::new("serialize_query_response", &name), |b| {
+            b.iter(|| serialize_query_response(&response, evaluator.params()))
+        });
+        group.bench_function(BenchmarkId::new("deserialize_query_response", &name), |b| {
+            b.iter(|| deserialize_query_response(&response_bytes, &psi_params, &evaluator).unwrap())
+        });
+    }
+    group.finish();
+}
+
+/// Benchmarks computing cuckoo table indices and inserting into db with each `HashBackend`
+fn bench_hash_backends(c: &mut Criterion) {
+    let mut rng = thread_rng();
+    let items = (0..HASHED_SET_SIZE)
+        .map(|_| random_u256(&mut rng))
+        .collect_vec();
+    let item_labels = items
+        .iter()
+        .map(|item| ItemLabel::new(*item, random_u256(&mut rng)))
+        .collect_vec();
+
+    let mut group = c.benchmark_group("hash_backends");
+    group.sample_size(10);
+    for backend in [
+        HashBackend::Sha256,
+        HashBackend::Blake3,
+        HashBackend::SipHash,
+    ] {
+        let name = format!("{backend:?}");
+        let cuckoo = Cuckoo::new(3, 4096).with_hasher(backend, 7);
+        group.bench_function(BenchmarkId::new("table_indices_many", &name), |b| {
+            b.iter(|| cuckoo.table_indices_many(items.par_iter()))
+        });
+
+        let psi_params = PsiParams::default().with_hash_backend(backend);
+        group.bench_function(BenchmarkId::new("db_insert_many", &name), |b| {
+            b.iter_batched(
+                || Db::new(&psi_params),
+                |mut db| db.insert_many(&item_labels),
+                BatchSize::LargeInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_newton_interpolate,
+    bench_insert_many,
+    bench_preprocess,
+    bench_ps,
+    bench_serialize,
+    bench_hash_backends
+);
+criterion_main!(benches);
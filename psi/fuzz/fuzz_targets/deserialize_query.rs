@@ -0,0 +1,62 @@
+//! Fuzzes `deserialize_query`, which server calls on every query it receives. Inputs whose first byte is 0 are
+//! deserialized as is, which mostly exercises length prefixes. Rest are written over a valid query at offset given by the
+//! following 4 bytes, thus ciphertext protos of a correctly sized query are truncated, resized, or corrupted.
+#![no_main]
+
+use bfv::{Evaluator, SecretKey};
+use libfuzzer_sys::fuzz_target;
+use psi::{
+    construct_query, deserialize_query, gen_bfv_params, random_u256, serialize_query, PsiParams,
+};
+use rand::thread_rng;
+
+struct Setup {
+    psi_params: PsiParams,
+    evaluator: Evaluator,
+    query_bytes: Vec<u8>,
+}
+
+impl Setup {
+    fn new() -> Setup {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let query_set = (0..10).map(|_| random_u256(&mut rng)).collect::<Vec<_>>();
+        let query_state = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        let query_bytes = serialize_query(query_state.query(), evaluator.params());
+        Setup {
+            psi_params,
+            evaluator,
+            query_bytes,
+        }
+    }
+}
+
+thread_local! {
+    static SETUP: Setup = Setup::new();
+}
+
+/// Returns input to deserialize for fuzzer's `data`. See module docs.
+fn fuzz_input(valid: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    match data.first()? {
+        0 => Some(data[1..].to_vec()),
+        _ if data.len() >= 5 => {
+            let mut bytes = valid.to_vec();
+            let offset = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize % bytes.len();
+            let patch = &data[5..];
+            let end = (offset + patch.len()).min(bytes.len());
+            bytes[offset..end].copy_from_slice(&patch[..end - offset]);
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    SETUP.with(|setup| {
+        if let Some(bytes) = fuzz_input(&setup.query_bytes, data) {
+            let _ = deserialize_query(&bytes, &setup.psi_params, &setup.evaluator);
+        }
+    });
+});
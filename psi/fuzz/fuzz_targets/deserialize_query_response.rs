@@ -0,0 +1,79 @@
+//! Fuzzes `deserialize_query_response`, which client calls on every response it receives. Inputs are bincode
+//! serialized `SerializedQueryResponse`s. Inputs whose first byte is 0 are deserialized as is. Rest are written over
+//! a valid response at offset given by the following 4 bytes, thus ciphertext protos are truncated, resized, or
+//! corrupted, and no. of ciphertexts per segment may not add up.
+#![no_main]
+
+use bfv::{Evaluator, SecretKey};
+use libfuzzer_sys::fuzz_target;
+use psi::{
+    construct_query, deserialize_query_response, gen_bfv_params, gen_random_item_labels,
+    generate_evaluation_key, serialize_query_response, PsiParams, SerializedQueryResponse, Server,
+};
+use rand::thread_rng;
+
+struct Setup {
+    psi_params: PsiParams,
+    evaluator: Evaluator,
+    response_bytes: Vec<u8>,
+}
+
+impl Setup {
+    fn new() -> Setup {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1 << 10, Some(0));
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let query_state = construct_query(
+            &[*item_labels[0].item()],
+            &psi_params,
+            &evaluator,
+            &sk,
+            &mut rng,
+        );
+        let response = server.query(query_state.query(), &ek).unwrap();
+        let response_bytes =
+            bincode::serialize(&serialize_query_response(&response, evaluator.params())).unwrap();
+        Setup {
+            psi_params,
+            evaluator,
+            response_bytes,
+        }
+    }
+}
+
+thread_local! {
+    static SETUP: Setup = Setup::new();
+}
+
+/// Returns input to deserialize for fuzzer's `data`. See module docs.
+fn fuzz_input(valid: &[u8], data: &[u8]) -> Option<Vec<u8>> {
+    match data.first()? {
+        0 => Some(data[1..].to_vec()),
+        _ if data.len() >= 5 => {
+            let mut bytes = valid.to_vec();
+            let offset = u32::from_le_bytes(data[1..5].try_into().unwrap()) as usize % bytes.len();
+            let patch = &data[5..];
+            let end = (offset + patch.len()).min(bytes.len());
+            bytes[offset..end].copy_from_slice(&patch[..end - offset]);
+            Some(bytes)
+        }
+        _ => None,
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    SETUP.with(|setup| {
+        let Some(bytes) = fuzz_input(&setup.response_bytes, data) else {
+            return;
+        };
+        if let Ok(serialized) = bincode::deserialize::<SerializedQueryResponse>(&bytes) {
+            let _ = deserialize_query_response(&serialized, &setup.psi_params, &setup.evaluator);
+        }
+    });
+});
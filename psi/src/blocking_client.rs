@@ -0,0 +1,170 @@
+use bfv::SecretKey;
+use crypto_bigint::U256;
+use std::{
+    io::{Read, Write},
+    net::{TcpStream, ToSocketAddrs},
+};
+
+use crate::{
+    oprf_outputs, write_frame_blocking, ClientCore, ClientId, Frame, FrameReader, MessageType,
+    PotentialResponseLabels, ProtocolError, PsiError, PsiParams, QueryMetadata, QueryState,
+    CAPABILITY_METADATA, CAPABILITY_ZSTD, MAX_FRAME_BYTES,
+};
+
+/// Same as `PsiClient` but over a blocking stream, thus applications that don't run a tokio runtime, for ex. CLI
+/// tools, can query server. Queries are sent sequentially over a single connection. Streamed and pipelined queries
+/// aren't supported.
+pub struct BlockingPsiClient<S = TcpStream> {
+    stream: FrameReader<S>,
+    core: ClientCore,
+    /// Metadata returned with response to the last query
+    last_metadata: Option<QueryMetadata>,
+}
+
+impl BlockingPsiClient<TcpStream> {
+    /// Same as `PsiClient::connect`
+    pub fn connect<A: ToSocketAddrs>(
+        addr: A,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> Result<BlockingPsiClient<TcpStream>, PsiError> {
+        let stream = TcpStream::connect(addr)?;
+        Ok(BlockingPsiClient::from_stream(
+            stream, psi_params, sk, client_id,
+        ))
+    }
+}
+
+impl<S: Read + Write> BlockingPsiClient<S> {
+    /// Creates client over an already established connection to server
+    pub fn from_stream(
+        stream: S,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> BlockingPsiClient<S> {
+        BlockingPsiClient {
+            stream: FrameReader::new(stream),
+            core: ClientCore::new(psi_params, sk, client_id),
+            last_metadata: None,
+        }
+    }
+
+    /// Same as `PsiClient::with_seed`
+    pub fn with_seed(mut self, seed: u64) -> BlockingPsiClient<S> {
+        self.core = self.core.with_seed(seed);
+        self
+    }
+
+    pub fn psi_params(&self) -> &PsiParams {
+        &self.core.psi_params
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.core.client_id
+    }
+
+    pub fn compression(&self) -> bool {
+        self.core.compression
+    }
+
+    /// Same as `PsiClient::enable_compression`
+    pub fn enable_compression(&mut self) -> Result<bool, PsiError> {
+        self.hello(self.core.capabilities() | CAPABILITY_ZSTD)?;
+        Ok(self.core.compression)
+    }
+
+    /// Same as `PsiClient::enable_metadata`
+    pub fn enable_metadata(&mut self) -> Result<bool, PsiError> {
+        self.hello(self.core.capabilities() | CAPABILITY_METADATA)?;
+        Ok(self.core.metadata)
+    }
+
+    /// Same as `PsiClient::last_metadata`
+    pub fn last_metadata(&self) -> Option<&QueryMetadata> {
+        self.last_metadata.as_ref()
+    }
+
+    /// Sends capability `flags`, which replace flags sent before, and enables flags server agreed to
+    fn hello(&mut self, flags: u8) -> Result<(), PsiError> {
+        let frame = Frame::new(MessageType::Hello, vec![flags]);
+        let payload = self.send(&frame)?.into_payload(MessageType::Hello)?;
+        self.core.process_hello(&payload)?;
+        Ok(())
+    }
+
+    /// Same as `PsiClient::authenticate`
+    pub fn authenticate(&mut self, token: &str) -> Result<(), PsiError> {
+        let frame = Frame::new(MessageType::Auth, token.as_bytes().to_vec());
+        self.send(&frame)?.into_payload(MessageType::Ack)?;
+        Ok(())
+    }
+
+    /// Same as `PsiClient::select_tenant`
+    pub fn select_tenant(&mut self, tenant_id: &str) -> Result<(), PsiError> {
+        let frame = Frame::new(MessageType::Tenant, tenant_id.as_bytes().to_vec());
+        self.send(&frame)?.into_payload(MessageType::Ack)?;
+        Ok(())
+    }
+
+    /// Sends `frame` and returns server's response
+    fn send(&mut self, frame: &Frame) -> Result<Frame, ProtocolError> {
+        write_frame_blocking(self.stream.get_mut(), frame)?;
+        self.stream
+            .read_blocking_with_limit(|_| MAX_FRAME_BYTES)?
+            .ok_or(ProtocolError::Io("Server closed connection".to_string()))
+    }
+
+    /// Same as `PsiClient::upload_keys`
+    pub fn upload_keys(&mut self) -> Result<(), PsiError> {
+        let frame = Frame::new(
+            MessageType::EvaluationKey,
+            self.core.evaluation_key_payload(),
+        );
+        self.send(&frame)?.into_payload(MessageType::Ack)?;
+        Ok(())
+    }
+
+    /// Same as `PsiClient::construct_queries`
+    pub fn construct_queries(&mut self, items: &[U256]) -> Result<Vec<QueryState>, PsiError> {
+        let oprf_outputs = if self.core.psi_params.oprf() {
+            let (blind_state, request) = self.core.oprf_request(items);
+            let frame = Frame::new(MessageType::OprfRequest, request);
+            let response_bytes = self.send(&frame)?.into_payload(MessageType::OprfResponse)?;
+            Some(oprf_outputs(&blind_state, &response_bytes)?)
+        } else {
+            None
+        };
+        Ok(self.core.construct_queries(items, oprf_outputs.as_deref()))
+    }
+
+    /// Same as `PsiClient::send_query`
+    pub fn send_query(
+        &mut self,
+        query_state: &QueryState,
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let frame = Frame::new(MessageType::Query, self.core.query_payload(query_state));
+
+        let mut response = self.send(&frame)?;
+        if response.message_type == MessageType::EvaluationKeyRequired {
+            self.upload_keys()?;
+            response = self.send(&frame)?;
+        }
+        let (potential_labels, metadata) = self
+            .core
+            .process_query_response_frame(query_state, response)?;
+        self.last_metadata = metadata;
+        Ok(potential_labels)
+    }
+
+    /// Same as `PsiClient::query`
+    pub fn query(&mut self, items: &[U256]) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let query_states = self.construct_queries(items)?;
+        let mut responses = vec![];
+        for query_state in &query_states {
+            responses.extend(self.send_query(query_state)?);
+        }
+        Ok(responses)
+    }
+}
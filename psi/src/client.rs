@@ -1,22 +1,28 @@
-use std::{collections::HashMap, ops::Deref};
+use std::{
+    collections::HashMap,
+    ops::{Deref, Range},
+};
 
-use bfv::{Ciphertext, Encoding, Evaluator, Modulus, Plaintext, SecretKey};
+use bfv::{Ciphertext, Encoding, Evaluator, Modulus, SecretKey};
 use crypto_bigint::U256;
 use itertools::{izip, Itertools};
 use rand::{CryptoRng, Rng, RngCore};
-use traits::{TryDecodingWithParameters, TryEncodingWithParameters};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn, Level};
+use traits::TryDecodingWithParameters;
 
 use crate::{
-    chunks_to_value,
     hash::{self, construct_hash_tables, Cuckoo, HashTableEntry},
-    server::{db, CiphertextSlots, HashTableSize, PsiPlaintext},
-    value_to_chunks, HashTableQueryResponse, PsiParams, QueryResponse,
+    rows_per_segment, segments_per_hash_table,
+    server::{db, label_checksum, CiphertextSlots, HashTableSize, Label, PsiPlaintext},
+    value_to_chunks, ClientId, HashTableQueryResponse, PowersKey, PsiError, PsiMode, PsiParams,
+    QueryEncryptionKey, QueryLayout, QueryResponse,
 };
 
 #[derive(Debug, Clone)]
 pub struct PotentialResponseLabels {
     pub(crate) item: U256,
-    pub(crate) labels: Vec<U256>,
+    pub(crate) labels: Vec<Label>,
 }
 
 impl PotentialResponseLabels {
@@ -24,7 +30,7 @@ impl PotentialResponseLabels {
         &self.item
     }
 
-    pub fn labels(&self) -> &[U256] {
+    pub fn labels(&self) -> &[Label] {
         &self.labels
     }
 }
@@ -55,6 +61,7 @@ pub fn calculate_source_powers(
 }
 
 /// Processed by server on each segment (ie vectors of InnerBoxes correspoding to a subset of hash table rows)
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct InnerBoxQuery {
     data: Vec<u32>,
     psi_pt: PsiPlaintext,
@@ -69,6 +76,11 @@ impl InnerBoxQuery {
         }
     }
 
+    /// Plaintext slots of the query, before they are raised to source powers and encrypted
+    pub fn data(&self) -> &[u32] {
+        &self.data
+    }
+
     pub fn value_chunks(&self, value: u128) -> Vec<u32> {
         let bits = self.psi_pt.bytes_per_chunk();
         let mask = (1 << bits) - 1;
@@ -93,37 +105,63 @@ impl InnerBoxQuery {
         }
     }
 
+    /// See `rows_per_segment`
     pub fn max_rows(ct_slots: &CiphertextSlots, psi_pt: &PsiPlaintext) -> u32 {
-        ct_slots.deref() / psi_pt.slots_required()
+        rows_per_segment(ct_slots, psi_pt)
     }
 
+    /// Returns potential labels at `expected_row` in response of a segment. Each InnerBox of the segment responds
+    /// with `PsiParams::label_parts` ciphertexts, one after another, and chunks at `expected_row` of all parts are
+    /// concatenated to obtain the label. With `PsiParams::membership`, last ciphertext of each InnerBox is its
+    /// membership ciphertext and only labels of InnerBoxes whose membership chunks at `expected_row` are 0 are
+    /// returned.
     pub fn process_segment_response_at_row(
-        psi_pt: &PsiPlaintext,
+        psi_params: &PsiParams,
         expected_row: u32,
-        segment_response: &Vec<Vec<u32>>,
-    ) -> Vec<U256> {
+        segment_response: &[Vec<u32>],
+    ) -> Vec<Label> {
+        let psi_pt = &psi_params.psi_pt;
         let real_row = expected_row * psi_pt.slots_required();
+        let real_rows = real_row..(real_row + psi_pt.slots_required());
+        let bytes_per_chunk = psi_pt.bytes_per_chunk() as usize;
+        let label_bytes = match psi_params.mode {
+            PsiMode::Labeled => psi_pt.encoded_label_bytes(),
+            PsiMode::Unlabeled => psi_pt.psi_pt_bytes,
+        } as usize;
+        let membership = psi_params.mode == PsiMode::Labeled && psi_params.membership;
 
         segment_response
-            .iter()
-            .map(|res| {
-                let mut res_value_chunks = vec![];
-                for i in real_row..(real_row + psi_pt.slots_required()) {
-                    res_value_chunks.push(res[i as usize]);
-                }
-
-                let res_value = chunks_to_value(
-                    &res_value_chunks,
-                    psi_pt.psi_pt_bytes,
-                    psi_pt.bytes_per_chunk(),
-                );
-                res_value
+            .chunks_exact(psi_params.label_parts() as usize)
+            .filter_map(|ib_response| {
+                let ib_response = if membership {
+                    let (membership_response, ib_response) = ib_response.split_last().unwrap();
+                    if real_rows
+                        .clone()
+                        .any(|i| membership_response[i as usize] != 0)
+                    {
+                        return None;
+                    }
+                    ib_response
+                } else {
+                    ib_response
+                };
+                let mut bytes = ib_response
+                    .iter()
+                    .flat_map(|res| {
+                        real_rows
+                            .clone()
+                            .flat_map(|i| res[i as usize].to_le_bytes()[..bytes_per_chunk].to_vec())
+                    })
+                    .collect_vec();
+                bytes.truncate(label_bytes);
+                Some(Label(bytes))
             })
             .collect_vec()
     }
 }
 
 /// Processed by server on BigBox
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct HashTableQuery {
     ib_queries: Vec<InnerBoxQuery>,
     ht_size: HashTableSize,
@@ -138,8 +176,8 @@ impl HashTableQuery {
         ct_slots: &CiphertextSlots,
         psi_pt: &PsiPlaintext,
     ) -> HashTableQuery {
-        let ib_query_rows = InnerBoxQuery::max_rows(ct_slots, psi_pt);
-        let segments = (ht_size.deref() + (ib_query_rows >> 1)) / ib_query_rows;
+        let ib_query_rows = rows_per_segment(ct_slots, psi_pt);
+        let segments = segments_per_hash_table(ht_size, ct_slots, psi_pt);
 
         let ib_queries = (0..segments)
             .into_iter()
@@ -154,14 +192,18 @@ impl HashTableQuery {
         }
     }
 
+    /// See `segments_per_hash_table`
     pub fn segments_count(
         ht_size: &HashTableSize,
         ct_slots: &CiphertextSlots,
         psi_pt: &PsiPlaintext,
     ) -> u32 {
-        let ib_query_rows = InnerBoxQuery::max_rows(ct_slots, psi_pt);
-        let segments = (ht_size.deref() + (ib_query_rows >> 1)) / ib_query_rows;
-        segments
+        segments_per_hash_table(ht_size, ct_slots, psi_pt)
+    }
+
+    /// InnerBoxQuery of each segment
+    pub fn inner_box_queries(&self) -> &[InnerBoxQuery] {
+        &self.ib_queries
     }
 
     pub fn process_hash_table(&mut self, hash_table: &HashMap<u32, HashTableEntry>) {
@@ -182,11 +224,15 @@ impl HashTableQuery {
         }
     }
 
-    pub fn process_inner_box_queries_with_source_powers_and_encrypt<R: CryptoRng + RngCore>(
+    /// Encrypts InnerBoxQuery of each segment raised to `source_powers` with `key`
+    pub fn process_inner_box_queries_with_source_powers_and_encrypt<
+        K: QueryEncryptionKey,
+        R: CryptoRng + RngCore,
+    >(
         &self,
         source_powers: &[usize],
         evaluator: &Evaluator,
-        sk: &SecretKey,
+        key: &K,
         rng: &mut R,
     ) -> HashTableQueryCts {
         let ht_table_query_cts = self
@@ -202,15 +248,7 @@ impl HashTableQuery {
                 // encrypt `q` raised to different source powers
                 let q_source_powers_ct = q_sources_powers
                     .iter()
-                    .map(|q_power| {
-                        let pt = Plaintext::try_encoding_with_parameters(
-                            q_power.as_slice(),
-                            evaluator.params(),
-                            Encoding::default(),
-                        );
-
-                        evaluator.encrypt(sk, &pt, rng)
-                    })
+                    .map(|q_power| key.encrypt_values(evaluator, q_power, rng))
                     .collect_vec();
 
                 q_source_powers_ct
@@ -220,6 +258,8 @@ impl HashTableQuery {
         HashTableQueryCts(ht_table_query_cts)
     }
 
+    /// Decrypts response of each segment that has an entry in `hash_table` and returns potential labels of each
+    /// entry, ordered by row. Responses of segments without entries aren't decrypted.
     pub fn process_hash_table_query_response(
         psi_params: &PsiParams,
         evaluator: &Evaluator,
@@ -228,80 +268,310 @@ impl HashTableQuery {
         ht_query_response: &HashTableQueryResponse,
     ) -> Vec<PotentialResponseLabels> {
         // InnerBoxQuery is constructed per Segment
-        let inner_box_max_rows = InnerBoxQuery::max_rows(&psi_params.ct_slots, &psi_params.psi_pt);
-        let original_inner_box_queries =
-            (psi_params.ht_size.0 + (inner_box_max_rows >> 1)) / inner_box_max_rows;
+        let layout = QueryLayout::new(psi_params);
 
         // segments in response and in the query must be equal
-        assert_eq!(
-            ht_query_response.0.len(),
-            original_inner_box_queries as usize
-        );
+        assert_eq!(ht_query_response.0.len(), layout.segments_per_hash_table());
+
+        izip!(
+            entries_by_segment(&layout, hash_table),
+            ht_query_response.0.iter()
+        )
+        .filter(|(entries, _)| !entries.is_empty())
+        .flat_map(|(entries, segment_cts)| {
+            let segment_response = decrypt_segment_response(evaluator, sk, segment_cts);
+            process_segment_entries(psi_params, &entries, &segment_response)
+        })
+        .collect_vec()
+    }
+}
+
+/// Returns entries of `hash_table` in each segment as (row within segment, item), ordered by row. Segments without
+/// entries are empty.
+fn entries_by_segment(
+    layout: &QueryLayout,
+    hash_table: &HashMap<u32, HashTableEntry>,
+) -> Vec<Vec<(u32, U256)>> {
+    let mut segments = vec![vec![]; layout.segments_per_hash_table()];
+    hash_table
+        .iter()
+        .sorted_by_key(|(row, _)| **row)
+        .for_each(|(row, entry)| {
+            let (segment_index, ib_row) = layout.segment_row(*row);
+            segments[segment_index].push((ib_row, *entry.entry_value()));
+        });
+    segments
+}
 
-        // decrypt responses
-        let segment_responses = ht_query_response
-            .0
+/// Decrypts response ciphertexts of a segment, one for each label part of each InnerBox
+fn decrypt_segment_response(
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    segment_cts: &[Ciphertext],
+) -> Vec<Vec<u32>> {
+    segment_cts
+        .iter()
+        .map(|ct| {
+            let pt = evaluator.decrypt(sk, ct);
+            Vec::<u32>::try_decoding_with_parameters(&pt, evaluator.params(), Encoding::default())
+        })
+        .collect_vec()
+}
+
+/// Returns potential labels of `entries` of a segment, given as (row within segment, item), from decrypted
+/// `segment_response`
+fn process_segment_entries(
+    psi_params: &PsiParams,
+    entries: &[(u32, U256)],
+    segment_response: &[Vec<u32>],
+) -> Vec<PotentialResponseLabels> {
+    entries
+        .iter()
+        .map(|(expected_ib_row, item)| {
+            // response corresponding to segment contains multiple vectors, since a segment is further divided into
+            // multiple innerboxes.
+            let potential_responses = InnerBoxQuery::process_segment_response_at_row(
+                psi_params,
+                *expected_ib_row,
+                segment_response,
+            );
+            PotentialResponseLabels {
+                item: *item,
+                labels: verify_label_checksums(psi_params, item, potential_responses),
+            }
+        })
+        .collect_vec()
+}
+
+/// Decrypts response of a query segment by segment, as segments arrive, instead of decrypting `QueryResponse` once it
+/// is complete. Ciphertexts of a segment can be dropped as soon as it is added, thus memory is bounded by potential
+/// labels instead of response ciphertexts. Segments without entries of the query (see `QueryState::occupied_segments`)
+/// aren't decrypted.
+pub struct StreamingResponseDecryptor {
+    psi_params: PsiParams,
+    /// Entries of each segment of each hash table as (row within segment, item), ordered by row
+    segment_entries: Vec<Vec<Vec<(u32, U256)>>>,
+    /// Potential labels of entries of each segment of each hash table. `None` until segment is added.
+    segment_labels: Vec<Vec<Option<Vec<PotentialResponseLabels>>>>,
+    received: usize,
+    decrypted: usize,
+}
+
+impl StreamingResponseDecryptor {
+    pub fn new(psi_params: &PsiParams, query_state: &QueryState) -> StreamingResponseDecryptor {
+        let layout = QueryLayout::new(psi_params);
+        let segment_entries = query_state
+            .hash_tables
             .iter()
-            .map(|segment_cts| {
-                segment_cts
-                    .iter()
-                    .map(|ct| {
-                        let pt = evaluator.decrypt(sk, ct);
-                        Vec::<u32>::try_decoding_with_parameters(
-                            &pt,
-                            evaluator.params(),
-                            Encoding::default(),
-                        )
-                    })
-                    .collect_vec()
-            })
+            .map(|hash_table| entries_by_segment(&layout, hash_table))
+            .collect_vec();
+        let segment_labels = segment_entries
+            .iter()
+            .map(|segments| vec![None; segments.len()])
             .collect_vec();
+        StreamingResponseDecryptor {
+            psi_params: psi_params.clone(),
+            segment_entries,
+            segment_labels,
+            received: 0,
+            decrypted: 0,
+        }
+    }
 
-        let mut response = vec![];
-        for i in 0..*psi_params.ht_size.deref() {
-            match hash_table.get(&i) {
-                Some(entry) => {
-                    // which segement do we expect the response to be in
-                    let segment_index = i / inner_box_max_rows;
+    /// Decrypts response ciphertexts `cts` of `segment` of BigBox `big_box` and extracts potential labels of entries
+    /// of the segment. `cts` aren't decrypted if query has no entry in the segment.
+    pub fn add_segment(
+        &mut self,
+        big_box: usize,
+        segment: usize,
+        cts: &[Ciphertext],
+        evaluator: &Evaluator,
+        sk: &SecretKey,
+    ) -> Result<(), PsiError> {
+        let slot = self
+            .segment_labels
+            .get_mut(big_box)
+            .and_then(|segments| segments.get_mut(segment))
+            .ok_or(PsiError::ParamsMismatch(format!(
+                "Response for non-existent segment {segment} of BigBox {big_box}"
+            )))?;
+        if slot.is_some() {
+            return Err(PsiError::Serialization(format!(
+                "Duplicate response for segment {segment} of BigBox {big_box}"
+            )));
+        }
 
-                    // response corresponding to segment contains multiple vectors, since a segment is further divided into
-                    // multiple innerboxes.
-                    let segment_response = &segment_responses[segment_index as usize];
+        let entries = &self.segment_entries[big_box][segment];
+        let labels = if entries.is_empty() {
+            vec![]
+        } else {
+            self.decrypted += 1;
+            let segment_response = decrypt_segment_response(evaluator, sk, cts);
+            process_segment_entries(&self.psi_params, entries, &segment_response)
+        };
+        *slot = Some(labels);
+        self.received += 1;
+        Ok(())
+    }
 
-                    let expected_ib_row = i % inner_box_max_rows;
+    /// Returns true once responses of all segments are added
+    pub fn is_complete(&self) -> bool {
+        self.received == self.segment_labels.iter().map(|s| s.len()).sum::<usize>()
+    }
 
-                    let potential_responses = InnerBoxQuery::process_segment_response_at_row(
-                        &psi_params.psi_pt,
-                        expected_ib_row,
-                        segment_response,
-                    );
+    /// No. of segment responses decrypted so far
+    pub fn decrypted_segments(&self) -> usize {
+        self.decrypted
+    }
 
-                    response.push(PotentialResponseLabels {
-                        item: entry.entry_value().clone(),
-                        labels: potential_responses,
-                    });
-                }
-                _ => {}
-            }
+    /// Returns potential labels of each item, in the same order as `process_query_response`. Fails if any segment is
+    /// missing.
+    pub fn finish(self) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        if !self.is_complete() {
+            return Err(PsiError::Serialization(
+                "Response is missing segments".to_string(),
+            ));
         }
+        Ok(self
+            .segment_labels
+            .into_iter()
+            .flatten()
+            .flat_map(Option::unwrap)
+            .collect_vec())
+    }
+}
 
-        response
+/// Returns `labels` whose checksum matches checksum of `item`, with checksum removed. Returns `labels` as is if label
+/// checksums are disabled or server is in `PsiMode::Unlabeled`.
+fn verify_label_checksums(psi_params: &PsiParams, item: &U256, labels: Vec<Label>) -> Vec<Label> {
+    let psi_pt = &psi_params.psi_pt;
+    if psi_pt.label_checksum_bytes == 0 || psi_params.mode == PsiMode::Unlabeled {
+        return labels;
     }
+
+    let checksum = label_checksum(item, psi_pt.label_checksum_bytes, psi_params.hash_seed);
+    labels
+        .into_iter()
+        .filter(|label| label.0[psi_pt.label_bytes as usize..] == checksum)
+        .map(|mut label| {
+            label.0.truncate(psi_pt.label_bytes as usize);
+            label
+        })
+        .collect_vec()
 }
 
 /// Encrypted queries for the HashTable. Though ciphertexts are stored in vector, they must be viewed as 2D array of ciphertexts stored in row major form. 2D array has
 /// `Segments` rows, since one InnerBoxQuery maps to one segment in BigBox. 2D array has source powers count columns since each row contains same InnerBoxQuery raised
 /// to different source powers.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct HashTableQueryCts(pub(crate) Vec<Ciphertext>);
 
-#[derive(Debug, PartialEq)]
-pub struct Query(pub(crate) Vec<HashTableQueryCts>);
+impl HashTableQueryCts {
+    /// Query ciphertexts of each segment raised to each source power, in row major order
+    pub fn ciphertexts(&self) -> &[Ciphertext] {
+        &self.0
+    }
+}
+
+/// Query ciphertexts of each hash table followed by encryptions of zero used by server to rerandomize response
+/// ciphertexts. Encryptions of zero are only sent when `PsiParams::circuit_privacy` is enabled.
+#[derive(Debug, Clone)]
+pub struct Query(
+    pub(crate) Vec<HashTableQueryCts>,
+    pub(crate) Vec<Ciphertext>,
+    /// BLAKE3 hash of serialized bytes of each query ciphertext of each hash table, as received by server. Empty
+    /// unless query was deserialized.
+    pub(crate) Vec<Vec<[u8; 32]>>,
+    /// Client that sent the query, set by server with `Query::with_client_id`
+    pub(crate) Option<ClientId>,
+);
+
+impl PartialEq for Query {
+    fn eq(&self, other: &Query) -> bool {
+        self.0 == other.0 && self.1 == other.1
+    }
+}
+
+impl Query {
+    /// Query ciphertexts of each hash table
+    pub fn hash_table_cts(&self) -> impl Iterator<Item = &HashTableQueryCts> {
+        self.0.iter()
+    }
+
+    /// Encryptions of zero used by server to rerandomize response ciphertexts. Empty unless
+    /// `PsiParams::circuit_privacy` is enabled.
+    pub fn zero_cts(&self) -> &[Ciphertext] {
+        &self.1
+    }
+
+    /// Binds deserialized query to client with `client_id`, thus PS powers of its segments are cached in server's
+    /// `PowersCache`
+    pub fn with_client_id(mut self, client_id: ClientId) -> Query {
+        self.3 = Some(client_id);
+        self
+    }
+
+    /// Key of PS powers of query ciphertexts at `cts` of hash table `ht_index` in `PowersCache`. `None` unless query
+    /// was deserialized and bound to its client with `with_client_id`.
+    pub(crate) fn powers_key(&self, ht_index: usize, cts: Range<usize>) -> Option<PowersKey> {
+        let client_id = self.3.as_ref()?;
+        let ct_digests = self.2.get(ht_index)?.get(cts)?;
+        Some(PowersKey::new(client_id, ct_digests))
+    }
+}
+
+/// Independent queries, for ex. queries of a large item set constructed with `construct_queries`, sent to server in a
+/// single request (see `serialize_query_batch`). Server evaluates them one after another and responds with
+/// `QueryResponse` of each, thus connection setup and evaluation key lookup are paid once for the whole batch.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueryBatch(pub(crate) Vec<Query>);
+
+impl QueryBatch {
+    pub fn new(queries: Vec<Query>) -> QueryBatch {
+        QueryBatch(queries)
+    }
+
+    /// Batch of queries in `query_states`, in order
+    pub fn from_query_states(query_states: &[QueryState]) -> QueryBatch {
+        QueryBatch(
+            query_states
+                .iter()
+                .map(|query_state| query_state.query.clone())
+                .collect(),
+        )
+    }
+
+    pub fn queries(&self) -> &[Query] {
+        &self.0
+    }
+
+    /// Binds every query of the batch to client with `client_id`, see `Query::with_client_id`
+    pub fn with_client_id(self, client_id: ClientId) -> QueryBatch {
+        QueryBatch(
+            self.0
+                .into_iter()
+                .map(|query| query.with_client_id(client_id))
+                .collect(),
+        )
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
 
 pub struct QueryState {
     pub(crate) query: Query,
     pub(crate) hash_tables: Vec<HashMap<u32, HashTableEntry>>,
     pub(crate) hash_table_stack: Vec<HashTableEntry>,
+    /// Maps OPRF output to original item. Empty if query was constructed without OPRF.
+    pub(crate) original_items: HashMap<U256, U256>,
+    /// Whether each segment of each hash table has at least one entry. See `occupied_segments`.
+    pub(crate) occupied_segments: Vec<Vec<bool>>,
 }
 
 impl QueryState {
@@ -316,25 +586,95 @@ impl QueryState {
     pub fn hash_table_stack(&self) -> &[HashTableEntry] {
         &self.hash_table_stack
     }
+
+    /// Returns original item corresponding to `item` in hash tables (or in response). If query was constructed
+    /// without OPRF, returns `item` as is.
+    pub fn original_item<'a>(&'a self, item: &'a U256) -> &'a U256 {
+        self.original_items.get(item).unwrap_or(item)
+    }
+
+    /// Whether each segment of each hash table has at least one entry of the query. Responses of unoccupied segments
+    /// don't hold labels of any queried item, thus aren't decrypted.
+    pub fn occupied_segments(&self) -> &[Vec<bool>] {
+        &self.occupied_segments
+    }
 }
 
-pub fn construct_query<R: RngCore + CryptoRng>(
+/// Returns whether each segment of each of `hash_tables` has at least one entry
+pub(crate) fn occupied_segments(
+    psi_params: &PsiParams,
+    hash_tables: &[HashMap<u32, HashTableEntry>],
+) -> Vec<Vec<bool>> {
+    let layout = QueryLayout::new(psi_params);
+    hash_tables
+        .iter()
+        .map(|hash_table| {
+            let mut occupied = vec![false; layout.segments_per_hash_table()];
+            hash_table
+                .keys()
+                .for_each(|row| occupied[layout.segment_row(*row).0] = true);
+            occupied
+        })
+        .collect_vec()
+}
+
+/// Plaintext stage of a query: cuckoo hash tables of items and `HashTableQuery` of each hash table, before encryption.
+/// Only `hash_table_queries` are needed to encrypt the query with `encrypt_query`, thus encryption can run in a separate
+/// process holding the secret key, while hashing and layout run here. Encrypted query is combined with the rest of
+/// the state with `into_query_state`.
+pub struct PlaintextQuery {
+    pub(crate) ht_queries: Vec<HashTableQuery>,
+    pub(crate) hash_tables: Vec<HashMap<u32, HashTableEntry>>,
+    pub(crate) hash_table_stack: Vec<HashTableEntry>,
+    pub(crate) original_items: HashMap<U256, U256>,
+    pub(crate) occupied_segments: Vec<Vec<bool>>,
+}
+
+impl PlaintextQuery {
+    pub fn hash_table_queries(&self) -> &[HashTableQuery] {
+        &self.ht_queries
+    }
+
+    pub fn hash_tables(&self) -> &[HashMap<u32, HashTableEntry>] {
+        &self.hash_tables
+    }
+
+    pub fn hash_table_stack(&self) -> &[HashTableEntry] {
+        &self.hash_table_stack
+    }
+
+    /// Returns `QueryState` of `query`, which must be `hash_table_queries` encrypted with `encrypt_query`.
+    ///
+    /// Panics if `query` does not have a ciphertext set for every hash table.
+    pub fn into_query_state(self, query: Query) -> QueryState {
+        assert_eq!(query.0.len(), self.ht_queries.len());
+        QueryState {
+            query,
+            hash_tables: self.hash_tables,
+            hash_table_stack: self.hash_table_stack,
+            original_items: self.original_items,
+            occupied_segments: self.occupied_segments,
+        }
+    }
+}
+
+/// Places `query_set` in cuckoo hash tables and lays out each hash table as `HashTableQuery`. Does not need the secret
+/// key, see `PlaintextQuery`.
+pub fn construct_plaintext_query<R: Rng>(
     query_set: &[U256],
     psi_params: &PsiParams,
-    evaluator: &Evaluator,
-    sk: &SecretKey,
     rng: &mut R,
-) -> QueryState {
+) -> PlaintextQuery {
     let ht_entries = query_set
         .iter()
         .map(|q| HashTableEntry::new(*q))
         .collect_vec();
 
-    let cuckoo = &Cuckoo::new(psi_params.no_of_hash_tables, *psi_params.ht_size.deref());
+    let cuckoo = &Cuckoo::for_params(psi_params);
 
     // Each hash table returned is a hash map storing values under key equivalent to respective index.
-    let (hash_tables, stack) = construct_hash_tables(&ht_entries, &cuckoo);
-    dbg!(stack.len());
+    let (hash_tables, stack) = construct_hash_tables(&ht_entries, &cuckoo, rng);
+    debug!(stack = stack.len(), "Constructed hash tables");
     let ht_queries = hash_tables
         .iter()
         .map(|ht| {
@@ -348,26 +688,215 @@ pub fn construct_query<R: RngCore + CryptoRng>(
         })
         .collect_vec();
 
-    // encrypt ht_queries
+    PlaintextQuery {
+        ht_queries,
+        occupied_segments: occupied_segments(psi_params, &hash_tables),
+        hash_tables,
+        hash_table_stack: stack,
+        original_items: HashMap::new(),
+    }
+}
+
+/// Encrypts `ht_queries`, raised to source powers, with `key` along with encryptions of zero of the query. Encryption
+/// stage of `construct_query`, see `PlaintextQuery`. `key` is either client's `SecretKey` or its `PublicKey`, thus
+/// queries can be encrypted by services that never hold the secret key.
+pub fn encrypt_query<K: QueryEncryptionKey, R: RngCore + CryptoRng>(
+    ht_queries: &[HashTableQuery],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    key: &K,
+    rng: &mut R,
+) -> Query {
     let ht_queries_cts = ht_queries
         .iter()
         .map(|htq| {
             htq.process_inner_box_queries_with_source_powers_and_encrypt(
                 &psi_params.source_powers,
                 &evaluator,
-                &sk,
+                key,
                 rng,
             )
         })
         .collect_vec();
 
-    QueryState {
-        query: Query(ht_queries_cts),
-        hash_tables: hash_tables,
-        hash_table_stack: stack,
+    let zero_cts = (0..psi_params.zero_cts_count())
+        .map(|_| key.encrypt_values(evaluator, &[], rng))
+        .collect_vec();
+
+    Query(ht_queries_cts, zero_cts, vec![], None)
+}
+
+pub fn construct_query<R: RngCore + CryptoRng>(
+    query_set: &[U256],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    rng: &mut R,
+) -> QueryState {
+    let plaintext_query = construct_plaintext_query(query_set, psi_params, rng);
+    let query = encrypt_query(
+        plaintext_query.hash_table_queries(),
+        psi_params,
+        evaluator,
+        sk,
+        rng,
+    );
+    plaintext_query.into_query_state(query)
+}
+
+/// Returns max. no. of items placed in hash tables of a single query. Cuckoo hashing fails for more items as tables
+/// fill up, thus tables are only filled up to `QUERY_LOAD_FACTOR` of their capacity.
+pub fn max_query_items(psi_params: &PsiParams) -> usize {
+    let capacity = psi_params.no_of_hash_tables as usize * *psi_params.ht_size.deref() as usize;
+    (capacity as f64 * QUERY_LOAD_FACTOR) as usize
+}
+
+/// Fraction of hash table rows filled by `construct_queries`
+pub const QUERY_LOAD_FACTOR: f64 = 0.8;
+
+/// Constructs as many queries as required to query all items in `query_set`. `query_set` is split into chunks of at
+/// most `max_query_items` items, and items that end up on hash table stack of a query are carried over to the next
+/// query. Thus, unlike `construct_query`, every item is queried: items on `QueryState::hash_table_stack` of a query are
+/// placed in a later query.
+pub fn construct_queries<R: RngCore + CryptoRng>(
+    query_set: &[U256],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    rng: &mut R,
+) -> Vec<QueryState> {
+    split_query_set(query_set, psi_params, |indices| {
+        let items = indices.iter().map(|i| query_set[*i]).collect_vec();
+        construct_query(&items, psi_params, evaluator, sk, rng)
+    })
+}
+
+/// Same as `construct_queries` but constructs each query with `construct_oprf_query`
+pub fn construct_oprf_queries<R: RngCore + CryptoRng>(
+    query_set: &[U256],
+    oprf_outputs: &[U256],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    rng: &mut R,
+) -> Vec<QueryState> {
+    assert_eq!(query_set.len(), oprf_outputs.len());
+
+    split_query_set(oprf_outputs, psi_params, |indices| {
+        let items = indices.iter().map(|i| query_set[*i]).collect_vec();
+        let outputs = indices.iter().map(|i| oprf_outputs[*i]).collect_vec();
+        construct_oprf_query(&items, &outputs, psi_params, evaluator, sk, rng)
+    })
+}
+
+/// Calls `construct` with indices of items in `hashed_items` placed in each query until all items are placed. Items are
+/// identified by value inserted in hash tables, ie `hashed_items`.
+fn split_query_set<F: FnMut(&[usize]) -> QueryState>(
+    hashed_items: &[U256],
+    psi_params: &PsiParams,
+    mut construct: F,
+) -> Vec<QueryState> {
+    let max_items = max_query_items(psi_params).max(1);
+
+    let mut query_states = vec![];
+    // items that could not be placed in previous query
+    let mut carried: Vec<usize> = vec![];
+    let mut next = 0;
+    while next < hashed_items.len() || !carried.is_empty() {
+        let take = max_items
+            .saturating_sub(carried.len())
+            .min(hashed_items.len() - next);
+        let indices = carried.drain(..).chain(next..next + take).collect_vec();
+        next += take;
+
+        let query_state = construct(&indices);
+        // occupied rows never become empty during cuckoo insertion, thus every query places at least one item and
+        // fewer items are carried than were queried
+        if !query_state.hash_table_stack().is_empty() {
+            let positions: HashMap<U256, usize> =
+                indices.iter().map(|i| (hashed_items[*i], *i)).collect();
+            carried = query_state
+                .hash_table_stack()
+                .iter()
+                .map(|entry| positions[entry.entry_value()])
+                .collect();
+            // duplicates of an item map to the same index
+            carried.sort_unstable();
+            carried.dedup();
+        }
+        debug!(
+            items = indices.len(),
+            carried = carried.len(),
+            "Constructed query"
+        );
+        query_states.push(query_state);
     }
+    query_states
+}
+
+/// Constructs query using OPRF outputs of items in `query_set`. `oprf_outputs[i]` must be OPRF output of `query_set[i]`
+/// obtained with `oprf_finalize`.
+pub fn construct_oprf_query<R: RngCore + CryptoRng>(
+    query_set: &[U256],
+    oprf_outputs: &[U256],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    rng: &mut R,
+) -> QueryState {
+    assert_eq!(query_set.len(), oprf_outputs.len());
+
+    let mut query_state = construct_query(oprf_outputs, psi_params, evaluator, sk, rng);
+    query_state.original_items = izip!(oprf_outputs.iter(), query_set.iter())
+        .map(|(output, item)| (*output, *item))
+        .collect();
+    query_state
+}
+
+/// Response ciphertexts with less than these many bits of noise budget left are reported by `process_query_response`.
+/// Noise of a ciphertext without budget overflows, thus it decrypts to wrong labels.
+pub const MIN_NOISE_BUDGET_BITS: u64 = 4;
+
+/// Noise budget left in ciphertexts of a response, measured with client's secret key
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NoiseBudget {
+    /// Lowest budget, in bits, across all ciphertexts
+    pub min_bits: u64,
+    /// No. of ciphertexts with less than `MIN_NOISE_BUDGET_BITS` bits of budget
+    pub low: usize,
+    pub ciphertexts: usize,
+}
+
+/// Measures noise budget of every ciphertext of `query_response`. Takes about as long as decrypting the response. Returns
+/// `None` if response has no ciphertexts.
+pub fn measure_response_noise(
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    query_response: &QueryResponse,
+) -> Option<NoiseBudget> {
+    let budgets = query_response
+        .0
+        .iter()
+        .flat_map(|ht_response| ht_response.0.iter().flatten())
+        .map(|ct| evaluator.measure_noise(sk, ct))
+        .collect_vec();
+    Some(NoiseBudget {
+        min_bits: *budgets.iter().min()?,
+        low: budgets
+            .iter()
+            .filter(|bits| **bits < MIN_NOISE_BUDGET_BITS)
+            .count(),
+        ciphertexts: budgets.len(),
+    })
 }
 
+/// Decrypts `query_response` and returns potential labels of each item in `hash_table`. Only responses of segments
+/// that have an item in `hash_table` are decrypted. Use `StreamingResponseDecryptor` to decrypt segments of a streamed
+/// response as they arrive.
+///
+/// When debug logs are enabled (for ex. `RUST_LOG=psi=debug`), noise budget of response ciphertexts is measured as well
+/// and a warning is logged if any ciphertext is left with less than `MIN_NOISE_BUDGET_BITS` bits, since labels
+/// decrypted from it may be silently wrong.
 pub fn process_query_response(
     psi_params: &PsiParams,
     hash_table: &[HashMap<u32, HashTableEntry>],
@@ -381,10 +910,27 @@ pub fn process_query_response(
         psi_params.no_of_hash_tables as usize
     );
 
-    println!("Ht responses {}", query_response.0.len());
-
-    let ht_response = &query_response.0[0];
-    println!("Ht responses segments {}", ht_response.0.len());
+    debug!(
+        hash_tables = query_response.0.len(),
+        segments = query_response.0[0].0.len(),
+        "Processing query response"
+    );
+    if tracing::enabled!(Level::DEBUG) {
+        if let Some(noise) = measure_response_noise(evaluator, sk, query_response) {
+            debug!(
+                min_bits = noise.min_bits,
+                "Measured noise budget of response"
+            );
+            if noise.low > 0 {
+                warn!(
+                    low = noise.low,
+                    ciphertexts = noise.ciphertexts,
+                    min_bits = noise.min_bits,
+                    "Response ciphertexts are close to noise overflow and may decrypt to wrong labels"
+                );
+            }
+        }
+    }
 
     // Process HashTableQueryResponse corresponding to each hash table
     let potential_response_labels = query_response
@@ -405,14 +951,159 @@ pub fn process_query_response(
     potential_response_labels
 }
 
+/// Processes responses of all shards of a sharded server (see `ShardCoordinator`) to the same query. Each item is stored
+/// in a single shard, thus potential labels of an item are potential labels of the item in response of every shard.
+pub fn process_sharded_query_response(
+    psi_params: &PsiParams,
+    hash_table: &[HashMap<u32, HashTableEntry>],
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    query_responses: &[QueryResponse],
+) -> Vec<PotentialResponseLabels> {
+    merge_potential_response_labels(
+        query_responses
+            .iter()
+            .map(|query_response| {
+                process_query_response(psi_params, hash_table, evaluator, sk, query_response)
+            })
+            .collect_vec(),
+    )
+}
+
+/// Merges potential labels of each item from responses of all shards. Responses to the same query list items in the same
+/// order.
+pub(crate) fn merge_potential_response_labels(
+    shard_responses: Vec<Vec<PotentialResponseLabels>>,
+) -> Vec<PotentialResponseLabels> {
+    let mut shard_responses = shard_responses.into_iter();
+    let mut merged = shard_responses.next().unwrap_or_default();
+    for shard_response in shard_responses {
+        assert_eq!(shard_response.len(), merged.len());
+        izip!(merged.iter_mut(), shard_response).for_each(|(merged, response)| {
+            assert_eq!(merged.item, response.item);
+            merged.labels.extend(response.labels);
+        });
+    }
+    merged
+}
+
+/// Potential labels of a single item merged across all responses that list the item, see `dedup_response_labels`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResponseLabel {
+    pub(crate) item: U256,
+    pub(crate) labels: Vec<Label>,
+}
+
+impl ResponseLabel {
+    pub fn item(&self) -> &U256 {
+        &self.item
+    }
+
+    /// Distinct potential labels of item. Atmost one if label checksums are enabled.
+    pub fn labels(&self) -> &[Label] {
+        &self.labels
+    }
+
+    /// Returns label of item if it has exactly one potential label
+    pub fn label(&self) -> Option<&Label> {
+        match self.labels.as_slice() {
+            [label] => Some(label),
+            _ => None,
+        }
+    }
+}
+
+/// Merges `potential_response_labels` that list the same item, for ex. an item processed with more than one hash table
+/// or query, into a single `ResponseLabel` with union of their labels. Items are returned in order of first occurrence.
+///
+/// With label checksums enabled (see `PsiParams::with_label_checksum_bytes`) labels are already verified against
+/// checksum of the item, thus items only have more than one distinct label on checksum collisions. These are resolved
+/// to the label reported most often, ties going to the label reported first.
+pub fn dedup_response_labels(
+    psi_params: &PsiParams,
+    potential_response_labels: Vec<PotentialResponseLabels>,
+) -> Vec<ResponseLabel> {
+    let mut positions = HashMap::<U256, usize>::new();
+    // distinct labels of each item, along with no. of times each was reported
+    let mut merged: Vec<(U256, Vec<(Label, usize)>)> = vec![];
+    for response in potential_response_labels {
+        let position = *positions.entry(response.item).or_insert_with(|| {
+            merged.push((response.item, vec![]));
+            merged.len() - 1
+        });
+        let candidates = &mut merged[position].1;
+        for label in response.labels {
+            match candidates
+                .iter_mut()
+                .find(|(candidate, _)| *candidate == label)
+            {
+                Some((_, count)) => *count += 1,
+                None => candidates.push((label, 1)),
+            }
+        }
+    }
+
+    let resolve =
+        psi_params.mode == PsiMode::Labeled && psi_params.psi_pt.label_checksum_bytes != 0;
+    merged
+        .into_iter()
+        .map(|(item, candidates)| {
+            let labels = if resolve {
+                // `max_by_key` returns the last of equal maxima
+                candidates
+                    .into_iter()
+                    .rev()
+                    .max_by_key(|(_, count)| *count)
+                    .map(|(label, _)| label)
+                    .into_iter()
+                    .collect_vec()
+            } else {
+                candidates.into_iter().map(|(label, _)| label).collect_vec()
+            };
+            ResponseLabel { item, labels }
+        })
+        .collect_vec()
+}
+
+/// Returns items at intersection from query response of server in `PsiMode::Unlabeled`. Membership polynomial
+/// evaluates to 0 at all chunks of an item if the item exists in server's set, thus an item is at intersection if
+/// response of any InnerBox at its row is 0.
+pub fn process_unlabeled_query_response(
+    psi_params: &PsiParams,
+    hash_table: &[HashMap<u32, HashTableEntry>],
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    query_response: &QueryResponse,
+) -> Vec<U256> {
+    assert_eq!(psi_params.mode, PsiMode::Unlabeled);
+
+    process_query_response(psi_params, hash_table, evaluator, sk, query_response)
+        .into_iter()
+        .filter(|response| {
+            response
+                .labels
+                .iter()
+                .any(|label| label.as_bytes().iter().all(|b| *b == 0))
+        })
+        .map(|response| response.item)
+        .collect_vec()
+}
+
 #[cfg(test)]
 mod tests {
     use rand::{distributions::Uniform, thread_rng};
 
     use crate::{
-        random_u256,
-        serialize::{deserialize_query, serialize_query},
+        gen_random_item_labels, generate_evaluation_key, random_u256,
+        serialize::{
+            decompress, deserialize_query, deserialize_query_response, deserialize_query_state,
+            serialize_query, serialize_query_compressed, serialize_query_response,
+            serialize_query_state, serialize_segment_response, size_of_seeded_ciphertext,
+            IncrementalQueryResponse, SerializedQueryResponse,
+        },
         utils::gen_bfv_params,
+        ItemLabel, PsiError, PublicKey, QueryMetadata, SegmentResponse, SegmentStageTimes, Server,
+        CIRCUIT_PRIVACY_ZERO_CTS,
     };
 
     use super::*;
@@ -434,6 +1125,100 @@ mod tests {
         let query_response = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
     }
 
+    #[test]
+    fn construct_plaintext_query_and_encrypt_query_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+
+        let bfv_params = gen_bfv_params(&psi_params);
+        let evaluator = Evaluator::new(bfv_params);
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+
+        let query_set = (0..100).map(|_| random_u256(&mut rng)).collect_vec();
+        let plaintext_query = construct_plaintext_query(&query_set, &psi_params, &mut rng);
+
+        // hash table queries cross to the process holding secret key in serialized form
+        let ht_queries: Vec<HashTableQuery> = bincode::deserialize(
+            &bincode::serialize(plaintext_query.hash_table_queries()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(ht_queries, plaintext_query.hash_table_queries());
+        let query = encrypt_query(&ht_queries, &psi_params, &evaluator, &sk, &mut rng);
+        assert_eq!(query.1.len(), psi_params.zero_cts_count());
+
+        // each ciphertext decrypts to data of its InnerBoxQuery raised to source power
+        let layout = QueryLayout::new(&psi_params);
+        for (ht_query, ht_query_cts) in izip!(ht_queries.iter(), query.0.iter()) {
+            assert_eq!(ht_query_cts.0.len(), layout.query_cts_per_hash_table());
+            for (ib_query, cts) in izip!(
+                ht_query.inner_box_queries(),
+                ht_query_cts.0.chunks_exact(psi_params.source_powers.len())
+            ) {
+                let expected = calculate_source_powers(
+                    ib_query.data(),
+                    &psi_params.source_powers,
+                    evaluator.params().plaintext_modulus as u32,
+                );
+                for (ct, expected) in izip!(cts, expected) {
+                    let values = Vec::<u32>::try_decoding_with_parameters(
+                        &evaluator.decrypt(&sk, ct),
+                        evaluator.params(),
+                        Encoding::default(),
+                    );
+                    assert_eq!(values[..expected.len()], expected[..]);
+                }
+            }
+        }
+
+        let query_state = plaintext_query.into_query_state(query);
+        let placed = query_state
+            .hash_tables()
+            .iter()
+            .map(|ht| ht.len())
+            .sum::<usize>();
+        assert_eq!(
+            placed + query_state.hash_table_stack().len(),
+            query_set.len()
+        );
+    }
+
+    #[test]
+    fn construct_queries_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+
+        let bfv_params = gen_bfv_params(&psi_params);
+        let evaluator = Evaluator::new(bfv_params);
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+
+        let max_items = max_query_items(&psi_params);
+        let query_set = (0..(2 * max_items + 100))
+            .map(|_| random_u256(&mut rng))
+            .collect_vec();
+
+        let query_states = construct_queries(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        assert!(query_states.len() >= 3);
+
+        // every item is placed in hash tables of exactly one query
+        let mut placed = query_states
+            .iter()
+            .flat_map(|query_state| {
+                query_state
+                    .hash_tables()
+                    .iter()
+                    .flat_map(|ht| ht.values().map(|entry| *entry.entry_value()))
+            })
+            .collect_vec();
+        assert!(query_states
+            .iter()
+            .all(|qs| { qs.hash_tables().iter().map(|ht| ht.len()).sum::<usize>() <= max_items }));
+        placed.sort();
+        let mut expected = query_set.clone();
+        expected.sort();
+        assert_eq!(placed, expected);
+        assert!(query_states.last().unwrap().hash_table_stack().is_empty());
+    }
+
     #[test]
     fn serialize_and_deserialize_query_works() {
         let mut rng = thread_rng();
@@ -454,8 +1239,450 @@ mod tests {
         let query_bytes = serialize_query(query_state.query(), evaluator.params());
 
         // query back
-        let query_back = deserialize_query(&query_bytes, &psi_params, &evaluator);
+        let query_back = deserialize_query(&query_bytes, &psi_params, &evaluator).unwrap();
 
         assert_eq!(&query_back, query_state.query());
+
+        // truncated query is rejected instead of panicking
+        assert!(matches!(
+            deserialize_query(
+                &query_bytes[..query_bytes.len() - 1],
+                &psi_params,
+                &evaluator
+            ),
+            Err(PsiError::Serialization(_))
+        ));
+
+        // query without its last ciphertext
+        let mut short_query = query_state.query().clone();
+        short_query.0.last_mut().unwrap().0.pop();
+        assert!(matches!(
+            deserialize_query(
+                &serialize_query(&short_query, evaluator.params()),
+                &psi_params,
+                &evaluator
+            ),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+
+        // ciphertexts of different sizes are framed by their length prefix
+        let pk = PublicKey::new(&evaluator, &sk, &mut rng);
+        let mut mixed_query = query_state.query().clone();
+        mixed_query.0[0].0[0] = pk.encrypt_values(&evaluator, &[], &mut rng);
+        let mixed_query_bytes = serialize_query(&mixed_query, evaluator.params());
+        assert_ne!(mixed_query_bytes.len(), query_bytes.len());
+        assert_eq!(
+            deserialize_query(&mixed_query_bytes, &psi_params, &evaluator).unwrap(),
+            mixed_query
+        );
+
+        // compressed query decompresses to the same bytes
+        let compressed = serialize_query_compressed(query_state.query(), evaluator.params());
+        assert_eq!(
+            decompress(&compressed, query_bytes.len()).unwrap(),
+            query_bytes
+        );
+        assert!(matches!(
+            decompress(&compressed, query_bytes.len() - 1),
+            Err(PsiError::Serialization(_))
+        ));
+
+        // encryptions of zero are serialized after query ciphertexts
+        let psi_params = psi_params.with_circuit_privacy();
+        let query_state = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        assert_eq!(query_state.query().1.len(), CIRCUIT_PRIVACY_ZERO_CTS);
+        let query_bytes = serialize_query(query_state.query(), evaluator.params());
+        let query_back = deserialize_query(&query_bytes, &psi_params, &evaluator).unwrap();
+        assert_eq!(&query_back, query_state.query());
+    }
+
+    #[test]
+    fn streaming_decryption_matches_process_query_response() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(100, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let query_set = [*item_labels[0].item(), random_u256(&mut rng)];
+        let query_state = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        let query_response = server.query(query_state.query(), &ek).unwrap();
+        let expected = process_query_response(
+            &psi_params,
+            query_state.hash_tables(),
+            &evaluator,
+            &sk,
+            &query_response,
+        );
+
+        // segments arrive in any order and only occupied ones are decrypted
+        let mut decryptor = StreamingResponseDecryptor::new(&psi_params, &query_state);
+        for (big_box, ht_response) in query_response.0.iter().enumerate().rev() {
+            for (segment, cts) in ht_response.0.iter().enumerate() {
+                decryptor
+                    .add_segment(big_box, segment, cts, &evaluator, &sk)
+                    .unwrap();
+            }
+        }
+        assert!(decryptor
+            .add_segment(0, 0, &query_response.0[0].0[0], &evaluator, &sk)
+            .is_err());
+        assert_eq!(
+            decryptor.decrypted_segments(),
+            query_state
+                .occupied_segments()
+                .iter()
+                .flatten()
+                .filter(|o| **o)
+                .count()
+        );
+        assert!(decryptor.decrypted_segments() <= 2 * psi_params.no_of_hash_tables as usize);
+        let potential_labels = decryptor.finish().unwrap();
+
+        assert_eq!(potential_labels.len(), expected.len());
+        izip!(potential_labels.iter(), expected.iter()).for_each(|(labels, expected)| {
+            assert_eq!(labels.item(), expected.item());
+            assert_eq!(labels.labels(), expected.labels());
+        });
+        assert!(potential_labels
+            .iter()
+            .any(|labels| labels.item() == item_labels[0].item()
+                && labels.labels().contains(item_labels[0].label())));
+    }
+
+    #[test]
+    fn serialize_and_deserialize_query_state_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+
+        let query_set = (0..100).map(|_| random_u256(&mut rng)).collect_vec();
+        let mut query_state = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        query_state
+            .hash_table_stack
+            .push(HashTableEntry::with_hash_index(random_u256(&mut rng), 2));
+        query_state
+            .original_items
+            .insert(random_u256(&mut rng), random_u256(&mut rng));
+
+        let bytes = serialize_query_state(&query_state, &psi_params, evaluator.params());
+        let state_back = deserialize_query_state(&bytes, &psi_params, &evaluator).unwrap();
+
+        let entries = |entries: &mut dyn Iterator<Item = (u32, &HashTableEntry)>| {
+            entries
+                .map(|(row, e)| (row, *e.entry_value(), e.hash_index()))
+                .sorted()
+                .collect_vec()
+        };
+        assert_eq!(state_back.query(), query_state.query());
+        izip!(state_back.hash_tables(), query_state.hash_tables()).for_each(|(back, ht)| {
+            assert_eq!(
+                entries(&mut back.iter().map(|(row, e)| (*row, e))),
+                entries(&mut ht.iter().map(|(row, e)| (*row, e)))
+            );
+        });
+        assert_eq!(
+            entries(&mut state_back.hash_table_stack().iter().map(|e| (0, e))),
+            entries(&mut query_state.hash_table_stack().iter().map(|e| (0, e)))
+        );
+        assert_eq!(state_back.original_items, query_state.original_items);
+
+        // state is bound to params
+        assert!(matches!(
+            deserialize_query_state(
+                &bytes,
+                &psi_params
+                    .clone()
+                    .with_hash_seed(psi_params.hash_seed() + 1),
+                &evaluator
+            ),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+        assert!(matches!(
+            deserialize_query_state(&bytes[..bytes.len() - 1], &psi_params, &evaluator),
+            Err(PsiError::Serialization(_))
+        ));
+        assert!(matches!(
+            deserialize_query_state(&bytes[1..], &psi_params, &evaluator),
+            Err(PsiError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn deserialize_rejects_corrupted_ciphertexts() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+
+        let query_set = (0..10).map(|_| random_u256(&mut rng)).collect_vec();
+        let query_state = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        let query_bytes = serialize_query(query_state.query(), evaluator.params());
+        let size_single_ct = size_of_seeded_ciphertext(&evaluator);
+
+        // ciphertext that isn't a valid proto
+        let mut corrupted = query_bytes.clone();
+        corrupted[..size_single_ct].fill(0xff);
+        assert!(matches!(
+            deserialize_query(&corrupted, &psi_params, &evaluator),
+            Err(PsiError::Serialization(_))
+        ));
+
+        // randomly corrupted ciphertexts are either rejected or decoded, but never panic
+        for _ in 0..20 {
+            let mut corrupted = query_bytes.clone();
+            for _ in 0..8 {
+                let index = rng.gen_range(0..corrupted.len());
+                corrupted[index] = rng.gen();
+            }
+            let _ = deserialize_query(&corrupted, &psi_params, &evaluator);
+        }
+
+        // response with single ciphertext for each segment
+        let segments_per_hash_table = QueryLayout::new(&psi_params).segments_per_hash_table();
+        let mut response_ct = evaluator.encrypt(
+            &sk,
+            &evaluator.plaintext_encode(&[], Encoding::default()),
+            &mut rng,
+        );
+        evaluator
+            .ciphertext_change_representation(&mut response_ct, bfv::Representation::Evaluation);
+        evaluator.mul_plaintext_assign(
+            &mut response_ct,
+            &evaluator.plaintext_encode(
+                &[1],
+                Encoding::simd(0, bfv::PolyCache::Mul(bfv::PolyType::Q)),
+            ),
+        );
+        evaluator
+            .ciphertext_change_representation(&mut response_ct, bfv::Representation::Coefficient);
+        evaluator.mod_down_level(&mut response_ct, psi_params.response_level());
+        let query_response = QueryResponse(
+            (0..psi_params.no_of_hash_tables)
+                .map(|_| {
+                    HashTableQueryResponse(vec![vec![response_ct.clone()]; segments_per_hash_table])
+                })
+                .collect(),
+        );
+        let response_bytes = bincode::serialize(&serialize_query_response(
+            &query_response,
+            evaluator.params(),
+        ))
+        .unwrap();
+        // ciphertext bytes follow their u64 length prefix
+        let cts_len = u64::from_le_bytes(response_bytes[..8].try_into().unwrap()) as usize;
+        for _ in 0..20 {
+            let mut corrupted = response_bytes.clone();
+            for _ in 0..8 {
+                let index = rng.gen_range(8..8 + cts_len);
+                corrupted[index] = rng.gen();
+            }
+            let serialized: SerializedQueryResponse = bincode::deserialize(&corrupted).unwrap();
+            let _ = deserialize_query_response(&serialized, &psi_params, &evaluator);
+        }
+    }
+
+    #[test]
+    fn merge_potential_response_labels_works() {
+        let mut rng = thread_rng();
+        let items = (0..3).map(|_| random_u256(&mut rng)).collect_vec();
+        let shard_response = |shard: u8| {
+            items
+                .iter()
+                .map(|item| PotentialResponseLabels {
+                    item: *item,
+                    labels: vec![Label::new(vec![shard])],
+                })
+                .collect_vec()
+        };
+
+        let merged = merge_potential_response_labels(vec![shard_response(0), shard_response(1)]);
+        assert_eq!(merged.len(), 3);
+        izip!(merged.iter(), items.iter()).for_each(|(response, item)| {
+            assert_eq!(response.item(), item);
+            assert_eq!(
+                response.labels(),
+                &[Label::new(vec![0]), Label::new(vec![1])]
+            );
+        });
+        assert!(merge_potential_response_labels(vec![]).is_empty());
+    }
+
+    #[test]
+    fn dedup_response_labels_works() {
+        let mut rng = thread_rng();
+        let items = (0..3).map(|_| random_u256(&mut rng)).collect_vec();
+        let response = |item: usize, labels: &[u8]| PotentialResponseLabels {
+            item: items[item],
+            labels: labels.iter().map(|l| Label::new(vec![*l])).collect_vec(),
+        };
+        let responses = vec![
+            response(0, &[1]),
+            response(1, &[]),
+            response(0, &[1, 2]),
+            response(2, &[3]),
+            response(0, &[2]),
+            response(1, &[4]),
+        ];
+
+        // labels are unioned without checksums
+        let deduped = dedup_response_labels(&PsiParams::default(), responses.clone());
+        assert_eq!(
+            deduped.iter().map(|r| *r.item()).collect_vec(),
+            items.clone()
+        );
+        assert_eq!(
+            deduped[0].labels(),
+            &[Label::new(vec![1]), Label::new(vec![2])]
+        );
+        assert_eq!(deduped[0].label(), None);
+        assert_eq!(deduped[1].label(), Some(&Label::new(vec![4])));
+
+        // and resolved to the label reported first among most reported ones with checksums
+        let psi_params = PsiParams::default().with_label_checksum_bytes(4);
+        let deduped = dedup_response_labels(&psi_params, responses);
+        assert_eq!(deduped[0].label(), Some(&Label::new(vec![1])));
+        assert_eq!(deduped[2].label(), Some(&Label::new(vec![3])));
+        assert!(dedup_response_labels(&psi_params, vec![]).is_empty());
+    }
+
+    #[test]
+    fn verify_label_checksums_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default()
+            .with_label_bytes(20)
+            .with_label_checksum_bytes(4)
+            .with_hash_seed(3);
+        assert_eq!(psi_params.psi_pt.encoded_label_bytes(), 24);
+
+        let item = random_u256(&mut rng);
+        let item_label = ItemLabel::new(item, Label::new(vec![9u8; 16]));
+        let encoded = item_label.with_label_checksum(&psi_params).unwrap();
+        assert_eq!(encoded.label().as_bytes().len(), 24);
+
+        // garbage labels of other InnerBoxes at the same row are dropped
+        let garbage = Label::new((0..24).map(|_| rng.gen()).collect_vec());
+        let labels = verify_label_checksums(
+            &psi_params,
+            &item,
+            vec![garbage.clone(), encoded.label().clone()],
+        );
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0].trim_padding(), *item_label.label());
+
+        // checksum is bound to item and seed
+        assert!(verify_label_checksums(
+            &psi_params,
+            &random_u256(&mut rng),
+            vec![encoded.label().clone()]
+        )
+        .is_empty());
+        assert!(verify_label_checksums(
+            &psi_params.clone().with_hash_seed(4),
+            &item,
+            vec![encoded.label().clone()]
+        )
+        .is_empty());
+
+        // disabled checksums keep all labels
+        assert!(ItemLabel::new(item, Label::new(vec![1]))
+            .with_label_checksum(&PsiParams::default())
+            .is_none());
+        assert_eq!(
+            verify_label_checksums(&PsiParams::default(), &item, vec![garbage.clone()]),
+            vec![garbage]
+        );
+    }
+
+    #[test]
+    fn incremental_query_response_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let segments_per_hash_table = QueryLayout::new(&psi_params).segments_per_hash_table();
+
+        // unseeded ciphertexts at last level, just like server's response ciphertexts
+        let pt = evaluator.plaintext_encode(
+            &[1],
+            Encoding::simd(0, bfv::PolyCache::Mul(bfv::PolyType::Q)),
+        );
+        let response_ct = || {
+            let mut ct = evaluator.encrypt(
+                &sk,
+                &evaluator.plaintext_encode(&[], Encoding::default()),
+                &mut thread_rng(),
+            );
+            evaluator.ciphertext_change_representation(&mut ct, bfv::Representation::Evaluation);
+            evaluator.mul_plaintext_assign(&mut ct, &pt);
+            evaluator.ciphertext_change_representation(&mut ct, bfv::Representation::Coefficient);
+            evaluator.mod_down_level(&mut ct, psi_params.response_level());
+            ct
+        };
+
+        let segment_responses = (0..psi_params.no_of_hash_tables as usize)
+            .flat_map(|big_box| (0..segments_per_hash_table).map(move |segment| (big_box, segment)))
+            .map(|(big_box, segment)| SegmentResponse {
+                big_box,
+                segment,
+                cts: (0..(segment % 3)).map(|_| response_ct()).collect(),
+                times: SegmentStageTimes {
+                    powers: std::time::Duration::from_millis(1),
+                    evaluation: std::time::Duration::from_millis(big_box as u64 + 1),
+                },
+            })
+            .collect_vec();
+
+        // segments arrive in any order
+        let mut incremental_response = IncrementalQueryResponse::new(&psi_params, &evaluator);
+        for segment_response in segment_responses.iter().rev() {
+            assert!(!incremental_response.is_complete());
+            let bytes = serialize_segment_response(segment_response, evaluator.params());
+            incremental_response
+                .add_segment(&bytes, &evaluator)
+                .unwrap();
+        }
+        assert!(incremental_response
+            .add_segment(
+                &serialize_segment_response(&segment_responses[0], evaluator.params()),
+                &evaluator
+            )
+            .is_err());
+
+        let query_response = incremental_response.finish().unwrap();
+        segment_responses.iter().for_each(|segment_response| {
+            assert_eq!(
+                query_response.0[segment_response.big_box].0[segment_response.segment],
+                segment_response.cts
+            );
+        });
+
+        // metadata sums times of segments of each BigBox and is sent along with response
+        let mut metadata = QueryMetadata::new(psi_params.no_of_hash_tables as usize, 7);
+        segment_responses.iter().for_each(|segment_response| {
+            metadata.record_segment(segment_response.big_box, &segment_response.times)
+        });
+        assert_eq!(
+            metadata.powers_time,
+            std::time::Duration::from_millis(segment_responses.len() as u64)
+        );
+        assert_eq!(
+            metadata.evaluation_times[1],
+            std::time::Duration::from_millis(2 * segments_per_hash_table as u64)
+        );
+
+        let serialized = serialize_query_response(&query_response, evaluator.params());
+        assert!(serialized.metadata().is_none());
+        let bytes = bincode::serialize(&serialized.with_metadata(metadata.clone())).unwrap();
+        let serialized_back: SerializedQueryResponse = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(serialized_back.metadata(), Some(&metadata));
+        assert_eq!(
+            deserialize_query_response(&serialized_back, &psi_params, &evaluator).unwrap(),
+            query_response
+        );
     }
 }
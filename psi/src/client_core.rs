@@ -0,0 +1,269 @@
+use bfv::{EvaluationKey, EvaluationKeyProto, Evaluator, SecretKey};
+use crypto_bigint::U256;
+use prost::Message;
+use rand_chacha::ChaCha20Rng;
+use traits::TryFromWithParameters;
+
+use crate::{
+    construct_oprf_queries, construct_oprf_query, construct_queries, construct_query,
+    deserialize_query_response, gen_bfv_params, generate_evaluation_key, oprf_blind, oprf_finalize,
+    process_sharded_query_response, seeded_rng, serialize_query, serialize_query_batch,
+    serialize_query_batch_compressed, serialize_query_compressed, ClientId, DbStats, Frame,
+    MessageType, OprfBlindState, OprfResponse, PotentialResponseLabels, ProtocolError, PsiError,
+    PsiParams, QueryBatch, QueryMetadata, QueryResponse, QueryState, SerializedQueryResponse,
+    CAPABILITY_DB_STATS, CAPABILITY_METADATA, CAPABILITY_ZSTD, MAX_FRAME_BYTES,
+};
+
+/// Transport independent part of a client: keys, capabilities agreed with server and randomness, along with
+/// construction of request payloads and processing of responses. `PsiClient`, `BlockingPsiClient` and
+/// `HttpPsiClient` only differ in how they exchange these payloads with server.
+pub(crate) struct ClientCore {
+    pub(crate) psi_params: PsiParams,
+    pub(crate) evaluator: Evaluator,
+    pub(crate) sk: SecretKey,
+    pub(crate) client_id: ClientId,
+    /// Generated from `sk` on first upload
+    ek: Option<EvaluationKey>,
+    /// Queries and responses are zstd compressed. Enabled with `CAPABILITY_ZSTD`.
+    pub(crate) compression: bool,
+    /// Server returns `QueryMetadata` with responses. Enabled with `CAPABILITY_METADATA`.
+    pub(crate) metadata: bool,
+    /// Randomness of evaluation key, OPRF blinding and query encryption. Seeded from OS entropy unless set with
+    /// `with_seed`.
+    rng: ChaCha20Rng,
+}
+
+impl ClientCore {
+    pub(crate) fn new(psi_params: &PsiParams, sk: SecretKey, client_id: ClientId) -> ClientCore {
+        ClientCore {
+            psi_params: psi_params.clone(),
+            evaluator: Evaluator::new(gen_bfv_params(psi_params)),
+            sk,
+            client_id,
+            ek: None,
+            compression: false,
+            metadata: false,
+            rng: seeded_rng(None),
+        }
+    }
+
+    /// See `PsiClient::with_seed`
+    pub(crate) fn with_seed(mut self, seed: u64) -> ClientCore {
+        self.rng = seeded_rng(Some(seed));
+        self
+    }
+
+    /// Capability flags currently enabled
+    pub(crate) fn capabilities(&self) -> u8 {
+        let mut flags = 0;
+        if self.compression {
+            flags |= CAPABILITY_ZSTD;
+        }
+        if self.metadata {
+            flags |= CAPABILITY_METADATA;
+        }
+        flags
+    }
+
+    /// Enables flags server agreed to in payload of its `MessageType::Hello`. Returns `DbStats` if server sent them.
+    pub(crate) fn process_hello(&mut self, payload: &[u8]) -> Result<Option<DbStats>, PsiError> {
+        let malformed =
+            || PsiError::Protocol(ProtocolError::InvalidMessage("Malformed hello".to_string()));
+        let (flags, db_stats) = payload.split_first().ok_or_else(malformed)?;
+        let db_stats = if flags & CAPABILITY_DB_STATS != 0 {
+            Some(bincode::deserialize(db_stats)?)
+        } else if db_stats.is_empty() {
+            None
+        } else {
+            return Err(malformed());
+        };
+        self.compression = flags & CAPABILITY_ZSTD != 0;
+        self.metadata = flags & CAPABILITY_METADATA != 0;
+        Ok(db_stats)
+    }
+
+    /// Payload of `MessageType::EvaluationKey`. Evaluation key is generated on first call.
+    pub(crate) fn evaluation_key_payload(&mut self) -> Vec<u8> {
+        if self.ek.is_none() {
+            self.ek = Some(generate_evaluation_key(
+                &self.psi_params,
+                &self.evaluator,
+                &self.sk,
+                &mut self.rng,
+            ));
+        }
+        self.generated_evaluation_key_payload().unwrap()
+    }
+
+    /// Same as `evaluation_key_payload` but returns `None` if evaluation key isn't generated yet
+    pub(crate) fn generated_evaluation_key_payload(&self) -> Option<Vec<u8>> {
+        let ek_bytes = EvaluationKeyProto::try_from_with_parameters(
+            self.ek.as_ref()?,
+            self.evaluator.params(),
+        )
+        .encode_to_vec();
+        Some(self.client_id.prefix(&ek_bytes))
+    }
+
+    /// Blinds `items` for OPRF round with server. Returns blinding state along with payload of
+    /// `MessageType::OprfRequest`.
+    pub(crate) fn oprf_request(&mut self, items: &[U256]) -> (OprfBlindState, Vec<u8>) {
+        let (blind_state, request) = oprf_blind(items, &mut self.rng);
+        (blind_state, request.to_bytes())
+    }
+
+    /// Constructs query for `items`. `oprf_outputs` of `items` are required if `PsiParams::oprf` is enabled.
+    pub(crate) fn construct_query(
+        &mut self,
+        items: &[U256],
+        oprf_outputs: Option<&[U256]>,
+    ) -> QueryState {
+        match oprf_outputs {
+            Some(oprf_outputs) => construct_oprf_query(
+                items,
+                oprf_outputs,
+                &self.psi_params,
+                &self.evaluator,
+                &self.sk,
+                &mut self.rng,
+            ),
+            None => construct_query(
+                items,
+                &self.psi_params,
+                &self.evaluator,
+                &self.sk,
+                &mut self.rng,
+            ),
+        }
+    }
+
+    /// Same as `construct_query` but constructs as many queries as required to query all `items` (see
+    /// `construct_queries`)
+    pub(crate) fn construct_queries(
+        &mut self,
+        items: &[U256],
+        oprf_outputs: Option<&[U256]>,
+    ) -> Vec<QueryState> {
+        match oprf_outputs {
+            Some(oprf_outputs) => construct_oprf_queries(
+                items,
+                oprf_outputs,
+                &self.psi_params,
+                &self.evaluator,
+                &self.sk,
+                &mut self.rng,
+            ),
+            None => construct_queries(
+                items,
+                &self.psi_params,
+                &self.evaluator,
+                &self.sk,
+                &mut self.rng,
+            ),
+        }
+    }
+
+    /// Payload of `MessageType::Query` or `MessageType::StreamedQuery` carrying query in `query_state`
+    pub(crate) fn query_payload(&self, query_state: &QueryState) -> Vec<u8> {
+        let serialized_query = if self.compression {
+            serialize_query_compressed(query_state.query(), self.evaluator.params())
+        } else {
+            serialize_query(query_state.query(), self.evaluator.params())
+        };
+        self.client_id.prefix(&serialized_query)
+    }
+
+    /// Payload of `MessageType::QueryBatch` carrying queries in `query_states`
+    pub(crate) fn query_batch_payload(&self, query_states: &[QueryState]) -> Vec<u8> {
+        let batch = QueryBatch::from_query_states(query_states);
+        let serialized_batch = if self.compression {
+            serialize_query_batch_compressed(&batch, self.evaluator.params())
+        } else {
+            serialize_query_batch(&batch, self.evaluator.params())
+        };
+        self.client_id.prefix(&serialized_batch)
+    }
+
+    /// Deserializes `QueryResponse` payload along with `QueryMetadata`, if any
+    pub(crate) fn deserialize_response(
+        &self,
+        response_bytes: &[u8],
+    ) -> Result<(QueryResponse, Option<QueryMetadata>), PsiError> {
+        let serialized_query_response = if self.compression {
+            SerializedQueryResponse::from_compressed(response_bytes, MAX_FRAME_BYTES as usize)?
+        } else {
+            bincode::deserialize(response_bytes)?
+        };
+        let query_response = deserialize_query_response(
+            &serialized_query_response,
+            &self.psi_params,
+            &self.evaluator,
+        )?;
+        Ok((
+            query_response,
+            serialized_query_response.metadata().cloned(),
+        ))
+    }
+
+    /// Decrypts and merges `query_responses` of each shard, or the only response of an unsharded server, and maps
+    /// items back to original items
+    pub(crate) fn process_response(
+        &self,
+        query_state: &QueryState,
+        query_responses: &[QueryResponse],
+    ) -> Vec<PotentialResponseLabels> {
+        let mut responses = process_sharded_query_response(
+            &self.psi_params,
+            query_state.hash_tables(),
+            &self.evaluator,
+            &self.sk,
+            query_responses,
+        );
+        responses
+            .iter_mut()
+            .for_each(|response| response.item = *query_state.original_item(&response.item));
+        responses
+    }
+
+    /// Processes `MessageType::QueryResponse` or `MessageType::ShardedQueryResponse` to query in `query_state`.
+    /// Returns `QueryMetadata` of the response along with potential labels. Metadata of sharded response is that of
+    /// the first shard.
+    pub(crate) fn process_query_response_frame(
+        &self,
+        query_state: &QueryState,
+        response: Frame,
+    ) -> Result<(Vec<PotentialResponseLabels>, Option<QueryMetadata>), PsiError> {
+        let payloads = if response.message_type == MessageType::ShardedQueryResponse {
+            bincode::deserialize(&response.into_payload(MessageType::ShardedQueryResponse)?)?
+        } else {
+            vec![response.into_payload(MessageType::QueryResponse)?]
+        };
+        let mut query_responses = Vec::with_capacity(payloads.len());
+        let mut first_metadata = None;
+        for (index, payload) in payloads.iter().enumerate() {
+            let (query_response, metadata) = self.deserialize_response(payload)?;
+            if index == 0 {
+                first_metadata = metadata;
+            }
+            query_responses.push(query_response);
+        }
+
+        Ok((
+            self.process_response(query_state, &query_responses),
+            first_metadata,
+        ))
+    }
+}
+
+/// OPRF outputs of items blinded in `blind_state` (see `ClientCore::oprf_request`), from payload of server's
+/// `MessageType::OprfResponse`
+pub(crate) fn oprf_outputs(
+    blind_state: &OprfBlindState,
+    response_bytes: &[u8],
+) -> Result<Vec<U256>, PsiError> {
+    OprfResponse::from_bytes(response_bytes)
+        .and_then(|response| oprf_finalize(blind_state, &response))
+        .ok_or(PsiError::Protocol(ProtocolError::InvalidMessage(
+            "Malformed OPRF response".to_string(),
+        )))
+}
@@ -0,0 +1,325 @@
+use crate::Node;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Strategy used to pick DAG for calculating target powers from source powers
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum DagStrategy {
+    /// Minimises depth, even if it requires calculating intermediate powers that aren't target powers
+    MinDepth,
+    /// Minimises no. of ciphertext multiplications, ie calculates intermediate powers only if a target power can't be
+    /// calculated otherwise. Same as `construct_dag` when all target powers can be calculated from each other.
+    MinMultiplications,
+    /// Minimises product of multiplications and depth + 1. Every additional level requires an additional modulus, which
+    /// makes every multiplication more expensive.
+    Balanced,
+}
+
+/// Cost of calculating target powers with a DAG
+#[derive(Clone, Debug, PartialEq)]
+pub struct DagCost {
+    pub depth: usize,
+    pub multiplications: usize,
+    /// Every ciphertext product is relinearized immediately
+    pub relinearizations: usize,
+    /// Estimated noise growth in bits of the deepest power
+    pub noise_bits: f64,
+}
+
+impl DagCost {
+    /// Relinearization is the only key switch required to calculate powers
+    pub fn key_switches(&self) -> usize {
+        self.relinearizations
+    }
+}
+
+/// DAG to calculate target powers from source powers along with its cost. May contain intermediate powers that aren't
+/// target powers.
+#[derive(Clone)]
+pub struct PowersDag {
+    nodes: HashMap<usize, Node>,
+    cost: DagCost,
+}
+
+impl PowersDag {
+    pub fn nodes(&self) -> &HashMap<usize, Node> {
+        &self.nodes
+    }
+
+    pub fn into_nodes(self) -> HashMap<usize, Node> {
+        self.nodes
+    }
+
+    pub fn cost(&self) -> &DagCost {
+        &self.cost
+    }
+}
+
+/// Returns DAG picked by `strategy` among Pareto optimal DAGs (see `pareto_dags`). Returns None if target powers can't
+/// be calculated from source powers.
+pub fn optimize_dag(
+    source_powers: &[usize],
+    target_powers: &[usize],
+    strategy: DagStrategy,
+    noise_bits_per_multiplication: f64,
+) -> Option<PowersDag> {
+    let dags = pareto_dags(source_powers, target_powers, noise_bits_per_multiplication);
+    // `dags` are sorted by increasing depth and decreasing multiplications
+    match strategy {
+        DagStrategy::MinDepth => dags.into_iter().next(),
+        DagStrategy::MinMultiplications => dags.into_iter().last(),
+        DagStrategy::Balanced => dags.into_iter().min_by_key(|dag| {
+            (
+                dag.cost.multiplications * (dag.cost.depth + 1),
+                dag.cost.depth,
+            )
+        }),
+    }
+}
+
+/// Returns DAGs to calculate `target_powers` from `source_powers` that are Pareto optimal in depth and no. of
+/// multiplications, sorted by increasing depth. Each product increases noise by `noise_bits_per_multiplication`.
+///
+/// DAG with least multiplications calculates only target powers. For every lower depth, DAG is constructed by
+/// calculating target powers in increasing order and, whenever a target power can't be calculated within the depth,
+/// searching for fewest intermediate powers that bring it within the depth.
+pub fn pareto_dags(
+    source_powers: &[usize],
+    target_powers: &[usize],
+    noise_bits_per_multiplication: f64,
+) -> Vec<PowersDag> {
+    // depth can't exceed no. of multiplications, thus the bound never restricts
+    let unbounded = target_powers.len() + 1;
+    let min_mults = match dag_with_max_depth(source_powers, target_powers, unbounded) {
+        Some(dag) => dag,
+        None => return vec![],
+    };
+
+    let mut dags = (1..min_mults.1)
+        .filter_map(|max_depth| dag_with_max_depth(source_powers, target_powers, max_depth))
+        .collect::<Vec<_>>();
+    dags.push(min_mults);
+
+    let mut dags = dags
+        .into_iter()
+        .map(|(nodes, depth)| {
+            let cost = dag_cost(&nodes, depth, noise_bits_per_multiplication);
+            PowersDag { nodes, cost }
+        })
+        .collect::<Vec<_>>();
+    dags.sort_by_key(|dag| (dag.cost.depth, dag.cost.multiplications));
+
+    // drop DAGs dominated by a DAG with lower depth
+    let mut pareto: Vec<PowersDag> = vec![];
+    for dag in dags {
+        if pareto
+            .last()
+            .map_or(true, |p| dag.cost.multiplications < p.cost.multiplications)
+        {
+            pareto.push(dag);
+        }
+    }
+    pareto
+}
+
+fn dag_cost(
+    nodes: &HashMap<usize, Node>,
+    depth: usize,
+    noise_bits_per_multiplication: f64,
+) -> DagCost {
+    let multiplications = nodes.values().filter(|node| node.depth != 0).count();
+    DagCost {
+        depth,
+        multiplications,
+        relinearizations: multiplications,
+        noise_bits: depth as f64 * noise_bits_per_multiplication,
+    }
+}
+
+/// Intermediate powers to calculate, in order, as (power, s1, s2)
+type Plan = Vec<(usize, usize, usize)>;
+
+/// Constructs DAG with depth <= `max_depth` that calculates target powers in increasing order and, if required,
+/// intermediate powers. Returns None if no such DAG is found.
+fn dag_with_max_depth(
+    source_powers: &[usize],
+    target_powers: &[usize],
+    max_depth: usize,
+) -> Option<(HashMap<usize, Node>, usize)> {
+    let mut nodes = HashMap::<usize, Node>::new();
+    source_powers.iter().for_each(|source| {
+        nodes.insert(
+            *source,
+            Node {
+                target: *source,
+                depth: 0,
+                s1: 0,
+                s2: 0,
+            },
+        );
+    });
+
+    let mut target_powers = target_powers.to_vec();
+    target_powers.sort();
+    let mut dag_depth = 0;
+    for target in target_powers.iter() {
+        let plan = plan_power(*target, max_depth, &nodes, &mut HashMap::new())?;
+        for (power, s1, s2) in plan {
+            if nodes.contains_key(&power) {
+                continue;
+            }
+            let depth = std::cmp::max(nodes[&s1].depth, nodes[&s2].depth) + 1;
+            nodes.insert(
+                power,
+                Node {
+                    target: power,
+                    depth,
+                    s1,
+                    s2,
+                },
+            );
+        }
+        dag_depth = dag_depth.max(nodes[target].depth);
+    }
+
+    Some((nodes, dag_depth))
+}
+
+/// Returns fewest new powers required to calculate `power` within `max_depth` given powers in `nodes`. Among
+/// direct products of existing powers, picks the one with least depth.
+fn plan_power(
+    power: usize,
+    max_depth: usize,
+    nodes: &HashMap<usize, Node>,
+    memo: &mut HashMap<(usize, usize), Option<Plan>>,
+) -> Option<Plan> {
+    if let Some(node) = nodes.get(&power) {
+        return (node.depth <= max_depth).then(Vec::new);
+    }
+    if max_depth == 0 {
+        return None;
+    }
+    if let Some(plan) = memo.get(&(power, max_depth)) {
+        return plan.clone();
+    }
+
+    // factors available within `max_depth - 1`, in increasing order
+    let mut available = nodes
+        .values()
+        .filter(|node| node.depth < max_depth && node.target < power)
+        .map(|node| (node.target, node.depth))
+        .collect::<Vec<_>>();
+    available.sort();
+
+    // product of existing powers
+    let direct = available
+        .iter()
+        .filter_map(|(s1, depth_s1)| {
+            let depth_s2 = nodes.get(&(power - s1))?.depth;
+            (depth_s2 < max_depth).then(|| (std::cmp::max(*depth_s1, depth_s2), *s1))
+        })
+        .min();
+    if let Some((_, s1)) = direct {
+        let plan = vec![(power, s1, power - s1)];
+        memo.insert((power, max_depth), Some(plan.clone()));
+        return Some(plan);
+    }
+
+    // product of an existing power and a new power
+    let mut best: Option<Plan> = None;
+    for (s1, _) in available.iter() {
+        if let Some(mut plan) = plan_power(power - s1, max_depth - 1, nodes, memo) {
+            if best.as_ref().map_or(true, |b| plan.len() + 1 < b.len()) {
+                plan.push((power, *s1, power - s1));
+                best = Some(plan);
+            }
+        }
+    }
+
+    // product of two new powers
+    if best.is_none() && power > 1 {
+        let s1 = power / 2;
+        let s2 = power - s1;
+        if let (Some(mut plan), Some(plan_s2)) = (
+            plan_power(s1, max_depth - 1, nodes, memo),
+            plan_power(s2, max_depth - 1, nodes, memo),
+        ) {
+            plan_s2.into_iter().for_each(|step| {
+                if !plan.contains(&step) {
+                    plan.push(step);
+                }
+            });
+            plan.push((power, s1, s2));
+            best = Some(plan);
+        }
+    }
+
+    memo.insert((power, max_depth), best.clone());
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{construct_dag, uncomputable_power, PsiParams};
+
+    use super::*;
+
+    #[test]
+    fn pareto_dags_works() {
+        let psi_params = PsiParams::default();
+        let target_powers = psi_params.ps_params.powers();
+
+        let dags = pareto_dags(&psi_params.source_powers, target_powers, 30.0);
+        assert!(!dags.is_empty());
+        for dag in dags.iter() {
+            assert!(uncomputable_power(dag.nodes(), target_powers).is_none());
+            // every power must be within DAG's depth
+            assert!(dag
+                .nodes()
+                .values()
+                .all(|node| node.depth <= dag.cost().depth));
+        }
+        for pair in dags.windows(2) {
+            assert!(pair[0].cost().depth < pair[1].cost().depth);
+            assert!(pair[0].cost().multiplications > pair[1].cost().multiplications);
+        }
+
+        // DAG with least multiplications calculates only target powers, same as `construct_dag`
+        let min_mults = optimize_dag(
+            &psi_params.source_powers,
+            target_powers,
+            DagStrategy::MinMultiplications,
+            30.0,
+        )
+        .unwrap();
+        let dag = construct_dag(&psi_params.source_powers, target_powers);
+        assert_eq!(min_mults.nodes().len(), dag.len());
+        assert_eq!(
+            min_mults.cost().depth,
+            dag.values().map(|node| node.depth).max().unwrap()
+        );
+
+        // intermediate power 4 reduces depth of 7 (ie 3 + 4 instead of 1 + 6)
+        let source_powers = [1];
+        let target_powers = [1, 2, 3, 6, 7];
+        let min_depth =
+            optimize_dag(&source_powers, &target_powers, DagStrategy::MinDepth, 30.0).unwrap();
+        assert_eq!(min_depth.cost().depth, 3);
+        assert_eq!(min_depth.cost().multiplications, 5);
+        assert!(min_depth.nodes().contains_key(&4));
+        assert!(uncomputable_power(min_depth.nodes(), &target_powers).is_none());
+
+        let min_mults = optimize_dag(
+            &source_powers,
+            &target_powers,
+            DagStrategy::MinMultiplications,
+            30.0,
+        )
+        .unwrap();
+        assert_eq!(min_mults.cost().depth, 4);
+        assert_eq!(min_mults.cost().multiplications, 4);
+        assert_eq!(min_mults.cost().noise_bits, 120.0);
+
+        assert!(optimize_dag(&[2], &[2, 3], DagStrategy::Balanced, 30.0).is_none());
+    }
+}
@@ -0,0 +1,88 @@
+use crate::{AuthError, InsertError, KeyStoreError, ProtocolError};
+
+/// Errors returned by public APIs of the crate. A malformed query or response returns an error instead of panicking.
+#[derive(Debug, PartialEq)]
+pub enum PsiError {
+    /// Bytes could not be decoded
+    Serialization(String),
+    /// Input does not match `PsiParams`. For ex, query with wrong no. of ciphertexts.
+    ParamsMismatch(String),
+    /// Query is rejected by `QueryValidator`
+    InvalidQuery(String),
+    /// `PsiParams` are inconsistent. For ex, more ciphertext slots than BFV degree.
+    InvalidParams(String),
+    /// Polynomial interpolation failed. For ex, due to repeated x values with different y values.
+    Interpolation(String),
+    Io(String),
+    Insert(InsertError),
+    KeyStore(KeyStoreError),
+    Protocol(ProtocolError),
+    Auth(AuthError),
+    /// TLS configuration is invalid. For ex, malformed certificate.
+    Tls(String),
+    /// Query was cancelled with `CancellationToken` before all segments were processed. For ex, due to timeout.
+    Cancelled(String),
+}
+
+impl std::fmt::Display for PsiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PsiError::Serialization(e) => write!(f, "Serialization error: {e}"),
+            PsiError::ParamsMismatch(e) => write!(f, "Parameter mismatch: {e}"),
+            PsiError::InvalidQuery(e) => write!(f, "Invalid query: {e}"),
+            PsiError::InvalidParams(e) => write!(f, "Invalid parameters: {e}"),
+            PsiError::Interpolation(e) => write!(f, "Interpolation failed: {e}"),
+            PsiError::Io(e) => write!(f, "I/O error: {e}"),
+            PsiError::Insert(e) => write!(f, "Insert failed: {e}"),
+            PsiError::KeyStore(e) => write!(f, "{e}"),
+            PsiError::Protocol(e) => write!(f, "{e}"),
+            PsiError::Auth(e) => write!(f, "{e}"),
+            PsiError::Tls(e) => write!(f, "TLS error: {e}"),
+            PsiError::Cancelled(e) => write!(f, "Query cancelled: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for PsiError {}
+
+impl From<std::io::Error> for PsiError {
+    fn from(value: std::io::Error) -> Self {
+        PsiError::Io(value.to_string())
+    }
+}
+
+impl From<bincode::Error> for PsiError {
+    fn from(value: bincode::Error) -> Self {
+        PsiError::Serialization(value.to_string())
+    }
+}
+
+impl From<prost::DecodeError> for PsiError {
+    fn from(value: prost::DecodeError) -> Self {
+        PsiError::Serialization(value.to_string())
+    }
+}
+
+impl From<InsertError> for PsiError {
+    fn from(value: InsertError) -> Self {
+        PsiError::Insert(value)
+    }
+}
+
+impl From<KeyStoreError> for PsiError {
+    fn from(value: KeyStoreError) -> Self {
+        PsiError::KeyStore(value)
+    }
+}
+
+impl From<ProtocolError> for PsiError {
+    fn from(value: ProtocolError) -> Self {
+        PsiError::Protocol(value)
+    }
+}
+
+impl From<AuthError> for PsiError {
+    fn from(value: AuthError) -> Self {
+        PsiError::Auth(value)
+    }
+}
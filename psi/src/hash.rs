@@ -1,18 +1,44 @@
 use crypto_bigint::{Encoding, U256};
 use itertools::Itertools;
 use rand::{distributions::Uniform, CryptoRng, Rng};
+use rayon::prelude::*;
 use ring::digest::{self, Digest};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use siphasher::sip::SipHasher13;
+use std::{collections::HashMap, hash::Hasher};
+
+use crate::PsiParams;
 
 fn sha256(item: &U256) -> Digest {
     digest::digest(&digest::SHA256, &item.to_le_bytes())
 }
 
-#[derive(Serialize, Deserialize)]
+/// Hash function `Cuckoo` derives table indices from. Server and client must use the same backend and seed (see
+/// `PsiParams::with_hash_backend` and `PsiParams::with_hash_seed`).
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub enum HashBackend {
+    /// SHA-256 of seed followed by item. Seed 0 hashes item alone, as earlier versions did.
+    #[default]
+    Sha256,
+    /// BLAKE3 keyed with seed. Faster than SHA-256 on bulk inserts of large sets, compare with
+    /// `cargo bench -p psi -- hash_backends`.
+    Blake3,
+    /// SipHash-1-3 keyed with seed. Fastest, but only a PRF under a secret seed.
+    SipHash,
+}
+
+/// Default max. no. of evictions while inserting a single entry into cuckoo hash tables. With 3 tables, random walk
+/// eviction reliably fills about 90% of rows.
+pub const DEFAULT_MAX_KICKS: u32 = 500;
+
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Cuckoo {
     no_of_tables: u8,
     table_size: u32,
+    backend: HashBackend,
+    seed: u64,
+    /// Max. no. of evictions while inserting a single entry before it is pushed onto stack
+    max_kicks: u32,
 }
 impl Cuckoo {
     pub fn new(no_of_tables: u8, table_size: u32) -> Cuckoo {
@@ -21,16 +47,71 @@ impl Cuckoo {
         Cuckoo {
             no_of_tables,
             table_size,
+            backend: HashBackend::default(),
+            seed: 0,
+            max_kicks: DEFAULT_MAX_KICKS,
+        }
+    }
+
+    /// Sets max. no. of evictions while inserting a single entry. More kicks place more entries in fuller tables at the
+    /// cost of slower insertion.
+    pub fn with_max_kicks(mut self, max_kicks: u32) -> Cuckoo {
+        self.max_kicks = max_kicks;
+        self
+    }
+
+    /// Derives indices with `backend` keyed with `seed`. Different seeds result in unrelated table layouts.
+    pub fn with_hasher(mut self, backend: HashBackend, seed: u64) -> Cuckoo {
+        self.backend = backend;
+        self.seed = seed;
+        self
+    }
+
+    /// Returns cuckoo hash functions server and client agree on with `psi_params`
+    pub fn for_params(psi_params: &PsiParams) -> Cuckoo {
+        Cuckoo::new(psi_params.no_of_hash_tables, *psi_params.ht_size)
+            .with_hasher(psi_params.hash_backend, psi_params.hash_seed)
+            .with_max_kicks(psi_params.cuckoo_max_kicks)
+    }
+
+    /// Returns 32 bytes hash of `data`, enough for 8 hash tables
+    fn digest(&self, data: &U256) -> [u8; 32] {
+        let bytes = data.to_le_bytes();
+        match self.backend {
+            HashBackend::Sha256 if self.seed == 0 => sha256(data).as_ref().try_into().unwrap(),
+            HashBackend::Sha256 => {
+                let mut context = digest::Context::new(&digest::SHA256);
+                context.update(&self.seed.to_le_bytes());
+                context.update(&bytes);
+                context.finish().as_ref().try_into().unwrap()
+            }
+            HashBackend::Blake3 => {
+                let mut key = [0u8; 32];
+                key[..8].copy_from_slice(&self.seed.to_le_bytes());
+                *blake3::keyed_hash(&key, &bytes).as_bytes()
+            }
+            HashBackend::SipHash => {
+                // each 64 bit output is keyed with seed and its position
+                let mut output = [0u8; 32];
+                output
+                    .chunks_exact_mut(8)
+                    .enumerate()
+                    .for_each(|(i, chunk)| {
+                        let mut hasher = SipHasher13::new_with_keys(self.seed, i as u64);
+                        hasher.write(&bytes);
+                        chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+                    });
+                output
+            }
         }
     }
 
     /// Hashes the data and return indices in each hash table
     pub fn table_indices(&self, data: &U256) -> Vec<u32> {
-        let digest = sha256(data);
+        let digest = self.digest(data);
 
         // We divide the digest in chunks of 32 bits and view each chunk as ouput from different hash functions
         let outputs = digest
-            .as_ref()
             .chunks_exact(4)
             .take(self.no_of_tables as usize)
             .map(|o| {
@@ -44,6 +125,19 @@ impl Cuckoo {
 
         outputs
     }
+
+    /// Returns `table_indices` of all `items`, hashed on all cores in chunks of equal size. Each item is hashed once
+    /// regardless of no. of tables, thus cost of bulk inserts is dominated by the backend.
+    pub fn table_indices_many<'a>(
+        &self,
+        items: impl IndexedParallelIterator<Item = &'a U256>,
+    ) -> Vec<Vec<u32>> {
+        let min_len = std::cmp::max(items.len() / rayon::current_num_threads(), 1);
+        items
+            .with_min_len(min_len)
+            .map(|item| self.table_indices(item))
+            .collect()
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -53,6 +147,11 @@ impl HashTableEntry {
         HashTableEntry(value, 0)
     }
 
+    /// Entry of `value` placed with hash function at `hash_index`
+    pub(crate) fn with_hash_index(value: U256, hash_index: u8) -> HashTableEntry {
+        HashTableEntry(value, hash_index)
+    }
+
     pub fn entry_value(&self) -> &U256 {
         &self.0
     }
@@ -66,47 +165,79 @@ impl HashTableEntry {
     }
 }
 
-pub fn construct_hash_tables(
+/// Inserts `input` into cuckoo hash tables with random walk eviction. An entry is placed in the first table whose row
+/// is empty. If rows in all tables are occupied, the entry evicts occupant of a random table, other than the one it was
+/// evicted from, and the evicted entry is inserted in turn. Entry that is still homeless after `Cuckoo::max_kicks`
+/// evictions is pushed onto the returned stack.
+///
+/// `HashTableEntry::hash_index` of each placed entry is the table it is placed in.
+pub fn construct_hash_tables<R: Rng>(
     input: &[HashTableEntry],
     cuckoo: &Cuckoo,
+    rng: &mut R,
 ) -> (Vec<HashMap<u32, HashTableEntry>>, Vec<HashTableEntry>) {
-    let mut hash_tables = vec![HashMap::new(); cuckoo.no_of_tables as usize];
-
-    let mut curr_index = 0;
-    let mut curr_element = Some(input[curr_index].clone());
-
+    let no_of_tables = cuckoo.no_of_tables as usize;
+    let mut hash_tables = vec![HashMap::new(); no_of_tables];
     let mut stack = vec![];
 
-    while curr_index < input.len() {
-        if curr_element.is_none() {
-            curr_element = Some(input[curr_index].clone());
-        }
-
-        let data = curr_element.clone().unwrap();
-        let indices = cuckoo.table_indices(data.entry_value());
-
-        let old_value = hash_tables[data.hash_index()].insert(indices[data.hash_index()], data);
-
-        if old_value.is_some() {
-            let mut v = old_value.unwrap();
-            v.increase_hash_index();
+    for entry in input {
+        let mut current = entry.clone();
+        // table `current` was evicted from
+        let mut evicted_from = None;
+        let mut kicks = 0;
+        loop {
+            let indices = cuckoo.table_indices(current.entry_value());
+            if let Some(table) =
+                (0..no_of_tables).find(|t| !hash_tables[*t].contains_key(&indices[*t]))
+            {
+                current.1 = table as u8;
+                hash_tables[table].insert(indices[table], current);
+                break;
+            }
 
-            if v.hash_index() == cuckoo.no_of_tables as usize {
-                stack.push(v);
-                curr_index += 1;
-                curr_element = None;
-            } else {
-                curr_element = Some(v);
+            if kicks == cuckoo.max_kicks {
+                stack.push(current);
+                break;
             }
-        } else {
-            curr_index += 1;
-            curr_element = None;
+            let table = loop {
+                let table = rng.gen_range(0..no_of_tables);
+                if no_of_tables == 1 || Some(table) != evicted_from {
+                    break table;
+                }
+            };
+            current.1 = table as u8;
+            current = hash_tables[table]
+                .insert(indices[table], current)
+                .expect("Row is occupied");
+            evicted_from = Some(table);
+            kicks += 1;
         }
     }
 
     (hash_tables, stack)
 }
 
+/// Inserts random entries into empty hash tables of `cuckoo` until first entry fails to be placed and returns fraction
+/// of rows filled by then, ie the load factor cuckoo hashing reliably achieves
+pub fn achievable_load_factor<R: Rng + CryptoRng>(cuckoo: &Cuckoo, rng: &mut R) -> f64 {
+    let capacity = cuckoo.no_of_tables as usize * cuckoo.table_size as usize;
+    let mut placed = vec![];
+    // tables are constructed from scratch for every attempt, thus entries are added in halving batches
+    let mut batch = capacity / 2;
+    while batch > 0 && placed.len() < capacity {
+        let mut entries = placed.clone();
+        entries.extend((0..batch).map(|_| HashTableEntry::new(random_u256(rng))));
+        let (_, stack) = construct_hash_tables(&entries, cuckoo, rng);
+        if stack.is_empty() {
+            placed = entries;
+            batch = batch.min(capacity - placed.len());
+        } else {
+            batch /= 2;
+        }
+    }
+    placed.len() as f64 / capacity as f64
+}
+
 pub fn random_u256<R: Rng + CryptoRng>(rng: &mut R) -> U256 {
     let mut bytes = [0u8; 32];
     rng.fill_bytes(&mut bytes);
@@ -140,7 +271,73 @@ mod tests {
             queue.push(HashTableEntry(data, 0));
         }
 
-        construct_hash_tables(&queue, &hasher);
+        construct_hash_tables(&queue, &hasher, &mut rng);
+    }
+
+    #[test]
+    fn random_walk_eviction_works() {
+        let mut rng = thread_rng();
+        let cuckoo = Cuckoo::new(3, 1024);
+        let entries = (0..2400)
+            .map(|_| HashTableEntry::new(random_u256(&mut rng)))
+            .collect_vec();
+
+        // 78% load
+        let (hash_tables, stack) = construct_hash_tables(&entries, &cuckoo, &mut rng);
+        assert!(stack.is_empty());
+        assert_eq!(hash_tables.iter().map(|ht| ht.len()).sum::<usize>(), 2400);
+        hash_tables.iter().enumerate().for_each(|(table, ht)| {
+            ht.iter().for_each(|(row, entry)| {
+                assert_eq!(entry.hash_index(), table);
+                assert_eq!(cuckoo.table_indices(entry.entry_value())[table], *row);
+            })
+        });
+
+        // without evictions entries are placed only if one of their rows is empty
+        let (_, stack) =
+            construct_hash_tables(&entries, &cuckoo.clone().with_max_kicks(0), &mut rng);
+        assert!(!stack.is_empty());
+
+        let load_factor = achievable_load_factor(&cuckoo, &mut rng);
+        assert!(load_factor > 0.8 && load_factor <= 1.0);
+    }
+
+    #[test]
+    fn seeded_table_indices_work() {
+        let mut rng = thread_rng();
+        let items = (0..100).map(|_| random_u256(&mut rng)).collect_vec();
+        let cuckoo = Cuckoo::new(3, 4096);
+
+        // unseeded SHA-256 layout is unchanged
+        items.iter().for_each(|item| {
+            let digest = sha256(item);
+            let expected = digest
+                .as_ref()
+                .chunks_exact(4)
+                .take(3)
+                .map(|c| u32::from_le_bytes(c.try_into().unwrap()) % 4096)
+                .collect_vec();
+            assert_eq!(cuckoo.table_indices(item), expected);
+        });
+
+        for backend in [
+            HashBackend::Sha256,
+            HashBackend::Blake3,
+            HashBackend::SipHash,
+        ] {
+            let seeded = Cuckoo::new(3, 4096).with_hasher(backend, 7);
+            let layout =
+                |cuckoo: &Cuckoo| items.iter().map(|i| cuckoo.table_indices(i)).collect_vec();
+            // deterministic
+            assert_eq!(layout(&seeded), layout(&seeded.clone()));
+            assert!(layout(&seeded).iter().flatten().all(|index| *index < 4096));
+            // different seeds result in different layouts
+            assert_ne!(
+                layout(&seeded),
+                layout(&seeded.clone().with_hasher(backend, 8))
+            );
+            assert_ne!(layout(&seeded), layout(&cuckoo));
+        }
     }
 
     #[test]
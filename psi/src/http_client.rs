@@ -0,0 +1,188 @@
+use bfv::SecretKey;
+use crypto_bigint::U256;
+use reqwest::{header::RETRY_AFTER, Method, RequestBuilder, Response, StatusCode};
+use std::time::Duration;
+
+use crate::{
+    BusyReason, ClientCore, ClientId, PotentialResponseLabels, ProtocolError, PsiError, PsiParams,
+    QueryState, HTTP_TENANT_HEADER,
+};
+
+/// Client of server's HTTP transport, for deployments where raw TCP is blocked by infrastructure. Requests carry the
+/// same payloads as frames sent by `PsiClient`, thus server caches client's evaluation key under `ClientId` and the
+/// key is uploaded only when server responds to a query with `428 Precondition Required`.
+///
+/// Compression, query metadata and OPRF aren't supported.
+pub struct HttpPsiClient {
+    http: reqwest::Client,
+    /// Server's URL without trailing slash, for ex. `http://127.0.0.1:6379`
+    base_url: String,
+    /// Compression is never enabled, since HTTP transport does not negotiate capabilities
+    core: ClientCore,
+    /// API token sent as bearer token with every request
+    token: Option<String>,
+    /// Tenant selected with `HTTP_TENANT_HEADER` on every request
+    tenant: Option<String>,
+}
+
+impl HttpPsiClient {
+    /// Creates client of server at `base_url`. `client_id` is same as in `PsiClient::connect`.
+    pub fn new(
+        base_url: &str,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> HttpPsiClient {
+        HttpPsiClient {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            core: ClientCore::new(psi_params, sk, client_id),
+            token: None,
+            tenant: None,
+        }
+    }
+
+    /// Trusts PEM encoded certificate of certificate authority that issued certificate of server's https URL, for ex.
+    /// of a reverse proxy terminating TLS in front of server
+    pub fn with_tls_ca(mut self, ca_pem: &[u8]) -> Result<HttpPsiClient, PsiError> {
+        self.http = reqwest::Certificate::from_pem(ca_pem)
+            .and_then(|ca| reqwest::Client::builder().add_root_certificate(ca).build())
+            .map_err(|e| PsiError::Tls(format!("Invalid CA certificate: {e}")))?;
+        Ok(self)
+    }
+
+    /// Authenticates every request with API `token`
+    pub fn with_token(mut self, token: &str) -> HttpPsiClient {
+        self.token = Some(token.to_string());
+        self
+    }
+
+    /// Queries dataset of tenant `tenant_id` instead of server's default tenant. `PsiParams` must match tenant's.
+    pub fn with_tenant(mut self, tenant_id: &str) -> HttpPsiClient {
+        self.tenant = Some(tenant_id.to_string());
+        self
+    }
+
+    /// Same as `PsiClient::with_seed`
+    pub fn with_seed(mut self, seed: u64) -> HttpPsiClient {
+        self.core = self.core.with_seed(seed);
+        self
+    }
+
+    pub fn psi_params(&self) -> &PsiParams {
+        &self.core.psi_params
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.core.client_id
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut request = self
+            .http
+            .request(method, format!("{}{path}", self.base_url));
+        if let Some(token) = &self.token {
+            request = request.bearer_auth(token);
+        }
+        if let Some(tenant) = &self.tenant {
+            request = request.header(HTTP_TENANT_HEADER, tenant);
+        }
+        request
+    }
+
+    /// Returns `PsiParams` of the selected tenant's server, for ex. to check that they match client's
+    pub async fn fetch_params(&self) -> Result<PsiParams, PsiError> {
+        let response = error_for_status(send(self.request(Method::GET, "/params")).await?).await?;
+        response
+            .json()
+            .await
+            .map_err(|e| PsiError::Serialization(format!("Malformed params: {e}")))
+    }
+
+    /// Same as `PsiClient::upload_keys`
+    pub async fn upload_keys(&mut self) -> Result<(), PsiError> {
+        let request = self
+            .request(Method::POST, "/keys")
+            .body(self.core.evaluation_key_payload());
+        error_for_status(send(request).await?).await?;
+        Ok(())
+    }
+
+    /// Same as `PsiClient::send_query`
+    pub async fn send_query(
+        &mut self,
+        query_state: &QueryState,
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let payload = self.core.query_payload(query_state);
+
+        let mut response = send(self.request(Method::POST, "/query").body(payload.clone())).await?;
+        if response.status() == StatusCode::PRECONDITION_REQUIRED {
+            self.upload_keys().await?;
+            response = send(self.request(Method::POST, "/query").body(payload)).await?;
+        }
+        let response_bytes = error_for_status(response)
+            .await?
+            .bytes()
+            .await
+            .map_err(http_error)?;
+        let (query_response, _) = self.core.deserialize_response(&response_bytes)?;
+        Ok(self.core.process_response(query_state, &[query_response]))
+    }
+
+    /// Same as `PsiClient::query`
+    pub async fn query(
+        &mut self,
+        items: &[U256],
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        if self.core.psi_params.oprf() {
+            return Err(PsiError::InvalidParams(
+                "OPRF isn't supported over HTTP transport".to_string(),
+            ));
+        }
+        let query_states = self.core.construct_queries(items, None);
+        let mut responses = vec![];
+        for query_state in &query_states {
+            responses.extend(self.send_query(query_state).await?);
+        }
+        Ok(responses)
+    }
+}
+
+fn http_error(e: reqwest::Error) -> PsiError {
+    PsiError::Protocol(ProtocolError::Io(e.to_string()))
+}
+
+async fn send(request: RequestBuilder) -> Result<Response, PsiError> {
+    request.send().await.map_err(http_error)
+}
+
+/// Returns `response` if it is successful. Otherwise returns error server responded with, which is
+/// `ProtocolError::Busy` if server rejected the request with `Retry-After`.
+async fn error_for_status(response: Response) -> Result<Response, PsiError> {
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok())
+        .map(Duration::from_secs);
+    let reason = match status {
+        StatusCode::TOO_MANY_REQUESTS => Some(BusyReason::RateLimited),
+        StatusCode::SERVICE_UNAVAILABLE => Some(BusyReason::Overloaded),
+        _ => None,
+    };
+    if let (Some(reason), Some(retry_after)) = (reason, retry_after) {
+        return Err(ProtocolError::Busy {
+            reason,
+            retry_after,
+        }
+        .into());
+    }
+    let message = response.text().await.unwrap_or_default();
+    Err(PsiError::Protocol(ProtocolError::Remote(format!(
+        "{status}: {message}"
+    ))))
+}
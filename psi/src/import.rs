@@ -0,0 +1,282 @@
+use crate::{ItemLabel, Label, PsiError};
+use base64::Engine;
+use crypto_bigint::{Encoding, U256};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::{
+    io::{BufRead, BufReader, Read},
+    str::FromStr,
+};
+
+/// Format of dataset read by `import_item_labels`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// Comma separated values with a header row naming the columns
+    Csv,
+    /// One JSON object per line
+    Jsonl,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ImportFormat::Csv),
+            "jsonl" => Ok(ImportFormat::Jsonl),
+            _ => Err(format!("Unknown format {s}, expected csv or jsonl")),
+        }
+    }
+}
+
+/// Encoding of item and label values in dataset
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValueEncoding {
+    /// Hex, optionally prefixed with `0x`
+    #[default]
+    Hex,
+    /// Standard base64 with padding
+    Base64,
+}
+
+impl FromStr for ValueEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(ValueEncoding::Hex),
+            "base64" => Ok(ValueEncoding::Base64),
+            _ => Err(format!("Unknown encoding {s}, expected hex or base64")),
+        }
+    }
+}
+
+impl ValueEncoding {
+    /// Encodes `bytes`. Hex is lowercase without `0x` prefix.
+    pub fn encode(&self, bytes: &[u8]) -> String {
+        match self {
+            ValueEncoding::Hex => hex::encode(bytes),
+            ValueEncoding::Base64 => base64::engine::general_purpose::STANDARD.encode(bytes),
+        }
+    }
+
+    fn decode(&self, value: &str) -> Result<Vec<u8>, String> {
+        let value = value.trim();
+        match self {
+            ValueEncoding::Hex => {
+                let value = value.strip_prefix("0x").unwrap_or(value);
+                hex::decode(value).map_err(|e| e.to_string())
+            }
+            ValueEncoding::Base64 => base64::engine::general_purpose::STANDARD
+                .decode(value)
+                .map_err(|e| e.to_string()),
+        }
+    }
+}
+
+/// Options of `import_item_labels`
+#[derive(Clone, Debug)]
+pub struct ImportOptions {
+    format: ImportFormat,
+    /// Name of CSV column or JSON field of items
+    item_col: String,
+    /// Name of CSV column or JSON field of labels. Labels are empty if not set, for ex. in `PsiMode::Unlabeled`.
+    label_col: Option<String>,
+    encoding: ValueEncoding,
+}
+
+impl ImportOptions {
+    pub fn new(format: ImportFormat, item_col: &str) -> ImportOptions {
+        ImportOptions {
+            format,
+            item_col: item_col.to_string(),
+            label_col: None,
+            encoding: ValueEncoding::default(),
+        }
+    }
+
+    pub fn with_label_col(mut self, label_col: &str) -> ImportOptions {
+        self.label_col = Some(label_col.to_string());
+        self
+    }
+
+    pub fn with_encoding(mut self, encoding: ValueEncoding) -> ImportOptions {
+        self.encoding = encoding;
+        self
+    }
+}
+
+/// Maps item of arbitrary length to `U256`. Items of at most 32 bytes are read as little endian integers, padded with
+/// zeros. Longer items are hashed with SHA-256.
+///
+/// Clients must map items in their set with the same function.
+pub fn item_from_bytes(bytes: &[u8]) -> U256 {
+    let mut item = [0u8; 32];
+    if bytes.len() <= 32 {
+        item[..bytes.len()].copy_from_slice(bytes);
+    } else {
+        item.copy_from_slice(&Sha256::digest(bytes));
+    }
+    U256::from_le_bytes(item)
+}
+
+/// Reads ItemLabels from dataset in `reader`. Items are mapped to `U256` with `item_from_bytes`. Labels are stored as
+/// decoded, thus labels longer than `PsiPlaintext::label_bytes` are rejected when they are inserted.
+pub fn import_item_labels<R: Read>(
+    reader: R,
+    options: &ImportOptions,
+) -> Result<Vec<ItemLabel>, PsiError> {
+    match options.format {
+        ImportFormat::Csv => import_csv(reader, options),
+        ImportFormat::Jsonl => import_jsonl(reader, options),
+    }
+}
+
+fn import_error(line: usize, reason: impl std::fmt::Display) -> PsiError {
+    PsiError::Serialization(format!("Failed to import line {line}: {reason}"))
+}
+
+/// Decodes item and label values of record at `line`
+fn decode_item_label(
+    item: &str,
+    label: Option<&str>,
+    line: usize,
+    options: &ImportOptions,
+) -> Result<ItemLabel, PsiError> {
+    let item = options
+        .encoding
+        .decode(item)
+        .map_err(|e| import_error(line, format!("Invalid item: {e}")))?;
+    let label = match label {
+        Some(label) => options
+            .encoding
+            .decode(label)
+            .map_err(|e| import_error(line, format!("Invalid label: {e}")))?,
+        None => vec![],
+    };
+    Ok(ItemLabel::new(item_from_bytes(&item), Label::new(label)))
+}
+
+fn import_csv<R: Read>(reader: R, options: &ImportOptions) -> Result<Vec<ItemLabel>, PsiError> {
+    let mut reader = csv::Reader::from_reader(reader);
+    let headers = reader.headers().map_err(|e| import_error(1, e))?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| import_error(1, format!("Missing column {name}")))
+    };
+    let item_index = column(&options.item_col)?;
+    let label_index = options.label_col.as_deref().map(column).transpose()?;
+
+    reader
+        .records()
+        .enumerate()
+        .map(|(index, record)| {
+            // header is line 1
+            let line = index + 2;
+            let record = record.map_err(|e| import_error(line, e))?;
+            let field = |i: usize| {
+                record
+                    .get(i)
+                    .ok_or_else(|| import_error(line, format!("Missing column {i}")))
+            };
+            let label = label_index.map(field).transpose()?;
+            decode_item_label(field(item_index)?, label, line, options)
+        })
+        .collect()
+}
+
+fn import_jsonl<R: Read>(reader: R, options: &ImportOptions) -> Result<Vec<ItemLabel>, PsiError> {
+    let mut item_labels = vec![];
+    for (index, line) in BufReader::new(reader).lines().enumerate() {
+        let line_no = index + 1;
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let record: Value = serde_json::from_str(&line).map_err(|e| import_error(line_no, e))?;
+        let field = |name: &str| {
+            record
+                .get(name)
+                .and_then(|v| v.as_str())
+                .ok_or_else(|| import_error(line_no, format!("Missing string field {name}")))
+        };
+        let label = options.label_col.as_deref().map(field).transpose()?;
+        item_labels.push(decode_item_label(
+            field(&options.item_col)?,
+            label,
+            line_no,
+            options,
+        )?);
+    }
+    Ok(item_labels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn import_item_labels_works() {
+        let long_item = [7u8; 40];
+        let expected = vec![
+            ItemLabel::new(U256::from_u8(0xab), Label::new(vec![1, 2])),
+            ItemLabel::new(item_from_bytes(&long_item), Label::new(vec![3])),
+        ];
+
+        let csv = format!(
+            "id,item,label\n1,0xab,0102\n2,{},03\n",
+            hex::encode(long_item)
+        );
+        let options = ImportOptions::new(ImportFormat::Csv, "item").with_label_col("label");
+        assert_eq!(
+            import_item_labels(csv.as_bytes(), &options).unwrap(),
+            expected
+        );
+
+        let b64 = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+        let jsonl = format!(
+            "{{\"item\": \"{}\", \"label\": \"{}\"}}\n\n{{\"item\": \"{}\", \"label\": \"{}\"}}\n",
+            b64(&[0xab]),
+            b64(&[1, 2]),
+            b64(&long_item),
+            b64(&[3])
+        );
+        let options = ImportOptions::new(ImportFormat::Jsonl, "item")
+            .with_label_col("label")
+            .with_encoding(ValueEncoding::Base64);
+        assert_eq!(
+            import_item_labels(jsonl.as_bytes(), &options).unwrap(),
+            expected
+        );
+
+        // labels are empty without label column
+        let options = ImportOptions::new(ImportFormat::Csv, "item");
+        assert!(import_item_labels(csv.as_bytes(), &options)
+            .unwrap()
+            .iter()
+            .all(|il| il.label().as_bytes().is_empty()));
+
+        // invalid values and missing columns are rejected
+        let options = ImportOptions::new(ImportFormat::Csv, "item").with_label_col("label");
+        assert!(matches!(
+            import_item_labels("item,label\nzz,01\n".as_bytes(), &options),
+            Err(PsiError::Serialization(_))
+        ));
+        assert!(matches!(
+            import_item_labels("item\n01\n".as_bytes(), &options),
+            Err(PsiError::Serialization(_))
+        ));
+    }
+
+    #[test]
+    fn encoded_values_decode() {
+        let bytes = [0u8, 0xab, 0xff, 7];
+        for encoding in [ValueEncoding::Hex, ValueEncoding::Base64] {
+            assert_eq!(encoding.decode(&encoding.encode(&bytes)).unwrap(), bytes);
+        }
+        assert_eq!(ValueEncoding::Hex.encode(&bytes), "00abff07");
+    }
+}
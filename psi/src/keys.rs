@@ -0,0 +1,427 @@
+use argon2::Argon2;
+use bfv::{
+    BfvParameters, Ciphertext, CiphertextProto, Encoding, Evaluator, Plaintext, PolyCache,
+    PolyType, Representation, SecretKey, SecretKeyProto,
+};
+use chacha20poly1305::{
+    aead::{Aead, KeyInit},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use prost::Message;
+use rand::{thread_rng, CryptoRng, RngCore};
+use std::{
+    io::{Read, Write},
+    path::PathBuf,
+};
+use traits::{TryEncodingWithParameters, TryFromWithParameters};
+use zeroize::Zeroizing;
+
+use crate::{prepare_zero_cts, rerandomize, serialize::decode_ciphertext, PsiError};
+
+/// Magic bytes at the start of every sealed secret key file
+const SEALED_KEY_MAGIC: &[u8; 4] = b"ULSK";
+const SALT_BYTES: usize = 16;
+const NONCE_BYTES: usize = 12;
+const HEADER_BYTES: usize = SEALED_KEY_MAGIC.len() + SALT_BYTES + NONCE_BYTES;
+
+#[derive(Debug, PartialEq)]
+pub enum KeyStoreError {
+    /// File is too short or does not start with the expected magic bytes
+    Malformed,
+    /// AEAD tag did not verify. Either the passphrase is wrong or the file was tampered with.
+    WrongPassphrase,
+    /// Key derivation from passphrase failed
+    KeyDerivation,
+    /// Reading or writing key file failed
+    Io(String),
+    /// Platform keyring returned an error
+    Keyring(String),
+}
+
+impl std::fmt::Display for KeyStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyStoreError::Malformed => write!(f, "Malformed sealed secret key file"),
+            KeyStoreError::WrongPassphrase => {
+                write!(f, "Wrong passphrase or corrupted secret key file")
+            }
+            KeyStoreError::KeyDerivation => write!(f, "Failed to derive key from passphrase"),
+            KeyStoreError::Io(e) => write!(f, "Key file I/O failed: {e}"),
+            KeyStoreError::Keyring(e) => write!(f, "Keyring error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyStoreError {}
+
+/// Serializes secret key into a buffer that is zeroized on drop
+pub fn serialize_secret_key(sk: &SecretKey, bfv_params: &BfvParameters) -> Zeroizing<Vec<u8>> {
+    let proto = SecretKeyProto::try_from_with_parameters(sk, bfv_params);
+    Zeroizing::new(proto.encode_to_vec())
+}
+
+pub fn deserialize_secret_key(bytes: &[u8], bfv_params: &BfvParameters) -> Option<SecretKey> {
+    let proto = SecretKeyProto::decode(bytes).ok()?;
+    Some(SecretKey::try_from_with_parameters(&proto, bfv_params))
+}
+
+/// Derives 256 bit AEAD key from `passphrase` and `salt` using argon2id
+fn derive_key(passphrase: &[u8], salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, KeyStoreError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase, salt, key.as_mut())
+        .map_err(|_| KeyStoreError::KeyDerivation)?;
+    Ok(key)
+}
+
+/// Encrypts secret key with key derived from `passphrase`.
+///
+/// Sealed file layout: magic (4 bytes) || argon2 salt (16 bytes) || nonce (12 bytes) || ChaCha20Poly1305 ciphertext
+pub fn seal_secret_key<R: RngCore + CryptoRng>(
+    sk: &SecretKey,
+    bfv_params: &BfvParameters,
+    passphrase: &[u8],
+    rng: &mut R,
+) -> Result<Vec<u8>, KeyStoreError> {
+    let mut salt = [0u8; SALT_BYTES];
+    let mut nonce = [0u8; NONCE_BYTES];
+    rng.fill_bytes(&mut salt);
+    rng.fill_bytes(&mut nonce);
+
+    let key = derive_key(passphrase, &salt)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+
+    let sk_bytes = serialize_secret_key(sk, bfv_params);
+    let ciphertext = cipher
+        .encrypt(Nonce::from_slice(&nonce), sk_bytes.as_slice())
+        .expect("Encryption of secret key failed");
+
+    let mut sealed = Vec::with_capacity(HEADER_BYTES + ciphertext.len());
+    sealed.extend_from_slice(SEALED_KEY_MAGIC);
+    sealed.extend_from_slice(&salt);
+    sealed.extend_from_slice(&nonce);
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypts secret key sealed with `seal_secret_key`. Intermediate plaintext buffers are zeroized on drop.
+pub fn unseal_secret_key(
+    sealed: &[u8],
+    bfv_params: &BfvParameters,
+    passphrase: &[u8],
+) -> Result<SecretKey, KeyStoreError> {
+    if sealed.len() < HEADER_BYTES || &sealed[..SEALED_KEY_MAGIC.len()] != SEALED_KEY_MAGIC {
+        return Err(KeyStoreError::Malformed);
+    }
+
+    let salt = &sealed[SEALED_KEY_MAGIC.len()..SEALED_KEY_MAGIC.len() + SALT_BYTES];
+    let nonce = &sealed[SEALED_KEY_MAGIC.len() + SALT_BYTES..HEADER_BYTES];
+
+    let key = derive_key(passphrase, salt)?;
+    let cipher = ChaCha20Poly1305::new(Key::from_slice(key.as_ref()));
+
+    let sk_bytes = Zeroizing::new(
+        cipher
+            .decrypt(Nonce::from_slice(nonce), &sealed[HEADER_BYTES..])
+            .map_err(|_| KeyStoreError::WrongPassphrase)?,
+    );
+
+    deserialize_secret_key(&sk_bytes, bfv_params).ok_or(KeyStoreError::Malformed)
+}
+
+/// Persistent storage for client secret key.
+///
+/// Evaluation key is public and is derived from secret key, thus stores only hold the secret key. Evaluation key can
+/// be regenerated using `generate_evaluation_key` whenever it needs to be uploaded.
+pub trait SecretKeyStore {
+    fn store(&self, sk: &SecretKey, bfv_params: &BfvParameters) -> Result<(), KeyStoreError>;
+
+    /// Returns `None` if no secret key has been stored yet
+    fn load(&self, bfv_params: &BfvParameters) -> Result<Option<SecretKey>, KeyStoreError>;
+}
+
+/// Stores secret key sealed with passphrase at `path`
+pub struct FileKeyStore {
+    path: PathBuf,
+    passphrase: Zeroizing<Vec<u8>>,
+}
+
+impl FileKeyStore {
+    pub fn new(path: PathBuf, passphrase: &[u8]) -> FileKeyStore {
+        FileKeyStore {
+            path,
+            passphrase: Zeroizing::new(passphrase.to_vec()),
+        }
+    }
+}
+
+impl SecretKeyStore for FileKeyStore {
+    fn store(&self, sk: &SecretKey, bfv_params: &BfvParameters) -> Result<(), KeyStoreError> {
+        let mut rng = thread_rng();
+        let sealed = seal_secret_key(sk, bfv_params, &self.passphrase, &mut rng)?;
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| KeyStoreError::Io(e.to_string()))?;
+        }
+        let mut file =
+            std::fs::File::create(&self.path).map_err(|e| KeyStoreError::Io(e.to_string()))?;
+        file.write_all(&sealed)
+            .map_err(|e| KeyStoreError::Io(e.to_string()))
+    }
+
+    fn load(&self, bfv_params: &BfvParameters) -> Result<Option<SecretKey>, KeyStoreError> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let mut file =
+            std::fs::File::open(&self.path).map_err(|e| KeyStoreError::Io(e.to_string()))?;
+        let mut sealed = Vec::new();
+        file.read_to_end(&mut sealed)
+            .map_err(|e| KeyStoreError::Io(e.to_string()))?;
+        unseal_secret_key(&sealed, bfv_params, &self.passphrase).map(Some)
+    }
+}
+
+/// Stores secret key in platform keyring (macOS Keychain, Windows Credential Manager, Secret Service on linux).
+/// Secret key is stored hex encoded under entry (`service`, `user`).
+#[cfg(feature = "keyring")]
+pub struct KeyringKeyStore {
+    entry: keyring::Entry,
+}
+
+#[cfg(feature = "keyring")]
+impl KeyringKeyStore {
+    pub fn new(service: &str, user: &str) -> Result<KeyringKeyStore, KeyStoreError> {
+        let entry = keyring::Entry::new(service, user)
+            .map_err(|e| KeyStoreError::Keyring(e.to_string()))?;
+        Ok(KeyringKeyStore { entry })
+    }
+}
+
+#[cfg(feature = "keyring")]
+impl SecretKeyStore for KeyringKeyStore {
+    fn store(&self, sk: &SecretKey, bfv_params: &BfvParameters) -> Result<(), KeyStoreError> {
+        let sk_bytes = serialize_secret_key(sk, bfv_params);
+        let sk_hex = Zeroizing::new(hex::encode(sk_bytes.as_slice()));
+        self.entry
+            .set_password(&sk_hex)
+            .map_err(|e| KeyStoreError::Keyring(e.to_string()))
+    }
+
+    fn load(&self, bfv_params: &BfvParameters) -> Result<Option<SecretKey>, KeyStoreError> {
+        let sk_hex = match self.entry.get_password() {
+            Ok(sk_hex) => Zeroizing::new(sk_hex),
+            Err(keyring::Error::NoEntry) => return Ok(None),
+            Err(e) => return Err(KeyStoreError::Keyring(e.to_string())),
+        };
+        let sk_bytes =
+            Zeroizing::new(hex::decode(sk_hex.as_str()).map_err(|_| KeyStoreError::Malformed)?);
+        deserialize_secret_key(&sk_bytes, bfv_params)
+            .ok_or(KeyStoreError::Malformed)
+            .map(Some)
+    }
+}
+
+/// No. of encryptions of zero in `PublicKey`
+pub const PUBLIC_KEY_ZERO_CTS: usize = 2;
+
+/// Key query plaintexts are encrypted with, either client's `SecretKey` or `PublicKey` derived from it
+pub trait QueryEncryptionKey {
+    /// Encrypts `values` encoded in SIMD slots at level 0. Returned ciphertext is in Coefficient representation.
+    fn encrypt_values<R: RngCore + CryptoRng>(
+        &self,
+        evaluator: &Evaluator,
+        values: &[u32],
+        rng: &mut R,
+    ) -> Ciphertext;
+}
+
+impl QueryEncryptionKey for SecretKey {
+    fn encrypt_values<R: RngCore + CryptoRng>(
+        &self,
+        evaluator: &Evaluator,
+        values: &[u32],
+        rng: &mut R,
+    ) -> Ciphertext {
+        let pt = Plaintext::try_encoding_with_parameters(
+            values,
+            evaluator.params(),
+            Encoding::default(),
+        );
+        evaluator.encrypt(self, &pt, rng)
+    }
+}
+
+/// Public key of client, which lets services encrypt queries without holding the secret key. Consists of an
+/// encryption of one and `PUBLIC_KEY_ZERO_CTS` encryptions of zero under client's secret key.
+///
+/// A plaintext is encrypted by multiplying encryption of one with it and adding random combination of encryptions of
+/// zero (see `rerandomize`). Unlike ciphertexts encrypted with secret key, these ciphertexts aren't seeded, thus are
+/// twice as large, and start with more noise. Check noise budget of responses with `measure_response_noise` before
+/// switching queries to public key encryption.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PublicKey {
+    /// Encryption of one, in Evaluation representation
+    one: Ciphertext,
+    /// Encryptions of zero, in Evaluation representation
+    zero_cts: Vec<Ciphertext>,
+}
+
+impl PublicKey {
+    pub fn new<R: RngCore + CryptoRng>(
+        evaluator: &Evaluator,
+        sk: &SecretKey,
+        rng: &mut R,
+    ) -> PublicKey {
+        let one = vec![1u32; evaluator.params().degree];
+        let cts = std::iter::once(one.as_slice())
+            .chain(std::iter::repeat([].as_slice()).take(PUBLIC_KEY_ZERO_CTS))
+            .map(|values| sk.encrypt_values(evaluator, values, rng))
+            .collect::<Vec<_>>();
+        PublicKey::from_cts(evaluator, &cts)
+    }
+
+    /// `cts` are encryption of one followed by encryptions of zero, in Coefficient representation
+    fn from_cts(evaluator: &Evaluator, cts: &[Ciphertext]) -> PublicKey {
+        let mut cts = prepare_zero_cts(evaluator, cts, 0);
+        let zero_cts = cts.split_off(1);
+        PublicKey {
+            one: cts.pop().unwrap(),
+            zero_cts,
+        }
+    }
+}
+
+impl QueryEncryptionKey for PublicKey {
+    fn encrypt_values<R: RngCore + CryptoRng>(
+        &self,
+        evaluator: &Evaluator,
+        values: &[u32],
+        rng: &mut R,
+    ) -> Ciphertext {
+        let pt = Plaintext::try_encoding_with_parameters(
+            values,
+            evaluator.params(),
+            Encoding::simd(0, PolyCache::Mul(PolyType::Q)),
+        );
+        let mut ct = evaluator.mul_plaintext(&self.one, &pt);
+        evaluator.ciphertext_change_representation(&mut ct, Representation::Coefficient);
+        rerandomize(evaluator, &mut ct, &self.zero_cts, rng);
+        ct
+    }
+}
+
+/// Serializes encryption of one followed by encryptions of zero of `pk`
+pub fn serialize_public_key(pk: &PublicKey, evaluator: &Evaluator) -> Vec<u8> {
+    let cts = std::iter::once(&pk.one)
+        .chain(pk.zero_cts.iter())
+        .map(|ct| {
+            let mut ct = ct.clone();
+            evaluator.ciphertext_change_representation(&mut ct, Representation::Coefficient);
+            CiphertextProto::try_from_with_parameters(&ct, evaluator.params()).encode_to_vec()
+        })
+        .collect::<Vec<_>>();
+    bincode::serialize(&cts).unwrap()
+}
+
+/// Returns `PsiError::Serialization` if `bytes` aren't public key serialized with `serialize_public_key`
+pub fn deserialize_public_key(bytes: &[u8], evaluator: &Evaluator) -> Result<PublicKey, PsiError> {
+    let cts_bytes: Vec<Vec<u8>> = bincode::deserialize(bytes)?;
+    if cts_bytes.len() != PUBLIC_KEY_ZERO_CTS + 1 {
+        return Err(PsiError::Serialization(format!(
+            "Expected {} ciphertexts in public key, found {}",
+            PUBLIC_KEY_ZERO_CTS + 1,
+            cts_bytes.len()
+        )));
+    }
+    let cts = cts_bytes
+        .iter()
+        .map(|ct_bytes| decode_ciphertext(ct_bytes, evaluator))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(PublicKey::from_cts(evaluator, &cts))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+    use traits::TryDecodingWithParameters;
+
+    use crate::utils::bfv_setup_test;
+
+    use super::*;
+
+    #[test]
+    fn seal_and_unseal_secret_key_works() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+
+        let sealed = seal_secret_key(&sk, evaluator.params(), b"passphrase", &mut rng).unwrap();
+
+        let sk_back = unseal_secret_key(&sealed, evaluator.params(), b"passphrase").unwrap();
+        assert_eq!(
+            serialize_secret_key(&sk, evaluator.params()),
+            serialize_secret_key(&sk_back, evaluator.params())
+        );
+
+        assert_eq!(
+            unseal_secret_key(&sealed, evaluator.params(), b"wrong passphrase").err(),
+            Some(KeyStoreError::WrongPassphrase)
+        );
+        assert_eq!(
+            unseal_secret_key(&sealed[..10], evaluator.params(), b"passphrase").err(),
+            Some(KeyStoreError::Malformed)
+        );
+    }
+
+    #[test]
+    fn file_key_store_works() {
+        let (evaluator, sk) = bfv_setup_test();
+
+        let mut path = std::env::temp_dir();
+        path.push(format!("ulpsi_file_key_store_{}.bin", std::process::id()));
+        let store = FileKeyStore::new(path.clone(), b"passphrase");
+
+        assert!(store.load(evaluator.params()).unwrap().is_none());
+
+        store.store(&sk, evaluator.params()).unwrap();
+        let sk_back = store.load(evaluator.params()).unwrap().unwrap();
+        assert_eq!(
+            serialize_secret_key(&sk, evaluator.params()),
+            serialize_secret_key(&sk_back, evaluator.params())
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn public_key_encryption_works() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+        let params = evaluator.params();
+
+        let pk = PublicKey::new(&evaluator, &sk, &mut rng);
+        let pk_back =
+            deserialize_public_key(&serialize_public_key(&pk, &evaluator), &evaluator).unwrap();
+        assert_eq!(pk_back, pk);
+
+        let m = (0..params.degree)
+            .map(|_| rng.gen_range(0..params.plaintext_modulus) as u32)
+            .collect::<Vec<u32>>();
+        let ct = pk_back.encrypt_values(&evaluator, &m, &mut rng);
+        assert_ne!(pk.encrypt_values(&evaluator, &m, &mut rng), ct);
+        assert_eq!(
+            Vec::<u32>::try_decoding_with_parameters(
+                &evaluator.decrypt(&sk, &ct),
+                params,
+                Encoding::default()
+            ),
+            m
+        );
+
+        assert!(matches!(
+            deserialize_public_key(&[0u8; 3], &evaluator),
+            Err(PsiError::Serialization(_))
+        ));
+    }
+}
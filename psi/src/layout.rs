@@ -0,0 +1,149 @@
+use crate::{CiphertextSlots, HashTableSize, PsiParams, PsiPlaintext};
+
+/// No. of hash table rows in a single segment, ie rows that fit in a ciphertext when each row spans
+/// `PsiPlaintext::slots_required` slots
+pub fn rows_per_segment(ct_slots: &CiphertextSlots, psi_pt: &PsiPlaintext) -> u32 {
+    ct_slots.0 / psi_pt.slots_required()
+}
+
+/// No. of segments of hash table with `ht_size` rows, rounded to the nearest. `PsiParams::validate` requires
+/// `ht_size` to be a multiple of `rows_per_segment`.
+pub fn segments_per_hash_table(
+    ht_size: &HashTableSize,
+    ct_slots: &CiphertextSlots,
+    psi_pt: &PsiPlaintext,
+) -> u32 {
+    let rows = rows_per_segment(ct_slots, psi_pt);
+    (ht_size.0 + (rows >> 1)) / rows
+}
+
+/// Layout of queries and responses with given `PsiParams`. Each hash table is split into segments of consecutive rows.
+/// Each segment is queried with a single ciphertext per source power, in which every row spans `slots_per_entry`
+/// consecutive slots, and is answered by every InnerBox of the segment.
+///
+/// Client queries, server db, query validation and (de)serialization all derive their layout from here.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueryLayout {
+    no_of_hash_tables: usize,
+    ht_size: u32,
+    slots_per_entry: u32,
+    rows_per_segment: u32,
+    segments_per_hash_table: u32,
+    source_powers: usize,
+    zero_cts: usize,
+}
+
+impl QueryLayout {
+    pub fn new(psi_params: &PsiParams) -> QueryLayout {
+        QueryLayout {
+            no_of_hash_tables: psi_params.no_of_hash_tables as usize,
+            ht_size: psi_params.ht_size.0,
+            slots_per_entry: psi_params.psi_pt.slots_required(),
+            rows_per_segment: rows_per_segment(&psi_params.ct_slots, &psi_params.psi_pt),
+            segments_per_hash_table: segments_per_hash_table(
+                &psi_params.ht_size,
+                &psi_params.ct_slots,
+                &psi_params.psi_pt,
+            ),
+            source_powers: psi_params.source_powers.len(),
+            zero_cts: psi_params.zero_cts_count(),
+        }
+    }
+
+    pub fn no_of_hash_tables(&self) -> usize {
+        self.no_of_hash_tables
+    }
+
+    /// No. of rows of each hash table
+    pub fn ht_size(&self) -> u32 {
+        self.ht_size
+    }
+
+    /// No. of ciphertext slots spanned by a single hash table row
+    pub fn slots_per_entry(&self) -> u32 {
+        self.slots_per_entry
+    }
+
+    /// See `rows_per_segment`
+    pub fn rows_per_segment(&self) -> u32 {
+        self.rows_per_segment
+    }
+
+    /// See `segments_per_hash_table`
+    pub fn segments_per_hash_table(&self) -> usize {
+        self.segments_per_hash_table as usize
+    }
+
+    /// No. of segments across all hash tables
+    pub fn segments(&self) -> usize {
+        self.no_of_hash_tables * self.segments_per_hash_table()
+    }
+
+    /// Returns segment of hash table row at `ht_index` and its row within the segment
+    pub fn segment_row(&self, ht_index: u32) -> (usize, u32) {
+        (
+            (ht_index / self.rows_per_segment) as usize,
+            ht_index % self.rows_per_segment,
+        )
+    }
+
+    /// Returns first ciphertext slot of `row` of a segment
+    pub fn first_slot(&self, row: u32) -> u32 {
+        row * self.slots_per_entry
+    }
+
+    /// No. of query ciphertexts of a single hash table, ie one per source power for each segment
+    pub fn query_cts_per_hash_table(&self) -> usize {
+        self.segments_per_hash_table() * self.source_powers
+    }
+
+    /// No. of ciphertexts of a query, ie query ciphertexts of all hash tables followed by encryptions of zero
+    pub fn query_cts(&self) -> usize {
+        self.no_of_hash_tables * self.query_cts_per_hash_table() + self.zero_cts
+    }
+
+    /// No. of encryptions of zero in a query. See `PsiParams::zero_cts_count`.
+    pub fn zero_cts(&self) -> usize {
+        self.zero_cts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layout_covers_hash_table() {
+        let psi_params = PsiParams::default();
+        let layout = QueryLayout::new(&psi_params);
+        assert_eq!(
+            layout.rows_per_segment() * layout.slots_per_entry(),
+            psi_params.ct_slots.0
+        );
+        assert_eq!(
+            layout.segments_per_hash_table() as u32 * layout.rows_per_segment(),
+            layout.ht_size()
+        );
+
+        // rows of a segment are consecutive
+        let last_row = layout.ht_size() - 1;
+        assert_eq!(layout.segment_row(0), (0, 0));
+        assert_eq!(layout.segment_row(layout.rows_per_segment()), (1, 0));
+        assert_eq!(
+            layout.segment_row(last_row),
+            (
+                layout.segments_per_hash_table() - 1,
+                layout.rows_per_segment() - 1
+            )
+        );
+        assert_eq!(
+            layout.first_slot(layout.rows_per_segment() - 1) + layout.slots_per_entry(),
+            psi_params.ct_slots.0
+        );
+
+        assert_eq!(
+            layout.query_cts(),
+            layout.segments() * psi_params.source_powers.len() + psi_params.zero_cts_count()
+        );
+    }
+}
@@ -2,31 +2,103 @@ use bfv::{
     BfvParameters, Ciphertext, Encoding, EvaluationKey, Evaluator, Plaintext, PolyCache, PolyType,
     Representation, SecretKey, SecretKeyProto,
 };
-use hash::Cuckoo;
 use itertools::{izip, Itertools};
 use rand::thread_rng;
 use rand_chacha::rand_core::le;
 use serde::{Deserialize, Serialize};
 use server::{
-    paterson_stockmeyer::PSParams, CiphertextSlots, EvalPolyDegree, HashTableSize, PsiPlaintext,
+    paterson_stockmeyer::{windowed_source_powers, PSParams},
+    CiphertextSlots, EvalPolyDegree, HashTableSize, PsiPlaintext,
 };
-use std::{collections::HashMap, hash::Hash};
+use sha2::{Digest, Sha256};
+use std::{collections::HashMap, hash::Hash, path::Path, sync::OnceLock};
 
-pub use client::*;
-pub use hash::*;
-pub use poly_interpolate::*;
-pub use serialize::*;
-pub use server::*;
-pub use utils::*;
+pub use prelude::*;
 
+#[cfg(feature = "sync")]
+pub(crate) use blocking_client::*;
+pub(crate) use client::*;
+pub(crate) use client_core::*;
+pub(crate) use dag::*;
+pub(crate) use error::*;
+pub(crate) use hash::*;
+#[cfg(feature = "http")]
+pub(crate) use http_client::*;
+pub(crate) use import::*;
+pub(crate) use keys::*;
+pub(crate) use layout::*;
+pub(crate) use moduli::*;
+pub(crate) use oprf::*;
+pub(crate) use poly_interpolate::*;
+pub(crate) use protocol::*;
+pub(crate) use psi_client::*;
+#[cfg(feature = "quic")]
+pub(crate) use quic::*;
+pub(crate) use serialize::*;
+pub(crate) use server::*;
+pub(crate) use shard::*;
+pub(crate) use tls::*;
+pub(crate) use transport::*;
+pub(crate) use utils::*;
+
+#[cfg(feature = "sync")]
+mod blocking_client;
 mod client;
+mod client_core;
+mod dag;
+mod error;
 mod hash;
+#[cfg(feature = "http")]
+mod http_client;
+mod import;
+mod keys;
+mod layout;
+mod moduli;
+mod oprf;
 mod poly_interpolate;
+pub mod prelude;
+mod protocol;
+mod psi_client;
+#[cfg(feature = "quic")]
+mod quic;
 mod serialize;
 mod server;
+mod shard;
+mod tls;
+mod transport;
 mod utils;
 
+/// Internals exposed only for benchmarks. Not part of the public API, see `prelude`.
+#[doc(hidden)]
+pub mod internals {
+    pub use crate::{
+        client::calculate_source_powers, poly_interpolate::newton_interpolate,
+        server::paterson_stockmeyer::ps_evaluate_poly, utils::calculate_ps_powers_with_dag,
+    };
+}
+
+/// Algorithm used to interpolate label polynomials during preprocessing
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum InterpolationMethod {
+    /// Newton interpolation. Takes O(n^2) operations but has low constant, thus is faster for small degrees.
+    Newton,
+    /// Subproduct tree interpolation with NTT based multiplication. Takes O(n log^2 n) operations, thus is faster for
+    /// large degrees.
+    SubproductTree,
+}
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum PsiMode {
+    /// Server returns labels of items at intersection
+    Labeled,
+    /// Server only returns whether items are at intersection. Server interpolates membership polynomials, that
+    /// evaluate to 0 at its items, and does not store labels.
+    Unlabeled,
+}
+
+/// Fields missing in a config file (see `PsiParams::from_file`) are taken from `PsiParams::default`
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct PsiParams {
     pub(crate) no_of_hash_tables: u8,
     pub(crate) ht_size: HashTableSize,
@@ -39,17 +111,59 @@ pub struct PsiParams {
     pub(crate) psi_pt: PsiPlaintext,
     pub(crate) ps_params: PSParams,
     pub(crate) source_powers: Vec<usize>,
+    /// Max. no. of InnerBoxes allowed in a single segment of BigBox. Response contains one ciphertext per InnerBox,
+    /// thus the cap bounds response size. `None` means unbounded.
+    pub(crate) max_inner_boxes_per_segment: Option<u32>,
+    /// When set, server inserts OPRF outputs of its items and client must obtain OPRF outputs of its items from server
+    /// before constructing the query.
+    pub(crate) oprf: bool,
+    pub(crate) mode: PsiMode,
+    /// When set, server encodes coefficients of all polynomials as plaintexts once after preprocessing instead of on
+    /// every query. Reduces query latency at the cost of server memory.
+    pub(crate) precompute_plaintexts: bool,
+    pub(crate) interpolation: InterpolationMethod,
+    /// Strategy used to pick DAG for calculating PS powers from source powers
+    pub(crate) dag_strategy: DagStrategy,
+    /// When set, client sends encryptions of zero with query and server rerandomizes response ciphertexts with them and
+    /// smudges their noise, so that decrypted responses don't leak server's polynomial coefficients through noise.
+    pub(crate) circuit_privacy: bool,
+    /// Bits of dedicated modulus appended to `bfv_moduli` to which responses are switched before serialization. `None`
+    /// switches responses to last modulus in `bfv_moduli`.
+    pub(crate) response_modulus: Option<usize>,
+    /// Hash function of cuckoo hash tables
+    pub(crate) hash_backend: HashBackend,
+    /// Per deployment key of cuckoo hash functions, thus deployments don't share table layouts
+    pub(crate) hash_seed: u64,
+    /// Max. no. of evictions while client inserts a single item into cuckoo hash tables
+    pub(crate) cuckoo_max_kicks: u32,
+    /// Level at which polynomials are evaluated. PS powers are calculated at level 0 and switched down to this level
+    /// before evaluation, which makes evaluation cheaper at the cost of noise budget and larger evaluation key.
+    pub(crate) evaluation_level: usize,
+    /// Window size in bits of source powers when they are windowed (see `PsiParams::with_windowed_powers`). `None` if
+    /// source powers are searched or hand picked.
+    pub(crate) powers_window_bits: Option<u32>,
+    /// When set, server pads unoccupied columns of every hash table row with random interpolation points during
+    /// preprocessing, thus polynomials have full degree regardless of occupancy and evaluate to random values for
+    /// non-members.
+    pub(crate) random_padding: bool,
+    /// When set in labeled mode, server also interpolates membership polynomial of each row and responds with one more
+    /// ciphertext per InnerBox, which tells client whether its item is in the InnerBox.
+    pub(crate) membership: bool,
 }
 
 impl Default for PsiParams {
     fn default() -> Self {
-        let ps_params = PSParams::new(44, 1304);
+        // searching for PS params takes a while, thus is done once
+        static DEFAULT_PS_PARAMS: OnceLock<(PSParams, Vec<usize>)> = OnceLock::new();
+        let (ps_params, source_powers) = DEFAULT_PS_PARAMS
+            .get_or_init(|| PSParams::optimize(1304, 4))
+            .clone();
         let psi_pt = PsiPlaintext::new(256, 16, 65537);
 
         PsiParams {
             no_of_hash_tables: 3,
             ht_size: HashTableSize(1 << 12),
-            ct_slots: CiphertextSlots(1 << 13),
+            ct_slots: CiphertextSlots::for_degree(1 << 13),
             eval_degree: ps_params.eval_degree(),
             bfv_moduli: vec![50, 50, 45],
             hybrid_ksk_moduli: [50, 50, 45],
@@ -57,10 +171,818 @@ impl Default for PsiParams {
             bfv_plaintext: 65537,
             psi_pt,
             ps_params,
-            source_powers: vec![1, 3, 11, 18, 45, 225],
+            source_powers,
+            max_inner_boxes_per_segment: None,
+            oprf: false,
+            mode: PsiMode::Labeled,
+            precompute_plaintexts: false,
+            interpolation: InterpolationMethod::Newton,
+            dag_strategy: DagStrategy::MinMultiplications,
+            circuit_privacy: false,
+            response_modulus: None,
+            hash_backend: HashBackend::Sha256,
+            hash_seed: 0,
+            cuckoo_max_kicks: DEFAULT_MAX_KICKS,
+            evaluation_level: 0,
+            powers_window_bits: None,
+            random_padding: false,
+            membership: false,
+        }
+    }
+}
+
+/// Response modulus must exceed plaintext modulus by atleast these many bits so that noise after switching to it
+/// doesn't overflow
+pub(crate) const MIN_RESPONSE_MODULUS_MARGIN_BITS: usize = 10;
+
+/// Server set sizes (log2) for which `PsiParams::for_server_size` has tuned presets
+pub const PRESET_SERVER_SIZES: [u32; 4] = [16, 20, 24, 28];
+
+/// BFV degrees for which `PsiParams::for_server_size_with_degree` has presets
+pub const PRESET_BFV_DEGREES: [usize; 3] = [1 << 13, 1 << 14, 1 << 15];
+
+impl PsiParams {
+    /// Returns preset parameters tuned for server set of `n_items` items with labels of `label_bytes` bytes. Preset for
+    /// smallest supported server size >= `n_items` is picked, or the largest preset if `n_items` exceeds 2^28.
+    ///
+    /// All presets support client sets of upto 4096 items. Smaller servers have fewer items per hash table row, thus
+    /// use polynomials of lower degree which require fewer ciphertext multiplications per InnerBox. Larger servers use
+    /// polynomials of higher degree to reduce no. of InnerBoxes, thus response size.
+    pub fn for_server_size(n_items: u64, label_bytes: u32) -> PsiParams {
+        let log_n = PRESET_SERVER_SIZES
+            .iter()
+            .find(|log_n| n_items <= 1u64 << **log_n)
+            .unwrap_or(PRESET_SERVER_SIZES.last().unwrap());
+
+        let params = match log_n {
+            16 => PsiParams::default().with_ps_search(63, 3),
+            20 => PsiParams::default().with_ps_search(575, 4),
+            24 => PsiParams::default(),
+            _ => PsiParams::default().with_ps_search(2047, 4),
+        }
+        .with_label_bytes(label_bytes);
+        debug_assert!(params.validate().is_ok());
+        params
+    }
+
+    /// Same as `for_server_size` but with BFV degree `bfv_degree`, one of `PRESET_BFV_DEGREES`. Presets of 2^13 are those
+    /// of `for_server_size`.
+    ///
+    /// Each doubling of degree doubles slots of a ciphertext, thus hash tables of twice the rows are queried with the
+    /// same no. of ciphertexts and presets support client sets of twice the size (8192 items with 2^14, 16384 with
+    /// 2^15). It also allows one more level of multiplicative depth, which is spent on fewer source powers, ie a
+    /// smaller query. Moduli are derived with `with_derived_moduli`.
+    pub fn for_server_size_with_degree(
+        n_items: u64,
+        label_bytes: u32,
+        bfv_degree: usize,
+    ) -> Result<PsiParams, PsiError> {
+        let base = PsiParams::for_server_size(n_items, label_bytes);
+        if !PRESET_BFV_DEGREES.contains(&bfv_degree) {
+            return Err(PsiError::InvalidParams(format!(
+                "No presets for BFV degree {bfv_degree}, expected one of {PRESET_BFV_DEGREES:?}"
+            )));
+        }
+        if bfv_degree == base.bfv_degree {
+            return Ok(base);
+        }
+
+        let doublings = (bfv_degree / base.bfv_degree).trailing_zeros();
+        let available_levels = evaluation_depth(&base)? + doublings as usize;
+        let mut params = base
+            .clone()
+            .with_bfv_degree(bfv_degree)
+            .with_ps_search(*base.eval_degree as usize, available_levels);
+        params.ht_size = HashTableSize(*base.ht_size << doublings);
+        params.with_derived_moduli()
+    }
+
+    /// Sets BFV degree along with ciphertext slots, which are derived from it. Moduli and hash table size are left
+    /// unchanged, thus may have to be adjusted for params to be valid (see `with_derived_moduli`).
+    pub fn with_bfv_degree(mut self, bfv_degree: usize) -> PsiParams {
+        self.bfv_degree = bfv_degree;
+        self.ct_slots = CiphertextSlots::for_degree(bfv_degree);
+        self
+    }
+
+    pub fn bfv_degree(&self) -> usize {
+        self.bfv_degree
+    }
+
+    /// Sets BFV plaintext modulus along with bits of item chunks, which must fit in it. Modulus must be a prime
+    /// congruent to 1 modulo 2 * BFV degree for SIMD encoding. Coefficients of polynomials take half the memory with
+    /// moduli of atmost 2^16 (see `coefficient_bytes`), which requires chunks of 8 bits.
+    pub fn with_plaintext_modulus(mut self, bfv_pt_bits: u32, bfv_plaintext: u64) -> PsiParams {
+        self.psi_pt = PsiPlaintext::new(self.psi_pt.psi_pt_bits, bfv_pt_bits, bfv_plaintext as u32)
+            .with_label_bytes(self.psi_pt.label_bytes)
+            .with_label_checksum_bytes(self.psi_pt.label_checksum_bytes);
+        self.bfv_plaintext = bfv_plaintext;
+        self
+    }
+
+    pub fn bfv_plaintext(&self) -> u64 {
+        self.bfv_plaintext
+    }
+
+    /// Sets PS params and source powers to those found by `PSParams::optimize` for polynomials of `eval_degree` within
+    /// `available_levels`
+    pub fn with_ps_search(mut self, eval_degree: usize, available_levels: usize) -> PsiParams {
+        let (ps_params, source_powers) = PSParams::optimize(eval_degree, available_levels);
+        self.eval_degree = ps_params.eval_degree();
+        self.ps_params = ps_params;
+        self.source_powers = source_powers;
+        self.powers_window_bits = None;
+        self
+    }
+
+    /// Sets PS params and source powers to windowed source powers found by `PSParams::optimize_windowed` for
+    /// polynomials of `eval_degree` within `available_levels`, and picks DAG with least depth.
+    ///
+    /// Client sends every multiple of power of two window that PS powers require, thus every PS power is a product of
+    /// atmost one source power per window. Compared to `with_ps_search`, query has more ciphertexts (see
+    /// `expected_query_bytes`) but server calculates PS powers with shallower DAG and fewer relinearizations.
+    pub fn with_windowed_powers(
+        mut self,
+        eval_degree: usize,
+        available_levels: usize,
+    ) -> PsiParams {
+        let (ps_params, source_powers, window_bits) =
+            PSParams::optimize_windowed(eval_degree, available_levels);
+        self.eval_degree = ps_params.eval_degree();
+        self.ps_params = ps_params;
+        self.source_powers = source_powers;
+        self.powers_window_bits = Some(window_bits);
+        self.dag_strategy = DagStrategy::MinDepth;
+        self
+    }
+
+    pub fn powers_window_bits(&self) -> Option<u32> {
+        self.powers_window_bits
+    }
+
+    /// Loads parameters from config file at `path` and validates them. Format is picked by extension: `.toml`,
+    /// `.json` or `.bin` (bincode). Fields missing in TOML and JSON files are taken from `PsiParams::default`, thus
+    /// the file only needs to contain the parameters that are tuned.
+    pub fn from_file(path: &Path) -> Result<PsiParams, PsiError> {
+        let bytes = std::fs::read(path)
+            .map_err(|e| PsiError::Io(format!("Failed to read {}: {e}", path.display())))?;
+        let parse_error =
+            |e: String| PsiError::InvalidParams(format!("Failed to parse {}: {e}", path.display()));
+
+        let psi_params: PsiParams = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => {
+                let s = std::str::from_utf8(&bytes).map_err(|e| parse_error(e.to_string()))?;
+                toml::from_str(s).map_err(|e| parse_error(e.to_string()))?
+            }
+            Some("json") => {
+                serde_json::from_slice(&bytes).map_err(|e| parse_error(e.to_string()))?
+            }
+            Some("bin") => bincode::deserialize(&bytes).map_err(|e| parse_error(e.to_string()))?,
+            _ => {
+                return Err(PsiError::InvalidParams(format!(
+                    "Unsupported config file {}, expected .toml, .json or .bin",
+                    path.display()
+                )))
+            }
+        };
+        psi_params.validate()?;
+        Ok(psi_params)
+    }
+
+    /// Checks that parameters are consistent with each other
+    pub fn validate(&self) -> Result<(), PsiError> {
+        let invalid = |e: String| Err(PsiError::InvalidParams(e));
+
+        if self.no_of_hash_tables == 0 {
+            return invalid("No. of hash tables must be > 0".to_string());
+        }
+        if !self.bfv_degree.is_power_of_two() {
+            return invalid(format!(
+                "BFV degree {} must be a power of 2",
+                self.bfv_degree
+            ));
+        }
+        if max_secure_modulus_bits(self.bfv_degree).is_none() {
+            return invalid(format!(
+                "BFV degree {} must be between 2^10 and 2^15",
+                self.bfv_degree
+            ));
+        }
+        if self.ct_slots != CiphertextSlots::for_degree(self.bfv_degree) {
+            return invalid(format!(
+                "Ciphertext slots {} must equal BFV degree {}",
+                *self.ct_slots, self.bfv_degree
+            ));
+        }
+        if self.bfv_moduli.is_empty() {
+            return invalid("BFV moduli must not be empty".to_string());
+        }
+        if self.evaluation_level >= self.bfv_moduli.len() {
+            return invalid(format!(
+                "Evaluation level {} must be below no. of BFV moduli {}",
+                self.evaluation_level,
+                self.bfv_moduli.len()
+            ));
+        }
+        if let Some(bits) = self.response_modulus {
+            let min_bits = (self.bfv_plaintext as f64).log2().ceil() as usize
+                + MIN_RESPONSE_MODULUS_MARGIN_BITS;
+            if bits < min_bits || bits >= *self.bfv_moduli.last().unwrap() {
+                return invalid(format!(
+                    "Response modulus of {bits} bits must be >= {min_bits} bits and smaller than last BFV modulus",
+                ));
+            }
+        }
+
+        // item chunks must fit in BFV plaintext
+        if self.psi_pt.bfv_pt as u64 != self.bfv_plaintext {
+            return invalid(format!(
+                "PSI plaintext modulus {} does not match BFV plaintext modulus {}",
+                self.psi_pt.bfv_pt, self.bfv_plaintext
+            ));
+        }
+        if self.psi_pt.bfv_pt_bits >= 32 || (1u64 << self.psi_pt.bfv_pt_bits) > self.bfv_plaintext {
+            return invalid(format!(
+                "Chunk of {} bits does not fit in BFV plaintext modulus {}",
+                self.psi_pt.bfv_pt_bits, self.bfv_plaintext
+            ));
+        }
+        // padding points would be extra roots of membership polynomials, thus non-members would be reported
+        if self.random_padding && self.mode == PsiMode::Unlabeled {
+            return invalid("Random padding is only supported in labeled mode".to_string());
+        }
+        // responses to unlabeled queries are membership ciphertexts already
+        if self.membership && self.mode == PsiMode::Unlabeled {
+            return invalid(
+                "Membership ciphertexts are only supported in labeled mode".to_string(),
+            );
+        }
+        // membership polynomials only have roots at occupied columns, thus would reveal occupancy of padded rows
+        if self.membership && self.random_padding {
+            return invalid(
+                "Membership ciphertexts are not supported with random padding".to_string(),
+            );
+        }
+
+        // chunks are read as whole bytes and keyed as u16 in `InnerBox::item_data_hash_set` (see `bytes_to_u16`).
+        // `PsiPlaintext::new` can't construct other chunks, but config files set fields directly.
+        if !matches!(self.psi_pt.bfv_pt_bits, 8 | 16)
+            || self.psi_pt.bfv_pt_bytes * 8 != self.psi_pt.bfv_pt_bits
+        {
+            return invalid(format!(
+                "Chunk of {} bits ({} bytes) must be of 8 or 16 bits",
+                self.psi_pt.bfv_pt_bits, self.psi_pt.bfv_pt_bytes
+            ));
+        }
+        if self.psi_pt.label_checksum_bytes > MAX_LABEL_CHECKSUM_BYTES {
+            return invalid(format!(
+                "Label checksum of {} bytes exceeds {MAX_LABEL_CHECKSUM_BYTES} bytes",
+                self.psi_pt.label_checksum_bytes
+            ));
+        }
+        if self.psi_pt.psi_pt_bits < self.psi_pt.bfv_pt_bits {
+            return invalid(format!(
+                "Item of {} bits is smaller than chunk of {} bits",
+                self.psi_pt.psi_pt_bits, self.psi_pt.bfv_pt_bits
+            ));
+        }
+        if self.psi_pt.psi_pt_bits > 256 {
+            return invalid(format!(
+                "Item of {} bits is larger than 256 bits",
+                self.psi_pt.psi_pt_bits
+            ));
+        }
+
+        // each ciphertext must hold whole hash table rows and hash table must span whole segments
+        let slots_required = self.psi_pt.slots_required();
+        if *self.ct_slots < slots_required {
+            return invalid(format!(
+                "Ciphertext slots {} can't hold a single item of {slots_required} chunks",
+                *self.ct_slots
+            ));
+        }
+        let ht_rows_per_ct = rows_per_segment(&self.ct_slots, &self.psi_pt);
+        if !self.ht_size.is_power_of_two() || *self.ht_size % ht_rows_per_ct != 0 {
+            return invalid(format!(
+                "Hash table size {} must be a power of 2 and a multiple of {ht_rows_per_ct} rows per ciphertext",
+                *self.ht_size
+            ));
+        }
+
+        if self.eval_degree != self.ps_params.eval_degree() {
+            return invalid(format!(
+                "Eval degree {} does not match Paterson-Stockmeyer degree {}",
+                *self.eval_degree,
+                *self.ps_params.eval_degree()
+            ));
+        }
+
+        // all powers required by Paterson-Stockmeyer must be computable from source powers
+        if !self.source_powers.contains(&1) {
+            return invalid("Source powers must contain 1".to_string());
+        }
+        if let Some(window_bits) = self.powers_window_bits {
+            if window_bits == 0 || window_bits >= usize::BITS {
+                return invalid(format!(
+                    "Window of {window_bits} bits must be > 0 and < {}",
+                    usize::BITS
+                ));
+            }
+            let windowed = windowed_source_powers(self.ps_params.powers(), window_bits);
+            if self.source_powers != windowed {
+                return invalid(format!(
+                    "Source powers {:?} are not windowed powers {windowed:?} of {window_bits} bit windows",
+                    self.source_powers
+                ));
+            }
+        }
+        let dag = self.powers_dag()?;
+        if let Some(power) = uncomputable_power(dag.nodes(), self.ps_params.powers()) {
+            return invalid(format!(
+                "Power {power} can't be computed from source powers {:?}",
+                self.source_powers
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Appends a dedicated modulus of `bits` bits to BFV moduli to which responses are switched before serialization.
+    /// Shrinks response ciphertexts from last modulus in `bfv_moduli` to `bits` per coefficient, but makes query
+    /// ciphertexts larger and server's evaluation slower since both happen with one more modulus.
+    pub fn with_response_modulus(mut self, bits: usize) -> PsiParams {
+        self.response_modulus = Some(bits);
+        self
+    }
+
+    pub fn response_modulus(&self) -> Option<usize> {
+        self.response_modulus
+    }
+
+    /// BFV ciphertext moduli, including response modulus
+    pub fn ciphertext_moduli(&self) -> Vec<usize> {
+        let mut moduli = self.bfv_moduli.clone();
+        moduli.extend(self.response_modulus);
+        moduli
+    }
+
+    /// Level to which response ciphertexts are switched, ie last level
+    pub fn response_level(&self) -> usize {
+        self.bfv_moduli.len() - 1 + self.response_modulus.is_some() as usize
+    }
+
+    /// Enables rerandomization and smudging of response ciphertexts (see `ResponseRerandomizer`). Adds
+    /// `CIRCUIT_PRIVACY_ZERO_CTS` ciphertexts to the query. Smudging noise needs `SMUDGING_SECURITY_BITS` more bits of
+    /// ciphertext modulus than evaluation alone, call `with_derived_moduli` afterwards to budget for it.
+    pub fn with_circuit_privacy(mut self) -> PsiParams {
+        self.circuit_privacy = true;
+        self
+    }
+
+    pub fn circuit_privacy(&self) -> bool {
+        self.circuit_privacy
+    }
+
+    /// No. of encryptions of zero sent with query
+    pub(crate) fn zero_cts_count(&self) -> usize {
+        if self.circuit_privacy {
+            CIRCUIT_PRIVACY_ZERO_CTS
+        } else {
+            0
+        }
+    }
+
+    pub fn with_dag_strategy(mut self, dag_strategy: DagStrategy) -> PsiParams {
+        self.dag_strategy = dag_strategy;
+        self
+    }
+
+    pub fn dag_strategy(&self) -> DagStrategy {
+        self.dag_strategy
+    }
+
+    /// Returns DAG picked by `dag_strategy` to calculate PS powers from source powers
+    pub fn powers_dag(&self) -> Result<PowersDag, PsiError> {
+        // product of ciphertexts grows noise by roughly plaintext modulus times degree
+        let noise_bits_per_multiplication =
+            (self.bfv_plaintext as f64).log2() + (self.bfv_degree as f64).log2();
+        optimize_dag(
+            &self.source_powers,
+            self.ps_params.powers(),
+            self.dag_strategy,
+            noise_bits_per_multiplication,
+        )
+        .ok_or(PsiError::InvalidParams(format!(
+            "PS powers can't be computed from source powers {:?}",
+            self.source_powers
+        )))
+    }
+
+    /// Caps no. of InnerBoxes per segment. Inserts that require a new InnerBox in a full segment are rejected.
+    pub fn with_max_inner_boxes_per_segment(mut self, max_inner_boxes: u32) -> PsiParams {
+        assert!(max_inner_boxes > 0);
+        self.max_inner_boxes_per_segment = Some(max_inner_boxes);
+        self
+    }
+
+    pub fn max_inner_boxes_per_segment(&self) -> Option<u32> {
+        self.max_inner_boxes_per_segment
+    }
+
+    /// Enables OPRF preprocessing of items
+    pub fn with_oprf(mut self) -> PsiParams {
+        self.oprf = true;
+        self
+    }
+
+    pub fn oprf(&self) -> bool {
+        self.oprf
+    }
+
+    pub fn with_mode(mut self, mode: PsiMode) -> PsiParams {
+        self.mode = mode;
+        self
+    }
+
+    pub fn mode(&self) -> PsiMode {
+        self.mode
+    }
+
+    /// Enables encoding polynomial coefficients as plaintexts at preprocessing. Encoded plaintexts take several times
+    /// more memory than coefficients but avoid encoding them on every query.
+    pub fn with_precomputed_plaintexts(mut self) -> PsiParams {
+        self.precompute_plaintexts = true;
+        self
+    }
+
+    pub fn precompute_plaintexts(&self) -> bool {
+        self.precompute_plaintexts
+    }
+
+    /// Pads unoccupied columns of hash table rows with random points during preprocessing. Hides occupancy of rows at
+    /// the cost of interpolating and storing polynomials of full degree for every row, including empty ones. Only
+    /// supported in labeled mode, since padding points would be roots of membership polynomials.
+    pub fn with_random_padding(mut self) -> PsiParams {
+        self.random_padding = true;
+        self
+    }
+
+    pub fn random_padding(&self) -> bool {
+        self.random_padding
+    }
+
+    /// Evaluates membership polynomial of each row alongside label polynomials, thus client learns whether an item is
+    /// at intersection from the membership ciphertext of InnerBox instead of verifying label chunks, and only receives
+    /// labels of InnerBoxes that store the item. Costs one more polynomial per row and one more response ciphertext
+    /// per InnerBox, and a row holds one item less. Only supported in labeled mode.
+    pub fn with_membership(mut self) -> PsiParams {
+        self.membership = true;
+        self
+    }
+
+    pub fn membership(&self) -> bool {
+        self.membership
+    }
+
+    /// Sets algorithm used to interpolate label polynomials. Has no effect in unlabeled mode.
+    pub fn with_interpolation(mut self, interpolation: InterpolationMethod) -> PsiParams {
+        self.interpolation = interpolation;
+        self
+    }
+
+    pub fn interpolation(&self) -> InterpolationMethod {
+        self.interpolation
+    }
+
+    /// Sets hash function of cuckoo hash tables
+    pub fn with_hash_backend(mut self, hash_backend: HashBackend) -> PsiParams {
+        self.hash_backend = hash_backend;
+        self
+    }
+
+    pub fn hash_backend(&self) -> HashBackend {
+        self.hash_backend
+    }
+
+    /// Keys cuckoo hash functions with `hash_seed`. Server and client must use the same seed.
+    pub fn with_hash_seed(mut self, hash_seed: u64) -> PsiParams {
+        self.hash_seed = hash_seed;
+        self
+    }
+
+    pub fn hash_seed(&self) -> u64 {
+        self.hash_seed
+    }
+
+    /// Sets max. no. of evictions while client inserts a single item into cuckoo hash tables. Items still homeless are
+    /// left on `QueryState::hash_table_stack`, and `construct_queries` carries them over to the next query.
+    pub fn with_cuckoo_max_kicks(mut self, max_kicks: u32) -> PsiParams {
+        self.cuckoo_max_kicks = max_kicks;
+        self
+    }
+
+    pub fn cuckoo_max_kicks(&self) -> u32 {
+        self.cuckoo_max_kicks
+    }
+
+    /// Evaluates polynomials at `level` instead of level 0. Multiplications at lower level operate on fewer moduli,
+    /// thus evaluation gets faster, but noise budget shrinks by the dropped moduli and clients upload relinearization
+    /// key of `level` as well (see `generate_evaluation_key`).
+    pub fn with_evaluation_level(mut self, level: usize) -> PsiParams {
+        self.evaluation_level = level;
+        self
+    }
+
+    pub fn evaluation_level(&self) -> usize {
+        self.evaluation_level
+    }
+
+    /// Sets max. label size in bytes, independent of item size
+    pub fn with_label_bytes(mut self, label_bytes: u32) -> PsiParams {
+        self.psi_pt = self.psi_pt.with_label_bytes(label_bytes);
+        self
+    }
+
+    pub fn label_bytes(&self) -> u32 {
+        self.psi_pt.label_bytes
+    }
+
+    /// Appends checksum of item of `label_checksum_bytes` bytes to each label, thus client receives only the label of
+    /// its item instead of a label from every InnerBox at the item's row. Checksums take label space, thus may increase
+    /// response size.
+    pub fn with_label_checksum_bytes(mut self, label_checksum_bytes: u32) -> PsiParams {
+        self.psi_pt = self.psi_pt.with_label_checksum_bytes(label_checksum_bytes);
+        self
+    }
+
+    pub fn label_checksum_bytes(&self) -> u32 {
+        self.psi_pt.label_checksum_bytes
+    }
+
+    pub fn ps_params(&self) -> &PSParams {
+        &self.ps_params
+    }
+
+    /// Powers of query values that client encrypts. Server calculates rest of PS powers from them.
+    pub fn source_powers(&self) -> &[usize] {
+        &self.source_powers
+    }
+
+    /// SHA-256 of bincode serialized params. Server set and db files carry digest of params they were stored with,
+    /// thus they are never loaded with other params.
+    pub fn digest(&self) -> [u8; 32] {
+        Sha256::digest(bincode::serialize(self).expect("PsiParams are serializable")).into()
+    }
+
+    /// No. of polynomials interpolated per real row of InnerBox, thus the no. of response ciphertexts per InnerBox.
+    /// Membership polynomial is a single polynomial in unlabeled mode, and follows polynomials of label parts in
+    /// labeled mode with `PsiParams::membership`.
+    pub fn label_parts(&self) -> u32 {
+        match self.mode {
+            PsiMode::Labeled => self.psi_pt.label_parts() + self.membership as u32,
+            PsiMode::Unlabeled => 1,
+        }
+    }
+
+    /// Index of membership polynomial among polynomials of a real row (see `label_parts`). `None` if rows only have
+    /// label polynomials.
+    pub(crate) fn membership_part(&self) -> Option<usize> {
+        match self.mode {
+            PsiMode::Labeled if !self.membership => None,
+            _ => Some(self.label_parts() as usize - 1),
+        }
+    }
+
+    /// Bytes of each stored polynomial coefficient. Coefficients are below plaintext modulus, thus they are stored as
+    /// u16s if plaintext modulus is atmost 2^16 and as u32s otherwise. Note that 65537, the usual modulus for 16 bit
+    /// chunks, needs u32s.
+    pub fn coefficient_bytes(&self) -> u32 {
+        if self.bfv_plaintext <= 1 << 16 {
+            2
+        } else {
+            4
+        }
+    }
+
+    /// No. of data points in a single row of InnerBox. Membership polynomial with n roots has degree n, thus with
+    /// membership polynomials a row holds one less data point than a label polynomial of same degree can interpolate.
+    pub(crate) fn inner_box_columns(&self) -> u32 {
+        match self.membership_part() {
+            None => self.eval_degree.inner_box_columns(),
+            Some(_) => self.eval_degree.inner_box_columns() - 1,
         }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn presets_are_valid() {
+        assert!(PsiParams::default().validate().is_ok());
+
+        for log_n in PRESET_SERVER_SIZES {
+            let params = PsiParams::for_server_size(1 << log_n, 32);
+            assert!(params.validate().is_ok());
+
+            // one item over picks next preset
+            if log_n != *PRESET_SERVER_SIZES.last().unwrap() {
+                let next = PsiParams::for_server_size((1 << log_n) + 1, 32);
+                assert!(*next.eval_degree > *params.eval_degree);
+            }
+        }
+
+        assert_eq!(
+            PsiParams::for_server_size(1 << 24, 32),
+            PsiParams::default()
+        );
+        assert_eq!(PsiParams::for_server_size(1 << 16, 64).label_bytes(), 64);
+
+        let params = PsiParams::default().with_response_modulus(30);
+        assert!(params.validate().is_ok());
+        assert_eq!(params.ciphertext_moduli(), vec![50, 50, 45, 30]);
+        assert_eq!(params.response_level(), 3);
+        assert_eq!(PsiParams::default().response_level(), 2);
+        assert!(matches!(
+            PsiParams::default().with_response_modulus(20).validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+
+        let mut params = PsiParams::default();
+        params.ct_slots = CiphertextSlots(1 << 14);
+        assert!(matches!(params.validate(), Err(PsiError::InvalidParams(_))));
+        params.ct_slots = CiphertextSlots(1 << 12);
+        assert!(matches!(params.validate(), Err(PsiError::InvalidParams(_))));
+        assert!(matches!(
+            PsiParams::default().with_bfv_degree(1 << 16).validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+
+        assert!(PsiParams::default()
+            .with_evaluation_level(1)
+            .validate()
+            .is_ok());
+        assert!(matches!(
+            PsiParams::default().with_evaluation_level(3).validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+
+        let mut params = PsiParams::default();
+        params.source_powers = vec![1, 3];
+        params.ps_params = PSParams::new(1, 5);
+        params.eval_degree = params.ps_params.eval_degree();
+        assert!(params.validate().is_ok());
+        params.source_powers = vec![3];
+        assert!(matches!(params.validate(), Err(PsiError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn validate_rejects_random_padding_in_unlabeled_mode() {
+        let params = PsiParams::default().with_random_padding();
+        assert!(params.validate().is_ok());
+        assert!(matches!(
+            params.with_mode(PsiMode::Unlabeled).validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_membership_in_unlabeled_mode_or_with_random_padding() {
+        let params = PsiParams::default().with_membership();
+        assert!(params.validate().is_ok());
+        assert_eq!(params.label_parts(), params.psi_pt.label_parts() + 1);
+        assert_eq!(
+            params.inner_box_columns(),
+            PsiParams::default().inner_box_columns() - 1
+        );
+        assert!(matches!(
+            params.clone().with_mode(PsiMode::Unlabeled).validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+        assert!(matches!(
+            params.with_random_padding().validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn validate_rejects_chunks_wider_than_16_bits() {
+        let mut params = PsiParams::default();
+        // plaintext modulus is large enough for any chunk below 32 bits
+        params.bfv_plaintext = 4294967291;
+        params.psi_pt.bfv_pt = 4294967291;
+        params.psi_pt.bfv_pt_bits = 16;
+        assert!(params.validate().is_ok());
+
+        for bfv_pt_bits in [17, 24, 31] {
+            let mut params = params.clone();
+            params.psi_pt.bfv_pt_bits = bfv_pt_bits;
+            params.psi_pt.bfv_pt_bytes = (bfv_pt_bits + 7) / 8;
+            assert!(matches!(params.validate(), Err(PsiError::InvalidParams(_))));
+        }
+
+        // bytes don't match bits
+        let mut params = PsiParams::default();
+        params.psi_pt.bfv_pt_bytes = 4;
+        assert!(matches!(params.validate(), Err(PsiError::InvalidParams(_))));
+    }
+
+    #[test]
+    fn degree_presets_are_valid() {
+        for log_n in PRESET_SERVER_SIZES {
+            assert_eq!(
+                PsiParams::for_server_size_with_degree(1 << log_n, 32, 1 << 13).unwrap(),
+                PsiParams::for_server_size(1 << log_n, 32)
+            );
+            for bfv_degree in [1 << 14, 1 << 15] {
+                let params =
+                    PsiParams::for_server_size_with_degree(1 << log_n, 32, bfv_degree).unwrap();
+                assert!(params.validate().is_ok());
+                assert_eq!(*params.ct_slots as usize, bfv_degree);
+                // hash table spans the same no. of segments as with 2^13
+                assert_eq!(
+                    *params.ht_size as usize * (1 << 13) / bfv_degree,
+                    *PsiParams::for_server_size(1 << log_n, 32).ht_size as usize
+                );
+            }
+        }
+        assert!(matches!(
+            PsiParams::for_server_size_with_degree(1 << 20, 32, 1 << 12),
+            Err(PsiError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn windowed_powers_work() {
+        let searched = PsiParams::default().with_ps_search(1304, 2);
+        let windowed = PsiParams::default().with_windowed_powers(1304, 2);
+        assert!(windowed.validate().is_ok());
+        assert!(windowed.powers_window_bits().is_some());
+        assert_eq!(windowed.dag_strategy(), DagStrategy::MinDepth);
+        assert!(evaluation_depth(&windowed).unwrap() <= 2);
+
+        // query has one ciphertext per source power per segment
+        let evaluator = Evaluator::new(gen_bfv_params(&windowed));
+        assert_eq!(
+            expected_query_bytes(&evaluator, &windowed) * searched.source_powers.len(),
+            expected_query_bytes(&evaluator, &searched) * windowed.source_powers.len()
+        );
+
+        // searching again drops the window
+        assert_eq!(
+            windowed
+                .clone()
+                .with_ps_search(1304, 2)
+                .powers_window_bits(),
+            None
+        );
+
+        let mut tampered = windowed;
+        tampered
+            .source_powers
+            .push(*tampered.ps_params.powers().last().unwrap());
+        assert!(matches!(
+            tampered.validate(),
+            Err(PsiError::InvalidParams(_))
+        ));
+    }
+
+    #[test]
+    fn from_file_works() {
+        let dir = std::env::temp_dir().join(format!("psi_params_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let params = PsiParams::default().with_response_modulus(30);
+
+        let toml_path = dir.join("params.toml");
+        std::fs::write(&toml_path, toml::to_string(&params).unwrap()).unwrap();
+        assert_eq!(PsiParams::from_file(&toml_path).unwrap(), params);
+
+        let json_path = dir.join("params.json");
+        std::fs::write(&json_path, serde_json::to_vec(&params).unwrap()).unwrap();
+        assert_eq!(PsiParams::from_file(&json_path).unwrap(), params);
+
+        // missing fields default
+        std::fs::write(&toml_path, "oprf = true\nbfv_moduli = [50, 50, 50]\n").unwrap();
+        let partial = PsiParams::from_file(&toml_path).unwrap();
+        assert!(partial.oprf());
+        assert_eq!(partial.bfv_moduli, vec![50, 50, 50]);
+        assert_eq!(partial.source_powers, PsiParams::default().source_powers);
+
+        // parsed but invalid
+        std::fs::write(&json_path, r#"{"no_of_hash_tables": 0}"#).unwrap();
+        assert!(matches!(
+            PsiParams::from_file(&json_path),
+            Err(PsiError::InvalidParams(_))
+        ));
+
+        std::fs::write(&toml_path, "oprf = 1").unwrap();
+        assert!(matches!(
+            PsiParams::from_file(&toml_path),
+            Err(PsiError::InvalidParams(_))
+        ));
+        assert!(PsiParams::from_file(&dir.join("params.yaml")).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
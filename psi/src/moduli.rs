@@ -0,0 +1,182 @@
+use crate::{
+    PsiError, PsiParams, MIN_NOISE_BUDGET_BITS, MIN_RESPONSE_MODULUS_MARGIN_BITS,
+    SMUDGING_SECURITY_BITS,
+};
+
+/// Max. bits of a single modulus picked by `derive_moduli`
+pub const MAX_DERIVED_MODULUS_BITS: usize = 50;
+
+/// Max. bits of ciphertext modulus at which BFV of each ring degree is 128-bit secure with ternary secrets, as per the
+/// homomorphic encryption security standard
+pub const MAX_MODULUS_BITS_128: [(usize, usize); 6] = [
+    (1 << 10, 27),
+    (1 << 11, 54),
+    (1 << 12, 109),
+    (1 << 13, 218),
+    (1 << 14, 438),
+    (1 << 15, 881),
+];
+
+/// Returns max. bits of ciphertext modulus at which `bfv_degree` is 128-bit secure. Returns `None` for degrees outside
+/// of `MAX_MODULUS_BITS_128`.
+pub fn max_secure_modulus_bits(bfv_degree: usize) -> Option<usize> {
+    MAX_MODULUS_BITS_128
+        .iter()
+        .find(|(degree, _)| *degree == bfv_degree)
+        .map(|(_, bits)| *bits)
+}
+
+/// Multiplicative depth of evaluating a polynomial with `psi_params`: depth of DAG calculating PS powers from source
+/// powers, plus one level for multiplying inner sums with high powers if polynomial has any.
+pub fn evaluation_depth(psi_params: &PsiParams) -> Result<usize, PsiError> {
+    let dag = psi_params.powers_dag()?;
+    let ps_params = psi_params.ps_params();
+    let outer_loop_count = ps_params.total_degree() / (ps_params.low_degree() + 1);
+    Ok(dag.cost().depth + (outer_loop_count > 0) as usize)
+}
+
+/// Estimates bits of noise of a response ciphertext after evaluating polynomials with `psi_params`, before it is
+/// rerandomized. Noise of every ciphertext multiplication is estimated the same way as by `PsiParams::powers_dag`, ie
+/// worst case, thus estimate is conservative.
+pub fn evaluation_noise_bits(psi_params: &PsiParams) -> Result<usize, PsiError> {
+    let t_bits = (psi_params.bfv_plaintext as f64).log2();
+    let n_bits = (psi_params.bfv_degree as f64).log2();
+    let depth = evaluation_depth(psi_params)?;
+
+    // fresh encryption has noise of roughly sqrt(n) times error bound
+    let fresh_noise_bits = n_bits / 2.0 + 5.0;
+    let multiplication_noise_bits = t_bits + n_bits;
+    // coefficients are below t and each inner sum adds upto low degree + 1 products
+    let plaintext_multiplication_noise_bits =
+        t_bits + ((psi_params.ps_params().low_degree() + 1) as f64).log2();
+
+    let bits = fresh_noise_bits
+        + depth as f64 * multiplication_noise_bits
+        + plaintext_multiplication_noise_bits;
+    Ok(bits.ceil() as usize)
+}
+
+/// Estimates bits of ciphertext modulus needed to evaluate polynomials with `psi_params` and decrypt the response with
+/// atleast `MIN_NOISE_BUDGET_BITS` bits of noise budget left (see `evaluation_noise_bits`). With circuit privacy,
+/// smudging noise exceeds evaluation noise by `SMUDGING_SECURITY_BITS` bits and is budgeted for as well.
+pub fn required_modulus_bits(psi_params: &PsiParams) -> Result<usize, PsiError> {
+    let noise_bits = if psi_params.circuit_privacy {
+        // sum of evaluation and smudging noise is below twice the smudging noise
+        evaluation_noise_bits(psi_params)? + SMUDGING_SECURITY_BITS + 1
+    } else {
+        evaluation_noise_bits(psi_params)?
+    };
+    // decryption needs noise below q / 2t
+    let decryption_bits = (psi_params.bfv_plaintext as f64).log2().ceil() as usize + 1;
+
+    Ok(noise_bits + decryption_bits + MIN_NOISE_BUDGET_BITS as usize)
+}
+
+/// Splits `bits` into fewest moduli of atmost `MAX_DERIVED_MODULUS_BITS` bits each, with sizes as equal as possible.
+/// Larger moduli come first, thus responses are switched to the smallest one.
+pub fn derive_moduli(bits: usize) -> Vec<usize> {
+    let count = (bits + MAX_DERIVED_MODULUS_BITS - 1) / MAX_DERIVED_MODULUS_BITS;
+    let count = count.max(1);
+    (0..count)
+        .map(|i| bits / count + (i < bits % count) as usize)
+        .collect()
+}
+
+impl PsiParams {
+    /// Replaces `bfv_moduli` and `hybrid_ksk_moduli` with moduli derived from multiplicative depth of evaluation (see
+    /// `required_modulus_bits`), instead of hand tuned ones. Special moduli of hybrid key switching are sized as the
+    /// largest ciphertext modulus.
+    ///
+    /// Returns `PsiError::InvalidParams` if ciphertext modulus, including response modulus, exceeds the bound at which
+    /// `bfv_degree` is 128-bit secure (see `MAX_MODULUS_BITS_128`). Use a larger BFV degree or a lower eval degree in
+    /// that case. Special moduli of key switching aren't counted towards the bound.
+    pub fn with_derived_moduli(mut self) -> Result<PsiParams, PsiError> {
+        let required_bits = required_modulus_bits(&self)?;
+        // responses are switched to last modulus unless there's a dedicated response modulus
+        let min_last_bits =
+            (self.bfv_plaintext as f64).log2().ceil() as usize + MIN_RESPONSE_MODULUS_MARGIN_BITS;
+        let mut bfv_moduli = derive_moduli(required_bits);
+        if self.response_modulus.is_none() && *bfv_moduli.last().unwrap() < min_last_bits {
+            *bfv_moduli.last_mut().unwrap() = min_last_bits;
+        }
+
+        let total_bits =
+            bfv_moduli.iter().sum::<usize>() + self.response_modulus.unwrap_or_default();
+        let max_bits = max_secure_modulus_bits(self.bfv_degree).ok_or_else(|| {
+            PsiError::InvalidParams(format!(
+                "No security bound for BFV degree {}",
+                self.bfv_degree
+            ))
+        })?;
+        if total_bits > max_bits {
+            return Err(PsiError::InvalidParams(format!(
+                "Evaluation of depth {} requires {total_bits} bits of ciphertext modulus, but BFV degree {} is only 128-bit secure upto {max_bits} bits",
+                evaluation_depth(&self)?,
+                self.bfv_degree
+            )));
+        }
+
+        let largest = *bfv_moduli.iter().max().unwrap();
+        self.hybrid_ksk_moduli = [largest; 3];
+        self.bfv_moduli = bfv_moduli;
+        self.validate()?;
+        Ok(self)
+    }
+
+    pub fn bfv_moduli(&self) -> &[usize] {
+        &self.bfv_moduli
+    }
+
+    pub fn hybrid_ksk_moduli(&self) -> &[usize; 3] {
+        &self.hybrid_ksk_moduli
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_moduli_works() {
+        assert_eq!(derive_moduli(145), vec![49, 48, 48]);
+        assert_eq!(derive_moduli(150), vec![50, 50, 50]);
+        assert_eq!(derive_moduli(151), vec![38, 38, 38, 37]);
+        assert_eq!(derive_moduli(20), vec![20]);
+    }
+
+    #[test]
+    fn derived_moduli_cover_required_bits() {
+        let shallow = PsiParams::default()
+            .with_ps_search(63, 3)
+            .with_derived_moduli()
+            .unwrap();
+        let deep = PsiParams::default().with_derived_moduli().unwrap();
+        let private = PsiParams::default()
+            .with_ps_search(63, 3)
+            .with_circuit_privacy()
+            .with_derived_moduli()
+            .unwrap();
+        for params in [&shallow, &deep, &private] {
+            assert!(
+                params.bfv_moduli().iter().sum::<usize>() >= required_modulus_bits(params).unwrap()
+            );
+        }
+        // smudging noise is budgeted for
+        assert!(
+            required_modulus_bits(&private).unwrap()
+                > required_modulus_bits(&shallow).unwrap() + SMUDGING_SECURITY_BITS
+        );
+        assert_eq!(
+            deep.hybrid_ksk_moduli()[0],
+            *deep.bfv_moduli().iter().max().unwrap()
+        );
+
+        // degree too small for required modulus is rejected
+        assert!(matches!(
+            PsiParams::default()
+                .with_bfv_degree(1 << 10)
+                .with_derived_moduli(),
+            Err(PsiError::InvalidParams(_))
+        ));
+    }
+}
@@ -0,0 +1,182 @@
+use crypto_bigint::{Encoding, U256};
+use curve25519_dalek::{ristretto::CompressedRistretto, RistrettoPoint, Scalar};
+use itertools::{izip, Itertools};
+use rand::{CryptoRng, RngCore};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+use sha2::Sha512;
+
+/// Size of a compressed ristretto point
+pub const OPRF_POINT_BYTES: usize = 32;
+
+fn hash_to_point(item: &U256) -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(&item.to_le_bytes())
+}
+
+/// Hashes `item` along with `point` (ie k * H(item)) to obtain OPRF output
+fn finalize_output(item: &U256, point: &RistrettoPoint) -> U256 {
+    let mut ctx = digest::Context::new(&digest::SHA256);
+    ctx.update(&item.to_le_bytes());
+    ctx.update(point.compress().as_bytes());
+    let digest = ctx.finish();
+
+    let mut bytes = [0u8; 32];
+    bytes.copy_from_slice(digest.as_ref());
+    U256::from_le_bytes(bytes)
+}
+
+fn points_to_bytes(points: &[[u8; OPRF_POINT_BYTES]]) -> Vec<u8> {
+    points.iter().flatten().cloned().collect_vec()
+}
+
+fn points_from_bytes(bytes: &[u8]) -> Option<Vec<[u8; OPRF_POINT_BYTES]>> {
+    if bytes.len() % OPRF_POINT_BYTES != 0 {
+        return None;
+    }
+    Some(
+        bytes
+            .chunks_exact(OPRF_POINT_BYTES)
+            .map(|c| {
+                let mut point = [0u8; OPRF_POINT_BYTES];
+                point.copy_from_slice(c);
+                point
+            })
+            .collect_vec(),
+    )
+}
+
+/// Server's OPRF key. OPRF output for item x is F_k(x) = SHA256(x || k * H(x)), where H hashes to ristretto point.
+///
+/// Server inserts F_k(item) instead of item in Db and client obtains F_k(item) for its items obliviously, without
+/// revealing items to the server and without learning k.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct OprfKey(Scalar);
+
+impl OprfKey {
+    pub fn random<R: RngCore + CryptoRng>(rng: &mut R) -> OprfKey {
+        OprfKey(Scalar::random(rng))
+    }
+
+    /// Evaluates OPRF on server's own item directly
+    pub fn evaluate_item(&self, item: &U256) -> U256 {
+        finalize_output(item, &(hash_to_point(item) * self.0))
+    }
+
+    /// Evaluates OPRF on blinded points sent by client. Returns `None` if any of the points is invalid.
+    pub fn evaluate(&self, request: &OprfRequest) -> Option<OprfResponse> {
+        request
+            .0
+            .iter()
+            .map(|p| {
+                CompressedRistretto(*p)
+                    .decompress()
+                    .map(|p| (p * self.0).compress().to_bytes())
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(OprfResponse)
+    }
+}
+
+/// Blinded items sent by client to server
+#[derive(Debug, PartialEq)]
+pub struct OprfRequest(pub(crate) Vec<[u8; OPRF_POINT_BYTES]>);
+
+impl OprfRequest {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        points_to_bytes(&self.0)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<OprfRequest> {
+        points_from_bytes(bytes).map(OprfRequest)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// Blinded items multiplied with server's OPRF key
+#[derive(Debug, PartialEq)]
+pub struct OprfResponse(pub(crate) Vec<[u8; OPRF_POINT_BYTES]>);
+
+impl OprfResponse {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        points_to_bytes(&self.0)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<OprfResponse> {
+        points_from_bytes(bytes).map(OprfResponse)
+    }
+}
+
+/// Blinding scalars held by client between sending `OprfRequest` and receiving `OprfResponse`
+pub struct OprfBlindState {
+    items: Vec<U256>,
+    blinds: Vec<Scalar>,
+}
+
+/// Blinds each item as r * H(item) with fresh random r
+pub fn oprf_blind<R: RngCore + CryptoRng>(
+    items: &[U256],
+    rng: &mut R,
+) -> (OprfBlindState, OprfRequest) {
+    let blinds = items.iter().map(|_| Scalar::random(rng)).collect_vec();
+    let points = izip!(items.iter(), blinds.iter())
+        .map(|(item, r)| (hash_to_point(item) * r).compress().to_bytes())
+        .collect_vec();
+
+    (
+        OprfBlindState {
+            items: items.to_vec(),
+            blinds,
+        },
+        OprfRequest(points),
+    )
+}
+
+/// Unblinds server's response and returns OPRF outputs in the same order as items passed to `oprf_blind`. Returns
+/// `None` if response is malformed.
+pub fn oprf_finalize(state: &OprfBlindState, response: &OprfResponse) -> Option<Vec<U256>> {
+    if response.0.len() != state.items.len() {
+        return None;
+    }
+
+    izip!(state.items.iter(), state.blinds.iter(), response.0.iter())
+        .map(|(item, r, p)| {
+            let p = CompressedRistretto(*p).decompress()?;
+            Some(finalize_output(item, &(p * r.invert())))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use crate::random_u256;
+
+    use super::*;
+
+    #[test]
+    fn oprf_works() {
+        let mut rng = thread_rng();
+        let key = OprfKey::random(&mut rng);
+
+        let items = (0..10).map(|_| random_u256(&mut rng)).collect_vec();
+
+        let (state, request) = oprf_blind(&items, &mut rng);
+        let request = OprfRequest::from_bytes(&request.to_bytes()).unwrap();
+
+        let response = key.evaluate(&request).unwrap();
+        let response = OprfResponse::from_bytes(&response.to_bytes()).unwrap();
+
+        let outputs = oprf_finalize(&state, &response).unwrap();
+        izip!(items.iter(), outputs.iter()).for_each(|(item, output)| {
+            assert_eq!(&key.evaluate_item(item), output);
+        });
+
+        // different key must give different outputs
+        let other_key = OprfKey::random(&mut rng);
+        assert_ne!(other_key.evaluate_item(&items[0]), outputs[0]);
+    }
+}
@@ -1,8 +1,34 @@
 use std::thread::panicking;
 
 use bfv::Modulus;
+use itertools::{izip, Itertools};
+use rayon::prelude::*;
+
+use crate::{error::PsiError, time_it, InterpolationMethod};
+
+/// Unsigned integer type holding values of `Z_modq`. Points and coefficients of polynomials are stored as `u16` if
+/// plaintext modulus is atmost 2^16, which halves their memory, and as `u32` otherwise. Arithmetic is always done on
+/// u64s.
+pub trait ModqElement:
+    Copy + Default + PartialEq + std::fmt::Debug + Send + Sync + Into<u64>
+{
+    /// Converts `value`, which must be below the modulus, to element
+    fn from_u64(value: u64) -> Self;
+}
 
-use crate::time_it;
+impl ModqElement for u16 {
+    fn from_u64(value: u64) -> u16 {
+        debug_assert!(value <= u16::MAX as u64);
+        value as u16
+    }
+}
+
+impl ModqElement for u32 {
+    fn from_u64(value: u64) -> u32 {
+        debug_assert!(value <= u32::MAX as u64);
+        value as u32
+    }
+}
 
 /// Multiplies a polynomial with a monomial and returns the product.
 ///
@@ -11,26 +37,64 @@ use crate::time_it;
 /// then p'(x) = p(x) (x - a) equals
 /// p'(x) = xp(x) - ap(x)
 /// = [0, c_0, ..., c_{n-1}, c_n] - [ac_0, a_c1, ..., ac_n, 0]
-fn poly_mul_monomial(poly: &mut Vec<u32>, a: u32, modq: &Modulus) {
+fn poly_mul_monomial<T: ModqElement>(poly: &mut Vec<T>, a: T, modq: &Modulus) {
     // make room for another degree
-    poly.push(0);
+    poly.push(T::default());
 
     let degree = poly.len() - 1;
 
     for i in (1..(degree + 1)).rev() {
         // In p'(x) i_th element is p[i-1] - a*p[i] since x*p(x) increases exponent of each
         // element in p(x) by 1
-        poly[i] = modq.sub_mod_fast(
-            poly[i - 1] as u64,
-            modq.mul_mod_fast(a as u64, poly[i] as u64),
-        ) as u32
+        poly[i] = T::from_u64(modq.sub_mod_fast(
+            poly[i - 1].into(),
+            modq.mul_mod_fast(a.into(), poly[i].into()),
+        ))
     }
 
     // process constant separately as -ac_0
-    poly[0] = modq.neg_mod_fast(modq.mul_mod_fast(a as u64, poly[0] as u64)) as u32
+    poly[0] = T::from_u64(modq.neg_mod_fast(modq.mul_mod_fast(a.into(), poly[0].into())))
 }
 
-fn divided_matrix(x: &[u32], y: &[u32], modq: &Modulus) -> Vec<Vec<u32>> {
+/// Replaces each value in `values` with its inverse using Montgomery's trick, ie with a single inversion and
+/// 3(n-1) multiplications. Fails if any value is 0.
+fn batch_inverse(values: &mut [u64], modq: &Modulus) -> Result<(), PsiError> {
+    if values.is_empty() {
+        return Ok(());
+    }
+
+    // prefix[i] = values[0] * ... * values[i]
+    let mut prefix = Vec::with_capacity(values.len());
+    let mut acc = 1u64;
+    for v in values.iter() {
+        if *v == 0 {
+            return Err(PsiError::Interpolation(
+                "Repeated x values with different y values".to_string(),
+            ));
+        }
+        acc = modq.mul_mod_fast(acc, *v);
+        prefix.push(acc);
+    }
+
+    // inverse of product of all values
+    let mut acc_inv = modq.inv(acc);
+    for i in (1..values.len()).rev() {
+        // (v_0 * ... * v_i)^-1 * (v_0 * ... * v_{i-1}) = v_i^-1
+        let v_inv = modq.mul_mod_fast(acc_inv, prefix[i - 1]);
+        acc_inv = modq.mul_mod_fast(acc_inv, values[i]);
+        values[i] = v_inv;
+    }
+    values[0] = acc_inv;
+    Ok(())
+}
+
+/// Constructs divided difference matrix. Denominators of each column are inverted together with `batch_inverse`,
+/// thus each column requires a single modular inversion.
+fn divided_matrix<T: ModqElement>(
+    x: &[T],
+    y: &[T],
+    modq: &Modulus,
+) -> Result<Vec<Vec<T>>, PsiError> {
     let degree = x.len() - 1;
 
     // construct divided difference matrix
@@ -45,58 +109,495 @@ fn divided_matrix(x: &[u32], y: &[u32], modq: &Modulus) -> Vec<Vec<u32>> {
         ddiff[row].push(y[row]);
     }
 
+    let mut denominators = Vec::with_capacity(degree);
     for col in 1..(degree + 1) {
-        for row in 0..((degree + 1) - col) {
+        let rows = (degree + 1) - col;
+
+        // x_k - x_b of all rows in the column
+        denominators.clear();
+        denominators
+            .extend((0..rows).map(|row| modq.sub_mod_fast(x[row + col].into(), x[row].into())));
+        batch_inverse(&mut denominators, modq)?;
+
+        for row in 0..rows {
             // y[k,...,a] in col_{i-1}
-            let y1 = ddiff[row + 1][col - 1] as u64;
+            let y1 = ddiff[row + 1][col - 1].into();
             // y[k-1,...,a,b] in col_{i-1}
-            let y0 = ddiff[row][col - 1] as u64;
+            let y0 = ddiff[row][col - 1].into();
 
             let y1_y0 = modq.sub_mod_fast(y1, y0);
 
-            let x_1_x0 = modq.sub_mod_fast(x[row + col] as u64, x[row] as u64);
-            if x_1_x0 == 0 {
-                panic!("Repeated x values with different y values");
-            }
-            let x1_x0_inv = modq.inv(x_1_x0);
-
             // (y[k,...,a] - y[k-1,...,b])/(x_k - x_b)
-            let v = modq.mul_mod_fast(y1_y0, x1_x0_inv) as u32;
+            let v = T::from_u64(modq.mul_mod_fast(y1_y0, denominators[row]));
 
             ddiff[row].push(v);
         }
     }
-    ddiff
+    Ok(ddiff)
 }
 
-pub fn newton_interpolate(x: &[u32], y: &[u32], modq: u32) -> Vec<u32> {
+/// Returns coefficients of polynomial of least degree that passes through points (x_i, y_i). Fails if `x` contains
+/// repeated values or if `x` and `y` have different lengths.
+pub fn newton_interpolate<T: ModqElement>(x: &[T], y: &[T], modq: u32) -> Result<Vec<T>, PsiError> {
+    if x.len() != y.len() {
+        return Err(PsiError::Interpolation(format!(
+            "{} x values but {} y values",
+            x.len(),
+            y.len()
+        )));
+    }
+
     if x.len() == 0 {
-        return vec![];
+        return Ok(vec![]);
     }
 
     let modq = Modulus::new(modq as u64);
 
-    assert!(x.len() == y.len());
-    let divided_matrix = divided_matrix(x, y, &modq);
+    let divided_matrix = divided_matrix(x, y, &modq)?;
 
     let degree = x.len() - 1;
 
     // apply horner's rule to construct coefficients
-    let mut coefficients = vec![0u32];
+    let mut coefficients = vec![T::default()];
     for i in (1..(degree + 1)).rev() {
         let a_i = divided_matrix[0][i];
-        coefficients[0] = modq.add_mod_fast(coefficients[0] as u64, a_i as u64) as u32;
+        coefficients[0] = T::from_u64(modq.add_mod_fast(coefficients[0].into(), a_i.into()));
 
         // (c_i(x^i) + ... + a_i) * (x - x_{i-1})
         poly_mul_monomial(&mut coefficients, x[i - 1], &modq);
     }
 
     // handle a_0
-    coefficients[0] = modq.add_mod_fast(coefficients[0] as u64, divided_matrix[0][0] as u64) as u32;
+    coefficients[0] =
+        T::from_u64(modq.add_mod_fast(coefficients[0].into(), divided_matrix[0][0].into()));
+
+    Ok(coefficients)
+}
+
+/// Min. no. of values processed by a single rayon task in `newton_interpolate_parallel`
+const PARALLEL_CHUNK_SIZE: usize = 128;
+
+/// Same as `newton_interpolate` but computes each column of divided difference matrix and each step of Horner's rule
+/// across multiple threads. Only worth it when there are fewer polynomials to interpolate than available threads,
+/// otherwise interpolating different polynomials in parallel is faster.
+pub fn newton_interpolate_parallel<T: ModqElement>(
+    x: &[T],
+    y: &[T],
+    modq: u32,
+) -> Result<Vec<T>, PsiError> {
+    if x.len() != y.len() {
+        return Err(PsiError::Interpolation(format!(
+            "{} x values but {} y values",
+            x.len(),
+            y.len()
+        )));
+    }
+
+    if x.len() == 0 {
+        return Ok(vec![]);
+    }
+
+    let modq = Modulus::new(modq as u64);
+    let n = x.len();
+
+    // Only first row of divided difference matrix is required, thus columns are computed one after another, each
+    // from the previous one.
+    let mut newton_coefficients = Vec::with_capacity(n);
+    let mut column = y.iter().map(|v| (*v).into()).collect_vec();
+    newton_coefficients.push(column[0]);
+    for col in 1..n {
+        let mut next_column = vec![0u64; n - col];
+        next_column
+            .par_chunks_mut(PARALLEL_CHUNK_SIZE)
+            .enumerate()
+            .try_for_each(|(chunk_index, chunk)| {
+                let start = chunk_index * PARALLEL_CHUNK_SIZE;
+                chunk.iter_mut().enumerate().for_each(|(offset, v)| {
+                    let row = start + offset;
+                    *v = modq.sub_mod_fast(x[row + col].into(), x[row].into());
+                });
+                batch_inverse(chunk, &modq)?;
+                chunk.iter_mut().enumerate().for_each(|(offset, v)| {
+                    let row = start + offset;
+                    let y1_y0 = modq.sub_mod_fast(column[row + 1], column[row]);
+                    *v = modq.mul_mod_fast(y1_y0, *v);
+                });
+                Ok::<(), PsiError>(())
+            })?;
+        newton_coefficients.push(next_column[0]);
+        column = next_column;
+    }
+
+    // apply horner's rule to construct coefficients
+    let mut coefficients = vec![0u64];
+    let mut product = vec![];
+    for i in (1..n).rev() {
+        coefficients[0] = modq.add_mod_fast(coefficients[0], newton_coefficients[i]);
+
+        // (c_i(x^i) + ... + a_i) * (x - x_{i-1})
+        let a = x[i - 1].into();
+        product.clear();
+        product.resize(coefficients.len() + 1, 0);
+        product
+            .par_chunks_mut(PARALLEL_CHUNK_SIZE)
+            .enumerate()
+            .for_each(|(chunk_index, chunk)| {
+                let start = chunk_index * PARALLEL_CHUNK_SIZE;
+                chunk.iter_mut().enumerate().for_each(|(offset, v)| {
+                    let j = start + offset;
+                    let shifted = if j > 0 { coefficients[j - 1] } else { 0 };
+                    let scaled = match coefficients.get(j) {
+                        Some(c) => modq.mul_mod_fast(a, *c),
+                        None => 0,
+                    };
+                    *v = modq.sub_mod_fast(shifted, scaled);
+                });
+            });
+        std::mem::swap(&mut coefficients, &mut product);
+    }
+
+    // handle a_0
+    coefficients[0] = modq.add_mod_fast(coefficients[0], newton_coefficients[0]);
+
+    Ok(coefficients.into_iter().map(T::from_u64).collect())
+}
+
+/// Below this length polynomials are multiplied and divided with schoolbook algorithms
+const SCHOOLBOOK_THRESHOLD: usize = 64;
+
+/// Arithmetic on polynomials with coefficients modulo prime `q`, stored with constant first. Multiplication uses NTT
+/// whenever `q - 1` is divisible by a large enough power of 2 (for ex, 65537 supports NTT of size upto 2^16).
+struct PolyRing {
+    modq: Modulus,
+    q: u64,
+    /// Largest `k` such that 2^k divides `q - 1`
+    two_adicity: u32,
+    /// Generator of multiplicative group of Z_q
+    generator: u64,
+}
+
+impl PolyRing {
+    fn new(q: u64) -> PolyRing {
+        PolyRing {
+            modq: Modulus::new(q),
+            q,
+            two_adicity: (q - 1).trailing_zeros(),
+            generator: primitive_root(q),
+        }
+    }
+
+    fn pow(&self, mut base: u64, mut exp: u64) -> u64 {
+        let mut res = 1;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                res = self.modq.mul_mod_fast(res, base);
+            }
+            base = self.modq.mul_mod_fast(base, base);
+            exp >>= 1;
+        }
+        res
+    }
+
+    /// In-place NTT of `a` with `root` of order `a.len()`
+    fn ntt(&self, a: &mut [u64], root: u64) {
+        let n = a.len();
+
+        // bit reversal permutation
+        let mut j = 0;
+        for i in 1..n {
+            let mut bit = n >> 1;
+            while j & bit != 0 {
+                j ^= bit;
+                bit >>= 1;
+            }
+            j |= bit;
+            if i < j {
+                a.swap(i, j);
+            }
+        }
+
+        let mut len = 2;
+        while len <= n {
+            let w_len = self.pow(root, (n / len) as u64);
+            for start in (0..n).step_by(len) {
+                let mut w = 1;
+                for k in 0..len / 2 {
+                    let u = a[start + k];
+                    let v = self.modq.mul_mod_fast(a[start + k + len / 2], w);
+                    a[start + k] = self.modq.add_mod_fast(u, v);
+                    a[start + k + len / 2] = self.modq.sub_mod_fast(u, v);
+                    w = self.modq.mul_mod_fast(w, w_len);
+                }
+            }
+            len <<= 1;
+        }
+    }
+
+    fn mul(&self, a: &[u64], b: &[u64]) -> Vec<u64> {
+        if a.is_empty() || b.is_empty() {
+            return vec![];
+        }
+
+        let out_len = a.len() + b.len() - 1;
+        let size = out_len.next_power_of_two();
+        if a.len().min(b.len()) <= SCHOOLBOOK_THRESHOLD || size.trailing_zeros() > self.two_adicity
+        {
+            let mut out = vec![0u64; out_len];
+            for (i, ai) in a.iter().enumerate() {
+                for (j, bj) in b.iter().enumerate() {
+                    out[i + j] = self
+                        .modq
+                        .add_mod_fast(out[i + j], self.modq.mul_mod_fast(*ai, *bj));
+                }
+            }
+            return out;
+        }
+
+        let root = self.pow(self.generator, (self.q - 1) / size as u64);
+        let mut fa = a.to_vec();
+        fa.resize(size, 0);
+        let mut fb = b.to_vec();
+        fb.resize(size, 0);
+        self.ntt(&mut fa, root);
+        self.ntt(&mut fb, root);
+        izip!(fa.iter_mut(), fb.iter()).for_each(|(x, y)| *x = self.modq.mul_mod_fast(*x, *y));
+
+        // inverse NTT
+        self.ntt(&mut fa, self.modq.inv(root));
+        let size_inv = self.modq.inv(size as u64);
+        fa.truncate(out_len);
+        fa.iter_mut()
+            .for_each(|x| *x = self.modq.mul_mod_fast(*x, size_inv));
+        fa
+    }
+
+    /// Returns `f^-1 mod x^k` using Newton iteration. `f[0]` must be non-zero.
+    fn inv_series(&self, f: &[u64], k: usize) -> Vec<u64> {
+        let mut g = vec![self.modq.inv(f[0])];
+        let mut len = 1;
+        while len < k {
+            len *= 2;
+            // g = g(2 - fg) mod x^len
+            let mut h = self.mul(&f[..len.min(f.len())], &g);
+            h.resize(len, 0);
+            h.iter_mut().for_each(|c| *c = self.modq.neg_mod_fast(*c));
+            h[0] = self.modq.add_mod_fast(h[0], 2);
+            g = self.mul(&g, &h);
+            g.truncate(len);
+        }
+        g.truncate(k);
+        g
+    }
+
+    /// Returns `a mod b`. Leading coefficient of `b` must be non-zero.
+    fn rem(&self, a: &[u64], b: &[u64]) -> Vec<u64> {
+        if a.len() < b.len() {
+            return a.to_vec();
+        }
+
+        let m = b.len() - 1;
+        let q_len = a.len() - m;
+        if b.len() <= SCHOOLBOOK_THRESHOLD || q_len <= SCHOOLBOOK_THRESHOLD {
+            // long division
+            let lead_inv = self.modq.inv(b[m]);
+            let mut r = a.to_vec();
+            for i in (m..r.len()).rev() {
+                let c = self.modq.mul_mod_fast(r[i], lead_inv);
+                if c == 0 {
+                    continue;
+                }
+                for j in 0..=m {
+                    r[i - m + j] = self
+                        .modq
+                        .sub_mod_fast(r[i - m + j], self.modq.mul_mod_fast(c, b[j]));
+                }
+            }
+            r.truncate(m);
+            return r;
+        }
+
+        // quotient of reversed polynomials is power series division, thus can be computed with `inv_series`
+        let a_rev = a.iter().rev().take(q_len).copied().collect_vec();
+        let b_rev = b.iter().rev().copied().collect_vec();
+        let mut quotient = self.mul(&a_rev, &self.inv_series(&b_rev, q_len));
+        quotient.truncate(q_len);
+        quotient.reverse();
+
+        let bq = self.mul(b, &quotient);
+        (0..m)
+            .map(|i| self.modq.sub_mod_fast(a[i], bq[i]))
+            .collect()
+    }
+}
+
+/// Returns a generator of multiplicative group of Z_q for prime `q`
+fn primitive_root(q: u64) -> u64 {
+    // prime factors of q - 1
+    let mut factors = vec![];
+    let mut n = q - 1;
+    let mut p = 2;
+    while p * p <= n {
+        if n % p == 0 {
+            factors.push(p);
+            while n % p == 0 {
+                n /= p;
+            }
+        }
+        p += 1;
+    }
+    if n > 1 {
+        factors.push(n);
+    }
 
+    let modq = Modulus::new(q);
+    let pow = |mut base: u64, mut exp: u64| {
+        let mut res = 1;
+        while exp > 0 {
+            if exp & 1 == 1 {
+                res = modq.mul_mod_fast(res, base);
+            }
+            base = modq.mul_mod_fast(base, base);
+            exp >>= 1;
+        }
+        res
+    };
+    (2..q)
+        .find(|g| factors.iter().all(|f| pow(*g, (q - 1) / f) != 1))
+        .unwrap()
+}
+
+/// Binary tree where each node holds product of (x - x_i) of all points x_i under it
+struct SubproductTree {
+    poly: Vec<u64>,
+    /// No. of points under the node
+    points: usize,
+    children: Option<Box<(SubproductTree, SubproductTree)>>,
+}
+
+impl SubproductTree {
+    fn new(x: &[u64], ring: &PolyRing) -> SubproductTree {
+        if x.len() == 1 {
+            return SubproductTree {
+                poly: vec![ring.modq.neg_mod_fast(x[0]), 1],
+                points: 1,
+                children: None,
+            };
+        }
+
+        let mid = x.len() / 2;
+        let left = SubproductTree::new(&x[..mid], ring);
+        let right = SubproductTree::new(&x[mid..], ring);
+        SubproductTree {
+            poly: ring.mul(&left.poly, &right.poly),
+            points: x.len(),
+            children: Some(Box::new((left, right))),
+        }
+    }
+
+    /// Evaluates `f` at all points under the node and appends evaluations to `out` in order of points
+    fn evaluate(&self, f: &[u64], ring: &PolyRing, out: &mut Vec<u64>) {
+        let r = ring.rem(f, &self.poly);
+        match &self.children {
+            None => out.push(r.first().copied().unwrap_or(0)),
+            Some(children) => {
+                children.0.evaluate(&r, ring, out);
+                children.1.evaluate(&r, ring, out);
+            }
+        }
+    }
+
+    /// Returns sum of w_i * (product of (x - x_j) for j != i) over points under the node
+    fn linear_combination(&self, w: &[u64], ring: &PolyRing) -> Vec<u64> {
+        match &self.children {
+            None => vec![w[0]],
+            Some(children) => {
+                let (left, right) = children.as_ref();
+                let left_comb = left.linear_combination(&w[..left.points], ring);
+                let right_comb = right.linear_combination(&w[left.points..], ring);
+
+                let mut a = ring.mul(&left_comb, &right.poly);
+                let b = ring.mul(&right_comb, &left.poly);
+                if a.len() < b.len() {
+                    a.resize(b.len(), 0);
+                }
+                izip!(a.iter_mut(), b.iter())
+                    .for_each(|(x, y)| *x = ring.modq.add_mod_fast(*x, *y));
+                a
+            }
+        }
+    }
+}
+
+/// Returns coefficients of polynomial of least degree that passes through points (x_i, y_i) using subproduct tree
+/// (ie Lagrange interpolation with fast multipoint evaluation). Takes O(n log^2 n) operations with NTT based
+/// multiplication instead of O(n^2) of `newton_interpolate`, thus is faster for large degrees. Output is identical to
+/// `newton_interpolate`.
+pub fn tree_interpolate<T: ModqElement>(x: &[T], y: &[T], modq: u32) -> Result<Vec<T>, PsiError> {
+    if x.len() != y.len() {
+        return Err(PsiError::Interpolation(format!(
+            "{} x values but {} y values",
+            x.len(),
+            y.len()
+        )));
+    }
+
+    if x.len() == 0 {
+        return Ok(vec![]);
+    }
+
+    let ring = PolyRing::new(modq as u64);
+    let x = x.iter().map(|v| (*v).into() % ring.q).collect_vec();
+    let tree = SubproductTree::new(&x, &ring);
+
+    // m'(x_i) = product of (x_i - x_j) for j != i
+    let derivative = tree.poly[1..]
+        .iter()
+        .enumerate()
+        .map(|(i, c)| ring.modq.mul_mod_fast(*c, (i + 1) as u64 % ring.q))
+        .collect_vec();
+    let mut weights = Vec::with_capacity(x.len());
+    tree.evaluate(&derivative, &ring, &mut weights);
+
+    // w_i = y_i / m'(x_i). m'(x_i) is 0 only if x_i is repeated.
+    batch_inverse(&mut weights, &ring.modq)?;
+    izip!(weights.iter_mut(), y.iter())
+        .for_each(|(w, y)| *w = ring.modq.mul_mod_fast(*w, (*y).into()));
+
+    let mut coefficients = tree
+        .linear_combination(&weights, &ring)
+        .into_iter()
+        .map(T::from_u64)
+        .collect_vec();
+    coefficients.resize(x.len(), T::default());
+    Ok(coefficients)
+}
+
+/// Interpolates polynomial through points (x_i, y_i) with `method`
+pub fn interpolate<T: ModqElement>(
+    x: &[T],
+    y: &[T],
+    modq: u32,
+    method: InterpolationMethod,
+) -> Result<Vec<T>, PsiError> {
+    match method {
+        InterpolationMethod::Newton => newton_interpolate(x, y, modq),
+        InterpolationMethod::SubproductTree => tree_interpolate(x, y, modq),
+    }
+}
+
+/// Returns coefficients of monic polynomial (x - r_0)(x - r_1)...(x - r_{n-1}) with `roots` r_i.
+/// With no roots returns constant polynomial 1.
+pub fn poly_from_roots<T: ModqElement>(roots: &[T], modq: u32) -> Vec<T> {
+    let modq = Modulus::new(modq as u64);
+
+    let mut coefficients = vec![T::from_u64(1)];
+    roots.iter().for_each(|r| {
+        poly_mul_monomial(&mut coefficients, *r, &modq);
+    });
     coefficients
 }
 
+#[cfg(test)]
 pub fn evaluate_poly(x: u32, coeffs: &[u32], modq: u32) -> u32 {
     let modq = Modulus::new(modq as u64);
     let mut y = 0;
@@ -110,22 +611,104 @@ pub fn evaluate_poly(x: u32, coeffs: &[u32], modq: u32) -> u32 {
 
 #[cfg(test)]
 mod tests {
-    use itertools::Itertools;
     use rand::{distributions::Uniform, thread_rng, Rng};
 
     use super::*;
 
+    /// Constructs divided difference matrix with one modular inversion per cell. Reference for `divided_matrix`.
+    fn divided_matrix_per_cell_inversion(
+        x: &[u32],
+        y: &[u32],
+        modq: &Modulus,
+    ) -> Result<Vec<Vec<u32>>, PsiError> {
+        let degree = x.len() - 1;
+
+        // construct divided difference matrix
+        let mut ddiff = Vec::with_capacity(degree + 1);
+        // We don't need an exact matrix since only upper triangle will hold values
+        for i in (1..(degree + 1 + 1)).rev() {
+            ddiff.push(Vec::with_capacity(i));
+        }
+
+        // process 0^th column
+        for row in 0..degree + 1 {
+            ddiff[row].push(y[row]);
+        }
+
+        for col in 1..(degree + 1) {
+            for row in 0..((degree + 1) - col) {
+                // y[k,...,a] in col_{i-1}
+                let y1 = ddiff[row + 1][col - 1] as u64;
+                // y[k-1,...,a,b] in col_{i-1}
+                let y0 = ddiff[row][col - 1] as u64;
+
+                let y1_y0 = modq.sub_mod_fast(y1, y0);
+
+                let x_1_x0 = modq.sub_mod_fast(x[row + col] as u64, x[row] as u64);
+                if x_1_x0 == 0 {
+                    return Err(PsiError::Interpolation(
+                        "Repeated x values with different y values".to_string(),
+                    ));
+                }
+                let x1_x0_inv = modq.inv(x_1_x0);
+
+                // (y[k,...,a] - y[k-1,...,b])/(x_k - x_b)
+                let v = modq.mul_mod_fast(y1_y0, x1_x0_inv) as u32;
+
+                ddiff[row].push(v);
+            }
+        }
+        Ok(ddiff)
+    }
+
     #[test]
     fn divided_difference_matrix_correct() {
         let x = vec![1, 2, 3, 4, 5, 6];
         let y: Vec<u32> = vec![1, 4, 2, 4, 1, 4];
-        let matrix = divided_matrix(&x, &y, &Modulus::new(65537));
+        let matrix = divided_matrix(&x, &y, &Modulus::new(65537)).unwrap();
         println!("{:?}", matrix);
     }
 
+    #[test]
+    fn batch_inverse_works() {
+        let modq = Modulus::new(65537);
+        let mut rng = thread_rng();
+        let values = (0..100).map(|_| rng.gen_range(1..65537u64)).collect_vec();
+
+        let mut inverses = values.clone();
+        batch_inverse(&mut inverses, &modq).unwrap();
+        izip!(values.iter(), inverses.iter()).for_each(|(v, v_inv)| {
+            assert_eq!(*v_inv, modq.inv(*v));
+        });
+
+        let mut with_zero = vec![3, 0, 5];
+        assert!(batch_inverse(&mut with_zero, &modq).is_err());
+    }
+
+    #[test]
+    fn bench_divided_matrix_batch_inversion() {
+        let mut rng = thread_rng();
+        let modq = Modulus::new(65537);
+        let degree = 1300;
+
+        let mut x = vec![];
+        let mut y: Vec<u32> = vec![];
+        while x.len() != degree {
+            let tmp_x = rng.gen::<u32>() % 65537;
+            if !x.contains(&tmp_x) {
+                x.push(tmp_x);
+                y.push(rng.gen::<u32>() % 65537);
+            }
+        }
+
+        time_it!("Divided matrix with per cell inversion", let expected = divided_matrix_per_cell_inversion(&x, &y, &modq).unwrap(););
+        time_it!("Divided matrix with batch inversion", let matrix = divided_matrix(&x, &y, &modq).unwrap(););
+        assert_eq!(matrix, expected);
+    }
+
     #[test]
     fn poly_mul_monomial_works() {
-        let mut x = vec![1, 4, 2, 4, 2, 4, 56, 6];
+        let mut x = vec![1u32, 4, 2, 4, 2, 4, 56, 6];
         let modq = Modulus::new(65537);
 
         poly_mul_monomial(&mut x, 3, &modq);
@@ -151,7 +734,7 @@ mod tests {
         }
 
         for _ in 0..100 {
-            time_it!("Newton Interpolate", let coeffs = newton_interpolate(&x, &y, modq););
+            time_it!("Newton Interpolate", let coeffs = newton_interpolate(&x, &y, modq).unwrap(););
 
             for i in 0..degree {
                 let y_res = evaluate_poly(x[i], &coeffs, modq);
@@ -160,6 +743,151 @@ mod tests {
         }
     }
 
+    #[test]
+    fn newton_interpolate_rejects_repeated_x() {
+        assert!(matches!(
+            newton_interpolate(&[1u32, 2, 1], &[3, 4, 5], 65537),
+            Err(PsiError::Interpolation(_))
+        ));
+        assert!(matches!(
+            newton_interpolate(&[1u32, 2], &[3], 65537),
+            Err(PsiError::Interpolation(_))
+        ));
+    }
+
+    #[test]
+    fn newton_interpolate_parallel_matches_newton() {
+        let mut rng = thread_rng();
+        let modq = 65537;
+
+        for degree in [1, 2, 200, 1305] {
+            let mut x = vec![];
+            let mut y: Vec<u32> = vec![];
+            while x.len() != degree {
+                let tmp_x = rng.gen::<u32>() % modq;
+                if !x.contains(&tmp_x) {
+                    x.push(tmp_x);
+                    y.push(rng.gen::<u32>() % modq);
+                }
+            }
+
+            time_it!("Newton Interpolate", let expected = newton_interpolate(&x, &y, modq).unwrap(););
+            time_it!("Parallel Newton Interpolate", let coeffs = newton_interpolate_parallel(&x, &y, modq).unwrap(););
+            assert_eq!(coeffs, expected);
+        }
+
+        assert!(matches!(
+            newton_interpolate_parallel(&[1u32, 2, 1], &[3, 4, 5], modq),
+            Err(PsiError::Interpolation(_))
+        ));
+    }
+
+    #[test]
+    fn u16_interpolation_matches_u32() {
+        let mut rng = thread_rng();
+        // NTT friendly prime below 2^16
+        let modq = 40961;
+
+        let mut x = vec![];
+        let mut y: Vec<u32> = vec![];
+        while x.len() != 300 {
+            let tmp_x = rng.gen::<u32>() % modq;
+            if !x.contains(&tmp_x) {
+                x.push(tmp_x);
+                y.push(rng.gen::<u32>() % modq);
+            }
+        }
+        let x16 = x.iter().map(|v| *v as u16).collect_vec();
+        let y16 = y.iter().map(|v| *v as u16).collect_vec();
+        let widen = |c: Vec<u16>| c.into_iter().map(u32::from).collect_vec();
+
+        let expected = newton_interpolate(&x, &y, modq).unwrap();
+        assert_eq!(
+            widen(newton_interpolate(&x16, &y16, modq).unwrap()),
+            expected
+        );
+        assert_eq!(
+            widen(newton_interpolate_parallel(&x16, &y16, modq).unwrap()),
+            expected
+        );
+        assert_eq!(widen(tree_interpolate(&x16, &y16, modq).unwrap()), expected);
+        assert_eq!(
+            widen(poly_from_roots(&x16, modq)),
+            poly_from_roots(&x, modq)
+        );
+    }
+
+    #[test]
+    fn tree_interpolate_matches_newton() {
+        let mut rng = thread_rng();
+        let modq = 65537;
+
+        // small degrees use schoolbook multiplication, large use NTT
+        for degree in [1, 2, 7, 100, 1305] {
+            let mut x = vec![];
+            let mut y: Vec<u32> = vec![];
+            while x.len() != degree {
+                let tmp_x = rng.gen::<u32>() % modq;
+                if !x.contains(&tmp_x) {
+                    x.push(tmp_x);
+                    y.push(rng.gen::<u32>() % modq);
+                }
+            }
+
+            time_it!("Newton Interpolate", let expected = newton_interpolate(&x, &y, modq).unwrap(););
+            time_it!("Tree Interpolate", let coeffs = tree_interpolate(&x, &y, modq).unwrap(););
+            assert_eq!(coeffs, expected);
+        }
+
+        assert!(matches!(
+            tree_interpolate(&[1u32, 2, 1], &[3, 4, 5], modq),
+            Err(PsiError::Interpolation(_))
+        ));
+    }
+
+    #[test]
+    fn poly_ring_works() {
+        let mut rng = thread_rng();
+        let ring = PolyRing::new(65537);
+        let a = (0..300).map(|_| rng.gen_range(0..65537u64)).collect_vec();
+        let mut b = (0..200).map(|_| rng.gen_range(0..65537u64)).collect_vec();
+        b.push(1);
+
+        // NTT product matches schoolbook product
+        let product = ring.mul(&a, &b);
+        let mut expected = vec![0u64; a.len() + b.len() - 1];
+        for (i, ai) in a.iter().enumerate() {
+            for (j, bj) in b.iter().enumerate() {
+                expected[i + j] = (expected[i + j] + ai * bj) % 65537;
+            }
+        }
+        assert_eq!(product, expected);
+
+        // (a * b + r) mod b = r
+        let r = (0..(b.len() - 1))
+            .map(|_| rng.gen_range(0..65537u64))
+            .collect_vec();
+        let mut dividend = product.clone();
+        izip!(dividend.iter_mut(), r.iter()).for_each(|(d, r)| *d = (*d + r) % 65537);
+        assert_eq!(ring.rem(&dividend, &b), r);
+    }
+
+    #[test]
+    fn poly_from_roots_works() {
+        let mut rng = thread_rng();
+        let modq = 65537;
+
+        let roots = (0..100).map(|_| rng.gen::<u32>() % modq).collect_vec();
+        let coeffs = poly_from_roots(&roots, modq);
+        assert_eq!(coeffs.len(), roots.len() + 1);
+
+        roots.iter().for_each(|r| {
+            assert_eq!(evaluate_poly(*r, &coeffs, modq), 0);
+        });
+
+        assert_eq!(poly_from_roots::<u32>(&[], modq), vec![1]);
+    }
+
     #[test]
     fn exp() {
         let modq = Modulus::new(65537);
@@ -0,0 +1,96 @@
+//! Public API of the crate. Items re-exported here are the ones external clients, servers and tools are expected to
+//! use and are kept stable across releases. The crate root exports exactly these items; everything else is internal.
+
+// params
+pub use crate::{
+    dag::{DagStrategy, PowersDag},
+    hash::HashBackend,
+    layout::QueryLayout,
+    moduli::{max_secure_modulus_bits, MAX_MODULUS_BITS_128},
+    InterpolationMethod, PsiMode, PsiParams, PRESET_BFV_DEGREES, PRESET_SERVER_SIZES,
+};
+
+// hashing
+pub use crate::{
+    hash::{achievable_load_factor, random_u256, Cuckoo},
+    import::item_from_bytes,
+    shard::shard_of,
+};
+
+// client
+#[cfg(feature = "sync")]
+pub use crate::blocking_client::BlockingPsiClient;
+#[cfg(feature = "http")]
+pub use crate::http_client::HttpPsiClient;
+pub use crate::{
+    client::{
+        construct_plaintext_query, construct_queries, construct_query, dedup_response_labels,
+        encrypt_query, max_query_items, measure_response_noise, process_query_response,
+        process_sharded_query_response, process_unlabeled_query_response, HashTableQuery,
+        NoiseBudget, PlaintextQuery, PotentialResponseLabels, QueryState, ResponseLabel,
+        StreamingResponseDecryptor, MIN_NOISE_BUDGET_BITS,
+    },
+    protocol::{ClientId, CLIENT_ID_BYTES},
+    psi_client::{PsiClient, QuerySubmission},
+    utils::{gen_bfv_params, generate_evaluation_key},
+};
+
+// keys
+#[cfg(feature = "keyring")]
+pub use crate::keys::KeyringKeyStore;
+pub use crate::keys::{
+    deserialize_public_key, deserialize_secret_key, serialize_public_key, serialize_secret_key,
+    FileKeyStore, PublicKey, QueryEncryptionKey, SecretKeyStore, PUBLIC_KEY_ZERO_CTS,
+};
+
+// server
+#[cfg(feature = "rocksdb")]
+pub use crate::server::RocksDbItemStore;
+#[cfg(feature = "sqlite")]
+pub use crate::server::SqliteItemStore;
+pub use crate::{
+    client::{Query, QueryBatch},
+    server::{
+        estimate_cost, read_deltas, run_bench, write_deltas, AuthError, BenchReport,
+        BincodeItemStore, CancelOnDrop, CancellationToken, ClientKey, Db, DbDelta, DbKey, DbStats,
+        DeltaSummary, EvaluationKeyCache, HashTableQueryResponse, InsertError, ItemLabel,
+        ItemStore, Label, LatencyPercentiles, Partition, ProgressSink, QueryLimiter, QueryResponse,
+        QueryStage, QueryValidator, RefreshHandle, Server, SetupStage, Tenant, Tenants,
+        ThreadPools, TokenId, TokenStore, DEFAULT_TENANT, ITEM_STORE_BATCH_SIZE,
+        MAX_TENANT_ID_BYTES, SMUDGING_SECURITY_BITS,
+    },
+    shard::{partition_item_labels, ShardCoordinator},
+};
+
+// import
+pub use crate::import::{import_item_labels, ImportFormat, ImportOptions, ValueEncoding};
+
+// serialize
+pub use crate::serialize::{
+    compress, decompress, deserialize_evaluation_key, deserialize_query, deserialize_query_batch,
+    deserialize_query_response, deserialize_query_state, deserialize_segment_response,
+    expected_public_key_query_bytes, expected_query_bytes, expected_response_bytes,
+    serialize_query, serialize_query_batch, serialize_query_response, serialize_query_state,
+    serialize_segment_response, QueryMetadata, SerializedQueryResponse,
+};
+
+// protocol
+#[cfg(feature = "quic")]
+pub use crate::quic::{quic_client_config, quic_server_config, QuicStream};
+pub use crate::{
+    oprf::{OprfRequest, OPRF_POINT_BYTES},
+    protocol::{
+        write_frame, BusyReason, Frame, FrameReader, MessageType, CAPABILITY_DB_STATS,
+        CAPABILITY_METADATA, CAPABILITY_ZSTD, HTTP_TENANT_HEADER,
+    },
+    tls::{tls_acceptor, tls_connector},
+    transport::InMemoryTransport,
+};
+
+// testing
+pub use crate::utils::{
+    gen_random_item_labels, generate_random_intersection_and_store, seeded_rng,
+};
+
+// errors
+pub use crate::{error::PsiError, protocol::ProtocolError};
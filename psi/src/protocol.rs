@@ -0,0 +1,739 @@
+use rand::{CryptoRng, RngCore};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Magic bytes at the start of every frame
+pub const PROTOCOL_MAGIC: &[u8; 4] = b"ULPS";
+/// Bumped whenever encoding of any message changes. Peers reject frames with a different version.
+pub const PROTOCOL_VERSION: u16 = 7;
+/// magic (4 bytes) || version (u16 LE) || message type (u8) || request id (u32 LE) || payload length (u64 LE)
+pub const FRAME_HEADER_BYTES: usize = 4 + 2 + 1 + 4 + 8;
+/// Max. payload size accepted in a single frame. Bounds the largest legitimate frame, an unstreamed query response
+/// (responses to larger dbs are streamed in `MessageType::QueryResponseSegment` frames). Server limits frames received
+/// from clients much further, see `QueryValidator::max_frame_bytes`.
+pub const MAX_FRAME_BYTES: u64 = 1 << 30;
+/// Max. payload size of frames that carry neither queries, responses nor keys, for ex. `MessageType::Hello`
+pub const MAX_CONTROL_FRAME_BYTES: u64 = 1 << 16;
+/// Capability flag in `MessageType::Hello`. When enabled, serialized queries and responses are zstd compressed.
+pub const CAPABILITY_ZSTD: u8 = 1;
+/// Capability flag in `MessageType::Hello`. When enabled, server returns `QueryMetadata` with every query response.
+pub const CAPABILITY_METADATA: u8 = 2;
+/// Capability flag in `MessageType::Hello`. When enabled, server appends bincode serialized `DbStats` of the selected
+/// tenant's db to its `Hello`. Unlike other flags, it only applies to the `Hello` that asks for it.
+pub const CAPABILITY_DB_STATS: u8 = 4;
+/// Header of HTTP requests that selects tenant whose db is queried, same as `MessageType::Tenant`. Requests without
+/// the header are served by `DEFAULT_TENANT`.
+pub const HTTP_TENANT_HEADER: &str = "x-psi-tenant";
+
+#[derive(Debug, PartialEq)]
+pub enum ProtocolError {
+    /// Frame does not start with `PROTOCOL_MAGIC`
+    BadMagic,
+    UnsupportedVersion(u16),
+    UnknownMessageType(u8),
+    FrameTooLarge {
+        length: u64,
+        limit: u64,
+    },
+    /// Frame is well formed but its payload isn't what was expected
+    InvalidMessage(String),
+    /// Peer responded with `MessageType::Error`
+    Remote(String),
+    /// Server responded with `MessageType::Busy`. Request may be retried after `retry_after`.
+    Busy {
+        reason: BusyReason,
+        retry_after: Duration,
+    },
+    Io(String),
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::BadMagic => write!(f, "Frame does not start with protocol magic bytes"),
+            ProtocolError::UnsupportedVersion(v) => write!(
+                f,
+                "Unsupported protocol version {v}, expected {PROTOCOL_VERSION}"
+            ),
+            ProtocolError::UnknownMessageType(t) => write!(f, "Unknown message type {t}"),
+            ProtocolError::FrameTooLarge { length, limit } => {
+                write!(f, "Frame of {length} bytes exceeds limit of {limit} bytes")
+            }
+            ProtocolError::InvalidMessage(e) => write!(f, "Invalid message: {e}"),
+            ProtocolError::Remote(e) => write!(f, "Peer returned error: {e}"),
+            ProtocolError::Busy {
+                reason,
+                retry_after,
+            } => write!(
+                f,
+                "Server is busy ({reason}). Retry after {} ms",
+                retry_after.as_millis()
+            ),
+            ProtocolError::Io(e) => write!(f, "I/O error: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+/// Reason server rejected a request with `MessageType::Busy`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BusyReason {
+    /// Server is processing max. no. of concurrent queries and max. no. of queries are already waiting
+    Overloaded = 0,
+    /// Client exceeded its max. no. of queries per minute
+    RateLimited = 1,
+}
+
+impl std::fmt::Display for BusyReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusyReason::Overloaded => write!(f, "too many queries in progress"),
+            BusyReason::RateLimited => write!(f, "rate limit exceeded"),
+        }
+    }
+}
+
+impl TryFrom<u8> for BusyReason {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        match value {
+            0 => Ok(BusyReason::Overloaded),
+            1 => Ok(BusyReason::RateLimited),
+            _ => Err(ProtocolError::InvalidMessage(format!(
+                "Unknown busy reason {value}"
+            ))),
+        }
+    }
+}
+
+impl From<std::io::Error> for ProtocolError {
+    fn from(value: std::io::Error) -> Self {
+        ProtocolError::Io(value.to_string())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageType {
+    /// `ClientId` || serialized `Query`
+    Query = 0,
+    /// Bincode serialized `SerializedQueryResponse`
+    QueryResponse = 1,
+    /// `OprfRequest` bytes
+    OprfRequest = 2,
+    /// `OprfResponse` bytes
+    OprfResponse = 3,
+    /// `ClientId` || protobuf encoded client `EvaluationKey`
+    EvaluationKey = 4,
+    /// Acknowledges a message that has no response
+    Ack = 5,
+    /// UTF-8 error message. Sender closes the connection after sending it.
+    Error = 6,
+    /// Server does not have evaluation key of the client. Client must upload it and resend the query.
+    EvaluationKeyRequired = 7,
+    /// Same as `Query` but server streams response as `QueryResponseSegment` frames followed by `QueryResponseEnd`
+    StreamedQuery = 8,
+    /// Response of single segment serialized with `serialize_segment_response`
+    QueryResponseSegment = 9,
+    /// Marks end of streamed response. Carries bincode serialized `QueryMetadata` if `CAPABILITY_METADATA` is enabled,
+    /// otherwise empty.
+    QueryResponseEnd = 10,
+    /// Capability flags (u8) supported by client. Server responds with `Hello` carrying flags enabled for rest of the
+    /// connection, ie flags supported by both, followed by `DbStats` if `CAPABILITY_DB_STATS` is enabled.
+    Hello = 11,
+    /// UTF-8 API token of client. Server responds with `Ack` or `Error` if token is invalid. Server that requires
+    /// authentication rejects all other requests, except `Hello`, until client authenticates.
+    Auth = 12,
+    /// UTF-8 id of tenant that subsequent requests of the connection are served by (see `Tenants`). Server responds
+    /// with `Ack` or `Error` if it does not host the tenant. Connections start with `DEFAULT_TENANT`.
+    Tenant = 13,
+    /// Sent by `ShardCoordinator` in response to `Query`. Carries bincode serialized `Vec<Vec<u8>>` of `QueryResponse`
+    /// payload of each shard.
+    ShardedQueryResponse = 14,
+    /// Server rejected the request without processing it, either because it is overloaded or because client exceeded
+    /// its rate limit. Carries `BusyReason` (u8) || milliseconds after which client may retry (u64 LE). Unlike
+    /// `Error`, connection stays open.
+    Busy = 15,
+    /// `ClientId` || `QueryBatch` serialized with `serialize_query_batch`. Server responds with `QueryBatchResponse`.
+    QueryBatch = 16,
+    /// Bincode serialized `Vec<Vec<u8>>` of `QueryResponse` payload of each query of `QueryBatch`, in order
+    QueryBatchResponse = 17,
+    /// Sent by `ShardCoordinator` in response to `StreamedQuery`. Shard index (u32 LE) || `QueryResponseSegment`
+    /// payload of the shard. Segments of all shards are followed by a single `QueryResponseEnd`.
+    ShardQueryResponseSegment = 18,
+}
+
+impl TryFrom<u8> for MessageType {
+    type Error = ProtocolError;
+
+    fn try_from(value: u8) -> Result<Self, ProtocolError> {
+        let message_type = match value {
+            0 => MessageType::Query,
+            1 => MessageType::QueryResponse,
+            2 => MessageType::OprfRequest,
+            3 => MessageType::OprfResponse,
+            4 => MessageType::EvaluationKey,
+            5 => MessageType::Ack,
+            6 => MessageType::Error,
+            7 => MessageType::EvaluationKeyRequired,
+            8 => MessageType::StreamedQuery,
+            9 => MessageType::QueryResponseSegment,
+            10 => MessageType::QueryResponseEnd,
+            11 => MessageType::Hello,
+            12 => MessageType::Auth,
+            13 => MessageType::Tenant,
+            14 => MessageType::ShardedQueryResponse,
+            15 => MessageType::Busy,
+            16 => MessageType::QueryBatch,
+            17 => MessageType::QueryBatchResponse,
+            18 => MessageType::ShardQueryResponseSegment,
+            _ => return Err(ProtocolError::UnknownMessageType(value)),
+        };
+        Ok(message_type)
+    }
+}
+
+pub const CLIENT_ID_BYTES: usize = 16;
+
+/// Random identifier chosen by client. Server caches client's evaluation key under it, so that the key, which is
+/// large, is uploaded only once instead of with every query.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ClientId(pub [u8; CLIENT_ID_BYTES]);
+
+impl ClientId {
+    pub fn random<R: RngCore + CryptoRng>(rng: &mut R) -> ClientId {
+        let mut id = [0u8; CLIENT_ID_BYTES];
+        rng.fill_bytes(&mut id);
+        ClientId(id)
+    }
+
+    /// Returns `ClientId || bytes`
+    pub fn prefix(&self, bytes: &[u8]) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(CLIENT_ID_BYTES + bytes.len());
+        payload.extend_from_slice(&self.0);
+        payload.extend_from_slice(bytes);
+        payload
+    }
+
+    /// Splits payload prefixed with `ClientId` into the id and rest of the payload
+    pub fn split_prefix(payload: &[u8]) -> Result<(ClientId, &[u8]), ProtocolError> {
+        if payload.len() < CLIENT_ID_BYTES {
+            return Err(ProtocolError::InvalidMessage(
+                "Payload is missing client id".to_string(),
+            ));
+        }
+        let mut id = [0u8; CLIENT_ID_BYTES];
+        id.copy_from_slice(&payload[..CLIENT_ID_BYTES]);
+        Ok((ClientId(id), &payload[CLIENT_ID_BYTES..]))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FrameHeader {
+    pub message_type: MessageType,
+    pub request_id: u32,
+    pub length: u64,
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_BYTES] {
+        let mut bytes = [0u8; FRAME_HEADER_BYTES];
+        bytes[..4].copy_from_slice(PROTOCOL_MAGIC);
+        bytes[4..6].copy_from_slice(&PROTOCOL_VERSION.to_le_bytes());
+        bytes[6] = self.message_type as u8;
+        bytes[7..11].copy_from_slice(&self.request_id.to_le_bytes());
+        bytes[11..].copy_from_slice(&self.length.to_le_bytes());
+        bytes
+    }
+
+    /// Validates magic, version, message type and length of the header
+    pub fn from_bytes(bytes: &[u8; FRAME_HEADER_BYTES]) -> Result<FrameHeader, ProtocolError> {
+        if &bytes[..4] != PROTOCOL_MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+
+        let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let message_type = MessageType::try_from(bytes[6])?;
+        let request_id = u32::from_le_bytes([bytes[7], bytes[8], bytes[9], bytes[10]]);
+
+        let mut length_bytes = [0u8; 8];
+        length_bytes.copy_from_slice(&bytes[11..]);
+        let length = u64::from_le_bytes(length_bytes);
+        if length > MAX_FRAME_BYTES {
+            return Err(ProtocolError::FrameTooLarge {
+                length,
+                limit: MAX_FRAME_BYTES,
+            });
+        }
+
+        Ok(FrameHeader {
+            message_type,
+            request_id,
+            length,
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub message_type: MessageType,
+    /// Chosen by client for each request and echoed by server in every frame of the response, thus client can have
+    /// multiple requests in flight on a single connection and match responses, which may arrive in any order, to
+    /// requests. Server processes requests with id `0` in order, one at a time, whereas queries with other ids are
+    /// processed concurrently.
+    pub request_id: u32,
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new(message_type: MessageType, payload: Vec<u8>) -> Frame {
+        Frame {
+            message_type,
+            request_id: 0,
+            payload,
+        }
+    }
+
+    pub fn with_request_id(mut self, request_id: u32) -> Frame {
+        self.request_id = request_id;
+        self
+    }
+
+    /// Header sent ahead of payload
+    pub fn header(&self) -> FrameHeader {
+        FrameHeader {
+            message_type: self.message_type,
+            request_id: self.request_id,
+            length: self.payload.len() as u64,
+        }
+    }
+
+    pub fn error(message: &str) -> Frame {
+        Frame::new(MessageType::Error, message.as_bytes().to_vec())
+    }
+
+    pub fn busy(reason: BusyReason, retry_after: Duration) -> Frame {
+        let mut payload = vec![reason as u8];
+        payload.extend_from_slice(&(retry_after.as_millis() as u64).to_le_bytes());
+        Frame::new(MessageType::Busy, payload)
+    }
+
+    /// Returns payload if frame is of `message_type`. Converts `MessageType::Error` frame to `ProtocolError::Remote`
+    /// and `MessageType::Busy` frame to `ProtocolError::Busy`.
+    pub fn into_payload(self, message_type: MessageType) -> Result<Vec<u8>, ProtocolError> {
+        if self.message_type == message_type {
+            Ok(self.payload)
+        } else if self.message_type == MessageType::Error {
+            Err(ProtocolError::Remote(
+                String::from_utf8_lossy(&self.payload).to_string(),
+            ))
+        } else if self.message_type == MessageType::Busy {
+            if self.payload.len() != 9 {
+                return Err(ProtocolError::InvalidMessage(
+                    "Busy payload must be 9 bytes".to_string(),
+                ));
+            }
+            let mut millis = [0u8; 8];
+            millis.copy_from_slice(&self.payload[1..]);
+            Err(ProtocolError::Busy {
+                reason: BusyReason::try_from(self.payload[0])?,
+                retry_after: Duration::from_millis(u64::from_le_bytes(millis)),
+            })
+        } else {
+            Err(ProtocolError::InvalidMessage(format!(
+                "Expected {:?} but received {:?}",
+                message_type, self.message_type
+            )))
+        }
+    }
+}
+
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), ProtocolError> {
+    writer.write_all(&frame.header().to_bytes()).await?;
+    writer.write_all(&frame.payload).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Same as `write_frame` but on a blocking writer
+#[cfg(feature = "sync")]
+pub fn write_frame_blocking<W: std::io::Write>(
+    writer: &mut W,
+    frame: &Frame,
+) -> Result<(), ProtocolError> {
+    writer.write_all(&frame.header().to_bytes())?;
+    writer.write_all(&frame.payload)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads next frame. Returns `None` if peer closed the connection before sending another frame. Rejects frame if its
+/// payload length exceeds `limit(message_type)`. Length is checked before payload is allocated, thus peer can't make
+/// the reader allocate more than the limit.
+pub async fn read_frame_with_limit<R: AsyncRead + Unpin, F: Fn(MessageType) -> u64>(
+    reader: &mut R,
+    limit: F,
+) -> Result<Option<Frame>, ProtocolError> {
+    let mut header_bytes = [0u8; FRAME_HEADER_BYTES];
+    let mut filled = 0;
+    while filled < FRAME_HEADER_BYTES {
+        let n = reader.read(&mut header_bytes[filled..]).await?;
+        if n == 0 {
+            if filled == 0 {
+                return Ok(None);
+            }
+            return Err(ProtocolError::Io(
+                "Connection closed in the middle of frame header".to_string(),
+            ));
+        }
+        filled += n;
+    }
+
+    let header = FrameHeader::from_bytes(&header_bytes)?;
+    let max_length = limit(header.message_type);
+    if header.length > max_length {
+        return Err(ProtocolError::FrameTooLarge {
+            length: header.length,
+            limit: max_length,
+        });
+    }
+    let mut payload = vec![0u8; header.length as usize];
+    reader.read_exact(&mut payload).await?;
+
+    Ok(Some(
+        Frame::new(header.message_type, payload).with_request_id(header.request_id),
+    ))
+}
+
+/// Reads frames, same as `read_frame_with_limit`, but keeps bytes read so far across calls. Thus, unlike
+/// `read_frame_with_limit`, reading is cancel safe, ie it can be raced against other futures, for ex. writing to the
+/// same connection, without losing part of a frame.
+pub struct FrameReader<R> {
+    reader: R,
+    /// Bytes of frames that are yet to be read completely
+    buffer: Vec<u8>,
+}
+
+/// Bytes read from a blocking reader at once, see `FrameReader::read_blocking_with_limit`
+#[cfg(feature = "sync")]
+const BLOCKING_READ_BYTES: usize = 64 * 1024;
+
+impl<R> FrameReader<R> {
+    pub fn new(reader: R) -> FrameReader<R> {
+        FrameReader {
+            reader,
+            buffer: vec![],
+        }
+    }
+
+    /// Underlying reader, for ex. to write to a connection that is both read and written. Reading from it directly
+    /// skips bytes buffered by `FrameReader`.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    /// Removes frame at the front of buffer and returns it, if it is read completely
+    fn take_frame<F: Fn(MessageType) -> u64>(
+        &mut self,
+        limit: &F,
+    ) -> Result<Option<Frame>, ProtocolError> {
+        if self.buffer.len() < FRAME_HEADER_BYTES {
+            return Ok(None);
+        }
+        let mut header_bytes = [0u8; FRAME_HEADER_BYTES];
+        header_bytes.copy_from_slice(&self.buffer[..FRAME_HEADER_BYTES]);
+        let header = FrameHeader::from_bytes(&header_bytes)?;
+        let max_length = limit(header.message_type);
+        if header.length > max_length {
+            return Err(ProtocolError::FrameTooLarge {
+                length: header.length,
+                limit: max_length,
+            });
+        }
+
+        let frame_bytes = FRAME_HEADER_BYTES + header.length as usize;
+        if self.buffer.len() < frame_bytes {
+            self.buffer.reserve(frame_bytes - self.buffer.len());
+            return Ok(None);
+        }
+        let payload = self.buffer[FRAME_HEADER_BYTES..frame_bytes].to_vec();
+        self.buffer.drain(..frame_bytes);
+        Ok(Some(
+            Frame::new(header.message_type, payload).with_request_id(header.request_id),
+        ))
+    }
+}
+
+impl<R: AsyncRead + Unpin> FrameReader<R> {
+    /// Reads next frame. Returns `None` if peer closed the connection before sending another frame. Rejects frame if
+    /// its payload length exceeds `limit(message_type)`, same as `read_frame_with_limit`.
+    pub async fn read_with_limit<F: Fn(MessageType) -> u64>(
+        &mut self,
+        limit: F,
+    ) -> Result<Option<Frame>, ProtocolError> {
+        loop {
+            if let Some(frame) = self.take_frame(&limit)? {
+                return Ok(Some(frame));
+            }
+            // cancel safe, unlike `read_exact`
+            if self.reader.read_buf(&mut self.buffer).await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ProtocolError::Io(
+                    "Connection closed in the middle of frame".to_string(),
+                ));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+impl<R: std::io::Read> FrameReader<R> {
+    /// Same as `read_with_limit` but on a blocking reader
+    pub fn read_blocking_with_limit<F: Fn(MessageType) -> u64>(
+        &mut self,
+        limit: F,
+    ) -> Result<Option<Frame>, ProtocolError> {
+        let mut bytes = vec![0u8; BLOCKING_READ_BYTES];
+        loop {
+            if let Some(frame) = self.take_frame(&limit)? {
+                return Ok(Some(frame));
+            }
+            let n = self.reader.read(&mut bytes)?;
+            if n == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                return Err(ProtocolError::Io(
+                    "Connection closed in the middle of frame".to_string(),
+                ));
+            }
+            self.buffer.extend_from_slice(&bytes[..n]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_header_works() {
+        let header = FrameHeader {
+            message_type: MessageType::Query,
+            request_id: 42,
+            length: 1234,
+        };
+        let bytes = header.to_bytes();
+        assert_eq!(FrameHeader::from_bytes(&bytes).unwrap(), header);
+
+        let mut bad_magic = bytes;
+        bad_magic[0] = 0;
+        assert_eq!(
+            FrameHeader::from_bytes(&bad_magic),
+            Err(ProtocolError::BadMagic)
+        );
+
+        let mut bad_version = bytes;
+        bad_version[4] = 1;
+        assert_eq!(
+            FrameHeader::from_bytes(&bad_version),
+            Err(ProtocolError::UnsupportedVersion(1))
+        );
+
+        let mut hello = bytes;
+        hello[6] = 11;
+        assert_eq!(
+            FrameHeader::from_bytes(&hello).unwrap().message_type,
+            MessageType::Hello
+        );
+
+        let mut bad_type = bytes;
+        bad_type[6] = 100;
+        assert_eq!(
+            FrameHeader::from_bytes(&bad_type),
+            Err(ProtocolError::UnknownMessageType(100))
+        );
+    }
+
+    #[test]
+    fn client_id_prefix_works() {
+        let id = ClientId::random(&mut rand::thread_rng());
+        let payload = id.prefix(&[1, 2, 3]);
+        assert_eq!(
+            ClientId::split_prefix(&payload).unwrap(),
+            (id, &[1u8, 2, 3][..])
+        );
+        assert!(ClientId::split_prefix(&payload[..10]).is_err());
+    }
+
+    #[tokio::test]
+    async fn multiple_frames_per_connection() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let frames = vec![
+            Frame::new(MessageType::EvaluationKey, vec![1; 100]),
+            Frame::new(MessageType::Query, vec![2; 3000]).with_request_id(7),
+            Frame::error("bad query").with_request_id(7),
+        ];
+
+        let writer = async {
+            for frame in frames.iter() {
+                write_frame(&mut client, frame).await.unwrap();
+            }
+            drop(client);
+        };
+        let reader = async {
+            let mut received = vec![];
+            while let Some(frame) = read_frame_with_limit(&mut server, |_| MAX_FRAME_BYTES)
+                .await
+                .unwrap()
+            {
+                received.push(frame);
+            }
+            received
+        };
+        let (_, received) = tokio::join!(writer, reader);
+        assert_eq!(received, frames);
+
+        assert_eq!(
+            Frame::error("bad query").into_payload(MessageType::QueryResponse),
+            Err(ProtocolError::Remote("bad query".to_string()))
+        );
+        assert_eq!(
+            Frame::busy(BusyReason::RateLimited, Duration::from_millis(1500))
+                .into_payload(MessageType::QueryResponse),
+            Err(ProtocolError::Busy {
+                reason: BusyReason::RateLimited,
+                retry_after: Duration::from_millis(1500)
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn frame_reader_keeps_partial_frame_when_cancelled() {
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut reader = FrameReader::new(server);
+
+        let frames = vec![
+            Frame::new(MessageType::Query, vec![3; 100]).with_request_id(5),
+            Frame::new(MessageType::Ack, vec![]).with_request_id(6),
+        ];
+        let mut bytes = vec![];
+        for frame in &frames {
+            write_frame(&mut bytes, frame).await.unwrap();
+        }
+
+        // cancelled after reading part of the header
+        client.write_all(&bytes[..10]).await.unwrap();
+        assert!(tokio::time::timeout(
+            Duration::from_millis(10),
+            reader.read_with_limit(|_| MAX_FRAME_BYTES)
+        )
+        .await
+        .is_err());
+        // cancelled after reading part of the payload
+        client.write_all(&bytes[10..50]).await.unwrap();
+        assert!(tokio::time::timeout(
+            Duration::from_millis(10),
+            reader.read_with_limit(|_| MAX_FRAME_BYTES)
+        )
+        .await
+        .is_err());
+
+        client.write_all(&bytes[50..]).await.unwrap();
+        drop(client);
+        assert_eq!(
+            reader
+                .read_with_limit(|_| MAX_FRAME_BYTES)
+                .await
+                .unwrap()
+                .as_ref(),
+            Some(&frames[0])
+        );
+        assert_eq!(
+            reader
+                .read_with_limit(|_| MAX_FRAME_BYTES)
+                .await
+                .unwrap()
+                .as_ref(),
+            Some(&frames[1])
+        );
+        assert_eq!(
+            reader.read_with_limit(|_| MAX_FRAME_BYTES).await.unwrap(),
+            None
+        );
+    }
+
+    #[cfg(feature = "sync")]
+    #[test]
+    fn blocking_frame_reader_works() {
+        let frames = vec![
+            Frame::new(MessageType::Query, vec![4; 3000]).with_request_id(9),
+            Frame::new(MessageType::Ack, vec![]),
+        ];
+        let mut bytes = vec![];
+        for frame in &frames {
+            write_frame_blocking(&mut bytes, frame).unwrap();
+        }
+
+        let mut reader = FrameReader::new(bytes.as_slice());
+        for frame in &frames {
+            assert_eq!(
+                reader
+                    .read_blocking_with_limit(|_| MAX_FRAME_BYTES)
+                    .unwrap()
+                    .as_ref(),
+                Some(frame)
+            );
+        }
+        assert_eq!(
+            reader.read_blocking_with_limit(|_| MAX_FRAME_BYTES),
+            Ok(None)
+        );
+
+        // limit is checked as in `read_with_limit`, and so is a truncated frame
+        let mut reader = FrameReader::new(bytes.as_slice());
+        assert_eq!(
+            reader.read_blocking_with_limit(|_| 100),
+            Err(ProtocolError::FrameTooLarge {
+                length: 3000,
+                limit: 100
+            })
+        );
+        let mut reader = FrameReader::new(&bytes[..FRAME_HEADER_BYTES + 10]);
+        assert!(matches!(
+            reader.read_blocking_with_limit(|_| MAX_FRAME_BYTES),
+            Err(ProtocolError::Io(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn read_frame_rejects_oversized_payload() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let frame = Frame::new(MessageType::Query, vec![0; 100]);
+        write_frame(&mut client, &frame).await.unwrap();
+
+        let limit = |message_type| match message_type {
+            MessageType::Query => 99,
+            _ => MAX_FRAME_BYTES,
+        };
+        assert_eq!(
+            read_frame_with_limit(&mut server, limit).await,
+            Err(ProtocolError::FrameTooLarge {
+                length: 100,
+                limit: 99
+            })
+        );
+    }
+}
@@ -0,0 +1,566 @@
+use bfv::{Evaluator, SecretKey};
+use crypto_bigint::U256;
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Mutex,
+    },
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Notify,
+};
+use tokio_rustls::{client::TlsStream, TlsConnector};
+
+use crate::{
+    decompress, deserialize_segment_response, expected_response_bytes,
+    merge_potential_response_labels, oprf_outputs, split_shard_index, tls_server_name, write_frame,
+    ClientCore, ClientId, DbStats, Frame, FrameReader, MessageType, PotentialResponseLabels,
+    ProtocolError, PsiError, PsiParams, QueryMetadata, QueryState, StreamingResponseDecryptor,
+    CAPABILITY_DB_STATS, CAPABILITY_METADATA, CAPABILITY_ZSTD, DEFAULT_MAX_BATCH_QUERIES,
+    MAX_FRAME_BYTES,
+};
+
+/// How `PsiClient::send_queries` submits multiple queries
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum QuerySubmission {
+    /// Each query is sent once response to the previous one is received
+    #[default]
+    Sequential,
+    /// All queries are sent without waiting for responses, which are read as they arrive (see `PsiClient::submit`).
+    /// Server processes queries concurrently, upto its max. no. of concurrent queries, and responds to each as soon as
+    /// it is processed.
+    Pipelined,
+    /// Queries are sent in batches of at most `DEFAULT_MAX_BATCH_QUERIES` queries, each in a single request (see
+    /// `PsiClient::send_query_batch`). Saves round trips and evaluation key lookups, but responses to a batch are
+    /// only read once server processed all of its queries.
+    Batched,
+}
+
+/// Client connected to a PSI server. Queries are sent over a single connection and server caches client's evaluation
+/// key under `ClientId`, thus the key is uploaded only when server asks for it.
+pub struct PsiClient<S = TcpStream> {
+    reader: tokio::sync::Mutex<FrameReader<ReadHalf<S>>>,
+    writer: tokio::sync::Mutex<WriteHalf<S>>,
+    /// Frames read while waiting for response to another request, by request id. Frames of requests whose response is
+    /// never awaited stay here for the lifetime of the client.
+    received: Mutex<HashMap<u32, VecDeque<Frame>>>,
+    /// Notified whenever a frame is added to `received`
+    received_notify: Notify,
+    /// Id of the next request submitted with `submit`. Requests sent with id `0` are processed in order by server.
+    next_request_id: AtomicU32,
+    core: ClientCore,
+    /// Metadata returned with response to the last query
+    last_metadata: Option<QueryMetadata>,
+    /// Layout of server's db, as of the last `fetch_db_stats`
+    db_stats: Option<DbStats>,
+}
+
+impl PsiClient<TcpStream> {
+    /// Connects to server at `addr`. `client_id` must change whenever `sk` changes, otherwise server may use stale
+    /// evaluation key cached for the client.
+    pub async fn connect<A: ToSocketAddrs>(
+        addr: A,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> Result<PsiClient<TcpStream>, PsiError> {
+        let stream = TcpStream::connect(addr).await?;
+        Ok(PsiClient::from_stream(stream, psi_params, sk, client_id))
+    }
+
+    /// Same as `connect` but wraps connection in TLS. Server's certificate must be valid for `domain` and issued by a
+    /// certificate authority trusted by `connector` (see `tls_connector`).
+    pub async fn connect_tls<A: ToSocketAddrs>(
+        addr: A,
+        domain: &str,
+        connector: &TlsConnector,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> Result<PsiClient<TlsStream<TcpStream>>, PsiError> {
+        let stream = TcpStream::connect(addr).await?;
+        let stream = connector
+            .connect(tls_server_name(domain)?, stream)
+            .await
+            .map_err(|e| PsiError::Tls(format!("Handshake failed: {e}")))?;
+        Ok(PsiClient::from_stream(stream, psi_params, sk, client_id))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> PsiClient<S> {
+    /// Creates client over an already established connection to server
+    pub fn from_stream(
+        stream: S,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> PsiClient<S> {
+        let (reader, writer) = tokio::io::split(stream);
+        PsiClient {
+            reader: tokio::sync::Mutex::new(FrameReader::new(reader)),
+            writer: tokio::sync::Mutex::new(writer),
+            received: Mutex::new(HashMap::new()),
+            received_notify: Notify::new(),
+            next_request_id: AtomicU32::new(1),
+            core: ClientCore::new(psi_params, sk, client_id),
+            last_metadata: None,
+            db_stats: None,
+        }
+    }
+
+    /// Seeds randomness of the client, thus queries are reproducible byte for byte. Only meant for tests and bug
+    /// reports, since anyone who knows the seed can reproduce randomness of query ciphertexts.
+    pub fn with_seed(mut self, seed: u64) -> PsiClient<S> {
+        self.core = self.core.with_seed(seed);
+        self
+    }
+
+    pub fn psi_params(&self) -> &PsiParams {
+        &self.core.psi_params
+    }
+
+    pub fn evaluator(&self) -> &Evaluator {
+        &self.core.evaluator
+    }
+
+    pub fn client_id(&self) -> &ClientId {
+        &self.core.client_id
+    }
+
+    pub fn compression(&self) -> bool {
+        self.core.compression
+    }
+
+    /// Asks server to zstd compress queries and responses for rest of the connection. Returns whether server agreed.
+    /// Worth it only for bandwidth constrained clients, since ciphertexts are almost uniformly random and compress
+    /// modestly.
+    pub async fn enable_compression(&mut self) -> Result<bool, PsiError> {
+        self.hello(self.core.capabilities() | CAPABILITY_ZSTD)
+            .await?;
+        Ok(self.core.compression)
+    }
+
+    /// Asks server to return `QueryMetadata` with responses for rest of the connection. Returns whether server agreed.
+    /// Metadata of the last query is available with `last_metadata`.
+    pub async fn enable_metadata(&mut self) -> Result<bool, PsiError> {
+        self.hello(self.core.capabilities() | CAPABILITY_METADATA)
+            .await?;
+        Ok(self.core.metadata)
+    }
+
+    /// Server side timing breakdown of the last query. `None` unless metadata is enabled with `enable_metadata`.
+    pub fn last_metadata(&self) -> Option<&QueryMetadata> {
+        self.last_metadata.as_ref()
+    }
+
+    /// Asks server for layout of its db (of the selected tenant). Returns `None` if server does not share it, for ex.
+    /// sharded servers.
+    pub async fn fetch_db_stats(&mut self) -> Result<Option<&DbStats>, PsiError> {
+        self.hello(self.core.capabilities() | CAPABILITY_DB_STATS)
+            .await?;
+        Ok(self.db_stats.as_ref())
+    }
+
+    /// Size of uncompressed response to a query, without metadata, as of the last `fetch_db_stats`. See
+    /// `expected_response_bytes`.
+    pub fn expected_response_bytes(&self) -> Option<usize> {
+        self.db_stats.as_ref().map(|db_stats| {
+            expected_response_bytes(&self.core.psi_params, &self.core.evaluator, db_stats)
+        })
+    }
+
+    /// Sends capability `flags`, which replace flags sent before, and enables flags server agreed to. Stores
+    /// `DbStats` if server sent them.
+    async fn hello(&mut self, flags: u8) -> Result<(), PsiError> {
+        let frame = Frame::new(MessageType::Hello, vec![flags]);
+        let payload = self.send(&frame).await?.into_payload(MessageType::Hello)?;
+        if let Some(db_stats) = self.core.process_hello(&payload)? {
+            self.db_stats = Some(db_stats);
+        }
+        Ok(())
+    }
+
+    /// Authenticates with API token `token`. Required before any other request if server requires authentication.
+    pub async fn authenticate(&mut self, token: &str) -> Result<(), PsiError> {
+        let frame = Frame::new(MessageType::Auth, token.as_bytes().to_vec());
+        self.send(&frame).await?.into_payload(MessageType::Ack)?;
+        Ok(())
+    }
+
+    /// Selects tenant `tenant_id` of server for rest of the connection. Client's `PsiParams` must match the tenant's.
+    /// Evaluation key is uploaded to each tenant separately, on first query.
+    pub async fn select_tenant(&mut self, tenant_id: &str) -> Result<(), PsiError> {
+        let frame = Frame::new(MessageType::Tenant, tenant_id.as_bytes().to_vec());
+        self.send(&frame).await?.into_payload(MessageType::Ack)?;
+        Ok(())
+    }
+
+    /// Sends `frame` and returns server's response
+    async fn send(&self, frame: &Frame) -> Result<Frame, ProtocolError> {
+        self.write(frame).await?;
+        self.receive(frame.request_id).await
+    }
+
+    /// Writes `frame` while reading frames of requests in flight, thus neither client nor server blocks on a full
+    /// socket buffer
+    async fn write(&self, frame: &Frame) -> Result<(), ProtocolError> {
+        let mut writer = self.writer.lock().await;
+        let write = write_frame(&mut *writer, frame);
+        tokio::pin!(write);
+        let mut connected = true;
+        loop {
+            tokio::select! {
+                written = &mut write => return written,
+                read = self.read_into_received(), if connected => connected = read?,
+            }
+        }
+    }
+
+    /// Returns next frame of request `request_id`. Frames of other requests read meanwhile are kept for them.
+    async fn receive(&self, request_id: u32) -> Result<Frame, ProtocolError> {
+        loop {
+            // wakes once another request reads a frame, which may be of this request
+            let notified = self.received_notify.notified();
+            if let Some(frame) = self.take_received(request_id) {
+                return Ok(frame);
+            }
+            tokio::select! {
+                read = self.read_into_received() => {
+                    if !read? {
+                        return self
+                            .take_received(request_id)
+                            .ok_or(ProtocolError::Io("Server closed connection".to_string()));
+                    }
+                }
+                _ = notified => {}
+            }
+        }
+    }
+
+    fn take_received(&self, request_id: u32) -> Option<Frame> {
+        self.received
+            .lock()
+            .unwrap()
+            .get_mut(&request_id)?
+            .pop_front()
+    }
+
+    /// Reads next frame into `received`. Returns `false` if server closed the connection.
+    async fn read_into_received(&self) -> Result<bool, ProtocolError> {
+        let mut reader = self.reader.lock().await;
+        let frame = match reader.read_with_limit(|_| MAX_FRAME_BYTES).await? {
+            Some(frame) => frame,
+            None => return Ok(false),
+        };
+        self.received
+            .lock()
+            .unwrap()
+            .entry(frame.request_id)
+            .or_default()
+            .push_back(frame);
+        self.received_notify.notify_waiters();
+        Ok(true)
+    }
+
+    /// Returns id of a request that server processes concurrently with other requests, ie any id but `0`
+    fn new_request_id(&self) -> u32 {
+        loop {
+            let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+            if request_id != 0 {
+                return request_id;
+            }
+        }
+    }
+
+    /// Uploads evaluation key to server. Evaluation key is generated on first upload.
+    pub async fn upload_keys(&mut self) -> Result<(), PsiError> {
+        let frame = Frame::new(
+            MessageType::EvaluationKey,
+            self.core.evaluation_key_payload(),
+        );
+        self.send(&frame).await?.into_payload(MessageType::Ack)?;
+        Ok(())
+    }
+
+    /// Obtains OPRF outputs of `items` from server without revealing `items`, if `PsiParams::oprf` is enabled.
+    /// Returns `None` otherwise.
+    async fn oprf(&mut self, items: &[U256]) -> Result<Option<Vec<U256>>, PsiError> {
+        if !self.core.psi_params.oprf() {
+            return Ok(None);
+        }
+        let (blind_state, request) = self.core.oprf_request(items);
+        let frame = Frame::new(MessageType::OprfRequest, request);
+        let response_bytes = self
+            .send(&frame)
+            .await?
+            .into_payload(MessageType::OprfResponse)?;
+        oprf_outputs(&blind_state, &response_bytes).map(Some)
+    }
+
+    /// Constructs query for `items`. Runs OPRF round with server first if `PsiParams::oprf` is enabled.
+    pub async fn construct_query(&mut self, items: &[U256]) -> Result<QueryState, PsiError> {
+        let oprf_outputs = self.oprf(items).await?;
+        Ok(self.core.construct_query(items, oprf_outputs.as_deref()))
+    }
+
+    /// Constructs as many queries as required to query all `items` (see `construct_queries`). Runs a single OPRF round
+    /// for all items if `PsiParams::oprf` is enabled.
+    pub async fn construct_queries(&mut self, items: &[U256]) -> Result<Vec<QueryState>, PsiError> {
+        let oprf_outputs = self.oprf(items).await?;
+        Ok(self.core.construct_queries(items, oprf_outputs.as_deref()))
+    }
+
+    /// Sends each of `query_states` with `submission` and returns potential labels of items of all queries.
+    /// `last_metadata` is metadata of the last query.
+    pub async fn send_queries(
+        &mut self,
+        query_states: &[QueryState],
+        submission: QuerySubmission,
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let mut responses = vec![];
+        match submission {
+            QuerySubmission::Sequential => {
+                for query_state in query_states {
+                    responses.extend(self.send_query(query_state).await?);
+                }
+            }
+            QuerySubmission::Pipelined => {
+                let (first, rest) = match query_states.split_first() {
+                    Some(split) => split,
+                    None => return Ok(responses),
+                };
+                // uploads evaluation key if server asks for it, before rest of the queries are sent
+                responses.extend(self.send_query(first).await?);
+                responses.extend(self.send_queries_pipelined(rest).await?);
+            }
+            QuerySubmission::Batched => {
+                for batch in query_states.chunks(DEFAULT_MAX_BATCH_QUERIES) {
+                    responses.extend(self.send_query_batch(batch).await?);
+                }
+            }
+        }
+        Ok(responses)
+    }
+
+    /// Submits all queries before awaiting their responses (see `submit`). `last_metadata` is metadata of the last
+    /// query.
+    async fn send_queries_pipelined(
+        &mut self,
+        query_states: &[QueryState],
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let mut pending = Vec::with_capacity(query_states.len());
+        for query_state in query_states {
+            pending.push(self.submit_query(query_state).await?);
+        }
+        let mut potential_labels = vec![];
+        let mut last_metadata = None;
+        // responses to later queries read meanwhile are kept for them
+        for response in pending {
+            let (labels, metadata) = response.await?;
+            potential_labels.extend(labels);
+            last_metadata = metadata;
+        }
+        self.last_metadata = last_metadata;
+        Ok(potential_labels)
+    }
+
+    /// Sends query in `query_state` with a new request id and returns a future that resolves to potential labels of
+    /// items placed in its hash tables once server responds. Thus any no. of queries can be in flight on the
+    /// connection at the same time. Server processes them concurrently, upto its max. no. of concurrent queries, and
+    /// responds to each as soon as it is processed. Futures may be awaited in any order, or concurrently, for ex. with
+    /// `tokio::join!`, since each picks response to its own query.
+    ///
+    /// Items on `QueryState::hash_table_stack` aren't queried, thus queries should be constructed with
+    /// `construct_queries`. Evaluation key must have been uploaded before, for ex. with `upload_keys`, whereas
+    /// server asking for it again, for ex. once it is evicted from server's cache, is handled. `last_metadata` isn't
+    /// updated.
+    pub async fn submit<'a>(
+        &'a self,
+        query_state: &'a QueryState,
+    ) -> Result<impl Future<Output = Result<Vec<PotentialResponseLabels>, PsiError>> + 'a, PsiError>
+    {
+        let response = self.submit_query(query_state).await?;
+        Ok(async move { response.await.map(|(potential_labels, _)| potential_labels) })
+    }
+
+    /// Same as `submit` but response also resolves to `QueryMetadata` of the query, if any
+    async fn submit_query<'a>(
+        &'a self,
+        query_state: &'a QueryState,
+    ) -> Result<
+        impl Future<Output = Result<(Vec<PotentialResponseLabels>, Option<QueryMetadata>), PsiError>>
+            + 'a,
+        PsiError,
+    > {
+        let frame = Frame::new(MessageType::Query, self.core.query_payload(query_state))
+            .with_request_id(self.new_request_id());
+        self.write(&frame).await?;
+
+        Ok(async move {
+            let mut response = self.receive(frame.request_id).await?;
+            if response.message_type == MessageType::EvaluationKeyRequired {
+                let ek_payload =
+                    self.core
+                        .generated_evaluation_key_payload()
+                        .ok_or(PsiError::Protocol(ProtocolError::InvalidMessage(
+                            "Evaluation key must be uploaded before queries are submitted"
+                                .to_string(),
+                        )))?;
+                let ek_frame = Frame::new(MessageType::EvaluationKey, ek_payload)
+                    .with_request_id(frame.request_id);
+                self.send(&ek_frame).await?.into_payload(MessageType::Ack)?;
+                response = self.send(&frame).await?;
+            }
+            self.core
+                .process_query_response_frame(query_state, response)
+        })
+    }
+
+    /// Sends queries in `query_states` in a single `MessageType::QueryBatch` request and returns potential labels of
+    /// items of all queries. `last_metadata` is metadata of the last query. Server rejects batches of more than
+    /// `QueryValidator::max_batch_queries` queries.
+    pub async fn send_query_batch(
+        &mut self,
+        query_states: &[QueryState],
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let frame = Frame::new(
+            MessageType::QueryBatch,
+            self.core.query_batch_payload(query_states),
+        );
+
+        let mut response = self.send(&frame).await?;
+        if response.message_type == MessageType::EvaluationKeyRequired {
+            self.upload_keys().await?;
+            response = self.send(&frame).await?;
+        }
+        let payloads: Vec<Vec<u8>> =
+            bincode::deserialize(&response.into_payload(MessageType::QueryBatchResponse)?)?;
+        if payloads.len() != query_states.len() {
+            return Err(PsiError::Protocol(ProtocolError::InvalidMessage(format!(
+                "Expected responses to {} queries, found {}",
+                query_states.len(),
+                payloads.len()
+            ))));
+        }
+
+        let mut potential_labels = vec![];
+        for (query_state, payload) in query_states.iter().zip(&payloads) {
+            let (query_response, metadata) = self.core.deserialize_response(payload)?;
+            self.last_metadata = metadata;
+            potential_labels.extend(self.core.process_response(query_state, &[query_response]));
+        }
+        Ok(potential_labels)
+    }
+
+    /// Sends query in `query_state` and returns potential labels of each queried item. Items are mapped back to
+    /// original items if query was constructed with OPRF.
+    ///
+    /// Items that could not be placed in any hash table (ie `QueryState::hash_table_stack`) are not part of the query,
+    /// thus aren't present in the returned vector.
+    pub async fn send_query(
+        &mut self,
+        query_state: &QueryState,
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let frame = Frame::new(MessageType::Query, self.core.query_payload(query_state));
+
+        let mut response = self.send(&frame).await?;
+        if response.message_type == MessageType::EvaluationKeyRequired {
+            self.upload_keys().await?;
+            response = self.send(&frame).await?;
+        }
+        let (potential_labels, metadata) = self
+            .core
+            .process_query_response_frame(query_state, response)?;
+        self.last_metadata = metadata;
+        Ok(potential_labels)
+    }
+
+    /// Same as `send_query` but server streams response of each segment as soon as it is processed. Segments are
+    /// deserialized as they arrive, which overlaps download and deserialization with server's processing.
+    ///
+    /// `ShardCoordinator` streams segments of all shards. Response of each shard is decrypted separately and potential
+    /// labels are merged, same as `process_sharded_query_response`.
+    pub async fn send_query_streamed(
+        &mut self,
+        query_state: &QueryState,
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let frame = Frame::new(
+            MessageType::StreamedQuery,
+            self.core.query_payload(query_state),
+        );
+
+        let mut response = self.send(&frame).await?;
+        if response.message_type == MessageType::EvaluationKeyRequired {
+            self.upload_keys().await?;
+            response = self.send(&frame).await?;
+        }
+
+        // segments are decrypted as they arrive and their ciphertexts dropped right away. Unsharded server only
+        // streams segments of shard 0.
+        let core = &self.core;
+        let mut decryptors = BTreeMap::from([(
+            0,
+            StreamingResponseDecryptor::new(&core.psi_params, query_state),
+        )]);
+        while response.message_type != MessageType::QueryResponseEnd {
+            let (shard, mut segment_bytes) = if response.message_type
+                == MessageType::ShardQueryResponseSegment
+            {
+                split_shard_index(response.into_payload(MessageType::ShardQueryResponseSegment)?)?
+            } else {
+                (0, response.into_payload(MessageType::QueryResponseSegment)?)
+            };
+            if core.compression {
+                segment_bytes = decompress(&segment_bytes, MAX_FRAME_BYTES as usize)?;
+            }
+            let (big_box, segment, cts) =
+                deserialize_segment_response(&segment_bytes, &core.psi_params, &core.evaluator)?;
+            decryptors
+                .entry(shard)
+                .or_insert_with(|| StreamingResponseDecryptor::new(&core.psi_params, query_state))
+                .add_segment(big_box, segment, &cts, &core.evaluator, &core.sk)?;
+            response = self.receive(0).await?;
+        }
+        let shard_labels = decryptors
+            .into_values()
+            .map(StreamingResponseDecryptor::finish)
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut potential_labels = merge_potential_response_labels(shard_labels);
+        potential_labels
+            .iter_mut()
+            .for_each(|labels| labels.item = *query_state.original_item(&labels.item));
+
+        let metadata_bytes = response.into_payload(MessageType::QueryResponseEnd)?;
+        self.last_metadata = if metadata_bytes.is_empty() {
+            None
+        } else {
+            Some(bincode::deserialize(&metadata_bytes)?)
+        };
+
+        Ok(potential_labels)
+    }
+
+    /// Queries `items` and returns potential labels of each item. Items that don't fit in a single query are split
+    /// across multiple queries, which are sent sequentially (see `query_with_submission`).
+    pub async fn query(
+        &mut self,
+        items: &[U256],
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        self.query_with_submission(items, QuerySubmission::Sequential)
+            .await
+    }
+
+    /// Same as `query` but queries are sent with `submission`
+    pub async fn query_with_submission(
+        &mut self,
+        items: &[U256],
+        submission: QuerySubmission,
+    ) -> Result<Vec<PotentialResponseLabels>, PsiError> {
+        let query_states = self.construct_queries(items).await?;
+        self.send_queries(&query_states, submission).await
+    }
+}
@@ -0,0 +1,211 @@
+use bfv::SecretKey;
+use quinn::{Connection, Endpoint, RecvStream, SendStream};
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::{self, version::TLS13};
+
+use crate::{
+    parse_certificates, parse_private_key, root_cert_store, ClientId, PsiClient, PsiError,
+    PsiParams,
+};
+
+/// ALPN protocol negotiated by QUIC peers, thus server rejects QUIC clients of other protocols
+pub const QUIC_ALPN: &[u8] = b"ulpsi";
+
+/// Returns config of QUIC endpoint of server with PEM encoded certificate chain `cert_chain_pem` and private key
+/// `key_pem`. QUIC always encrypts with TLS 1.3.
+pub fn quic_server_config(
+    cert_chain_pem: &[u8],
+    key_pem: &[u8],
+) -> Result<quinn::ServerConfig, PsiError> {
+    let mut crypto = rustls::ServerConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&TLS13])
+        .map_err(|e| PsiError::Tls(e.to_string()))?
+        .with_no_client_auth()
+        .with_single_cert(
+            parse_certificates(cert_chain_pem)?,
+            parse_private_key(key_pem)?,
+        )
+        .map_err(|e| PsiError::Tls(e.to_string()))?;
+    crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    Ok(quinn::ServerConfig::with_crypto(Arc::new(crypto)))
+}
+
+/// Returns config of client's QUIC connections that only trusts servers with certificates issued by certificate
+/// authorities in PEM encoded `ca_pem`
+pub fn quic_client_config(ca_pem: &[u8]) -> Result<quinn::ClientConfig, PsiError> {
+    let mut crypto = rustls::ClientConfig::builder()
+        .with_safe_default_cipher_suites()
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(&[&TLS13])
+        .map_err(|e| PsiError::Tls(e.to_string()))?
+        .with_root_certificates(root_cert_store(ca_pem)?)
+        .with_no_client_auth();
+    crypto.alpn_protocols = vec![QUIC_ALPN.to_vec()];
+    Ok(quinn::ClientConfig::new(Arc::new(crypto)))
+}
+
+/// Connects to QUIC endpoint of server at `addr`, whose certificate must be valid for `domain`. Connection survives
+/// changes of client's address, for ex. when a phone switches networks, and carries any no. of `QuicStream`s.
+pub async fn quic_connect(
+    addr: SocketAddr,
+    domain: &str,
+    config: &quinn::ClientConfig,
+) -> Result<Connection, PsiError> {
+    let bind_addr: SocketAddr = if addr.is_ipv6() {
+        "[::]:0".parse().unwrap()
+    } else {
+        "0.0.0.0:0".parse().unwrap()
+    };
+    let endpoint = Endpoint::client(bind_addr)?;
+    endpoint
+        .connect_with(config.clone(), addr, domain)
+        .map_err(|e| PsiError::Tls(format!("Failed to connect: {e}")))?
+        .await
+        .map_err(|e| PsiError::Tls(format!("Handshake failed: {e}")))
+}
+
+/// Bidirectional stream of a QUIC connection. Frames are written and read with `write_frame` and
+/// `read_frame_with_limit`, same as on TCP connections, and server serves each stream as a separate session. Streams of
+/// a connection are multiplexed, thus a large response on one stream does not hold up others.
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl QuicStream {
+    pub fn new(send: SendStream, recv: RecvStream) -> QuicStream {
+        QuicStream { send, recv }
+    }
+
+    /// Opens a new stream on `connection`. Peer sees the stream once something is written to it.
+    pub async fn open(connection: &Connection) -> Result<QuicStream, PsiError> {
+        let (send, recv) = connection
+            .open_bi()
+            .await
+            .map_err(|e| PsiError::Io(format!("Failed to open QUIC stream: {e}")))?;
+        Ok(QuicStream::new(send, recv))
+    }
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.send).poll_shutdown(cx)
+    }
+}
+
+impl PsiClient<QuicStream> {
+    /// Same as `PsiClient::connect_tls` but over a new QUIC connection (see `quic_connect`). Further clients can
+    /// share the connection with `PsiClient::from_stream` and `QuicStream::open`.
+    pub async fn connect_quic(
+        addr: SocketAddr,
+        domain: &str,
+        config: &quinn::ClientConfig,
+        psi_params: &PsiParams,
+        sk: SecretKey,
+        client_id: ClientId,
+    ) -> Result<PsiClient<QuicStream>, PsiError> {
+        let connection = quic_connect(addr, domain, config).await?;
+        let stream = QuicStream::open(&connection).await?;
+        Ok(PsiClient::from_stream(stream, psi_params, sk, client_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{read_frame_with_limit, write_frame, Frame, MessageType, MAX_FRAME_BYTES};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn quic_streams_carry_frames() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+
+        let server_config = quic_server_config(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        let endpoint = Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        // echoes frames on every stream
+        tokio::spawn(async move {
+            while let Some(connecting) = endpoint.accept().await {
+                tokio::spawn(async move {
+                    let Ok(connection) = connecting.await else {
+                        return;
+                    };
+                    while let Ok((send, recv)) = connection.accept_bi().await {
+                        tokio::spawn(async move {
+                            let mut stream = QuicStream::new(send, recv);
+                            while let Ok(Some(frame)) =
+                                read_frame_with_limit(&mut stream, |_| MAX_FRAME_BYTES).await
+                            {
+                                write_frame(&mut stream, &frame).await.unwrap();
+                            }
+                        });
+                    }
+                });
+            }
+        });
+
+        let client_config = quic_client_config(cert_pem.as_bytes()).unwrap();
+        let connection = quic_connect(addr, "localhost", &client_config)
+            .await
+            .unwrap();
+        // large frame on one stream does not hold up the other
+        let large = Frame::new(MessageType::Query, vec![1; 1 << 22]);
+        let small = Frame::new(MessageType::Ack, vec![2; 10]);
+        let mut large_stream = QuicStream::open(&connection).await.unwrap();
+        let mut small_stream = QuicStream::open(&connection).await.unwrap();
+        let (large_echo, small_echo) = tokio::join!(
+            async {
+                write_frame(&mut large_stream, &large).await.unwrap();
+                read_frame_with_limit(&mut large_stream, |_| MAX_FRAME_BYTES)
+                    .await
+                    .unwrap()
+            },
+            async {
+                write_frame(&mut small_stream, &small).await.unwrap();
+                read_frame_with_limit(&mut small_stream, |_| MAX_FRAME_BYTES)
+                    .await
+                    .unwrap()
+            }
+        );
+        assert_eq!(large_echo, Some(large));
+        assert_eq!(small_echo, Some(small));
+
+        // server certificate isn't issued for the domain
+        assert!(matches!(
+            quic_connect(addr, "example.com", &client_config).await,
+            Err(PsiError::Tls(_))
+        ));
+    }
+}
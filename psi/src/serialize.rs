@@ -1,26 +1,137 @@
 use crate::{
-    db, HashTableQuery, HashTableQueryCts, HashTableQueryResponse, PsiParams, Query, QueryResponse,
+    client::occupied_segments, db, generate_evaluation_key, DbStats, HashTableEntry,
+    HashTableQueryCts, HashTableQueryResponse, PsiError, PsiParams, Query, QueryBatch, QueryLayout,
+    QueryResponse, QueryState, SegmentResponse, SegmentStageTimes,
 };
 use bfv::{
-    BfvParameters, Ciphertext, CiphertextProto, Encoding, Evaluator, PolyCache, Representation,
-    SecretKey,
+    BfvParameters, Ciphertext, CiphertextProto, Encoding, EvaluationKey, EvaluationKeyProto,
+    Evaluator, PolyCache, Representation, SecretKey,
 };
+use crypto_bigint::{Encoding as _, U256};
 use itertools::Itertools;
-use prost::Message;
+use prost::{
+    encoding::{decode_varint, encoded_len_varint},
+    Message,
+};
 use rand::thread_rng;
 use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    io::Read,
+    panic::{catch_unwind, AssertUnwindSafe},
+    time::Duration,
+};
 use traits::TryFromWithParameters;
 
+/// zstd level used to compress queries and responses
+pub const ZSTD_LEVEL: i32 = 3;
+
+/// Compresses `bytes` with zstd
+pub fn compress(bytes: &[u8]) -> Vec<u8> {
+    zstd::encode_all(bytes, ZSTD_LEVEL).expect("Compressing in memory bytes can't fail")
+}
+
+/// Decompresses zstd compressed `bytes`. Returns error instead of decompressing more than `max_bytes`, thus a small
+/// malicious payload can't make the receiver allocate arbitrary memory.
+pub fn decompress(bytes: &[u8], max_bytes: usize) -> Result<Vec<u8>, PsiError> {
+    let decoder = zstd::stream::read::Decoder::new(bytes)?;
+    let mut decompressed = vec![];
+    decoder
+        .take(max_bytes as u64 + 1)
+        .read_to_end(&mut decompressed)?;
+    if decompressed.len() > max_bytes {
+        return Err(PsiError::Serialization(format!(
+            "Decompressed payload exceeds limit of {max_bytes} bytes"
+        )));
+    }
+    Ok(decompressed)
+}
+
+/// Time server spent in each stage of processing a query. Returned to clients that enable `CAPABILITY_METADATA`.
+///
+/// Segments are processed in parallel, thus powers and evaluation times, which are summed across segments, may exceed
+/// wall clock time of the query.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct QueryMetadata {
+    pub deserialize_time: Duration,
+    /// Calculating PS powers across all segments
+    pub powers_time: Duration,
+    /// Evaluating polynomials across all segments of each BigBox, one for each BigBox (ie hash table)
+    pub evaluation_times: Vec<Duration>,
+    pub serialize_time: Duration,
+    /// Version of server's db the query was processed against. See `Server::db_version`.
+    pub db_version: u64,
+}
+
+impl QueryMetadata {
+    pub fn new(big_boxes: usize, db_version: u64) -> QueryMetadata {
+        QueryMetadata {
+            evaluation_times: vec![Duration::ZERO; big_boxes],
+            db_version,
+            ..Default::default()
+        }
+    }
+
+    /// Adds time spent processing a segment of BigBox `big_box`
+    pub fn record_segment(&mut self, big_box: usize, times: &SegmentStageTimes) {
+        self.powers_time += times.powers;
+        self.evaluation_times[big_box] += times.evaluation;
+    }
+
+    /// Total evaluation time across all BigBoxes
+    pub fn evaluation_time(&self) -> Duration {
+        self.evaluation_times.iter().sum()
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct SerializedQueryResponse {
     // TODO: check response size with and without `serde_bytes`
     #[serde(with = "serde_bytes")]
     bytes: Vec<u8>,
-    /// indicates no. of inner boxes within a segment. Segments of each bigbox are stored in continuation.
+    /// indicates no. of response ciphertexts within a segment (ie no. of inner boxes times `PsiParams::label_parts`).
+    /// Segments of each bigbox are stored in continuation.
     inner_boxes_per_segment: Vec<usize>,
+    /// Set only if client enabled `CAPABILITY_METADATA`
+    metadata: Option<QueryMetadata>,
 }
 
-pub fn size_of_unseeded_ciphertext_last_level(evaluator: &Evaluator) -> usize {
+impl SerializedQueryResponse {
+    pub fn with_metadata(mut self, metadata: QueryMetadata) -> SerializedQueryResponse {
+        self.metadata = Some(metadata);
+        self
+    }
+
+    pub fn metadata(&self) -> Option<&QueryMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Returns bincode serialized response compressed with zstd
+    pub fn compressed(&self) -> Result<Vec<u8>, PsiError> {
+        Ok(compress(&bincode::serialize(self)?))
+    }
+
+    /// Decodes response compressed with `SerializedQueryResponse::compressed`. Fails if decompressed response exceeds
+    /// `max_bytes`.
+    pub fn from_compressed(
+        bytes: &[u8],
+        max_bytes: usize,
+    ) -> Result<SerializedQueryResponse, PsiError> {
+        Ok(bincode::deserialize(&decompress(bytes, max_bytes)?)?)
+    }
+}
+
+/// Size of serialized response ciphertext, ie unseeded ciphertext at `PsiParams::response_level`
+pub fn size_of_response_ciphertext(evaluator: &Evaluator, psi_params: &PsiParams) -> usize {
+    size_of_unseeded_ciphertext(evaluator, psi_params.response_level())
+}
+
+/// Size of serialized query ciphertext encrypted with `PublicKey`, ie unseeded ciphertext at level 0
+pub fn size_of_unseeded_query_ciphertext(evaluator: &Evaluator) -> usize {
+    size_of_unseeded_ciphertext(evaluator, 0)
+}
+
+fn size_of_unseeded_ciphertext(evaluator: &Evaluator, level: usize) -> usize {
     let mut rng = thread_rng();
     let m = vec![];
     let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
@@ -35,9 +146,11 @@ pub fn size_of_unseeded_ciphertext_last_level(evaluator: &Evaluator) -> usize {
     let pt = evaluator.plaintext_encode(&m, Encoding::simd(0, PolyCache::Mul(bfv::PolyType::Q)));
     evaluator.mul_plaintext_assign(&mut ct, &pt);
 
-    // mod down to last level
+    // mod down to `level`
     evaluator.ciphertext_change_representation(&mut ct, Representation::Coefficient);
-    evaluator.mod_down_level(&mut ct, evaluator.params().ciphertext_moduli.len() - 1);
+    if level > 0 {
+        evaluator.mod_down_level(&mut ct, level);
+    }
 
     let ct_proto = CiphertextProto::try_from_with_parameters(&ct, evaluator.params());
     ct_proto.encode_to_vec().len()
@@ -56,77 +169,351 @@ pub fn size_of_seeded_ciphertext(evaluator: &Evaluator) -> usize {
     ct_proto.encode_to_vec().len()
 }
 
+/// Serializes query ciphertexts of each hash table followed by encryptions of zero. Each ciphertext proto is prefixed
+/// with its length as varint, thus ciphertexts of different sizes (for ex. seeded and unseeded) are framed correctly.
 pub fn serialize_query(query: &Query, bfv_params: &BfvParameters) -> Vec<u8> {
     query
         .0
         .iter()
-        .flat_map(|ht_query_cts| {
-            ht_query_cts.0.iter().flat_map(|ct| {
-                let ct_proto = CiphertextProto::try_from_with_parameters(ct, bfv_params);
-                ct_proto.encode_to_vec()
-            })
+        .flat_map(|ht_query_cts| ht_query_cts.0.iter())
+        .chain(query.1.iter())
+        .flat_map(|ct| {
+            let ct_proto = CiphertextProto::try_from_with_parameters(ct, bfv_params);
+            ct_proto.encode_length_delimited_to_vec()
         })
         .collect_vec()
 }
 
+/// Size of ciphertext proto of `ct_bytes` prefixed with its length
+fn length_delimited_size(ct_bytes: usize) -> usize {
+    encoded_len_varint(ct_bytes as u64) + ct_bytes
+}
+
+/// Splits `bytes` into ciphertext protos, each prefixed with its length as varint. Returns `PsiError::Serialization` if
+/// a length is malformed or exceeds the remaining bytes.
+fn split_length_delimited(mut bytes: &[u8]) -> Result<Vec<&[u8]>, PsiError> {
+    let mut cts = vec![];
+    while !bytes.is_empty() {
+        let len = decode_varint(&mut bytes)
+            .map_err(|_| PsiError::Serialization("Malformed ciphertext length".to_string()))?;
+        if len > bytes.len() as u64 {
+            return Err(PsiError::Serialization(format!(
+                "Ciphertext of {len} bytes exceeds remaining {} bytes",
+                bytes.len()
+            )));
+        }
+        let (ct_bytes, rest) = bytes.split_at(len as usize);
+        cts.push(ct_bytes);
+        bytes = rest;
+    }
+    Ok(cts)
+}
+
+/// Same as `serialize_query` but compressed with zstd
+pub fn serialize_query_compressed(query: &Query, bfv_params: &BfvParameters) -> Vec<u8> {
+    compress(&serialize_query(query, bfv_params))
+}
+
+/// Size of serialized query, ie one seeded ciphertext per source power per segment of each hash table plus encryptions
+/// of zero. Grows with no. of source powers, thus with windowed source powers (see `PsiParams::with_windowed_powers`).
 pub fn expected_query_bytes(evaluator: &Evaluator, psi_params: &PsiParams) -> usize {
-    let size_single_ct = size_of_seeded_ciphertext(evaluator);
-    size_single_ct
-        * psi_params.source_powers.len()
-        * HashTableQuery::segments_count(
-            &psi_params.ht_size,
-            &psi_params.ct_slots,
-            &psi_params.psi_pt,
-        ) as usize
-        * psi_params.no_of_hash_tables as usize
+    length_delimited_size(size_of_seeded_ciphertext(evaluator))
+        * QueryLayout::new(psi_params).query_cts()
+}
+
+/// Size of serialized query encrypted with `PublicKey`. Ciphertexts encrypted with public key aren't seeded, thus
+/// query is about twice as large as `expected_query_bytes`.
+pub fn expected_public_key_query_bytes(evaluator: &Evaluator, psi_params: &PsiParams) -> usize {
+    length_delimited_size(size_of_unseeded_query_ciphertext(evaluator))
+        * QueryLayout::new(psi_params).query_cts()
+}
+
+/// Size of serialized `EvaluationKey` generated with `generate_evaluation_key`, ie what clients upload in
+/// `MessageType::EvaluationKey`
+pub fn expected_evaluation_key_bytes(evaluator: &Evaluator, psi_params: &PsiParams) -> usize {
+    let mut rng = thread_rng();
+    let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+    let ek = generate_evaluation_key(psi_params, evaluator, &sk, &mut rng);
+    EvaluationKeyProto::try_from_with_parameters(&ek, evaluator.params())
+        .encode_to_vec()
+        .len()
+}
+
+/// Size of bincode serialized `SerializedQueryResponse`, without metadata and uncompressed, to a query against db with
+/// `db_stats`. Lets clients pre-allocate buffers and operators estimate bandwidth.
+pub fn expected_response_bytes(
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    db_stats: &DbStats,
+) -> usize {
+    let inner_boxes_per_segment = db_stats
+        .inner_boxes_per_segment
+        .iter()
+        .flatten()
+        .map(|inner_boxes| inner_boxes * psi_params.label_parts() as usize)
+        .collect_vec();
+    let total_cts: usize = inner_boxes_per_segment.iter().sum();
+    let response = SerializedQueryResponse {
+        bytes: vec![],
+        inner_boxes_per_segment,
+        metadata: None,
+    };
+    bincode::serialized_size(&response).expect("Serializing in memory response can't fail") as usize
+        + total_cts * size_of_response_ciphertext(evaluator, psi_params)
+}
+
+/// Decodes a single ciphertext. Ciphertext protos received over the wire may be truncated or corrupted (for ex. wrong
+/// no. of polynomials or coefficients), which BFV assumes never happens and panics on while converting proto to
+/// ciphertext. Such protos are rejected with an error instead, thus a malformed query can't bring down the server.
+pub(crate) fn decode_ciphertext(
+    bytes_ct: &[u8],
+    evaluator: &Evaluator,
+) -> Result<Ciphertext, PsiError> {
+    let ct_proto = CiphertextProto::decode(bytes_ct)?;
+    catch_unwind(AssertUnwindSafe(|| {
+        Ciphertext::try_from_with_parameters(&ct_proto, evaluator.params())
+    }))
+    .map_err(|_| PsiError::Serialization("Malformed ciphertext".to_string()))
 }
 
-pub fn deserialize_query(bytes: &[u8], psi_params: &PsiParams, evaluator: &Evaluator) -> Query {
-    // validate
-    let size_single_ct = size_of_seeded_ciphertext(evaluator);
+/// Deserializes evaluation key uploaded by client. Same as with `decode_ciphertext`, protos BFV panics on while
+/// converting them to a key, for ex. keys generated with other `BfvParameters`, are rejected with
+/// `PsiError::Serialization`.
+pub fn deserialize_evaluation_key(
+    bytes: &[u8],
+    evaluator: &Evaluator,
+) -> Result<EvaluationKey, PsiError> {
+    let ek_proto = EvaluationKeyProto::decode(bytes)?;
+    catch_unwind(AssertUnwindSafe(|| {
+        EvaluationKey::try_from_with_parameters(&ek_proto, evaluator.params())
+    }))
+    .map_err(|_| PsiError::Serialization("Malformed evaluation key".to_string()))
+}
+
+/// Deserializes query serialized with `serialize_query`. Returns `PsiError::ParamsMismatch` if `bytes` don't contain as
+/// many ciphertexts as expected by `psi_params` and `PsiError::Serialization` if any length prefix is malformed or any
+/// ciphertext fails to decode.
+pub fn deserialize_query(
+    bytes: &[u8],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+) -> Result<Query, PsiError> {
+    let layout = QueryLayout::new(psi_params);
 
     // Query should have 1 HashTableQuery for each BigBox. Each HashTableQuery must have 1 InnerBoxQuery for each segment in its corresponding BigBox. A single InnerBoxQuery is a vector of ciphertext, where initial query is raised to all source powers.
-    let expected_bytes = size_single_ct
-        * psi_params.source_powers.len()
-        * HashTableQuery::segments_count(
-            &psi_params.ht_size,
-            &psi_params.ct_slots,
-            &psi_params.psi_pt,
-        ) as usize
-        * psi_params.no_of_hash_tables as usize;
-    assert_eq!(bytes.len(), expected_bytes);
-
-    let bytes_in_single_ht_query = HashTableQuery::segments_count(
-        &psi_params.ht_size,
-        &psi_params.ct_slots,
-        &psi_params.psi_pt,
-    ) as usize
-        * psi_params.source_powers.len()
-        * size_single_ct;
-    let bytes_in_single_inner_box_query_all_powers =
-        size_single_ct * psi_params.source_powers.len();
-    // process each HashTableQuery
-    let ht_query_cts = bytes
-        .chunks_exact(bytes_in_single_ht_query)
-        .map(|bytes_ht_query| {
-            // process each InnerBoxQuery (raised to source powers) within HashTableQuery
-            let ht_query_cts = bytes_ht_query
-                .chunks_exact(bytes_in_single_inner_box_query_all_powers)
-                .flat_map(|bytes_inner_box_query_all_powers| {
-                    // process each power ciphertext
-                    bytes_inner_box_query_all_powers
-                        .chunks_exact(size_single_ct)
-                        .map(|bytes_ct| {
-                            let ct_proto = CiphertextProto::decode(bytes_ct).unwrap();
-                            Ciphertext::try_from_with_parameters(&ct_proto, evaluator.params())
-                        })
+    let cts_bytes = split_length_delimited(bytes)?;
+    if cts_bytes.len() != layout.query_cts() {
+        return Err(PsiError::ParamsMismatch(format!(
+            "Expected query of {} ciphertexts, found {}",
+            layout.query_cts(),
+            cts_bytes.len()
+        )));
+    }
+
+    let decode_ct = |bytes_ct: &&[u8]| decode_ciphertext(bytes_ct, evaluator);
+
+    // encryptions of zero follow query ciphertexts of all hash tables
+    let (ht_cts_bytes, zero_cts_bytes) = cts_bytes.split_at(cts_bytes.len() - layout.zero_cts());
+
+    // process each HashTableQuery, ie InnerBoxQuery of each segment raised to source powers
+    let ht_query_cts = ht_cts_bytes
+        .chunks_exact(layout.query_cts_per_hash_table())
+        .map(|ht_query_bytes| {
+            let ht_query_cts = ht_query_bytes
+                .iter()
+                .map(decode_ct)
+                .collect::<Result<Vec<_>, PsiError>>()?;
+            Ok(HashTableQueryCts(ht_query_cts))
+        })
+        .collect::<Result<Vec<_>, PsiError>>()?;
+
+    let zero_cts = zero_cts_bytes
+        .iter()
+        .map(decode_ct)
+        .collect::<Result<Vec<_>, PsiError>>()?;
+
+    // hashed as received, thus server caches PS powers without serializing ciphertexts again
+    let ct_digests = ht_cts_bytes
+        .chunks_exact(layout.query_cts_per_hash_table())
+        .map(|ht_query_bytes| {
+            ht_query_bytes
+                .iter()
+                .map(|bytes_ct| *blake3::hash(bytes_ct).as_bytes())
+                .collect_vec()
+        })
+        .collect_vec();
+
+    Ok(Query(ht_query_cts, zero_cts, ct_digests, None))
+}
+
+/// Serializes each query of `batch` with `serialize_query`, as bincode serialized `Vec<Vec<u8>>`
+pub fn serialize_query_batch(batch: &QueryBatch, bfv_params: &BfvParameters) -> Vec<u8> {
+    let queries = batch
+        .0
+        .iter()
+        .map(|query| serialize_query(query, bfv_params))
+        .collect_vec();
+    bincode::serialize(&queries).expect("Serializing in memory batch can't fail")
+}
+
+/// Same as `serialize_query_batch` but compressed with zstd
+pub fn serialize_query_batch_compressed(batch: &QueryBatch, bfv_params: &BfvParameters) -> Vec<u8> {
+    compress(&serialize_query_batch(batch, bfv_params))
+}
+
+/// Size of bincode serialized batch of `queries` queries of `query_bytes` each
+pub fn query_batch_bytes(queries: usize, query_bytes: usize) -> usize {
+    // bincode prefixes the vector and each query with its length as u64
+    8 + queries * (8 + query_bytes)
+}
+
+/// Deserializes batch serialized with `serialize_query_batch`. Each query is deserialized with `deserialize_query`.
+pub fn deserialize_query_batch(
+    bytes: &[u8],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+) -> Result<QueryBatch, PsiError> {
+    let queries: Vec<Vec<u8>> = bincode::deserialize(bytes)?;
+    let queries = queries
+        .iter()
+        .map(|query_bytes| deserialize_query(query_bytes, psi_params, evaluator))
+        .collect::<Result<Vec<_>, PsiError>>()?;
+    Ok(QueryBatch(queries))
+}
+
+/// Magic at start of `QueryState` serialized with `serialize_query_state`
+pub const QUERY_STATE_MAGIC: [u8; 8] = *b"ULPSI-QS";
+/// Bumped whenever layout of serialized `QueryState` changes
+pub const QUERY_STATE_VERSION: u32 = 2;
+
+/// Bincode serialized body of `QueryState`. Items are stored as little endian bytes.
+#[derive(Serialize, Deserialize)]
+struct SerializedQueryState {
+    /// Digest of `PsiParams` query was constructed with
+    params_digest: [u8; 32],
+    /// Placed entries of each hash table as (row, item, hash index), ordered by row
+    hash_tables: Vec<Vec<(u32, [u8; 32], u8)>>,
+    /// Items that couldn't be placed in hash tables, along with their hash index
+    hash_table_stack: Vec<([u8; 32], u8)>,
+    /// (OPRF output, original item) pairs
+    original_items: Vec<([u8; 32], [u8; 32])>,
+    /// Query serialized with `serialize_query`
+    query: Vec<u8>,
+}
+
+/// Serializes `query_state` so that a client can persist it, submit the query and process the response in a later
+/// process or on another machine (see `deserialize_query_state`).
+///
+/// Layout: magic (8 bytes) || version (u32 LE) || bincode serialized hash tables, stack, OPRF items and query
+/// ciphertexts. Serialized state holds queried items in plain, thus must be stored as carefully as the items. Secret key
+/// isn't part of it and must be persisted separately, for ex. with `SecretKeyStore`.
+pub fn serialize_query_state(
+    query_state: &QueryState,
+    psi_params: &PsiParams,
+    bfv_params: &BfvParameters,
+) -> Vec<u8> {
+    let entry =
+        |entry: &HashTableEntry| (entry.entry_value().to_le_bytes(), entry.hash_index() as u8);
+    let state = SerializedQueryState {
+        params_digest: psi_params.digest(),
+        hash_tables: query_state
+            .hash_tables
+            .iter()
+            .map(|ht| {
+                ht.iter()
+                    .sorted_by_key(|(row, _)| **row)
+                    .map(|(row, e)| {
+                        let (item, hash_index) = entry(e);
+                        (*row, item, hash_index)
+                    })
+                    .collect_vec()
+            })
+            .collect_vec(),
+        hash_table_stack: query_state.hash_table_stack.iter().map(entry).collect_vec(),
+        original_items: query_state
+            .original_items
+            .iter()
+            .sorted_by_key(|(oprf_item, _)| **oprf_item)
+            .map(|(oprf_item, item)| (oprf_item.to_le_bytes(), item.to_le_bytes()))
+            .collect_vec(),
+        query: serialize_query(&query_state.query, bfv_params),
+    };
+
+    let mut bytes = QUERY_STATE_MAGIC.to_vec();
+    bytes.extend_from_slice(&QUERY_STATE_VERSION.to_le_bytes());
+    bincode::serialize_into(&mut bytes, &state)
+        .expect("Serializing in memory query state can't fail");
+    bytes
+}
+
+/// Deserializes `QueryState` serialized with `serialize_query_state`. Returns `PsiError::ParamsMismatch` if state was
+/// serialized with params other than `psi_params`, and `PsiError::Serialization` if `bytes` are malformed.
+pub fn deserialize_query_state(
+    bytes: &[u8],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+) -> Result<QueryState, PsiError> {
+    let malformed =
+        |reason: &str| PsiError::Serialization(format!("Malformed query state: {reason}"));
+    if bytes.len() < QUERY_STATE_MAGIC.len() + 4 || !bytes.starts_with(&QUERY_STATE_MAGIC) {
+        return Err(malformed("missing magic"));
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != QUERY_STATE_VERSION {
+        return Err(PsiError::Serialization(format!(
+            "Unsupported query state version {version}, expected {QUERY_STATE_VERSION}"
+        )));
+    }
+    let state: SerializedQueryState = bincode::deserialize(&bytes[12..])?;
+    if state.params_digest != psi_params.digest() {
+        return Err(PsiError::ParamsMismatch(
+            "Query state was serialized with different PsiParams".to_string(),
+        ));
+    }
+    if state.hash_tables.len() != psi_params.no_of_hash_tables as usize
+        || state
+            .hash_tables
+            .iter()
+            .flatten()
+            .any(|(row, _, _)| *row >= psi_params.ht_size.0)
+    {
+        return Err(malformed("hash tables don't match params"));
+    }
+
+    let hash_tables = state
+        .hash_tables
+        .into_iter()
+        .map(|ht| {
+            ht.into_iter()
+                .map(|(row, item, hash_index)| {
+                    (
+                        row,
+                        HashTableEntry::with_hash_index(U256::from_le_bytes(item), hash_index),
+                    )
                 })
-                .collect_vec();
-            HashTableQueryCts(ht_query_cts)
+                .collect::<HashMap<_, _>>()
+        })
+        .collect_vec();
+    let hash_table_stack = state
+        .hash_table_stack
+        .into_iter()
+        .map(|(item, hash_index)| {
+            HashTableEntry::with_hash_index(U256::from_le_bytes(item), hash_index)
         })
+        .collect_vec();
+    let original_items = state
+        .original_items
+        .into_iter()
+        .map(|(oprf_item, item)| (U256::from_le_bytes(oprf_item), U256::from_le_bytes(item)))
         .collect();
 
-    Query(ht_query_cts)
+    Ok(QueryState {
+        query: deserialize_query(&state.query, psi_params, evaluator)?,
+        occupied_segments: occupied_segments(psi_params, &hash_tables),
+        hash_tables,
+        hash_table_stack,
+        original_items,
+    })
 }
 
 pub fn serialize_query_response(
@@ -161,6 +548,7 @@ pub fn serialize_query_response(
     SerializedQueryResponse {
         bytes,
         inner_boxes_per_segment: inner_box_lengths,
+        metadata: None,
     }
 }
 
@@ -168,47 +556,195 @@ pub fn deserialize_query_response(
     serialized_query_response: &SerializedQueryResponse,
     psi_params: &PsiParams,
     evaluator: &Evaluator,
-) -> QueryResponse {
+) -> Result<QueryResponse, PsiError> {
     // Can't validate bytes directly since response size is variable.
-    let bytes_single_ct = size_of_unseeded_ciphertext_last_level(evaluator);
-
-    let segments_per_hash_table = HashTableQuery::segments_count(
-        &psi_params.ht_size,
-        &psi_params.ct_slots,
-        &psi_params.psi_pt,
-    ) as usize;
-    let total_expected_segments_response =
-        psi_params.no_of_hash_tables as usize * segments_per_hash_table;
-    assert_eq!(
-        serialized_query_response.inner_boxes_per_segment.len(),
-        total_expected_segments_response
-    );
+    let bytes_single_ct = size_of_response_ciphertext(evaluator, psi_params);
+
+    let layout = QueryLayout::new(psi_params);
+    let segments_per_hash_table = layout.segments_per_hash_table();
+    let total_expected_segments_response = layout.segments();
+    if serialized_query_response.inner_boxes_per_segment.len() != total_expected_segments_response {
+        return Err(PsiError::ParamsMismatch(format!(
+            "Expected response for {total_expected_segments_response} segments, found {}",
+            serialized_query_response.inner_boxes_per_segment.len()
+        )));
+    }
+    let total_cts: usize = serialized_query_response
+        .inner_boxes_per_segment
+        .iter()
+        .try_fold(0usize, |acc, len| acc.checked_add(*len))
+        .ok_or(PsiError::Serialization(
+            "Response ciphertext count overflows".to_string(),
+        ))?;
+    if total_cts.checked_mul(bytes_single_ct) != Some(serialized_query_response.bytes.len()) {
+        return Err(PsiError::Serialization(format!(
+            "Expected {total_cts} response ciphertexts of {bytes_single_ct} bytes, found {} bytes",
+            serialized_query_response.bytes.len()
+        )));
+    }
 
     let mut query_response = vec![];
     let mut ciphertexts_processed = 0;
-    serialized_query_response
+    for segments in serialized_query_response
         .inner_boxes_per_segment
         .chunks_exact(segments_per_hash_table)
-        .for_each(|segments| {
-            // process segments of BigBox
-            let mut ht_table_query_response = vec![];
-            segments.iter().for_each(|segment_length| {
-                // process response ciphertexts for the segment
-                let mut segment_query_response = vec![];
-                for inner_box_index in 0..*segment_length {
-                    let bytes = &serialized_query_response.bytes[ciphertexts_processed
-                        * bytes_single_ct
-                        ..(ciphertexts_processed + 1) * bytes_single_ct];
-                    let ct_proto = CiphertextProto::decode(bytes).unwrap();
-                    let ct = Ciphertext::try_from_with_parameters(&ct_proto, evaluator.params());
-                    segment_query_response.push(ct);
-                    ciphertexts_processed += 1;
+    {
+        // process segments of BigBox
+        let mut ht_table_query_response = vec![];
+        for segment_length in segments {
+            // process response ciphertexts for the segment
+            let mut segment_query_response = vec![];
+            for _ in 0..*segment_length {
+                let bytes = &serialized_query_response.bytes[ciphertexts_processed * bytes_single_ct
+                    ..(ciphertexts_processed + 1) * bytes_single_ct];
+                let ct = decode_ciphertext(bytes, evaluator)?;
+                if ct.level() != psi_params.response_level() {
+                    return Err(PsiError::Serialization(format!(
+                        "Response ciphertext at level {}, expected {}",
+                        ct.level(),
+                        psi_params.response_level()
+                    )));
                 }
-                ht_table_query_response.push(segment_query_response);
-            });
+                segment_query_response.push(ct);
+                ciphertexts_processed += 1;
+            }
+            ht_table_query_response.push(segment_query_response);
+        }
+
+        query_response.push(HashTableQueryResponse(ht_table_query_response));
+    }
+
+    Ok(QueryResponse(query_response))
+}
+
+/// Serializes response of single segment as BigBox index (u32 LE) || segment index (u32 LE) || response ciphertexts
+pub fn serialize_segment_response(
+    segment_response: &SegmentResponse,
+    bfv_params: &BfvParameters,
+) -> Vec<u8> {
+    let mut bytes = vec![];
+    bytes.extend_from_slice(&(segment_response.big_box as u32).to_le_bytes());
+    bytes.extend_from_slice(&(segment_response.segment as u32).to_le_bytes());
+    segment_response.cts.iter().for_each(|ct| {
+        let ct_proto = CiphertextProto::try_from_with_parameters(ct, bfv_params);
+        bytes.extend(ct_proto.encode_to_vec());
+    });
+    bytes
+}
+
+/// Deserializes segment response serialized with `serialize_segment_response` and returns its BigBox index, segment
+/// index and response ciphertexts. Used with `StreamingResponseDecryptor` to decrypt segments as they arrive.
+pub fn deserialize_segment_response(
+    bytes: &[u8],
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+) -> Result<(usize, usize, Vec<Ciphertext>), PsiError> {
+    decode_segment_response(
+        bytes,
+        size_of_response_ciphertext(evaluator, psi_params),
+        psi_params.response_level(),
+        evaluator,
+    )
+}
 
-            query_response.push(HashTableQueryResponse(ht_table_query_response));
-        });
+fn decode_segment_response(
+    bytes: &[u8],
+    bytes_single_ct: usize,
+    response_level: usize,
+    evaluator: &Evaluator,
+) -> Result<(usize, usize, Vec<Ciphertext>), PsiError> {
+    if bytes.len() < 8 || (bytes.len() - 8) % bytes_single_ct != 0 {
+        return Err(PsiError::Serialization(format!(
+            "Malformed segment response of {} bytes",
+            bytes.len()
+        )));
+    }
+    let big_box = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let segment = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+
+    let cts = bytes[8..]
+        .chunks_exact(bytes_single_ct)
+        .map(|bytes_ct| {
+            let ct = decode_ciphertext(bytes_ct, evaluator)?;
+            if ct.level() != response_level {
+                return Err(PsiError::Serialization(format!(
+                    "Response ciphertext at level {}, expected {response_level}",
+                    ct.level(),
+                )));
+            }
+            Ok(ct)
+        })
+        .collect::<Result<Vec<_>, PsiError>>()?;
+    Ok((big_box, segment, cts))
+}
+
+/// Assembles `QueryResponse` from segment responses streamed by server in any order. Counterpart of
+/// `deserialize_query_response` for streamed responses.
+pub struct IncrementalQueryResponse {
+    /// Response ciphertexts of each segment of each BigBox. `None` until segment is received.
+    segments: Vec<Vec<Option<Vec<Ciphertext>>>>,
+    received: usize,
+    bytes_single_ct: usize,
+    response_level: usize,
+}
+
+impl IncrementalQueryResponse {
+    pub fn new(psi_params: &PsiParams, evaluator: &Evaluator) -> IncrementalQueryResponse {
+        let segments_per_hash_table = QueryLayout::new(psi_params).segments_per_hash_table();
+        IncrementalQueryResponse {
+            segments: vec![
+                vec![None; segments_per_hash_table];
+                psi_params.no_of_hash_tables as usize
+            ],
+            received: 0,
+            bytes_single_ct: size_of_response_ciphertext(evaluator, psi_params),
+            response_level: psi_params.response_level(),
+        }
+    }
 
-    QueryResponse(query_response)
+    /// Deserializes segment response serialized with `serialize_segment_response` and adds it to the response
+    pub fn add_segment(&mut self, bytes: &[u8], evaluator: &Evaluator) -> Result<(), PsiError> {
+        let (big_box, segment, cts) =
+            decode_segment_response(bytes, self.bytes_single_ct, self.response_level, evaluator)?;
+
+        let slot = self
+            .segments
+            .get_mut(big_box)
+            .and_then(|segments| segments.get_mut(segment))
+            .ok_or(PsiError::ParamsMismatch(format!(
+                "Response for non-existent segment {segment} of BigBox {big_box}"
+            )))?;
+        if slot.is_some() {
+            return Err(PsiError::Serialization(format!(
+                "Duplicate response for segment {segment} of BigBox {big_box}"
+            )));
+        }
+
+        *slot = Some(cts);
+        self.received += 1;
+        Ok(())
+    }
+
+    /// Returns true once responses of all segments are received
+    pub fn is_complete(&self) -> bool {
+        self.received == self.segments.iter().map(|s| s.len()).sum::<usize>()
+    }
+
+    /// Returns assembled `QueryResponse`. Fails if any segment is missing.
+    pub fn finish(self) -> Result<QueryResponse, PsiError> {
+        if !self.is_complete() {
+            return Err(PsiError::Serialization(
+                "Response is missing segments".to_string(),
+            ));
+        }
+
+        Ok(QueryResponse(
+            self.segments
+                .into_iter()
+                .map(|segments| {
+                    HashTableQueryResponse(segments.into_iter().map(Option::unwrap).collect())
+                })
+                .collect(),
+        ))
+    }
 }
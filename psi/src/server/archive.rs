@@ -0,0 +1,128 @@
+use crate::{
+    hash::Cuckoo,
+    oprf::OprfKey,
+    server::{
+        db::BigBox,
+        storage::{malformed, params_mismatch, CoefficientsShape},
+    },
+    Db, PsiError, PsiParams,
+};
+use itertools::Itertools;
+use ndarray::Array2;
+use rkyv::{AlignedVec, Archive, Deserialize, Infallible, Serialize};
+use std::collections::BTreeMap;
+
+/// Scratch space of serializer used to archive db section, in bytes
+const ARCHIVE_SCRATCH_BYTES: usize = 4096;
+
+/// Db section of db file stored with `Db::store`, archived with rkyv so that it is read in place from memory mapped
+/// file. Polynomial coefficients aren't part of it, they follow the db section (see `MappedCoefficients`).
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub(crate) struct DbRecord {
+    /// Bincode serialized `Cuckoo`, `PsiParams` and OPRF key of db, which are small, followed by bincode serialized
+    /// expiries of items. Expiries are missing in db files stored before they were added.
+    meta: Vec<u8>,
+    big_boxes: Vec<BigBoxRecord>,
+    /// Shape of coefficients of each InnerBox, in order of `Db::inner_boxes`
+    coefficients_shapes: Vec<CoefficientsShape>,
+}
+
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub(crate) struct BigBoxRecord {
+    pub(crate) id: u64,
+    pub(crate) inner_box_rows: u32,
+    /// InnerBoxes of each segment
+    pub(crate) inner_boxes: Vec<Vec<InnerBoxRecord>>,
+}
+
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub(crate) struct InnerBoxRecord {
+    /// No. of occupied columns of each InnerBoxRow
+    pub(crate) curr_cols: Vec<u32>,
+    pub(crate) item_data: DataRecord,
+    pub(crate) label_data: DataRecord,
+    pub(crate) initialised: bool,
+}
+
+/// Row major `Array2<u8>`
+#[derive(Archive, Serialize)]
+#[archive(check_bytes)]
+pub(crate) struct DataRecord {
+    pub(crate) rows: u64,
+    pub(crate) cols: u64,
+    pub(crate) data: Vec<u8>,
+}
+
+impl DataRecord {
+    pub(crate) fn of(data: &Array2<u8>) -> DataRecord {
+        DataRecord {
+            rows: data.shape()[0] as u64,
+            cols: data.shape()[1] as u64,
+            data: data.iter().copied().collect(),
+        }
+    }
+}
+
+impl ArchivedDataRecord {
+    /// Copies archived data into an `Array2<u8>`
+    pub(crate) fn to_array(&self) -> Result<Array2<u8>, PsiError> {
+        Array2::from_shape_vec((self.rows as usize, self.cols as usize), self.data.to_vec())
+            .map_err(|_| malformed("unexpected shape of data"))
+    }
+}
+
+/// Archives db, without polynomial coefficients, along with `shapes` of coefficients of each InnerBox. Item and label
+/// data of every InnerBox are copied into the archive, thus storing briefly holds a second copy of them.
+pub(crate) fn archive_db(db: &Db, shapes: &[CoefficientsShape]) -> Result<AlignedVec, PsiError> {
+    let mut meta = bincode::serialize(&(&db.cuckoo, &db.psi_params, &db.oprf_key))?;
+    meta.extend(bincode::serialize(&db.expiries)?);
+    let record = DbRecord {
+        meta,
+        big_boxes: db.big_boxes.iter().map(|bb| bb.record()).collect_vec(),
+        coefficients_shapes: shapes.to_vec(),
+    };
+    rkyv::to_bytes::<_, ARCHIVE_SCRATCH_BYTES>(&record)
+        .map_err(|e| PsiError::Serialization(format!("Failed to archive db: {e}")))
+}
+
+/// Validates db section archived with `archive_db` and restores db, without coefficients, along with shapes of
+/// coefficients of each InnerBox. `bytes` must be aligned to 16 bytes. Returns `PsiError::ParamsMismatch` if db was
+/// archived with params other than `psi_params`.
+pub(crate) fn unarchive_db(
+    bytes: &[u8],
+    psi_params: &PsiParams,
+) -> Result<(Vec<CoefficientsShape>, Db), PsiError> {
+    let archived = rkyv::check_archived_root::<DbRecord>(bytes)
+        .map_err(|e| malformed(&format!("invalid db section: {e}")))?;
+    let mut meta = archived.meta.as_slice();
+    let (cuckoo, stored_params, oprf_key): (Cuckoo, PsiParams, Option<OprfKey>) =
+        bincode::deserialize_from(&mut meta)?;
+    if &stored_params != psi_params {
+        return Err(params_mismatch());
+    }
+    let expiries = match meta.is_empty() {
+        true => BTreeMap::new(),
+        false => bincode::deserialize_from(&mut meta)?,
+    };
+
+    let big_boxes = archived
+        .big_boxes
+        .iter()
+        .map(|bb| BigBox::from_record(bb, psi_params))
+        .collect::<Result<Vec<_>, _>>()?;
+    let shapes: Vec<CoefficientsShape> = archived
+        .coefficients_shapes
+        .deserialize(&mut Infallible)
+        .unwrap();
+    let db = Db {
+        cuckoo,
+        big_boxes,
+        psi_params: stored_params,
+        oprf_key,
+        expiries,
+    };
+    Ok((shapes, db))
+}
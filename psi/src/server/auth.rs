@@ -0,0 +1,185 @@
+use crate::PsiError;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    /// Token is not in server's `TokenStore`
+    InvalidToken,
+    /// Server requires client to send `MessageType::Auth` before other requests
+    NotAuthenticated,
+    /// Token has used up all of its queries
+    QuotaExceeded { limit: u64 },
+    /// Token file is malformed
+    Malformed(String),
+}
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::InvalidToken => write!(f, "Invalid API token"),
+            AuthError::NotAuthenticated => write!(f, "Client must authenticate with an API token"),
+            AuthError::QuotaExceeded { limit } => {
+                write!(f, "API token exceeded its quota of {limit} queries")
+            }
+            AuthError::Malformed(e) => write!(f, "Malformed token file: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {}
+
+/// Identifies an API token without storing the token itself, ie SHA-256 of the token
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TokenId([u8; 32]);
+
+impl TokenId {
+    fn new(token: &str) -> TokenId {
+        TokenId(Sha256::digest(token.as_bytes()).into())
+    }
+}
+
+struct TokenEntry {
+    /// Max. no. of queries allowed with the token. `None` means unlimited.
+    max_queries: Option<u64>,
+    queries: AtomicU64,
+}
+
+/// API tokens of clients authorized to query the server along with no. of queries each token has made.
+///
+/// Token file contains one token per line, optionally followed by max. no. of queries allowed with it, for ex.
+/// `3f2a9c... 1000`. Empty lines and lines starting with `#` are ignored. Tokens are only held as SHA-256 hashes in
+/// memory and query counters reset whenever the file is loaded.
+pub struct TokenStore {
+    tokens: HashMap<TokenId, TokenEntry>,
+}
+
+impl TokenStore {
+    pub fn from_file(path: &Path) -> Result<TokenStore, PsiError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| PsiError::Io(format!("Failed to read {}: {e}", path.display())))?;
+        TokenStore::parse(&contents)
+    }
+
+    /// Parses tokens in format of token file
+    pub fn parse(contents: &str) -> Result<TokenStore, PsiError> {
+        let mut tokens = HashMap::new();
+        for (index, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut fields = line.split_whitespace();
+            let token = fields.next().unwrap();
+            let max_queries = match fields.next() {
+                Some(max) => Some(max.parse::<u64>().map_err(|_| {
+                    AuthError::Malformed(format!("Invalid quota {max} on line {}", index + 1))
+                })?),
+                None => None,
+            };
+            if fields.next().is_some() {
+                return Err(AuthError::Malformed(format!(
+                    "Unexpected field on line {}",
+                    index + 1
+                ))
+                .into());
+            }
+
+            tokens.insert(
+                TokenId::new(token),
+                TokenEntry {
+                    max_queries,
+                    queries: AtomicU64::new(0),
+                },
+            );
+        }
+        Ok(TokenStore { tokens })
+    }
+
+    /// Returns id of `token` if it is authorized
+    pub fn authenticate(&self, token: &str) -> Result<TokenId, AuthError> {
+        let id = TokenId::new(token);
+        if self.tokens.contains_key(&id) {
+            Ok(id)
+        } else {
+            Err(AuthError::InvalidToken)
+        }
+    }
+
+    /// Counts a query made with token `id`. Returns error without counting the query if token has used up its quota.
+    pub fn record_query(&self, id: &TokenId) -> Result<(), AuthError> {
+        let entry = self.tokens.get(id).ok_or(AuthError::InvalidToken)?;
+        entry
+            .queries
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queries| {
+                match entry.max_queries {
+                    Some(max) if queries >= max => None,
+                    _ => Some(queries + 1),
+                }
+            })
+            .map(|_| ())
+            .map_err(|_| AuthError::QuotaExceeded {
+                limit: entry.max_queries.unwrap_or_default(),
+            })
+    }
+
+    /// Returns no. of queries made with token `id`
+    pub fn queries(&self, id: &TokenId) -> u64 {
+        self.tokens
+            .get(id)
+            .map_or(0, |entry| entry.queries.load(Ordering::SeqCst))
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_store_works() {
+        let store = TokenStore::parse(
+            "# tokens of authorized clients
+            alice-token 2
+
+            bob-token",
+        )
+        .unwrap();
+        assert_eq!(store.len(), 2);
+
+        assert_eq!(
+            store.authenticate("eve-token"),
+            Err(AuthError::InvalidToken)
+        );
+
+        let alice = store.authenticate("alice-token").unwrap();
+        assert!(store.record_query(&alice).is_ok());
+        assert!(store.record_query(&alice).is_ok());
+        assert_eq!(
+            store.record_query(&alice),
+            Err(AuthError::QuotaExceeded { limit: 2 })
+        );
+        assert_eq!(store.queries(&alice), 2);
+
+        let bob = store.authenticate("bob-token").unwrap();
+        (0..10).for_each(|_| store.record_query(&bob).unwrap());
+        assert_eq!(store.queries(&bob), 10);
+
+        assert!(matches!(
+            TokenStore::parse("alice-token many"),
+            Err(PsiError::Auth(AuthError::Malformed(_)))
+        ));
+    }
+}
@@ -0,0 +1,274 @@
+use crate::{
+    construct_query, deserialize_query, deserialize_query_response, gen_bfv_params,
+    gen_random_item_labels, generate_evaluation_key, process_query_response, seeded_rng,
+    serialize_query, serialize_query_response, ItemLabel, ProgressSink, PsiError, PsiParams,
+    SerializedQueryResponse, Server, SetupStage,
+};
+use bfv::{Evaluator, SecretKey};
+use itertools::Itertools;
+use rand::Rng;
+use serde::Serialize;
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Measured setup and query costs of a synthetic server set, see `run_bench`
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BenchReport {
+    pub set_size: usize,
+    pub clients: usize,
+    pub iterations: usize,
+    /// No. of threads of rayon's current thread pool
+    pub threads: usize,
+    /// Time to insert server set into the db
+    pub insert_time: Duration,
+    /// Time to interpolate polynomials of all InnerBoxes and encode coefficients
+    pub preprocess_time: Duration,
+    /// Time to insert and preprocess, ie `insert_time + preprocess_time`
+    pub setup_time: Duration,
+    /// No. of InnerBoxes across all BigBoxes
+    pub inner_boxes: usize,
+    /// See `DbStats::bytes`
+    pub db_bytes: u64,
+    /// Size of serialized query
+    pub query_bytes: usize,
+    /// Size of bincode serialized, uncompressed response
+    pub response_bytes: usize,
+    /// Time from constructing a query to decrypting its labels, including serialization of query and response
+    pub query_latency: LatencyPercentiles,
+    /// Time server spent evaluating a query, excluding serialization
+    pub server_latency: LatencyPercentiles,
+    /// Queries answered per second across all clients
+    pub queries_per_second: f64,
+    /// No. of queries whose response is missing label of queried item. Always 0 unless server is broken.
+    pub missed_queries: usize,
+}
+
+/// Distribution of latencies of all queries of a benchmark
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct LatencyPercentiles {
+    pub min: Duration,
+    pub p50: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+}
+
+impl LatencyPercentiles {
+    /// Percentiles are nearest rank. Returns default if `latencies` is empty.
+    pub fn new(latencies: &[Duration]) -> LatencyPercentiles {
+        if latencies.is_empty() {
+            return LatencyPercentiles::default();
+        }
+        let sorted = latencies.iter().copied().sorted().collect_vec();
+        let percentile = |p: usize| sorted[((sorted.len() * p + 99) / 100).max(1) - 1];
+        LatencyPercentiles {
+            min: sorted[0],
+            p50: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: sorted[sorted.len() - 1],
+            mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+        }
+    }
+}
+
+/// `ProgressSink` recording time spent inserting ItemLabels
+#[derive(Default)]
+struct InsertTimer {
+    started: Mutex<Option<Instant>>,
+    elapsed: Mutex<Duration>,
+}
+
+impl ProgressSink for InsertTimer {
+    fn start(&self, stage: SetupStage, _total: u64) {
+        if stage == SetupStage::Insert {
+            *self.started.lock().unwrap() = Some(Instant::now());
+        }
+    }
+
+    fn advance(&self, _stage: SetupStage, _units: u64) {}
+
+    fn finish(&self, stage: SetupStage) {
+        if let (SetupStage::Insert, Some(started)) = (stage, self.started.lock().unwrap().take()) {
+            *self.elapsed.lock().unwrap() += started.elapsed();
+        }
+    }
+}
+
+/// Latencies of a single query, see `BenchReport`
+struct QueryTimings {
+    found: bool,
+    latency: Duration,
+    server_latency: Duration,
+    query_bytes: usize,
+    response_bytes: usize,
+}
+
+/// Sets up server with `set_size` random ItemLabels generated with `seed` (see `gen_random_item_labels`) and
+/// `psi_params`, then runs `clients` concurrent clients in-process that each query a random item of the set
+/// `iterations` times. Queries and responses are serialized and deserialized as they would be over the wire. Server
+/// and clients use rayon's current thread pool.
+pub fn run_bench(
+    psi_params: &PsiParams,
+    set_size: usize,
+    clients: usize,
+    iterations: usize,
+    seed: Option<u64>,
+) -> Result<BenchReport, PsiError> {
+    psi_params.validate()?;
+    if set_size == 0 {
+        return Err(PsiError::InvalidParams(
+            "Benchmark requires non-empty server set".to_string(),
+        ));
+    }
+    let item_labels = gen_random_item_labels(set_size, seed);
+
+    let mut server = Server::new(psi_params);
+    let insert_timer = InsertTimer::default();
+    let start = Instant::now();
+    server.setup_with_progress(&item_labels, &insert_timer)?;
+    let setup_time = start.elapsed();
+    let insert_time = *insert_timer.elapsed.lock().unwrap();
+    let stats = server.snapshot().stats();
+
+    let start = Instant::now();
+    let timings = std::thread::scope(|scope| {
+        let handles = (0..clients)
+            .map(|client| {
+                let server = &server;
+                let item_labels = &item_labels;
+                // distinct clients use distinct keys
+                let seed = seed.map(|seed| seed.wrapping_add(client as u64 + 1));
+                scope.spawn(move || run_client(server, item_labels, iterations, seed))
+            })
+            .collect_vec();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("Benchmark client panicked"))
+            .collect::<Result<Vec<_>, PsiError>>()
+    })?;
+    let elapsed = start.elapsed();
+
+    let timings = timings.into_iter().flatten().collect_vec();
+    let latencies = timings.iter().map(|t| t.latency).collect_vec();
+    let server_latencies = timings.iter().map(|t| t.server_latency).collect_vec();
+    Ok(BenchReport {
+        set_size,
+        clients,
+        iterations,
+        threads: rayon::current_num_threads(),
+        insert_time,
+        preprocess_time: setup_time.saturating_sub(insert_time),
+        setup_time,
+        inner_boxes: stats.inner_boxes(),
+        db_bytes: stats.bytes,
+        query_bytes: timings.first().map(|t| t.query_bytes).unwrap_or_default(),
+        response_bytes: timings
+            .first()
+            .map(|t| t.response_bytes)
+            .unwrap_or_default(),
+        query_latency: LatencyPercentiles::new(&latencies),
+        server_latency: LatencyPercentiles::new(&server_latencies),
+        queries_per_second: timings.len() as f64 / elapsed.as_secs_f64(),
+        missed_queries: timings.iter().filter(|t| !t.found).count(),
+    })
+}
+
+/// Queries a random item of `item_labels` `iterations` times with a fresh client key seeded with `seed`
+fn run_client(
+    server: &Server,
+    item_labels: &[ItemLabel],
+    iterations: usize,
+    seed: Option<u64>,
+) -> Result<Vec<QueryTimings>, PsiError> {
+    let psi_params = server.psi_params();
+    let evaluator = Evaluator::new(gen_bfv_params(psi_params));
+    let mut rng = seeded_rng(seed);
+    let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+    let ek = generate_evaluation_key(psi_params, &evaluator, &sk, &mut rng);
+
+    (0..iterations)
+        .map(|_| {
+            let item_label = &item_labels[rng.gen_range(0..item_labels.len())];
+
+            let start = Instant::now();
+            let query_state =
+                construct_query(&[*item_label.item()], psi_params, &evaluator, &sk, &mut rng);
+            let query_bytes = serialize_query(query_state.query(), evaluator.params());
+
+            let query = deserialize_query(&query_bytes, psi_params, server.evaluator())?;
+            let evaluated = Instant::now();
+            let response = server.query(&query, &ek)?;
+            let server_latency = evaluated.elapsed();
+            let response_bytes = bincode::serialize(&serialize_query_response(
+                &response,
+                server.evaluator().params(),
+            ))?;
+
+            let response = bincode::deserialize::<SerializedQueryResponse>(&response_bytes)?;
+            let response = deserialize_query_response(&response, psi_params, &evaluator)?;
+            let potential_labels = process_query_response(
+                psi_params,
+                query_state.hash_tables(),
+                &evaluator,
+                &sk,
+                &response,
+            );
+            let latency = start.elapsed();
+
+            Ok(QueryTimings {
+                found: potential_labels.iter().any(|labels| {
+                    labels.item() == item_label.item()
+                        && labels.labels().contains(item_label.label())
+                }),
+                latency,
+                server_latency,
+                query_bytes: query_bytes.len(),
+                response_bytes: response_bytes.len(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bench_reports_all_queries() {
+        let psi_params = PsiParams::default();
+        let report = run_bench(&psi_params, 1000, 2, 2, Some(7)).unwrap();
+
+        assert_eq!(report.set_size, 1000);
+        assert_eq!(
+            report.setup_time,
+            report.insert_time + report.preprocess_time
+        );
+        assert!(report.preprocess_time > Duration::ZERO);
+        assert!(report.inner_boxes > 0);
+        assert!(report.query_bytes > 0);
+        assert!(report.response_bytes > 0);
+
+        let latency = report.query_latency;
+        assert!(latency.min <= latency.p50 && latency.p50 <= latency.p99);
+        assert!(latency.p99 <= latency.max);
+        assert!(report.server_latency.max <= latency.max);
+        assert!(report.queries_per_second > 0.0);
+        assert_eq!(report.missed_queries, 0);
+    }
+
+    #[test]
+    fn percentiles_are_nearest_rank() {
+        let latencies = (1..=100).rev().map(Duration::from_millis).collect_vec();
+        let percentiles = LatencyPercentiles::new(&latencies);
+        assert_eq!(percentiles.min, Duration::from_millis(1));
+        assert_eq!(percentiles.p50, Duration::from_millis(50));
+        assert_eq!(percentiles.p90, Duration::from_millis(90));
+        assert_eq!(percentiles.p99, Duration::from_millis(99));
+        assert_eq!(percentiles.max, Duration::from_millis(100));
+        assert_eq!(LatencyPercentiles::new(&[]), LatencyPercentiles::default());
+    }
+}
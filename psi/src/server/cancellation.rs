@@ -0,0 +1,95 @@
+use crate::PsiError;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// Cooperative cancellation of a query. Query processing checks the token before starting each segment, thus once
+/// token is cancelled or its deadline passes, segments in progress finish but remaining segments are skipped and
+/// query returns `PsiError::Cancelled`. Clones share cancellation.
+#[derive(Clone, Debug, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    /// Deadline along with the timeout it was set from
+    deadline: Option<(Instant, Duration)>,
+}
+
+impl CancellationToken {
+    /// Returns token that is only cancelled with `cancel`
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Returns token that is cancelled once `timeout` elapses from now
+    pub fn with_timeout(timeout: Duration) -> CancellationToken {
+        CancellationToken {
+            cancelled: Arc::default(),
+            deadline: Some((Instant::now() + timeout, timeout)),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline.map(|(deadline, _)| deadline)
+    }
+
+    /// Returns true if token is cancelled or its deadline has passed
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+            || matches!(self.deadline, Some((deadline, _)) if Instant::now() >= deadline)
+    }
+
+    /// Returns `PsiError::Cancelled` if token is cancelled
+    pub fn check(&self) -> Result<(), PsiError> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(PsiError::Cancelled(
+                "Cancelled before all segments were processed".to_string(),
+            ));
+        }
+        match self.deadline {
+            Some((deadline, timeout)) if Instant::now() >= deadline => Err(PsiError::Cancelled(
+                format!("Exceeded timeout of {} ms", timeout.as_millis()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Cancels token once dropped, for ex. when future waiting for the query is dropped because client disconnected
+pub struct CancelOnDrop(pub CancellationToken);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.cancel();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancellation_works() {
+        let token = CancellationToken::new();
+        assert!(token.check().is_ok());
+        {
+            let _guard = CancelOnDrop(token.clone());
+        }
+        assert!(token.is_cancelled());
+        assert!(matches!(token.check(), Err(PsiError::Cancelled(_))));
+
+        let token = CancellationToken::with_timeout(Duration::ZERO);
+        assert!(token.is_cancelled());
+        assert_eq!(
+            token.check(),
+            Err(PsiError::Cancelled("Exceeded timeout of 0 ms".to_string()))
+        );
+        assert!(!CancellationToken::with_timeout(Duration::from_secs(60)).is_cancelled());
+    }
+}
@@ -0,0 +1,249 @@
+use bfv::{Ciphertext, Encoding, Evaluator, Plaintext, PolyCache, PolyType, Representation};
+use num_bigint::{BigUint, RandBigInt};
+use rand::{CryptoRng, Rng, RngCore};
+use traits::TryEncodingWithParameters;
+
+use crate::{evaluation_noise_bits, PsiError, PsiParams};
+
+/// No. of encryptions of zero client sends with query when `PsiParams::circuit_privacy` is enabled
+pub const CIRCUIT_PRIVACY_ZERO_CTS: usize = 2;
+
+/// Statistical security parameter of smudging. Smudging noise exceeds worst case evaluation noise by these many bits,
+/// thus distribution of noise of a response ciphertext is within statistical distance 2^-40 of one independent of
+/// server's polynomials.
+pub const SMUDGING_SECURITY_BITS: usize = 40;
+
+/// Bits of smudging noise added to response ciphertexts of `psi_params`, ie `evaluation_noise_bits` plus
+/// `SMUDGING_SECURITY_BITS`. Polynomials evaluated at a lower level (see `PsiParams::with_evaluation_level`) have
+/// noise smaller by bits of dropped moduli, and so does smudging noise.
+pub fn smudging_noise_bits(psi_params: &PsiParams) -> Result<usize, PsiError> {
+    let dropped_bits = psi_params.bfv_moduli[..psi_params.evaluation_level]
+        .iter()
+        .sum::<usize>();
+    Ok((evaluation_noise_bits(psi_params)? + SMUDGING_SECURITY_BITS).saturating_sub(dropped_bits))
+}
+
+/// Server side of circuit privacy of a single query, see `PsiParams::with_circuit_privacy`
+pub struct ResponseRerandomizer {
+    /// Client's encryptions of zero, prepared with `prepare_zero_cts` at evaluation level
+    zero_cts: Vec<Ciphertext>,
+    /// See `smudging_noise_bits`
+    noise_bits: usize,
+}
+
+impl ResponseRerandomizer {
+    /// Returns `None` if `psi_params` don't enable circuit privacy
+    pub fn new(
+        evaluator: &Evaluator,
+        psi_params: &PsiParams,
+        zero_cts: &[Ciphertext],
+    ) -> Result<Option<ResponseRerandomizer>, PsiError> {
+        if !psi_params.circuit_privacy {
+            return Ok(None);
+        }
+        Ok(Some(ResponseRerandomizer {
+            zero_cts: prepare_zero_cts(evaluator, zero_cts, psi_params.evaluation_level),
+            noise_bits: smudging_noise_bits(psi_params)?,
+        }))
+    }
+
+    /// Rerandomizes response ciphertext `ct` (see `rerandomize`) and smudges its noise (see `smudge`)
+    pub fn apply<R: RngCore + CryptoRng>(
+        &self,
+        evaluator: &Evaluator,
+        ct: &mut Ciphertext,
+        rng: &mut R,
+    ) {
+        rerandomize(evaluator, ct, &self.zero_cts, rng);
+        smudge(evaluator, ct, self.noise_bits, rng);
+    }
+}
+
+/// Prepares encryptions of zero sent with query for `rerandomize` of ciphertexts at `level`. Ciphertexts are switched
+/// down to `level`, since responses are evaluated there (see `PsiParams::with_evaluation_level`), and changed to
+/// Evaluation representation once so that they can be multiplied with plaintexts for every response ciphertext.
+pub fn prepare_zero_cts(
+    evaluator: &Evaluator,
+    zero_cts: &[Ciphertext],
+    level: usize,
+) -> Vec<Ciphertext> {
+    zero_cts
+        .iter()
+        .map(|ct| {
+            let mut ct = ct.clone();
+            evaluator.mod_down_level(&mut ct, level);
+            evaluator.ciphertext_change_representation(&mut ct, Representation::Evaluation);
+            ct
+        })
+        .collect()
+}
+
+/// Rerandomizes `ct` by adding random linear combination of encryptions of zero in `zero_cts`, prepared with
+/// `prepare_zero_cts`. `ct` must be at the level `zero_cts` were prepared for, in Coefficient representation.
+///
+/// Each encryption of zero is multiplied with a uniformly random plaintext, thus the added ciphertext is uniformly
+/// random. This hides the ciphertext itself but not its noise, which is chosen by the client's encryptions. Response
+/// ciphertexts are smudged with `smudge` as well.
+pub fn rerandomize<R: RngCore + CryptoRng>(
+    evaluator: &Evaluator,
+    ct: &mut Ciphertext,
+    zero_cts: &[Ciphertext],
+    rng: &mut R,
+) {
+    if zero_cts.is_empty() {
+        return;
+    }
+
+    let params = evaluator.params();
+    let mut flood = Ciphertext::placeholder();
+    for (index, zero_ct) in zero_cts.iter().enumerate() {
+        let m = (0..params.degree)
+            .map(|_| rng.gen_range(0..params.plaintext_modulus))
+            .collect::<Vec<u64>>();
+        let pt = Plaintext::try_encoding_with_parameters(
+            m.as_slice(),
+            params,
+            Encoding::simd(0, PolyCache::Mul(PolyType::Q)),
+        );
+
+        if index == 0 {
+            flood = evaluator.mul_plaintext(zero_ct, &pt);
+        } else {
+            evaluator.add_assign(&mut flood, &evaluator.mul_plaintext(zero_ct, &pt));
+        }
+    }
+
+    evaluator.ciphertext_change_representation(&mut flood, Representation::Coefficient);
+    evaluator.add_assign(ct, &flood);
+}
+
+/// Adds noise sampled uniformly from `[0, 2^noise_bits)` by the server to each coefficient of `ct`, which must be in
+/// Coefficient representation. With `noise_bits` from `smudging_noise_bits`, smudging noise floods noise of polynomial
+/// evaluation, which otherwise is a deterministic function of query and server's polynomial coefficients and leaks them
+/// through decrypted noise. Moduli must have room for the extra noise, see `required_modulus_bits`.
+pub fn smudge<R: RngCore + CryptoRng>(
+    evaluator: &Evaluator,
+    ct: &mut Ciphertext,
+    noise_bits: usize,
+    rng: &mut R,
+) {
+    let params = evaluator.params();
+    let ctx = params.poly_ctx(&PolyType::Q, ct.level());
+    let noise = (0..params.degree)
+        .map(|_| rng.gen_biguint(noise_bits as u64))
+        .collect::<Vec<BigUint>>();
+    let noise = ctx.try_convert_from_biguint(&noise, Representation::Coefficient);
+
+    let c0 = &mut ct.c_ref_mut()[0];
+    assert!(c0.representation() == &Representation::Coefficient);
+    ctx.add_assign(c0, &noise);
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use crate::utils::bfv_setup_test;
+
+    use super::*;
+
+    #[test]
+    fn rerandomize_works() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+        let params = evaluator.params();
+
+        let m = (0..params.degree)
+            .map(|_| rng.gen_range(0..params.plaintext_modulus))
+            .collect::<Vec<u64>>();
+        let ct = evaluator.encrypt(
+            &sk,
+            &evaluator.plaintext_encode(&m, Encoding::default()),
+            &mut rng,
+        );
+
+        let zero_cts = (0..CIRCUIT_PRIVACY_ZERO_CTS)
+            .map(|_| {
+                evaluator.encrypt(
+                    &sk,
+                    &evaluator.plaintext_encode(&[], Encoding::default()),
+                    &mut rng,
+                )
+            })
+            .collect::<Vec<_>>();
+        let zero_cts = prepare_zero_cts(&evaluator, &zero_cts, 0);
+
+        let mut rerandomized = ct.clone();
+        rerandomize(&evaluator, &mut rerandomized, &zero_cts, &mut rng);
+        assert_ne!(rerandomized, ct);
+        assert_eq!(
+            evaluator.plaintext_decode(&evaluator.decrypt(&sk, &rerandomized), Encoding::default()),
+            m
+        );
+    }
+
+    #[test]
+    fn smudged_noise_is_independent_of_input() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+        let params = evaluator.params();
+
+        let m = (0..params.degree)
+            .map(|_| rng.gen_range(0..params.plaintext_modulus))
+            .collect::<Vec<u64>>();
+        let fresh = evaluator.encrypt(
+            &sk,
+            &evaluator.plaintext_encode(&m, Encoding::default()),
+            &mut rng,
+        );
+        // rerandomizing keeps message but grows noise
+        let zero_cts = (0..CIRCUIT_PRIVACY_ZERO_CTS)
+            .map(|_| {
+                evaluator.encrypt(
+                    &sk,
+                    &evaluator.plaintext_encode(&[], Encoding::default()),
+                    &mut rng,
+                )
+            })
+            .collect::<Vec<_>>();
+        let mut noisy = fresh.clone();
+        rerandomize(
+            &evaluator,
+            &mut noisy,
+            &prepare_zero_cts(&evaluator, &zero_cts, 0),
+            &mut rng,
+        );
+        let fresh_budget = evaluator.measure_noise(&sk, &fresh);
+        assert!(evaluator.measure_noise(&sk, &noisy) < fresh_budget);
+
+        // smudging noise far above noise of either input leaves the same budget for both
+        let noise_bits = (fresh_budget as usize).saturating_sub(20);
+        let budgets = [fresh, noisy]
+            .into_iter()
+            .map(|mut ct| {
+                smudge(&evaluator, &mut ct, noise_bits, &mut rng);
+                assert_eq!(
+                    evaluator.plaintext_decode(&evaluator.decrypt(&sk, &ct), Encoding::default()),
+                    m
+                );
+                evaluator.measure_noise(&sk, &ct)
+            })
+            .collect::<Vec<_>>();
+        assert!(budgets[0].abs_diff(budgets[1]) <= 1);
+        assert!(budgets[0] < fresh_budget);
+    }
+
+    #[test]
+    fn smudging_noise_exceeds_evaluation_noise() {
+        let psi_params = PsiParams::default();
+        assert_eq!(
+            smudging_noise_bits(&psi_params).unwrap(),
+            evaluation_noise_bits(&psi_params).unwrap() + SMUDGING_SECURITY_BITS
+        );
+        let dropped = psi_params.clone().with_evaluation_level(1);
+        assert_eq!(
+            smudging_noise_bits(&dropped).unwrap() + psi_params.bfv_moduli[0],
+            smudging_noise_bits(&psi_params).unwrap()
+        );
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    paterson_stockmeyer::{ps_encode_coefficients, ps_evaluate_poly, PSParams},
+    ModqElement, PsiError, PsiParams,
+};
+use bfv::{Ciphertext, EvaluationKey, Evaluator, Plaintext};
+use ndarray::{s, Array2, ArrayView2};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Polynomial coefficients of a single label part of InnerBox, with a row per slot and a column per degree.
+/// Coefficients are stored as u16s or u32s depending on plaintext modulus, see `PsiParams::coefficient_bytes`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Coefficients {
+    U16(Array2<u16>),
+    U32(Array2<u32>),
+}
+
+impl Coefficients {
+    /// Returns zero coefficients of `shape` stored with element type of `psi_params`
+    pub(crate) fn zeros(psi_params: &PsiParams, shape: (usize, usize)) -> Coefficients {
+        match psi_params.coefficient_bytes() {
+            2 => Coefficients::U16(Array2::zeros(shape)),
+            _ => Coefficients::U32(Array2::zeros(shape)),
+        }
+    }
+
+    pub(crate) fn view(&self) -> CoefficientsView<'_> {
+        match self {
+            Coefficients::U16(c) => CoefficientsView::U16(c.view()),
+            Coefficients::U32(c) => CoefficientsView::U32(c.view()),
+        }
+    }
+
+    pub(crate) fn shape(&self) -> &[usize] {
+        match self {
+            Coefficients::U16(c) => c.shape(),
+            Coefficients::U32(c) => c.shape(),
+        }
+    }
+
+    /// Resizes to `cols` columns. Columns that are kept retain their values, new columns are zero.
+    pub(crate) fn resize_columns(&mut self, cols: usize) {
+        fn resize<T: ModqElement>(coefficients: &Array2<T>, cols: usize) -> Array2<T> {
+            let kept = cols.min(coefficients.shape()[1]);
+            let mut resized = Array2::<T>::from_elem((coefficients.shape()[0], cols), T::default());
+            resized
+                .slice_mut(s![.., ..kept])
+                .assign(&coefficients.slice(s![.., ..kept]));
+            resized
+        }
+
+        match self {
+            Coefficients::U16(c) => *c = resize(c, cols),
+            Coefficients::U32(c) => *c = resize(c, cols),
+        }
+    }
+
+    /// Sets row at `index` to `values` followed by zeros. Values are converted to element type of coefficients, thus
+    /// must be below plaintext modulus.
+    pub(crate) fn set_row<V: ModqElement>(&mut self, index: usize, values: &[V]) {
+        fn set<T: ModqElement, V: ModqElement>(row: &mut [T], values: &[V]) {
+            row.fill(T::default());
+            row.iter_mut()
+                .zip(values)
+                .for_each(|(c, v)| *c = T::from_u64((*v).into()));
+        }
+
+        match self {
+            Coefficients::U16(c) => set(c.row_mut(index).as_slice_mut().unwrap(), values),
+            Coefficients::U32(c) => set(c.row_mut(index).as_slice_mut().unwrap(), values),
+        }
+    }
+}
+
+/// Sets each row of `coefficients`, in parallel, to coefficients returned by `row_coefficients` for its index followed
+/// by zeros
+pub(crate) fn try_fill_rows<T: ModqElement>(
+    coefficients: &mut Array2<T>,
+    row_coefficients: impl Fn(usize) -> Result<Vec<T>, PsiError> + Sync,
+) -> Result<(), PsiError> {
+    coefficients
+        .outer_iter_mut()
+        .enumerate()
+        .par_bridge()
+        .try_for_each(|(index, mut row)| {
+            let c = row_coefficients(index)?;
+            row.as_slice_mut().unwrap()[..c.len()].copy_from_slice(&c);
+            Ok(())
+        })
+}
+
+/// Borrowed `Coefficients`, either owned by InnerBox or memory mapped from db file
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum CoefficientsView<'a> {
+    U16(ArrayView2<'a, u16>),
+    U32(ArrayView2<'a, u32>),
+}
+
+impl CoefficientsView<'_> {
+    pub(crate) fn shape(&self) -> &[usize] {
+        match self {
+            CoefficientsView::U16(c) => c.shape(),
+            CoefficientsView::U32(c) => c.shape(),
+        }
+    }
+
+    /// No. of coefficients
+    pub(crate) fn len(&self) -> usize {
+        self.shape()[0] * self.shape()[1]
+    }
+
+    /// Bytes of a single coefficient
+    pub(crate) fn value_bytes(&self) -> u32 {
+        match self {
+            CoefficientsView::U16(_) => 2,
+            CoefficientsView::U32(_) => 4,
+        }
+    }
+
+    pub(crate) fn into_owned(self) -> Coefficients {
+        match self {
+            CoefficientsView::U16(c) => Coefficients::U16(c.to_owned()),
+            CoefficientsView::U32(c) => Coefficients::U32(c.to_owned()),
+        }
+    }
+
+    /// Returns copy of coefficients widened to u32s
+    pub(crate) fn widened(self) -> Array2<u32> {
+        match self {
+            CoefficientsView::U16(c) => c.mapv(u32::from),
+            CoefficientsView::U32(c) => c.to_owned(),
+        }
+    }
+
+    /// Encodes coefficients as plaintexts at `level`, see `ps_encode_coefficients`
+    pub(crate) fn encode(
+        &self,
+        evaluator: &Evaluator,
+        ps_params: &PSParams,
+        level: usize,
+    ) -> Vec<Plaintext> {
+        match self {
+            CoefficientsView::U16(c) => ps_encode_coefficients(evaluator, ps_params, *c, level),
+            CoefficientsView::U32(c) => ps_encode_coefficients(evaluator, ps_params, *c, level),
+        }
+    }
+
+    /// Evaluates polynomials on `x_powers` with coefficients encoded at `level`, see `ps_evaluate_poly`
+    pub(crate) fn evaluate(
+        &self,
+        evaluator: &Evaluator,
+        ek: &EvaluationKey,
+        x_powers: &HashMap<usize, Ciphertext>,
+        ps_params: &PSParams,
+        level: usize,
+    ) -> Ciphertext {
+        match self {
+            CoefficientsView::U16(c) => {
+                ps_evaluate_poly(evaluator, ek, x_powers, ps_params, *c, level)
+            }
+            CoefficientsView::U32(c) => {
+                ps_evaluate_poly(evaluator, ek, x_powers, ps_params, *c, level)
+            }
+        }
+    }
+}
@@ -1,24 +1,165 @@
-use ndarray::Axis;
-use rayon::{prelude::*, slice::ParallelSlice};
-
-use crate::time_it;
+use ndarray::{s, Axis};
+use rand::{thread_rng, Rng};
+use rayon::prelude::*;
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, debug_span, info, info_span};
+
+use crate::{rows_per_segment, server::storage::malformed, time_it, ModqElement, QueryLayout};
 
 use super::*;
 
+/// No. of InnerBoxRow columns by which item and label data of InnerBox grow once occupied columns exceed allocated ones
+const DATA_COLUMNS_BLOCK: u32 = 64;
+
 /// Vector of `HashTableQueryResponse`, one for each BigBox
 #[derive(Debug, PartialEq)]
 pub struct QueryResponse(pub(crate) Vec<HashTableQueryResponse>);
 
+impl QueryResponse {
+    /// Responses of each hash table, in order of BigBoxes
+    pub fn hash_table_responses(&self) -> impl Iterator<Item = &HashTableQueryResponse> {
+        self.0.iter()
+    }
+
+    /// No. of response ciphertexts across all hash tables
+    pub fn ciphertexts_count(&self) -> usize {
+        self.0.iter().map(|ht| ht.0.iter().flatten().count()).sum()
+    }
+}
+
+/// Response ciphertexts of a single segment of BigBox `big_box`. Streamed to client as soon as the segment is
+/// processed.
+#[derive(Debug, PartialEq)]
+pub struct SegmentResponse {
+    pub(crate) big_box: usize,
+    pub(crate) segment: usize,
+    pub(crate) cts: Vec<Ciphertext>,
+    pub(crate) times: SegmentStageTimes,
+}
+
+impl SegmentResponse {
+    pub fn big_box(&self) -> usize {
+        self.big_box
+    }
+
+    pub fn segment(&self) -> usize {
+        self.segment
+    }
+
+    pub fn times(&self) -> &SegmentStageTimes {
+        &self.times
+    }
+}
+
+/// Time spent in each stage of processing query of a single segment
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SegmentStageTimes {
+    /// Calculating PS powers from source powers
+    pub powers: Duration,
+    /// Evaluating polynomials of all InnerBoxes of the segment on PS powers
+    pub evaluation: Duration,
+}
+
 /// Contains 2D array of ciphertexts where each row contains response ciphertexts corresponding to a single Segment in BigBox (ie hash table)
 #[derive(Debug, PartialEq)]
 pub struct HashTableQueryResponse(pub(crate) Vec<Vec<Ciphertext>>);
 
+impl HashTableQueryResponse {
+    /// Response ciphertexts of each segment, `PsiParams::label_parts` for each InnerBox of the segment
+    pub fn segments(&self) -> impl Iterator<Item = &[Ciphertext]> {
+        self.0.iter().map(|cts| cts.as_slice())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum InsertError {
+    /// None of the InnerBoxes in segment have space at the row and the segment already has
+    /// `PsiParams::max_inner_boxes_per_segment` InnerBoxes.
+    SegmentFull {
+        big_box: usize,
+        segment: usize,
+        max_inner_boxes: u32,
+    },
+    /// Label is longer than `PsiPlaintext::label_bytes`
+    LabelTooLong {
+        label_bytes: usize,
+        max_label_bytes: u32,
+    },
+}
+
+impl std::fmt::Display for InsertError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            InsertError::SegmentFull {
+                big_box,
+                segment,
+                max_inner_boxes,
+            } => write!(
+                f,
+                "Segment {segment} of BigBox {big_box} is full ({max_inner_boxes} InnerBoxes). Consider increasing `ht_size` or `eval_degree`."
+            ),
+            InsertError::LabelTooLong {
+                label_bytes,
+                max_label_bytes,
+            } => write!(
+                f,
+                "Label of {label_bytes} bytes exceeds max. label size of {max_label_bytes} bytes"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InsertError {}
+
+fn check_label_size(item_label: &ItemLabel, psi_params: &PsiParams) -> Result<(), InsertError> {
+    let label_bytes = item_label.label().as_bytes().len();
+    if psi_params.mode == PsiMode::Labeled && label_bytes > psi_params.psi_pt.label_bytes as usize {
+        return Err(InsertError::LabelTooLong {
+            label_bytes,
+            max_label_bytes: psi_params.psi_pt.label_bytes,
+        });
+    }
+    Ok(())
+}
+
+/// Moves `span` bytes at column `from` to column `to` in `row` of `data` and zeroes bytes at `from`
+fn move_col(data: &mut Array2<u8>, row: usize, from: usize, to: usize, span: usize) {
+    let mut data_row = data.row_mut(row);
+    let data_row = data_row.as_slice_mut().unwrap();
+    if from != to {
+        data_row.copy_within(from..from + span, to);
+    }
+    data_row[from..from + span].fill(0);
+}
+
+/// Returns `count` random points of `Z_modq`, distinct from each other and from points in `x`. Used to pad rows with
+/// `PsiParams::random_padding`.
+fn random_padding_points<T: ModqElement>(x: &[T], count: usize, modq: u32) -> Vec<T> {
+    let mut rng = thread_rng();
+    let mut taken = x.iter().map(|v| (*v).into()).collect::<HashSet<u64>>();
+    let mut points = Vec::with_capacity(count);
+    while points.len() < count {
+        let point = rng.gen_range(0..modq as u64);
+        if taken.insert(point) {
+            points.push(T::from_u64(point));
+        }
+    }
+    points
+}
+
 /// A single InnerBoxRow is a wrapper over `span` rows.
 /// It helps view a single column spanned across multiple
 /// rows as a single row. This is required since a single data
 /// entry spans across multiple Rows.
 #[derive(Serialize, Deserialize)]
-pub struct InnerBoxRow {
+pub(crate) struct InnerBoxRow {
     /// No. of real rows in a single InnerBoxRow
     row_span: u32,
     /// No. of real cols u8s in single InnerBoxRow column
@@ -26,17 +167,22 @@ pub struct InnerBoxRow {
     max_cols: u32,
     // no. of curr columns occupied
     curr_cols: u32,
+    /// Set when columns of row change after coefficients were generated, until row is re-interpolated by
+    /// `InnerBox::regenerate_dirty`
+    #[serde(skip)]
+    dirty: bool,
 }
 impl InnerBoxRow {
-    fn new(psi_pt: &PsiPlaintext, eval_degree: &EvalPolyDegree) -> InnerBoxRow {
+    fn new(psi_pt: &PsiPlaintext, max_cols: u32) -> InnerBoxRow {
         let row_span = psi_pt.slots_required();
         // A real row within InnerBoxRow is byte buffer. Thus a single InnerBoxRow column spans across multiple columns to store value with `bfv_pt_bytes` bytes.
         let col_span = psi_pt.bfv_pt_bytes;
         InnerBoxRow {
             row_span,
             col_span,
-            max_cols: eval_degree.inner_box_columns(),
+            max_cols,
             curr_cols: 0,
+            dirty: false,
         }
     }
 
@@ -65,10 +211,98 @@ impl InnerBoxRow {
     }
 }
 
+/// Interpolates polynomials of real rows of InnerBox. Holds fields of InnerBox separately so that it can be used while
+/// coefficients of InnerBox are mutably borrowed.
+struct RowInterpolator<'a> {
+    psi_params: &'a PsiParams,
+    ht_rows: &'a [InnerBoxRow],
+    item_data: &'a Array2<u8>,
+    label_data: &'a Array2<u8>,
+    /// Interpolate each polynomial using multiple threads, which is only useful when few rows are interpolated
+    parallel: bool,
+}
+
+impl RowInterpolator<'_> {
+    /// Interpolates polynomial for label part `part` at real row `index` and returns its coefficients as `T`s, which
+    /// must hold values below plaintext modulus
+    fn interpolate<T: ModqElement>(&self, part: usize, index: usize) -> Result<Vec<T>, PsiError> {
+        let psi_params = self.psi_params;
+        let ht_rows = self.ht_rows;
+
+        // map real row to InnerBoxRow index
+        let ibr_index = index / psi_params.psi_pt.slots_required() as usize;
+
+        // limit polynomial interpolation to maximum columns occupied
+        let cols_occupied = ht_rows[ibr_index].curr_cols as usize;
+        let col_span = ht_rows[ibr_index].col_span as usize;
+
+        // empty rows aren't interpolated unless padded. Label polynomial of empty row is zero and its membership
+        // polynomial is 1, ie has no roots.
+        let membership = psi_params.membership_part() == Some(part);
+        if cols_occupied == 0 && !psi_params.random_padding {
+            return Ok(match membership {
+                false => vec![],
+                true => vec![T::from_u64(1)],
+            });
+        }
+
+        // convert buffers to values for interpolation
+        let modq = psi_params.psi_pt.bfv_pt as u32;
+        let to_value = |value_bytes: &[u8]| T::from_u64(bytes_to_u32(value_bytes) as u64);
+        let mut x = self.item_data.row(index).as_slice().unwrap()[..col_span * cols_occupied]
+            .chunks_exact(col_span)
+            .map(to_value)
+            .collect_vec();
+        let padding = if psi_params.random_padding {
+            ht_rows[ibr_index].max_cols() as usize - cols_occupied
+        } else {
+            0
+        };
+        x.extend(random_padding_points(&x, padding, modq));
+
+        if membership {
+            // membership polynomial evaluates to 0 only at item chunks in the row. Random padding, which would add
+            // roots, is rejected with membership polynomials by `PsiParams::validate`.
+            return Ok(poly_from_roots(&x, modq));
+        }
+
+        let mut rng = thread_rng();
+        let y = self
+            .label_data
+            .row(part * psi_params.ct_slots.0 as usize + index)
+            .as_slice()
+            .unwrap()[..col_span * cols_occupied]
+            .chunks_exact(col_span)
+            .map(to_value)
+            .chain((0..padding).map(|_| T::from_u64(rng.gen_range(0..modq) as u64)))
+            .collect_vec();
+        match psi_params.interpolation {
+            InterpolationMethod::Newton if self.parallel => {
+                newton_interpolate_parallel(&x, &y, modq)
+            }
+            method => interpolate(&x, &y, modq, method),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
-pub struct InnerBox {
-    coefficients_data: Array2<u32>,
+pub(crate) struct InnerBox {
+    /// Coefficients of interpolated polynomials, one matrix for each label part. Empty if coefficients are memory
+    /// mapped.
+    coefficients_data: Vec<Coefficients>,
+    /// Coefficients memory mapped from db file stored with `Db::store`. Copied into `coefficients_data` before they are
+    /// updated.
+    #[serde(skip)]
+    mapped_coefficients: Option<MappedCoefficients>,
+    /// Coefficients of `coefficients_data` encoded as plaintexts at level 0, one vector (indexed by degree) for each
+    /// label part. Empty unless `PsiParams::precompute_plaintexts` is set. Cleared whenever coefficients change.
+    #[serde(skip)]
+    encoded_coefficients: Vec<Vec<Plaintext>>,
+    /// Item chunks of occupied columns. Only columns upto the most occupied row, rounded up to `DATA_COLUMNS_BLOCK`,
+    /// are allocated.
     item_data: Array2<u8>,
+    /// Label chunks of each label part stored one after another. Label part `p` occupies real rows
+    /// `p * ct_slots..(p + 1) * ct_slots`.
     label_data: Array2<u8>,
     ht_rows: Vec<InnerBoxRow>,
     /// Is set to initialised when a new item is added
@@ -83,18 +317,22 @@ impl InnerBox {
     /// has `lane_span`rows.
     fn new(psi_params: &PsiParams) -> InnerBox {
         // A single entry spans across multiple slots
-        let slots_per_entry = psi_params.psi_pt.slots_required();
-        let row_count = psi_params.ct_slots.0 / slots_per_entry;
+        let row_count = rows_per_segment(&psi_params.ct_slots, &psi_params.psi_pt);
         let ht_rows = (0..row_count)
             .into_iter()
-            .map(|_| InnerBoxRow::new(&psi_params.psi_pt, &psi_params.eval_degree))
+            .map(|_| InnerBoxRow::new(&psi_params.psi_pt, psi_params.inner_box_columns()))
             .collect_vec();
 
-        // initialise containers for data
-        let col_count =
-            (psi_params.eval_degree.inner_box_columns() * psi_params.psi_pt.bfv_pt_bytes) as usize;
-        let label_data = Array2::<u8>::zeros((psi_params.ct_slots.0 as usize, col_count));
-        let item_data = Array2::<u8>::zeros((psi_params.ct_slots.0 as usize, col_count));
+        // initialise empty containers for data, columns are allocated on insert (see `reserve_data_columns`). Labels
+        // aren't stored in unlabeled mode.
+        let label_data = match psi_params.mode {
+            PsiMode::Labeled => Array2::<u8>::zeros((
+                (psi_params.ct_slots.0 * psi_params.psi_pt.label_parts()) as usize,
+                0,
+            )),
+            PsiMode::Unlabeled => Array2::<u8>::zeros((0, 0)),
+        };
+        let item_data = Array2::<u8>::zeros((psi_params.ct_slots.0 as usize, 0));
 
         // println!(
         //     "Created InnerBox with {row_count} rows and {} cols",
@@ -102,7 +340,9 @@ impl InnerBox {
         // );
 
         InnerBox {
-            coefficients_data: Array2::zeros((0, 0)),
+            coefficients_data: vec![],
+            mapped_coefficients: None,
+            encoded_coefficients: vec![],
             item_data,
             label_data,
             ht_rows,
@@ -112,6 +352,29 @@ impl InnerBox {
         }
     }
 
+    /// Makes sure item and label data have atleast `cols` InnerBoxRow columns allocated. Data grows in blocks of
+    /// `DATA_COLUMNS_BLOCK` columns, upto `PsiParams::inner_box_columns`, thus sparsely occupied InnerBoxes don't
+    /// allocate data for all columns.
+    fn reserve_data_columns(&mut self, cols: u32) {
+        let col_span = self.psi_params.psi_pt.bfv_pt_bytes as usize;
+        if self.item_data.shape()[1] >= cols as usize * col_span {
+            return;
+        }
+
+        let allocated_cols = (cols.div_ceil(DATA_COLUMNS_BLOCK) * DATA_COLUMNS_BLOCK)
+            .min(self.psi_params.inner_box_columns()) as usize
+            * col_span;
+        let grow = |data: &Array2<u8>| {
+            let mut grown = Array2::<u8>::zeros((data.shape()[0], allocated_cols));
+            grown.slice_mut(s![.., ..data.shape()[1]]).assign(data);
+            grown
+        };
+        self.item_data = grow(&self.item_data);
+        if self.psi_params.mode == PsiMode::Labeled {
+            self.label_data = grow(&self.label_data);
+        }
+    }
+
     /// Checks whether ItemLabel can be inserted in row at `index`.
     ///
     /// To insert, two conditions must be met
@@ -128,8 +391,8 @@ impl InnerBox {
         let real_row = row * row_span as usize;
         let mut can_insert = true;
         for i in real_row..real_row + self.psi_params.psi_pt.slots_required() as usize {
-            let (item_chunk, _) =
-                item_label.get_chunk_at_index((i - real_row) as u32, &self.psi_params.psi_pt);
+            let item_chunk =
+                item_label.item_chunk_at_index((i - real_row) as u32, &self.psi_params.psi_pt);
 
             if self
                 .item_data_hash_set
@@ -167,98 +430,148 @@ impl InnerBox {
         // get next free column at InnerRow
         let col = self.ht_rows[row].next_free_col_index();
         let col_span = self.ht_rows[row].col_span as usize;
+        self.reserve_data_columns(col as u32 + 1);
         let real_col_start = col * col_span;
         let real_col_end = col * col_span + col_span;
 
         // map InnerRow to row in container row
         let real_row = row * self.psi_params.psi_pt.slots_required() as usize;
 
-        for ri in real_row..(real_row + self.psi_params.psi_pt.slots_required() as usize) {
+        let slots_required = self.psi_params.psi_pt.slots_required() as usize;
+        for ri in real_row..(real_row + slots_required) {
             // get data chunk
             let chunk_index = (ri - real_row) as u32;
-            let (item_chunk, label_chunk) = item_label.get_chunk_at_index(chunk_index, psi_pt);
+            let item_chunk = item_label.item_chunk_at_index(chunk_index, psi_pt);
 
-            // println!(
-            //     "[IB] Inserting ItemLabel - item:{}, chunk_index:{chunk_index}, chunk:{:?}, label:{:?}, InnerBox Row:{row}, Real Row:{ri}",
-            //     item_label.item(),
-            //     &item_chunk,
-            //     &label_chunk
-            // );
-
-            // add the item and label chunk
+            // add the item chunk
             for ci in real_col_start..real_col_end {
                 let entry = self.item_data.get_mut((ri, ci)).unwrap();
                 *entry = item_chunk[ci - real_col_start];
-                let entry = self.label_data.get_mut((ri, ci)).unwrap();
-                *entry = label_chunk[ci - real_col_start];
             }
 
             self.item_data_hash_set
                 .insert((ri, bytes_to_u16(&item_chunk)));
         }
+        self.write_label(row, col, item_label);
 
         // increase columns occupancy by 1
         self.ht_rows[row].curr_cols += 1;
+        self.ht_rows[row].dirty = true;
         self.initialised = true;
     }
 
-    /// Returns maximum no. of rows it can have depending on params
-    fn max_rows(psi_pt: &PsiPlaintext, ct_slots: &CiphertextSlots) -> u32 {
-        ct_slots.0 / psi_pt.slots_required()
+    /// Writes label chunks of each label part of `item_label` at column `col` of InnerBoxRow at `row`. Does nothing in
+    /// unlabeled mode.
+    fn write_label(&mut self, row: usize, col: usize, item_label: &ItemLabel) {
+        if self.psi_params.mode != PsiMode::Labeled {
+            return;
+        }
+        let psi_pt = &self.psi_params.psi_pt;
+
+        // label chunks include checksum of item, if enabled
+        let checksummed = item_label.with_label_checksum(&self.psi_params);
+        let item_label = checksummed.as_ref().unwrap_or(item_label);
+
+        let col_span = self.ht_rows[row].col_span as usize;
+        let real_col_start = col * col_span;
+        let real_row = self.ht_rows[row].map_to_real_row(row);
+        let slots_required = psi_pt.slots_required() as usize;
+        for ri in real_row..(real_row + slots_required) {
+            let chunk_index = (ri - real_row) as u32;
+            for part in 0..psi_pt.label_parts() as usize {
+                let label_chunk = item_label
+                    .label_chunk_at_index((part * slots_required) as u32 + chunk_index, psi_pt);
+                let label_row = part * self.psi_params.ct_slots.0 as usize + ri;
+                for ci in real_col_start..real_col_start + col_span {
+                    let entry = self.label_data.get_mut((label_row, ci)).unwrap();
+                    *entry = label_chunk[ci - real_col_start];
+                }
+            }
+        }
+    }
+
+    /// Replaces label of item of `item_label` in InnerBoxRow at `row` with label of `item_label`, marking the row dirty.
+    /// Returns false if item does not exist at `row`.
+    fn replace_label(&mut self, row: usize, item_label: &ItemLabel) -> bool {
+        let col = match self.find_item_col(row, item_label.item()) {
+            Some(col) => col,
+            None => return false,
+        };
+        self.write_label(row, col, item_label);
+        self.ht_rows[row].dirty = true;
+        true
     }
 
-    /// Iterates through all rows and generates coefficients
-    ///
-    /// TODO: Avoid rows that haven't been touched
-    fn generate_coefficients(&mut self) {
-        self.coefficients_data = Array2::<u32>::zeros((
-            self.psi_params.ct_slots.0 as usize,
-            self.psi_params.eval_degree.inner_box_columns() as usize,
-        ));
-
-        println!(
-            "
-            --------------------------------------
-            [IB] Generating Coefficients for IB with InnerBoxRows: {},
-            No. of polynomials with degree {} interpolate: {}
-
-            ",
-            self.ht_rows.len(),
-            self.coefficients_data.shape()[1],
-            self.coefficients_data.shape()[0]
+    /// No. of coefficient columns of polynomials of InnerBox, set by its most occupied row. See
+    /// `PSParams::columns_for_degree`.
+    pub(crate) fn coefficients_columns(&self) -> usize {
+        // padded rows are fully occupied
+        let max_cols = self
+            .ht_rows
+            .iter()
+            .map(|row| match self.psi_params.random_padding {
+                true => row.max_cols() as usize,
+                false => row.curr_cols as usize,
+            })
+            .max()
+            .unwrap_or(0);
+        // labels of n items are interpolated with polynomial of degree n - 1, membership polynomial of n items has
+        // degree n
+        let degree = match self.psi_params.membership_part() {
+            None => max_cols.saturating_sub(1),
+            Some(_) => max_cols,
+        };
+        self.psi_params.ps_params.columns_for_degree(degree)
+    }
+
+    /// Iterates through all rows and generates coefficients. Empty rows aren't interpolated and coefficients are only
+    /// stored upto the degree of most occupied row (see `coefficients_columns`). Clears dirty flags of all rows.
+    pub(crate) fn generate_coefficients(&mut self) -> Result<(), PsiError> {
+        self.ht_rows.iter_mut().for_each(|row| row.dirty = false);
+        self.encoded_coefficients.clear();
+        self.mapped_coefficients = None;
+        let ct_slots = self.psi_params.ct_slots.0 as usize;
+        let columns = self.coefficients_columns();
+        self.coefficients_data = (0..self.psi_params.label_parts())
+            .map(|_| Coefficients::zeros(&self.psi_params, (ct_slots, columns)))
+            .collect_vec();
+
+        let empty_rows = match self.psi_params.random_padding {
+            true => 0,
+            false => self.ht_rows.iter().filter(|row| row.curr_cols == 0).count(),
+        };
+        debug!(
+            rows = self.ht_rows.len(),
+            empty_rows,
+            degree = columns,
+            polynomials = self.coefficients_data[0].shape()[0] * self.coefficients_data.len(),
+            "Generating coefficients of InnerBox"
         );
 
-        izip!(
-            self.coefficients_data.outer_iter_mut(),
-            self.item_data.outer_iter(),
-            self.label_data.outer_iter()
-        )
-        .enumerate()
-        .par_bridge()
-        .for_each(|(index, (mut coeffs, item, label))| {
-            // map real row to InnerBoxRow index
-            let ibr_index = index / self.psi_params.psi_pt.slots_required() as usize;
-
-            // limit polynomial interpolation to maximum columns occupied
-            let cols_occupied = self.ht_rows[ibr_index].curr_cols as usize;
-            let col_span = self.ht_rows[ibr_index].col_span as usize;
-
-            // TODO: uncomment
-            // println!("[IB] Interpolating polynomial of degree {cols_occupied}");
-
-            // convert buffers to values for interpolation
-            let x = item.as_slice().unwrap()[..col_span * cols_occupied]
-                .chunks_exact(col_span)
-                .map(|value_bytes| bytes_to_u32(value_bytes))
-                .collect_vec();
-            let y = label.as_slice().unwrap()[..col_span * cols_occupied]
-                .chunks_exact(col_span)
-                .map(|value_bytes| bytes_to_u32(value_bytes))
-                .collect_vec();
-
-            let c = newton_interpolate(&x, &y, self.psi_params.psi_pt.bfv_pt as u32);
-            coeffs.as_slice_mut().unwrap()[..cols_occupied].copy_from_slice(&c);
-        });
+        // Interpolate each polynomial with multiple threads if there are fewer polynomials than threads
+        let no_of_polys = (self.ht_rows.len() - empty_rows)
+            * self.psi_params.psi_pt.slots_required() as usize
+            * self.coefficients_data.len();
+        let parallel = no_of_polys < rayon::current_num_threads();
+
+        // polynomials are interpolated directly in element type of coefficients
+        let interpolator = RowInterpolator {
+            psi_params: &self.psi_params,
+            ht_rows: &self.ht_rows,
+            item_data: &self.item_data,
+            label_data: &self.label_data,
+            parallel,
+        };
+        for (part, coefficients) in self.coefficients_data.iter_mut().enumerate() {
+            match coefficients {
+                Coefficients::U16(c) => {
+                    try_fill_rows(c, |index| interpolator.interpolate(part, index))
+                }
+                Coefficients::U32(c) => {
+                    try_fill_rows(c, |index| interpolator.interpolate(part, index))
+                }
+            }?;
+        }
 
         // println!(
         //     "
@@ -266,28 +579,314 @@ impl InnerBox {
         //     ########
         //     ",
         // )
+        Ok(())
+    }
+
+    /// Re-interpolates polynomials of real rows spanned by dirty InnerBoxRows only, that is rows whose columns changed
+    /// since coefficients were generated, and clears their dirty flags. If InnerBox hasn't been preprocessed yet (for
+    /// ex, it was created by an incremental insert), coefficients are generated for all rows. Returns no. of dirty
+    /// InnerBoxRows.
+    pub(crate) fn regenerate_dirty(&mut self) -> Result<usize, PsiError> {
+        let dirty_rows = self
+            .ht_rows
+            .iter()
+            .enumerate()
+            .filter(|(_, ibr)| ibr.dirty)
+            .map(|(row, _)| row)
+            .collect_vec();
+        if dirty_rows.is_empty() {
+            return Ok(0);
+        }
+        if !self.has_coefficients() {
+            self.generate_coefficients()?;
+            return Ok(dirty_rows.len());
+        }
+        self.materialize_coefficients();
+        self.encoded_coefficients.clear();
+
+        // degree of most occupied row changes with inserts and removals. Truncated columns are zero in every row other
+        // than those re-interpolated.
+        let columns = self.coefficients_columns();
+        if self.coefficients_data[0].shape()[1] != columns {
+            self.coefficients_data
+                .iter_mut()
+                .for_each(|coefficients| coefficients.resize_columns(columns));
+        }
+
+        let ht_rows = &self.ht_rows;
+        let real_rows = (0..self.coefficients_data.len())
+            .flat_map(|part| {
+                dirty_rows.iter().flat_map(move |row| {
+                    let ibr = &ht_rows[*row];
+                    let real_row = ibr.map_to_real_row(*row);
+                    (real_row..real_row + ibr.row_span as usize).map(move |index| (part, index))
+                })
+            })
+            .collect_vec();
+
+        // Interpolate rows in parallel if there are enough rows to occupy all threads. Otherwise interpolate each
+        // polynomial with multiple threads. Only a few rows are re-interpolated, thus they are interpolated as u32s and
+        // converted to element type of coefficients when set.
+        let parallel_rows = real_rows.len() >= rayon::current_num_threads();
+        let interpolator = RowInterpolator {
+            psi_params: &self.psi_params,
+            ht_rows: &self.ht_rows,
+            item_data: &self.item_data,
+            label_data: &self.label_data,
+            parallel: !parallel_rows,
+        };
+        let interpolate_row =
+            |(part, index): &(usize, usize)| interpolator.interpolate::<u32>(*part, *index);
+        let coefficients = if parallel_rows {
+            real_rows
+                .par_iter()
+                .map(interpolate_row)
+                .collect::<Result<Vec<_>, PsiError>>()?
+        } else {
+            real_rows
+                .iter()
+                .map(interpolate_row)
+                .collect::<Result<Vec<_>, PsiError>>()?
+        };
+
+        izip!(real_rows.iter(), coefficients.iter()).for_each(|((part, index), c)| {
+            self.coefficients_data[*part].set_row(*index, c);
+        });
+        dirty_rows
+            .iter()
+            .for_each(|row| self.ht_rows[*row].dirty = false);
+        Ok(dirty_rows.len())
+    }
+
+    /// Returns coefficients of each label part, whether they are owned or memory mapped
+    pub(crate) fn coefficients(&self) -> Vec<CoefficientsView<'_>> {
+        match &self.mapped_coefficients {
+            Some(mapped) => mapped.views(),
+            None => self.coefficients_data.iter().map(|c| c.view()).collect(),
+        }
+    }
+
+    /// Returns false if InnerBox hasn't been preprocessed yet
+    pub(crate) fn has_coefficients(&self) -> bool {
+        !self.coefficients_data.is_empty() || self.mapped_coefficients.is_some()
+    }
+
+    /// Copies memory mapped coefficients into memory so that they can be updated
+    fn materialize_coefficients(&mut self) {
+        if let Some(mapped) = self.mapped_coefficients.take() {
+            self.coefficients_data = mapped.views().iter().map(|c| c.into_owned()).collect();
+        }
+    }
+
+    /// Moves out owned coefficients, leaving InnerBox without them. Used to drop coefficients once they are written to
+    /// db file.
+    pub(crate) fn take_coefficients(&mut self) -> Vec<Coefficients> {
+        std::mem::take(&mut self.coefficients_data)
+    }
+
+    pub(crate) fn set_mapped_coefficients(&mut self, mapped: MappedCoefficients) {
+        self.coefficients_data.clear();
+        self.encoded_coefficients.clear();
+        self.mapped_coefficients = Some(mapped);
+    }
+
+    /// Returns InnerBox without coefficients as `InnerBoxRecord` to be archived with rest of db
+    pub(crate) fn record(&self) -> InnerBoxRecord {
+        InnerBoxRecord {
+            curr_cols: self.ht_rows.iter().map(|r| r.curr_cols).collect_vec(),
+            item_data: DataRecord::of(&self.item_data),
+            label_data: DataRecord::of(&self.label_data),
+            initialised: self.initialised,
+        }
+    }
+
+    /// Restores InnerBox without coefficients from `archived` record. Item chunks of occupied columns are inserted
+    /// into `item_data_hash_set` again, since it isn't archived.
+    pub(crate) fn from_record(
+        archived: &ArchivedInnerBoxRecord,
+        psi_params: &PsiParams,
+    ) -> Result<InnerBox, PsiError> {
+        let mut inner_box = InnerBox::new(psi_params);
+        if archived.curr_cols.len() != inner_box.ht_rows.len() {
+            return Err(malformed("unexpected no. of InnerBox rows"));
+        }
+        for (ibr, curr_cols) in izip!(inner_box.ht_rows.iter_mut(), archived.curr_cols.iter()) {
+            if *curr_cols > ibr.max_cols() {
+                return Err(malformed("InnerBox row has too many columns"));
+            }
+            ibr.curr_cols = *curr_cols;
+        }
+
+        let item_data = archived.item_data.to_array()?;
+        let label_data = archived.label_data.to_array()?;
+        let col_span = psi_params.psi_pt.bfv_pt_bytes as usize;
+        let occupied_cols = inner_box
+            .ht_rows
+            .iter()
+            .map(|r| r.curr_cols as usize)
+            .max()
+            .unwrap_or(0);
+        let label_cols = match psi_params.mode {
+            PsiMode::Labeled => item_data.shape()[1],
+            PsiMode::Unlabeled => 0,
+        };
+        if item_data.shape()[0] != inner_box.item_data.shape()[0]
+            || item_data.shape()[1] < occupied_cols * col_span
+            || label_data.shape() != [inner_box.label_data.shape()[0], label_cols]
+        {
+            return Err(malformed("unexpected shape of InnerBox data"));
+        }
+
+        for (row, ibr) in inner_box.ht_rows.iter().enumerate() {
+            let real_row = ibr.map_to_real_row(row);
+            for ri in real_row..real_row + ibr.row_span as usize {
+                let chunks = item_data.row(ri).to_slice().unwrap();
+                for col in 0..ibr.curr_cols as usize {
+                    let real_col = ibr.map_to_real_col(col);
+                    inner_box
+                        .item_data_hash_set
+                        .insert((ri, bytes_to_u16(&chunks[real_col..real_col + col_span])));
+                }
+            }
+        }
+        inner_box.item_data = item_data;
+        inner_box.label_data = label_data;
+        inner_box.initialised = archived.initialised;
+        Ok(inner_box)
     }
 
+    /// Returns column of InnerBoxRow at `row` that stores `item`. Columns are only scanned if every chunk of `item` is
+    /// in `item_data_hash_set` of its real row, thus InnerBoxes that don't store `item` are mostly skipped without
+    /// scanning.
+    fn find_item_col(&self, row: usize, item: &U256) -> Option<usize> {
+        let ibr = &self.ht_rows[row];
+        let real_row = ibr.map_to_real_row(row);
+        let col_span = ibr.col_span as usize;
+        let item_bytes = item.to_le_bytes();
+
+        let chunks_exist = (0..ibr.row_span as usize).all(|i| {
+            self.item_data_hash_set.contains(&(
+                real_row + i,
+                bytes_to_u16(&item_bytes[i * col_span..(i + 1) * col_span]),
+            ))
+        });
+        if !chunks_exist {
+            return None;
+        }
+
+        (0..ibr.curr_cols as usize).find(|col| {
+            let real_col = ibr.map_to_real_col(*col);
+            (0..ibr.row_span as usize).all(|i| {
+                self.item_data.row(real_row + i).as_slice().unwrap()[real_col..real_col + col_span]
+                    == item_bytes[i * col_span..(i + 1) * col_span]
+            })
+        })
+    }
+
+    /// Removes `item` (and its label) from InnerBoxRow at `row`. Last occupied column of the row is moved into freed
+    /// column so that occupied columns stay contiguous. Returns false if `item` does not exist at `row`.
+    fn remove_item(&mut self, row: usize, item: &U256) -> bool {
+        let col = match self.find_item_col(row, item) {
+            Some(col) => col,
+            None => return false,
+        };
+
+        let ibr = &self.ht_rows[row];
+        let real_row = ibr.map_to_real_row(row);
+        let row_span = ibr.row_span as usize;
+        let col_span = ibr.col_span as usize;
+        let real_col = ibr.map_to_real_col(col);
+        let last_real_col = ibr.map_to_real_col(ibr.curr_cols as usize - 1);
+
+        // label data is empty in unlabeled mode
+        let ct_slots = self.psi_params.ct_slots.0 as usize;
+        let label_parts = self.label_data.shape()[0] / ct_slots;
+        for ri in real_row..real_row + row_span {
+            let item_chunk =
+                self.item_data.row(ri).as_slice().unwrap()[real_col..real_col + col_span].to_vec();
+            self.item_data_hash_set
+                .remove(&(ri, bytes_to_u16(&item_chunk)));
+
+            move_col(&mut self.item_data, ri, last_real_col, real_col, col_span);
+            for part in 0..label_parts {
+                move_col(
+                    &mut self.label_data,
+                    part * ct_slots + ri,
+                    last_real_col,
+                    real_col,
+                    col_span,
+                );
+            }
+        }
+
+        self.ht_rows[row].curr_cols -= 1;
+        self.ht_rows[row].dirty = true;
+        true
+    }
+
+    /// Encodes coefficients of all polynomials as plaintexts, unless they are already encoded, so that query evaluation
+    /// only multiplies with plaintexts.
+    fn encode_coefficients(&mut self, evaluator: &Evaluator) {
+        if !self.encoded_coefficients.is_empty() {
+            return;
+        }
+
+        self.encoded_coefficients = self
+            .coefficients()
+            .into_par_iter()
+            .map(|coefficients| {
+                coefficients.encode(
+                    evaluator,
+                    &self.psi_params.ps_params,
+                    self.psi_params.evaluation_level,
+                )
+            })
+            .collect();
+    }
+
+    /// Evaluates polynomials of each label part on `ps_powers`. Response ciphertexts are rerandomized with
+    /// `rerandomizer` if set.
     fn evaluate_ps_on_query_ct(
         &self,
         ps_powers: &HashMap<usize, Ciphertext>,
         evalutor: &Evaluator,
         ek: &EvaluationKey,
+        rerandomizer: Option<&ResponseRerandomizer>,
         level: usize,
-    ) -> Ciphertext {
-        let mut res_ct = ps_evaluate_poly(
-            evalutor,
-            ek,
-            &ps_powers,
-            &self.psi_params.ps_params,
-            &self.coefficients_data,
-            level,
-        );
+    ) -> Vec<Ciphertext> {
+        self.coefficients()
+            .into_iter()
+            .enumerate()
+            .map(|(part, coefficients)| {
+                let mut res_ct = match self.encoded_coefficients.get(part) {
+                    // encoded plaintexts are at evaluation level
+                    Some(encoded_coefficients) if level == self.psi_params.evaluation_level => {
+                        ps_evaluate_encoded_poly(
+                            evalutor,
+                            ek,
+                            &ps_powers,
+                            &self.psi_params.ps_params,
+                            encoded_coefficients,
+                        )
+                    }
+                    _ => coefficients.evaluate(
+                        evalutor,
+                        ek,
+                        &ps_powers,
+                        &self.psi_params.ps_params,
+                        level,
+                    ),
+                };
+
+                if let Some(rerandomizer) = rerandomizer {
+                    rerandomizer.apply(evalutor, &mut res_ct, &mut thread_rng());
+                }
 
-        //TODO: evalutor.mod_down_level(&mut res_ct, 0);
-        // mod down to last level
-        evalutor.mod_down_level(&mut res_ct, self.psi_params.bfv_moduli.len() - 1);
-        res_ct
+                // mod down to last level
+                evalutor.mod_down_level(&mut res_ct, self.psi_params.response_level());
+                res_ct
+            })
+            .collect_vec()
     }
 }
 
@@ -298,7 +897,7 @@ impl InnerBox {
 /// to segment has enough space at row. If yes, then ItemLabel is inserted. Otherwise, a new InnerBox is created and appended to vec and then
 /// the item is inserted.
 #[derive(Serialize, Deserialize)]
-pub struct BigBox {
+pub(crate) struct BigBox {
     /// Although inner_boxes is a 2d array of `InnerBox`, can't store it as such since length of each row is a not equal
     inner_boxes: Vec<Vec<InnerBox>>,
     psi_params: PsiParams,
@@ -309,10 +908,10 @@ pub struct BigBox {
 impl BigBox {
     pub fn new(psi_params: &PsiParams, id: usize) -> BigBox {
         // rows in single inner box
+        let layout = QueryLayout::new(psi_params);
+        let inner_box_rows = layout.rows_per_segment();
 
-        let inner_box_rows = InnerBox::max_rows(&psi_params.psi_pt, &psi_params.ct_slots);
-
-        let segments = (psi_params.ht_size.0 + (inner_box_rows >> 1)) / inner_box_rows;
+        let segments = layout.segments_per_hash_table();
         let mut inner_boxes = vec![];
         // setup inner boxes for stack rows
         (0..segments)
@@ -327,65 +926,153 @@ impl BigBox {
         }
     }
 
+    /// Returns BigBox without coefficients as `BigBoxRecord` to be archived with rest of db
+    pub(crate) fn record(&self) -> BigBoxRecord {
+        BigBoxRecord {
+            id: self.id as u64,
+            inner_box_rows: self.inner_box_rows,
+            inner_boxes: self
+                .inner_boxes
+                .iter()
+                .map(|segment| segment.iter().map(|ib| ib.record()).collect_vec())
+                .collect_vec(),
+        }
+    }
+
+    /// Restores BigBox without coefficients from `archived` record. InnerBoxes of each segment are restored in
+    /// parallel.
+    pub(crate) fn from_record(
+        archived: &ArchivedBigBoxRecord,
+        psi_params: &PsiParams,
+    ) -> Result<BigBox, PsiError> {
+        let mut big_box = BigBox::new(psi_params, archived.id as usize);
+        if archived.inner_box_rows != big_box.inner_box_rows
+            || archived.inner_boxes.len() != big_box.inner_boxes.len()
+            || archived
+                .inner_boxes
+                .iter()
+                .any(|segment| segment.is_empty())
+        {
+            return Err(malformed("unexpected no. of segments"));
+        }
+        big_box.inner_boxes = archived
+            .inner_boxes
+            .as_slice()
+            .par_iter()
+            .map(|segment| {
+                segment
+                    .iter()
+                    .map(|ib| InnerBox::from_record(ib, psi_params))
+                    .collect::<Result<Vec<_>, _>>()
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(big_box)
+    }
+
     /// Returns the segment in which `ht_index` falls
     fn ht_index_to_segment_index(&self, ht_index: usize) -> usize {
         ht_index / self.inner_box_rows as usize
     }
 
     // Maps ht_index to row of InnerBox in a segment
-    fn ht_index_to_inner_box_row(&self, ht_index: usize) -> usize {
+    pub(crate) fn ht_index_to_inner_box_row(&self, ht_index: usize) -> usize {
         ht_index % self.inner_box_rows as usize
     }
 
+    /// Inserts ItemLabels at their respective indices for the BigBox. Returns indices of rejected ItemLabels along with
+    /// the reason.
     pub fn insert_many(
         &mut self,
         item_labels: &[ItemLabel],
         item_labels_table_indices: &[Vec<u32>],
-    ) {
+        progress: &dyn ProgressSink,
+    ) -> Vec<(usize, InsertError)> {
+        let mut rejected = vec![];
         izip!(item_labels.iter(), item_labels_table_indices.iter())
             .enumerate()
             .for_each(|(index, (il, tb_indices))| {
-                // Print at every million^th item
-                if index % 1000000 == 0 {
-                    println!("[BB {}] Inserting Item Index {index}", self.id);
+                if let Err(e) = self.insert(il, tb_indices[self.id] as usize) {
+                    rejected.push((index, e));
+                }
+                if (index + 1) % INSERT_PROGRESS_CHUNK == 0 {
+                    progress.advance(SetupStage::Insert, INSERT_PROGRESS_CHUNK as u64);
                 }
-                self.insert(il, tb_indices[self.id] as usize);
             });
+        progress.advance(
+            SetupStage::Insert,
+            (item_labels.len() % INSERT_PROGRESS_CHUNK) as u64,
+        );
+        rejected
     }
 
-    pub fn insert(&mut self, item_label: &ItemLabel, ht_index: usize) {
+    /// Returns index of InnerBox in segment that has free space for `item_label` at `inner_box_row`. Returns `None` if
+    /// a new InnerBox must be created.
+    fn find_inner_box(
+        &self,
+        item_label: &ItemLabel,
+        segment_index: usize,
+        inner_box_row: usize,
+    ) -> Option<usize> {
+        (0..self.inner_boxes[segment_index].len())
+            .find(|i| self.inner_boxes[segment_index][*i].can_insert(item_label, inner_box_row))
+    }
+
+    /// Returns error if segment already has `max_inner_boxes_per_segment` InnerBoxes and cannot have a new one
+    fn check_segment_can_grow(&self, segment_index: usize) -> Result<(), InsertError> {
+        match self.psi_params.max_inner_boxes_per_segment {
+            Some(max_inner_boxes)
+                if self.inner_boxes[segment_index].len() >= max_inner_boxes as usize =>
+            {
+                Err(InsertError::SegmentFull {
+                    big_box: self.id,
+                    segment: segment_index,
+                    max_inner_boxes,
+                })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks whether `item_label` can be inserted at `ht_index` without violating `max_inner_boxes_per_segment`
+    pub fn check_capacity(
+        &self,
+        item_label: &ItemLabel,
+        ht_index: usize,
+    ) -> Result<(), InsertError> {
         let segment_index = self.ht_index_to_segment_index(ht_index);
         let inner_box_row = self.ht_index_to_inner_box_row(ht_index);
 
-        // println!(
-        //     "[BB {}] Inserting item: {} at ht_index: {}; segment_index: {}, ib_row: {}",
-        //     self.id,
-        //     item_label.label(),
-        //     ht_index,
-        //     segment_index,
-        //     inner_box_row
-        // );
+        match self.find_inner_box(item_label, segment_index, inner_box_row) {
+            Some(_) => Ok(()),
+            None => self.check_segment_can_grow(segment_index),
+        }
+    }
+
+    pub fn insert(&mut self, item_label: &ItemLabel, ht_index: usize) -> Result<(), InsertError> {
+        self.insert_at_inner_box(item_label, ht_index).map(|_| ())
+    }
+
+    /// Inserts ItemLabel and returns (segment index, InnerBox index) of InnerBox it was inserted in
+    pub(crate) fn insert_at_inner_box(
+        &mut self,
+        item_label: &ItemLabel,
+        ht_index: usize,
+    ) -> Result<(usize, usize), InsertError> {
+        check_label_size(item_label, &self.psi_params)?;
+
+        let segment_index = self.ht_index_to_segment_index(ht_index);
+        let inner_box_row = self.ht_index_to_inner_box_row(ht_index);
 
         // Find the first InnerBox in segment that has free space at row
-        let mut inner_box_index = None;
-        for i in 0..self.inner_boxes[segment_index].len() {
-            if self.inner_boxes[segment_index][i].can_insert(item_label, inner_box_row) {
-                inner_box_index = Some(i);
-                break;
+        let inner_box_index = match self.find_inner_box(item_label, segment_index, inner_box_row) {
+            Some(index) => index,
+            None => {
+                // None of the inner boxes in segment have space available at row. Create a new one if segment isn't at capacity.
+                self.check_segment_can_grow(segment_index)?;
+                self.inner_boxes[segment_index].push(InnerBox::new(&self.psi_params));
+                self.inner_boxes[segment_index].len() - 1
             }
-        }
-        if inner_box_index.is_none() {
-            // println!(
-            //     "[BB {}] All InnerBoxes at segment {segment_index} at row {inner_box_row} are full. Creating new IB"
-            //     ,
-            //     self.id
-            // );
-            // None of the inner boxes in segment have space available at row. Create a new one.
-            self.inner_boxes[segment_index].push(InnerBox::new(&self.psi_params));
-            // set the index to newly inserted InnerBox
-            inner_box_index = Some(self.inner_boxes[segment_index].len() - 1);
-        }
-        let inner_box_index = inner_box_index.unwrap();
+        };
 
         // insert item label
         self.inner_boxes[segment_index][inner_box_index].insert_item_label(
@@ -394,130 +1081,359 @@ impl BigBox {
             &self.psi_params.psi_pt,
         );
 
-        // println!(
-        //     "[BB {}] Item {} for ht_index:{ht_index} inserted; segment {segment_index}, inner_box_index {inner_box_index}, ib_row: {inner_box_row}",
-        //     self.id,
-        //     item_label.item()
-        // );
+        Ok((segment_index, inner_box_index))
+    }
+
+    /// Inserts ItemLabel into preprocessed BigBox and re-interpolates only dirty rows of the affected InnerBox
+    pub fn insert_and_update(
+        &mut self,
+        item_label: &ItemLabel,
+        ht_index: usize,
+    ) -> Result<(), PsiError> {
+        let (segment_index, inner_box_index) = self.insert_at_inner_box(item_label, ht_index)?;
+        self.inner_boxes[segment_index][inner_box_index].regenerate_dirty()?;
+        Ok(())
+    }
+
+    /// Returns true if `item` is stored at `ht_index`
+    pub fn contains(&self, item: &U256, ht_index: usize) -> bool {
+        let segment_index = self.ht_index_to_segment_index(ht_index);
+        let inner_box_row = self.ht_index_to_inner_box_row(ht_index);
+        self.inner_boxes[segment_index]
+            .iter()
+            .any(|ib| ib.find_item_col(inner_box_row, item).is_some())
+    }
+
+    /// Replaces label of item of `item_label` at `ht_index`, or inserts `item_label` if item does not exist, and
+    /// re-interpolates only dirty rows of the affected InnerBox. Returns true if label was replaced.
+    pub fn upsert(&mut self, item_label: &ItemLabel, ht_index: usize) -> Result<bool, PsiError> {
+        check_label_size(item_label, &self.psi_params)?;
+
+        let segment_index = self.ht_index_to_segment_index(ht_index);
+        let inner_box_row = self.ht_index_to_inner_box_row(ht_index);
+        let replaced_at = self.inner_boxes[segment_index]
+            .iter_mut()
+            .position(|ib| ib.replace_label(inner_box_row, item_label));
+        let (inner_box_index, replaced) = match replaced_at {
+            Some(index) => (index, true),
+            None => (self.insert_at_inner_box(item_label, ht_index)?.1, false),
+        };
+        self.inner_boxes[segment_index][inner_box_index].regenerate_dirty()?;
+        Ok(replaced)
+    }
+
+    /// Removes `item` at `ht_index` and re-interpolates only dirty rows of the affected InnerBox. Returns false if
+    /// `item` does not exist at `ht_index`.
+    ///
+    /// InnerBoxes are not removed from segment even if they become empty, since no. of InnerBoxes per segment only
+    /// affects server's runtime and response size.
+    pub fn remove(&mut self, item: &U256, ht_index: usize) -> Result<bool, PsiError> {
+        let (segment_index, inner_box_index) = match self.remove_at_inner_box(item, ht_index) {
+            Some(removed) => removed,
+            None => return Ok(false),
+        };
+        let ib = &mut self.inner_boxes[segment_index][inner_box_index];
+        if ib.has_coefficients() {
+            ib.regenerate_dirty()?;
+        }
+        Ok(true)
+    }
+
+    /// Removes `item` at `ht_index` without re-interpolating, leaving its row dirty, and returns (segment index,
+    /// InnerBox index) of InnerBox it was removed from, or None if `item` does not exist at `ht_index`
+    pub(crate) fn remove_at_inner_box(
+        &mut self,
+        item: &U256,
+        ht_index: usize,
+    ) -> Option<(usize, usize)> {
+        let segment_index = self.ht_index_to_segment_index(ht_index);
+        let inner_box_row = self.ht_index_to_inner_box_row(ht_index);
+        let inner_box_index = self.inner_boxes[segment_index]
+            .iter_mut()
+            .position(|ib| ib.remove_item(inner_box_row, item))?;
+        Some((segment_index, inner_box_index))
+    }
+
+    /// Re-interpolates dirty rows of all InnerBoxes in parallel (see `InnerBox::regenerate_dirty`). Returns no. of
+    /// re-interpolated InnerBox rows.
+    pub(crate) fn regenerate_dirty(&mut self) -> Result<usize, PsiError> {
+        self.inner_boxes
+            .par_iter_mut()
+            .flat_map(|segment| segment.par_iter_mut())
+            .map(|ib| ib.regenerate_dirty())
+            .try_reduce(|| 0, |a, b| Ok(a + b))
     }
 
-    /// Preprocesses each InnerBox
-    pub fn preprocess(&mut self) {
+    /// Preprocesses each InnerBox and reports each preprocessed InnerBox to `progress`. Each segment is preprocessed
+    /// within `preprocess_segment` span.
+    pub fn preprocess_with_progress(
+        &mut self,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        let id = self.id;
         self.inner_boxes
             .par_iter_mut()
             .enumerate()
-            .for_each(|(s_i, segment)| {
+            .try_for_each(|(s_i, segment)| {
+                let span = info_span!("preprocess_segment", big_box = id, segment = s_i);
                 segment
                     .par_iter_mut()
                     .enumerate()
-                    .for_each(|(ib_index, ib)| {
-                        println!(
-                            "[BB {}] Preprocessing IB from segment {s_i} at index {ib_index}",
-                            self.id,
-                        );
-                        ib.generate_coefficients();
-                    });
-            });
+                    .try_for_each(|(ib_index, ib)| {
+                        // InnerBoxes are preprocessed on rayon's threads, thus segment's span is entered by each
+                        let _entered = span.enter();
+                        debug!(inner_box = ib_index, "Preprocessing InnerBox");
+                        ib.generate_coefficients()?;
+                        progress.advance(SetupStage::Preprocess, 1);
+                        Ok(())
+                    })
+            })
+    }
+
+    /// Preprocesses InnerBoxes one at a time, in order, and calls `on_preprocessed` with each InnerBox as soon as its
+    /// coefficients are generated. Unlike `preprocess_with_progress`, at most one InnerBox is preprocessed at once
+    /// (with all threads) so that `on_preprocessed` can write coefficients out and drop them.
+    pub(crate) fn preprocess_each<F>(&mut self, mut on_preprocessed: F) -> Result<(), PsiError>
+    where
+        F: FnMut(&mut InnerBox) -> Result<(), PsiError>,
+    {
+        for (s_i, segment) in self.inner_boxes.iter_mut().enumerate() {
+            let _entered =
+                info_span!("preprocess_segment", big_box = self.id, segment = s_i).entered();
+            for (ib_index, ib) in segment.iter_mut().enumerate() {
+                debug!(inner_box = ib_index, "Preprocessing InnerBox");
+                ib.generate_coefficients()?;
+                on_preprocessed(ib)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes coefficients of InnerBoxes that haven't been encoded yet. See `InnerBox::encode_coefficients`.
+    pub fn encode_coefficients(&mut self, evaluator: &Evaluator) {
+        self.inner_boxes.par_iter_mut().for_each(|segment| {
+            segment
+                .par_iter_mut()
+                .for_each(|ib| ib.encode_coefficients(evaluator))
+        });
     }
 
-    /// Process hash table query cts
-    pub fn process_query(
+    /// Calculates PS powers of segment from query ciphertext powers and levels them down to
+    /// `PsiParams::evaluation_level`. Powers are calculated in parallel (see `calculate_ps_powers_with_dag`) and
+    /// returned behind `Arc` to be shared by all InnerBoxes of the segment.
+    pub fn calculate_segment_powers(
         &self,
-        ht_query_cts: &HashTableQueryCts,
+        query_ct_powers: &[Ciphertext],
         evaluator: &Evaluator,
         ek: &EvaluationKey,
         powers_dag: &HashMap<usize, Node>,
-    ) -> HashTableQueryResponse {
-        // there must be one query ciphertext (raised to different source powers) for each segment
-        assert!(
-            ht_query_cts.0.len() == self.inner_boxes.len() * self.psi_params.source_powers.len()
+    ) -> Arc<HashMap<usize, Ciphertext>> {
+        let mut ps_target_powers = calculate_ps_powers_with_dag(
+            evaluator,
+            ek,
+            query_ct_powers,
+            &self.psi_params.source_powers,
+            self.psi_params.ps_params.powers(),
+            powers_dag,
+            &self.psi_params.ps_params,
         );
 
-        let ht_query_cts_chunked_as_source_powers = ht_query_cts
-            .0
-            .par_chunks_exact(self.psi_params.source_powers.len());
+        // Levelling down speeds up polynomial evaluation without loss of correctness, but requires relinearization key
+        // at evaluation level
+        let level = self.psi_params.evaluation_level;
+        if level > 0 {
+            ps_target_powers
+                .par_iter_mut()
+                .for_each(|(_, ct)| evaluator.mod_down_level(ct, level));
+        }
+        Arc::new(ps_target_powers)
+    }
 
-        let mut ht_response = Vec::new();
-        ht_query_cts_chunked_as_source_powers
-            .into_par_iter()
-            .zip(self.inner_boxes.par_iter())
-            .map(|(query_ct_powers, segment)| {
-                // calculate PS powers from source powers
-                // TODO: parallelizing `calculate_ps_powers_with_dag` can give speed up since it bottlenecks further multithreading. Usually there will be far less segments to process in parallel than available threads (with default parameters segments = 8).
-                let ps_target_powers = calculate_ps_powers_with_dag(
-                    evaluator,
-                    ek,
-                    &query_ct_powers,
-                    &self.psi_params.source_powers,
-                    self.psi_params.ps_params.powers(),
-                    powers_dag,
-                    &self.psi_params.ps_params,
-                );
+    /// Evaluates query ciphertext powers of segment at `segment_index` on all InnerBoxes of the segment.
+    /// Returns `PsiParams::label_parts` response ciphertexts per InnerBox, rerandomized with `rerandomizer` if set,
+    /// along with time spent in each stage. If `powers_cache` is set, powers are looked up in the cache under the key
+    /// before they are calculated.
+    #[allow(clippy::too_many_arguments)]
+    pub fn process_segment_query(
+        &self,
+        segment_index: usize,
+        query_ct_powers: &[Ciphertext],
+        evaluator: &Evaluator,
+        ek: &EvaluationKey,
+        powers_dag: &HashMap<usize, Node>,
+        rerandomizer: Option<&ResponseRerandomizer>,
+        powers_cache: Option<(&PowersCache, PowersKey)>,
+    ) -> (Vec<Ciphertext>, SegmentStageTimes) {
+        let now = Instant::now();
+        let calculate =
+            || self.calculate_segment_powers(query_ct_powers, evaluator, ek, powers_dag);
+        let ps_target_powers = match powers_cache {
+            Some((cache, key)) => cache.get_or_insert_with(key, calculate),
+            None => calculate(),
+        };
+        let powers = now.elapsed();
+
+        // Each InnerBox responds with one ciphertext per label part, stored one after another
+        let now = Instant::now();
+        let level = self.psi_params.evaluation_level;
+        let cts = self.inner_boxes[segment_index]
+            .par_iter()
+            .flat_map(|ib| {
+                ib.evaluate_ps_on_query_ct(&ps_target_powers, evaluator, ek, rerandomizer, level)
+            })
+            .collect();
 
-                // NOTE: We can level down here to improve the runtime for polynomial evaluation without any loss of correctness. But there exists a trade-off since levelling down will require
-                // relinerization key for level 1. So level down only when run time of polynomia l evaluation is the bottleneck.
-                let mut ib_responses = Vec::new();
-                segment
-                    .par_iter()
-                    .map(|ib| ib.evaluate_ps_on_query_ct(&ps_target_powers, evaluator, ek, 0))
-                    .collect_into_vec(&mut ib_responses);
+        (
+            cts,
+            SegmentStageTimes {
+                powers,
+                evaluation: now.elapsed(),
+            },
+        )
+    }
 
-                ib_responses
+    /// Returns no. of occupied columns and total no. of columns across all InnerBoxes, ie no. of items stored and
+    /// max. no. of items that can be stored without adding InnerBoxes.
+    pub fn occupancy(&self) -> (u64, u64) {
+        self.inner_boxes
+            .iter()
+            .flatten()
+            .flat_map(|ib| ib.ht_rows.iter())
+            .fold((0, 0), |(occupied, capacity), row| {
+                (
+                    occupied + row.curr_cols as u64,
+                    capacity + row.max_cols() as u64,
+                )
             })
-            .collect_into_vec(&mut ht_response);
+    }
 
-        HashTableQueryResponse(ht_response)
+    /// Returns no. of InnerBoxes in each segment
+    pub fn inner_boxes_per_segment(&self) -> Vec<usize> {
+        self.inner_boxes.iter().map(|s| s.len()).collect()
     }
 
+    /// Logs layout of BigBox at info level
     pub fn print_diagnosis(&self) {
         let single_ib = &self.inner_boxes[0][0];
 
-        println!(
-            "
-            ------------------------------------------
-            BigBox Id : {}
-            ",
-            self.id
-        );
-        println!(
-            "
-            No. of Segments: {}
-            ",
-            self.inner_boxes.len()
-        );
-        println!(
-            "
-                No. of HashTable rows per Segment (ie InnerBox): {}
-                
-            ",
-            single_ib.ht_rows.len(),
-        );
-        println!(
-            "
-                InnerBox
-                    Max no. of columns (ie data points) per InnerBox: {},
-                    No. of real rows per InnerBox: {}
-
-            ",
-            single_ib.item_data.shape()[1],
-            single_ib.item_data.shape()[0],
+        info!(
+            big_box = self.id,
+            segments = self.inner_boxes.len(),
+            ht_rows_per_segment = single_ib.ht_rows.len(),
+            inner_box_columns = self.psi_params.inner_box_columns(),
+            inner_box_rows = single_ib.item_data.shape()[0],
+            "BigBox layout"
         );
         self.inner_boxes
             .iter()
             .enumerate()
             .for_each(|(segment_index, inner_boxes)| {
-                println!(
-                    "
-                        Segment Index {segment_index} InnerBoxes count: {}
-                    ",
-                    inner_boxes.len()
+                info!(
+                    big_box = self.id,
+                    segment = segment_index,
+                    inner_boxes = inner_boxes.len(),
+                    "Segment layout"
                 );
             });
-        println!(
-            "
-            ------------------------------------------
-            "
+    }
+}
+
+/// Processing time of each segment of each BigBox measured in previous queries. Used to schedule segments longest-first.
+///
+/// Measured times are smoothed with exponential moving average. Time is stored per InnerBox so that the estimate stays
+/// meaningful when InnerBoxes are added to segment.
+pub struct SegmentTimings {
+    /// micro seconds per InnerBox for each (BigBox, segment). 0 indicates no measurement yet.
+    per_inner_box_us: Vec<Vec<AtomicU64>>,
+}
+
+impl SegmentTimings {
+    pub fn new(db: &Db) -> SegmentTimings {
+        let per_inner_box_us = db
+            .big_boxes
+            .iter()
+            .map(|bb| {
+                (0..bb.inner_boxes.len())
+                    .map(|_| AtomicU64::new(0))
+                    .collect_vec()
+            })
+            .collect_vec();
+        SegmentTimings { per_inner_box_us }
+    }
+
+    /// Returns estimated processing time in micro seconds for segment with `ib_count` InnerBoxes. Segments without
+    /// a measurement are assumed to cost as much per InnerBox as the average of measured segments.
+    pub fn estimate(&self, bb_index: usize, segment_index: usize, ib_count: usize) -> u64 {
+        let measured = self.per_inner_box_us[bb_index][segment_index].load(Ordering::Relaxed);
+        let per_inner_box = if measured != 0 {
+            measured
+        } else {
+            let (sum, count) = self
+                .per_inner_box_us
+                .iter()
+                .flatten()
+                .map(|t| t.load(Ordering::Relaxed))
+                .filter(|t| *t != 0)
+                .fold((0u64, 0u64), |(sum, count), t| (sum + t, count + 1));
+            if count == 0 {
+                1
+            } else {
+                sum / count
+            }
+        };
+        per_inner_box * ib_count as u64
+    }
+
+    pub fn record(
+        &self,
+        bb_index: usize,
+        segment_index: usize,
+        ib_count: usize,
+        elapsed: std::time::Duration,
+    ) {
+        let sample = std::cmp::max(
+            elapsed.as_micros() as u64 / std::cmp::max(ib_count, 1) as u64,
+            1,
         );
+        let timing = &self.per_inner_box_us[bb_index][segment_index];
+        let previous = timing.load(Ordering::Relaxed);
+        let updated = if previous == 0 {
+            sample
+        } else {
+            (previous * 3 + sample) / 4
+        };
+        timing.store(updated, Ordering::Relaxed);
+    }
+}
+
+/// Layout and occupancy of db, for monitoring and capacity planning. Layout determines size of response to a query,
+/// since each InnerBox responds with `PsiParams::label_parts` ciphertexts. Sent to clients that ask for
+/// `CAPABILITY_DB_STATS`. See `expected_response_bytes`.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DbStats {
+    /// No. of InnerBoxes in each segment of each BigBox
+    pub inner_boxes_per_segment: Vec<Vec<usize>>,
+    /// Histogram of InnerBox rows across all InnerBoxes, indexed by no. of occupied columns of the row
+    pub row_occupancy: Vec<u64>,
+    /// No. of occupied columns across all InnerBoxes. See `Db::occupancy`.
+    pub occupied: u64,
+    /// No. of columns across all InnerBoxes. See `Db::occupancy`.
+    pub capacity: u64,
+    /// No. of interpolated coefficients across all InnerBoxes. 0 until db is preprocessed.
+    pub coefficients: u64,
+    /// Size of coefficients, item data and label data across all InnerBoxes, whether in memory or memory mapped
+    pub bytes: u64,
+}
+
+impl DbStats {
+    /// Total no. of segments across all BigBoxes
+    pub fn segments(&self) -> usize {
+        self.inner_boxes_per_segment.iter().map(|bb| bb.len()).sum()
+    }
+
+    /// Total no. of InnerBoxes across all BigBoxes
+    pub fn inner_boxes(&self) -> usize {
+        self.inner_boxes_per_segment.iter().flatten().sum()
     }
 }
 
@@ -526,84 +1442,483 @@ pub struct Db {
     pub(crate) cuckoo: Cuckoo,
     pub(crate) big_boxes: Vec<BigBox>,
     pub(crate) psi_params: PsiParams,
+    /// Set if `PsiParams::oprf` is enabled. Items are replaced with their OPRF outputs before insertion.
+    pub(crate) oprf_key: Option<OprfKey>,
+    /// Expiry of inserted items that have one, keyed by little endian bytes of item (OPRF output if OPRF is enabled).
+    /// See `Db::purge_expired`.
+    #[serde(skip)]
+    pub(crate) expiries: BTreeMap<[u8; 32], u64>,
 }
 
 impl Db {
     pub fn new(psi_params: &PsiParams) -> Db {
-        let cuckoo = Cuckoo::new(psi_params.no_of_hash_tables, *psi_params.ht_size);
+        let cuckoo = Cuckoo::for_params(psi_params);
         let big_boxes = (0..psi_params.no_of_hash_tables)
             .into_iter()
             .map(|i| BigBox::new(&psi_params, i as usize))
             .collect_vec();
 
+        let oprf_key = if psi_params.oprf {
+            Some(OprfKey::random(&mut thread_rng()))
+        } else {
+            None
+        };
+
         Db {
             cuckoo,
             big_boxes,
             psi_params: psi_params.clone(),
+            oprf_key,
+            expiries: BTreeMap::new(),
+        }
+    }
+
+    pub fn oprf_key(&self) -> Option<&OprfKey> {
+        self.oprf_key.as_ref()
+    }
+
+    /// Replaces item with its OPRF output if OPRF is enabled
+    pub(crate) fn oprf_item_label(&self, item_label: &ItemLabel) -> ItemLabel {
+        match &self.oprf_key {
+            Some(key) => ItemLabel {
+                item: key.evaluate_item(item_label.item()),
+                ..item_label.clone()
+            },
+            None => item_label.clone(),
         }
     }
 
-    /// Inserts many ItemLabels. Uses all the cores to reduce insert time
-    pub fn insert_many(&mut self, item_labels: &[ItemLabel]) {
+    /// Inserts many ItemLabels. Uses all the cores to reduce insert time.
+    ///
+    /// Returns indices of ItemLabels rejected by at least one BigBox due to `max_inner_boxes_per_segment`. Since BigBoxes
+    /// are filled in parallel, a rejected ItemLabel may still exist in other BigBoxes and must be considered as not served.
+    pub fn insert_many(&mut self, item_labels: &[ItemLabel]) -> Vec<(usize, InsertError)> {
+        self.insert_many_with_progress(item_labels, &NoProgress)
+    }
+
+    /// Same as `insert_many` but reports progress of `SetupStage::Insert` to `progress`. Each ItemLabel is inserted
+    /// into every BigBox, thus there are `item_labels.len()` times no. of BigBoxes units of work.
+    pub fn insert_many_with_progress(
+        &mut self,
+        item_labels: &[ItemLabel],
+        progress: &dyn ProgressSink,
+    ) -> Vec<(usize, InsertError)> {
+        progress.start(
+            SetupStage::Insert,
+            (item_labels.len() * self.big_boxes.len()) as u64,
+        );
+        let rejected = self.insert_batch(item_labels, progress);
+        progress.finish(SetupStage::Insert);
+        rejected
+    }
+
+    /// Inserts `item_labels` and reports progress of `SetupStage::Insert` to `progress` without starting or finishing
+    /// the stage, so that ItemLabels can be inserted in multiple batches
+    pub(crate) fn insert_batch(
+        &mut self,
+        item_labels: &[ItemLabel],
+        progress: &dyn ProgressSink,
+    ) -> Vec<(usize, InsertError)> {
         // TODO: check that there are no repeated items
-        println!("Inserting {} ItemLabels", item_labels.len());
+        info!(count = item_labels.len(), "Inserting ItemLabels");
+
+        let oprf_item_labels: Vec<ItemLabel>;
+        let item_labels = if self.oprf_key.is_some() {
+            oprf_item_labels = item_labels
+                .par_iter()
+                .map(|il| self.oprf_item_label(il))
+                .collect();
+            &oprf_item_labels
+        } else {
+            item_labels
+        };
 
         // hash using all cores
-        let cores = rayon::current_num_threads();
-        let chunk_size = item_labels.len() / cores;
-        let item_labels_table_indices: Vec<Vec<u32>> = item_labels
-            .par_chunks(chunk_size)
-            .flat_map(|chunk_item_labels| {
-                chunk_item_labels
-                    .iter()
-                    .map(|il| self.cuckoo.table_indices(il.item()))
-                    .collect_vec()
-            })
-            .collect();
+        let item_labels_table_indices = self
+            .cuckoo
+            .table_indices_many(item_labels.par_iter().map(ItemLabel::item));
 
         // insert ItemLabels in BigBox in parallel
-        self.big_boxes.par_iter_mut().for_each(|(bb)| {
-            bb.insert_many(item_labels, &item_labels_table_indices);
-        });
+        let mut rejected: Vec<(usize, InsertError)> = self
+            .big_boxes
+            .par_iter_mut()
+            .flat_map(|bb| bb.insert_many(item_labels, &item_labels_table_indices, progress))
+            .collect();
+
+        // report each rejected ItemLabel once
+        rejected.sort_by_key(|(index, _)| *index);
+        rejected.dedup_by_key(|(index, _)| *index);
+
+        let mut rejected_indices = rejected.iter().map(|(index, _)| *index).peekable();
+        for (index, item_label) in item_labels.iter().enumerate() {
+            if rejected_indices.next_if_eq(&index).is_none() {
+                self.record_expiry(item_label);
+            }
+        }
+        rejected
+    }
+
+    /// Records expiry of inserted `item_label`, whose item is already replaced with its OPRF output, if it has one
+    pub(crate) fn record_expiry(&mut self, item_label: &ItemLabel) {
+        if let Some(expires_at) = item_label.expires_at() {
+            self.expiries
+                .insert(item_label.item().to_le_bytes(), expires_at);
+        }
     }
 
-    pub fn insert(&mut self, item_label: &ItemLabel) -> bool {
+    /// Inserts ItemLabel in all BigBoxes. ItemLabel is inserted only if none of the BigBoxes reject it.
+    pub fn insert(&mut self, item_label: &ItemLabel) -> Result<(), PsiError> {
+        let item_label = &self.oprf_item_label(item_label);
+
         // get index for item for all hash tables
         let indices = self.cuckoo.table_indices(item_label.item());
 
+        // check that all BigBoxes have capacity before modifying any
+        for (big_box, ht_index) in izip!(self.big_boxes.iter(), indices.iter()) {
+            big_box.check_capacity(item_label, *ht_index as usize)?;
+        }
+
         // insert item at index corresponding to hash table
-        izip!(self.big_boxes.iter_mut(), indices.iter()).for_each(|(big_box, ht_index)| {
-            big_box.insert(&item_label, *ht_index as usize);
-        });
+        for (big_box, ht_index) in izip!(self.big_boxes.iter_mut(), indices.iter()) {
+            big_box.insert(item_label, *ht_index as usize)?;
+        }
+        self.record_expiry(item_label);
 
-        true
+        Ok(())
+    }
+
+    /// Inserts ItemLabel in already preprocessed Db. Unlike `insert` followed by `preprocess`, only polynomials of
+    /// rows the ItemLabel is inserted in are re-interpolated.
+    pub fn insert_and_update(&mut self, item_label: &ItemLabel) -> Result<(), PsiError> {
+        let item_label = &self.oprf_item_label(item_label);
+
+        let indices = self.cuckoo.table_indices(item_label.item());
+
+        // check that all BigBoxes have capacity before modifying any
+        for (big_box, ht_index) in izip!(self.big_boxes.iter(), indices.iter()) {
+            big_box.check_capacity(item_label, *ht_index as usize)?;
+        }
+
+        for (big_box, ht_index) in izip!(self.big_boxes.iter_mut(), indices.iter()) {
+            big_box.insert_and_update(item_label, *ht_index as usize)?;
+        }
+        self.record_expiry(item_label);
+
+        Ok(())
+    }
+
+    /// Removes `item` and its label from all BigBoxes and re-interpolates only the affected rows. Returns false if
+    /// `item` does not exist in Db.
+    pub fn remove(&mut self, item: &U256) -> Result<bool, PsiError> {
+        let item = match &self.oprf_key {
+            Some(key) => key.evaluate_item(item),
+            None => *item,
+        };
+
+        let indices = self.cuckoo.table_indices(&item);
+        let mut removed = false;
+        for (big_box, ht_index) in izip!(self.big_boxes.iter_mut(), indices.iter()) {
+            removed |= big_box.remove(&item, *ht_index as usize)?;
+        }
+        self.expiries.remove(&item.to_le_bytes());
+        Ok(removed)
+    }
+
+    /// Inserts ItemLabel in already preprocessed Db or, if its item already exists, replaces item's label (and expiry)
+    /// in place. Only polynomials of rows that changed are re-interpolated. Returns true if label was replaced.
+    ///
+    /// Unlike `insert`, an item that already exists isn't stored again, thus an item can be upserted any no. of times.
+    pub fn upsert(&mut self, item_label: &ItemLabel) -> Result<bool, PsiError> {
+        let item_label = &self.oprf_item_label(item_label);
+
+        let indices = self.cuckoo.table_indices(item_label.item());
+
+        // check that BigBoxes that don't store item yet have capacity before modifying any
+        for (big_box, ht_index) in izip!(self.big_boxes.iter(), indices.iter()) {
+            let ht_index = *ht_index as usize;
+            if !big_box.contains(item_label.item(), ht_index) {
+                big_box.check_capacity(item_label, ht_index)?;
+            }
+        }
+
+        let mut replaced = false;
+        for (big_box, ht_index) in izip!(self.big_boxes.iter_mut(), indices.iter()) {
+            replaced |= big_box.upsert(item_label, *ht_index as usize)?;
+        }
+        self.expiries.remove(&item_label.item().to_le_bytes());
+        self.record_expiry(item_label);
+
+        Ok(replaced)
+    }
+
+    /// Re-interpolates only rows of InnerBoxes whose columns changed since they were last interpolated, with BigBoxes
+    /// updated in parallel. Returns no. of re-interpolated InnerBox rows across all BigBoxes.
+    pub(crate) fn regenerate_dirty(&mut self) -> Result<usize, PsiError> {
+        self.big_boxes
+            .par_iter_mut()
+            .map(|bb| bb.regenerate_dirty())
+            .try_reduce(|| 0, |a, b| Ok(a + b))
+    }
+
+    pub fn preprocess(&mut self) -> Result<(), PsiError> {
+        self.preprocess_with_progress(&NoProgress)
+    }
+
+    /// Same as `preprocess` but reports progress of `SetupStage::Preprocess`, one unit per InnerBox, to `progress`
+    pub fn preprocess_with_progress(
+        &mut self,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        progress.start(SetupStage::Preprocess, self.inner_boxes_count() as u64);
+        self.big_boxes
+            .par_iter_mut()
+            .try_for_each(|bb| bb.preprocess_with_progress(progress))?;
+        progress.finish(SetupStage::Preprocess);
+        Ok(())
     }
 
-    pub fn preprocess(&mut self) {
-        self.big_boxes.par_iter_mut().for_each(|bb| bb.preprocess());
+    /// Encodes polynomial coefficients of all InnerBoxes as plaintexts. Only InnerBoxes updated since last call are
+    /// encoded again.
+    pub fn encode_coefficients(&mut self, evaluator: &Evaluator) {
+        self.big_boxes
+            .par_iter_mut()
+            .for_each(|bb| bb.encode_coefficients(evaluator));
     }
 
+    /// Processes query across all segments of all BigBoxes. Segments are dispatched to threads longest-first using
+    /// processing time estimates in `timings`, which are updated with the times measured for this query.
     pub fn handle_query(
         &self,
         query: &Query,
         evaluator: &Evaluator,
         ek: &EvaluationKey,
         powers_dag: &HashMap<usize, Node>,
-    ) -> QueryResponse {
-        assert!(query.0.len() == self.psi_params.no_of_hash_tables as usize);
+        timings: &SegmentTimings,
+    ) -> Result<QueryResponse, PsiError> {
+        let segment_responses = Mutex::new(vec![]);
+        self.handle_query_streamed(
+            query,
+            evaluator,
+            ek,
+            powers_dag,
+            timings,
+            None,
+            &CancellationToken::new(),
+            |response| segment_responses.lock().unwrap().push(response),
+        )?;
+        Ok(self.assemble_response(segment_responses.into_inner().unwrap()))
+    }
 
-        let mut ht_responses = Vec::new();
-        query
-            .0
-            .par_iter()
-            .zip(self.big_boxes.par_iter())
-            .map(|(ht_query_cts, bb)| {
-                let ht_response = bb.process_query(ht_query_cts, evaluator, ek, powers_dag);
-                ht_response
+    /// Assembles responses of all segments, in any order, into `QueryResponse`
+    pub fn assemble_response(&self, segment_responses: Vec<SegmentResponse>) -> QueryResponse {
+        // restore BigBox and segment order
+        let mut ht_responses = self
+            .big_boxes
+            .iter()
+            .map(|bb| vec![vec![]; bb.inner_boxes.len()])
+            .collect_vec();
+        segment_responses.into_iter().for_each(|response| {
+            ht_responses[response.big_box][response.segment] = response.cts;
+        });
+
+        QueryResponse(
+            ht_responses
+                .into_iter()
+                .map(HashTableQueryResponse)
+                .collect(),
+        )
+    }
+
+    /// Same as `handle_query` but calls `on_segment` with response of each segment as soon as the segment is
+    /// processed, instead of waiting for all segments. Segments are passed in order of completion.
+    ///
+    /// `cancellation` is checked before each segment starts. Once it is cancelled, remaining segments are skipped and
+    /// `PsiError::Cancelled` is returned after segments in progress finish. Powers of segments are cached in
+    /// `powers_cache` if set and `query` was bound to its client with `Query::with_client_id`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn handle_query_streamed<F: Fn(SegmentResponse) + Sync + Send>(
+        &self,
+        query: &Query,
+        evaluator: &Evaluator,
+        ek: &EvaluationKey,
+        powers_dag: &HashMap<usize, Node>,
+        timings: &SegmentTimings,
+        powers_cache: Option<&PowersCache>,
+        cancellation: &CancellationToken,
+        on_segment: F,
+    ) -> Result<(), PsiError> {
+        if query.0.len() != self.big_boxes.len() {
+            return Err(PsiError::ParamsMismatch(format!(
+                "Expected query for {} hash tables, found {}",
+                self.big_boxes.len(),
+                query.0.len()
+            )));
+        }
+
+        let source_powers_count = self.psi_params.source_powers.len();
+        for (bb_index, (bb, ht_query_cts)) in
+            izip!(self.big_boxes.iter(), query.0.iter()).enumerate()
+        {
+            let expected_cts = bb.inner_boxes.len() * source_powers_count;
+            if ht_query_cts.0.len() != expected_cts {
+                return Err(PsiError::ParamsMismatch(format!(
+                    "Expected {expected_cts} query ciphertexts for hash table {bb_index}, found {}",
+                    ht_query_cts.0.len()
+                )));
+            }
+        }
+
+        if query.1.len() != self.psi_params.zero_cts_count() {
+            return Err(PsiError::ParamsMismatch(format!(
+                "Expected {} encryptions of zero, found {}",
+                self.psi_params.zero_cts_count(),
+                query.1.len()
+            )));
+        }
+        let rerandomizer = ResponseRerandomizer::new(evaluator, &self.psi_params, &query.1)?;
+
+        // (BigBox index, segment index) sorted by estimated processing time in descending order
+        let mut tasks = self
+            .big_boxes
+            .iter()
+            .enumerate()
+            .flat_map(|(bb_index, bb)| {
+                bb.inner_boxes_per_segment()
+                    .into_iter()
+                    .enumerate()
+                    .map(move |(segment_index, ib_count)| (bb_index, segment_index, ib_count))
+            })
+            .collect_vec();
+        tasks.sort_by_cached_key(|(bb_index, segment_index, ib_count)| {
+            std::cmp::Reverse(timings.estimate(*bb_index, *segment_index, *ib_count))
+        });
+
+        // Spans are created upfront with explicit parents since segments are processed on rayon's threads, where
+        // caller's span isn't entered.
+        let query_span = info_span!("query", hash_tables = self.big_boxes.len());
+        let ht_spans = (0..self.big_boxes.len())
+            .map(|bb_index| info_span!(parent: &query_span, "hash_table", index = bb_index))
+            .collect_vec();
+
+        // `par_bridge` hands out tasks in order as threads become free, thus longest segments start first.
+        let skipped = AtomicBool::new(false);
+        tasks
+            .into_iter()
+            .par_bridge()
+            .for_each(|(bb_index, segment_index, ib_count)| {
+                if cancellation.is_cancelled() {
+                    skipped.store(true, Ordering::Relaxed);
+                    return;
+                }
+                let _entered = debug_span!(
+                    parent: &ht_spans[bb_index],
+                    "segment",
+                    index = segment_index,
+                    inner_boxes = ib_count
+                )
+                .entered();
+                let bb = &self.big_boxes[bb_index];
+                let ht_query_cts = &query.0[bb_index];
+
+                let cts =
+                    segment_index * source_powers_count..(segment_index + 1) * source_powers_count;
+                let query_ct_powers = &ht_query_cts.0[cts.clone()];
+                let powers_cache = powers_cache.zip(query.powers_key(bb_index, cts));
+
+                let now = Instant::now();
+                let (cts, times) = bb.process_segment_query(
+                    segment_index,
+                    query_ct_powers,
+                    evaluator,
+                    ek,
+                    powers_dag,
+                    rerandomizer.as_ref(),
+                    powers_cache,
+                );
+                timings.record(bb_index, segment_index, ib_count, now.elapsed());
+                debug!(
+                    powers_ms = times.powers.as_millis() as u64,
+                    evaluation_ms = times.evaluation.as_millis() as u64,
+                    "Processed segment"
+                );
+
+                on_segment(SegmentResponse {
+                    big_box: bb_index,
+                    segment: segment_index,
+                    cts,
+                    times,
+                });
+            });
+
+        if skipped.into_inner() {
+            cancellation.check()?;
+        }
+        Ok(())
+    }
+
+    /// Returns no. of occupied columns and total no. of columns across all BigBoxes. See `BigBox::occupancy`.
+    pub fn occupancy(&self) -> (u64, u64) {
+        self.big_boxes
+            .iter()
+            .map(|bb| bb.occupancy())
+            .fold((0, 0), |(occupied, capacity), (o, c)| {
+                (occupied + o, capacity + c)
             })
-            .collect_into_vec(&mut ht_responses);
+    }
+
+    /// Returns total no. of InnerBoxes across all BigBoxes
+    pub fn inner_boxes_count(&self) -> usize {
+        self.big_boxes
+            .iter()
+            .map(|bb| bb.inner_boxes.iter().map(|s| s.len()).sum::<usize>())
+            .sum()
+    }
 
-        QueryResponse(ht_responses)
+    /// Returns layout and occupancy of db. See `DbStats`.
+    pub fn stats(&self) -> DbStats {
+        let (occupied, capacity) = self.occupancy();
+        let mut row_occupancy = vec![0u64; self.psi_params.inner_box_columns() as usize + 1];
+        let mut coefficients = 0;
+        let mut bytes = 0;
+        self.inner_boxes().for_each(|ib| {
+            ib.ht_rows
+                .iter()
+                .for_each(|row| row_occupancy[row.curr_cols as usize] += 1);
+            let ib_coefficients = ib
+                .coefficients()
+                .iter()
+                .map(|c| c.len() as u64)
+                .sum::<u64>();
+            coefficients += ib_coefficients;
+            bytes += ib_coefficients * self.psi_params.coefficient_bytes() as u64
+                + (ib.item_data.len() + ib.label_data.len()) as u64;
+        });
+
+        DbStats {
+            inner_boxes_per_segment: self
+                .big_boxes
+                .iter()
+                .map(|bb| bb.inner_boxes_per_segment())
+                .collect(),
+            row_occupancy,
+            occupied,
+            capacity,
+            coefficients,
+            bytes,
+        }
+    }
+
+    /// InnerBoxes of all segments of all BigBoxes, in order
+    pub(crate) fn inner_boxes(&self) -> impl Iterator<Item = &InnerBox> {
+        self.big_boxes
+            .iter()
+            .flat_map(|bb| bb.inner_boxes.iter().flatten())
+    }
+
+    pub(crate) fn inner_boxes_mut(&mut self) -> impl Iterator<Item = &mut InnerBox> {
+        self.big_boxes
+            .iter_mut()
+            .flat_map(|bb| bb.inner_boxes.iter_mut().flatten())
     }
 
     pub fn print_diagnosis(&self) {
@@ -615,9 +1930,13 @@ impl Db {
 
 #[cfg(test)]
 mod tests {
-    use crate::{random_u256, time_it};
+    use crate::{
+        construct_query, evaluate_poly, gen_bfv_params, gen_random_item_labels,
+        generate_evaluation_key, random_u256, time_it,
+    };
 
     use super::*;
+    use bfv::SecretKey;
     use rand::thread_rng;
 
     #[test]
@@ -625,18 +1944,416 @@ mod tests {
         let psi_params = PsiParams::default();
         let mut inner_box = InnerBox::new(&psi_params);
         let mut rng = thread_rng();
-        for i in 0..InnerBox::max_rows(&psi_params.psi_pt, &psi_params.ct_slots) {
+        for i in 0..rows_per_segment(&psi_params.ct_slots, &psi_params.psi_pt) {
             while inner_box.ht_rows[i as usize].is_free() {
                 let item_label = {
                     let item = random_u256(&mut rng);
                     let label = random_u256(&mut rng);
-                    ItemLabel { item, label }
+                    ItemLabel::new(item, label)
                 };
                 if inner_box.can_insert(&item_label, i as usize) {
                     inner_box.insert_item_label(i as usize, &item_label, &psi_params.psi_pt);
                 }
             }
         }
-        time_it!("Generate coefficients", inner_box.generate_coefficients(););
+        time_it!("Generate coefficients", inner_box.generate_coefficients().unwrap(););
+    }
+
+    #[test]
+    fn insert_rejects_when_segment_is_full() {
+        let psi_params = PsiParams::default().with_max_inner_boxes_per_segment(1);
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+
+        // fill all columns of row 0 in the only InnerBox of segment 0
+        let max_cols = psi_params.inner_box_columns();
+        let mut inserted = 0;
+        while inserted < max_cols {
+            let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+            if big_box.inner_boxes[0][0].can_insert(&item_label, 0) {
+                big_box.insert(&item_label, 0).unwrap();
+                inserted += 1;
+            }
+        }
+
+        let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        assert_eq!(
+            big_box.insert(&item_label, 0),
+            Err(InsertError::SegmentFull {
+                big_box: 0,
+                segment: 0,
+                max_inner_boxes: 1
+            })
+        );
+        assert_eq!(big_box.inner_boxes[0].len(), 1);
+
+        // other rows of the segment still have space
+        assert!(big_box.insert(&item_label, 1).is_ok());
+    }
+
+    #[test]
+    fn inner_box_data_is_allocated_lazily() {
+        let psi_params = PsiParams::default();
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+        let col_span = psi_params.psi_pt.bfv_pt_bytes as usize;
+        let data_cols = |big_box: &BigBox| {
+            let ib = &big_box.inner_boxes[0][0];
+            assert_eq!(ib.item_data.shape()[1], ib.label_data.shape()[1]);
+            ib.item_data.shape()[1] / col_span
+        };
+
+        // no data is allocated before first insert
+        assert_eq!(data_cols(&big_box), 0);
+        assert_eq!(big_box.inner_boxes[1][0].item_data.len(), 0);
+
+        let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        big_box.insert(&item_label, 0).unwrap();
+        assert_eq!(data_cols(&big_box), DATA_COLUMNS_BLOCK as usize);
+        let first_col = big_box.inner_boxes[0][0]
+            .item_data
+            .slice(s![.., ..col_span])
+            .to_owned();
+
+        // data grows in blocks as row 0 fills up, upto all columns
+        let max_cols = psi_params.inner_box_columns();
+        let mut inserted = 1;
+        while inserted < max_cols {
+            let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+            if big_box.inner_boxes[0][0].can_insert(&item_label, 0) {
+                big_box.insert(&item_label, 0).unwrap();
+                inserted += 1;
+                assert_eq!(
+                    data_cols(&big_box),
+                    (inserted.div_ceil(DATA_COLUMNS_BLOCK) * DATA_COLUMNS_BLOCK).min(max_cols)
+                        as usize
+                );
+            }
+        }
+        assert_eq!(data_cols(&big_box), max_cols as usize);
+
+        // growing keeps existing data
+        assert_eq!(
+            big_box.inner_boxes[0][0]
+                .item_data
+                .slice(s![.., ..col_span]),
+            first_col
+        );
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+    }
+
+    #[test]
+    fn incremental_updates_match_full_preprocess() {
+        let psi_params = PsiParams::default();
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+
+        let item_labels = (0..50)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        item_labels.iter().enumerate().for_each(|(i, il)| {
+            big_box.insert(il, i % 4).unwrap();
+        });
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+
+        let coefficients = |big_box: &BigBox| {
+            big_box
+                .inner_boxes
+                .iter()
+                .flatten()
+                .map(|ib| ib.coefficients_data.clone())
+                .collect_vec()
+        };
+
+        // insert
+        let new_item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        big_box.insert_and_update(&new_item_label, 2).unwrap();
+        let updated = coefficients(&big_box);
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+        assert_eq!(updated, coefficients(&big_box));
+
+        // remove
+        assert!(big_box.remove(item_labels[5].item(), 1).unwrap());
+        assert!(!big_box.remove(item_labels[5].item(), 1).unwrap());
+        let updated = coefficients(&big_box);
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+        assert_eq!(updated, coefficients(&big_box));
+
+        // removed item can be inserted again
+        big_box.insert_and_update(&item_labels[5], 1).unwrap();
+    }
+
+    #[test]
+    fn regenerate_dirty_only_updates_dirty_rows() {
+        let psi_params = PsiParams::default();
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+
+        let item_labels = (0..50)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        item_labels.iter().enumerate().for_each(|(i, il)| {
+            big_box.insert(il, i % 4).unwrap();
+        });
+        // rows changed before preprocessing aren't dirty afterwards
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+        assert_eq!(big_box.regenerate_dirty().unwrap(), 0);
+
+        // inserts in rows 2 and 3 and a removal in row 1 leave three rows dirty
+        for ht_index in [2, 3] {
+            let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+            big_box.insert(&item_label, ht_index).unwrap();
+        }
+        assert!(big_box
+            .remove_at_inner_box(item_labels[5].item(), 1)
+            .is_some());
+        assert_eq!(big_box.regenerate_dirty().unwrap(), 3);
+        assert_eq!(big_box.regenerate_dirty().unwrap(), 0);
+
+        let coefficients = |big_box: &BigBox| {
+            big_box
+                .inner_boxes
+                .iter()
+                .flatten()
+                .map(|ib| ib.coefficients_data.clone())
+                .collect_vec()
+        };
+        let updated = coefficients(&big_box);
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+        assert_eq!(updated, coefficients(&big_box));
+    }
+
+    #[test]
+    fn upsert_replaces_label_in_place() {
+        let psi_params = PsiParams::default();
+        let mut rng = thread_rng();
+        let item_labels = gen_random_item_labels(100, None);
+        let mut db = Db::new(&psi_params);
+        assert!(db.insert_many(&item_labels).is_empty());
+        db.preprocess().unwrap();
+
+        let relabeled = ItemLabel::new(*item_labels[0].item(), random_u256(&mut rng));
+        assert!(db.upsert(&relabeled).unwrap());
+        assert!(db.upsert(&relabeled.clone().with_expiry(100)).unwrap());
+        let new_item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        assert!(!db.upsert(&new_item_label).unwrap());
+        assert_eq!(db.expiries.len(), 1);
+
+        // same as inserting the updated set from scratch
+        let mut expected_item_labels = item_labels.clone();
+        expected_item_labels[0] = relabeled;
+        expected_item_labels.push(new_item_label);
+        let mut expected_db = Db::new(&psi_params);
+        assert!(expected_db.insert_many(&expected_item_labels).is_empty());
+        expected_db.preprocess().unwrap();
+        let coefficients = |db: &Db| {
+            db.inner_boxes()
+                .map(|ib| ib.coefficients_data.clone())
+                .collect_vec()
+        };
+        assert_eq!(coefficients(&db), coefficients(&expected_db));
+    }
+
+    #[test]
+    fn segment_queries_respond_for_each_inner_box() {
+        let psi_params = PsiParams::default();
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+        // rows 0 and 1 fall into different segments, and segment 0 spills into a second InnerBox
+        let rows_per_segment = QueryLayout::new(&psi_params).rows_per_segment() as usize;
+        for row in [0, 0, rows_per_segment] {
+            for _ in 0..(psi_params.inner_box_columns() as usize / 2 + 1) {
+                let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+                big_box.insert(&item_label, row).unwrap();
+            }
+        }
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+        assert_eq!(big_box.inner_boxes_per_segment()[0], 2);
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let query_state = construct_query(
+            &[random_u256(&mut rng)],
+            &psi_params,
+            &evaluator,
+            &sk,
+            &mut rng,
+        );
+        let ht_query_cts = &query_state.query().0[0];
+        let powers_dag = psi_params.powers_dag().unwrap().into_nodes();
+
+        let segment_responses = ht_query_cts
+            .0
+            .chunks_exact(psi_params.source_powers.len())
+            .enumerate()
+            .map(|(segment_index, query_ct_powers)| {
+                big_box
+                    .process_segment_query(
+                        segment_index,
+                        query_ct_powers,
+                        &evaluator,
+                        &ek,
+                        &powers_dag,
+                        None,
+                        None,
+                    )
+                    .0
+            })
+            .collect_vec();
+        for (segment_responses, ib_count) in
+            izip!(segment_responses, big_box.inner_boxes_per_segment())
+        {
+            assert_eq!(
+                segment_responses.len(),
+                ib_count * psi_params.label_parts() as usize
+            );
+        }
+    }
+
+    #[test]
+    fn random_padding_fills_rows() {
+        let psi_params = PsiParams::default().with_random_padding();
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+        let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        big_box.insert(&item_label, 0).unwrap();
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+
+        // polynomials of occupied and empty rows alike have full degree
+        let ib = &big_box.inner_boxes[0][0];
+        let coefficients = ib.coefficients()[0].widened();
+        assert_eq!(
+            coefficients.shape()[1],
+            psi_params.eval_degree.inner_box_columns() as usize
+        );
+        let last_row = coefficients.shape()[0] - 1;
+        assert!(coefficients.row(0).iter().any(|c| *c != 0));
+        assert!(coefficients.row(last_row).iter().any(|c| *c != 0));
+    }
+
+    #[test]
+    fn coefficients_are_stored_as_u16_when_modulus_fits() {
+        // 40961 is an NTT friendly prime for BFV degree 2^12
+        let psi_params = PsiParams::default()
+            .with_bfv_degree(1 << 12)
+            .with_plaintext_modulus(8, 40961);
+        assert_eq!(psi_params.coefficient_bytes(), 2);
+        assert_eq!(PsiParams::default().coefficient_bytes(), 4);
+
+        let mut big_box = BigBox::new(&psi_params, 0);
+        let mut rng = thread_rng();
+        for _ in 0..20 {
+            let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+            big_box.insert(&item_label, 0).unwrap();
+        }
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+
+        // label chunks are interpolated at item chunks. Colliding chunks spill items into more InnerBoxes.
+        let modq = psi_params.psi_pt.bfv_pt;
+        for ib in big_box.inner_boxes[0].iter() {
+            let coefficients = ib.coefficients();
+            assert!(matches!(coefficients[0], CoefficientsView::U16(_)));
+            let coefficients = coefficients[0].widened();
+            for col in 0..ib.ht_rows[0].curr_cols as usize {
+                let real_col = ib.ht_rows[0].map_to_real_col(col);
+                let value = |data: &Array2<u8>| {
+                    bytes_to_u32(&data.row(0).as_slice().unwrap()[real_col..real_col + 1])
+                };
+                assert_eq!(
+                    evaluate_poly(
+                        value(&ib.item_data),
+                        coefficients.row(0).as_slice().unwrap(),
+                        modq
+                    ),
+                    value(&ib.label_data)
+                );
+            }
+        }
+
+        // incremental updates store u16s as well
+        let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        big_box.insert_and_update(&item_label, 0).unwrap();
+        let coefficients = |big_box: &BigBox| {
+            big_box.inner_boxes[0]
+                .iter()
+                .map(|ib| ib.coefficients_data.clone())
+                .collect_vec()
+        };
+        let updated = coefficients(&big_box);
+        big_box.preprocess_with_progress(&NoProgress).unwrap();
+        assert_eq!(updated, coefficients(&big_box));
+    }
+
+    #[test]
+    fn stats_works() {
+        let psi_params = PsiParams::default();
+        let mut db = Db::new(&psi_params);
+        assert!(db
+            .insert_many(&gen_random_item_labels(1000, None))
+            .is_empty());
+
+        let stats = db.stats();
+        assert_eq!(stats.inner_boxes(), db.inner_boxes_count());
+        assert_eq!(
+            stats.segments(),
+            psi_params.no_of_hash_tables as usize * db.big_boxes[0].inner_boxes.len()
+        );
+        assert_eq!((stats.occupied, stats.capacity), db.occupancy());
+        // histogram accounts for every row and every occupied column
+        let rows = db
+            .inner_boxes()
+            .map(|ib| ib.ht_rows.len() as u64)
+            .sum::<u64>();
+        assert_eq!(stats.row_occupancy.iter().sum::<u64>(), rows);
+        let occupied = stats
+            .row_occupancy
+            .iter()
+            .enumerate()
+            .map(|(cols, count)| cols as u64 * count)
+            .sum::<u64>();
+        assert_eq!(occupied, stats.occupied);
+        assert_eq!(stats.coefficients, 0);
+
+        db.preprocess().unwrap();
+        let stats = db.stats();
+        let expected_coefficients = db
+            .inner_boxes()
+            .map(|ib| {
+                (psi_params.label_parts() * psi_params.ct_slots.0) as u64
+                    * ib.coefficients_columns() as u64
+            })
+            .sum::<u64>();
+        assert_eq!(stats.coefficients, expected_coefficients);
+        // rows of 1000 items are sparsely occupied
+        assert!(
+            stats.coefficients
+                < (stats.inner_boxes() as u32
+                    * psi_params.label_parts()
+                    * psi_params.ct_slots.0
+                    * psi_params.eval_degree.inner_box_columns()) as u64
+        );
+        assert!(stats.bytes > stats.coefficients * 4);
+
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(serde_json::from_str::<DbStats>(&json).unwrap(), stats);
+    }
+
+    #[test]
+    fn segment_timings_estimate() {
+        let psi_params = PsiParams::default();
+        let db = Db::new(&psi_params);
+        let timings = SegmentTimings::new(&db);
+
+        // unmeasured segments are ordered by InnerBox count
+        assert!(timings.estimate(0, 0, 2) > timings.estimate(0, 1, 1));
+
+        timings.record(0, 0, 2, std::time::Duration::from_micros(400));
+        assert_eq!(timings.estimate(0, 0, 2), 400);
+        // unmeasured segment uses average of measured
+        assert_eq!(timings.estimate(1, 0, 3), 600);
+
+        timings.record(0, 0, 2, std::time::Duration::from_micros(800));
+        assert_eq!(timings.estimate(0, 0, 1), 250);
     }
 }
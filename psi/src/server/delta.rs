@@ -0,0 +1,352 @@
+use crate::{Db, InsertError, ItemLabel, Label, PsiError};
+use crypto_bigint::{Encoding, U256};
+use itertools::izip;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// Magic at start of delta files stored with `write_deltas`
+pub const DELTA_FILE_MAGIC: [u8; 8] = *b"ULPSI-DL";
+/// Bumped whenever layout of delta file changes
+pub const DELTA_FILE_VERSION: u32 = 1;
+/// magic (8 bytes) || version (u32 LE) || no. of deltas (u64 LE) || xxh3 checksum of deltas (u64 LE)
+const DELTA_FILE_HEADER_BYTES: usize = 28;
+
+const INSERT_TAG: u8 = 0;
+const REMOVE_TAG: u8 = 1;
+/// Insert of ItemLabel with expiry
+const INSERT_WITH_EXPIRY_TAG: u8 = 2;
+
+/// Update of server set applied to a preprocessed db with `Db::apply_delta`
+#[derive(Clone, Debug, PartialEq)]
+pub enum DbDelta {
+    Insert(ItemLabel),
+    /// Removes item and its label
+    Remove(U256),
+}
+
+impl DbDelta {
+    /// Appends delta to `bytes` as tag (u8) || item (32 bytes LE), followed by expiry (u64 LE) if set and label length
+    /// (u32 LE) || label if delta is an insert
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        match self {
+            DbDelta::Insert(item_label) => {
+                match item_label.expires_at() {
+                    Some(expires_at) => {
+                        bytes.push(INSERT_WITH_EXPIRY_TAG);
+                        bytes.extend(item_label.item().to_le_bytes());
+                        bytes.extend(expires_at.to_le_bytes());
+                    }
+                    None => {
+                        bytes.push(INSERT_TAG);
+                        bytes.extend(item_label.item().to_le_bytes());
+                    }
+                }
+                let label = item_label.label().as_bytes();
+                bytes.extend((label.len() as u32).to_le_bytes());
+                bytes.extend(label);
+            }
+            DbDelta::Remove(item) => {
+                bytes.push(REMOVE_TAG);
+                bytes.extend(item.to_le_bytes());
+            }
+        }
+    }
+
+    /// Decodes delta encoded with `encode` from start of `bytes` and returns it along with rest of `bytes`
+    fn decode(bytes: &[u8]) -> Result<(DbDelta, &[u8]), PsiError> {
+        let truncated = || malformed("truncated delta");
+        let (tag, rest) = bytes.split_first().ok_or_else(truncated)?;
+        if rest.len() < 32 {
+            return Err(truncated());
+        }
+        let (item, rest) = rest.split_at(32);
+        let item = U256::from_le_bytes(item.try_into().unwrap());
+        match *tag {
+            INSERT_TAG | INSERT_WITH_EXPIRY_TAG => {
+                let expiry_bytes = if *tag == INSERT_WITH_EXPIRY_TAG { 8 } else { 0 };
+                if rest.len() < expiry_bytes + 4 {
+                    return Err(truncated());
+                }
+                let (expires_at, rest) = rest.split_at(expiry_bytes);
+                let (len, rest) = rest.split_at(4);
+                let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+                if rest.len() < len {
+                    return Err(truncated());
+                }
+                let (label, rest) = rest.split_at(len);
+                let mut item_label = ItemLabel::new(item, Label::new(label.to_vec()));
+                if !expires_at.is_empty() {
+                    item_label =
+                        item_label.with_expiry(u64::from_le_bytes(expires_at.try_into().unwrap()));
+                }
+                Ok((DbDelta::Insert(item_label), rest))
+            }
+            REMOVE_TAG => Ok((DbDelta::Remove(item), rest)),
+            tag => Err(malformed(&format!("unknown delta tag {tag}"))),
+        }
+    }
+}
+
+fn malformed(reason: &str) -> PsiError {
+    PsiError::Serialization(format!("Malformed delta file: {reason}"))
+}
+
+/// Stores `deltas` at `path`, in order, prefixed with a header carrying their count and checksum
+pub fn write_deltas(path: &Path, deltas: &[DbDelta]) -> Result<(), PsiError> {
+    let mut body = vec![];
+    deltas.iter().for_each(|delta| delta.encode(&mut body));
+    let file = File::create(path)
+        .map_err(|e| PsiError::Io(format!("Failed to create {}: {e}", path.display())))?;
+    let mut writer = BufWriter::new(file);
+    writer.write_all(&DELTA_FILE_MAGIC)?;
+    writer.write_all(&DELTA_FILE_VERSION.to_le_bytes())?;
+    writer.write_all(&(deltas.len() as u64).to_le_bytes())?;
+    writer.write_all(&xxh3_64(&body).to_le_bytes())?;
+    writer.write_all(&body)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Reads deltas stored at `path` with `write_deltas`. Returns `PsiError::Serialization` if the file is malformed or its
+/// checksum doesn't match.
+pub fn read_deltas(path: &Path) -> Result<Vec<DbDelta>, PsiError> {
+    let bytes = std::fs::read(path)
+        .map_err(|e| PsiError::Io(format!("Failed to read {}: {e}", path.display())))?;
+    if bytes.len() < DELTA_FILE_HEADER_BYTES || !bytes.starts_with(&DELTA_FILE_MAGIC) {
+        return Err(malformed("truncated header"));
+    }
+    let version = u32::from_le_bytes(bytes[8..12].try_into().unwrap());
+    if version != DELTA_FILE_VERSION {
+        return Err(PsiError::Serialization(format!(
+            "Unsupported delta file version {version}, expected {DELTA_FILE_VERSION}"
+        )));
+    }
+    let count = u64::from_le_bytes(bytes[12..20].try_into().unwrap());
+    let checksum = u64::from_le_bytes(bytes[20..28].try_into().unwrap());
+    let mut rest = &bytes[DELTA_FILE_HEADER_BYTES..];
+    if xxh3_64(rest) != checksum {
+        return Err(malformed("checksum mismatch"));
+    }
+
+    let mut deltas = vec![];
+    while !rest.is_empty() {
+        let (delta, next) = DbDelta::decode(rest)?;
+        deltas.push(delta);
+        rest = next;
+    }
+    if deltas.len() as u64 != count {
+        return Err(malformed("unexpected no. of deltas"));
+    }
+    Ok(deltas)
+}
+
+/// Outcome of `Db::apply_delta`
+#[derive(Debug, Default)]
+pub struct DeltaSummary {
+    /// No. of inserted ItemLabels
+    pub inserted: usize,
+    /// No. of removed items
+    pub removed: usize,
+    /// No. of removals of items that don't exist in db
+    pub missing: usize,
+    /// Indices of inserts rejected due to `max_inner_boxes_per_segment`, along with the reason. Rejected ItemLabels
+    /// aren't inserted in any BigBox.
+    pub rejected: Vec<(usize, InsertError)>,
+    /// No. of InnerBox rows re-interpolated across all BigBoxes
+    pub updated_rows: usize,
+}
+
+impl Db {
+    /// Applies `deltas` to preprocessed db, in order, and re-interpolates only rows they touched. Unlike calling
+    /// `insert_and_update` or `remove` for each delta, a row touched by many deltas is re-interpolated once and rows
+    /// of different InnerBoxes are re-interpolated in parallel, thus cost of a delta is proportional to no. of
+    /// touched rows instead of size of the db.
+    ///
+    /// Inserts rejected due to `max_inner_boxes_per_segment` are skipped and reported in `DeltaSummary::rejected`.
+    pub fn apply_delta(&mut self, deltas: &[DbDelta]) -> Result<DeltaSummary, PsiError> {
+        let mut summary = DeltaSummary::default();
+        for (index, delta) in deltas.iter().enumerate() {
+            match delta {
+                DbDelta::Insert(item_label) => {
+                    let item_label = self.oprf_item_label(item_label);
+                    let indices = self.cuckoo.table_indices(item_label.item());
+                    let capacity = izip!(self.big_boxes.iter(), indices.iter()).try_for_each(
+                        |(big_box, ht_index)| {
+                            big_box.check_capacity(&item_label, *ht_index as usize)
+                        },
+                    );
+                    if let Err(e) = capacity {
+                        summary.rejected.push((index, e));
+                        continue;
+                    }
+                    for (big_box, ht_index) in izip!(self.big_boxes.iter_mut(), indices.iter()) {
+                        big_box.insert_at_inner_box(&item_label, *ht_index as usize)?;
+                    }
+                    self.record_expiry(&item_label);
+                    summary.inserted += 1;
+                }
+                DbDelta::Remove(item) => {
+                    let item = match &self.oprf_key {
+                        Some(key) => key.evaluate_item(item),
+                        None => *item,
+                    };
+                    match self.remove_stored(&item) {
+                        true => summary.removed += 1,
+                        false => summary.missing += 1,
+                    }
+                }
+            }
+        }
+
+        summary.updated_rows = self.regenerate_dirty()?;
+        Ok(summary)
+    }
+
+    /// Removes items whose expiry (see `ItemLabel::with_expiry`) is at or before `now`, in seconds since unix epoch,
+    /// and re-interpolates only rows they were removed from. Returns no. of removed items.
+    pub fn purge_expired(&mut self, now: u64) -> Result<usize, PsiError> {
+        let expired = self
+            .expiries
+            .iter()
+            .filter(|(_, expires_at)| **expires_at <= now)
+            .map(|(item, _)| U256::from_le_bytes(*item))
+            .collect::<Vec<_>>();
+        for item in &expired {
+            self.remove_stored(item);
+        }
+        self.regenerate_dirty()?;
+        Ok(expired.len())
+    }
+
+    /// Removes `item`, which is already replaced with its OPRF output, from all BigBoxes without re-interpolating,
+    /// leaving rows it was removed from dirty. Returns false if `item` does not exist in db.
+    fn remove_stored(&mut self, item: &U256) -> bool {
+        let indices = self.cuckoo.table_indices(item);
+        let mut removed = false;
+        for (big_box, ht_index) in izip!(self.big_boxes.iter_mut(), indices.iter()) {
+            removed |= big_box
+                .remove_at_inner_box(item, *ht_index as usize)
+                .is_some();
+        }
+        self.expiries.remove(&item.to_le_bytes());
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rand::thread_rng;
+
+    use crate::{random_u256, Coefficients, PsiParams};
+
+    use super::*;
+
+    fn coefficients(db: &Db) -> Vec<Vec<Coefficients>> {
+        db.inner_boxes()
+            .map(|ib| {
+                ib.coefficients()
+                    .iter()
+                    .map(|c| c.into_owned())
+                    .collect_vec()
+            })
+            .collect_vec()
+    }
+
+    #[test]
+    fn apply_delta_matches_incremental_updates() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        let mut db = Db::new(&psi_params);
+        let mut expected_db = Db::new(&psi_params);
+        for db in [&mut db, &mut expected_db] {
+            assert!(db.insert_many(&item_labels).is_empty());
+            db.preprocess().unwrap();
+        }
+
+        let deltas = (0..10)
+            .map(|_| DbDelta::Insert(ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng))))
+            .chain(
+                item_labels[..5]
+                    .iter()
+                    .map(|item_label| DbDelta::Remove(*item_label.item())),
+            )
+            .chain([
+                DbDelta::Remove(random_u256(&mut rng)),
+                DbDelta::Insert(
+                    ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)).with_expiry(100),
+                ),
+            ])
+            .collect_vec();
+        let path = std::env::temp_dir().join(format!("ulpsi_deltas_{}.bin", std::process::id()));
+        write_deltas(&path, &deltas).unwrap();
+        assert_eq!(read_deltas(&path).unwrap(), deltas);
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, bytes).unwrap();
+        assert!(matches!(
+            read_deltas(&path),
+            Err(PsiError::Serialization(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+
+        let summary = db.apply_delta(&deltas).unwrap();
+        assert_eq!(
+            (summary.inserted, summary.removed, summary.missing),
+            (11, 5, 1)
+        );
+        assert!(summary.rejected.is_empty());
+        for delta in &deltas {
+            match delta {
+                DbDelta::Insert(item_label) => expected_db.insert_and_update(item_label).unwrap(),
+                DbDelta::Remove(item) => {
+                    expected_db.remove(item).unwrap();
+                }
+            }
+        }
+        assert_eq!(coefficients(&db), coefficients(&expected_db));
+    }
+
+    #[test]
+    fn purge_expired_removes_expired_items() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = (0..100)
+            .map(|index| {
+                let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+                match index / 10 {
+                    0 => item_label.with_expiry(100),
+                    1 => item_label.with_expiry(200),
+                    _ => item_label,
+                }
+            })
+            .collect_vec();
+        let mut db = Db::new(&psi_params);
+        let mut expected_db = Db::new(&psi_params);
+        for db in [&mut db, &mut expected_db] {
+            assert!(db.insert_many(&item_labels).is_empty());
+            db.preprocess().unwrap();
+        }
+
+        assert_eq!(db.purge_expired(150).unwrap(), 10);
+        for item_label in &item_labels[..10] {
+            assert!(expected_db.remove(item_label.item()).unwrap());
+        }
+        assert_eq!(coefficients(&db), coefficients(&expected_db));
+        assert_eq!(db.purge_expired(150).unwrap(), 0);
+
+        // expiries are stored with db
+        let path = std::env::temp_dir().join(format!("ulpsi_expiry_db_{}.bin", std::process::id()));
+        db.store(&path).unwrap();
+        let mut db = Db::load(&path, &psi_params).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(db.purge_expired(200).unwrap(), 10);
+    }
+}
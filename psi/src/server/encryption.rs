@@ -0,0 +1,336 @@
+use crate::{Db, PsiError, PsiParams};
+use chacha20poly1305::{
+    aead::{AeadInPlace, KeyInit},
+    ChaCha20Poly1305, Key, Nonce, Tag,
+};
+use memmap2::MmapMut;
+use rand::{thread_rng, CryptoRng, RngCore};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Read, Write},
+    path::Path,
+};
+use zeroize::Zeroizing;
+
+/// Magic at start of db files stored with `Db::store_encrypted`
+pub const ENCRYPTED_DB_FILE_MAGIC: [u8; 8] = *b"ULPSI-DE";
+/// Bumped whenever encryption of db files changes. Layout of decrypted db file is versioned by `DB_FILE_VERSION`.
+pub const ENCRYPTED_DB_FILE_VERSION: u32 = 1;
+/// Db file is encrypted in chunks of this many bytes, each with its own authentication tag, thus it is neither held in
+/// memory twice nor decrypted before it is authenticated
+const CHUNK_BYTES: usize = 1 << 20;
+const TAG_BYTES: usize = 16;
+const NONCE_PREFIX_BYTES: usize = 7;
+/// magic (8 bytes) || version (u32 LE) || nonce prefix (7 bytes)
+const ENCRYPTED_HEADER_BYTES: usize = 19;
+
+/// Key of encrypted db files. Zeroized on drop.
+#[derive(Clone)]
+pub struct DbKey(Zeroizing<[u8; 32]>);
+
+impl DbKey {
+    pub fn new(key: [u8; 32]) -> DbKey {
+        DbKey(Zeroizing::new(key))
+    }
+
+    pub fn random<R: RngCore + CryptoRng>(rng: &mut R) -> DbKey {
+        let mut key = Zeroizing::new([0u8; 32]);
+        rng.fill_bytes(key.as_mut());
+        DbKey(key)
+    }
+
+    /// Parses key from 64 hex characters
+    pub fn from_hex(key: &str) -> Result<DbKey, PsiError> {
+        let mut bytes = Zeroizing::new([0u8; 32]);
+        hex::decode_to_slice(key.trim(), bytes.as_mut()).map_err(|_| {
+            PsiError::InvalidParams("Db key must be 32 hex encoded bytes".to_string())
+        })?;
+        Ok(DbKey(bytes))
+    }
+
+    fn cipher(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new(Key::from_slice(self.0.as_ref()))
+    }
+}
+
+/// Nonce of chunk `index`: nonce prefix (7 bytes) || index (u32 BE) || 1 if chunk is the last one, otherwise 0. Marking
+/// the last chunk detects truncation at a chunk boundary.
+fn chunk_nonce(prefix: &[u8], index: u32, last: bool) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[..NONCE_PREFIX_BYTES].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_BYTES..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    *Nonce::from_slice(&nonce)
+}
+
+fn malformed(reason: &str) -> PsiError {
+    PsiError::Serialization(format!("Malformed encrypted db file: {reason}"))
+}
+
+/// Encrypts bytes written to it chunk by chunk. Every chunk, except the last, is `CHUNK_BYTES` long. `finish` must be
+/// called to write the last chunk.
+struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: ChaCha20Poly1305,
+    header: [u8; ENCRYPTED_HEADER_BYTES],
+    buffer: Zeroizing<Vec<u8>>,
+    index: u32,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    fn new<R: RngCore + CryptoRng>(
+        mut inner: W,
+        key: &DbKey,
+        rng: &mut R,
+    ) -> Result<EncryptingWriter<W>, PsiError> {
+        let mut header = [0u8; ENCRYPTED_HEADER_BYTES];
+        header[..8].copy_from_slice(&ENCRYPTED_DB_FILE_MAGIC);
+        header[8..12].copy_from_slice(&ENCRYPTED_DB_FILE_VERSION.to_le_bytes());
+        rng.fill_bytes(&mut header[12..]);
+        inner.write_all(&header)?;
+
+        Ok(EncryptingWriter {
+            inner,
+            cipher: key.cipher(),
+            header,
+            buffer: Zeroizing::new(Vec::with_capacity(CHUNK_BYTES + TAG_BYTES)),
+            index: 0,
+        })
+    }
+
+    /// Encrypts and writes first `len` bytes of buffer as chunk
+    fn write_chunk(&mut self, len: usize, last: bool) -> Result<(), PsiError> {
+        let nonce = chunk_nonce(&self.header[12..], self.index, last);
+        let tag = self
+            .cipher
+            .encrypt_in_place_detached(&nonce, &self.header, &mut self.buffer[..len])
+            .map_err(|_| PsiError::Serialization("Db encryption failed".to_string()))?;
+        self.inner.write_all(&self.buffer[..len])?;
+        self.inner.write_all(&tag)?;
+        self.buffer.drain(..len);
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| PsiError::Serialization("Db is too large to encrypt".to_string()))?;
+        Ok(())
+    }
+
+    /// Writes the last chunk and returns the inner writer
+    fn finish(mut self) -> Result<W, PsiError> {
+        let len = self.buffer.len();
+        self.write_chunk(len, true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // keep at least one byte buffered, since the last chunk is only known on `finish`
+        while self.buffer.len() > CHUNK_BYTES {
+            self.write_chunk(CHUNK_BYTES, false)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Db {
+    /// Same as `Db::store` but encrypts and authenticates the db file with `key`, since polynomial coefficients
+    /// reveal the entire server set. File is encrypted in chunks with ChaCha20Poly1305.
+    ///
+    /// ChaCha20Poly1305 is used instead of AES-GCM with a separate HMAC of the file. It is the AEAD that already seals
+    /// client secret keys, it doesn't depend on AES hardware support to be fast and constant time, and the tag of each
+    /// chunk covers its contents, the header and the chunk's position, so a file HMAC would add nothing.
+    ///
+    /// Encrypted layout: magic (8 bytes) || version (u32 LE) || nonce prefix (7 bytes) || encrypted chunks of
+    /// `Db::store` layout, each followed by its tag. Header is authenticated with every chunk.
+    pub fn store_encrypted(&mut self, path: &Path, key: &DbKey) -> Result<(), PsiError> {
+        let file = File::create(path)
+            .map_err(|e| PsiError::Io(format!("Failed to create {}: {e}", path.display())))?;
+        let mut writer = EncryptingWriter::new(BufWriter::new(file), key, &mut thread_rng())?;
+        self.write_to(&mut writer)?;
+        writer.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Loads db stored with `Db::store_encrypted`. Returns `PsiError::Serialization` if `key` is wrong or the file was
+    /// modified, and `PsiError::ParamsMismatch` if db was stored with params other than `psi_params`.
+    ///
+    /// Encrypted file can't be memory mapped, thus db is decrypted into anonymous memory, chunk by chunk, and
+    /// coefficients are evaluated in place from there as they are from the file in `Db::load`. That memory isn't
+    /// locked, since a large db would exceed `RLIMIT_MEMLOCK`, so the OS may swap decrypted pages to disk. Hosts that
+    /// need the db to never touch disk in plaintext must disable swap or encrypt it.
+    pub fn load_encrypted(
+        path: &Path,
+        key: &DbKey,
+        psi_params: &PsiParams,
+    ) -> Result<Db, PsiError> {
+        let file = File::open(path)
+            .map_err(|e| PsiError::Io(format!("Failed to open {}: {e}", path.display())))?;
+        let file_len = file.metadata()?.len() as usize;
+        let mut reader = BufReader::new(file);
+
+        let mut header = [0u8; ENCRYPTED_HEADER_BYTES];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| malformed("truncated header"))?;
+        if header[..8] != ENCRYPTED_DB_FILE_MAGIC {
+            return Err(malformed("missing magic"));
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != ENCRYPTED_DB_FILE_VERSION {
+            return Err(PsiError::Serialization(format!(
+                "Unsupported encrypted db file version {version}, expected {ENCRYPTED_DB_FILE_VERSION}"
+            )));
+        }
+
+        // every chunk but the last is full, and the last chunk has at least its tag
+        let body_len = file_len - ENCRYPTED_HEADER_BYTES;
+        let chunks = (body_len + CHUNK_BYTES + TAG_BYTES - 1) / (CHUNK_BYTES + TAG_BYTES);
+        let last_chunk_len = body_len
+            .checked_sub((chunks.max(1) - 1) * (CHUNK_BYTES + TAG_BYTES))
+            .filter(|len| *len >= TAG_BYTES)
+            .ok_or(malformed("truncated chunk"))?;
+        let db_len = (chunks - 1) * CHUNK_BYTES + last_chunk_len - TAG_BYTES;
+        if db_len == 0 {
+            return Err(malformed("empty db"));
+        }
+
+        let cipher = key.cipher();
+        let mut plaintext = MmapMut::map_anon(db_len)?;
+        let mut tag = Tag::default();
+        for (index, chunk) in plaintext.chunks_mut(CHUNK_BYTES).enumerate() {
+            reader.read_exact(chunk)?;
+            reader.read_exact(&mut tag)?;
+            let nonce = chunk_nonce(&header[12..], index as u32, index == chunks - 1);
+            cipher
+                .decrypt_in_place_detached(&nonce, &header, chunk, &tag)
+                .map_err(|_| {
+                    PsiError::Serialization(
+                        "Wrong db key or corrupted encrypted db file".to_string(),
+                    )
+                })?;
+        }
+
+        Db::from_mmap(plaintext.make_read_only()?, psi_params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+
+    use crate::{random_u256, ItemLabel};
+
+    use super::*;
+
+    #[test]
+    fn store_and_load_encrypted_db_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let mut db = Db::new(&psi_params);
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        assert!(db.insert_many(&item_labels).is_empty());
+        db.preprocess().unwrap();
+
+        let coefficients = |db: &Db| {
+            db.inner_boxes()
+                .map(|ib| {
+                    ib.coefficients()
+                        .iter()
+                        .map(|c| c.into_owned())
+                        .collect_vec()
+                })
+                .collect_vec()
+        };
+
+        let key = DbKey::random(&mut rng);
+        let path = std::env::temp_dir().join(format!("ulpsi_enc_db_{}.bin", std::process::id()));
+        db.store_encrypted(&path, &key).unwrap();
+        assert_eq!(
+            coefficients(&Db::load_encrypted(&path, &key, &psi_params).unwrap()),
+            coefficients(&db)
+        );
+
+        // coefficients aren't stored in plaintext
+        let bytes = std::fs::read(&path).unwrap();
+        let mut plain = vec![];
+        db.write_to(&mut plain).unwrap();
+        assert!(!bytes.windows(64).any(|w| w == &plain[plain.len() - 64..]));
+
+        // encrypted db is rejected with wrong key, by `Db::load` and once modified or truncated
+        assert!(matches!(
+            Db::load_encrypted(&path, &DbKey::random(&mut rng), &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+        assert!(matches!(
+            Db::load(&path, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+        let mut modified = bytes.clone();
+        modified[ENCRYPTED_HEADER_BYTES + 100] ^= 1;
+        std::fs::write(&path, &modified).unwrap();
+        assert!(matches!(
+            Db::load_encrypted(&path, &key, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(
+            Db::load_encrypted(&path, &key, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn chunks_are_bound_to_position() {
+        let key = DbKey::from_hex(&"ab".repeat(32)).unwrap();
+        assert!(DbKey::from_hex("abcd").is_err());
+
+        // db spanning multiple chunks, with the last chunk full
+        let plaintext = (0..2 * CHUNK_BYTES).map(|i| i as u8).collect_vec();
+        let mut writer = EncryptingWriter::new(vec![], &key, &mut thread_rng()).unwrap();
+        writer.write_all(&plaintext).unwrap();
+        let bytes = writer.finish().unwrap();
+        assert_eq!(
+            bytes.len(),
+            ENCRYPTED_HEADER_BYTES + plaintext.len() + 2 * TAG_BYTES
+        );
+
+        // swapping chunks fails authentication
+        let header = &bytes[..ENCRYPTED_HEADER_BYTES];
+        let chunks = bytes[ENCRYPTED_HEADER_BYTES..]
+            .chunks(CHUNK_BYTES + TAG_BYTES)
+            .collect_vec();
+        let decrypt = |index: usize, last: bool, chunk: &[u8]| {
+            let (ct, tag) = chunk.split_at(chunk.len() - TAG_BYTES);
+            let mut buffer = ct.to_vec();
+            key.cipher()
+                .decrypt_in_place_detached(
+                    &chunk_nonce(&header[12..], index as u32, last),
+                    header,
+                    &mut buffer,
+                    Tag::from_slice(tag),
+                )
+                .map(|_| buffer)
+        };
+        assert_eq!(
+            decrypt(0, false, chunks[0]).unwrap(),
+            &plaintext[..CHUNK_BYTES]
+        );
+        assert_eq!(
+            decrypt(1, true, chunks[1]).unwrap(),
+            &plaintext[CHUNK_BYTES..]
+        );
+        assert!(decrypt(0, false, chunks[1]).is_err());
+        assert!(decrypt(1, false, chunks[1]).is_err());
+    }
+}
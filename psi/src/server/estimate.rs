@@ -0,0 +1,207 @@
+use crate::{
+    construct_query, expected_query_bytes, expected_response_bytes, gen_bfv_params,
+    gen_random_item_labels, generate_evaluation_key, interpolate, poly_from_roots, random_u256,
+    CancellationToken, DbStats, ItemLabel, Label, PsiError, PsiMode, PsiParams, QueryLayout,
+    Server,
+};
+use bfv::{Evaluator, SecretKey};
+use itertools::Itertools;
+use rand::{thread_rng, Rng};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+/// No. of ItemLabels in sample db that is queried to measure polynomial evaluation time
+const SAMPLE_SET_SIZE: usize = 256;
+
+/// No. of polynomials interpolated to measure interpolation throughput
+const SAMPLE_POLYNOMIALS: usize = 16;
+
+/// Predicted resource usage of a server set with given `PsiParams`, see `estimate_cost`.
+///
+/// Estimates assume items are spread uniformly across hash table rows and ignore collisions of item chunks, which
+/// spill some items into additional InnerBoxes. Times assume work is spread perfectly across all threads.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CostEstimate {
+    pub set_size: u64,
+    /// Predicted no. of InnerBoxes in each segment of each BigBox
+    pub inner_boxes_per_segment: usize,
+    /// Predicted no. of InnerBoxes across all BigBoxes
+    pub inner_boxes: usize,
+    /// Size of coefficients, item data and label data of preprocessed db, assuming every InnerBox has a fully occupied
+    /// row. See `DbStats::bytes`.
+    pub db_bytes: u64,
+    /// Time to interpolate polynomials of all InnerBoxes
+    pub preprocess_time: Duration,
+    /// Size of serialized query. See `expected_query_bytes`.
+    pub query_bytes: usize,
+    /// Size of uncompressed response. See `expected_response_bytes`.
+    pub response_bytes: usize,
+    /// Time to calculate PS powers and evaluate polynomials of all InnerBoxes for a single query
+    pub query_latency: Duration,
+}
+
+/// Predicts db memory, preprocessing time, query and response sizes and query latency of server set of `set_size`
+/// items with `psi_params`, without generating the set. Sizes are calculated from db layout. Times are extrapolated
+/// from interpolation throughput and query processing time of a small sample db measured on this machine, using
+/// rayon's current thread pool.
+pub fn estimate_cost(psi_params: &PsiParams, set_size: u64) -> Result<CostEstimate, PsiError> {
+    psi_params.validate()?;
+    let evaluator = Evaluator::new(gen_bfv_params(psi_params));
+    let threads = rayon::current_num_threads() as u32;
+
+    let segments = QueryLayout::new(psi_params).segments_per_hash_table();
+    let inner_boxes_per_segment = predicted_inner_boxes_per_segment(psi_params, set_size);
+    let inner_boxes = psi_params.no_of_hash_tables as usize * segments * inner_boxes_per_segment;
+
+    let db_stats = DbStats {
+        inner_boxes_per_segment: vec![
+            vec![inner_boxes_per_segment; segments];
+            psi_params.no_of_hash_tables as usize
+        ],
+        ..Default::default()
+    };
+
+    // each real row of InnerBox has one polynomial per label part
+    let polynomials =
+        (inner_boxes * psi_params.ct_slots.0 as usize) as u32 * psi_params.label_parts();
+    let preprocess_time = measure_interpolation(psi_params)? * polynomials / threads;
+
+    let (powers_time, evaluation_time_per_inner_box) = measure_query(psi_params, &evaluator)?;
+    let query_latency =
+        (powers_time + evaluation_time_per_inner_box * inner_boxes as u32) / threads;
+
+    Ok(CostEstimate {
+        set_size,
+        inner_boxes_per_segment,
+        inner_boxes,
+        db_bytes: inner_box_bytes(psi_params) * inner_boxes as u64,
+        preprocess_time,
+        query_bytes: expected_query_bytes(&evaluator, psi_params),
+        response_bytes: expected_response_bytes(psi_params, &evaluator, &db_stats),
+        query_latency,
+    })
+}
+
+/// Each item is inserted into every hash table, thus each hash table row holds `set_size / ht_size` items on average.
+/// No. of InnerBoxes of a segment is set by the most loaded of its rows, whose load is approximated with normal
+/// approximation of the maximum of Poisson distributed row loads.
+fn predicted_inner_boxes_per_segment(psi_params: &PsiParams, set_size: u64) -> usize {
+    let mean_load = set_size as f64 / psi_params.ht_size.0 as f64;
+    let rows = QueryLayout::new(psi_params).rows_per_segment() as f64;
+    let max_load = mean_load + (2.0 * mean_load * rows.ln()).sqrt();
+    // every segment starts with a single InnerBox
+    ((max_load / psi_params.inner_box_columns() as f64).ceil() as usize).max(1)
+}
+
+/// Size of a single preprocessed InnerBox with a fully occupied row. Data and coefficients are only allocated upto
+/// most occupied row, thus sparsely occupied InnerBoxes are smaller.
+fn inner_box_bytes(psi_params: &PsiParams) -> u64 {
+    let ct_slots = psi_params.ct_slots.0 as u64;
+    let label_parts = psi_params.label_parts() as u64;
+    let coefficients = label_parts * ct_slots * psi_params.eval_degree.inner_box_columns() as u64;
+    let data_row_bytes = (psi_params.inner_box_columns() * psi_params.psi_pt.bfv_pt_bytes) as u64;
+    // membership polynomial, if any, is interpolated from item data
+    let label_rows = match psi_params.mode {
+        PsiMode::Labeled => psi_params.psi_pt.label_parts() as u64 * ct_slots,
+        PsiMode::Unlabeled => 0,
+    };
+    coefficients * psi_params.coefficient_bytes() as u64 + (ct_slots + label_rows) * data_row_bytes
+}
+
+/// Returns time to interpolate polynomial of a fully occupied real row on a single thread
+fn measure_interpolation(psi_params: &PsiParams) -> Result<Duration, PsiError> {
+    let mut rng = thread_rng();
+    let modq = psi_params.psi_pt.bfv_pt as u32;
+    let points = psi_params.inner_box_columns();
+    // x values of a row are distinct
+    let x = (1..=points).collect_vec();
+
+    let start = Instant::now();
+    for _ in 0..SAMPLE_POLYNOMIALS {
+        let y = (0..points).map(|_| rng.gen::<u32>() % modq).collect_vec();
+        match psi_params.mode {
+            PsiMode::Labeled => {
+                interpolate(&x, &y, modq, psi_params.interpolation)?;
+            }
+            PsiMode::Unlabeled => {
+                poly_from_roots(&y, modq);
+            }
+        }
+    }
+    Ok(start.elapsed() / SAMPLE_POLYNOMIALS as u32)
+}
+
+/// Queries sample db and returns time spent calculating PS powers across all segments and evaluating polynomials of a
+/// single InnerBox. Time to calculate powers doesn't depend on set size since powers are calculated once per segment.
+fn measure_query(
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+) -> Result<(Duration, Duration), PsiError> {
+    let mut rng = thread_rng();
+    let label_bytes = psi_params.label_bytes() as usize;
+    let item_labels = gen_random_item_labels(SAMPLE_SET_SIZE, None)
+        .iter()
+        .map(|item_label| {
+            let label = (0..label_bytes).map(|_| rng.gen()).collect_vec();
+            ItemLabel::new(*item_label.item(), Label::new(label))
+        })
+        .collect_vec();
+    let mut server = Server::new(psi_params);
+    server.setup(&item_labels)?;
+
+    let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+    let ek = generate_evaluation_key(psi_params, evaluator, &sk, &mut rng);
+    let query_state = construct_query(
+        &[random_u256(&mut rng)],
+        psi_params,
+        evaluator,
+        &sk,
+        &mut rng,
+    );
+    let (_, metadata) =
+        server.query_with_metadata(query_state.query(), &ek, &CancellationToken::new())?;
+
+    let sample_inner_boxes = server.snapshot().stats().inner_boxes() as u32;
+    Ok((
+        metadata.powers_time,
+        metadata.evaluation_time() / sample_inner_boxes,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimate_matches_db_layout() {
+        let psi_params = PsiParams::default();
+        let set_size = 1000;
+        let estimate = estimate_cost(&psi_params, set_size).unwrap();
+
+        // small sets fit in a single InnerBox per segment
+        let mut server = Server::new(&psi_params);
+        server
+            .setup(&gen_random_item_labels(set_size as usize, None))
+            .unwrap();
+        let stats = server.snapshot().stats();
+        assert_eq!(estimate.inner_boxes_per_segment, 1);
+        assert_eq!(estimate.inner_boxes, stats.inner_boxes());
+        // rows of small sets are sparsely occupied
+        assert!(estimate.db_bytes >= stats.bytes);
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        assert_eq!(
+            estimate.query_bytes,
+            expected_query_bytes(&evaluator, &psi_params)
+        );
+        assert_eq!(
+            estimate.response_bytes,
+            expected_response_bytes(&psi_params, &evaluator, &stats)
+        );
+        assert!(estimate.preprocess_time > Duration::ZERO);
+        assert!(estimate.query_latency > Duration::ZERO);
+
+        // larger sets need more InnerBoxes
+        assert!(predicted_inner_boxes_per_segment(&psi_params, 1 << 24) > 1);
+    }
+}
@@ -0,0 +1,471 @@
+#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
+use crate::Label;
+use crate::{ItemLabel, PsiError, PsiParams};
+#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
+use crypto_bigint::{Encoding, U256};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// No. of ItemLabels `Server::setup_from_store` reads from `ItemStore` at once
+pub const ITEM_STORE_BATCH_SIZE: usize = 1 << 20;
+/// Magic at start of server set files stored with `BincodeItemStore`
+pub const SERVER_SET_FILE_MAGIC: [u8; 8] = *b"ULPSI-SS";
+/// Bumped whenever layout of server set file changes
+pub const SERVER_SET_FILE_VERSION: u32 = 1;
+/// magic (8 bytes) || version (u32 LE) || digest of PsiParams (32 bytes) || checksum of ItemLabels (u64 LE)
+const SERVER_SET_HEADER_BYTES: u64 = 52;
+/// Offset of checksum in header, which is followed by length prefix of ItemLabels
+const SERVER_SET_CHECKSUM_OFFSET: u64 = 44;
+
+/// Source of truth of server's ItemLabels. `Server::setup_from_store` reads ItemLabels from the store in batches, thus
+/// the entire dataset is never held in memory at once.
+pub trait ItemStore {
+    /// Returns no. of ItemLabels in store
+    fn len(&self) -> Result<usize, PsiError>;
+
+    fn is_empty(&self) -> Result<bool, PsiError> {
+        Ok(self.len()? == 0)
+    }
+
+    /// Calls `f` with consecutive batches of at most `batch_size` ItemLabels, in order, until all ItemLabels are read
+    /// or `f` returns an error
+    fn for_each_batch(
+        &self,
+        batch_size: usize,
+        f: &mut dyn FnMut(Vec<ItemLabel>) -> Result<(), PsiError>,
+    ) -> Result<(), PsiError>;
+
+    /// Appends `item_labels` to store
+    fn insert(&mut self, item_labels: &[ItemLabel]) -> Result<(), PsiError>;
+}
+
+/// ItemLabels held in memory
+impl ItemStore for Vec<ItemLabel> {
+    fn len(&self) -> Result<usize, PsiError> {
+        Ok(Vec::len(self))
+    }
+
+    fn for_each_batch(
+        &self,
+        batch_size: usize,
+        f: &mut dyn FnMut(Vec<ItemLabel>) -> Result<(), PsiError>,
+    ) -> Result<(), PsiError> {
+        self.chunks(batch_size.max(1))
+            .try_for_each(|chunk| f(chunk.to_vec()))
+    }
+
+    fn insert(&mut self, item_labels: &[ItemLabel]) -> Result<(), PsiError> {
+        self.extend_from_slice(item_labels);
+        Ok(())
+    }
+}
+
+/// Checksum of a single ItemLabel. Checksum of server set is the wrapping sum of checksums of its ItemLabels, thus
+/// ItemLabels are appended without reading the file again.
+fn item_label_checksum(item_label: &ItemLabel) -> u64 {
+    xxh3_64(&bincode::serialize(item_label).expect("ItemLabel is serializable"))
+}
+
+fn checksum_of(item_labels: &[ItemLabel]) -> u64 {
+    item_labels.iter().fold(0, |sum, item_label| {
+        sum.wrapping_add(item_label_checksum(item_label))
+    })
+}
+
+/// ItemLabels stored in bincode serialized `Vec<ItemLabel>` file, ie server_set.bin, prefixed with a header carrying
+/// digest of `PsiParams` and checksum of ItemLabels. ItemLabels are read one at a time and appended without rewriting
+/// the file.
+pub struct BincodeItemStore {
+    path: PathBuf,
+    /// Offset of serialized ItemLabels in file. 0 for files stored without header by earlier versions, which are
+    /// neither checked against `PsiParams` nor checksummed.
+    offset: u64,
+}
+
+impl BincodeItemStore {
+    /// Opens store at `path`. Creates file with no ItemLabels if it doesn't exist. Returns `PsiError::ParamsMismatch`
+    /// if the file was stored with params other than `psi_params`.
+    pub fn open(path: &Path, psi_params: &PsiParams) -> Result<BincodeItemStore, PsiError> {
+        if !path.exists() {
+            let mut file = BufWriter::new(File::create(path)?);
+            file.write_all(&SERVER_SET_FILE_MAGIC)?;
+            file.write_all(&SERVER_SET_FILE_VERSION.to_le_bytes())?;
+            file.write_all(&psi_params.digest())?;
+            file.write_all(&0u64.to_le_bytes())?;
+            bincode::serialize_into(&mut file, &Vec::<ItemLabel>::new())?;
+            file.flush()?;
+        }
+
+        let store = BincodeItemStore {
+            path: path.to_path_buf(),
+            offset: 0,
+        };
+        let mut header = [0u8; SERVER_SET_HEADER_BYTES as usize];
+        let mut file = store.open_file()?;
+        if file.read_exact(&mut header).is_err() || header[..8] != SERVER_SET_FILE_MAGIC {
+            return Ok(store);
+        }
+        let version = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        if version != SERVER_SET_FILE_VERSION {
+            return Err(PsiError::Serialization(format!(
+                "Unsupported server set file version {version}, expected {SERVER_SET_FILE_VERSION}"
+            )));
+        }
+        if header[12..44] != psi_params.digest() {
+            return Err(PsiError::ParamsMismatch(format!(
+                "Server set at {} was stored with different PsiParams",
+                path.display()
+            )));
+        }
+        Ok(BincodeItemStore {
+            offset: SERVER_SET_HEADER_BYTES,
+            ..store
+        })
+    }
+
+    fn open_file(&self) -> Result<File, PsiError> {
+        File::open(&self.path)
+            .map_err(|e| PsiError::Io(format!("Failed to open {}: {e}", self.path.display())))
+    }
+
+    fn has_header(&self) -> bool {
+        self.offset == SERVER_SET_HEADER_BYTES
+    }
+
+    /// Reads checksum in header, if any, and length prefix of serialized vector
+    fn read_checksum_and_len<R: Read + Seek>(
+        &self,
+        reader: &mut R,
+    ) -> Result<(u64, u64), PsiError> {
+        let mut checksum = [0u8; 8];
+        if self.has_header() {
+            reader.seek(SeekFrom::Start(SERVER_SET_CHECKSUM_OFFSET))?;
+            reader.read_exact(&mut checksum)?;
+        }
+        let mut len = [0u8; 8];
+        reader.read_exact(&mut len)?;
+        Ok((u64::from_le_bytes(checksum), u64::from_le_bytes(len)))
+    }
+}
+
+impl ItemStore for BincodeItemStore {
+    fn len(&self) -> Result<usize, PsiError> {
+        Ok(self.read_checksum_and_len(&mut self.open_file()?)?.1 as usize)
+    }
+
+    /// Returns error after the last batch if checksum of ItemLabels doesn't match checksum in header
+    fn for_each_batch(
+        &self,
+        batch_size: usize,
+        f: &mut dyn FnMut(Vec<ItemLabel>) -> Result<(), PsiError>,
+    ) -> Result<(), PsiError> {
+        let mut reader = BufReader::new(self.open_file()?);
+        let (expected_checksum, len) = self.read_checksum_and_len(&mut reader)?;
+        let mut remaining = len as usize;
+        let mut checksum = 0u64;
+        while remaining > 0 {
+            let batch = (0..remaining.min(batch_size.max(1)))
+                .map(|_| bincode::deserialize_from(&mut reader))
+                .collect::<Result<Vec<ItemLabel>, _>>()?;
+            remaining -= batch.len();
+            if self.has_header() {
+                checksum = checksum.wrapping_add(checksum_of(&batch));
+            }
+            f(batch)?;
+        }
+
+        if checksum != expected_checksum {
+            return Err(PsiError::Serialization(format!(
+                "Checksum of server set at {} doesn't match",
+                self.path.display()
+            )));
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, item_labels: &[ItemLabel]) -> Result<(), PsiError> {
+        let mut file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let (checksum, len) = self.read_checksum_and_len(&mut file)?;
+
+        file.seek(SeekFrom::End(0))?;
+        let mut writer = BufWriter::new(&mut file);
+        for item_label in item_labels {
+            bincode::serialize_into(&mut writer, item_label)?;
+        }
+        writer.flush()?;
+        drop(writer);
+
+        // update checksum and length prefix, which follows it, only after ItemLabels are written
+        let len = (len + item_labels.len() as u64).to_le_bytes();
+        if self.has_header() {
+            let checksum = checksum.wrapping_add(checksum_of(item_labels));
+            file.seek(SeekFrom::Start(SERVER_SET_CHECKSUM_OFFSET))?;
+            file.write_all(&[checksum.to_le_bytes(), len].concat())?;
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&len)?;
+        }
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+/// Returns ItemLabel with item read from 32 little endian bytes
+#[cfg(any(feature = "sqlite", feature = "rocksdb"))]
+fn item_label_from_bytes(item: &[u8], label: Vec<u8>) -> Result<ItemLabel, PsiError> {
+    let item: [u8; 32] = item.try_into().map_err(|_| {
+        PsiError::Serialization(format!("Expected item of 32 bytes, found {}", item.len()))
+    })?;
+    Ok(ItemLabel::new(U256::from_le_bytes(item), Label::new(label)))
+}
+
+/// ItemLabels stored in SQLite table `item_labels(id INTEGER PRIMARY KEY, item BLOB, label BLOB)`. Items are stored as
+/// 32 little endian bytes. ItemLabels are read in order of `id`.
+#[cfg(feature = "sqlite")]
+pub struct SqliteItemStore {
+    connection: rusqlite::Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for PsiError {
+    fn from(value: rusqlite::Error) -> Self {
+        PsiError::Io(format!("SQLite error: {value}"))
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteItemStore {
+    /// Opens SQLite database at `path` and creates `item_labels` table if it doesn't exist
+    pub fn open(path: &Path) -> Result<SqliteItemStore, PsiError> {
+        let connection = rusqlite::Connection::open(path)?;
+        connection.execute(
+            "CREATE TABLE IF NOT EXISTS item_labels (
+                id INTEGER PRIMARY KEY,
+                item BLOB NOT NULL,
+                label BLOB NOT NULL
+            )",
+            (),
+        )?;
+        Ok(SqliteItemStore { connection })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl ItemStore for SqliteItemStore {
+    fn len(&self) -> Result<usize, PsiError> {
+        let len: i64 =
+            self.connection
+                .query_row("SELECT COUNT(*) FROM item_labels", (), |row| row.get(0))?;
+        Ok(len as usize)
+    }
+
+    fn for_each_batch(
+        &self,
+        batch_size: usize,
+        f: &mut dyn FnMut(Vec<ItemLabel>) -> Result<(), PsiError>,
+    ) -> Result<(), PsiError> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT item, label FROM item_labels ORDER BY id")?;
+        let mut rows = statement.query(())?;
+        let mut batch = vec![];
+        while let Some(row) = rows.next()? {
+            let item: Vec<u8> = row.get(0)?;
+            batch.push(item_label_from_bytes(&item, row.get(1)?)?);
+            if batch.len() >= batch_size {
+                f(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            f(batch)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, item_labels: &[ItemLabel]) -> Result<(), PsiError> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut statement =
+                transaction.prepare("INSERT INTO item_labels (item, label) VALUES (?1, ?2)")?;
+            for item_label in item_labels {
+                statement.execute((
+                    item_label.item().to_le_bytes().as_slice(),
+                    item_label.label().as_bytes(),
+                ))?;
+            }
+        }
+        transaction.commit()?;
+        Ok(())
+    }
+}
+
+/// ItemLabels stored in RocksDB with item (32 little endian bytes) as key and label as value. ItemLabels are read in
+/// order of keys, and inserting an existing item replaces its label.
+#[cfg(feature = "rocksdb")]
+pub struct RocksDbItemStore {
+    db: rocksdb::DB,
+}
+
+#[cfg(feature = "rocksdb")]
+impl From<rocksdb::Error> for PsiError {
+    fn from(value: rocksdb::Error) -> Self {
+        PsiError::Io(format!("RocksDB error: {value}"))
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksDbItemStore {
+    /// Opens RocksDB database at `path`. Creates database if it doesn't exist.
+    pub fn open(path: &Path) -> Result<RocksDbItemStore, PsiError> {
+        let mut options = rocksdb::Options::default();
+        options.create_if_missing(true);
+        Ok(RocksDbItemStore {
+            db: rocksdb::DB::open(&options, path)?,
+        })
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl ItemStore for RocksDbItemStore {
+    /// RocksDB only estimates no. of keys, thus all keys are counted
+    fn len(&self) -> Result<usize, PsiError> {
+        let mut len = 0;
+        for entry in self.db.iterator(rocksdb::IteratorMode::Start) {
+            entry?;
+            len += 1;
+        }
+        Ok(len)
+    }
+
+    fn for_each_batch(
+        &self,
+        batch_size: usize,
+        f: &mut dyn FnMut(Vec<ItemLabel>) -> Result<(), PsiError>,
+    ) -> Result<(), PsiError> {
+        let mut batch = vec![];
+        for entry in self.db.iterator(rocksdb::IteratorMode::Start) {
+            let (item, label) = entry?;
+            batch.push(item_label_from_bytes(&item, label.to_vec())?);
+            if batch.len() >= batch_size {
+                f(std::mem::take(&mut batch))?;
+            }
+        }
+        if !batch.is_empty() {
+            f(batch)?;
+        }
+        Ok(())
+    }
+
+    fn insert(&mut self, item_labels: &[ItemLabel]) -> Result<(), PsiError> {
+        let mut write_batch = rocksdb::WriteBatch::default();
+        for item_label in item_labels {
+            write_batch.put(
+                item_label.item().to_le_bytes(),
+                item_label.label().as_bytes(),
+            );
+        }
+        self.db.write(write_batch)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rand::thread_rng;
+
+    use crate::random_u256;
+
+    use super::*;
+
+    #[test]
+    fn bincode_item_store_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = (0..10)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+
+        let path =
+            std::env::temp_dir().join(format!("ulpsi_item_store_{}.bin", std::process::id()));
+        // server_set.bin stored without header by earlier versions
+        std::fs::write(
+            &path,
+            bincode::serialize(&item_labels[..4].to_vec()).unwrap(),
+        )
+        .unwrap();
+
+        let mut store = BincodeItemStore::open(&path, &psi_params).unwrap();
+        store.insert(&item_labels[4..]).unwrap();
+        assert_eq!(store.len().unwrap(), 10);
+
+        let mut batches = vec![];
+        store
+            .for_each_batch(3, &mut |batch| {
+                batches.push(batch);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            batches.iter().map(|b| b.len()).collect_vec(),
+            vec![3, 3, 3, 1]
+        );
+        assert_eq!(batches.concat(), item_labels);
+
+        let stored: Vec<ItemLabel> = bincode::deserialize(&std::fs::read(&path).unwrap()).unwrap();
+        assert_eq!(stored, item_labels);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn bincode_item_store_checks_header() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = (0..10)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+
+        let path = std::env::temp_dir().join(format!(
+            "ulpsi_item_store_header_{}.bin",
+            std::process::id()
+        ));
+        let mut store = BincodeItemStore::open(&path, &psi_params).unwrap();
+        store.insert(&item_labels[..4]).unwrap();
+        store.insert(&item_labels[4..]).unwrap();
+
+        let read_all = |store: &BincodeItemStore| {
+            let mut read = vec![];
+            store
+                .for_each_batch(3, &mut |batch| {
+                    read.extend(batch);
+                    Ok(())
+                })
+                .map(|_| read)
+        };
+        let store = BincodeItemStore::open(&path, &psi_params).unwrap();
+        assert_eq!(store.len().unwrap(), 10);
+        assert_eq!(read_all(&store).unwrap(), item_labels);
+
+        // store with other params is rejected
+        let other_params = psi_params
+            .clone()
+            .with_hash_seed(psi_params.hash_seed() + 1);
+        assert!(matches!(
+            BincodeItemStore::open(&path, &other_params),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+
+        // modified ItemLabel fails checksum
+        let mut bytes = std::fs::read(&path).unwrap();
+        *bytes.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &bytes).unwrap();
+        assert!(matches!(read_all(&store), Err(PsiError::Serialization(_))));
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use crate::{ClientId, ClientKey};
+use bfv::EvaluationKey;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Default no. of client evaluation keys held in memory by the server
+pub const DEFAULT_KEY_CACHE_CAPACITY: usize = 64;
+
+/// Evaluation keys uploaded by clients, keyed by `ClientId` and `ClientKey` of the uploader.
+///
+/// `ClientId` is chosen by client and sent in the clear, thus anyone who learns it could otherwise replace the
+/// client's key with their own. Keys are only returned to clients with the same `ClientKey` (ie API token, or IP
+/// address if server does not require authentication) as the client that uploaded them.
+///
+/// Holds at most `capacity` keys. Once full, key uploaded earliest is evicted and its client is asked to upload the
+/// key again with its next query.
+pub struct EvaluationKeyCache {
+    capacity: usize,
+    inner: Mutex<KeyCacheInner>,
+}
+
+/// `ClientKey` of uploader, `None` for in-memory connections, and id of the client
+type CacheKey = (Option<ClientKey>, ClientId);
+
+struct KeyCacheInner {
+    keys: HashMap<CacheKey, Arc<EvaluationKey>>,
+    /// Keys in order of upload
+    order: VecDeque<CacheKey>,
+}
+
+impl EvaluationKeyCache {
+    pub fn new(capacity: usize) -> EvaluationKeyCache {
+        assert!(capacity > 0);
+        EvaluationKeyCache {
+            capacity,
+            inner: Mutex::new(KeyCacheInner {
+                keys: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+
+    /// Stores `ek` for `client_id` uploaded by client with `owner`, replacing any existing key of the client uploaded
+    /// by the same owner
+    pub fn insert(&self, owner: Option<ClientKey>, client_id: ClientId, ek: Arc<EvaluationKey>) {
+        let key = (owner, client_id);
+        let mut inner = self.inner.lock().unwrap();
+        if inner.keys.insert(key, ek).is_some() {
+            inner.order.retain(|k| *k != key);
+        }
+        inner.order.push_back(key);
+
+        while inner.order.len() > self.capacity {
+            let evicted = inner.order.pop_front().unwrap();
+            inner.keys.remove(&evicted);
+        }
+    }
+
+    /// Returns key of `client_id` if it was uploaded by client with `owner`
+    pub fn get(
+        &self,
+        owner: Option<ClientKey>,
+        client_id: &ClientId,
+    ) -> Option<Arc<EvaluationKey>> {
+        self.inner
+            .lock()
+            .unwrap()
+            .keys
+            .get(&(owner, *client_id))
+            .cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().keys.len()
+    }
+}
+
+impl Default for EvaluationKeyCache {
+    fn default() -> Self {
+        EvaluationKeyCache::new(DEFAULT_KEY_CACHE_CAPACITY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use std::net::Ipv4Addr;
+
+    use crate::{generate_evaluation_key, utils::bfv_setup_test, PsiParams, CLIENT_ID_BYTES};
+
+    use super::*;
+
+    #[test]
+    fn key_cache_evicts_oldest() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+
+        let cache = EvaluationKeyCache::new(2);
+        let ids = (0..3)
+            .map(|_| ClientId::random(&mut rng))
+            .collect::<Vec<_>>();
+
+        let mut ek = || {
+            Arc::new(generate_evaluation_key(
+                &PsiParams::default(),
+                &evaluator,
+                &sk,
+                &mut rng,
+            ))
+        };
+        cache.insert(None, ids[0], ek());
+        cache.insert(None, ids[1], ek());
+        // re-upload moves client to the back
+        cache.insert(None, ids[0], ek());
+        cache.insert(None, ids[2], ek());
+
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(None, &ids[0]).is_some());
+        assert!(cache.get(None, &ids[1]).is_none());
+        assert!(cache.get(None, &ids[2]).is_some());
+    }
+
+    #[test]
+    fn key_cache_binds_keys_to_uploader() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+        let mut ek = || {
+            Arc::new(generate_evaluation_key(
+                &PsiParams::default(),
+                &evaluator,
+                &sk,
+                &mut rng,
+            ))
+        };
+
+        let cache = EvaluationKeyCache::default();
+        let client_id = ClientId([1; CLIENT_ID_BYTES]);
+        let owner = ClientKey::Ip(Ipv4Addr::new(10, 0, 0, 1).into());
+        let other = ClientKey::Ip(Ipv4Addr::new(10, 0, 0, 2).into());
+        let owner_ek = ek();
+        cache.insert(Some(owner), client_id, owner_ek.clone());
+
+        // other client can't use the key, nor replace it, under the same client id
+        assert!(cache.get(Some(other), &client_id).is_none());
+        assert!(cache.get(None, &client_id).is_none());
+        cache.insert(Some(other), client_id, ek());
+        assert!(Arc::ptr_eq(
+            &cache.get(Some(owner), &client_id).unwrap(),
+            &owner_ek
+        ));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{BusyReason, ProtocolError, PsiError, TokenId};
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+/// Delay after which client may retry a query rejected with `BusyReason::Overloaded`
+pub const OVERLOADED_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// Rate limits of clients that have not queried for long are forgotten once this many clients are tracked
+const MAX_TRACKED_CLIENTS: usize = 1 << 16;
+
+/// Identifies a client for rate limiting. Authenticated clients are limited per API token, others per IP address.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ClientKey {
+    Token(TokenId),
+    Ip(IpAddr),
+}
+
+impl ClientKey {
+    /// Returns key of client authenticated with `token`, or of client at `peer` if server does not require
+    /// authentication. Returns `None` if neither is known, for ex. for in-memory connections, which aren't rate
+    /// limited.
+    pub fn new(token: Option<&TokenId>, peer: Option<IpAddr>) -> Option<ClientKey> {
+        match (token, peer) {
+            (Some(token), _) => Some(ClientKey::Token(*token)),
+            (None, Some(ip)) => Some(ClientKey::Ip(ip)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Token bucket holding upto `max_per_minute` queries, refilled at `max_per_minute` queries a minute
+struct Bucket {
+    queries: f64,
+    refilled_at: Instant,
+}
+
+impl Bucket {
+    /// Returns no. of queries in the bucket at `now`
+    fn queries_at(&self, now: Instant, per_second: f64, capacity: f64) -> f64 {
+        let refill = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64()
+            * per_second;
+        (self.queries + refill).min(capacity)
+    }
+}
+
+/// Admission control of queries, thus one aggressive client can't starve the rayon pool queries are evaluated on.
+///
+/// At most `max_concurrent` queries are evaluated at once. Queries beyond that wait for a permit, unless `max_queued`
+/// queries are already waiting, in which case they are rejected with `BusyReason::Overloaded`. Each client (see
+/// `ClientKey`) may also be limited to `max_per_minute` queries a minute, beyond which its queries are rejected with
+/// `BusyReason::RateLimited` until it is under the limit again. Bursts of upto `max_per_minute` queries are allowed.
+pub struct QueryLimiter {
+    max_concurrent: usize,
+    permits: Semaphore,
+    /// Queries wait for a permit without bound if not set
+    max_queued: Option<usize>,
+    queued: AtomicUsize,
+    /// Clients aren't rate limited if not set
+    max_per_minute: Option<u32>,
+    buckets: Mutex<HashMap<ClientKey, Bucket>>,
+}
+
+impl QueryLimiter {
+    pub fn new(max_concurrent: usize) -> QueryLimiter {
+        assert!(max_concurrent > 0);
+        QueryLimiter {
+            max_concurrent,
+            permits: Semaphore::new(max_concurrent),
+            max_queued: None,
+            queued: AtomicUsize::new(0),
+            max_per_minute: None,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Rejects queries once `max_queued` queries are waiting for a permit
+    pub fn with_max_queued(mut self, max_queued: usize) -> QueryLimiter {
+        self.max_queued = Some(max_queued);
+        self
+    }
+
+    /// Limits each client to `max_per_minute` queries a minute
+    pub fn with_max_per_minute(mut self, max_per_minute: u32) -> QueryLimiter {
+        assert!(max_per_minute > 0);
+        self.max_per_minute = Some(max_per_minute);
+        self
+    }
+
+    pub fn max_concurrent(&self) -> usize {
+        self.max_concurrent
+    }
+
+    /// No. of queries waiting for a permit
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Counts a query of `client` towards its rate limit. Returns `ProtocolError::Busy` without counting the query if
+    /// client exceeded its limit. Queries of unknown clients are never rate limited.
+    pub fn check_rate(&self, client: Option<&ClientKey>) -> Result<(), PsiError> {
+        self.check_rate_at(client, Instant::now())
+    }
+
+    fn check_rate_at(&self, client: Option<&ClientKey>, now: Instant) -> Result<(), PsiError> {
+        let (Some(max_per_minute), Some(client)) = (self.max_per_minute, client) else {
+            return Ok(());
+        };
+        let capacity = max_per_minute as f64;
+        let per_second = capacity / 60.0;
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS && !buckets.contains_key(client) {
+            // full buckets are indistinguishable from new ones
+            buckets.retain(|_, bucket| bucket.queries_at(now, per_second, capacity) < capacity);
+        }
+        let bucket = buckets.entry(*client).or_insert(Bucket {
+            queries: capacity,
+            refilled_at: now,
+        });
+        bucket.queries = bucket.queries_at(now, per_second, capacity);
+        bucket.refilled_at = now;
+
+        if bucket.queries < 1.0 {
+            return Err(ProtocolError::Busy {
+                reason: BusyReason::RateLimited,
+                retry_after: Duration::from_secs_f64((1.0 - bucket.queries) / per_second),
+            }
+            .into());
+        }
+        bucket.queries -= 1.0;
+        Ok(())
+    }
+
+    /// Waits until less than `max_concurrent` queries are being evaluated. Returns `ProtocolError::Busy` without
+    /// waiting if `max_queued` queries are already waiting.
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, PsiError> {
+        if let Ok(permit) = self.permits.try_acquire() {
+            return Ok(permit);
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::SeqCst);
+        let _dequeue = Dequeue(&self.queued);
+        if matches!(self.max_queued, Some(max_queued) if queued >= max_queued) {
+            return Err(ProtocolError::Busy {
+                reason: BusyReason::Overloaded,
+                retry_after: OVERLOADED_RETRY_AFTER,
+            }
+            .into());
+        }
+        self.permits
+            .acquire()
+            .await
+            .map_err(|e| PsiError::Io(format!("Query permits closed: {e}")))
+    }
+}
+
+/// Removes query from the queue once it gets a permit, is rejected or is cancelled
+struct Dequeue<'a>(&'a AtomicUsize);
+
+impl Drop for Dequeue<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_busy(result: Result<impl Sized, PsiError>, expected: BusyReason) -> bool {
+        matches!(
+            result,
+            Err(PsiError::Protocol(ProtocolError::Busy { reason, .. })) if reason == expected
+        )
+    }
+
+    #[test]
+    fn rate_limit_works() {
+        let limiter = QueryLimiter::new(1).with_max_per_minute(2);
+        let client = ClientKey::Ip("127.0.0.1".parse().unwrap());
+        let other = ClientKey::Ip("127.0.0.2".parse().unwrap());
+        let now = Instant::now();
+
+        // burst of `max_per_minute` queries is allowed
+        assert!(limiter.check_rate_at(Some(&client), now).is_ok());
+        assert!(limiter.check_rate_at(Some(&client), now).is_ok());
+        match limiter.check_rate_at(Some(&client), now) {
+            Err(PsiError::Protocol(ProtocolError::Busy {
+                reason: BusyReason::RateLimited,
+                retry_after,
+            })) => assert!((retry_after.as_secs_f64() - 30.0).abs() < 0.01),
+            result => panic!("Expected rate limit but got {result:?}"),
+        }
+
+        // other clients aren't affected
+        assert!(limiter.check_rate_at(Some(&other), now).is_ok());
+        assert!(limiter.check_rate_at(None, now).is_ok());
+
+        // one query is refilled every 30 s
+        let later = now + Duration::from_secs(31);
+        assert!(limiter.check_rate_at(Some(&client), later).is_ok());
+        assert!(is_busy(
+            limiter.check_rate_at(Some(&client), later),
+            BusyReason::RateLimited
+        ));
+    }
+
+    #[tokio::test]
+    async fn queue_limit_works() {
+        let limiter = QueryLimiter::new(1).with_max_queued(1);
+        let permit = limiter.acquire().await.unwrap();
+
+        // first query waits for the permit, second is rejected
+        let waiting = limiter.acquire();
+        tokio::pin!(waiting);
+        assert!(poll_once(waiting.as_mut()).await.is_none());
+        assert_eq!(limiter.queued(), 1);
+        assert!(is_busy(limiter.acquire().await, BusyReason::Overloaded));
+
+        drop(permit);
+        assert!(waiting.await.is_ok());
+        assert_eq!(limiter.queued(), 0);
+    }
+
+    /// Polls `future` once, returning its output if it is ready
+    async fn poll_once<F: std::future::Future + Unpin>(future: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            output = future => Some(output),
+            _ = std::future::ready(()) => None,
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::{Db, SegmentStageTimes};
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Upper bounds (in seconds) of buckets of latency histograms
+pub const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0,
+];
+
+/// Stage of processing a query with its own latency histogram
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QueryStage {
+    /// Deserializing query received from client
+    Deserialize,
+    /// Calculating PS powers of a single segment
+    Powers,
+    /// Evaluating polynomials of a single segment
+    Evaluation,
+    /// Serializing response
+    Serialize,
+}
+
+impl QueryStage {
+    const ALL: [QueryStage; 4] = [
+        QueryStage::Deserialize,
+        QueryStage::Powers,
+        QueryStage::Evaluation,
+        QueryStage::Serialize,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            QueryStage::Deserialize => "deserialize",
+            QueryStage::Powers => "powers",
+            QueryStage::Evaluation => "evaluation",
+            QueryStage::Serialize => "serialize",
+        }
+    }
+}
+
+/// Cumulative histogram of durations with `LATENCY_BUCKETS`
+struct Histogram {
+    /// No. of observations <= each bucket's upper bound
+    buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            count: AtomicU64::new(0),
+            sum_us: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        buckets_with_bounds(&self.buckets)
+            .filter(|(bound, _)| seconds <= *bound)
+            .for_each(|(_, bucket)| {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            });
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+fn buckets_with_bounds(
+    buckets: &[AtomicU64; LATENCY_BUCKETS.len()],
+) -> impl Iterator<Item = (f64, &AtomicU64)> {
+    LATENCY_BUCKETS.iter().copied().zip(buckets.iter())
+}
+
+/// Server metrics rendered in Prometheus text exposition format by `ServerMetrics::render`
+pub struct ServerMetrics {
+    queries: AtomicU64,
+    failed_queries: AtomicU64,
+    response_bytes: AtomicU64,
+    /// One histogram per `QueryStage`, in order of `QueryStage::ALL`
+    stages: [Histogram; QueryStage::ALL.len()],
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        ServerMetrics {
+            queries: AtomicU64::new(0),
+            failed_queries: AtomicU64::new(0),
+            response_bytes: AtomicU64::new(0),
+            stages: std::array::from_fn(|_| Histogram::new()),
+        }
+    }
+}
+
+impl ServerMetrics {
+    pub fn record_query(&self, success: bool) {
+        self.queries.fetch_add(1, Ordering::Relaxed);
+        if !success {
+            self.failed_queries.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_response_bytes(&self, bytes: usize) {
+        self.response_bytes
+            .fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, stage: QueryStage, duration: Duration) {
+        self.stages[stage as usize].observe(duration);
+    }
+
+    pub fn record_segment(&self, times: &SegmentStageTimes) {
+        self.observe(QueryStage::Powers, times.powers);
+        self.observe(QueryStage::Evaluation, times.evaluation);
+    }
+
+    pub fn queries(&self) -> u64 {
+        self.queries.load(Ordering::Relaxed)
+    }
+
+    /// Returns metrics, along with occupancy of `db`, in Prometheus text exposition format
+    pub fn render(&self, db: &Db) -> String {
+        let mut out = String::new();
+        let mut counter = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} counter");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        counter(
+            "psi_queries_total",
+            "Queries processed",
+            self.queries.load(Ordering::Relaxed),
+        );
+        counter(
+            "psi_failed_queries_total",
+            "Queries that failed",
+            self.failed_queries.load(Ordering::Relaxed),
+        );
+        counter(
+            "psi_response_bytes_total",
+            "Bytes of serialized responses",
+            self.response_bytes.load(Ordering::Relaxed),
+        );
+
+        let name = "psi_query_stage_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {name} Latency of each stage of query processing. Powers and evaluation are per segment."
+        );
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for stage in QueryStage::ALL {
+            let histogram = &self.stages[stage as usize];
+            let label = stage.label();
+            for (bound, bucket) in buckets_with_bounds(&histogram.buckets) {
+                let _ = writeln!(
+                    out,
+                    "{name}_bucket{{stage=\"{label}\",le=\"{bound}\"}} {}",
+                    bucket.load(Ordering::Relaxed)
+                );
+            }
+            let count = histogram.count.load(Ordering::Relaxed);
+            let _ = writeln!(
+                out,
+                "{name}_bucket{{stage=\"{label}\",le=\"+Inf\"}} {count}"
+            );
+            let _ = writeln!(
+                out,
+                "{name}_sum{{stage=\"{label}\"}} {}",
+                histogram.sum_us.load(Ordering::Relaxed) as f64 / 1e6
+            );
+            let _ = writeln!(out, "{name}_count{{stage=\"{label}\"}} {count}");
+        }
+
+        let (occupied, capacity) = db.occupancy();
+        let mut gauge = |name: &str, help: &str, value: u64| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {value}");
+        };
+        gauge("psi_db_items", "Items stored in DB", occupied);
+        gauge(
+            "psi_db_capacity",
+            "Items DB can store without adding InnerBoxes",
+            capacity,
+        );
+        gauge(
+            "psi_db_inner_boxes",
+            "InnerBoxes across all BigBoxes",
+            db.inner_boxes_count() as u64,
+        );
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PsiParams;
+
+    use super::*;
+
+    #[test]
+    fn server_metrics_render() {
+        let metrics = ServerMetrics::default();
+        metrics.record_query(true);
+        metrics.record_query(false);
+        metrics.record_response_bytes(1000);
+        metrics.observe(QueryStage::Deserialize, Duration::from_millis(20));
+        metrics.record_segment(&SegmentStageTimes {
+            powers: Duration::from_millis(3),
+            evaluation: Duration::from_secs(2),
+        });
+
+        let db = Db::new(&PsiParams::default());
+        let rendered = metrics.render(&db);
+        assert!(rendered.contains("psi_queries_total 2\n"));
+        assert!(rendered.contains("psi_failed_queries_total 1\n"));
+        assert!(rendered.contains("psi_response_bytes_total 1000\n"));
+        assert!(rendered
+            .contains("psi_query_stage_seconds_bucket{stage=\"deserialize\",le=\"0.01\"} 0\n"));
+        assert!(rendered
+            .contains("psi_query_stage_seconds_bucket{stage=\"deserialize\",le=\"0.05\"} 1\n"));
+        assert!(rendered
+            .contains("psi_query_stage_seconds_bucket{stage=\"evaluation\",le=\"2.5\"} 1\n"));
+        assert!(rendered.contains("psi_query_stage_seconds_count{stage=\"serialize\"} 0\n"));
+        assert!(rendered.contains("psi_db_items 0\n"));
+    }
+}
@@ -1,10 +1,11 @@
 use crate::{
-    client::{HashTableQueryCts, Query},
+    client::{Query, QueryBatch},
     hash::Cuckoo,
-    poly_interpolate::newton_interpolate,
-    server::paterson_stockmeyer::ps_evaluate_poly,
-    utils::{calculate_ps_powers_with_dag, construct_dag, gen_bfv_params, Node},
-    PsiParams,
+    oprf::{OprfKey, OprfRequest, OprfResponse},
+    poly_interpolate::{interpolate, newton_interpolate_parallel, poly_from_roots},
+    server::paterson_stockmeyer::ps_evaluate_encoded_poly,
+    utils::{calculate_ps_powers_with_dag, gen_bfv_params, Node},
+    InterpolationMethod, PsiError, PsiMode, PsiParams, QueryMetadata,
 };
 use bfv::{Ciphertext, EvaluationKey, Evaluator, Plaintext, Representation};
 use crypto_bigint::{Encoding, U256};
@@ -15,11 +16,55 @@ use serde::{de::Visitor, Deserialize, Serialize};
 use std::{
     collections::{HashMap, HashSet},
     ops::Deref,
+    path::Path,
+    sync::{Arc, Mutex, RwLock},
+    time::Duration,
 };
+use tracing::warn;
 
+pub use archive::*;
+pub use auth::*;
+pub use bench::*;
+pub use cancellation::*;
+pub use circuit_privacy::*;
+pub use coefficients::*;
 pub use db::*;
+pub use delta::*;
+pub use encryption::*;
+pub use estimate::*;
+pub use item_store::*;
+pub use key_cache::*;
+pub use limiter::*;
+pub use metrics::*;
+pub use powers_cache::*;
+pub use progress::*;
+pub use refresh::*;
+pub use storage::*;
+pub use tenants::*;
+pub use thread_pools::*;
+pub use validator::*;
+pub mod archive;
+pub mod auth;
+pub mod bench;
+pub mod cancellation;
+pub mod circuit_privacy;
+pub mod coefficients;
 pub mod db;
+pub mod delta;
+pub mod encryption;
+pub mod estimate;
+pub mod item_store;
+pub mod key_cache;
+pub mod limiter;
+pub mod metrics;
 pub mod paterson_stockmeyer;
+pub mod powers_cache;
+pub mod progress;
+pub mod refresh;
+pub mod storage;
+pub mod tenants;
+pub mod thread_pools;
+pub mod validator;
 
 /// No. of rows on a hash table
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
@@ -39,12 +84,18 @@ pub struct PsiPlaintext {
     pub(crate) bfv_pt_bits: u32,
     pub(crate) bfv_pt_bytes: u32,
     pub(crate) bfv_pt: u32,
+    /// Max. size of label in bytes. Defaults to item size.
+    pub(crate) label_bytes: u32,
+    /// Bytes of item checksum appended to each label (see `label_checksum`). 0 disables checksums.
+    #[serde(default)]
+    pub(crate) label_checksum_bytes: u32,
 }
 
 impl PsiPlaintext {
     pub fn new(psi_pt_bits: u32, bfv_pt_bits: u32, bfv_pt: u32) -> PsiPlaintext {
-        assert!(bfv_pt_bits.is_power_of_two() && bfv_pt_bits >= 8);
-        assert!(psi_pt_bits.is_power_of_two() && psi_pt_bits >= 8);
+        // chunks are read as u32 and items are U256
+        assert!(bfv_pt_bits.is_power_of_two() && (8..=32).contains(&bfv_pt_bits));
+        assert!(psi_pt_bits.is_power_of_two() && (8..=256).contains(&psi_pt_bits));
 
         PsiPlaintext {
             psi_pt_bits,
@@ -52,9 +103,18 @@ impl PsiPlaintext {
             bfv_pt_bits,
             bfv_pt_bytes: bfv_pt_bits / 8,
             bfv_pt,
+            label_bytes: psi_pt_bits / 8,
+            label_checksum_bytes: 0,
         }
     }
 
+    /// Sets max. label size independent of item size
+    pub fn with_label_bytes(mut self, label_bytes: u32) -> PsiPlaintext {
+        assert!(label_bytes > 0);
+        self.label_bytes = label_bytes;
+        self
+    }
+
     pub fn slots_required(&self) -> u32 {
         // both are power of 2
         self.psi_pt_bytes / self.bfv_pt_bytes
@@ -63,12 +123,42 @@ impl PsiPlaintext {
     pub fn bytes_per_chunk(&self) -> u32 {
         self.bfv_pt_bytes
     }
+
+    /// Appends checksum of `label_checksum_bytes` bytes to each label
+    pub fn with_label_checksum_bytes(mut self, label_checksum_bytes: u32) -> PsiPlaintext {
+        assert!(label_checksum_bytes <= MAX_LABEL_CHECKSUM_BYTES);
+        self.label_checksum_bytes = label_checksum_bytes;
+        self
+    }
+
+    /// Size of label as stored by server, ie label padded to `label_bytes` followed by its checksum
+    pub fn encoded_label_bytes(&self) -> u32 {
+        self.label_bytes + self.label_checksum_bytes
+    }
+
+    /// No. of chunks label is split into
+    pub fn label_chunks(&self) -> u32 {
+        (self.encoded_label_bytes() + self.bfv_pt_bytes - 1) / self.bfv_pt_bytes
+    }
+
+    /// Each real row interpolates one label chunk for every item chunk. If label has more chunks than item, label
+    /// chunks are split into multiple parts, each interpolated separately. Returns no. of parts.
+    pub fn label_parts(&self) -> u32 {
+        (self.label_chunks() + self.slots_required() - 1) / self.slots_required()
+    }
 }
 
 /// No. of slots in a single BFV ciphertext. Equivalent to degree of ciphertext.
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
 pub struct CiphertextSlots(pub(crate) u32);
 
+impl CiphertextSlots {
+    /// SIMD encoding packs one value into each coefficient, thus a ciphertext of `bfv_degree` has as many slots
+    pub fn for_degree(bfv_degree: usize) -> CiphertextSlots {
+        CiphertextSlots(bfv_degree as u32)
+    }
+}
+
 impl Deref for CiphertextSlots {
     type Target = u32;
     fn deref(&self) -> &Self::Target {
@@ -89,43 +179,132 @@ impl EvalPolyDegree {
     }
 }
 
-/// Warning: We assume that bits in both label and item are equal.
+impl Deref for EvalPolyDegree {
+    type Target = u32;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Label of arbitrary length. Labels shorter than `PsiPlaintext::label_bytes` are padded with zeros.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Label(pub(crate) Vec<u8>);
+
+impl Label {
+    pub fn new(bytes: Vec<u8>) -> Label {
+        Label(bytes)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns label with trailing zero bytes removed. Useful to compare labels shorter than max. label size with
+    /// labels returned in response, which are always padded.
+    pub fn trim_padding(&self) -> Label {
+        let len = self.0.iter().rposition(|b| *b != 0).map_or(0, |i| i + 1);
+        Label(self.0[..len].to_vec())
+    }
+}
+
+/// Max. size of label checksum in bytes
+pub const MAX_LABEL_CHECKSUM_BYTES: u32 = 32;
+
+/// Returns first `checksum_bytes` bytes of BLAKE3 hash of `item` keyed with `hash_seed`. Server appends checksum of item
+/// to its label, thus client tells the label of its item apart from garbage labels returned by other InnerBoxes at the
+/// same row. Garbage label passes the check with probability 2^-(8 * `checksum_bytes`).
+pub fn label_checksum(item: &U256, checksum_bytes: u32, hash_seed: u64) -> Vec<u8> {
+    let key = blake3::derive_key("ulpsi label checksum", &hash_seed.to_le_bytes());
+    blake3::keyed_hash(&key, &item.to_le_bytes()).as_bytes()[..checksum_bytes as usize].to_vec()
+}
+
+impl From<U256> for Label {
+    fn from(value: U256) -> Self {
+        Label(value.to_le_bytes().to_vec())
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct ItemLabel {
     item: U256,
-    label: U256,
+    label: Label,
+    /// Seconds since unix epoch after which item is removed by `Db::purge_expired`. Never expires if not set.
+    expires_at: Option<u64>,
 }
 impl ItemLabel {
-    pub fn new(item: U256, label: U256) -> ItemLabel {
-        ItemLabel { item, label }
+    pub fn new(item: U256, label: impl Into<Label>) -> ItemLabel {
+        ItemLabel {
+            item,
+            label: label.into(),
+            expires_at: None,
+        }
+    }
+
+    /// Sets expiry of ItemLabel to `expires_at`, in seconds since unix epoch. Expiry is kept by db, but isn't stored
+    /// in server set files.
+    pub fn with_expiry(mut self, expires_at: u64) -> ItemLabel {
+        self.expires_at = Some(expires_at);
+        self
     }
 
     pub fn item(&self) -> &U256 {
         &self.item
     }
 
-    pub fn label(&self) -> &U256 {
+    pub fn label(&self) -> &Label {
         &self.label
     }
 
-    /// `item` is greater
+    pub fn expires_at(&self) -> Option<u64> {
+        self.expires_at
+    }
+
+    /// Returns ItemLabel with label padded to `PsiPlaintext::label_bytes` and followed by checksum of item. Returns
+    /// `None` if label checksums are disabled.
+    pub(crate) fn with_label_checksum(&self, psi_params: &PsiParams) -> Option<ItemLabel> {
+        let psi_pt = &psi_params.psi_pt;
+        if psi_pt.label_checksum_bytes == 0 {
+            return None;
+        }
+
+        let mut label = self.label.0.clone();
+        label.resize(psi_pt.label_bytes as usize, 0);
+        label.extend(label_checksum(
+            &self.item,
+            psi_pt.label_checksum_bytes,
+            psi_params.hash_seed,
+        ));
+        Some(ItemLabel {
+            item: self.item,
+            label: Label(label),
+            expires_at: self.expires_at,
+        })
+    }
+
+    /// Returns bytes of `item` chunk at `chunk_index`
     ///
     /// TODO: Switch this to an iterator
-    pub fn get_chunk_at_index(
-        &self,
-        chunk_index: u32,
-        psi_pt: &PsiPlaintext,
-    ) -> (Vec<u8>, Vec<u8>) {
+    pub fn item_chunk_at_index(&self, chunk_index: u32, psi_pt: &PsiPlaintext) -> Vec<u8> {
         let bytes_per_chunk = psi_pt.bytes_per_chunk();
         let bytes_to_skip = (chunk_index * bytes_per_chunk) as usize;
 
-        let item_chunk_bytes = self.item().to_le_bytes()
-            [bytes_to_skip..bytes_to_skip + bytes_per_chunk as usize]
-            .to_vec();
-        let label_chunk_bytes = self.label().to_le_bytes()
-            [bytes_to_skip..bytes_to_skip + bytes_per_chunk as usize]
-            .to_vec();
-        (item_chunk_bytes, label_chunk_bytes)
+        self.item().to_le_bytes()[bytes_to_skip..bytes_to_skip + bytes_per_chunk as usize].to_vec()
+    }
+
+    /// Returns bytes of `label` chunk at `chunk_index`. Chunks beyond label's length are zero.
+    pub fn label_chunk_at_index(&self, chunk_index: u32, psi_pt: &PsiPlaintext) -> Vec<u8> {
+        let bytes_per_chunk = psi_pt.bytes_per_chunk() as usize;
+        let bytes_to_skip = chunk_index as usize * bytes_per_chunk;
+
+        let mut chunk = vec![0u8; bytes_per_chunk];
+        self.label
+            .0
+            .iter()
+            .skip(bytes_to_skip)
+            .take(bytes_per_chunk)
+            .enumerate()
+            .for_each(|(i, b)| chunk[i] = *b);
+        chunk
     }
 }
 
@@ -135,7 +314,7 @@ impl Serialize for ItemLabel {
         S: serde::Serializer,
     {
         let mut v = self.item().to_le_bytes().to_vec();
-        v.extend(self.label().to_le_bytes().iter());
+        v.extend(self.label.0.iter());
         serializer.serialize_bytes(&v)
     }
 }
@@ -153,24 +332,22 @@ impl<'de> Visitor<'de> for ItemLabelVisitor {
     where
         E: serde::de::Error,
     {
-        // must have 64 byte, 32 for item and 32 for label
-        // if v.len() != 64 {
-        //     return serde::de::Error::invalid_length(v.len(), &self);
-        // }
-        assert_eq!(v.len(), 64);
+        // must have 32 bytes for item followed by label bytes
+        if v.len() < 32 {
+            return Err(E::invalid_length(v.len(), &self));
+        }
 
         let mut item_bytes = [0u8; 32];
-        let mut label_bytes = [0u8; 32];
-
-        for i in 0..32 {
-            item_bytes[i] = v[i];
-            label_bytes[i] = v[i + 32];
-        }
+        item_bytes.copy_from_slice(&v[..32]);
 
         let item = U256::from_le_bytes(item_bytes);
-        let label = U256::from_le_bytes(label_bytes);
+        let label = Label(v[32..].to_vec());
 
-        Ok(ItemLabel { item, label })
+        Ok(ItemLabel {
+            item,
+            label,
+            expires_at: None,
+        })
     }
 }
 
@@ -183,25 +360,71 @@ impl<'de> Deserialize<'de> for ItemLabel {
     }
 }
 
+/// Returns little endian value of `bytes`. Panics if there are more than 4 bytes, instead of silently dropping them.
 pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
-    bytes.iter().enumerate().fold(0u32, |acc, (index, byte)| {
-        let acc = acc + ((*byte as u32) << (index * 8));
-        acc
-    })
+    assert!(bytes.len() <= 4, "{} bytes do not fit in u32", bytes.len());
+    let mut le_bytes = [0u8; 4];
+    le_bytes[..bytes.len()].copy_from_slice(bytes);
+    u32::from_le_bytes(le_bytes)
 }
 
+/// Returns little endian value of `bytes`. Panics if there are more than 2 bytes, instead of silently dropping them.
+/// Item chunks always fit, since `PsiParams::validate` rejects chunks wider than 16 bits.
 pub fn bytes_to_u16(bytes: &[u8]) -> u16 {
-    bytes.iter().enumerate().fold(0u16, |acc, (index, byte)| {
-        let acc = acc + ((*byte as u16) << (index * 8));
-        acc
-    })
+    assert!(bytes.len() <= 2, "{} bytes do not fit in u16", bytes.len());
+    let mut le_bytes = [0u8; 2];
+    le_bytes[..bytes.len()].copy_from_slice(bytes);
+    u16::from_le_bytes(le_bytes)
+}
+
+/// Snapshot of server's db along with its version. Queries hold a snapshot for their entire duration, thus they see
+/// the same db even if it is replaced with `Server::swap_db` meanwhile.
+#[derive(Clone)]
+pub struct DbSnapshot {
+    db: Arc<Db>,
+    version: u64,
+}
+
+impl DbSnapshot {
+    pub fn db(&self) -> &Db {
+        &self.db
+    }
+
+    /// See `Server::db_version`
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Returns db for updates. Panics if snapshot is shared, ie a snapshot returned by `Server::snapshot` is still
+    /// held. Queries drop their snapshot before they return.
+    fn db_mut(&mut self) -> &mut Db {
+        Arc::get_mut(&mut self.db).expect("Db snapshot must not outlive query")
+    }
+}
+
+impl Deref for DbSnapshot {
+    type Target = Db;
+
+    fn deref(&self) -> &Self::Target {
+        &self.db
+    }
 }
 
 pub struct Server {
-    db: Db,
+    /// Current db. Lock is only held to clone or replace the snapshot.
+    db: RwLock<DbSnapshot>,
+    segment_timings: SegmentTimings,
     powers_dag: HashMap<usize, Node>,
     psi_params: PsiParams,
     evaluator: Evaluator,
+    query_validator: QueryValidator,
+    /// Clients must authenticate with a token in the store if set
+    token_store: Option<TokenStore>,
+    metrics: ServerMetrics,
+    /// Preprocessing and queries run on rayon's global pool if not set
+    thread_pools: Option<ThreadPools>,
+    /// Powers of every segment are calculated if not set
+    powers_cache: Option<PowersCache>,
 }
 
 impl Server {
@@ -213,17 +436,116 @@ impl Server {
         &self.evaluator
     }
 
+    pub fn query_validator(&self) -> &QueryValidator {
+        &self.query_validator
+    }
+
+    /// Replaces default `QueryValidator`, for ex. to change max. query size
+    pub fn with_query_validator(mut self, query_validator: QueryValidator) -> Server {
+        self.query_validator = query_validator;
+        self
+    }
+
+    /// Requires clients to authenticate with an API token in `token_store`, which also counts queries made with each
+    /// token for quota enforcement
+    pub fn with_token_store(mut self, token_store: TokenStore) -> Server {
+        self.token_store = Some(token_store);
+        self
+    }
+
+    /// Runs preprocessing on a dedicated pool of `preprocess_threads` threads and queries on a dedicated pool of
+    /// `query_threads` threads, thus background preprocessing, for ex. encoding a db swapped in with `swap_db`, does
+    /// not slow down queries beyond its share of cores. See `ThreadPools::new`.
+    pub fn with_thread_pools(
+        self,
+        preprocess_threads: usize,
+        query_threads: usize,
+    ) -> Result<Server, PsiError> {
+        Ok(self.with_shared_thread_pools(ThreadPools::new(preprocess_threads, query_threads)?))
+    }
+
+    /// Same as `with_thread_pools` but with `pools` shared with other servers, for ex. of other tenants
+    pub fn with_shared_thread_pools(mut self, pools: ThreadPools) -> Server {
+        self.thread_pools = Some(pools);
+        self
+    }
+
+    pub fn thread_pools(&self) -> Option<&ThreadPools> {
+        self.thread_pools.as_ref()
+    }
+
+    /// Caches PS powers of at most `capacity` segments, thus identical queries, for ex. retries, skip calculating
+    /// powers. See `PowersCache`.
+    pub fn with_powers_cache(mut self, capacity: usize) -> Server {
+        self.powers_cache = Some(PowersCache::new(capacity));
+        self
+    }
+
+    pub fn powers_cache(&self) -> Option<&PowersCache> {
+        self.powers_cache.as_ref()
+    }
+
+    /// Runs `op` on server's preprocessing pool, for ex. to preprocess a db before swapping it in with `swap_db`
+    pub fn install_preprocess<R: Send>(&self, op: impl FnOnce() -> R + Send) -> R {
+        install_preprocess(self.thread_pools.as_ref(), op)
+    }
+
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+
+    /// Returns server metrics along with DB occupancy in Prometheus text exposition format
+    pub fn render_metrics(&self) -> String {
+        self.metrics.render(&self.snapshot())
+    }
+
+    pub fn token_store(&self) -> Option<&TokenStore> {
+        self.token_store.as_ref()
+    }
+
+    /// Returns id of `token` if server requires authentication, otherwise `None`
+    pub fn authenticate(&self, token: &str) -> Result<Option<TokenId>, PsiError> {
+        match &self.token_store {
+            Some(store) => Ok(Some(store.authenticate(token)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Counts query of client authenticated with `token` towards token's quota. Returns error if server requires
+    /// authentication and client isn't authenticated or if token has used up its quota.
+    pub fn record_query(&self, token: Option<&TokenId>) -> Result<(), PsiError> {
+        match (&self.token_store, token) {
+            (Some(store), Some(token)) => Ok(store.record_query(token)?),
+            (Some(_), None) => Err(AuthError::NotAuthenticated.into()),
+            (None, _) => Ok(()),
+        }
+    }
+
     pub fn new(psi_params: &PsiParams) -> Server {
         let evaluator = Evaluator::new(gen_bfv_params(psi_params));
-        let powers_dag = construct_dag(&psi_params.source_powers, psi_params.ps_params.powers());
+        let powers_dag = psi_params
+            .powers_dag()
+            .expect("Invalid source powers")
+            .into_nodes();
 
         let db = Db::new(psi_params);
+        let segment_timings = SegmentTimings::new(&db);
+        let query_validator = QueryValidator::new(psi_params, &evaluator);
 
         Server {
             powers_dag,
-            db,
+            db: RwLock::new(DbSnapshot {
+                db: Arc::new(db),
+                version: 0,
+            }),
+            segment_timings,
             psi_params: psi_params.clone(),
             evaluator,
+            query_validator,
+            token_store: None,
+            metrics: ServerMetrics::default(),
+            thread_pools: None,
+            powers_cache: None,
         }
     }
 
@@ -231,46 +553,488 @@ impl Server {
         assert_eq!(&db.psi_params, psi_params);
 
         let evaluator = Evaluator::new(gen_bfv_params(psi_params));
-        let powers_dag = construct_dag(&psi_params.source_powers, psi_params.ps_params.powers());
+        let powers_dag = psi_params
+            .powers_dag()
+            .expect("Invalid source powers")
+            .into_nodes();
+        let segment_timings = SegmentTimings::new(&db);
+        let query_validator = QueryValidator::new(psi_params, &evaluator);
 
-        Server {
+        // encoded plaintexts aren't stored with db
+        let mut server = Server {
             powers_dag,
-            db,
+            db: RwLock::new(DbSnapshot {
+                db: Arc::new(db),
+                version: 0,
+            }),
+            segment_timings,
             psi_params: psi_params.clone(),
             evaluator,
+            query_validator,
+            token_store: None,
+            metrics: ServerMetrics::default(),
+            thread_pools: None,
+            powers_cache: None,
+        };
+        server.encode_coefficients();
+        server
+    }
+
+    /// Inserts `item_labels` and preprocesses the db. Fails without preprocessing if any ItemLabel is rejected due to
+    /// `max_inner_boxes_per_segment`.
+    pub fn setup(&mut self, item_labels: &[ItemLabel]) -> Result<(), PsiError> {
+        self.setup_with_progress(item_labels, &NoProgress)
+    }
+
+    /// Same as `setup` but reports progress of inserting and preprocessing to `progress`
+    pub fn setup_with_progress(
+        &mut self,
+        item_labels: &[ItemLabel],
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        let pools = self.thread_pools.clone();
+        install_preprocess(pools.as_ref(), || {
+            let rejected = self
+                .db_mut()
+                .insert_many_with_progress(item_labels, progress);
+            if let Some((_, e)) = rejected.first() {
+                warn!(count = rejected.len(), "ItemLabels rejected during insert");
+                return Err(e.clone().into());
+            }
+            self.db_mut().preprocess_with_progress(progress)?;
+            self.encode_coefficients();
+            Ok(())
+        })?;
+        self.db.get_mut().unwrap().version += 1;
+        Ok(())
+    }
+
+    /// Same as `setup_with_progress` but reads ItemLabels from `store` in batches of `ITEM_STORE_BATCH_SIZE` instead
+    /// of from memory
+    pub fn setup_from_store(
+        &mut self,
+        store: &dyn ItemStore,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        self.insert_from_store(store, progress)?;
+        let pools = self.thread_pools.clone();
+        install_preprocess(pools.as_ref(), || {
+            self.db_mut().preprocess_with_progress(progress)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(())
+        })?;
+        self.db.get_mut().unwrap().version += 1;
+        Ok(())
+    }
+
+    /// Same as `setup_from_store` followed by `store_db`, except that coefficients of each InnerBox are written to
+    /// `path` as soon as they are generated. See `Db::preprocess_and_store`.
+    pub fn setup_and_store(
+        &mut self,
+        store: &dyn ItemStore,
+        path: &Path,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        self.insert_from_store(store, progress)?;
+        let pools = self.thread_pools.clone();
+        install_preprocess(pools.as_ref(), || {
+            self.db_mut().preprocess_and_store(path, progress)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(())
+        })?;
+        self.db.get_mut().unwrap().version += 1;
+        Ok(())
+    }
+
+    /// Same as `setup_and_store` but only InnerBoxes in `partition` are preprocessed and stored at `path`. See
+    /// `Db::preprocess_partition_and_store`. Server isn't meant to serve queries afterwards, thus coefficients aren't
+    /// encoded.
+    pub fn setup_partition_and_store(
+        &mut self,
+        store: &dyn ItemStore,
+        path: &Path,
+        partition: Partition,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        self.insert_from_store(store, progress)?;
+        let pools = self.thread_pools.clone();
+        install_preprocess(pools.as_ref(), || {
+            self.db_mut()
+                .preprocess_partition_and_store(path, partition, progress)
+        })
+    }
+
+    /// Inserts ItemLabels in `store` batch by batch. Returns error of first rejected ItemLabel after all ItemLabels are
+    /// inserted.
+    fn insert_from_store(
+        &mut self,
+        store: &dyn ItemStore,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        let pools = self.thread_pools.clone();
+        insert_from_store(self.db_mut(), store, pools.as_ref(), progress)
+    }
+
+    /// Builds a new db from ItemLabels in `store`, preprocesses it on server's preprocessing pool and swaps it in with
+    /// `swap_db`. Returns the new db version. Queries are processed against current db meanwhile. New db keeps OPRF key
+    /// of current db, thus OPRF outputs clients obtained earlier remain valid.
+    pub fn refresh_from_store(&self, store: &dyn ItemStore) -> Result<u64, PsiError> {
+        let mut db = Db::new(&self.psi_params);
+        db.oprf_key = self.snapshot().oprf_key.clone();
+        insert_from_store(&mut db, store, self.thread_pools.as_ref(), &NoProgress)?;
+        self.install_preprocess(|| db.preprocess())?;
+        self.swap_db(db)
+    }
+
+    /// Refreshes db from `source` with `refresh_from_store` every `interval` on a background thread, thus server's
+    /// data follows its item store without downtime. Refresh competes with queries for cores unless server has
+    /// dedicated thread pools (see `with_thread_pools`). Failed refreshes are logged and retried after `interval`.
+    /// Refreshes stop once returned handle is stopped or dropped.
+    pub fn schedule_refresh(
+        self: &Arc<Server>,
+        interval: Duration,
+        source: Box<dyn ItemStore + Send>,
+    ) -> Result<RefreshHandle, PsiError> {
+        RefreshHandle::spawn(self.clone(), interval, source)
+    }
+
+    /// Inserts ItemLabel after `setup` without re-preprocessing the entire db
+    pub fn insert_and_update(&mut self, item_label: &ItemLabel) -> Result<(), PsiError> {
+        let pools = self.thread_pools.clone();
+        install_preprocess(pools.as_ref(), || {
+            self.db_mut().insert_and_update(item_label)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(())
+        })?;
+        self.db.get_mut().unwrap().version += 1;
+        Ok(())
+    }
+
+    /// Inserts ItemLabel after `setup`, or replaces label of its item if item already exists. See `Db::upsert`.
+    pub fn upsert(&mut self, item_label: &ItemLabel) -> Result<bool, PsiError> {
+        let pools = self.thread_pools.clone();
+        let replaced = install_preprocess(pools.as_ref(), || {
+            let replaced = self.db_mut().upsert(item_label)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(replaced)
+        })?;
+        self.db.get_mut().unwrap().version += 1;
+        Ok(replaced)
+    }
+
+    /// Applies `deltas` after `setup`, re-interpolating only rows they touch. See `Db::apply_delta`.
+    pub fn apply_delta(&mut self, deltas: &[DbDelta]) -> Result<DeltaSummary, PsiError> {
+        let pools = self.thread_pools.clone();
+        let summary = install_preprocess(pools.as_ref(), || {
+            let summary = self.db_mut().apply_delta(deltas)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(summary)
+        })?;
+        self.db.get_mut().unwrap().version += 1;
+        Ok(summary)
+    }
+
+    /// Removes items whose expiry is at or before `now` (seconds since unix epoch) after `setup`. Returns count of
+    /// removed items.
+    pub fn purge_expired(&mut self, now: u64) -> Result<usize, PsiError> {
+        let pools = self.thread_pools.clone();
+        let purged = install_preprocess(pools.as_ref(), || {
+            let purged = self.db_mut().purge_expired(now)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(purged)
+        })?;
+        if purged > 0 {
+            self.db.get_mut().unwrap().version += 1;
+        }
+        Ok(purged)
+    }
+
+    /// Removes item and its label after `setup`. Returns false if item does not exist.
+    pub fn remove(&mut self, item: &U256) -> Result<bool, PsiError> {
+        let pools = self.thread_pools.clone();
+        let removed = install_preprocess(pools.as_ref(), || {
+            let removed = self.db_mut().remove(item)?;
+            self.encode_coefficients();
+            Ok::<_, PsiError>(removed)
+        })?;
+        if removed {
+            self.db.get_mut().unwrap().version += 1;
+        }
+        Ok(removed)
+    }
+
+    /// Encodes polynomial coefficients as plaintexts if `PsiParams::precompute_plaintexts` is set
+    fn encode_coefficients(&mut self) {
+        if self.psi_params.precompute_plaintexts {
+            self.db
+                .get_mut()
+                .unwrap()
+                .db_mut()
+                .encode_coefficients(&self.evaluator);
+        }
+    }
+
+    /// Version of db, starting at 0 when server is created and incremented whenever db is updated. Queries processed
+    /// against the same version see the same server set.
+    pub fn db_version(&self) -> u64 {
+        self.db.read().unwrap().version
+    }
+
+    /// Returns snapshot of current db. Db of snapshot stays the same even if db is swapped. Snapshot must be dropped
+    /// before server's db is updated with `insert_and_update`, `remove` or `setup`.
+    pub fn snapshot(&self) -> DbSnapshot {
+        self.db.read().unwrap().clone()
+    }
+
+    fn db_mut(&mut self) -> &mut Db {
+        self.db.get_mut().unwrap().db_mut()
+    }
+
+    /// Atomically replaces server's db with `db`, for ex. a freshly preprocessed db loaded with `Db::load`, and
+    /// returns the new db version. Queries in progress finish against the previous db, which is dropped once the last
+    /// of them finishes. Coefficients of `db` are encoded before it is swapped in, thus queries aren't blocked
+    /// meanwhile.
+    ///
+    /// `db` must have same `PsiParams` as server. If OPRF is enabled, db must be preprocessed with the same OPRF key,
+    /// otherwise clients that ran OPRF before the swap receive no matches.
+    pub fn swap_db(&self, mut db: Db) -> Result<u64, PsiError> {
+        if db.psi_params != self.psi_params {
+            return Err(PsiError::ParamsMismatch(
+                "Db must have same PsiParams as server".to_string(),
+            ));
         }
+        if self.psi_params.precompute_plaintexts {
+            self.install_preprocess(|| db.encode_coefficients(&self.evaluator));
+        }
+
+        let mut current = self.db.write().unwrap();
+        let version = current.version + 1;
+        *current = DbSnapshot {
+            db: Arc::new(db),
+            version,
+        };
+        Ok(version)
     }
 
-    pub fn setup(&mut self, item_labels: &[ItemLabel]) {
-        // item_labels.iter().for_each(|(i)| {
-        //     if self.db.insert(i) {
-        //         // println!("Item {} inserted", i.item());
-        //     } else {
-        //         println!("Item {} insert failed. Duplicate Item.", i.item());
-        //     }
-        // });
-        self.db.insert_many(item_labels);
-        self.db.preprocess();
+    /// Returns error without processing `query` if it is rejected by `QueryValidator`
+    pub fn query(&self, query: &Query, ek: &EvaluationKey) -> Result<QueryResponse, PsiError> {
+        Ok(self
+            .query_with_metadata(query, ek, &CancellationToken::new())?
+            .0)
     }
 
-    pub fn query(&self, query: &Query, ek: &EvaluationKey) -> QueryResponse {
-        self.db
-            .handle_query(query, &self.evaluator, ek, &self.powers_dag)
+    /// Same as `query` but also returns time spent calculating powers and evaluating polynomials. Deserialization and
+    /// serialization times are left for the caller to fill in. Returns `PsiError::Cancelled` if `cancellation` is
+    /// cancelled before all segments are processed.
+    pub fn query_with_metadata(
+        &self,
+        query: &Query,
+        ek: &EvaluationKey,
+        cancellation: &CancellationToken,
+    ) -> Result<(QueryResponse, QueryMetadata), PsiError> {
+        self.query_snapshot_assembled(&self.snapshot(), query, ek, cancellation)
+    }
+
+    /// Same as `query_with_metadata` but for each query of `batch`, evaluated one after another against the same db
+    /// snapshot, thus responses are consistent with each other even if db is swapped meanwhile. Returns response and
+    /// metadata of each query in order, or the first error.
+    pub fn query_batch(
+        &self,
+        batch: &QueryBatch,
+        ek: &EvaluationKey,
+        cancellation: &CancellationToken,
+    ) -> Result<Vec<(QueryResponse, QueryMetadata)>, PsiError> {
+        self.query_validator.validate_batch(batch)?;
+        let snapshot = self.snapshot();
+        batch
+            .queries()
+            .iter()
+            .map(|query| self.query_snapshot_assembled(&snapshot, query, ek, cancellation))
+            .collect()
+    }
+
+    /// Processes `query` against db of `snapshot` and assembles responses of all segments
+    fn query_snapshot_assembled(
+        &self,
+        snapshot: &DbSnapshot,
+        query: &Query,
+        ek: &EvaluationKey,
+        cancellation: &CancellationToken,
+    ) -> Result<(QueryResponse, QueryMetadata), PsiError> {
+        let segment_responses = Mutex::new(vec![]);
+        let metadata = self.query_snapshot(snapshot, query, ek, cancellation, |response| {
+            segment_responses.lock().unwrap().push(response)
+        })?;
+        let query_response = snapshot.assemble_response(segment_responses.into_inner().unwrap());
+        Ok((query_response, metadata))
+    }
+
+    /// Same as `query_with_metadata` but passes response of each segment to `on_segment` as soon as it is processed.
+    /// See `Db::handle_query_streamed`. Returns time spent calculating powers and evaluating polynomials.
+    pub fn query_streamed<F: Fn(SegmentResponse) + Sync + Send>(
+        &self,
+        query: &Query,
+        ek: &EvaluationKey,
+        cancellation: &CancellationToken,
+        on_segment: F,
+    ) -> Result<QueryMetadata, PsiError> {
+        self.query_snapshot(&self.snapshot(), query, ek, cancellation, on_segment)
+    }
+
+    /// Processes `query` against db of `snapshot`
+    fn query_snapshot<F: Fn(SegmentResponse) + Sync + Send>(
+        &self,
+        snapshot: &DbSnapshot,
+        query: &Query,
+        ek: &EvaluationKey,
+        cancellation: &CancellationToken,
+        on_segment: F,
+    ) -> Result<QueryMetadata, PsiError> {
+        let metadata = Mutex::new(QueryMetadata::new(
+            self.psi_params.no_of_hash_tables as usize,
+            snapshot.version,
+        ));
+        let result = self.query_validator.validate(query).and_then(|_| {
+            install_query(self.thread_pools.as_ref(), || {
+                snapshot.handle_query_streamed(
+                    query,
+                    &self.evaluator,
+                    ek,
+                    &self.powers_dag,
+                    &self.segment_timings,
+                    self.powers_cache.as_ref(),
+                    cancellation,
+                    |response| {
+                        self.metrics.record_segment(&response.times);
+                        metadata
+                            .lock()
+                            .unwrap()
+                            .record_segment(response.big_box, &response.times);
+                        on_segment(response)
+                    },
+                )
+            })
+        });
+        self.metrics.record_query(result.is_ok());
+        result.map(|_| metadata.into_inner().unwrap())
+    }
+
+    /// Evaluates server's OPRF on client's blinded items. Returns `None` if OPRF is disabled or request is malformed.
+    pub fn oprf_evaluate(&self, request: &OprfRequest) -> Option<OprfResponse> {
+        self.snapshot().oprf_key()?.evaluate(request)
     }
 
     pub fn print_diagnosis(&self) {
-        self.db.print_diagnosis();
+        self.snapshot().print_diagnosis();
     }
 
-    pub fn db(&self) -> &Db {
-        &self.db
+    /// Returns snapshot of current db. See `snapshot`.
+    pub fn db(&self) -> DbSnapshot {
+        self.snapshot()
+    }
+
+    /// Stores db at `path`. See `Db::store`.
+    pub fn store_db(&mut self, path: &Path) -> Result<(), PsiError> {
+        self.db_mut().store(path)
+    }
+
+    /// Stores db at `path` encrypted with `key`. See `Db::store_encrypted`.
+    pub fn store_db_encrypted(&mut self, path: &Path, key: &DbKey) -> Result<(), PsiError> {
+        self.db_mut().store_encrypted(path, key)
+    }
+}
+/// Inserts ItemLabels in `store` into `db` batch by batch. Returns error of first rejected ItemLabel after all
+/// ItemLabels are inserted.
+fn insert_from_store(
+    db: &mut Db,
+    store: &dyn ItemStore,
+    pools: Option<&ThreadPools>,
+    progress: &dyn ProgressSink,
+) -> Result<(), PsiError> {
+    progress.start(
+        SetupStage::Insert,
+        (store.len()? * db.psi_params.no_of_hash_tables as usize) as u64,
+    );
+    let mut rejected = vec![];
+    let mut offset = 0;
+    store.for_each_batch(ITEM_STORE_BATCH_SIZE, &mut |batch| {
+        rejected.extend(
+            install_preprocess(pools, || db.insert_batch(&batch, progress))
+                .into_iter()
+                .map(|(index, e)| (offset + index, e)),
+        );
+        offset += batch.len();
+        Ok(())
+    })?;
+    progress.finish(SetupStage::Insert);
+
+    if let Some((_, e)) = rejected.first() {
+        warn!(count = rejected.len(), "ItemLabels rejected during insert");
+        return Err(e.clone().into());
     }
+    Ok(())
 }
+
 #[cfg(test)]
 mod tests {
+    use crypto_bigint::{Encoding, U256};
+    use proptest::prelude::*;
     use rand::thread_rng;
 
-    use crate::{bytes_to_u32, random_u256, ItemLabel};
+    use std::{sync::Arc, time::Duration};
+
+    use bfv::{EvaluationKey, Evaluator, SecretKey};
+
+    use crate::{
+        bytes_to_u32, construct_plaintext_query, construct_query, deserialize_query,
+        deserialize_query_batch, encrypt_query, expected_public_key_query_bytes,
+        expected_response_bytes, gen_bfv_params, gen_random_item_labels, generate_evaluation_key,
+        measure_response_noise, process_query_response, process_unlabeled_query_response,
+        random_u256, serialize_query, serialize_query_batch, serialize_query_response,
+        CancellationToken, ClientId, Db, ItemLabel, Label, PotentialResponseLabels, PsiError,
+        PsiMode, PsiParams, PsiPlaintext, PublicKey, QueryBatch, QueryLayout, QueryResponse,
+        QueryState, QueryValidator, Server, CLIENT_ID_BYTES, MIN_NOISE_BUDGET_BITS,
+    };
+
+    proptest! {
+        #[test]
+        fn bytes_to_u32_works(bytes in proptest::collection::vec(any::<u8>(), 0..=4)) {
+            let mut le_bytes = [0u8; 4];
+            le_bytes[..bytes.len()].copy_from_slice(&bytes);
+            prop_assert_eq!(bytes_to_u32(&bytes), u32::from_le_bytes(le_bytes));
+        }
+
+        #[test]
+        fn item_and_label_chunks_roundtrip(
+            item in any::<[u8; 32]>(),
+            label in proptest::collection::vec(any::<u8>(), 0..100),
+            bfv_pt_bits in prop_oneof![Just(8u32), Just(16), Just(32)],
+            psi_pt_bits in prop_oneof![Just(64u32), Just(128), Just(256)],
+        ) {
+            let psi_pt = PsiPlaintext::new(psi_pt_bits, bfv_pt_bits, 65537)
+                .with_label_bytes(label.len().max(1) as u32);
+            let item_label = ItemLabel::new(U256::from_le_bytes(item), Label::new(label.clone()));
+
+            // item is truncated to `psi_pt_bytes`
+            let item_bytes = (0..psi_pt.slots_required())
+                .flat_map(|index| item_label.item_chunk_at_index(index, &psi_pt))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(&item_bytes[..], &item[..psi_pt.psi_pt_bytes as usize]);
+
+            // label is zero padded to a multiple of chunk size
+            let label_bytes = (0..psi_pt.label_chunks())
+                .flat_map(|index| item_label.label_chunk_at_index(index, &psi_pt))
+                .collect::<Vec<_>>();
+            prop_assert_eq!(&label_bytes[..label.len()], &label[..]);
+            prop_assert!(label_bytes[label.len()..].iter().all(|b| *b == 0));
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn bytes_to_u32_rejects_more_than_4_bytes() {
+        bytes_to_u32(&[1, 2, 3, 4, 5]);
+    }
 
     #[test]
     fn test_byte_to_u32() {
@@ -291,5 +1055,488 @@ mod tests {
         let item_label_back: ItemLabel = bincode::deserialize(&bytes).unwrap();
 
         assert_eq!(item_label, item_label_back);
+
+        // labels need not be of same size as item
+        let item_label = ItemLabel::new(item, Label::new(vec![7u8; 70]));
+        let bytes = bincode::serialize(&item_label).unwrap();
+        let item_label_back: ItemLabel = bincode::deserialize(&bytes).unwrap();
+        assert_eq!(item_label, item_label_back);
+    }
+
+    #[test]
+    fn label_chunks_are_independent_of_item_chunks() {
+        // 16 byte items with 64 byte labels
+        let psi_pt = PsiPlaintext::new(128, 16, 65537).with_label_bytes(64);
+        assert_eq!(psi_pt.slots_required(), 8);
+        assert_eq!(psi_pt.label_chunks(), 32);
+        assert_eq!(psi_pt.label_parts(), 4);
+
+        let label = (0..60u8).collect::<Vec<u8>>();
+        let item_label = ItemLabel::new(random_u256(&mut thread_rng()), Label::new(label));
+        assert_eq!(item_label.label_chunk_at_index(1, &psi_pt), vec![2, 3]);
+        // chunks beyond label length are padded
+        assert_eq!(item_label.label_chunk_at_index(30, &psi_pt), vec![0, 0]);
+    }
+
+    #[test]
+    fn swap_db_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let mut item_labels = || {
+            (0..10)
+                .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+                .collect::<Vec<_>>()
+        };
+
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels()).unwrap();
+        assert_eq!(server.db_version(), 1);
+        let old_snapshot = server.snapshot();
+
+        let mut db = Db::new(&psi_params);
+        assert!(db.insert_many(&item_labels()).is_empty());
+        db.preprocess().unwrap();
+        assert_eq!(server.swap_db(db).unwrap(), 2);
+        assert_eq!(server.db_version(), 2);
+
+        // snapshot taken before swap still refers to previous db
+        assert_eq!(old_snapshot.version(), 1);
+        assert!(!Arc::ptr_eq(&old_snapshot.db, &server.snapshot().db));
+
+        // db with different params is rejected
+        let other_params = psi_params.clone().with_label_bytes(64);
+        assert!(matches!(
+            server.swap_db(Db::new(&other_params)),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+        assert_eq!(server.db_version(), 2);
+    }
+
+    /// Client of tests with its own secret and evaluation keys
+    struct TestClient {
+        psi_params: PsiParams,
+        evaluator: Evaluator,
+        sk: SecretKey,
+        ek: EvaluationKey,
+    }
+
+    impl TestClient {
+        fn new(psi_params: &PsiParams) -> TestClient {
+            let mut rng = thread_rng();
+            let evaluator = Evaluator::new(gen_bfv_params(psi_params));
+            let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+            let ek = generate_evaluation_key(psi_params, &evaluator, &sk, &mut rng);
+            TestClient {
+                psi_params: psi_params.clone(),
+                evaluator,
+                sk,
+                ek,
+            }
+        }
+
+        fn construct_query(&self, items: &[U256]) -> QueryState {
+            construct_query(
+                items,
+                &self.psi_params,
+                &self.evaluator,
+                &self.sk,
+                &mut thread_rng(),
+            )
+        }
+
+        fn process_response(
+            &self,
+            query_state: &QueryState,
+            response: &QueryResponse,
+        ) -> Vec<PotentialResponseLabels> {
+            process_query_response(
+                &self.psi_params,
+                query_state.hash_tables(),
+                &self.evaluator,
+                &self.sk,
+                response,
+            )
+        }
+    }
+
+    /// Queries `items` from `server` with a new `TestClient` and returns decrypted potential labels
+    fn query_items(server: &Server, items: &[U256]) -> Vec<PotentialResponseLabels> {
+        let client = TestClient::new(server.psi_params());
+        let query_state = client.construct_query(items);
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+        client.process_response(&query_state, &response)
+    }
+
+    /// Returns whether label of `item_label` is one of potential labels of its item in `responses`
+    fn has_label(responses: &[PotentialResponseLabels], item_label: &ItemLabel) -> bool {
+        responses.iter().any(|response| {
+            response.item() == item_label.item() && response.labels().contains(item_label.label())
+        })
+    }
+
+    #[test]
+    fn query_on_thread_pools_works() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params).with_thread_pools(1, 2).unwrap();
+        server.setup(&item_labels).unwrap();
+
+        // db preprocessed elsewhere is swapped in
+        let mut db = Db::new(&psi_params);
+        assert!(db.insert_many(&item_labels).is_empty());
+        server.install_preprocess(|| db.preprocess()).unwrap();
+        assert_eq!(server.swap_db(db).unwrap(), 2);
+
+        let responses = query_items(&server, &[*item_labels[0].item()]);
+        assert!(has_label(&responses, &item_labels[0]));
+    }
+
+    /// Queries `items` from `server` in `PsiMode::Unlabeled` and returns items at intersection
+    fn query_unlabeled_items(server: &Server, items: &[U256]) -> Vec<U256> {
+        let client = TestClient::new(server.psi_params());
+        let query_state = client.construct_query(items);
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+        process_unlabeled_query_response(
+            &client.psi_params,
+            query_state.hash_tables(),
+            &client.evaluator,
+            &client.sk,
+            &response,
+        )
+    }
+
+    #[test]
+    fn unlabeled_query_finds_member() {
+        let psi_params = PsiParams::default().with_mode(PsiMode::Unlabeled);
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let member = *item_labels[0].item();
+        assert_eq!(query_unlabeled_items(&server, &[member]), vec![member]);
+    }
+
+    #[test]
+    fn unlabeled_query_misses_non_member() {
+        let psi_params = PsiParams::default().with_mode(PsiMode::Unlabeled);
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let non_member = random_u256(&mut thread_rng());
+        assert!(query_unlabeled_items(&server, &[non_member]).is_empty());
+    }
+
+    #[test]
+    fn removed_item_is_not_found() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(100, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        assert!(server.remove(item_labels[0].item()).unwrap());
+        assert!(!server.remove(item_labels[0].item()).unwrap());
+        assert_eq!(server.db_version(), 2);
+
+        let responses = query_items(&server, &[*item_labels[0].item(), *item_labels[1].item()]);
+        assert!(!has_label(&responses, &item_labels[0]));
+        assert!(has_label(&responses, &item_labels[1]));
+
+        // removed item can be inserted again
+        server.insert_and_update(&item_labels[0]).unwrap();
+    }
+
+    #[test]
+    fn cancelled_query_returns_error() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let client = TestClient::new(&psi_params);
+        let query_state = client.construct_query(&[*item_labels[0].item()]);
+        let cancellation = CancellationToken::with_timeout(Duration::ZERO);
+        assert!(matches!(
+            server.query_with_metadata(query_state.query(), &client.ek, &cancellation),
+            Err(PsiError::Cancelled(_))
+        ));
+        assert!(server.query(query_state.query(), &client.ek).is_ok());
+    }
+
+    #[test]
+    fn response_noise_budget_is_not_exhausted() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let client = TestClient::new(&psi_params);
+        let query_state = client.construct_query(&[*item_labels[0].item()]);
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+
+        let noise = measure_response_noise(&client.evaluator, &client.sk, &response).unwrap();
+        assert_eq!(
+            noise.ciphertexts,
+            server.snapshot().stats().inner_boxes() * psi_params.label_parts() as usize
+        );
+        assert!(noise.min_bits >= MIN_NOISE_BUDGET_BITS);
+        assert_eq!(noise.low, 0);
+    }
+
+    #[test]
+    fn repeated_query_uses_cached_powers() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params).with_powers_cache(64);
+        server.setup(&item_labels).unwrap();
+
+        let client = TestClient::new(&psi_params);
+        let query_state = client.construct_query(&[*item_labels[0].item()]);
+        let segments = QueryLayout::new(&psi_params).segments() as u64;
+        let query_bytes = serialize_query(query_state.query(), client.evaluator.params());
+        let receive = |client_id: ClientId| {
+            deserialize_query(&query_bytes, &psi_params, server.evaluator())
+                .unwrap()
+                .with_client_id(client_id)
+        };
+        let client_id = ClientId([1; CLIENT_ID_BYTES]);
+
+        // powers of queries not bound to a client aren't cached
+        server.query(query_state.query(), &client.ek).unwrap();
+        assert_eq!(server.powers_cache().unwrap().misses(), 0);
+
+        let response = server.query(&receive(client_id), &client.ek).unwrap();
+        let cache = server.powers_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (0, segments));
+
+        // retried query skips calculating powers and receives the same response
+        let retried = server.query(&receive(client_id), &client.ek).unwrap();
+        let cache = server.powers_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (segments, segments));
+
+        // same query sent by another client is evaluated with its own powers
+        server
+            .query(&receive(ClientId([2; CLIENT_ID_BYTES])), &client.ek)
+            .unwrap();
+        let cache = server.powers_cache().unwrap();
+        assert_eq!((cache.hits(), cache.misses()), (segments, 2 * segments));
+        let labels = |response: &QueryResponse| {
+            client
+                .process_response(&query_state, response)
+                .into_iter()
+                .map(|labels| (*labels.item(), labels.labels().to_vec()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(labels(&retried), labels(&response));
+    }
+
+    #[test]
+    fn query_batch_works() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let client = TestClient::new(&psi_params);
+        let query_states = item_labels[..2]
+            .iter()
+            .map(|item_label| client.construct_query(&[*item_label.item()]))
+            .collect::<Vec<_>>();
+
+        let batch = QueryBatch::from_query_states(&query_states);
+        let batch_bytes = serialize_query_batch(&batch, client.evaluator.params());
+        assert!(server
+            .query_validator()
+            .validate_batch_bytes(&batch_bytes)
+            .is_ok());
+        let batch = deserialize_query_batch(&batch_bytes, &psi_params, &client.evaluator).unwrap();
+        let responses = server
+            .query_batch(&batch, &client.ek, &CancellationToken::new())
+            .unwrap();
+
+        assert_eq!(responses.len(), 2);
+        for ((query_state, item_label), (response, _)) in
+            query_states.iter().zip(&item_labels).zip(&responses)
+        {
+            let potential_labels = client.process_response(query_state, response);
+            assert!(has_label(&potential_labels, item_label));
+        }
+
+        assert!(matches!(
+            server.query_batch(
+                &QueryBatch::new(vec![]),
+                &client.ek,
+                &CancellationToken::new()
+            ),
+            Err(PsiError::InvalidQuery(_))
+        ));
+    }
+
+    #[test]
+    fn query_encrypted_with_public_key_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let client = TestClient::new(&psi_params);
+        let evaluator = &client.evaluator;
+        let pk = PublicKey::new(evaluator, &client.sk, &mut rng);
+
+        let plaintext_query =
+            construct_plaintext_query(&[*item_labels[0].item()], &psi_params, &mut rng);
+        let query = encrypt_query(
+            plaintext_query.hash_table_queries(),
+            &psi_params,
+            evaluator,
+            &pk,
+            &mut rng,
+        );
+        let query_state = plaintext_query.into_query_state(query);
+
+        // unseeded query ciphertexts are accepted by server
+        let query_bytes = serialize_query(query_state.query(), evaluator.params());
+        assert_eq!(
+            query_bytes.len(),
+            expected_public_key_query_bytes(evaluator, &psi_params)
+        );
+        let validator = QueryValidator::new(&psi_params, evaluator);
+        assert!(validator.validate_query_bytes(&query_bytes).is_ok());
+        let query = deserialize_query(&query_bytes, &psi_params, evaluator).unwrap();
+        assert!(validator.validate(&query).is_ok());
+
+        let response = server.query(&query, &client.ek).unwrap();
+        let noise = measure_response_noise(evaluator, &client.sk, &response).unwrap();
+        assert_eq!(noise.low, 0);
+        let responses = client.process_response(&query_state, &response);
+        assert!(has_label(&responses, &item_labels[0]));
+    }
+
+    #[test]
+    fn query_with_u16_coefficients_works() {
+        // 40961 is an NTT friendly prime for BFV degree 2^12, thus coefficients are stored as u16s
+        let psi_params = PsiParams::default()
+            .with_bfv_degree(1 << 12)
+            .with_plaintext_modulus(8, 40961);
+        let item_labels = gen_random_item_labels(100, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let responses = query_items(&server, &[*item_labels[0].item()]);
+        assert!(has_label(&responses, &item_labels[0]));
+    }
+
+    #[test]
+    fn query_with_random_padding_works() {
+        let psi_params = PsiParams::default().with_random_padding();
+        let item_labels = gen_random_item_labels(100, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let responses = query_items(&server, &[*item_labels[0].item()]);
+        assert!(has_label(&responses, &item_labels[0]));
+    }
+
+    #[test]
+    fn query_with_membership_works() {
+        let psi_params = PsiParams::default().with_membership();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        // every InnerBox responds with labels and membership ciphertexts
+        let client = TestClient::new(&psi_params);
+        let non_member = random_u256(&mut thread_rng());
+        let query_state = client.construct_query(&[*item_labels[0].item(), non_member]);
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+        let noise = measure_response_noise(&client.evaluator, &client.sk, &response).unwrap();
+        assert_eq!(
+            noise.ciphertexts,
+            server.snapshot().stats().inner_boxes() * psi_params.label_parts() as usize
+        );
+        assert_eq!(noise.low, 0);
+
+        // only InnerBox that stores an item responds with its label
+        let responses = client.process_response(&query_state, &response);
+        let labels_of = |item: &U256| {
+            responses
+                .iter()
+                .find(|response| response.item() == item)
+                .unwrap()
+                .labels()
+                .to_vec()
+        };
+        assert_eq!(
+            labels_of(item_labels[0].item()),
+            [item_labels[0].label().clone()]
+        );
+        assert!(labels_of(&non_member).is_empty());
+    }
+
+    #[test]
+    fn query_at_lower_evaluation_level_works() {
+        let psi_params = PsiParams::default().with_evaluation_level(1);
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let client = TestClient::new(&psi_params);
+        let query_state = client.construct_query(&[*item_labels[0].item()]);
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+        let noise = measure_response_noise(&client.evaluator, &client.sk, &response).unwrap();
+        assert_eq!(noise.low, 0);
+        let responses = client.process_response(&query_state, &response);
+        assert!(has_label(&responses, &item_labels[0]));
+    }
+
+    #[test]
+    fn query_with_circuit_privacy_at_lower_evaluation_level_works() {
+        let psi_params = PsiParams::default()
+            .with_circuit_privacy()
+            .with_derived_moduli()
+            .unwrap()
+            .with_evaluation_level(1);
+        psi_params.validate().unwrap();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        // encryptions of zero are at level 0 whereas responses are rerandomized at evaluation level
+        let client = TestClient::new(&psi_params);
+        let query_state = client.construct_query(&[*item_labels[0].item()]);
+        assert!(!query_state.query().1.is_empty());
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+        let noise = measure_response_noise(&client.evaluator, &client.sk, &response).unwrap();
+        assert_eq!(noise.low, 0);
+        let responses = client.process_response(&query_state, &response);
+        assert!(has_label(&responses, &item_labels[0]));
+    }
+
+    #[test]
+    fn expected_response_bytes_works() {
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let db_stats = server.snapshot().stats();
+        assert_eq!(
+            db_stats.inner_boxes_per_segment.len(),
+            psi_params.no_of_hash_tables as usize
+        );
+
+        let client = TestClient::new(&psi_params);
+        let query_state = client.construct_query(&[*item_labels[0].item()]);
+        let response = server.query(query_state.query(), &client.ek).unwrap();
+        let response_bytes = bincode::serialize(&serialize_query_response(
+            &response,
+            client.evaluator.params(),
+        ))
+        .unwrap();
+        assert_eq!(
+            response_bytes.len(),
+            expected_response_bytes(&psi_params, &client.evaluator, &db_stats)
+        );
     }
 }
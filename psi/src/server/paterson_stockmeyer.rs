@@ -1,11 +1,12 @@
-use crate::PsiParams;
+use crate::{ModqElement, PsiParams};
 
 use super::{EvalPolyDegree, InnerBox};
 use bfv::{Ciphertext, Encoding, EvaluationKey, Evaluator, Plaintext, Representation};
 use itertools::{izip, Itertools};
-use ndarray::Array2;
+use ndarray::ArrayView2;
 use serde::{Deserialize, Serialize};
 use std::{
+    cmp::Reverse,
     collections::{HashMap, HashSet},
     ops::Deref,
 };
@@ -36,6 +37,95 @@ impl PSParams {
         }
     }
 
+    /// Searches for low degree split and source powers to evaluate polynomial of `total_degree` within
+    /// `available_levels` multiplicative depth. Returns PS params along with source powers.
+    ///
+    /// Each source power adds a ciphertext to the query, thus splits are first compared by no. of source powers, then
+    /// by no. of ciphertext multiplications (ie to calculate powers and to multiply inner sums with high powers), then
+    /// by depth.
+    pub fn optimize(total_degree: usize, available_levels: usize) -> (PSParams, Vec<usize>) {
+        assert!(total_degree > 0 && available_levels > 0);
+
+        // no. of ciphertext multiplications is minimised around sqrt(total_degree)
+        let sqrt = (total_degree as f64).sqrt() as usize;
+        let mut best: Option<((usize, usize, usize), PSParams, Vec<usize>)> = None;
+        for low_degree in (sqrt / 2).max(1)..=(2 * sqrt).min(total_degree) {
+            let ps_params = PSParams::new(low_degree, total_degree);
+            let outer_loop_count = total_degree / (low_degree + 1);
+
+            // multiplying inner sums with high powers consumes a level
+            let max_depth = if outer_loop_count > 0 {
+                available_levels - 1
+            } else {
+                available_levels
+            };
+            let (source_powers, depth) = search_source_powers(&ps_params.powers, max_depth);
+
+            let ct_multiplications =
+                ps_params.powers.len() - source_powers.len() + outer_loop_count;
+            let key = (source_powers.len(), ct_multiplications, depth);
+            if best.as_ref().map_or(true, |(best_key, ..)| key < *best_key) {
+                best = Some((key, ps_params, source_powers));
+            }
+        }
+
+        let (_, ps_params, source_powers) = best.unwrap();
+        (ps_params, source_powers)
+    }
+
+    /// Searches for low degree split and window size (in bits) of windowed source powers (see
+    /// `windowed_source_powers`) to evaluate polynomial of `total_degree` within `available_levels` multiplicative
+    /// depth. Returns PS params along with source powers and window size.
+    ///
+    /// Splits are compared by no. of source powers, then by depth of calculating PS powers as balanced products of their
+    /// window digits. Windows as wide as `total_degree` make every PS power a source power, thus a split is always
+    /// found.
+    pub fn optimize_windowed(
+        total_degree: usize,
+        available_levels: usize,
+    ) -> (PSParams, Vec<usize>, u32) {
+        assert!(total_degree > 0 && available_levels > 0);
+
+        let sqrt = (total_degree as f64).sqrt() as usize;
+        let max_window_bits = usize::BITS - total_degree.leading_zeros();
+        let mut best: Option<((usize, usize, u32), PSParams, Vec<usize>)> = None;
+        for low_degree in (sqrt / 2).max(1)..=(2 * sqrt).min(total_degree) {
+            let ps_params = PSParams::new(low_degree, total_degree);
+            let outer_loop_count = total_degree / (low_degree + 1);
+            let max_depth = if outer_loop_count > 0 {
+                available_levels - 1
+            } else {
+                available_levels
+            };
+
+            for window_bits in 1..=max_window_bits {
+                let depth = windowed_depth(&ps_params.powers, window_bits);
+                if depth > max_depth {
+                    continue;
+                }
+                let source_powers = windowed_source_powers(&ps_params.powers, window_bits);
+                let key = (source_powers.len(), depth, window_bits);
+                if best.as_ref().map_or(true, |(best_key, ..)| key < *best_key) {
+                    best = Some((key, ps_params.clone(), source_powers));
+                }
+            }
+        }
+
+        let ((_, _, window_bits), ps_params, source_powers) = best.unwrap();
+        (ps_params, source_powers, window_bits)
+    }
+
+    /// No. of coefficient columns, starting at degree 0, required to evaluate polynomials of atmost `degree`. Columns
+    /// beyond are zero, thus aren't stored and their plaintext multiplications are skipped. Every evaluated block of
+    /// `low_degree + 1` degrees must contain degree 1 term, thus columns may include one more degree than required.
+    pub fn columns_for_degree(&self, degree: usize) -> usize {
+        let mut columns = degree.max(1) + 1;
+        if (columns - 1) % (self.low_degree + 1) == 0 {
+            columns += 1;
+        }
+        columns.min(self.total_degree + 1)
+    }
+
     pub fn low_degree(&self) -> usize {
         self.low_degree
     }
@@ -44,28 +134,239 @@ impl PSParams {
         &self.powers
     }
 
+    /// Degree of evaluated polynomials, thus each polynomial has `total_degree + 1` coefficients
+    pub fn total_degree(&self) -> usize {
+        self.total_degree
+    }
+
     pub fn eval_degree(&self) -> EvalPolyDegree {
         EvalPolyDegree(self.total_degree as u32)
     }
 }
 
-pub fn ps_evaluate_poly(
+/// Returns source powers of `window_bits` bit windows required to calculate `target_powers`, ie `j * 2^(window_bits * i)`
+/// for every non zero digit `j` at position `i` of any target power written in base `2^window_bits`. Every target power
+/// is product of the source powers of its digits.
+pub fn windowed_source_powers(target_powers: &[usize], window_bits: u32) -> Vec<usize> {
+    target_powers
+        .iter()
+        .flat_map(|power| window_digits(*power, window_bits))
+        .unique()
+        .sorted()
+        .collect()
+}
+
+/// Max. depth of calculating any of `target_powers` as balanced product of the source powers of its window digits
+pub fn windowed_depth(target_powers: &[usize], window_bits: u32) -> usize {
+    target_powers
+        .iter()
+        .map(|power| {
+            let digits = window_digits(*power, window_bits).len();
+            (usize::BITS - (digits.max(1) - 1).leading_zeros()) as usize
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Returns non zero digits of `power` in base `2^window_bits`, each multiplied with its place value
+fn window_digits(power: usize, window_bits: u32) -> Vec<usize> {
+    assert!(window_bits > 0 && window_bits < usize::BITS);
+    let mask = (1usize << window_bits) - 1;
+    (0..usize::BITS)
+        .step_by(window_bits as usize)
+        .map(|shift| power & (mask << shift))
+        .filter(|digit| *digit != 0)
+        .collect()
+}
+
+/// Greedily picks source powers from which all `target_powers` can be calculated within `max_depth`. Whenever a target
+/// power can't be calculated, adds the source power after which most target powers (in order) can be calculated.
+/// Returns source powers and depth of DAG.
+fn search_source_powers(target_powers: &[usize], max_depth: usize) -> (Vec<usize>, usize) {
+    let mut source_powers = vec![1];
+    loop {
+        let (calculable, depth) = dag_depth(&source_powers, target_powers, max_depth);
+        if calculable == target_powers.len() {
+            return (source_powers, depth);
+        }
+
+        let failed = target_powers[calculable];
+        let next = target_powers
+            .iter()
+            .copied()
+            .filter(|p| *p > 1 && *p <= failed && !source_powers.contains(p))
+            .max_by_key(|p| {
+                let mut candidate = source_powers.clone();
+                candidate.push(*p);
+                candidate.sort();
+                // prefer smaller power on ties
+                (
+                    dag_depth(&candidate, target_powers, max_depth).0,
+                    Reverse(*p),
+                )
+            })
+            .unwrap();
+        source_powers.push(next);
+        source_powers.sort();
+    }
+}
+
+/// Returns no. of leading `target_powers` that can be calculated from `source_powers` within `max_depth` and max.
+/// depth among them. Depths are same as of DAG constructed by `construct_dag`.
+fn dag_depth(source_powers: &[usize], target_powers: &[usize], max_depth: usize) -> (usize, usize) {
+    let max_power = target_powers
+        .iter()
+        .chain(source_powers.iter())
+        .max()
+        .copied()
+        .unwrap_or(0);
+    let mut depths: Vec<Option<usize>> = vec![None; max_power + 1];
+    source_powers.iter().for_each(|p| depths[*p] = Some(0));
+
+    let mut dag_depth = 0;
+    for (index, target) in target_powers.iter().enumerate() {
+        let depth = match depths[*target] {
+            Some(depth) => Some(depth),
+            None => {
+                let depth = target_powers
+                    .iter()
+                    .take_while(|s1| *s1 < target)
+                    .filter_map(|s1| Some(depths[*s1]?.max(depths[target - s1]?) + 1))
+                    .min();
+                depths[*target] = depth;
+                depth
+            }
+        };
+
+        match depth {
+            Some(depth) if depth <= max_depth => dag_depth = dag_depth.max(depth),
+            _ => return (index, dag_depth),
+        }
+    }
+
+    (target_powers.len(), dag_depth)
+}
+
+/// Coefficients of polynomial evaluated with Paterson-Stockmeyer
+#[derive(Clone, Copy)]
+enum PsCoefficients<'a, T> {
+    /// Coefficients are encoded as plaintexts at `level` while evaluating
+    Raw(ArrayView2<'a, T>, usize),
+    /// Plaintexts encoded in advance with `ps_encode_coefficients`
+    Encoded(&'a [Plaintext]),
+}
+
+/// Encodes coefficients of `degree` as plaintext at `level`. Coefficients at degrees multiple of `low_degree + 1` are
+/// added to ciphertext in Evaluation representation, rest are multiplied with ciphertexts.
+fn encode_coefficient<T: ModqElement>(
+    evaluator: &Evaluator,
+    ps_params: &PSParams,
+    coefficients: ArrayView2<T>,
+    degree: usize,
+    level: usize,
+) -> Plaintext {
+    let poly_cache = if degree % (ps_params.low_degree + 1) == 0 {
+        bfv::PolyCache::AddSub(bfv::Representation::Evaluation)
+    } else {
+        bfv::PolyCache::Mul(bfv::PolyType::Q)
+    };
+    let column = coefficients
+        .column(degree)
+        .iter()
+        .map(|c| (*c).into())
+        .collect::<Vec<u64>>();
+    Plaintext::try_encoding_with_parameters(
+        column.as_slice(),
+        evaluator.params(),
+        Encoding::simd(level, poly_cache),
+    )
+}
+
+/// Asserts that `coefficients` has a row per slot and atmost `total_degree + 1` columns, see
+/// `PSParams::columns_for_degree`
+fn assert_coefficients_shape<T>(
+    evaluator: &Evaluator,
+    ps_params: &PSParams,
+    coefficients: ArrayView2<T>,
+) {
+    assert_eq!(coefficients.shape()[0], evaluator.params().degree);
+    assert!(coefficients.shape()[1] > 1 && coefficients.shape()[1] <= ps_params.total_degree + 1);
+}
+
+/// Encodes all coefficient columns as plaintexts at `level` for `ps_evaluate_encoded_poly`. Encoding is
+/// independent of query, thus can be done once at preprocessing at the cost of memory.
+pub fn ps_encode_coefficients<T: ModqElement>(
+    evaluator: &Evaluator,
+    ps_params: &PSParams,
+    coefficients: ArrayView2<T>,
+    level: usize,
+) -> Vec<Plaintext> {
+    assert_coefficients_shape(evaluator, ps_params, coefficients);
+
+    (0..coefficients.shape()[1])
+        .map(|degree| encode_coefficient(evaluator, ps_params, coefficients, degree, level))
+        .collect()
+}
+
+pub fn ps_evaluate_poly<T: ModqElement>(
     evalutor: &Evaluator,
     ek: &EvaluationKey,
     x_powers: &HashMap<usize, Ciphertext>,
     ps_params: &PSParams,
-    coefficients: &Array2<u32>,
+    coefficients: ArrayView2<T>,
     level: usize,
 ) -> Ciphertext {
     // validate coefficients are well formed for interpolation
-    assert_eq!(
-        coefficients.shape(),
-        [evalutor.params().degree, ps_params.total_degree + 1]
+    assert_coefficients_shape(evalutor, ps_params, coefficients);
+
+    ps_evaluate(
+        evalutor,
+        ek,
+        x_powers,
+        ps_params,
+        PsCoefficients::Raw(coefficients, level),
+        coefficients.shape()[1] - 1,
+    )
+}
+
+/// Same as `ps_evaluate_poly` but with coefficients already encoded with `ps_encode_coefficients` at level of `x_powers`
+pub fn ps_evaluate_encoded_poly(
+    evalutor: &Evaluator,
+    ek: &EvaluationKey,
+    x_powers: &HashMap<usize, Ciphertext>,
+    ps_params: &PSParams,
+    encoded_coefficients: &[Plaintext],
+) -> Ciphertext {
+    assert!(
+        encoded_coefficients.len() > 1 && encoded_coefficients.len() <= ps_params.total_degree + 1
     );
 
+    // element type of raw coefficients is irrelevant once they are encoded
+    ps_evaluate::<u32>(
+        evalutor,
+        ek,
+        x_powers,
+        ps_params,
+        PsCoefficients::Encoded(encoded_coefficients),
+        encoded_coefficients.len() - 1,
+    )
+}
+
+/// Evaluates polynomial of degree atmost `max_degree`. Coefficients beyond `max_degree` are zero, thus their
+/// multiplications, along with multiplications with high powers of outer loop iterations beyond `max_degree`, are
+/// skipped.
+fn ps_evaluate<T: ModqElement>(
+    evalutor: &Evaluator,
+    ek: &EvaluationKey,
+    x_powers: &HashMap<usize, Ciphertext>,
+    ps_params: &PSParams,
+    coefficients: PsCoefficients<T>,
+    max_degree: usize,
+) -> Ciphertext {
     let high_degree = ps_params.low_degree + 1;
     let inner_loop_count = high_degree;
-    let outer_loop_count = ps_params.total_degree / (ps_params.low_degree + 1);
+    let outer_loop_count =
+        (ps_params.total_degree / (ps_params.low_degree + 1)).min(max_degree / high_degree);
     let mut outer_sum = Ciphertext::placeholder();
     let mut first_inner_sum = Ciphertext::placeholder();
 
@@ -74,36 +375,42 @@ pub fn ps_evaluate_poly(
         for k in 1..inner_loop_count {
             let degree = m * inner_loop_count + k;
 
-            if degree > ps_params.total_degree {
+            if degree > max_degree {
                 break;
             }
 
-            let pt = Plaintext::try_encoding_with_parameters(
-                coefficients.column(degree),
-                evalutor.params(),
-                Encoding::simd(level, bfv::PolyCache::Mul(bfv::PolyType::Q)),
-            );
+            let encoded_pt;
+            let pt = match coefficients {
+                PsCoefficients::Raw(coefficients, level) => {
+                    encoded_pt =
+                        encode_coefficient(evalutor, ps_params, coefficients, degree, level);
+                    &encoded_pt
+                }
+                PsCoefficients::Encoded(pts) => &pts[degree],
+            };
 
             let op1 = x_powers.get(&k).unwrap();
 
             if k == 1 {
-                inner_sum = evalutor.mul_plaintext(op1, &pt);
+                inner_sum = evalutor.mul_plaintext(op1, pt);
             } else {
-                evalutor.add_assign(&mut inner_sum, &evalutor.mul_plaintext(op1, &pt));
+                evalutor.add_assign(&mut inner_sum, &evalutor.mul_plaintext(op1, pt));
             }
         }
 
         // add constant (ie inner degree 0)
-        if m * inner_loop_count <= ps_params.total_degree {
-            let pt = Plaintext::try_encoding_with_parameters(
-                coefficients.column(m * inner_loop_count),
-                evalutor.params(),
-                Encoding::simd(
-                    level,
-                    bfv::PolyCache::AddSub(bfv::Representation::Evaluation),
-                ),
-            );
-            evalutor.add_assign_plaintext(&mut inner_sum, &pt);
+        let degree = m * inner_loop_count;
+        if degree <= max_degree {
+            let encoded_pt;
+            let pt = match coefficients {
+                PsCoefficients::Raw(coefficients, level) => {
+                    encoded_pt =
+                        encode_coefficient(evalutor, ps_params, coefficients, degree, level);
+                    &encoded_pt
+                }
+                PsCoefficients::Encoded(pts) => &pts[degree],
+            };
+            evalutor.add_assign_plaintext(&mut inner_sum, pt);
         }
 
         if m == 0 {
@@ -124,6 +431,11 @@ pub fn ps_evaluate_poly(
         }
     }
 
+    // polynomial has no high degree terms
+    if outer_loop_count == 0 {
+        return first_inner_sum;
+    }
+
     let mut outer_sum = evalutor.scale_and_round(&mut outer_sum);
     outer_sum = evalutor.relinearize(&outer_sum, &ek);
 
@@ -140,8 +452,10 @@ mod tests {
 
     use crate::{
         client::calculate_source_powers,
+        optimize_dag,
         poly_interpolate::{evaluate_poly, newton_interpolate},
-        utils::{bfv_setup_test, calculate_ps_powers_with_dag, construct_dag},
+        utils::{bfv_setup_test, calculate_ps_powers_with_dag, construct_dag, uncomputable_power},
+        DagStrategy,
     };
 
     use super::*;
@@ -170,7 +484,7 @@ mod tests {
                 y.push(rng.gen::<u32>() % modq);
             }
         }
-        let coeffs = newton_interpolate(&x, &y, modq);
+        let coeffs = newton_interpolate(&x, &y, modq).unwrap();
 
         // turns coefficients into 2D array just like InnerBox
         let mut coefficients_2d = Array2::zeros((evaluator.params().degree, data_points_count));
@@ -227,7 +541,7 @@ mod tests {
             &ek,
             &target_power_cts,
             &ps_params,
-            &coefficients_2d,
+            coefficients_2d.view(),
             1,
         );
 
@@ -239,5 +553,99 @@ mod tests {
         let expected_evaluated_res = evaluate_poly(x_input, &coeffs, modq);
 
         assert_eq!(evaluated_res[0] as u32, expected_evaluated_res);
+
+        // Evaluate with coefficients encoded in advance
+        let encoded_coefficients =
+            ps_encode_coefficients(&evaluator, &ps_params, coefficients_2d.view(), 1);
+        let evaluated_ct = ps_evaluate_encoded_poly(
+            &evaluator,
+            &ek,
+            &target_power_cts,
+            &ps_params,
+            &encoded_coefficients,
+        );
+        let evaluated_res =
+            evaluator.plaintext_decode(&evaluator.decrypt(&sk, &evaluated_ct), Encoding::default());
+        assert_eq!(evaluated_res[0] as u32, expected_evaluated_res);
+
+        // Evaluate polynomials of lower degree with coefficients upto their degree only, with and without high degree
+        // terms
+        for points in [3, 2 * (ps_params.low_degree + 1) + 3] {
+            let coeffs = newton_interpolate(&x[..points], &y[..points], modq).unwrap();
+            let columns = ps_params.columns_for_degree(points - 1);
+            assert!(columns < ps_params.total_degree + 1);
+            let mut coefficients_2d = Array2::zeros((evaluator.params().degree, columns));
+            coefficients_2d.row_mut(0).as_slice_mut().unwrap()[..points].copy_from_slice(&coeffs);
+
+            let evaluated_ct = ps_evaluate_poly(
+                &evaluator,
+                &ek,
+                &target_power_cts,
+                &ps_params,
+                coefficients_2d.view(),
+                1,
+            );
+            let evaluated_res = evaluator
+                .plaintext_decode(&evaluator.decrypt(&sk, &evaluated_ct), Encoding::default());
+            assert_eq!(
+                evaluated_res[0] as u32,
+                evaluate_poly(x_input, &coeffs, modq)
+            );
+        }
+    }
+
+    #[test]
+    fn ps_optimize_works() {
+        for (total_degree, available_levels) in [(63, 3), (575, 4), (1304, 4)] {
+            let (ps_params, source_powers) = PSParams::optimize(total_degree, available_levels);
+            assert_eq!(ps_params.total_degree, total_degree);
+
+            let dag = construct_dag(&source_powers, ps_params.powers());
+            assert!(uncomputable_power(&dag, ps_params.powers()).is_none());
+
+            let (calculable, depth) =
+                dag_depth(&source_powers, ps_params.powers(), available_levels - 1);
+            assert_eq!(calculable, ps_params.powers().len());
+            assert!(depth < available_levels);
+        }
+
+        // no worse than hand picked parameters
+        let (ps_params, source_powers) = PSParams::optimize(1304, 4);
+        let hand_picked = PSParams::new(44, 1304);
+        assert!(source_powers.len() <= 6);
+        assert!(
+            ps_params.powers().len() - source_powers.len() + 1304 / (ps_params.low_degree + 1)
+                <= hand_picked.powers().len() - 6 + 1304 / 45
+        );
+    }
+
+    #[test]
+    fn windowed_source_powers_works() {
+        // 13 = 0b1101 = 1 + 12 in base 4
+        assert_eq!(window_digits(13, 2), vec![1, 12]);
+        assert_eq!(
+            windowed_source_powers(&[1, 2, 3, 13, 20], 2),
+            vec![1, 2, 3, 4, 12, 16]
+        );
+        assert_eq!(windowed_depth(&[1, 2, 3], 2), 0);
+        assert_eq!(windowed_depth(&[13, 20], 2), 1);
+        assert_eq!(windowed_depth(&[21], 2), 2);
+
+        for (total_degree, available_levels) in [(63, 2), (575, 2), (1304, 2)] {
+            let (ps_params, source_powers, window_bits) =
+                PSParams::optimize_windowed(total_degree, available_levels);
+            assert_eq!(ps_params.total_degree, total_degree);
+            let depth = windowed_depth(ps_params.powers(), window_bits);
+            assert!(depth < available_levels);
+
+            let dag = optimize_dag(
+                &source_powers,
+                ps_params.powers(),
+                DagStrategy::MinDepth,
+                0.0,
+            )
+            .unwrap();
+            assert!(dag.cost().depth <= depth);
+        }
     }
 }
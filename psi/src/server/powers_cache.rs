@@ -0,0 +1,169 @@
+use crate::ClientId;
+use bfv::Ciphertext;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// PS powers of a segment calculated from its query ciphertext powers
+pub type SegmentPowers = Arc<HashMap<usize, Ciphertext>>;
+
+/// Hash of id of client that sent the query and of serialized query ciphertext powers of a single segment. Powers are
+/// calculated with client's evaluation key, thus the same ciphertexts sent by another client don't share powers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PowersKey([u8; 32]);
+
+impl PowersKey {
+    /// `ct_digests` are hashes of serialized query ciphertexts as received by server, see `Query::powers_key`
+    pub fn new(client_id: &ClientId, ct_digests: &[[u8; 32]]) -> PowersKey {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(&client_id.0);
+        ct_digests.iter().for_each(|digest| {
+            hasher.update(digest);
+        });
+        PowersKey(*hasher.finalize().as_bytes())
+    }
+}
+
+/// PS powers of recently queried segments, keyed by `PowersKey`. A client re-submitting an identical query, for ex.
+/// retrying after a network failure, skips calculating powers (see `BigBox::process_segment_query`). Only powers of
+/// queries deserialized by server and bound to their client with `Query::with_client_id` are cached.
+///
+/// Holds powers of at most `capacity` segments and evicts the least recently used. Powers of a single segment take as
+/// much memory as all PS powers ciphertexts, thus each query adds `QueryLayout::segments` entries.
+///
+/// Powers don't depend on server's db, thus entries remain valid when db is swapped.
+pub struct PowersCache {
+    capacity: usize,
+    inner: Mutex<PowersCacheInner>,
+}
+
+struct PowersCacheInner {
+    powers: HashMap<PowersKey, SegmentPowers>,
+    /// Keys in order of use, least recently used first
+    order: VecDeque<PowersKey>,
+    hits: u64,
+    misses: u64,
+}
+
+impl PowersCache {
+    pub fn new(capacity: usize) -> PowersCache {
+        assert!(capacity > 0);
+        PowersCache {
+            capacity,
+            inner: Mutex::new(PowersCacheInner {
+                powers: HashMap::new(),
+                order: VecDeque::new(),
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    /// Returns powers cached under `key`, otherwise caches and returns powers returned by `calculate`. Lock isn't held
+    /// while `calculate` runs, thus concurrent misses of the same key calculate powers more than once.
+    pub fn get_or_insert_with(
+        &self,
+        key: PowersKey,
+        calculate: impl FnOnce() -> SegmentPowers,
+    ) -> SegmentPowers {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if let Some(powers) = inner.powers.get(&key).cloned() {
+                inner.hits += 1;
+                inner.order.retain(|k| *k != key);
+                inner.order.push_back(key);
+                return powers;
+            }
+            inner.misses += 1;
+        }
+
+        let powers = calculate();
+        self.insert(key, powers.clone());
+        powers
+    }
+
+    fn insert(&self, key: PowersKey, powers: SegmentPowers) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.powers.insert(key, powers).is_some() {
+            inner.order.retain(|k| *k != key);
+        }
+        inner.order.push_back(key);
+
+        while inner.order.len() > self.capacity {
+            let evicted = inner.order.pop_front().unwrap();
+            inner.powers.remove(&evicted);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().powers.len()
+    }
+
+    /// No. of segments whose powers were found in the cache
+    pub fn hits(&self) -> u64 {
+        self.inner.lock().unwrap().hits
+    }
+
+    /// No. of segments whose powers were calculated
+    pub fn misses(&self) -> u64 {
+        self.inner.lock().unwrap().misses
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bfv::Encoding;
+    use rand::thread_rng;
+
+    use crate::utils::bfv_setup_test;
+
+    use super::*;
+
+    #[test]
+    fn powers_cache_evicts_least_recently_used() {
+        let mut rng = thread_rng();
+        let (evaluator, sk) = bfv_setup_test();
+        let cts = (0..3)
+            .map(|_| {
+                evaluator.encrypt(
+                    &sk,
+                    &evaluator.plaintext_encode(&[], Encoding::default()),
+                    &mut rng,
+                )
+            })
+            .collect::<Vec<_>>();
+        let client_id = ClientId::random(&mut rng);
+        let keys = (0..3u8)
+            .map(|i| PowersKey::new(&client_id, &[[i; 32]]))
+            .collect::<Vec<_>>();
+        assert_eq!(keys[0], PowersKey::new(&client_id, &[[0; 32]]));
+        assert_ne!(keys[0], keys[1]);
+        // same ciphertexts of another client
+        assert_ne!(
+            keys[0],
+            PowersKey::new(&ClientId::random(&mut rng), &[[0; 32]])
+        );
+
+        let powers =
+            |ct: &Ciphertext| -> SegmentPowers { Arc::new(HashMap::from([(1, ct.clone())])) };
+        let cache = PowersCache::new(2);
+        cache.get_or_insert_with(keys[0], || powers(&cts[0]));
+        cache.get_or_insert_with(keys[1], || powers(&cts[1]));
+        // hit moves key to the back
+        let hit = cache.get_or_insert_with(keys[0], || unreachable!());
+        assert_eq!(hit[&1], cts[0]);
+        cache.get_or_insert_with(keys[2], || powers(&cts[2]));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!((cache.hits(), cache.misses()), (1, 3));
+        cache.get_or_insert_with(keys[0], || unreachable!());
+        cache.get_or_insert_with(keys[2], || unreachable!());
+        let mut calculated = false;
+        cache.get_or_insert_with(keys[1], || {
+            calculated = true;
+            powers(&cts[1])
+        });
+        assert!(calculated);
+    }
+}
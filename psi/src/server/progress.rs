@@ -0,0 +1,102 @@
+/// Long running stage of server setup reported to `ProgressSink`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum SetupStage {
+    /// Inserting ItemLabels into BigBoxes. A unit of work is an ItemLabel inserted into a single BigBox.
+    Insert,
+    /// Generating polynomial coefficients. A unit of work is a single InnerBox.
+    Preprocess,
+}
+
+impl std::fmt::Display for SetupStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SetupStage::Insert => write!(f, "Inserting"),
+            SetupStage::Preprocess => write!(f, "Preprocessing"),
+        }
+    }
+}
+
+/// Receives progress of `Server::setup_with_progress`, `Db::insert_many_with_progress` and
+/// `Db::preprocess_with_progress`, for ex. to draw a progress bar with ETA.
+///
+/// Work is spread across rayon's threads, thus `advance` may be called concurrently.
+pub trait ProgressSink: Send + Sync {
+    /// Called once before `stage` starts with total no. of units of work in `stage`
+    fn start(&self, stage: SetupStage, total: u64);
+
+    /// Called whenever `units` of work of `stage` are done
+    fn advance(&self, stage: SetupStage, units: u64);
+
+    /// Called once after all work of `stage` is done
+    fn finish(&self, stage: SetupStage);
+}
+
+/// `ProgressSink` that ignores progress
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoProgress;
+
+impl ProgressSink for NoProgress {
+    fn start(&self, _stage: SetupStage, _total: u64) {}
+
+    fn advance(&self, _stage: SetupStage, _units: u64) {}
+
+    fn finish(&self, _stage: SetupStage) {}
+}
+
+/// No. of ItemLabels BigBox inserts before reporting them to `ProgressSink`
+pub(crate) const INSERT_PROGRESS_CHUNK: usize = 10_000;
+
+#[cfg(test)]
+mod tests {
+    use itertools::Itertools;
+    use rand::thread_rng;
+    use std::{collections::HashMap, sync::Mutex};
+
+    use crate::{random_u256, Db, ItemLabel, PsiParams};
+
+    use super::*;
+
+    /// Records total and units done of each stage
+    #[derive(Default)]
+    struct RecordingSink(Mutex<HashMap<SetupStage, (u64, u64, bool)>>);
+
+    impl ProgressSink for RecordingSink {
+        fn start(&self, stage: SetupStage, total: u64) {
+            self.0.lock().unwrap().insert(stage, (total, 0, false));
+        }
+
+        fn advance(&self, stage: SetupStage, units: u64) {
+            self.0.lock().unwrap().get_mut(&stage).unwrap().1 += units;
+        }
+
+        fn finish(&self, stage: SetupStage) {
+            self.0.lock().unwrap().get_mut(&stage).unwrap().2 = true;
+        }
+    }
+
+    #[test]
+    fn progress_sink_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let mut db = Db::new(&psi_params);
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+
+        let sink = RecordingSink::default();
+        assert!(db.insert_many_with_progress(&item_labels, &sink).is_empty());
+        db.preprocess_with_progress(&sink).unwrap();
+
+        let stages = sink.0.into_inner().unwrap();
+        let big_boxes = psi_params.no_of_hash_tables as u64;
+        assert_eq!(
+            stages[&SetupStage::Insert],
+            (100 * big_boxes, 100 * big_boxes, true)
+        );
+        let inner_boxes = db.inner_boxes_count() as u64;
+        assert_eq!(
+            stages[&SetupStage::Preprocess],
+            (inner_boxes, inner_boxes, true)
+        );
+    }
+}
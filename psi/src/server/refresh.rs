@@ -0,0 +1,104 @@
+use crate::{ItemStore, PsiError, Server};
+use std::{
+    sync::{
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+use tracing::{error, info};
+
+/// Handle of background thread refreshing server's db, returned by `Server::schedule_refresh`. Dropping the handle
+/// stops refreshes without waiting for a refresh in progress, which still swaps its db in.
+pub struct RefreshHandle {
+    stop: mpsc::Sender<()>,
+    thread: JoinHandle<()>,
+}
+
+impl RefreshHandle {
+    pub(crate) fn spawn(
+        server: Arc<Server>,
+        interval: Duration,
+        source: Box<dyn ItemStore + Send>,
+    ) -> Result<RefreshHandle, PsiError> {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("psi-refresh".to_string())
+            .spawn(move || {
+                // sender is dropped, or sends, once refreshes are stopped
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    info!("Refreshing db");
+                    match server.refresh_from_store(source.as_ref()) {
+                        Ok(db_version) => info!(db_version, "Db refreshed"),
+                        Err(e) => error!("Failed to refresh db: {e}"),
+                    }
+                }
+            })
+            .map_err(|e| PsiError::Io(format!("Failed to spawn refresh thread: {e}")))?;
+        Ok(RefreshHandle { stop, thread })
+    }
+
+    /// Stops refreshes and waits for a refresh in progress, if any, to finish
+    pub fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bfv::{Evaluator, SecretKey};
+    use rand::thread_rng;
+
+    use crate::{
+        construct_query, gen_bfv_params, gen_random_item_labels, generate_evaluation_key,
+        process_query_response, PsiParams,
+    };
+
+    use super::*;
+
+    #[test]
+    fn scheduled_refresh_swaps_in_store() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let mut server = Server::new(&psi_params);
+        server.setup(&gen_random_item_labels(100, None)).unwrap();
+        let server = Arc::new(server);
+
+        let item_labels = gen_random_item_labels(100, None);
+        let refresh = server
+            .schedule_refresh(Duration::from_millis(10), Box::new(item_labels.clone()))
+            .unwrap();
+        let db_version = server.db_version();
+        while server.db_version() == db_version {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        refresh.stop();
+
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let item_label = &item_labels[0];
+        let query_state = construct_query(
+            &[*item_label.item()],
+            &psi_params,
+            &evaluator,
+            &sk,
+            &mut rng,
+        );
+        let response = server.query(query_state.query(), &ek).unwrap();
+        let potential_labels = process_query_response(
+            &psi_params,
+            query_state.hash_tables(),
+            &evaluator,
+            &sk,
+            &response,
+        );
+        let labels = potential_labels
+            .iter()
+            .find(|labels| labels.item() == item_label.item())
+            .unwrap();
+        assert!(labels.labels().contains(item_label.label()));
+    }
+}
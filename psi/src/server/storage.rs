@@ -0,0 +1,950 @@
+use crate::{
+    archive_db, unarchive_db, CoefficientsView, Db, ProgressSink, PsiError, PsiParams, SetupStage,
+    ENCRYPTED_DB_FILE_MAGIC,
+};
+use itertools::{izip, Itertools};
+use memmap2::Mmap;
+use ndarray::ArrayView2;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    ops::Range,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+
+/// Magic at start of db files stored with `Db::store`
+pub const DB_FILE_MAGIC: [u8; 8] = *b"ULPSI-DB";
+/// Bumped whenever layout of db file changes
+pub const DB_FILE_VERSION: u32 = 7;
+/// Version of db files whose db section is serialized with bincode instead of archived with rkyv. These are still
+/// loaded, but slowly. See `Db::load`.
+pub const BINCODE_DB_FILE_VERSION: u32 = 6;
+/// magic (8 bytes) || version (u32 LE) || digest of PsiParams (32 bytes) || xxh3 checksum of rest of file (u64 LE) ||
+/// length of db section (u64 LE)
+const DB_FILE_HEADER_BYTES: usize = 60;
+/// Coefficients section starts at a multiple of this many bytes
+const COEFFICIENTS_ALIGNMENT: usize = 64;
+
+/// Shape of polynomial coefficients of a single InnerBox. All zeros if InnerBox hasn't been preprocessed.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    rkyv::Archive,
+    rkyv::Serialize,
+    rkyv::Deserialize,
+)]
+#[archive(check_bytes)]
+pub(crate) struct CoefficientsShape {
+    /// No. of label parts, each with its own coefficients matrix
+    parts: u32,
+    rows: u32,
+    cols: u32,
+    /// Bytes of each coefficient, see `PsiParams::coefficient_bytes`
+    value_bytes: u32,
+}
+
+impl CoefficientsShape {
+    fn of(coefficients: &[CoefficientsView]) -> CoefficientsShape {
+        match coefficients.first() {
+            Some(c) => CoefficientsShape {
+                parts: coefficients.len() as u32,
+                rows: c.shape()[0] as u32,
+                cols: c.shape()[1] as u32,
+                value_bytes: c.value_bytes(),
+            },
+            None => CoefficientsShape::default(),
+        }
+    }
+
+    fn values_per_part(&self) -> usize {
+        self.rows as usize * self.cols as usize
+    }
+
+    /// Returns None on overflow, which is only possible for malformed db files
+    fn bytes(&self) -> Option<usize> {
+        (self.rows as usize)
+            .checked_mul(self.cols as usize)?
+            .checked_mul(self.parts as usize)?
+            .checked_mul(self.value_bytes as usize)
+    }
+}
+
+/// Partition `index` of `count` partitions of InnerBoxes of a db, for preprocessing a db across multiple processes or
+/// machines (see `Db::preprocess_partition_and_store`). InnerBoxes of all BigBoxes, in order, are split into `count`
+/// contiguous ranges of nearly equal size. Parsed from and displayed as `index/count`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Partition {
+    index: usize,
+    count: usize,
+}
+
+impl Partition {
+    pub fn new(index: usize, count: usize) -> Result<Partition, PsiError> {
+        if index >= count {
+            return Err(PsiError::InvalidParams(format!(
+                "Partition {index} is out of range of {count} partitions"
+            )));
+        }
+        Ok(Partition { index, count })
+    }
+
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Range of indices of InnerBoxes in this partition out of `inner_boxes` InnerBoxes
+    pub fn inner_boxes(&self, inner_boxes: usize) -> Range<usize> {
+        self.index * inner_boxes / self.count..(self.index + 1) * inner_boxes / self.count
+    }
+}
+
+impl FromStr for Partition {
+    type Err = PsiError;
+
+    fn from_str(s: &str) -> Result<Partition, PsiError> {
+        let invalid =
+            || PsiError::InvalidParams(format!("Expected partition as index/count, got {s}"));
+        let (index, count) = s.split_once('/').ok_or_else(invalid)?;
+        Partition::new(
+            index.trim().parse().map_err(|_| invalid())?,
+            count.trim().parse().map_err(|_| invalid())?,
+        )
+    }
+}
+
+impl std::fmt::Display for Partition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+/// Polynomial coefficients of an InnerBox read in place from memory mapped db file
+pub(crate) struct MappedCoefficients {
+    mmap: Arc<Mmap>,
+    /// Offset of coefficients of first label part in `mmap`. Coefficients of each label part follow one another.
+    offset: usize,
+    shape: CoefficientsShape,
+}
+
+impl MappedCoefficients {
+    /// Returns coefficients of each label part without copying them
+    pub(crate) fn views(&self) -> Vec<CoefficientsView<'_>> {
+        let part_bytes = self.shape.values_per_part() * self.shape.value_bytes as usize;
+        let shape = (self.shape.rows as usize, self.shape.cols as usize);
+        (0..self.shape.parts as usize)
+            .map(|part| {
+                let bytes = &self.mmap[self.offset + part * part_bytes..][..part_bytes];
+                // Safety: any bit pattern is a valid u16 or u32. Mapping is page aligned and coefficients of each part
+                // start at multiple of 2 or 4 bytes respectively, which is checked anyways.
+                match self.shape.value_bytes {
+                    2 => CoefficientsView::U16(view_as(unsafe { bytes.align_to::<u16>() }, shape)),
+                    _ => CoefficientsView::U32(view_as(unsafe { bytes.align_to::<u32>() }, shape)),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Returns values of `bytes` split with `align_to` as matrix of `shape`. Panics if `bytes` weren't aligned.
+fn view_as<'a, T>(
+    (prefix, values, suffix): (&[u8], &'a [T], &[u8]),
+    shape: (usize, usize),
+) -> ArrayView2<'a, T> {
+    assert!(
+        prefix.is_empty() && suffix.is_empty(),
+        "Misaligned coefficients"
+    );
+    ArrayView2::from_shape(shape, values).expect("Shape is checked when db is loaded")
+}
+
+fn align_up(offset: usize) -> usize {
+    (offset + COEFFICIENTS_ALIGNMENT - 1) / COEFFICIENTS_ALIGNMENT * COEFFICIENTS_ALIGNMENT
+}
+
+pub(crate) fn malformed(reason: &str) -> PsiError {
+    PsiError::Serialization(format!("Malformed db file: {reason}"))
+}
+
+pub(crate) fn params_mismatch() -> PsiError {
+    PsiError::ParamsMismatch("Db file was stored with different PsiParams".to_string())
+}
+
+/// Returns header of db file stored with `psi_params`, db section of `db_len` bytes and rest of the file with
+/// `checksum`
+fn file_header(psi_params: &PsiParams, checksum: u64, db_len: u64) -> [u8; DB_FILE_HEADER_BYTES] {
+    let mut header = [0u8; DB_FILE_HEADER_BYTES];
+    header[..8].copy_from_slice(&DB_FILE_MAGIC);
+    header[8..12].copy_from_slice(&DB_FILE_VERSION.to_le_bytes());
+    header[12..44].copy_from_slice(&psi_params.digest());
+    header[44..52].copy_from_slice(&checksum.to_le_bytes());
+    header[52..60].copy_from_slice(&db_len.to_le_bytes());
+    header
+}
+
+/// Offset at which db section starts in db file of `version`. Archived db section starts at an aligned offset.
+fn db_section_start(version: u32) -> usize {
+    if version == BINCODE_DB_FILE_VERSION {
+        DB_FILE_HEADER_BYTES
+    } else {
+        align_up(DB_FILE_HEADER_BYTES)
+    }
+}
+
+/// Validates header of memory mapped db file and returns its version along with range of db section. Returns
+/// `PsiError::ParamsMismatch` if db file was stored with params other than `psi_params`, before anything else is read.
+/// Checksum is checked separately by `verify_checksum`.
+fn parse_header(mmap: &[u8], psi_params: &PsiParams) -> Result<(u32, Range<usize>), PsiError> {
+    if cfg!(target_endian = "big") {
+        return Err(PsiError::Serialization(
+            "Memory mapped db requires little endian platform".to_string(),
+        ));
+    }
+    if mmap.len() < DB_FILE_HEADER_BYTES || !mmap.starts_with(&DB_FILE_MAGIC) {
+        return Err(malformed("truncated header"));
+    }
+    let version = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+    if version != DB_FILE_VERSION && version != BINCODE_DB_FILE_VERSION {
+        return Err(PsiError::Serialization(format!(
+            "Unsupported db file version {version}, expected {DB_FILE_VERSION}"
+        )));
+    }
+    if mmap[12..44] != psi_params.digest() {
+        return Err(params_mismatch());
+    }
+    let db_start = db_section_start(version);
+    let db_len = u64::from_le_bytes(mmap[52..60].try_into().unwrap());
+    let db_end = usize::try_from(db_len)
+        .ok()
+        .and_then(|db_len| db_start.checked_add(db_len))
+        .filter(|db_end| *db_end <= mmap.len())
+        .ok_or(malformed("truncated db section"))?;
+    Ok((version, db_start..db_end))
+}
+
+/// Checks xxh3 checksum in header of db file against rest of the file. Reads the entire file.
+fn verify_checksum(mmap: &[u8]) -> Result<(), PsiError> {
+    let checksum = u64::from_le_bytes(mmap[44..52].try_into().unwrap());
+    if xxh3_64(&mmap[DB_FILE_HEADER_BYTES..]) != checksum {
+        return Err(malformed("checksum mismatch"));
+    }
+    Ok(())
+}
+
+/// Writer that computes xxh3 checksum of all bytes written to `inner`
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Xxh3,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> ChecksumWriter<W> {
+        ChecksumWriter {
+            inner,
+            hasher: Xxh3::new(),
+        }
+    }
+
+    /// Returns `inner` and checksum of bytes written to it
+    fn finish(self) -> (W, u64) {
+        (self.inner, self.hasher.digest())
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes coefficients as little endian u16s or u32s, row by row
+fn write_coefficients<W: Write>(
+    writer: &mut W,
+    coefficients: &[CoefficientsView],
+) -> Result<(), PsiError> {
+    for c in coefficients {
+        match c {
+            CoefficientsView::U16(c) => {
+                for row in c.rows() {
+                    let bytes = row.iter().flat_map(|v| v.to_le_bytes()).collect_vec();
+                    writer.write_all(&bytes)?;
+                }
+            }
+            CoefficientsView::U32(c) => {
+                for row in c.rows() {
+                    let bytes = row.iter().flat_map(|v| v.to_le_bytes()).collect_vec();
+                    writer.write_all(&bytes)?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns version of db file at `path` stored with `Db::store`, or `None` if it was stored with plain bincode or is
+/// encrypted
+pub fn db_file_version(path: &Path) -> Result<Option<u32>, PsiError> {
+    let (_, mmap) = map_file(path)?;
+    if mmap.len() < DB_FILE_HEADER_BYTES || !mmap.starts_with(&DB_FILE_MAGIC) {
+        return Ok(None);
+    }
+    Ok(Some(u32::from_le_bytes(mmap[8..12].try_into().unwrap())))
+}
+
+/// Opens file at `path` and maps it into memory
+fn map_file(path: &Path) -> Result<(File, Mmap), PsiError> {
+    let file = File::open(path)
+        .map_err(|e| PsiError::Io(format!("Failed to open {}: {e}", path.display())))?;
+    // Safety: callers require that the file isn't modified while it is mapped
+    let mmap = unsafe { Mmap::map(&file)? };
+    Ok((file, mmap))
+}
+
+impl Db {
+    /// Stores db at `path` in layout that `Db::load` memory maps: header, db without polynomial coefficients archived
+    /// with rkyv (see `DbRecord`) and, aligned at `COEFFICIENTS_ALIGNMENT`, coefficients of every InnerBox as little
+    /// endian u16s or u32s (see `PsiParams::coefficient_bytes`). Header carries digest of `PsiParams` of db and checksum
+    /// of rest of the file. Db section starts at `COEFFICIENTS_ALIGNMENT` as well, since archive is read in place.
+    ///
+    /// Coefficients are never copied while db is stored. Db must not be stored at the file it is loaded from.
+    pub fn store(&mut self, path: &Path) -> Result<(), PsiError> {
+        let shapes = self.coefficients_shapes();
+        self.create_file(path, &shapes, |db, writer| {
+            for ib in db.inner_boxes() {
+                write_coefficients(writer, &ib.coefficients())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Writes db to `writer` in layout of `Db::store`. `writer` needn't be seekable, thus db is serialized twice: once
+    /// to compute checksum in header and once to `writer`.
+    pub fn write_to<W: Write>(&mut self, writer: &mut W) -> Result<(), PsiError> {
+        let shapes = self.coefficients_shapes();
+        let mut checksum_writer = ChecksumWriter::new(std::io::sink());
+        let db_len = self.write_body(&mut checksum_writer, &shapes)?;
+        let (_, checksum) = checksum_writer.finish();
+        writer.write_all(&file_header(&self.psi_params, checksum, db_len))?;
+        self.write_body(writer, &shapes)?;
+        Ok(())
+    }
+
+    /// Shapes of coefficients of every InnerBox
+    fn coefficients_shapes(&self) -> Vec<CoefficientsShape> {
+        self.inner_boxes()
+            .map(|ib| CoefficientsShape::of(&ib.coefficients()))
+            .collect_vec()
+    }
+
+    /// Writes everything after header: db section and coefficients of every InnerBox. Returns length of db section.
+    fn write_body<W: Write>(
+        &mut self,
+        writer: &mut W,
+        shapes: &[CoefficientsShape],
+    ) -> Result<u64, PsiError> {
+        let db_len = self.write_db_section(writer, shapes)?;
+        for ib in self.inner_boxes() {
+            write_coefficients(writer, &ib.coefficients())?;
+        }
+        Ok(db_len)
+    }
+
+    /// Creates db file at `path` with db section followed by coefficients written by `write_rest`. Header is written
+    /// last, once checksum of rest of the file is known.
+    fn create_file(
+        &mut self,
+        path: &Path,
+        shapes: &[CoefficientsShape],
+        write_rest: impl FnOnce(&mut Db, &mut ChecksumWriter<BufWriter<File>>) -> Result<(), PsiError>,
+    ) -> Result<(), PsiError> {
+        let file = File::create(path)
+            .map_err(|e| PsiError::Io(format!("Failed to create {}: {e}", path.display())))?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(&[0u8; DB_FILE_HEADER_BYTES])?;
+
+        let mut writer = ChecksumWriter::new(writer);
+        let db_len = self.write_db_section(&mut writer, shapes)?;
+        write_rest(self, &mut writer)?;
+        let (mut writer, checksum) = writer.finish();
+
+        writer.seek(SeekFrom::Start(0))?;
+        writer.write_all(&file_header(&self.psi_params, checksum, db_len))?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Same as `preprocess` followed by `store`, except that coefficients of each InnerBox are written to `path` as
+    /// soon as they are generated and dropped from memory. Afterwards coefficients are memory mapped from `path`, same
+    /// as db loaded with `Db::load`. Thus peak memory is that of db without coefficients, instead of db and all its
+    /// coefficients.
+    ///
+    /// InnerBoxes are preprocessed one at a time, each with all threads. Each is reported to `progress` once it is
+    /// written.
+    pub fn preprocess_and_store(
+        &mut self,
+        path: &Path,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        // every InnerBox is regenerated. Drop existing coefficients upfront so that they don't add to peak memory.
+        self.inner_boxes_mut().for_each(|ib| {
+            ib.take_coefficients();
+        });
+        let shapes = self
+            .inner_boxes()
+            .map(|ib| self.coefficients_shape(ib.coefficients_columns() as u32))
+            .collect_vec();
+
+        progress.start(SetupStage::Preprocess, shapes.len() as u64);
+        self.create_file(path, &shapes, |db, writer| {
+            for bb in db.big_boxes.iter_mut() {
+                bb.preprocess_each(|ib| {
+                    write_coefficients(writer, &ib.coefficients())?;
+                    ib.take_coefficients();
+                    progress.advance(SetupStage::Preprocess, 1);
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })?;
+        progress.finish(SetupStage::Preprocess);
+        self.map_stored_coefficients(path, shapes)
+    }
+
+    /// Same as `preprocess_and_store` but only InnerBoxes in `partition` are preprocessed. Db file at `path` holds
+    /// entire db but coefficients of InnerBoxes in `partition` only, thus it is loaded with `Db::load` as a db whose
+    /// other InnerBoxes haven't been preprocessed. Each partition can be preprocessed by a different process, from the
+    /// same server set and params, and partial db files are merged with `Db::merge_partitions`.
+    ///
+    /// Returns `PsiError::InvalidParams` if `PsiParams::oprf` is enabled, since each db has its own random OPRF key.
+    pub fn preprocess_partition_and_store(
+        &mut self,
+        path: &Path,
+        partition: Partition,
+        progress: &dyn ProgressSink,
+    ) -> Result<(), PsiError> {
+        if self.oprf_key.is_some() {
+            return Err(PsiError::InvalidParams(
+                "Db with OPRF enabled can't be preprocessed in partitions".to_string(),
+            ));
+        }
+        self.inner_boxes_mut().for_each(|ib| {
+            ib.take_coefficients();
+        });
+        let range = partition.inner_boxes(self.inner_boxes_count());
+        let shapes = self
+            .inner_boxes()
+            .enumerate()
+            .map(|(index, ib)| match range.contains(&index) {
+                true => self.coefficients_shape(ib.coefficients_columns() as u32),
+                false => CoefficientsShape::default(),
+            })
+            .collect_vec();
+
+        progress.start(SetupStage::Preprocess, range.len() as u64);
+        self.create_file(path, &shapes, |db, writer| {
+            for ib in db.inner_boxes_mut().skip(range.start).take(range.len()) {
+                ib.generate_coefficients()?;
+                write_coefficients(writer, &ib.coefficients())?;
+                ib.take_coefficients();
+                progress.advance(SetupStage::Preprocess, 1);
+            }
+            Ok(())
+        })?;
+        progress.finish(SetupStage::Preprocess);
+        self.map_stored_coefficients(path, shapes)
+    }
+
+    /// Merges partial db files at `partials`, stored with `Db::preprocess_partition_and_store`, into a single db
+    /// file at `path` and returns the merged db, with coefficients memory mapped from `path` as in `Db::load`.
+    /// Coefficients are copied from memory mapped partial db files, thus they are never read into memory at once.
+    ///
+    /// Returns `PsiError::Serialization` if partial dbs hold different server sets or if any InnerBox is preprocessed
+    /// in none or more than one of them. `path` must not be one of `partials`.
+    pub fn merge_partitions(
+        partials: &[PathBuf],
+        path: &Path,
+        psi_params: &PsiParams,
+    ) -> Result<Db, PsiError> {
+        let mut dbs = partials
+            .iter()
+            .map(|partial| Db::load(partial, psi_params))
+            .collect::<Result<Vec<_>, PsiError>>()?;
+        let (base, rest) = dbs
+            .split_first_mut()
+            .ok_or(PsiError::Io("No partial db files to merge".to_string()))?;
+
+        let data_digest = base.data_digest()?;
+        for db in rest.iter() {
+            if db.data_digest()? != data_digest {
+                return Err(malformed("partial dbs hold different server sets"));
+            }
+        }
+
+        // index of partial db holding coefficients of each InnerBox
+        let mut inner_boxes = std::iter::once(&*base)
+            .chain(rest.iter())
+            .map(|db| db.inner_boxes())
+            .collect_vec();
+        let mut sources = vec![];
+        let mut shapes = vec![];
+        for index in 0..base.inner_boxes_count() {
+            let preprocessed = inner_boxes
+                .iter_mut()
+                .map(|ibs| ibs.next().unwrap())
+                .enumerate()
+                .filter(|(_, ib)| ib.has_coefficients())
+                .collect_vec();
+            match preprocessed[..] {
+                [(source, ib)] => {
+                    sources.push(source);
+                    shapes.push(CoefficientsShape::of(&ib.coefficients()));
+                }
+                [] => {
+                    return Err(malformed(&format!(
+                        "InnerBox {index} isn't preprocessed in any partial db"
+                    )))
+                }
+                _ => {
+                    return Err(malformed(&format!(
+                        "InnerBox {index} is preprocessed in more than one partial db"
+                    )))
+                }
+            }
+        }
+        drop(inner_boxes);
+
+        base.create_file(path, &shapes, |base, writer| {
+            let mut inner_boxes = std::iter::once(&*base)
+                .chain(rest.iter())
+                .map(|db| db.inner_boxes())
+                .collect_vec();
+            for source in sources {
+                let ibs = inner_boxes
+                    .iter_mut()
+                    .map(|ibs| ibs.next().unwrap())
+                    .collect_vec();
+                write_coefficients(writer, &ibs[source].coefficients())?;
+            }
+            Ok(())
+        })?;
+
+        let mut db = dbs.swap_remove(0);
+        db.map_stored_coefficients(path, shapes)?;
+        Ok(db)
+    }
+
+    /// Digest of db without coefficients, ie of its items and labels, layout and params
+    fn data_digest(&self) -> Result<u64, PsiError> {
+        let shapes = vec![CoefficientsShape::default(); self.inner_boxes_count()];
+        Ok(xxh3_64(&archive_db(self, &shapes)?))
+    }
+
+    /// Maps coefficients, with shape in `shapes`, of db file at `path` just stored by `create_file`
+    fn map_stored_coefficients(
+        &mut self,
+        path: &Path,
+        shapes: Vec<CoefficientsShape>,
+    ) -> Result<(), PsiError> {
+        let (_, mmap) = map_file(path)?;
+        let (_, db_section) = parse_header(&mmap, &self.psi_params)?;
+        self.map_coefficients(Arc::new(mmap), align_up(db_section.end), shapes)
+    }
+
+    /// Shape of coefficients with `cols` columns generated by `InnerBox::generate_coefficients`
+    fn coefficients_shape(&self, cols: u32) -> CoefficientsShape {
+        CoefficientsShape {
+            parts: self.psi_params.label_parts(),
+            rows: self.psi_params.ct_slots.0,
+            cols,
+            value_bytes: self.psi_params.coefficient_bytes(),
+        }
+    }
+
+    /// Writes padding up to start of db section, db archived without coefficients along with `shapes` of coefficients
+    /// of each InnerBox, and padding up to start of coefficients. Returns length of db section, without padding.
+    fn write_db_section<W: Write>(
+        &mut self,
+        writer: &mut W,
+        shapes: &[CoefficientsShape],
+    ) -> Result<u64, PsiError> {
+        let db_start = db_section_start(DB_FILE_VERSION);
+        writer.write_all(&vec![0u8; db_start - DB_FILE_HEADER_BYTES])?;
+        let archived = archive_db(self, shapes)?;
+        writer.write_all(&archived)?;
+
+        let db_end = db_start + archived.len();
+        writer.write_all(&vec![0u8; align_up(db_end) - db_end])?;
+        Ok(archived.len() as u64)
+    }
+
+    /// Loads db stored with `Db::store`. Polynomial coefficients, which take up most of the db, are memory mapped and
+    /// evaluated in place instead of being read into memory. Thus loading is fast and OS pages coefficients in as
+    /// queries need them. Coefficients of an InnerBox are copied into memory only when the InnerBox is updated.
+    ///
+    /// Returns `PsiError::ParamsMismatch` if db was stored with params other than `psi_params`, checked before db is
+    /// deserialized, and `PsiError::Serialization` if checksum of the file doesn't match. Verifying the checksum reads
+    /// the entire file once, after which coefficients remain in page cache.
+    ///
+    /// Db files of `BINCODE_DB_FILE_VERSION` are still loaded, but their db section is deserialized with bincode which
+    /// takes minutes for large sets. Db files stored by earlier versions with plain bincode are read entirely into
+    /// memory. Either can be rewritten in current layout with `Db::migrate`. Encrypted db files must be loaded with
+    /// `Db::load_encrypted`.
+    ///
+    /// File must not be modified while db is in use.
+    pub fn load(path: &Path, psi_params: &PsiParams) -> Result<Db, PsiError> {
+        let (file, mmap) = map_file(path)?;
+        if mmap.starts_with(&ENCRYPTED_DB_FILE_MAGIC) {
+            return Err(PsiError::Serialization(
+                "Db file is encrypted. Load it with a db key.".to_string(),
+            ));
+        }
+        if !mmap.starts_with(&DB_FILE_MAGIC) {
+            drop(mmap);
+            let db: Db = bincode::deserialize_from(BufReader::new(file))?;
+            if &db.psi_params != psi_params {
+                return Err(params_mismatch());
+            }
+            return Ok(db);
+        }
+        Db::from_mmap(mmap, psi_params)
+    }
+
+    /// Loads db from `mmap` holding db in layout of `Db::store`. Coefficients are evaluated in place from `mmap`.
+    pub(crate) fn from_mmap(mmap: Mmap, psi_params: &PsiParams) -> Result<Db, PsiError> {
+        let (version, db_section) = parse_header(&mmap, psi_params)?;
+        verify_checksum(&mmap)?;
+        let (shapes, mut db) = if version == BINCODE_DB_FILE_VERSION {
+            let (shapes, db): (Vec<CoefficientsShape>, Db) =
+                bincode::deserialize(&mmap[db_section.clone()])?;
+            if &db.psi_params != psi_params {
+                return Err(params_mismatch());
+            }
+            (shapes, db)
+        } else {
+            unarchive_db(&mmap[db_section.clone()], psi_params)?
+        };
+        db.map_coefficients(Arc::new(mmap), align_up(db_section.end), shapes)?;
+        Ok(db)
+    }
+
+    /// Rewrites unencrypted db file at `path`, stored by an earlier version (see `Db::load`), in current layout of
+    /// `Db::store`. Migrated db is first stored next to `path` and then moved over it, thus `path` is left as is if
+    /// migration fails. Returns whether db file had to be migrated.
+    pub fn migrate(path: &Path, psi_params: &PsiParams) -> Result<bool, PsiError> {
+        if db_file_version(path)? == Some(DB_FILE_VERSION) {
+            return Ok(false);
+        }
+        let mut db = Db::load(path, psi_params)?;
+        let migrated_path = path.with_extension("migrating");
+        db.store(&migrated_path)?;
+        std::fs::rename(&migrated_path, path)?;
+        Ok(true)
+    }
+
+    /// Sets coefficients of each InnerBox, with shape in `shapes`, to coefficients stored one after another in `mmap`
+    /// starting at `offset`
+    fn map_coefficients(
+        &mut self,
+        mmap: Arc<Mmap>,
+        mut offset: usize,
+        shapes: Vec<CoefficientsShape>,
+    ) -> Result<(), PsiError> {
+        if shapes.len() != self.inner_boxes_count() {
+            return Err(malformed("coefficients of some InnerBoxes are missing"));
+        }
+
+        // files stored before empty columns were dropped have coefficients upto eval degree
+        let full_shape = self.coefficients_shape(self.psi_params.eval_degree.inner_box_columns());
+        for (ib, shape) in izip!(self.inner_boxes_mut(), shapes) {
+            if shape == CoefficientsShape::default() {
+                continue;
+            }
+            let expected_shape = CoefficientsShape {
+                cols: ib.coefficients_columns() as u32,
+                ..full_shape
+            };
+            if shape != expected_shape && shape != full_shape {
+                return Err(malformed("unexpected shape of coefficients"));
+            }
+            let end = shape
+                .bytes()
+                .and_then(|bytes| offset.checked_add(bytes))
+                .filter(|end| *end <= mmap.len())
+                .ok_or(malformed("truncated coefficients"))?;
+            ib.set_mapped_coefficients(MappedCoefficients {
+                mmap: mmap.clone(),
+                offset,
+                shape,
+            });
+            offset = end;
+        }
+        if offset != mmap.len() {
+            return Err(malformed("trailing bytes"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+
+    use crate::{random_u256, Coefficients, ItemLabel, NoProgress, PsiParams};
+
+    use super::*;
+
+    fn coefficients(db: &Db) -> Vec<Vec<Coefficients>> {
+        db.inner_boxes()
+            .map(|ib| {
+                ib.coefficients()
+                    .iter()
+                    .map(|c| c.into_owned())
+                    .collect_vec()
+            })
+            .collect_vec()
+    }
+
+    /// Item data, label data and occupied columns of each InnerBox
+    fn data(db: &Db) -> Vec<(Vec<u32>, Vec<u8>, Vec<u8>)> {
+        db.inner_boxes()
+            .map(|ib| {
+                let record = ib.record();
+                (
+                    record.curr_cols,
+                    record.item_data.data,
+                    record.label_data.data,
+                )
+            })
+            .collect_vec()
+    }
+
+    /// Writes db at `path` in layout of `BINCODE_DB_FILE_VERSION`, with db section serialized with bincode
+    fn write_bincode_db_file(db: &Db, path: &Path) {
+        let mut body = bincode::serialize(&(db.coefficients_shapes(), db)).unwrap();
+        let db_len = body.len() as u64;
+        body.resize(
+            align_up(DB_FILE_HEADER_BYTES + body.len()) - DB_FILE_HEADER_BYTES,
+            0,
+        );
+        for ib in db.inner_boxes() {
+            write_coefficients(&mut body, &ib.coefficients()).unwrap();
+        }
+        let mut header = file_header(&db.psi_params, xxh3_64(&body), db_len);
+        header[8..12].copy_from_slice(&BINCODE_DB_FILE_VERSION.to_le_bytes());
+        std::fs::write(path, [header.as_slice(), &body].concat()).unwrap();
+    }
+
+    #[test]
+    fn store_and_load_db_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let mut db = Db::new(&psi_params);
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        assert!(db.insert_many(&item_labels).is_empty());
+        db.preprocess().unwrap();
+
+        let path = std::env::temp_dir().join(format!("ulpsi_db_{}.bin", std::process::id()));
+        db.store(&path).unwrap();
+        let mut mapped_db = Db::load(&path, &psi_params).unwrap();
+        assert_eq!(coefficients(&mapped_db), coefficients(&db));
+        assert_eq!(data(&mapped_db), data(&db));
+
+        // updates copy coefficients of affected InnerBoxes into memory
+        let item_label = ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng));
+        db.insert_and_update(&item_label).unwrap();
+        mapped_db.insert_and_update(&item_label).unwrap();
+        assert_eq!(coefficients(&mapped_db), coefficients(&db));
+
+        // db stored with other params is rejected
+        let other_params = psi_params
+            .clone()
+            .with_hash_seed(psi_params.hash_seed() + 1);
+        assert!(matches!(
+            Db::load(&path, &other_params),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+
+        // truncated or modified file is rejected
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        assert!(matches!(
+            Db::load(&path, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+        let mut modified = bytes.clone();
+        *modified.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &modified).unwrap();
+        assert!(matches!(
+            Db::load(&path, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+
+        // db written to a writer is the same as db stored at a file
+        let mut written = vec![];
+        db.write_to(&mut written).unwrap();
+        db.store(&path).unwrap();
+        assert_eq!(written, std::fs::read(&path).unwrap());
+
+        // db files stored with plain bincode are still loaded
+        std::fs::write(&path, bincode::serialize(&db).unwrap()).unwrap();
+        assert_eq!(
+            coefficients(&Db::load(&path, &psi_params).unwrap()),
+            coefficients(&db)
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn migrate_bincode_db_file_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let mut db = Db::new(&psi_params);
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        assert!(db.insert_many(&item_labels).is_empty());
+        db.preprocess().unwrap();
+
+        let path =
+            std::env::temp_dir().join(format!("ulpsi_bincode_db_{}.bin", std::process::id()));
+        write_bincode_db_file(&db, &path);
+        assert_eq!(
+            db_file_version(&path).unwrap(),
+            Some(BINCODE_DB_FILE_VERSION)
+        );
+        let loaded = Db::load(&path, &psi_params).unwrap();
+        assert_eq!(coefficients(&loaded), coefficients(&db));
+        assert_eq!(data(&loaded), data(&db));
+
+        assert!(Db::migrate(&path, &psi_params).unwrap());
+        assert_eq!(db_file_version(&path).unwrap(), Some(DB_FILE_VERSION));
+        let migrated = Db::load(&path, &psi_params).unwrap();
+        assert_eq!(coefficients(&migrated), coefficients(&db));
+        assert_eq!(data(&migrated), data(&db));
+
+        // db files in current layout are left as is
+        assert!(!Db::migrate(&path, &psi_params).unwrap());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn preprocess_and_store_works() {
+        let mut rng = thread_rng();
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+
+        // coefficients are stored as u32s and u16s respectively
+        for psi_params in [
+            PsiParams::default(),
+            PsiParams::default()
+                .with_bfv_degree(1 << 12)
+                .with_plaintext_modulus(8, 40961),
+        ] {
+            let mut db = Db::new(&psi_params);
+            assert!(db.insert_many(&item_labels).is_empty());
+            db.preprocess().unwrap();
+
+            let mut streamed_db = Db::new(&psi_params);
+            assert!(streamed_db.insert_many(&item_labels).is_empty());
+            let path =
+                std::env::temp_dir().join(format!("ulpsi_streamed_db_{}.bin", std::process::id()));
+            streamed_db
+                .preprocess_and_store(&path, &NoProgress)
+                .unwrap();
+
+            assert_eq!(coefficients(&streamed_db), coefficients(&db));
+            assert_eq!(
+                coefficients(&Db::load(&path, &psi_params).unwrap()),
+                coefficients(&db)
+            );
+
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn merge_partitions_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = (0..100)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+        let mut db = Db::new(&psi_params);
+        assert!(db.insert_many(&item_labels).is_empty());
+        db.preprocess().unwrap();
+
+        // more partitions than InnerBoxes leaves some partitions empty
+        let count = db.inner_boxes_count() + 1;
+        let partials = (0..count)
+            .map(|index| {
+                let mut partial_db = Db::new(&psi_params);
+                assert!(partial_db.insert_many(&item_labels).is_empty());
+                let path = std::env::temp_dir().join(format!(
+                    "ulpsi_partial_db_{}_{index}.bin",
+                    std::process::id()
+                ));
+                partial_db
+                    .preprocess_partition_and_store(
+                        &path,
+                        Partition::new(index, count).unwrap(),
+                        &NoProgress,
+                    )
+                    .unwrap();
+                path
+            })
+            .collect_vec();
+
+        let path = std::env::temp_dir().join(format!("ulpsi_merged_db_{}.bin", std::process::id()));
+        let merged = Db::merge_partitions(&partials, &path, &psi_params).unwrap();
+        assert_eq!(coefficients(&merged), coefficients(&db));
+        assert_eq!(
+            coefficients(&Db::load(&path, &psi_params).unwrap()),
+            coefficients(&db)
+        );
+
+        // every InnerBox must be preprocessed in exactly one partial db
+        assert!(matches!(
+            Db::merge_partitions(&partials[..count - 1], &path, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+        let duplicated = [partials.clone(), partials[count - 1..].to_vec()].concat();
+        assert!(matches!(
+            Db::merge_partitions(&duplicated, &path, &psi_params),
+            Err(PsiError::Serialization(_))
+        ));
+
+        assert_eq!(
+            "1/3".parse::<Partition>().unwrap(),
+            Partition::new(1, 3).unwrap()
+        );
+        assert!("3/3".parse::<Partition>().is_err());
+
+        partials
+            .iter()
+            .chain([&path])
+            .for_each(|path| std::fs::remove_file(path).unwrap());
+    }
+}
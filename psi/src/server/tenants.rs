@@ -0,0 +1,130 @@
+use crate::{EvaluationKeyCache, ProtocolError, PsiError, Server};
+use std::{collections::HashMap, sync::Arc};
+
+/// Tenant that connections query until they select another one with `MessageType::Tenant`
+pub const DEFAULT_TENANT: &str = "default";
+/// Max. length of tenant id in bytes
+pub const MAX_TENANT_ID_BYTES: usize = 255;
+
+/// Independent dataset hosted by the server. Each tenant has its own `Server`, thus its own `PsiParams` and db.
+/// Evaluation keys depend on `PsiParams`, thus clients upload their key to each tenant separately.
+pub struct Tenant {
+    id: String,
+    server: Arc<Server>,
+    key_cache: EvaluationKeyCache,
+}
+
+impl Tenant {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn server(&self) -> &Arc<Server> {
+        &self.server
+    }
+
+    pub fn key_cache(&self) -> &EvaluationKeyCache {
+        &self.key_cache
+    }
+}
+
+/// Tenants hosted by a single server process, keyed by tenant id. Always contains `DEFAULT_TENANT`.
+pub struct Tenants {
+    tenants: HashMap<String, Arc<Tenant>>,
+}
+
+impl Tenants {
+    /// Creates tenants with `server` as `DEFAULT_TENANT`
+    pub fn new(server: Server, key_cache: EvaluationKeyCache) -> Tenants {
+        Tenants {
+            tenants: HashMap::new(),
+        }
+        .with_tenant(DEFAULT_TENANT, server, key_cache)
+    }
+
+    /// Adds tenant `id` served by `server`. Replaces existing tenant with the same id.
+    pub fn with_tenant(
+        mut self,
+        id: &str,
+        server: Server,
+        key_cache: EvaluationKeyCache,
+    ) -> Tenants {
+        assert!(id.len() <= MAX_TENANT_ID_BYTES, "Tenant id is too long");
+        self.tenants.insert(
+            id.to_string(),
+            Arc::new(Tenant {
+                id: id.to_string(),
+                server: Arc::new(server),
+                key_cache,
+            }),
+        );
+        self
+    }
+
+    pub fn default_tenant(&self) -> &Arc<Tenant> {
+        &self.tenants[DEFAULT_TENANT]
+    }
+
+    /// Returns tenant with `id`, decoded from payload of `MessageType::Tenant`
+    pub fn get(&self, id: &[u8]) -> Result<&Arc<Tenant>, PsiError> {
+        std::str::from_utf8(id)
+            .ok()
+            .and_then(|id| self.tenants.get(id))
+            .ok_or_else(|| {
+                PsiError::Protocol(ProtocolError::InvalidMessage(format!(
+                    "Unknown tenant {}",
+                    String::from_utf8_lossy(id)
+                )))
+            })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<Tenant>> {
+        self.tenants.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tenants.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tenants.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PsiParams;
+
+    use super::*;
+
+    #[test]
+    fn tenants_work() {
+        let psi_params = PsiParams::default();
+        let other_params = psi_params.clone().with_label_bytes(64);
+        let tenants = Tenants::new(Server::new(&psi_params), EvaluationKeyCache::default())
+            .with_tenant(
+                "other",
+                Server::new(&other_params),
+                EvaluationKeyCache::default(),
+            );
+
+        assert_eq!(tenants.len(), 2);
+        assert_eq!(tenants.default_tenant().id(), DEFAULT_TENANT);
+        assert_eq!(
+            tenants
+                .get(DEFAULT_TENANT.as_bytes())
+                .unwrap()
+                .server()
+                .psi_params(),
+            &psi_params
+        );
+        assert_eq!(
+            tenants.get(b"other").unwrap().server().psi_params(),
+            &other_params
+        );
+        assert!(matches!(
+            tenants.get(b"missing"),
+            Err(PsiError::Protocol(ProtocolError::InvalidMessage(_)))
+        ));
+    }
+}
@@ -0,0 +1,85 @@
+use crate::PsiError;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use std::sync::Arc;
+
+/// Dedicated rayon thread pools of the server, one for preprocessing and one for query evaluation, thus a serving node
+/// can reserve cores for queries while the db is re-preprocessed or swapped in the background. Pools are reference
+/// counted and can be shared by servers of several tenants.
+#[derive(Clone)]
+pub struct ThreadPools {
+    preprocess: Arc<ThreadPool>,
+    query: Arc<ThreadPool>,
+}
+
+impl ThreadPools {
+    /// Builds pool of `preprocess_threads` threads for inserting, preprocessing and encoding the db and pool of
+    /// `query_threads` threads for evaluating queries. 0 threads defaults to one thread per CPU, same as rayon's
+    /// global pool.
+    pub fn new(preprocess_threads: usize, query_threads: usize) -> Result<ThreadPools, PsiError> {
+        Ok(ThreadPools {
+            preprocess: Arc::new(build_pool("psi-preprocess", preprocess_threads)?),
+            query: Arc::new(build_pool("psi-query", query_threads)?),
+        })
+    }
+
+    pub fn preprocess_pool(&self) -> &ThreadPool {
+        &self.preprocess
+    }
+
+    pub fn query_pool(&self) -> &ThreadPool {
+        &self.query
+    }
+}
+
+fn build_pool(name: &'static str, threads: usize) -> Result<ThreadPool, PsiError> {
+    ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(move |index| format!("{name}-{index}"))
+        .build()
+        .map_err(|e| PsiError::Io(format!("Failed to build {name} thread pool: {e}")))
+}
+
+/// Runs `op` on preprocessing pool of `pools`, or on rayon's global pool if `pools` isn't set
+pub(crate) fn install_preprocess<R: Send>(
+    pools: Option<&ThreadPools>,
+    op: impl FnOnce() -> R + Send,
+) -> R {
+    match pools {
+        Some(pools) => pools.preprocess.install(op),
+        None => op(),
+    }
+}
+
+/// Runs `op` on query pool of `pools`, or on rayon's global pool if `pools` isn't set
+pub(crate) fn install_query<R: Send>(
+    pools: Option<&ThreadPools>,
+    op: impl FnOnce() -> R + Send,
+) -> R {
+    match pools {
+        Some(pools) => pools.query.install(op),
+        None => op(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn work_runs_on_dedicated_pools() {
+        let pools = ThreadPools::new(1, 3).unwrap();
+        assert_eq!(
+            install_preprocess(Some(&pools), rayon::current_num_threads),
+            1
+        );
+        assert_eq!(install_query(Some(&pools), rayon::current_num_threads), 3);
+        let thread_name = install_query(Some(&pools), || {
+            std::thread::current().name().map(str::to_string)
+        });
+        assert!(thread_name.unwrap().starts_with("psi-query-"));
+        assert_eq!(
+            install_query(None, rayon::current_num_threads),
+            rayon::current_num_threads()
+        );
+    }
+}
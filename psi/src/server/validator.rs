@@ -0,0 +1,301 @@
+use crate::{
+    expected_evaluation_key_bytes, expected_public_key_query_bytes, query_batch_bytes, MessageType,
+    PsiError, PsiParams, Query, QueryBatch, QueryLayout, CLIENT_ID_BYTES, MAX_CONTROL_FRAME_BYTES,
+    OPRF_POINT_BYTES,
+};
+use bfv::{Ciphertext, Evaluator, Representation};
+
+/// Default max. no. of queries in a single `QueryBatch`
+pub const DEFAULT_MAX_BATCH_QUERIES: usize = 16;
+
+/// Validates client queries before they are processed. Queries are rejected with an error instead of panicking
+/// while they are evaluated across threads.
+#[derive(Debug, Clone)]
+pub struct QueryValidator {
+    /// Max. size of serialized query accepted. Defaults to `expected_public_key_query_bytes`, since queries encrypted
+    /// with public key are larger than queries encrypted with secret key.
+    max_query_bytes: usize,
+    /// Max. no. of queries in a single `QueryBatch`
+    max_batch_queries: usize,
+    /// Max. size of serialized evaluation key accepted, see `expected_evaluation_key_bytes`
+    max_evaluation_key_bytes: usize,
+    /// Max. size of `OprfRequest` accepted, ie one point for every row of all hash tables
+    max_oprf_request_bytes: usize,
+    no_of_hash_tables: usize,
+    /// No. of ciphertexts expected in query of each hash table (ie segments times source powers)
+    cts_per_hash_table: usize,
+    /// No. of encryptions of zero expected in query
+    zero_cts: usize,
+}
+
+impl QueryValidator {
+    pub fn new(psi_params: &PsiParams, evaluator: &Evaluator) -> QueryValidator {
+        let layout = QueryLayout::new(psi_params);
+        QueryValidator {
+            max_query_bytes: expected_public_key_query_bytes(evaluator, psi_params),
+            max_batch_queries: DEFAULT_MAX_BATCH_QUERIES,
+            // proto fields are varint encoded, thus keys of other clients may be slightly larger
+            max_evaluation_key_bytes: 2 * expected_evaluation_key_bytes(evaluator, psi_params),
+            max_oprf_request_bytes: layout.no_of_hash_tables()
+                * layout.ht_size() as usize
+                * OPRF_POINT_BYTES,
+            no_of_hash_tables: layout.no_of_hash_tables(),
+            cts_per_hash_table: layout.query_cts_per_hash_table(),
+            zero_cts: layout.zero_cts(),
+        }
+    }
+
+    /// Sets max. size of serialized query accepted
+    pub fn with_max_query_bytes(mut self, max_query_bytes: usize) -> QueryValidator {
+        self.max_query_bytes = max_query_bytes;
+        self
+    }
+
+    pub fn max_query_bytes(&self) -> usize {
+        self.max_query_bytes
+    }
+
+    /// Sets max. no. of queries accepted in a single `QueryBatch`
+    pub fn with_max_batch_queries(mut self, max_batch_queries: usize) -> QueryValidator {
+        self.max_batch_queries = max_batch_queries;
+        self
+    }
+
+    pub fn max_batch_queries(&self) -> usize {
+        self.max_batch_queries
+    }
+
+    /// Max. size of serialized `QueryBatch` accepted, ie `max_batch_queries` queries of `max_query_bytes` each
+    pub fn max_batch_bytes(&self) -> usize {
+        query_batch_bytes(self.max_batch_queries, self.max_query_bytes)
+    }
+
+    /// Max. payload size of frame of `message_type`. Every frame a client may send is limited to the largest
+    /// legitimate message of its type so that server does not allocate whatever size client claims. Query frames are
+    /// limited to `max_query_bytes` (plus client id). Compressed query may be slightly larger than the query, thus the
+    /// limit is zstd's bound for compressing `max_query_bytes`. Frames that carry neither queries nor keys are limited
+    /// to `MAX_CONTROL_FRAME_BYTES`.
+    pub fn max_frame_bytes(&self, message_type: MessageType) -> u64 {
+        match message_type {
+            MessageType::Query | MessageType::StreamedQuery => {
+                (CLIENT_ID_BYTES + zstd::zstd_safe::compress_bound(self.max_query_bytes)) as u64
+            }
+            MessageType::QueryBatch => {
+                (CLIENT_ID_BYTES + zstd::zstd_safe::compress_bound(self.max_batch_bytes())) as u64
+            }
+            MessageType::EvaluationKey => (CLIENT_ID_BYTES + self.max_evaluation_key_bytes) as u64,
+            MessageType::OprfRequest => self.max_oprf_request_bytes as u64,
+            _ => MAX_CONTROL_FRAME_BYTES,
+        }
+    }
+
+    /// Checks size of serialized query before it is deserialized. No. of ciphertexts is checked by `deserialize_query`,
+    /// since ciphertexts are length prefixed and may differ in size.
+    pub fn validate_query_bytes(&self, bytes: &[u8]) -> Result<(), PsiError> {
+        if bytes.len() > self.max_query_bytes {
+            return Err(PsiError::InvalidQuery(format!(
+                "Query of {} bytes exceeds limit of {} bytes",
+                bytes.len(),
+                self.max_query_bytes
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks size of serialized `QueryBatch` before it is deserialized
+    pub fn validate_batch_bytes(&self, bytes: &[u8]) -> Result<(), PsiError> {
+        if bytes.len() > self.max_batch_bytes() {
+            return Err(PsiError::InvalidQuery(format!(
+                "Query batch of {} bytes exceeds limit of {} bytes",
+                bytes.len(),
+                self.max_batch_bytes()
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that `batch` has at least one and at most `max_batch_queries` queries. Each query is checked with
+    /// `validate` once it is processed.
+    pub fn validate_batch(&self, batch: &QueryBatch) -> Result<(), PsiError> {
+        if batch.is_empty() || batch.len() > self.max_batch_queries {
+            return Err(PsiError::InvalidQuery(format!(
+                "Query batch of {} queries, expected between 1 and {}",
+                batch.len(),
+                self.max_batch_queries
+            )));
+        }
+        Ok(())
+    }
+
+    /// Checks that `query` has expected no. of ciphertexts and that each ciphertext is a fresh ciphertext, ie at level
+    /// 0 with 2 polynomials in coefficient representation.
+    pub fn validate(&self, query: &Query) -> Result<(), PsiError> {
+        if query.1.len() != self.zero_cts {
+            return Err(PsiError::ParamsMismatch(format!(
+                "Expected {} encryptions of zero, found {}",
+                self.zero_cts,
+                query.1.len()
+            )));
+        }
+        for (ct_index, ct) in query.1.iter().enumerate() {
+            Self::validate_fresh_ct(ct).map_err(|e| {
+                PsiError::InvalidQuery(format!("Encryption of zero {ct_index} {e}"))
+            })?;
+        }
+
+        if query.0.len() != self.no_of_hash_tables {
+            return Err(PsiError::ParamsMismatch(format!(
+                "Expected query for {} hash tables, found {}",
+                self.no_of_hash_tables,
+                query.0.len()
+            )));
+        }
+
+        for (ht_index, ht_query_cts) in query.0.iter().enumerate() {
+            if ht_query_cts.0.len() != self.cts_per_hash_table {
+                return Err(PsiError::ParamsMismatch(format!(
+                    "Expected {} query ciphertexts for hash table {ht_index}, found {}",
+                    self.cts_per_hash_table,
+                    ht_query_cts.0.len()
+                )));
+            }
+
+            for (ct_index, ct) in ht_query_cts.0.iter().enumerate() {
+                Self::validate_fresh_ct(ct).map_err(|e| {
+                    PsiError::InvalidQuery(format!(
+                        "Ciphertext {ct_index} of hash table {ht_index} {e}"
+                    ))
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns reason if `ct` isn't at level 0 with 2 polynomials in coefficient representation
+    fn validate_fresh_ct(ct: &Ciphertext) -> Result<(), String> {
+        if ct.level() != 0 {
+            return Err(format!("is at level {}, expected 0", ct.level()));
+        }
+        if ct.c_ref().len() != 2
+            || ct
+                .c_ref()
+                .iter()
+                .any(|c| c.representation() != &Representation::Coefficient)
+        {
+            return Err("must have 2 polynomials in coefficient representation".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bfv::{EvaluationKeyProto, SecretKey};
+    use prost::Message;
+    use rand::thread_rng;
+
+    use crate::{
+        construct_query, gen_bfv_params, generate_evaluation_key, max_query_items, random_u256,
+        serialize_query, MAX_FRAME_BYTES,
+    };
+
+    use super::*;
+
+    #[test]
+    fn query_validator_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+
+        let query_set = (0..10).map(|_| random_u256(&mut rng)).collect::<Vec<_>>();
+        let query_state = construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+        let query_bytes = serialize_query(query_state.query(), evaluator.params());
+
+        let validator = QueryValidator::new(&psi_params, &evaluator);
+        assert!(validator.validate_query_bytes(&query_bytes).is_ok());
+        assert!(validator.validate(query_state.query()).is_ok());
+
+        // query larger than limit
+        let small_validator = validator
+            .clone()
+            .with_max_query_bytes(query_bytes.len() - 1);
+        assert!(matches!(
+            small_validator.validate_query_bytes(&query_bytes),
+            Err(PsiError::InvalidQuery(_))
+        ));
+
+        // missing ciphertext
+        let mut query = query_state.query().clone();
+        query.0[0].0.pop();
+        assert!(matches!(
+            validator.validate(&query),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+
+        // ciphertext in evaluation representation
+        let mut query = query_state.query().clone();
+        evaluator
+            .ciphertext_change_representation(&mut query.0[1].0[0], Representation::Evaluation);
+        assert!(matches!(
+            validator.validate(&query),
+            Err(PsiError::InvalidQuery(_))
+        ));
+
+        // ciphertext not at level 0
+        let mut query = query_state.query().clone();
+        evaluator.mod_down_level(&mut query.0[0].0[0], 1);
+        assert!(matches!(
+            validator.validate(&query),
+            Err(PsiError::InvalidQuery(_))
+        ));
+
+        // encryptions of zero required with circuit privacy
+        let private_params = psi_params.clone().with_circuit_privacy();
+        let private_validator = QueryValidator::new(&private_params, &evaluator);
+        assert!(matches!(
+            private_validator.validate(query_state.query()),
+            Err(PsiError::ParamsMismatch(_))
+        ));
+        let private_query_state =
+            construct_query(&query_set, &private_params, &evaluator, &sk, &mut rng);
+        assert!(private_validator
+            .validate(private_query_state.query())
+            .is_ok());
+        assert!(private_validator
+            .validate_query_bytes(&serialize_query(
+                private_query_state.query(),
+                evaluator.params()
+            ))
+            .is_ok());
+    }
+
+    #[test]
+    fn max_frame_bytes_bounds_client_frames() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+        let ek = generate_evaluation_key(&psi_params, &evaluator, &sk, &mut rng);
+        let ek_bytes =
+            EvaluationKeyProto::try_from_with_parameters(&ek, evaluator.params()).encode_to_vec();
+
+        let validator = QueryValidator::new(&psi_params, &evaluator);
+        let ek_limit = validator.max_frame_bytes(MessageType::EvaluationKey);
+        assert!((CLIENT_ID_BYTES + ek_bytes.len()) as u64 <= ek_limit);
+        assert!(ek_limit < MAX_FRAME_BYTES);
+        assert!(
+            validator.max_frame_bytes(MessageType::OprfRequest)
+                >= (max_query_items(&psi_params) * OPRF_POINT_BYTES) as u64
+        );
+        [MessageType::Hello, MessageType::Auth, MessageType::Tenant]
+            .into_iter()
+            .for_each(|message_type| {
+                assert_eq!(
+                    validator.max_frame_bytes(message_type),
+                    MAX_CONTROL_FRAME_BYTES
+                )
+            });
+    }
+}
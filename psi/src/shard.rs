@@ -0,0 +1,493 @@
+use crate::{
+    gen_bfv_params, read_frame_with_limit, write_frame, Frame, ItemLabel, MessageType,
+    ProtocolError, PsiError, PsiParams, QueryValidator, CAPABILITY_METADATA, CAPABILITY_ZSTD,
+    MAX_CONTROL_FRAME_BYTES, MAX_FRAME_BYTES,
+};
+use bfv::Evaluator;
+use crypto_bigint::{Encoding, U256};
+use itertools::Itertools;
+use sha2::{Digest, Sha256};
+use std::{future::Future, net::SocketAddr, pin::Pin, task::Poll};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+use tracing::{debug, info};
+
+/// Bytes of shard index that prefix payload of `MessageType::ShardQueryResponseSegment`
+const SHARD_INDEX_BYTES: usize = 4;
+
+/// Returns index of shard that stores `item` among `shard_count` shards. Items are partitioned by the first 8 bytes of
+/// SHA-256 hash of their little endian bytes, thus shards are of roughly equal size regardless of item distribution.
+pub fn shard_of(item: &U256, shard_count: usize) -> usize {
+    assert!(shard_count > 0, "No. of shards must be positive");
+    let digest = Sha256::digest(item.to_le_bytes());
+    let prefix = u64::from_le_bytes(digest[..8].try_into().unwrap());
+    (prefix % shard_count as u64) as usize
+}
+
+/// Partitions `item_labels` into `shard_count` sets with `shard_of`. Each shard is preprocessed and served by its own
+/// server, with the same `PsiParams`.
+pub fn partition_item_labels(item_labels: &[ItemLabel], shard_count: usize) -> Vec<Vec<ItemLabel>> {
+    let mut shards = vec![vec![]; shard_count];
+    item_labels.iter().for_each(|item_label| {
+        shards[shard_of(item_label.item(), shard_count)].push(item_label.clone());
+    });
+    shards
+}
+
+/// Fans requests of clients out to all shards of a sharded server. Every client connection to the coordinator is
+/// served over its own connection to each shard, thus shards keep per connection state (capabilities, authentication,
+/// tenant and evaluation key) as if the client was connected to them directly.
+///
+/// Query is evaluated by every shard and coordinator responds with `MessageType::ShardedQueryResponse` carrying
+/// response of each shard. Client merges them with `process_sharded_query_response`. Queries whose responses together
+/// exceed `MAX_FRAME_BYTES` must be streamed: segments of `MessageType::StreamedQuery` are forwarded as soon as any
+/// shard sends them, in `MessageType::ShardQueryResponseSegment` frames. OPRF is not supported, since each shard has
+/// its own OPRF key.
+pub struct ShardCoordinator {
+    shard_addrs: Vec<SocketAddr>,
+    /// Limits size of frames received from clients, same as shards do. Built from `PsiParams` shared by all shards.
+    query_validator: QueryValidator,
+}
+
+impl ShardCoordinator {
+    pub fn new(shard_addrs: Vec<SocketAddr>, psi_params: &PsiParams) -> ShardCoordinator {
+        assert!(!shard_addrs.is_empty(), "Coordinator requires a shard");
+        let evaluator = Evaluator::new(gen_bfv_params(psi_params));
+        ShardCoordinator {
+            shard_addrs,
+            query_validator: QueryValidator::new(psi_params, &evaluator),
+        }
+    }
+
+    pub fn shard_addrs(&self) -> &[SocketAddr] {
+        &self.shard_addrs
+    }
+
+    /// Serves framed requests of client on `socket` until client closes the connection. If a request fails, error is
+    /// sent to client as `MessageType::Error` frame and the connection is closed.
+    pub async fn serve_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        mut socket: S,
+    ) -> Result<(), PsiError> {
+        let mut shards = Vec::with_capacity(self.shard_addrs.len());
+        for addr in &self.shard_addrs {
+            let stream = TcpStream::connect(addr)
+                .await
+                .map_err(|e| PsiError::Io(format!("Failed to connect to shard at {addr}: {e}")))?;
+            stream.set_nodelay(true)?;
+            shards.push(stream);
+        }
+
+        loop {
+            let frame = match read_frame_with_limit(&mut socket, |message_type| {
+                self.query_validator.max_frame_bytes(message_type)
+            })
+            .await
+            {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    info!("Client disconnected");
+                    return Ok(());
+                }
+                Err(e) => {
+                    let _ = write_frame(&mut socket, &Frame::error(&e.to_string())).await;
+                    return Err(e.into());
+                }
+            };
+
+            // coordinator processes requests one at a time, but responses still carry their request's id
+            let request_id = frame.request_id;
+            let response = match frame.message_type {
+                MessageType::Hello => broadcast(&mut shards, &frame).await.map(|responses| {
+                    // flags enabled by all shards
+                    let flags = responses
+                        .iter()
+                        .fold(CAPABILITY_ZSTD | CAPABILITY_METADATA, |flags, response| {
+                            flags & response.payload.first().copied().unwrap_or(0)
+                        });
+                    Frame::new(MessageType::Hello, vec![flags])
+                }),
+                MessageType::Auth | MessageType::Tenant | MessageType::EvaluationKey => {
+                    broadcast(&mut shards, &frame)
+                        .await
+                        .map(|_| Frame::new(MessageType::Ack, vec![]))
+                }
+                MessageType::Query => {
+                    info!(shards = shards.len(), "Received new query");
+                    query_shards(&mut shards, &frame).await
+                }
+                MessageType::StreamedQuery => {
+                    info!(shards = shards.len(), "Received new streamed query");
+                    query_shards_streamed(&mut shards, &frame, &mut socket, request_id).await
+                }
+                MessageType::OprfRequest => Err(PsiError::Protocol(ProtocolError::InvalidMessage(
+                    "OPRF is not supported by sharded server".to_string(),
+                ))),
+                message_type => Err(PsiError::Protocol(ProtocolError::InvalidMessage(format!(
+                    "Coordinator does not accept {message_type:?} messages"
+                )))),
+            };
+
+            match response {
+                Ok(response) => {
+                    write_frame(&mut socket, &response.with_request_id(request_id)).await?
+                }
+                Err(e) => {
+                    let error = Frame::error(&e.to_string()).with_request_id(request_id);
+                    write_frame(&mut socket, &error).await?;
+                    return Err(e);
+                }
+            }
+        }
+    }
+}
+
+/// Sends `frame` to all shards before waiting for their responses, thus shards process the request concurrently.
+/// Returns error if any shard responds with `MessageType::Error`.
+async fn broadcast(shards: &mut [TcpStream], frame: &Frame) -> Result<Vec<Frame>, PsiError> {
+    for shard in shards.iter_mut() {
+        write_frame(shard, frame).await?;
+    }
+
+    let mut responses = Vec::with_capacity(shards.len());
+    for (index, shard) in shards.iter_mut().enumerate() {
+        let response = read_frame_with_limit(shard, max_shard_frame_bytes)
+            .await?
+            .ok_or_else(|| PsiError::Io(format!("Shard {index} closed connection")))?;
+        if response.message_type == MessageType::Error {
+            // surfaces shard's error message
+            response.into_payload(MessageType::Ack)?;
+        }
+        responses.push(response);
+    }
+    Ok(responses)
+}
+
+/// Max. payload size of frame of `message_type` received from a shard. Only query responses may be large, other
+/// responses (for ex. `MessageType::Ack`) are limited to `MAX_CONTROL_FRAME_BYTES`.
+fn max_shard_frame_bytes(message_type: MessageType) -> u64 {
+    match message_type {
+        MessageType::QueryResponse => MAX_FRAME_BYTES,
+        // forwarded with shard index prefixed
+        MessageType::QueryResponseSegment => MAX_FRAME_BYTES - SHARD_INDEX_BYTES as u64,
+        _ => MAX_CONTROL_FRAME_BYTES,
+    }
+}
+
+/// Sends `query` to all shards and returns `MessageType::ShardedQueryResponse` with their responses, in order of
+/// shards. Returns `MessageType::EvaluationKeyRequired` if any shard does not have client's evaluation key.
+async fn query_shards(shards: &mut [TcpStream], query: &Frame) -> Result<Frame, PsiError> {
+    let responses = broadcast(shards, query).await?;
+    if responses
+        .iter()
+        .any(|response| response.message_type == MessageType::EvaluationKeyRequired)
+    {
+        debug!("Evaluation key of client is not cached by all shards. Requesting upload");
+        return Ok(Frame::new(MessageType::EvaluationKeyRequired, vec![]));
+    }
+
+    let payloads = responses
+        .into_iter()
+        .map(|response| response.into_payload(MessageType::QueryResponse))
+        .collect::<Result<Vec<_>, _>>()?;
+    sharded_query_response(&payloads, MAX_FRAME_BYTES)
+}
+
+/// Returns `MessageType::ShardedQueryResponse` carrying `payloads` of all shards. Fails if its payload would exceed
+/// `max_frame_bytes`, since client would reject the frame.
+fn sharded_query_response(payloads: &[Vec<u8>], max_frame_bytes: u64) -> Result<Frame, PsiError> {
+    let length = bincode::serialized_size(payloads)?;
+    if length > max_frame_bytes {
+        debug!(length, "Responses of shards exceed frame limit");
+        return Err(PsiError::Protocol(ProtocolError::FrameTooLarge {
+            length,
+            limit: max_frame_bytes,
+        }));
+    }
+    Ok(Frame::new(
+        MessageType::ShardedQueryResponse,
+        bincode::serialize(payloads)?,
+    ))
+}
+
+/// Sends streamed `query` to all shards and forwards segments of their responses to client on `socket`, as
+/// `MessageType::ShardQueryResponseSegment` frames of `request_id`, in the order they arrive from any shard. Returns
+/// `MessageType::QueryResponseEnd`, carrying metadata of the first shard, once all shards are done. Returns
+/// `MessageType::EvaluationKeyRequired` if any shard does not have client's evaluation key, in which case no segment
+/// is forwarded.
+async fn query_shards_streamed<S: AsyncWrite + Unpin>(
+    shards: &mut Vec<TcpStream>,
+    query: &Frame,
+    socket: &mut S,
+    request_id: u32,
+) -> Result<Frame, PsiError> {
+    // first frame of every shard tells whether it has client's evaluation key. Shards that do, stream the rest of
+    // their segments in the meantime.
+    let first_frames = broadcast(shards, query).await?;
+    let key_required = first_frames
+        .iter()
+        .any(|frame| frame.message_type == MessageType::EvaluationKeyRequired);
+    if key_required {
+        debug!("Evaluation key of client is not cached by all shards. Requesting upload");
+    }
+
+    // shards that are still streaming are owned by their pending read
+    let mut idle_shards = std::mem::take(shards).into_iter().map(Some).collect_vec();
+    let mut reads = Vec::with_capacity(idle_shards.len());
+    let mut first_frames = first_frames.into_iter().enumerate();
+    let mut metadata = vec![];
+    loop {
+        let (index, frame) = match first_frames.next() {
+            Some(first_frame) => first_frame,
+            None if reads.is_empty() => break,
+            None => {
+                let (index, shard, frame) = next_shard_frame(&mut reads).await;
+                idle_shards[index] = Some(shard);
+                let frame = frame?
+                    .ok_or_else(|| PsiError::Io(format!("Shard {index} closed connection")))?;
+                (index, frame)
+            }
+        };
+
+        match frame.message_type {
+            MessageType::QueryResponseSegment => {
+                reads.push(read_shard(index, idle_shards[index].take().unwrap()));
+                // remaining segments are drained if client has to upload its evaluation key and resend the query
+                if !key_required {
+                    let mut payload = Vec::with_capacity(SHARD_INDEX_BYTES + frame.payload.len());
+                    payload.extend_from_slice(&(index as u32).to_le_bytes());
+                    payload.extend_from_slice(&frame.payload);
+                    let segment = Frame::new(MessageType::ShardQueryResponseSegment, payload)
+                        .with_request_id(request_id);
+                    write_frame(socket, &segment).await?;
+                }
+            }
+            MessageType::QueryResponseEnd => {
+                if index == 0 {
+                    metadata = frame.payload;
+                }
+            }
+            MessageType::EvaluationKeyRequired => {}
+            _ => {
+                // surfaces shard's error message
+                frame.into_payload(MessageType::QueryResponseSegment)?;
+            }
+        }
+    }
+
+    *shards = idle_shards.into_iter().map(Option::unwrap).collect();
+    if key_required {
+        Ok(Frame::new(MessageType::EvaluationKeyRequired, vec![]))
+    } else {
+        Ok(Frame::new(MessageType::QueryResponseEnd, metadata))
+    }
+}
+
+/// Splits payload of `MessageType::ShardQueryResponseSegment` into shard index and `MessageType::QueryResponseSegment`
+/// payload of the shard
+pub(crate) fn split_shard_index(mut payload: Vec<u8>) -> Result<(usize, Vec<u8>), ProtocolError> {
+    if payload.len() < SHARD_INDEX_BYTES {
+        return Err(ProtocolError::InvalidMessage(
+            "Payload is missing shard index".to_string(),
+        ));
+    }
+    let index = u32::from_le_bytes(payload[..SHARD_INDEX_BYTES].try_into().unwrap());
+    payload.drain(..SHARD_INDEX_BYTES);
+    Ok((index as usize, payload))
+}
+
+/// Next frame of shard at `index`, along with the shard. Owns the shard's connection until it resolves, thus reads of
+/// all shards can be pending at once.
+type ShardRead =
+    Pin<Box<dyn Future<Output = (usize, TcpStream, Result<Option<Frame>, ProtocolError>)> + Send>>;
+
+fn read_shard(index: usize, mut shard: TcpStream) -> ShardRead {
+    Box::pin(async move {
+        let frame = read_frame_with_limit(&mut shard, max_shard_frame_bytes).await;
+        (index, shard, frame)
+    })
+}
+
+/// Resolves with whichever of `reads` completes first and removes it. Never resolves if `reads` is empty.
+async fn next_shard_frame(
+    reads: &mut Vec<ShardRead>,
+) -> (usize, TcpStream, Result<Option<Frame>, ProtocolError>) {
+    std::future::poll_fn(|cx| {
+        for index in 0..reads.len() {
+            if let Poll::Ready(read) = reads[index].as_mut().poll(cx) {
+                reads.swap_remove(index);
+                return Poll::Ready(read);
+            }
+        }
+        Poll::Pending
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::thread_rng;
+    use tokio::net::TcpListener;
+
+    use crate::random_u256;
+
+    use super::*;
+
+    /// Starts shard that responds to every streamed query with `segments` followed by `QueryResponseEnd` carrying
+    /// `metadata`, or with `EvaluationKeyRequired` if `segments` is `None`
+    async fn start_shard(segments: Option<Vec<Vec<u8>>>, metadata: &[u8]) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let metadata = metadata.to_vec();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            while let Some(frame) = read_frame_with_limit(&mut socket, |_| MAX_FRAME_BYTES)
+                .await
+                .unwrap()
+            {
+                assert_eq!(frame.message_type, MessageType::StreamedQuery);
+                let mut responses = match &segments {
+                    Some(segments) => segments
+                        .iter()
+                        .map(|segment| {
+                            Frame::new(MessageType::QueryResponseSegment, segment.clone())
+                        })
+                        .collect_vec(),
+                    None => vec![Frame::new(MessageType::EvaluationKeyRequired, vec![])],
+                };
+                if segments.is_some() {
+                    responses.push(Frame::new(MessageType::QueryResponseEnd, metadata.clone()));
+                }
+                for response in &responses {
+                    write_frame(&mut socket, response).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    /// Sends streamed query to coordinator of `shard_addrs` and returns frames of the response
+    async fn query_coordinator(shard_addrs: Vec<SocketAddr>) -> Vec<Frame> {
+        let coordinator = ShardCoordinator::new(shard_addrs, &PsiParams::default());
+        let (mut client, server) = tokio::io::duplex(1 << 16);
+        let client = async {
+            let query = Frame::new(MessageType::StreamedQuery, vec![0; 64]).with_request_id(5);
+            write_frame(&mut client, &query).await.unwrap();
+            let mut frames = vec![];
+            loop {
+                let frame = read_frame_with_limit(&mut client, |_| MAX_FRAME_BYTES)
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(frame.request_id, 5);
+                let done = frame.message_type != MessageType::ShardQueryResponseSegment;
+                frames.push(frame);
+                if done {
+                    break;
+                }
+            }
+            drop(client);
+            frames
+        };
+        let (served, frames) = tokio::join!(coordinator.serve_connection(server), client);
+        served.unwrap();
+        frames
+    }
+
+    #[tokio::test]
+    async fn streamed_query_forwards_segments_of_all_shards() {
+        let shard_addrs = vec![
+            start_shard(Some(vec![vec![0, 1], vec![0, 2]]), b"shard 0").await,
+            start_shard(Some(vec![vec![1, 1]]), b"shard 1").await,
+            start_shard(Some(vec![vec![2, 1], vec![2, 2], vec![2, 3]]), b"shard 2").await,
+        ];
+        let mut frames = query_coordinator(shard_addrs).await;
+
+        let end = frames.pop().unwrap();
+        assert_eq!(
+            end.into_payload(MessageType::QueryResponseEnd).unwrap(),
+            b"shard 0"
+        );
+        // segments of a shard arrive in order, but are interleaved with segments of other shards
+        let segments = frames
+            .into_iter()
+            .map(|frame| {
+                split_shard_index(
+                    frame
+                        .into_payload(MessageType::ShardQueryResponseSegment)
+                        .unwrap(),
+                )
+                .unwrap()
+            })
+            .into_group_map();
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[&0], vec![vec![0, 1], vec![0, 2]]);
+        assert_eq!(segments[&1], vec![vec![1, 1]]);
+        assert_eq!(segments[&2], vec![vec![2, 1], vec![2, 2], vec![2, 3]]);
+    }
+
+    #[tokio::test]
+    async fn streamed_query_requires_evaluation_key_of_all_shards() {
+        let shard_addrs = vec![
+            start_shard(Some(vec![vec![0, 1], vec![0, 2]]), b"shard 0").await,
+            start_shard(None, b"").await,
+        ];
+        let frames = query_coordinator(shard_addrs).await;
+
+        // segments of shard 0 aren't forwarded
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].message_type, MessageType::EvaluationKeyRequired);
+    }
+
+    #[test]
+    fn sharded_query_response_respects_frame_limit() {
+        let payloads = vec![vec![1; 10], vec![2; 20]];
+        let response = sharded_query_response(&payloads, 100).unwrap();
+        let received: Vec<Vec<u8>> = bincode::deserialize(
+            &response
+                .into_payload(MessageType::ShardedQueryResponse)
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(received, payloads);
+
+        assert!(matches!(
+            sharded_query_response(&payloads, 40),
+            Err(PsiError::Protocol(ProtocolError::FrameTooLarge {
+                length: 54,
+                limit: 40
+            }))
+        ));
+    }
+
+    #[test]
+    fn split_shard_index_works() {
+        let payload = [7u32.to_le_bytes().as_slice(), &[1, 2, 3]].concat();
+        assert_eq!(split_shard_index(payload).unwrap(), (7, vec![1, 2, 3]));
+        assert!(split_shard_index(vec![1, 2]).is_err());
+    }
+
+    #[test]
+    fn partition_item_labels_works() {
+        let mut rng = thread_rng();
+        let item_labels = (0..1000)
+            .map(|_| ItemLabel::new(random_u256(&mut rng), random_u256(&mut rng)))
+            .collect_vec();
+
+        let shards = partition_item_labels(&item_labels, 4);
+        assert_eq!(shards.len(), 4);
+        assert_eq!(shards.iter().map(|s| s.len()).sum::<usize>(), 1000);
+        shards.iter().enumerate().for_each(|(index, shard)| {
+            // roughly equal
+            assert!(shard.len() > 150);
+            assert!(shard
+                .iter()
+                .all(|item_label| shard_of(item_label.item(), 4) == index));
+        });
+
+        // single shard stores all items
+        assert_eq!(partition_item_labels(&item_labels, 1)[0], item_labels);
+    }
+}
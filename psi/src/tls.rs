@@ -0,0 +1,128 @@
+use crate::PsiError;
+use std::sync::Arc;
+use tokio_rustls::{
+    rustls::{self, Certificate, ClientConfig, PrivateKey, RootCertStore, ServerConfig},
+    TlsAcceptor, TlsConnector,
+};
+
+/// Returns certificates in PEM encoded `pem`
+pub(crate) fn parse_certificates(pem: &[u8]) -> Result<Vec<Certificate>, PsiError> {
+    let certs = rustls_pemfile::certs(&mut &pem[..])
+        .map_err(|e| PsiError::Tls(format!("Malformed certificate: {e}")))?;
+    if certs.is_empty() {
+        return Err(PsiError::Tls("No certificate found".to_string()));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+/// Returns first PKCS8, RSA or EC private key in PEM encoded `pem`
+pub(crate) fn parse_private_key(pem: &[u8]) -> Result<PrivateKey, PsiError> {
+    let items = rustls_pemfile::read_all(&mut &pem[..])
+        .map_err(|e| PsiError::Tls(format!("Malformed private key: {e}")))?;
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or(PsiError::Tls("No private key found".to_string()))
+}
+
+/// Returns acceptor that wraps server's connections in TLS with PEM encoded certificate chain `cert_chain_pem` and
+/// private key `key_pem`
+pub fn tls_acceptor(cert_chain_pem: &[u8], key_pem: &[u8]) -> Result<TlsAcceptor, PsiError> {
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(
+            parse_certificates(cert_chain_pem)?,
+            parse_private_key(key_pem)?,
+        )
+        .map_err(|e| PsiError::Tls(e.to_string()))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Returns connector that wraps client's connection in TLS and only trusts servers with certificates issued by
+/// certificate authorities in PEM encoded `ca_pem`
+pub fn tls_connector(ca_pem: &[u8]) -> Result<TlsConnector, PsiError> {
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(root_cert_store(ca_pem)?)
+        .with_no_client_auth();
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Returns store of certificate authorities in PEM encoded `ca_pem`
+pub(crate) fn root_cert_store(ca_pem: &[u8]) -> Result<RootCertStore, PsiError> {
+    let mut root_store = RootCertStore::empty();
+    for cert in parse_certificates(ca_pem)? {
+        root_store
+            .add(&cert)
+            .map_err(|e| PsiError::Tls(e.to_string()))?;
+    }
+    Ok(root_store)
+}
+
+/// Returns `domain` as server name verified against server's certificate
+pub fn tls_server_name(domain: &str) -> Result<rustls::ServerName, PsiError> {
+    rustls::ServerName::try_from(domain)
+        .map_err(|_| PsiError::Tls(format!("Invalid server name {domain}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{read_frame_with_limit, write_frame, Frame, MessageType, MAX_FRAME_BYTES};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn tls_works() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert_pem = cert.serialize_pem().unwrap();
+        let key_pem = cert.serialize_private_key_pem();
+
+        let acceptor = tls_acceptor(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        let connector = tls_connector(cert_pem.as_bytes()).unwrap();
+
+        let (client, server) = tokio::io::duplex(1 << 16);
+        let server_task = tokio::spawn(async move {
+            let mut stream = acceptor.accept(server).await.unwrap();
+            let frame = read_frame_with_limit(&mut stream, |_| MAX_FRAME_BYTES)
+                .await
+                .unwrap()
+                .unwrap();
+            write_frame(&mut stream, &frame).await.unwrap();
+        });
+
+        let mut stream = connector
+            .connect(tls_server_name("localhost").unwrap(), client)
+            .await
+            .unwrap();
+        let frame = Frame::new(MessageType::Ack, vec![1, 2, 3]);
+        write_frame(&mut stream, &frame).await.unwrap();
+        assert_eq!(
+            read_frame_with_limit(&mut stream, |_| MAX_FRAME_BYTES)
+                .await
+                .unwrap()
+                .unwrap(),
+            frame
+        );
+        server_task.await.unwrap();
+
+        // server certificate isn't issued for the domain
+        let acceptor = tls_acceptor(cert_pem.as_bytes(), key_pem.as_bytes()).unwrap();
+        let (client, server) = tokio::io::duplex(1 << 16);
+        tokio::spawn(async move { acceptor.accept(server).await });
+        assert!(connector
+            .connect(tls_server_name("example.com").unwrap(), client)
+            .await
+            .is_err());
+
+        assert!(matches!(
+            tls_acceptor(b"", key_pem.as_bytes()),
+            Err(PsiError::Tls(_))
+        ));
+    }
+}
@@ -0,0 +1,99 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+
+/// Bytes each end of `InMemoryTransport` buffers before writes wait for the other end to read
+pub const IN_MEMORY_TRANSPORT_BUFFER_BYTES: usize = 1 << 20;
+
+/// One end of an in-process connection. Frames are written and read with `write_frame` and `read_frame_with_limit`,
+/// same as on TCP connections, thus client (see `PsiClient::from_stream`) and server can run in a single process, for
+/// ex. in tests, without sockets.
+pub struct InMemoryTransport {
+    stream: DuplexStream,
+}
+
+impl InMemoryTransport {
+    /// Returns both ends of a new connection
+    pub fn pair() -> (InMemoryTransport, InMemoryTransport) {
+        let (a, b) = tokio::io::duplex(IN_MEMORY_TRANSPORT_BUFFER_BYTES);
+        (
+            InMemoryTransport { stream: a },
+            InMemoryTransport { stream: b },
+        )
+    }
+}
+
+impl AsyncRead for InMemoryTransport {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for InMemoryTransport {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{read_frame_with_limit, write_frame, Frame, MessageType, MAX_FRAME_BYTES};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn in_memory_transport_carries_frames_both_ways() {
+        let (mut client, mut server) = InMemoryTransport::pair();
+
+        // larger than buffer, thus writer waits for reader
+        let query = Frame::new(
+            MessageType::Query,
+            vec![1; 3 * IN_MEMORY_TRANSPORT_BUFFER_BYTES],
+        );
+        let response = Frame::new(MessageType::QueryResponse, vec![2; 100]);
+
+        let client_task = async {
+            write_frame(&mut client, &query).await.unwrap();
+            let received = read_frame_with_limit(&mut client, |_| MAX_FRAME_BYTES)
+                .await
+                .unwrap();
+            drop(client);
+            received
+        };
+        let server_task = async {
+            let received = read_frame_with_limit(&mut server, |_| MAX_FRAME_BYTES)
+                .await
+                .unwrap();
+            write_frame(&mut server, &response).await.unwrap();
+            // client closed connection
+            assert_eq!(
+                read_frame_with_limit(&mut server, |_| MAX_FRAME_BYTES)
+                    .await
+                    .unwrap(),
+                None
+            );
+            received
+        };
+        let (client_received, server_received) = tokio::join!(client_task, server_task);
+        assert_eq!(client_received, Some(response));
+        assert_eq!(server_received, Some(query));
+    }
+}
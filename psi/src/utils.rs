@@ -9,35 +9,22 @@ use bfv::{
 };
 use crypto_bigint::{Encoding, U256};
 use itertools::{izip, Itertools};
-use rand::{distributions::Uniform, thread_rng, Rng};
-use rand_chacha::rand_core::le;
-use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+use rand::{distributions::Uniform, CryptoRng, Rng, RngCore, SeedableRng};
+use rand_chacha::{rand_core::le, ChaCha20Rng};
+use rayon::prelude::{IntoParallelIterator, IntoParallelRefMutIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use traits::TryEncodingWithParameters;
 
-pub fn decrypt_and_print(
-    evaluator: &Evaluator,
-    sk: &SecretKey,
-    ct: &Ciphertext,
-    tag: &str,
-    m_start: usize,
-    m_end: usize,
-) {
-    let noise = evaluator.measure_noise(sk, ct);
-    let m = &evaluator.plaintext_decode(&evaluator.decrypt(sk, ct), bfv::Encoding::default())
-        [m_start..m_end];
-    println!("{tag} - Noise: {noise}; m[{m_start}..{m_end}]: {:?}", m);
-}
-
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Node {
-    target: usize,
-    depth: usize,
-    s1: usize,
-    s2: usize,
+    pub(crate) target: usize,
+    pub(crate) depth: usize,
+    pub(crate) s1: usize,
+    pub(crate) s2: usize,
 }
 
+#[cfg(test)]
 pub fn construct_dag(source_powers: &[usize], target_powers: &[usize]) -> HashMap<usize, Node> {
     let mut dag = HashMap::<usize, Node>::new();
     let mut max_depth = 0;
@@ -104,9 +91,25 @@ pub fn construct_dag(source_powers: &[usize], target_powers: &[usize]) -> HashMa
     dag
 }
 
+/// Returns first power in `target_powers` that can't be computed from `source_powers` using `dag`, if any
+pub fn uncomputable_power(dag: &HashMap<usize, Node>, target_powers: &[usize]) -> Option<usize> {
+    target_powers
+        .iter()
+        .copied()
+        .find(|power| match dag.get(power) {
+            Some(node) => {
+                node.depth != 0 && !(dag.contains_key(&node.s1) && dag.contains_key(&node.s2))
+            }
+            None => true,
+        })
+}
+
 /// Calculates target powers ciphertexts from source powers ciphertexts using DAG. All source powers ciphertexts
 /// must be in Coefficient representation. Before returning all ciphertexts corresponding to power <= low_degree are changed
 /// to Evaluation representation for efficient plaintext multiplication in inner k loop for PS.
+///
+/// Powers are calculated in waves, each wave consisting of all powers whose sources are already calculated. Powers of a
+/// wave are independent of each other, thus are calculated in parallel.
 pub fn calculate_ps_powers_with_dag(
     evaluator: &Evaluator,
     ek: &EvaluationKey,
@@ -124,37 +127,64 @@ pub fn calculate_ps_powers_with_dag(
         target_powers_cts.insert(*p, ct.clone());
     });
 
-    // calculate target powers from the respective source powers
+    // collect target powers, and any intermediate powers they depend on, that aren't source powers
+    let mut pending = vec![];
     target_powers.iter().for_each(|p| {
-        if !target_powers_cts.contains_key(p) {
-            let node = dag.get(&p).unwrap();
-
-            let op1 = target_powers_cts.get(&node.s1).expect("Source 1 missing");
-            let op2 = target_powers_cts.get(&node.s2).expect("Source 2 missing");
-            let mut power_ct = evaluator.mul(op1, op2);
-            power_ct = evaluator.relinearize(&power_ct, ek);
-            // insert target power
-            target_powers_cts.insert(*p, power_ct);
-        }
+        collect_power_with_dag(*p, dag, &target_powers_cts, &mut pending);
     });
 
+    // calculate target powers from the respective source powers
+    while !pending.is_empty() {
+        let (ready, rest): (Vec<_>, Vec<_>) = pending.into_iter().partition(|node: &&Node| {
+            target_powers_cts.contains_key(&node.s1) && target_powers_cts.contains_key(&node.s2)
+        });
+        assert!(!ready.is_empty(), "DAG has a cycle");
+
+        let wave = ready
+            .into_par_iter()
+            .map(|node| {
+                let op1 = target_powers_cts.get(&node.s1).expect("Source 1 missing");
+                let op2 = target_powers_cts.get(&node.s2).expect("Source 2 missing");
+                let power_ct = evaluator.mul(op1, op2);
+                (node.target, evaluator.relinearize(&power_ct, ek))
+            })
+            .collect::<Vec<_>>();
+        target_powers_cts.extend(wave);
+        pending = rest;
+    }
+
     // convert all powers <= low_degree to `Evaluation` for efficient plaintext multiplication
-    for i in 0..ps_params.low_degree() {
-        let power = i + 1;
+    target_powers_cts
+        .par_iter_mut()
+        .filter(|(power, _)| **power <= ps_params.low_degree())
+        .for_each(|(_, ct)| {
+            evaluator.ciphertext_change_representation(ct, Representation::Evaluation);
+        });
 
-        match target_powers_cts.get_mut(&power) {
-            Some(ct) => {
-                evaluator.ciphertext_change_representation(ct, Representation::Evaluation);
-            }
-            _ => {}
-        }
+    target_powers_cts
+}
+
+/// Collects node of `power` and of any intermediate powers it depends on in `dag` into `pending`, if not already in
+/// `powers_cts` or `pending`
+fn collect_power_with_dag<'a>(
+    power: usize,
+    dag: &'a HashMap<usize, Node>,
+    powers_cts: &HashMap<usize, Ciphertext>,
+    pending: &mut Vec<&'a Node>,
+) {
+    if powers_cts.contains_key(&power) || pending.iter().any(|node| node.target == power) {
+        return;
     }
 
-    target_powers_cts
+    let node = dag.get(&power).unwrap();
+    collect_power_with_dag(node.s1, dag, powers_cts, pending);
+    collect_power_with_dag(node.s2, dag, powers_cts, pending);
+    pending.push(node);
 }
 
+#[cfg(test)]
 pub fn bfv_setup_test() -> (Evaluator, SecretKey) {
-    let mut rng = thread_rng();
+    let mut rng = rand::thread_rng();
     let psi_params = PsiParams::default();
     let mut params = BfvParameters::new(
         &psi_params.bfv_moduli,
@@ -169,30 +199,41 @@ pub fn bfv_setup_test() -> (Evaluator, SecretKey) {
 
 pub fn gen_bfv_params(psi_params: &PsiParams) -> BfvParameters {
     let mut params = BfvParameters::new(
-        &psi_params.bfv_moduli,
+        &psi_params.ciphertext_moduli(),
         psi_params.bfv_plaintext,
         psi_params.bfv_degree,
     );
-    params.enable_hybrid_key_switching(&[50, 50, 50]);
+    params.enable_hybrid_key_switching(&psi_params.hybrid_ksk_moduli);
     params
 }
 
-pub fn gen_random_item_labels(count: usize) -> Vec<ItemLabel> {
-    let cores = rayon::current_num_threads();
+/// Returns ChaCha20 RNG seeded with `seed`, or with OS entropy if `seed` isn't set. Runs with the same seed generate
+/// the same item labels, keys and ciphertexts, thus can be reproduced byte for byte.
+pub fn seeded_rng(seed: Option<u64>) -> ChaCha20Rng {
+    match seed {
+        Some(seed) => ChaCha20Rng::seed_from_u64(seed),
+        None => ChaCha20Rng::from_entropy(),
+    }
+}
 
-    let count_per_thread = count / cores;
-    let count_last_thread = (count - count_per_thread * cores) + count_per_thread;
-    dbg!(cores);
-    // Use up all cores.
-    (0..cores)
+/// No. of ItemLabels `gen_random_item_labels` generates from a single ChaCha20 stream
+const ITEM_LABELS_PER_STREAM: usize = 1 << 16;
+
+/// Generates `count` random ItemLabels using all cores. ItemLabels are generated in batches, each from its own stream of
+/// RNG seeded with `seed` (see `seeded_rng`), thus the same seed generates the same ItemLabels regardless of no. of
+/// cores.
+pub fn gen_random_item_labels(count: usize, seed: Option<u64>) -> Vec<ItemLabel> {
+    let rng = seeded_rng(seed);
+    let streams = (count + ITEM_LABELS_PER_STREAM - 1) / ITEM_LABELS_PER_STREAM;
+    (0..streams)
         .into_par_iter()
-        .flat_map(|core_index| {
-            let take = if core_index == cores - 1 {
-                count_last_thread
-            } else {
-                count_per_thread
-            };
-            let mut rng = thread_rng();
+        .flat_map(|stream| {
+            let take = std::cmp::min(
+                ITEM_LABELS_PER_STREAM,
+                count - stream * ITEM_LABELS_PER_STREAM,
+            );
+            let mut rng = rng.clone();
+            rng.set_stream(stream as u64);
             (0..take)
                 .into_iter()
                 .map(|_| {
@@ -205,34 +246,50 @@ pub fn gen_random_item_labels(count: usize) -> Vec<ItemLabel> {
         .collect()
 }
 
+/// Splits little endian bytes of `value` into `no_of_chunks` chunks of `bytes_per_chunk` bytes, each read as little
+/// endian u32. Only low `no_of_chunks * bytes_per_chunk` bytes of `value` are chunked. Bytes beyond 32 bytes of `value`
+/// are zero, thus last chunk is zero padded if 32 isn't a multiple of `bytes_per_chunk`.
 pub fn value_to_chunks(value: &U256, no_of_chunks: u32, bytes_per_chunk: u32) -> Vec<u32> {
+    assert!(
+        (1..=4).contains(&bytes_per_chunk),
+        "Chunk of {bytes_per_chunk} bytes does not fit in u32"
+    );
     let value_bytes = value.to_le_bytes();
 
-    let mut chunks = vec![];
-    for chunk_index in 0..no_of_chunks {
-        let chunk_start = (chunk_index * bytes_per_chunk) as usize;
-        let bytes = &value_bytes[(chunk_start..chunk_start + bytes_per_chunk as usize)];
-        chunks.push(bytes_to_u32(bytes));
-    }
-
-    chunks
+    (0..no_of_chunks as usize)
+        .map(|chunk_index| {
+            let chunk_start = (chunk_index * bytes_per_chunk as usize).min(value_bytes.len());
+            let chunk_end = (chunk_start + bytes_per_chunk as usize).min(value_bytes.len());
+            bytes_to_u32(&value_bytes[chunk_start..chunk_end])
+        })
+        .collect()
 }
 
-/// Chunks must be in little endian
+/// Inverse of `value_to_chunks`. Returns value of low `total_bytes` bytes held by `chunks` of `bytes_per_chunk` bytes
+/// each, in little endian. Panics if a chunk has bits set beyond `bytes_per_chunk` bytes or beyond `total_bytes`,
+/// instead of silently dropping them.
+#[cfg(test)]
 pub fn chunks_to_value(chunks: &[u32], total_bytes: u32, bytes_per_chunk: u32) -> U256 {
-    assert!(chunks.len() == (total_bytes / bytes_per_chunk) as usize);
+    assert!((1..=4).contains(&bytes_per_chunk) && total_bytes <= 32);
+    assert!(chunks.len() == ((total_bytes + bytes_per_chunk - 1) / bytes_per_chunk) as usize);
+
+    let bytes = chunks
+        .iter()
+        .flat_map(|c| {
+            assert!(
+                (*c as u64) >> (bytes_per_chunk * 8) == 0,
+                "Chunk {c} exceeds {bytes_per_chunk} bytes"
+            );
+            c.to_le_bytes().into_iter().take(bytes_per_chunk as usize)
+        })
+        .collect_vec();
+    assert!(
+        bytes[total_bytes as usize..].iter().all(|b| *b == 0),
+        "Chunks exceed {total_bytes} bytes"
+    );
 
     let mut u256_bytes = [0u8; 32];
-
-    let mut byte_index = 0;
-    chunks.iter().enumerate().for_each(|(_, c)| {
-        (0..bytes_per_chunk).into_iter().for_each(|index| {
-            // extract byte
-            u256_bytes[byte_index] = ((c >> (index * 8)) & 255) as u8;
-            byte_index += 1;
-        });
-    });
-
+    u256_bytes[..total_bytes as usize].copy_from_slice(&bytes[..total_bytes as usize]);
     U256::from_le_bytes(u256_bytes)
 }
 
@@ -251,14 +308,24 @@ macro_rules! time_it{
     }
 }
 
-pub fn generate_evaluation_key(evaluator: &Evaluator, sk: &SecretKey) -> EvaluationKey {
-    let mut rng = thread_rng();
-    EvaluationKey::new(evaluator.params(), &sk, &[0], &[], &[], &mut rng)
+/// Generates evaluation key with relinearization keys at level 0, at which PS powers are calculated, and at
+/// `PsiParams::evaluation_level`, at which polynomials are evaluated
+pub fn generate_evaluation_key<R: RngCore + CryptoRng>(
+    psi_params: &PsiParams,
+    evaluator: &Evaluator,
+    sk: &SecretKey,
+    rng: &mut R,
+) -> EvaluationKey {
+    let rlk_levels = [0, psi_params.evaluation_level]
+        .into_iter()
+        .dedup()
+        .collect_vec();
+    EvaluationKey::new(evaluator.params(), &sk, &rlk_levels, &[], &[], rng)
 }
 
 /// Generates random ItemLabels and stores them update /data dir. We store the file as .bin since it is the fastest.
 fn generate_random_item_labels_and_store(set_size: usize) {
-    let server_set = gen_random_item_labels(set_size);
+    let server_set = gen_random_item_labels(set_size, None);
 
     // // create parent directory for data
     std::fs::create_dir_all("./../data").expect("Create data directory failed");
@@ -268,15 +335,15 @@ fn generate_random_item_labels_and_store(set_size: usize) {
     bincode::serialize_into(server_file, &server_set).unwrap();
 }
 
-pub fn generate_random_intersection_and_store(
+pub fn generate_random_intersection_and_store<R: Rng>(
     server_set: &[ItemLabel],
     intersection_size: usize,
+    rng: &mut R,
 ) -> Vec<ItemLabel> {
     assert!(server_set.len() > intersection_size);
 
     let mut inserted_indices = vec![];
     let mut client_set = vec![];
-    let mut rng = thread_rng();
     while inserted_indices.len() != intersection_size {
         let index = rng.gen_range(0..server_set.len());
         if !inserted_indices.contains(&index) {
@@ -291,11 +358,75 @@ pub fn generate_random_intersection_and_store(
 #[cfg(test)]
 mod tests {
     use itertools::Itertools;
+    use proptest::prelude::*;
+    use rand::thread_rng;
 
     use crate::client::calculate_source_powers;
 
     use super::*;
 
+    proptest! {
+        #[test]
+        fn value_chunks_roundtrip(bytes in any::<[u8; 32]>(), bytes_per_chunk in 1u32..=4) {
+            let value = U256::from_le_bytes(bytes);
+            let no_of_chunks = (32 + bytes_per_chunk - 1) / bytes_per_chunk;
+            let chunks = value_to_chunks(&value, no_of_chunks, bytes_per_chunk);
+            prop_assert!(chunks.iter().all(|c| (*c as u64) >> (bytes_per_chunk * 8) == 0));
+            prop_assert_eq!(chunks_to_value(&chunks, 32, bytes_per_chunk), value);
+        }
+
+        #[test]
+        fn value_chunks_of_low_bytes_roundtrip(
+            mut bytes in any::<[u8; 32]>(),
+            bytes_per_chunk in 1u32..=4,
+            total_bytes in 1u32..=32,
+        ) {
+            // only low `total_bytes` bytes are chunked
+            bytes[total_bytes as usize..].fill(0);
+            let value = U256::from_le_bytes(bytes);
+            let no_of_chunks = (total_bytes + bytes_per_chunk - 1) / bytes_per_chunk;
+            let chunks = value_to_chunks(&value, no_of_chunks, bytes_per_chunk);
+            prop_assert_eq!(chunks_to_value(&chunks, total_bytes, bytes_per_chunk), value);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn chunks_to_value_rejects_oversized_chunks() {
+        chunks_to_value(&[1 << 16, 0], 4, 2);
+    }
+
+    #[test]
+    fn seeded_runs_are_reproducible() {
+        let count = ITEM_LABELS_PER_STREAM + 10;
+        let item_labels = gen_random_item_labels(count, Some(7));
+        assert_eq!(item_labels.len(), count);
+        assert_eq!(item_labels, gen_random_item_labels(count, Some(7)));
+        assert_ne!(item_labels, gen_random_item_labels(count, Some(8)));
+        assert_ne!(
+            gen_random_item_labels(10, None),
+            gen_random_item_labels(10, None)
+        );
+
+        // same seed results in the same secret key and query ciphertexts
+        let psi_params = PsiParams::default();
+        let evaluator = Evaluator::new(gen_bfv_params(&psi_params));
+        let query_set = item_labels
+            .iter()
+            .take(100)
+            .map(|il| il.item().clone())
+            .collect_vec();
+        let query_bytes = |seed| {
+            let mut rng = seeded_rng(Some(seed));
+            let sk = SecretKey::random_with_params(evaluator.params(), &mut rng);
+            let query_state =
+                crate::construct_query(&query_set, &psi_params, &evaluator, &sk, &mut rng);
+            crate::serialize_query(query_state.query(), evaluator.params())
+        };
+        assert_eq!(query_bytes(3), query_bytes(3));
+        assert_ne!(query_bytes(3), query_bytes(4));
+    }
+
     #[test]
     fn dag() {
         let source_powers = vec![1, 3, 11, 18, 45, 225];
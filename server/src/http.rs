@@ -0,0 +1,332 @@
+//! HTTP transport of the server, for deployments where raw TCP is blocked by infrastructure. Requests carry the same
+//! payloads as frames of the TCP protocol:
+//!
+//! - `POST /keys` caches evaluation key in the body, prefixed with client id, same as `MessageType::EvaluationKey`
+//! - `POST /query` evaluates query in the body, prefixed with client id, same as `MessageType::Query`, and responds
+//!   with bincode serialized `SerializedQueryResponse`. Responds with `428 Precondition Required` if evaluation key of
+//!   the client isn't cached, in which case client uploads it with `POST /keys` and retries.
+//! - `GET /params` responds with `PsiParams` as JSON
+//!
+//! Clients authenticate every request with `Authorization: Bearer <token>` if server requires API tokens and select
+//! tenant with `HTTP_TENANT_HEADER`. Compression and query metadata aren't supported.
+//!
+//! Queries rejected by `QueryLimiter` are responded with `429 Too Many Requests` if client exceeded its rate limit or
+//! `503 Service Unavailable` if server is overloaded, along with `Retry-After` header in seconds.
+
+use axum::{
+    body::Bytes,
+    extract::{ConnectInfo, DefaultBodyLimit, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json, Router,
+};
+use psi::{
+    serialize_query_response, AuthError, BusyReason, ClientId, ClientKey, MessageType,
+    ProtocolError, PsiError, QueryStage, Tenant, TokenId, HTTP_TENANT_HEADER,
+};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::net::TcpListener;
+use tracing::{info, warn};
+
+use crate::{
+    decode_evaluation_key, deserialize_client_query, evaluate_query, query_cancellation,
+    shutdown_signal, ServerContext,
+};
+
+/// Returns router serving endpoints of HTTP transport with `context`. Bodies are limited to max. frame size of
+/// default tenant's server, same as frames of TCP protocol.
+pub(crate) fn router(context: Arc<ServerContext>) -> Router {
+    let validator = context.tenants.default_tenant().server().query_validator();
+    let limit = |message_type: MessageType| {
+        DefaultBodyLimit::max(validator.max_frame_bytes(message_type) as usize)
+    };
+    Router::new()
+        .route(
+            "/keys",
+            post(upload_keys).layer(limit(MessageType::EvaluationKey)),
+        )
+        .route("/query", post(query).layer(limit(MessageType::Query)))
+        .route("/params", get(params))
+        .with_state(context)
+}
+
+/// Serves HTTP transport on `listener` until SIGINT or SIGTERM, after which in-flight requests are given upto
+/// `shutdown_timeout` to finish
+pub(crate) async fn serve(
+    listener: TcpListener,
+    context: Arc<ServerContext>,
+    shutdown_timeout: Duration,
+) -> Result<(), PsiError> {
+    let app = router(context.clone());
+    let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+    let server = axum::Server::from_tcp(listener.into_std()?)
+        .map_err(|e| PsiError::Io(format!("Failed to start HTTP server: {e}")))?
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async {
+            let _ = stopped.await;
+        });
+    tokio::pin!(server);
+
+    tokio::select! {
+        result = &mut server => {
+            return result.map_err(|e| PsiError::Io(format!("HTTP server failed: {e}")));
+        }
+        _ = shutdown_signal() => {}
+    }
+    info!("Shutting down. Waiting for in-flight requests to finish");
+    context.shutdown.send_replace(true);
+    let _ = stop.send(());
+    match tokio::time::timeout(shutdown_timeout, server).await {
+        Ok(result) => result.map_err(|e| PsiError::Io(format!("HTTP server failed: {e}"))),
+        Err(_) => {
+            warn!(
+                "Aborting requests that did not finish within {} s",
+                shutdown_timeout.as_secs()
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Responds with `result`, or with status matching the error and its description as body
+fn respond(result: Result<Response, PsiError>) -> Response {
+    match result {
+        Ok(response) => response,
+        Err(PsiError::Protocol(ProtocolError::Busy {
+            reason,
+            retry_after,
+        })) => {
+            let status = match reason {
+                BusyReason::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+                BusyReason::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            };
+            warn!(%status, "Rejected request of busy client");
+            // Retry-After is in whole seconds
+            let retry_after = retry_after.as_secs_f64().ceil() as u64;
+            (
+                status,
+                [(header::RETRY_AFTER, retry_after.to_string())],
+                format!("Server is busy ({reason})"),
+            )
+                .into_response()
+        }
+        Err(e) => {
+            let status = match &e {
+                PsiError::Auth(AuthError::QuotaExceeded { .. }) => StatusCode::TOO_MANY_REQUESTS,
+                PsiError::Auth(AuthError::Malformed(_)) => StatusCode::INTERNAL_SERVER_ERROR,
+                PsiError::Auth(_) => StatusCode::UNAUTHORIZED,
+                PsiError::Cancelled(_) => StatusCode::SERVICE_UNAVAILABLE,
+                PsiError::Serialization(_)
+                | PsiError::ParamsMismatch(_)
+                | PsiError::InvalidQuery(_)
+                | PsiError::Protocol(_) => StatusCode::BAD_REQUEST,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
+            warn!(%status, "Request failed with error: {e}");
+            (status, e.to_string()).into_response()
+        }
+    }
+}
+
+/// Authenticates request with bearer token in `Authorization` header. Returns `None` if server does not require
+/// authentication.
+fn authenticate(context: &ServerContext, headers: &HeaderMap) -> Result<Option<TokenId>, PsiError> {
+    let server = context.tenants.default_tenant().server();
+    if server.token_store().is_none() {
+        return Ok(None);
+    }
+    let token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .ok_or(AuthError::NotAuthenticated)?;
+    server.authenticate(token)
+}
+
+/// Returns tenant selected with `HTTP_TENANT_HEADER`, or `DEFAULT_TENANT` if the header isn't set
+fn tenant<'a>(
+    context: &'a ServerContext,
+    headers: &HeaderMap,
+) -> Result<&'a Arc<Tenant>, PsiError> {
+    match headers.get(HTTP_TENANT_HEADER) {
+        Some(id) => context.tenants.get(id.as_bytes()),
+        None => Ok(context.tenants.default_tenant()),
+    }
+}
+
+async fn upload_keys(
+    State(context): State<Arc<ServerContext>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let peer = peer.map(|ConnectInfo(addr)| addr.ip());
+    respond(cache_evaluation_key(&context, peer, &headers, &body))
+}
+
+/// Caches evaluation key in `body` of client at `peer` under client id it is prefixed with. Key is bound to client's
+/// `ClientKey`, same as keys uploaded over TCP.
+fn cache_evaluation_key(
+    context: &ServerContext,
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, PsiError> {
+    let token = authenticate(context, headers)?;
+    let tenant = tenant(context, headers)?;
+    let (client_id, ek) = decode_evaluation_key(body, tenant.server())?;
+    tenant
+        .key_cache()
+        .insert(ClientKey::new(token.as_ref(), peer), client_id, ek);
+    info!(tenant = tenant.id(), "Cached evaluation key of client");
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
+
+async fn query(
+    State(context): State<Arc<ServerContext>>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let peer = peer.map(|ConnectInfo(addr)| addr.ip());
+    respond(process_query(&context, peer, &headers, &body).await)
+}
+
+/// Evaluates query in `body` of client at `peer` and responds with bincode serialized `SerializedQueryResponse`
+async fn process_query(
+    context: &ServerContext,
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<Response, PsiError> {
+    info!("Received new query");
+    let cancellation = query_cancellation(context);
+
+    let token = authenticate(context, headers)?;
+    let tenant = tenant(context, headers)?;
+    let server = tenant.server();
+    let client_key = ClientKey::new(token.as_ref(), peer);
+    let (client_id, payload) = ClientId::split_prefix(body)?;
+    let client_evaluation_key = match tenant.key_cache().get(client_key, &client_id) {
+        Some(ek) => ek,
+        None => {
+            info!("Evaluation key of client is not cached. Requesting upload");
+            return Ok(StatusCode::PRECONDITION_REQUIRED.into_response());
+        }
+    };
+    let (query, _) = deserialize_client_query(payload, server)?;
+    let query = query.with_client_id(client_id);
+    context.limiter.check_rate(client_key.as_ref())?;
+    context
+        .tenants
+        .default_tenant()
+        .server()
+        .record_query(token.as_ref())?;
+
+    let (query_response, _) =
+        evaluate_query(context, server, query, client_evaluation_key, cancellation).await?;
+
+    let now = std::time::Instant::now();
+    let response_bytes = bincode::serialize(&serialize_query_response(
+        &query_response,
+        server.evaluator().params(),
+    ))?;
+    server
+        .metrics()
+        .observe(QueryStage::Serialize, now.elapsed());
+    server.metrics().record_response_bytes(response_bytes.len());
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/octet-stream")],
+        response_bytes,
+    )
+        .into_response())
+}
+
+async fn params(State(context): State<Arc<ServerContext>>, headers: HeaderMap) -> Response {
+    respond(
+        tenant(&context, &headers)
+            .map(|tenant| Json(tenant.server().psi_params().clone()).into_response()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use bfv::SecretKey;
+    use psi::{
+        gen_bfv_params, gen_random_item_labels, random_u256, EvaluationKeyCache, HttpPsiClient,
+        PsiParams, QueryLimiter, Server, Tenants,
+    };
+    use rand::thread_rng;
+    use tokio::sync::watch;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn query_over_http_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let context = Arc::new(ServerContext {
+            tenants: Tenants::new(server, EvaluationKeyCache::default()),
+            limiter: QueryLimiter::new(1),
+            query_timeout: None,
+            shutdown: watch::channel(false).0,
+        });
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(
+            axum::Server::from_tcp(listener)
+                .unwrap()
+                .serve(router(context).into_make_service()),
+        );
+
+        let sk = SecretKey::random_with_params(&gen_bfv_params(&psi_params), &mut rng);
+        let mut client = HttpPsiClient::new(
+            &format!("http://{addr}/"),
+            &psi_params,
+            sk,
+            ClientId::random(&mut rng),
+        );
+        assert_eq!(client.fetch_params().await.unwrap(), psi_params);
+
+        // evaluation key is uploaded when server asks for it on the first query and is cached for the second
+        for _ in 0..2 {
+            let items = item_labels[..10]
+                .iter()
+                .map(|item_label| *item_label.item())
+                .chain((0..10).map(|_| random_u256(&mut rng)))
+                .collect::<Vec<_>>();
+            let responses = client.query(&items).await.unwrap();
+            item_labels[..10].iter().for_each(|item_label| {
+                let response = responses
+                    .iter()
+                    .find(|response| response.item() == item_label.item())
+                    .expect("Item at intersection is missing in response");
+                assert!(response.labels().contains(item_label.label()));
+            });
+        }
+
+        // unknown tenant
+        let sk = SecretKey::random_with_params(&gen_bfv_params(&psi_params), &mut rng);
+        let client = HttpPsiClient::new(
+            &format!("http://{addr}"),
+            &psi_params,
+            sk,
+            ClientId::random(&mut rng),
+        )
+        .with_tenant("missing");
+        assert!(matches!(
+            client.fetch_params().await,
+            Err(PsiError::Protocol(_))
+        ));
+    }
+}
@@ -1,221 +1,1959 @@
-use bfv::{EvaluationKey, EvaluationKeyProto};
+use bfv::EvaluationKey;
 use clap::{Parser, Subcommand};
-use prost::Message;
+use indicatif::{ProgressBar, ProgressStyle};
 use psi::{
-    db::{self, Db},
-    deserialize_query, expected_query_bytes, gen_random_item_labels,
-    generate_random_intersection_and_store, serialize_query_response, ItemLabel, PsiParams, Server,
-};
-use std::{
-    error::Error,
-    io::{BufReader, BufWriter, Read},
+    compress, decompress, deserialize_evaluation_key, deserialize_query, deserialize_query_batch,
+    estimate_cost, gen_random_item_labels, generate_random_intersection_and_store,
+    import_item_labels, partition_item_labels, read_deltas, run_bench, seeded_rng,
+    serialize_query_response, serialize_segment_response, tls_acceptor, write_frame, AuthError,
+    BincodeItemStore, CancelOnDrop, CancellationToken, ClientId, ClientKey, Db, DbKey,
+    EvaluationKeyCache, Frame, FrameReader, ImportFormat, ImportOptions, ItemStore, MessageType,
+    OprfRequest, Partition, ProgressSink, ProtocolError, PsiError, PsiParams, Query, QueryLimiter,
+    QueryMetadata, QueryResponse, QueryStage, Server, SetupStage, ShardCoordinator, Tenant,
+    Tenants, ThreadPools, TokenId, TokenStore, ValueEncoding, CAPABILITY_DB_STATS,
+    CAPABILITY_METADATA, CAPABILITY_ZSTD, DEFAULT_TENANT, ITEM_STORE_BATCH_SIZE,
+    MAX_TENANT_ID_BYTES, OPRF_POINT_BYTES,
 };
+use std::io::{BufReader, BufWriter};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::{
     fs::File,
+    future::Future,
     path::{Path, PathBuf},
+    pin::Pin,
+    task::Poll,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::TcpListener,
+    sync::{mpsc::Sender, watch, SemaphorePermit},
+    task::JoinSet,
 };
-use tokio::io::{AsyncReadExt, AsyncWriteExt, Result};
-use tokio::net::{TcpListener, TcpStream};
-use traits::TryFromWithParameters;
-
-pub fn read_client_evaluation_key(server: &Server) -> Result<EvaluationKey> {
-    let mut file = std::fs::File::open("./../data/client/client_evaluation_key.bin")?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer)?;
-    let ek_proto = EvaluationKeyProto::decode(&*buffer)?;
-    let evaluation_key =
-        EvaluationKey::try_from_with_parameters(&ek_proto, server.evaluator().params());
-    Ok(evaluation_key)
-}
-
-/// Randomly generates `count` ItemLabels as server and stores them under directory ./data/{count}/server_set.bin
-fn generate_random_server_set(count: usize) {
+use tokio_rustls::TlsAcceptor;
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+
+mod http;
+#[cfg(feature = "quic")]
+mod quic;
+
+/// Randomly generates `count` ItemLabels as server and stores them under directory `dir_path`/server_set.bin, with
+/// `psi_params` in header. The same `seed` generates the same ItemLabels.
+fn generate_random_server_set(
+    count: usize,
+    dir_path: &Path,
+    seed: Option<u64>,
+    psi_params: &PsiParams,
+) -> Result<(), PsiError> {
     // check server_set.bin already exists at necessary path. If it does, abort
-    let dir_path = format!("./../data/{}", count);
-    let mut server_set_file_path = PathBuf::from(dir_path.clone());
+    let mut server_set_file_path = PathBuf::from(dir_path);
     server_set_file_path.push("server_set.bin");
     if Path::exists(&server_set_file_path) {
-        panic!(
+        return Err(PsiError::Io(format!(
             "Server dataset for {} already exists at {}",
             count,
             server_set_file_path.display()
-        );
+        )));
     }
 
-    let server_set = gen_random_item_labels(count);
+    let server_set = gen_random_item_labels(count, seed);
 
-    std::fs::create_dir_all(dir_path.clone())
-        .expect(&format!("Creating directory at {} failed", dir_path));
+    std::fs::create_dir_all(dir_path)?;
+    BincodeItemStore::open(&server_set_file_path, psi_params)?.insert(&server_set)
+}
+
+/// Imports server set from dataset at `input` and stores it under `data_dir`/{set_size}/server_set.bin, where set_size
+/// is the no. of ItemLabels imported. Server set is then preprocessed with `preprocess {set_size}`.
+fn import_server_set(
+    input: &Path,
+    data_dir: &Path,
+    options: &ImportOptions,
+    psi_params: &PsiParams,
+) -> Result<(), PsiError> {
+    let file = File::open(input)
+        .map_err(|e| PsiError::Io(format!("Failed to open {}: {e}", input.display())))?;
+    let item_labels = import_item_labels(BufReader::new(file), options)?;
+
+    let dir_path = set_size_to_dir_path(data_dir, item_labels.len());
+    let server_set_file_path = dir_path.join("server_set.bin");
+    if Path::exists(&server_set_file_path) {
+        return Err(PsiError::Io(format!(
+            "Server dataset already exists at {}",
+            server_set_file_path.display()
+        )));
+    }
 
-    // rust does not uses buffered I/O by default. Use BufWriter to use buffered I/O.
-    // Ref - https://stackoverflow.com/questions/49983101/serialization-of-large-struct-to-disk-with-serde-and-bincode-is-slow
-    let mut server_file = BufWriter::new(
-        File::create(server_set_file_path).expect("Failed to create server_set.bin"),
+    std::fs::create_dir_all(&dir_path)?;
+    BincodeItemStore::open(&server_set_file_path, psi_params)?.insert(&item_labels)?;
+    info!(
+        count = item_labels.len(),
+        path = %server_set_file_path.display(),
+        "Imported server set"
     );
-    bincode::serialize_into(&mut server_file, &server_set).unwrap();
+    Ok(())
+}
+
+/// Partitions server set stored at `dir_path`/server_set.bin into `shard_count` shards with `partition_item_labels`.
+/// Shard `i` is stored at `dir_path`/shards/{i}/server_set.bin, thus it is preprocessed and started with
+/// `--data-dir {dir_path}/shards preprocess {i}` and `start {i}`. Server set is read in batches, thus it need not fit
+/// in memory.
+fn shard_server_set(
+    dir_path: &Path,
+    shard_count: usize,
+    psi_params: &PsiParams,
+) -> Result<(), PsiError> {
+    if shard_count == 0 {
+        return Err(PsiError::InvalidParams(
+            "No. of shards must be positive".to_string(),
+        ));
+    }
+    let server_set = BincodeItemStore::open(&dir_path.join("server_set.bin"), psi_params)?;
+
+    let mut shards = vec![];
+    for index in 0..shard_count {
+        let shard_dir = dir_path.join("shards").join(index.to_string());
+        let shard_set_path = shard_dir.join("server_set.bin");
+        if Path::exists(&shard_set_path) {
+            return Err(PsiError::Io(format!(
+                "Shard already exists at {}",
+                shard_set_path.display()
+            )));
+        }
+        std::fs::create_dir_all(&shard_dir)?;
+        shards.push(BincodeItemStore::open(&shard_set_path, psi_params)?);
+    }
+
+    server_set.for_each_batch(ITEM_STORE_BATCH_SIZE, &mut |batch| {
+        for (shard, item_labels) in shards
+            .iter_mut()
+            .zip(partition_item_labels(&batch, shard_count))
+        {
+            shard.insert(&item_labels)?;
+        }
+        Ok(())
+    })?;
+
+    for (index, shard) in shards.iter().enumerate() {
+        info!(shard = index, count = shard.len()?, "Stored shard");
+    }
+    Ok(())
 }
 
-/// Runs preprocessing for server using server set stored at `dir_path`/server_set.bin (for ex, data/1000/server_set.bin). Then stores pre-processed server's `Db` at `dir_path`/server_db_preprocessed.bin.
-fn preprocess_and_store_dataset(dir_path: &Path, psi_params: &PsiParams) -> Server {
+/// Draws progress bar with ETA of current setup stage on stderr. Bars are hidden if `quiet`.
+struct ProgressBarSink {
+    bar: Mutex<Option<ProgressBar>>,
+    quiet: bool,
+}
+
+impl ProgressBarSink {
+    fn new(quiet: bool) -> ProgressBarSink {
+        ProgressBarSink {
+            bar: Mutex::new(None),
+            quiet,
+        }
+    }
+}
+
+impl ProgressSink for ProgressBarSink {
+    fn start(&self, stage: SetupStage, total: u64) {
+        let bar = if self.quiet {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(total)
+        };
+        bar.set_style(
+            ProgressStyle::with_template(
+                "{msg} [{elapsed_precise}] {wide_bar} {human_pos}/{human_len} (ETA {eta})",
+            )
+            .expect("Template is valid"),
+        );
+        bar.set_message(stage.to_string());
+        *self.bar.lock().unwrap() = Some(bar);
+    }
+
+    fn advance(&self, _stage: SetupStage, units: u64) {
+        if let Some(bar) = self.bar.lock().unwrap().as_ref() {
+            bar.inc(units);
+        }
+    }
+
+    fn finish(&self, _stage: SetupStage) {
+        if let Some(bar) = self.bar.lock().unwrap().take() {
+            bar.finish();
+        }
+    }
+}
+
+/// Opens `ItemStore` of server set. `item_store` is `sqlite:{path}` or `rocksdb:{path}`, if the server is built with
+/// the respective feature. Defaults to `dir_path`/server_set.bin, which must have been stored with `psi_params`.
+fn open_item_store(
+    item_store: Option<&str>,
+    dir_path: &Path,
+    psi_params: &PsiParams,
+) -> Result<Box<dyn ItemStore>, PsiError> {
+    match item_store.map(|spec| spec.split_once(':').unwrap_or((spec, ""))) {
+        None => {
+            let server_set_path = dir_path.join("server_set.bin");
+            if !Path::exists(&server_set_path) {
+                return Err(PsiError::Io(format!(
+                    "Server set does not exist at {}",
+                    server_set_path.display()
+                )));
+            }
+            Ok(Box::new(BincodeItemStore::open(
+                &server_set_path,
+                psi_params,
+            )?))
+        }
+        #[cfg(feature = "sqlite")]
+        Some(("sqlite", path)) => Ok(Box::new(psi::SqliteItemStore::open(Path::new(path))?)),
+        #[cfg(feature = "rocksdb")]
+        Some(("rocksdb", path)) => Ok(Box::new(psi::RocksDbItemStore::open(Path::new(path))?)),
+        Some((kind, _)) => Err(PsiError::Io(format!(
+            "Unsupported item store {kind}. Expected sqlite:{{path}} or rocksdb:{{path}} with the respective feature enabled."
+        ))),
+    }
+}
+
+/// Runs preprocessing for server using server set in `item_store` (see `open_item_store`), which defaults to server set stored at `dir_path`/server_set.bin (for ex, data/1000/server_set.bin). Then stores pre-processed server's `Db` at `dir_path`/server_db_preprocessed.bin, encrypted with `db_key` if set. Preprocessing runs on `thread_pools` if set.
+fn preprocess_and_store_dataset(
+    dir_path: &Path,
+    item_store: Option<&str>,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+    thread_pools: Option<&ThreadPools>,
+    progress: &dyn ProgressSink,
+) -> Result<Server, PsiError> {
     // check that preprocessed data already exists. If it does then abort
     let mut server_db_preprocessed_path = PathBuf::from(dir_path);
     server_db_preprocessed_path.push("server_db_preprocessed.bin");
     if Path::exists(&server_db_preprocessed_path) {
-        panic!(
+        return Err(PsiError::Io(format!(
             "server_db_preprocessed.bin file already exists at {}",
             server_db_preprocessed_path.display()
-        );
+        )));
     }
 
-    // read server set
-    let mut server_set_path = PathBuf::from(dir_path);
-    server_set_path.push("server_set.bin");
-    let file = std::fs::File::open(server_set_path.clone()).expect(&format!(
-        "Failed to open server_set.bin at {}",
-        server_set_path.display()
-    ));
-    let reader = BufReader::new(file);
-    let item_labels: Vec<ItemLabel> =
-        bincode::deserialize_from(reader).expect("Invalid server_set.bin file");
-
-    println!(
-        "Preprocessing server set with {} ItemLabels",
-        item_labels.len()
-    );
+    // server set is read from store in batches
+    let store = open_item_store(item_store, dir_path, psi_params)?;
+    info!(count = store.len()?, "Preprocessing server set");
 
-    // create new server and setup
+    // create new server and setup. Coefficients are streamed to server_db_preprocessed.bin, laid out for memory
+    // mapping, as soon as they are generated. Encrypted db is stored once all coefficients are generated.
     let mut server = Server::new(psi_params);
-    server.setup(&item_labels);
+    if let Some(thread_pools) = thread_pools {
+        server = server.with_shared_thread_pools(thread_pools.clone());
+    }
+    match db_key {
+        Some(db_key) => {
+            server.setup_from_store(store.as_ref(), progress)?;
+            server.store_db_encrypted(&server_db_preprocessed_path, db_key)?;
+        }
+        None => server.setup_and_store(store.as_ref(), &server_db_preprocessed_path, progress)?,
+    }
     server.print_diagnosis();
 
-    // serialize and store server db in server_db_preprocessed.bin
-    let mut server_db_preprocessed_file =
-        BufWriter::new(std::fs::File::create(server_db_preprocessed_path).unwrap());
-    bincode::serialize_into(&mut server_db_preprocessed_file, server.db()).unwrap();
-
-    server
+    Ok(server)
 }
 
-/// Returns an active instance of `Server` by loading preprocessed server db file stored at `server_db_preprocessed`
-fn load_server(server_db_preprocessed: &Path, psi_params: &PsiParams) -> Server {
-    let file = std::fs::File::open(server_db_preprocessed.clone()).expect(&format!(
-        "Failed to open server_db_preprocessed.bin at {}",
-        server_db_preprocessed.display()
-    ));
-    let reader = BufReader::new(file);
-    let db: Db = bincode::deserialize_from(reader).expect(&format!(
-        "Malformed server db bin file {}",
-        server_db_preprocessed.display()
+/// Preprocesses InnerBoxes of `partition` of server set stored under `dir_path` and stores partial db at
+/// `dir_path`/server_db_partial_{i}_{n}.bin (see `Db::preprocess_partition_and_store`). Partial dbs aren't encrypted,
+/// thus partitioned preprocessing is refused if `db_key` is set.
+fn preprocess_and_store_partition(
+    dir_path: &Path,
+    item_store: Option<&str>,
+    partition: Partition,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+    thread_pools: Option<&ThreadPools>,
+    progress: &dyn ProgressSink,
+) -> Result<(), PsiError> {
+    if db_key.is_some() {
+        return Err(PsiError::InvalidParams(
+            "Partial dbs can't be encrypted. Preprocess encrypted dbs without --shard.".to_string(),
+        ));
+    }
+    let path = dir_path.join(format!(
+        "server_db_partial_{}_{}.bin",
+        partition.index(),
+        partition.count()
     ));
+    if path.exists() {
+        return Err(PsiError::Io(format!(
+            "Partial db already exists at {}",
+            path.display()
+        )));
+    }
 
-    Server::new_with_db(db, psi_params)
+    let store = open_item_store(item_store, dir_path, psi_params)?;
+    info!(count = store.len()?, %partition, "Preprocessing partition of server set");
+    let mut server = Server::new(psi_params);
+    if let Some(thread_pools) = thread_pools {
+        server = server.with_shared_thread_pools(thread_pools.clone());
+    }
+    server.setup_partition_and_store(store.as_ref(), &path, partition, progress)?;
+    info!(path = %path.display(), "Partial db stored");
+    Ok(())
+}
+
+/// Merges partial dbs at `partials`, or all server_db_partial_*.bin files under `dir_path` if empty, into
+/// `dir_path`/server_db_preprocessed.bin (see `Db::merge_partitions`)
+fn merge_db(
+    dir_path: &Path,
+    mut partials: Vec<PathBuf>,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+) -> Result<(), PsiError> {
+    if db_key.is_some() {
+        return Err(PsiError::InvalidParams(
+            "Partial dbs can't be merged into an encrypted db".to_string(),
+        ));
+    }
+    let path = dir_path.join("server_db_preprocessed.bin");
+    if path.exists() {
+        return Err(PsiError::Io(format!(
+            "server_db_preprocessed.bin file already exists at {}",
+            path.display()
+        )));
+    }
+    if partials.is_empty() {
+        for entry in std::fs::read_dir(dir_path)? {
+            let entry_path = entry?.path();
+            let name = entry_path.file_name().and_then(|name| name.to_str());
+            if matches!(name, Some(name) if name.starts_with("server_db_partial_") && name.ends_with(".bin"))
+            {
+                partials.push(entry_path);
+            }
+        }
+        partials.sort();
+    }
+
+    info!(partials = partials.len(), "Merging partial dbs");
+    let db = Db::merge_partitions(&partials, &path, psi_params)?;
+    db.print_diagnosis();
+    info!(path = %path.display(), "Merged db stored");
+    Ok(())
+}
+
+/// Env variable with hex encoded key of encrypted db files. Preprocessed db is stored encrypted, and must be loaded with
+/// the key, if set.
+const DB_KEY_ENV: &str = "SERVER_DB_KEY";
+
+/// Reads key of encrypted db files from `DB_KEY_ENV`
+fn db_key_from_env() -> Result<Option<DbKey>, PsiError> {
+    match std::env::var(DB_KEY_ENV) {
+        Ok(key) => Ok(Some(DbKey::from_hex(&key)?)),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Loads db stored at `path`, decrypting it with `db_key` if set (see `Db::load_encrypted`). Refuses to load db stored
+/// with params other than `psi_params`.
+fn load_db(path: &Path, psi_params: &PsiParams, db_key: Option<&DbKey>) -> Result<Db, PsiError> {
+    match db_key {
+        Some(db_key) => Db::load_encrypted(path, db_key, psi_params),
+        None => Db::load(path, psi_params),
+    }
+}
+
+/// Returns an active instance of `Server` by loading preprocessed server db file stored at `server_db_preprocessed`.
+/// Polynomial coefficients are memory mapped instead of read into memory (see `Db::load`). Returns
+/// `PsiError::ParamsMismatch` if db was preprocessed with params other than `psi_params`.
+fn load_server(
+    server_db_preprocessed: &Path,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+) -> Result<Server, PsiError> {
+    let db = load_db(server_db_preprocessed, psi_params, db_key)?;
+    Ok(Server::new_with_db(db, psi_params))
 }
 
 /// Loads server_set.bin stored at `dir_path`/server_set.bin and randomly generates client_set of `intersection_size`. Stores the client set at `dir_path/client_set.bin`.
-fn generate_random_client_intersection_set(intersection_size: usize, dir_path: &Path) {
+fn generate_random_client_intersection_set(
+    intersection_size: usize,
+    dir_path: &Path,
+    seed: Option<u64>,
+    psi_params: &PsiParams,
+) -> Result<(), PsiError> {
     let mut server_set_path = PathBuf::from(dir_path);
     server_set_path.push("server_set.bin");
 
     let mut client_set_path = PathBuf::from(dir_path);
     client_set_path.push("client_set.bin");
 
-    let file = std::fs::File::open(server_set_path.clone()).expect(&format!(
-        "Failed to open server_set.bin at {}",
-        server_set_path.display()
-    ));
-    let reader = BufReader::new(file);
-    let item_labels: Vec<ItemLabel> = bincode::deserialize_from(reader).expect(&format!(
-        "Malformed server set bin file {}",
-        server_set_path.display()
-    ));
+    let mut item_labels = vec![];
+    BincodeItemStore::open(&server_set_path, psi_params)?.for_each_batch(
+        ITEM_STORE_BATCH_SIZE,
+        &mut |batch| {
+            item_labels.extend(batch);
+            Ok(())
+        },
+    )?;
+    if intersection_size >= item_labels.len() {
+        return Err(PsiError::ParamsMismatch(format!(
+            "Client set of size {intersection_size} must be smaller than server set of size {}",
+            item_labels.len()
+        )));
+    }
 
-    let client_set = generate_random_intersection_and_store(&item_labels, intersection_size);
-    assert_eq!(client_set.len(), intersection_size);
+    let client_set = generate_random_intersection_and_store(
+        &item_labels,
+        intersection_size,
+        &mut seeded_rng(seed),
+    );
 
-    let mut client_set_file =
-        BufWriter::new(File::create(client_set_path).expect("Failed to create client_set.bin"));
-    bincode::serialize_into(&mut client_set_file, &client_set).unwrap();
+    let mut client_set_file = BufWriter::new(File::create(client_set_path)?);
+    bincode::serialize_into(&mut client_set_file, &client_set)?;
+    Ok(())
 }
 
-/// Starts the server from DB state stored at `dir_path`/server_db_preprocessed.bin.
-async fn start_server_from_stored_db_state(dir_path: &Path) {
-    let psi_params = PsiParams::default();
+/// Rewrites preprocessed db at `dir_path`/server_db_preprocessed.bin, stored by an earlier version, in current layout
+/// which loads without deserializing the db (see `Db::migrate`). Encrypted db is decrypted with `db_key` and always
+/// rewritten, since its layout is only known once decrypted.
+fn migrate_db(
+    dir_path: &Path,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+) -> Result<(), PsiError> {
+    let path = dir_path.join("server_db_preprocessed.bin");
+    info!(path = %path.display(), "Migrating db");
+    let migrated = match db_key {
+        Some(db_key) => {
+            let mut db = Db::load_encrypted(&path, db_key, psi_params)?;
+            let migrated_path = path.with_extension("migrating");
+            db.store_encrypted(&migrated_path, db_key)?;
+            std::fs::rename(&migrated_path, &path)?;
+            true
+        }
+        None => Db::migrate(&path, psi_params)?,
+    };
+    if migrated {
+        info!(path = %path.display(), "Db migrated");
+    } else {
+        info!(path = %path.display(), "Db is already in current layout");
+    }
+    Ok(())
+}
 
-    let mut server_db_preprocessed_path = PathBuf::from(dir_path);
-    server_db_preprocessed_path.push("server_db_preprocessed.bin");
+/// Applies deltas stored at `deltas_path` to db preprocessed under `dir_path` (see `Db::apply_delta`). Updated db is
+/// first stored next to server_db_preprocessed.bin and then moved over it, thus a running server picks it up on SIGHUP
+/// and db is left as is if anything fails.
+fn apply_delta(
+    dir_path: &Path,
+    deltas_path: &Path,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+    thread_pools: Option<&ThreadPools>,
+) -> Result<(), PsiError> {
+    let path = dir_path.join("server_db_preprocessed.bin");
+    let deltas = read_deltas(deltas_path)?;
+    info!(count = deltas.len(), path = %path.display(), "Applying deltas");
+    let mut db = load_db(&path, psi_params, db_key)?;
+    let summary = match thread_pools {
+        Some(pools) => pools
+            .preprocess_pool()
+            .install(|| db.apply_delta(&deltas))?,
+        None => db.apply_delta(&deltas)?,
+    };
+    if !summary.rejected.is_empty() {
+        warn!(count = summary.rejected.len(), "Inserts rejected");
+    }
+    info!(
+        inserted = summary.inserted,
+        removed = summary.removed,
+        missing = summary.missing,
+        updated_rows = summary.updated_rows,
+        "Deltas applied"
+    );
+    store_updated_db(&mut db, &path, db_key)
+}
+
+/// Removes items of preprocessed db in `dir_path` whose expiry has passed
+fn purge_expired(
+    dir_path: &Path,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+    thread_pools: Option<&ThreadPools>,
+) -> Result<(), PsiError> {
+    let path = dir_path.join("server_db_preprocessed.bin");
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| PsiError::Io(format!("System time before unix epoch: {e}")))?
+        .as_secs();
+    let mut db = load_db(&path, psi_params, db_key)?;
+    let purged = match thread_pools {
+        Some(pools) => pools.preprocess_pool().install(|| db.purge_expired(now))?,
+        None => db.purge_expired(now)?,
+    };
+    info!(purged, now, "Expired items purged");
+    if purged > 0 {
+        store_updated_db(&mut db, &path, db_key)?;
+    }
+    Ok(())
+}
+
+/// Stores updated `db` next to `path` and renames it over `path`, so that a crash doesn't leave partially written db
+fn store_updated_db(db: &mut Db, path: &Path, db_key: Option<&DbKey>) -> Result<(), PsiError> {
+    let updated_path = path.with_extension("updating");
+    match db_key {
+        Some(db_key) => db.store_encrypted(&updated_path, db_key)?,
+        None => db.store(&updated_path)?,
+    }
+    std::fs::rename(&updated_path, path)?;
+    Ok(())
+}
+
+/// Predicts resources needed to serve set of `set_size` items with `psi_params` and prints the estimate as JSON to
+/// stdout. Uses preset of `PsiParams::for_server_size` if `psi_params` aren't set. `label_bytes` overrides label size
+/// of the params.
+fn estimate_set(
+    set_size: u64,
+    label_bytes: Option<u32>,
+    psi_params: Option<PsiParams>,
+) -> Result<(), PsiError> {
+    let psi_params = match (psi_params, label_bytes) {
+        (Some(psi_params), Some(label_bytes)) => psi_params.with_label_bytes(label_bytes),
+        (Some(psi_params), None) => psi_params,
+        (None, label_bytes) => PsiParams::for_server_size(set_size, label_bytes.unwrap_or(32)),
+    };
+
+    info!(set_size, "Measuring interpolation and query throughput");
+    let estimate = estimate_cost(&psi_params, set_size)?;
+    info!(
+        inner_boxes = estimate.inner_boxes,
+        db_mb = estimate.db_bytes / (1 << 20),
+        preprocess_s = estimate.preprocess_time.as_secs(),
+        query_kb = estimate.query_bytes / (1 << 10),
+        response_kb = estimate.response_bytes / (1 << 10),
+        query_latency_ms = estimate.query_latency.as_millis() as u64,
+        "Estimated cost"
+    );
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&estimate).expect("Serializing estimate can't fail")
+    );
+    Ok(())
+}
+
+/// Benchmarks `clients` concurrent clients querying a random server set of `set_size` items `iterations` times each
+/// (see `run_bench`) and prints the report as JSON to stdout. Uses preset of `PsiParams::for_server_size` if
+/// `psi_params` aren't set.
+fn bench(
+    set_size: usize,
+    clients: usize,
+    iterations: usize,
+    seed: Option<u64>,
+    psi_params: Option<PsiParams>,
+) -> Result<(), PsiError> {
+    let psi_params = psi_params.unwrap_or_else(|| PsiParams::for_server_size(set_size as u64, 32));
 
-    println!("Loading server db state in memory...");
-    let server = load_server(&server_db_preprocessed_path, &psi_params);
+    info!(set_size, clients, iterations, "Running benchmark");
+    let report = run_bench(&psi_params, set_size, clients, iterations, seed)?;
+    info!(
+        setup_s = report.setup_time.as_secs(),
+        p50_ms = report.query_latency.p50.as_millis() as u64,
+        p99_ms = report.query_latency.p99.as_millis() as u64,
+        queries_per_second = report.queries_per_second,
+        "Benchmark finished"
+    );
+    if report.missed_queries > 0 {
+        warn!(
+            missed = report.missed_queries,
+            "Responses are missing labels of queried items"
+        );
+    }
+    println!(
+        "{}",
+        serde_json::to_string_pretty(&report).expect("Serializing report can't fail")
+    );
+    Ok(())
+}
+
+/// Loads `PsiParams` from config file at `config`. Returns `PsiParams::default` if `config` isn't set.
+fn load_psi_params(config: Option<&Path>) -> Result<PsiParams, PsiError> {
+    match config {
+        Some(path) => PsiParams::from_file(path),
+        None => Ok(PsiParams::default()),
+    }
+}
+
+/// Tenant passed with `--tenant ID=DIR[,CONFIG]`. Tenant's db is loaded from DIR/server_db_preprocessed.bin with
+/// `PsiParams` in CONFIG, or `PsiParams::default` if CONFIG isn't set.
+#[derive(Clone, Debug)]
+struct TenantArg {
+    id: String,
+    dir_path: PathBuf,
+    config: Option<PathBuf>,
+}
+
+impl FromStr for TenantArg {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (id, paths) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Invalid tenant {s}, expected ID=DIR[,CONFIG]"))?;
+        if id.is_empty() || id.len() > MAX_TENANT_ID_BYTES {
+            return Err(format!(
+                "Tenant id must be 1 to {MAX_TENANT_ID_BYTES} bytes long"
+            ));
+        }
+        if id == DEFAULT_TENANT {
+            return Err(format!("Tenant id {DEFAULT_TENANT} is reserved"));
+        }
+        let (dir_path, config) = match paths.split_once(',') {
+            Some((dir_path, config)) => (dir_path, Some(PathBuf::from(config))),
+            None => (paths, None),
+        };
+        Ok(TenantArg {
+            id: id.to_string(),
+            dir_path: PathBuf::from(dir_path),
+            config,
+        })
+    }
+}
+
+/// Server of tenant along with path its db is reloaded from on SIGHUP
+struct TenantServer {
+    id: String,
+    server: Server,
+    db_path: PathBuf,
+}
+
+/// Loads server of tenant from DB state stored at `dir_path`/server_db_preprocessed.bin
+fn load_tenant_server(
+    id: &str,
+    dir_path: &Path,
+    psi_params: &PsiParams,
+    db_key: Option<&DbKey>,
+) -> Result<TenantServer, PsiError> {
+    let db_path = dir_path.join("server_db_preprocessed.bin");
+    info!(tenant = id, path = %db_path.display(), "Loading server db state");
+    let server = load_server(&db_path, psi_params, db_key)?;
     server.print_diagnosis();
+    Ok(TenantServer {
+        id: id.to_string(),
+        server,
+        db_path,
+    })
+}
+
+/// Starts the server from DB state stored at `dir_path`/server_db_preprocessed.bin as `DEFAULT_TENANT`, along with
+/// additional `tenants`.
+async fn start_server_from_stored_db_state(
+    dir_path: &Path,
+    psi_params: &PsiParams,
+    tenants: &[TenantArg],
+    options: ServeOptions,
+) -> Result<(), PsiError> {
+    let db_key = options.db_key.as_ref();
+    let mut tenant_servers = vec![load_tenant_server(
+        DEFAULT_TENANT,
+        dir_path,
+        psi_params,
+        db_key,
+    )?];
+    for tenant in tenants {
+        let psi_params = load_psi_params(tenant.config.as_deref())?;
+        tenant_servers.push(load_tenant_server(
+            &tenant.id,
+            &tenant.dir_path,
+            &psi_params,
+            db_key,
+        )?);
+    }
+    start_server(tenant_servers, options).await
+}
+
+/// Transport clients reach the server over
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+enum Transport {
+    /// Framed protocol over TCP, optionally wrapped in TLS
+    #[default]
+    Tcp,
+    /// `POST /keys`, `POST /query` and `GET /params` endpoints (see `http::router`), for deployments where raw TCP is
+    /// blocked
+    Http,
+    /// Framed protocol over QUIC, for clients on lossy or mobile networks (see `quic::serve`). Requires `tls-cert` and
+    /// `tls-key`.
+    #[cfg(feature = "quic")]
+    Quic,
+}
+
+/// Options of running server set with CLI flags
+struct ServeOptions {
+    addr: SocketAddr,
+    transport: Transport,
+    /// Connections are wrapped in TLS if set
+    tls: Option<TlsAcceptor>,
+    /// Config of QUIC endpoint. Set iff transport is QUIC.
+    #[cfg(feature = "quic")]
+    quic: Option<quinn::ServerConfig>,
+    /// Clients must authenticate with a token in the store if set
+    token_store: Option<TokenStore>,
+    max_concurrent_queries: usize,
+    /// Queries are rejected as busy once this many are waiting for one of `max_concurrent_queries` slots. Unbounded
+    /// if not set.
+    max_queued_queries: Option<usize>,
+    /// Queries that aren't processed within the timeout, starting from when they are received, are cancelled.
+    /// Unlimited if not set.
+    query_timeout: Option<Duration>,
+    /// Max. no. of queries each API token, or IP address if server does not require authentication, may make a
+    /// minute. Unlimited if not set.
+    queries_per_minute: Option<u32>,
+    /// Time to wait for in-flight requests to finish after shutdown signal
+    shutdown_timeout: Duration,
+    /// Address of HTTP endpoint serving metrics at `/metrics`. Disabled if not set.
+    metrics_addr: Option<SocketAddr>,
+    /// Key of encrypted db files, read from `DB_KEY_ENV`. Db files are stored and loaded in plaintext if not set.
+    db_key: Option<DbKey>,
+    /// Dedicated pools shared by servers of all tenants. Rayon's global pool is used if not set.
+    thread_pools: Option<ThreadPools>,
+    /// Max. no. of segments whose PS powers each tenant's server caches. Disabled if not set.
+    powers_cache_size: Option<usize>,
+}
+
+impl ServeOptions {
+    fn from_cli(cli: &Cli) -> Result<ServeOptions, PsiError> {
+        let tls = match (cli.transport, &cli.tls_cert, &cli.tls_key) {
+            (Transport::Tcp, Some(cert_path), Some(key_path)) => {
+                Some(load_tls_acceptor(cert_path, key_path)?)
+            }
+            (Transport::Http, Some(_), Some(_)) => {
+                return Err(PsiError::Tls(
+                    "TLS isn't supported with HTTP transport. Terminate TLS at a reverse proxy instead.".to_string(),
+                ))
+            }
+            _ => None,
+        };
+        #[cfg(feature = "quic")]
+        let quic = match (cli.transport, &cli.tls_cert, &cli.tls_key) {
+            (Transport::Quic, Some(cert_path), Some(key_path)) => Some(psi::quic_server_config(
+                &read_file(cert_path)?,
+                &read_file(key_path)?,
+            )?),
+            (Transport::Quic, _, _) => {
+                return Err(PsiError::Tls(
+                    "QUIC transport requires --tls-cert and --tls-key".to_string(),
+                ))
+            }
+            _ => None,
+        };
+        let token_store = match &cli.tokens {
+            Some(path) => Some(TokenStore::from_file(path)?),
+            None => None,
+        };
+        let thread_pools = match (cli.preprocess_threads, cli.query_threads) {
+            (None, None) => None,
+            (preprocess_threads, query_threads) => Some(ThreadPools::new(
+                preprocess_threads.unwrap_or(0),
+                query_threads.unwrap_or(0),
+            )?),
+        };
+        Ok(ServeOptions {
+            addr: SocketAddr::new(cli.bind, cli.port),
+            transport: cli.transport,
+            tls,
+            #[cfg(feature = "quic")]
+            quic,
+            token_store,
+            max_concurrent_queries: cli.max_concurrent_queries.max(1),
+            max_queued_queries: cli.max_queued_queries,
+            query_timeout: cli.query_timeout_ms.map(Duration::from_millis),
+            queries_per_minute: cli.queries_per_minute.filter(|limit| *limit > 0),
+            shutdown_timeout: Duration::from_secs(cli.shutdown_timeout),
+            metrics_addr: cli.metrics_port.map(|port| SocketAddr::new(cli.bind, port)),
+            db_key: db_key_from_env()?,
+            thread_pools,
+            powers_cache_size: cli.powers_cache_size.filter(|size| *size > 0),
+        })
+    }
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, PsiError> {
+    std::fs::read(path).map_err(|e| PsiError::Io(format!("Failed to read {}: {e}", path.display())))
+}
+
+/// Returns TLS acceptor with PEM encoded certificate chain and private key stored at `cert_path` and `key_path`
+fn load_tls_acceptor(cert_path: &Path, key_path: &Path) -> Result<TlsAcceptor, PsiError> {
+    tls_acceptor(&read_file(cert_path)?, &read_file(key_path)?)
+}
+
+/// State shared by all connections
+struct ServerContext {
+    /// Datasets served. Evaluation keys uploaded by clients are cached per tenant and persist across connections.
+    /// API tokens, their quotas and metrics are those of `DEFAULT_TENANT`'s server, thus shared by all tenants.
+    tenants: Tenants,
+    /// Bounds no. of queries processed concurrently and rate of queries of each client. Each query is already
+    /// parallelised over rayon's thread pool, thus processing more queries at once only oversubscribes the pool and
+    /// holds more responses in memory.
+    limiter: QueryLimiter,
+    /// Time within which each query must be deserialized and processed, otherwise remaining work of the query is
+    /// cancelled and client receives `PsiError::Cancelled`
+    query_timeout: Option<Duration>,
+    /// Set to true once server starts shutting down. Connections close once their in-flight request is done.
+    shutdown: watch::Sender<bool>,
+}
+
+/// Resolves on SIGINT or, on unix, SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
 
-    start_server(&server).await;
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = ctrl_c => {},
+                    _ = sigterm.recv() => {},
+                }
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM: {e}");
+                let _ = ctrl_c.await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = ctrl_c.await;
+    }
 }
 
-/// Starts a server instance
-async fn start_server(server: &Server) {
+/// Reloads db of each tenant from its db path on every SIGHUP and swaps it in with `Server::swap_db`. Queries in
+/// progress finish against the previous db.
+#[cfg(unix)]
+async fn reload_on_sighup(tenants: Vec<(Arc<Tenant>, PathBuf)>, db_key: Option<DbKey>) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            warn!("Failed to listen for SIGHUP: {e}");
+            return;
+        }
+    };
+
+    while sighup.recv().await.is_some() {
+        for (tenant, db_path) in &tenants {
+            let tenant_id = tenant.id();
+            info!(tenant = tenant_id, path = %db_path.display(), "Reloading db");
+            let server = tenant.server().clone();
+            let db_path = db_path.clone();
+            let db_key = db_key.clone();
+            let result = tokio::task::spawn_blocking(move || {
+                server.swap_db(load_db(&db_path, server.psi_params(), db_key.as_ref())?)
+            })
+            .await;
+            match result {
+                Ok(Ok(db_version)) => info!(tenant = tenant_id, db_version, "Db reloaded"),
+                Ok(Err(e)) => error!(tenant = tenant_id, "Failed to reload db: {e}"),
+                Err(e) => error!(tenant = tenant_id, "Db reload task failed: {e}"),
+            }
+        }
+    }
+}
+
+/// Returns limiter admitting at most `max_concurrent` queries at once, see `QueryLimiter`
+fn query_limiter(
+    max_concurrent: usize,
+    max_queued: Option<usize>,
+    per_minute: Option<u32>,
+) -> QueryLimiter {
+    let mut limiter = QueryLimiter::new(max_concurrent);
+    if let Some(max_queued) = max_queued {
+        limiter = limiter.with_max_queued(max_queued);
+    }
+    if let Some(per_minute) = per_minute {
+        limiter = limiter.with_max_per_minute(per_minute);
+    }
+    limiter
+}
+
+/// Starts a server instance with `options`. Each connection is served on its own task.
+///
+/// Serves each of `tenants`, the first of which must be `DEFAULT_TENANT`.
+///
+/// On SIGINT or SIGTERM server stops accepting connections and waits upto `shutdown_timeout` for connections to
+/// finish their in-flight requests, after which remaining connections are aborted. On SIGHUP db of each tenant is
+/// reloaded from its db path.
+async fn start_server(tenants: Vec<TenantServer>, options: ServeOptions) -> Result<(), PsiError> {
+    let ServeOptions {
+        addr,
+        transport,
+        tls,
+        #[cfg(feature = "quic")]
+        quic,
+        token_store,
+        max_concurrent_queries,
+        max_queued_queries,
+        queries_per_minute,
+        query_timeout,
+        shutdown_timeout,
+        metrics_addr,
+        db_key,
+        thread_pools,
+        powers_cache_size,
+    } = options;
+    // thread pools and powers cache are set for servers of all tenants
+    let configure = |server: Server| {
+        let server = match &thread_pools {
+            Some(thread_pools) => server.with_shared_thread_pools(thread_pools.clone()),
+            None => server,
+        };
+        match powers_cache_size {
+            Some(size) => server.with_powers_cache(size),
+            None => server,
+        }
+    };
+    let mut tenants = tenants.into_iter();
+    let default_tenant = tenants.next().expect("Default tenant is missing");
+    assert_eq!(default_tenant.id, DEFAULT_TENANT);
+    let server = match token_store {
+        Some(token_store) => default_tenant.server.with_token_store(token_store),
+        None => default_tenant.server,
+    };
+    let mut db_paths = vec![(DEFAULT_TENANT.to_string(), default_tenant.db_path)];
+    let mut hosted = Tenants::new(configure(server), EvaluationKeyCache::default());
+    for tenant in tenants {
+        hosted = hosted.with_tenant(
+            &tenant.id,
+            configure(tenant.server),
+            EvaluationKeyCache::default(),
+        );
+        db_paths.push((tenant.id, tenant.db_path));
+    }
+    let context = Arc::new(ServerContext {
+        tenants: hosted,
+        limiter: query_limiter(
+            max_concurrent_queries,
+            max_queued_queries,
+            queries_per_minute,
+        ),
+        query_timeout,
+        shutdown: watch::channel(false).0,
+    });
+
+    if let Some(metrics_addr) = metrics_addr {
+        let metrics_listener = TcpListener::bind(metrics_addr).await?;
+        info!("Serving metrics on http://{metrics_addr}/metrics");
+        tokio::spawn(serve_metrics(
+            metrics_listener,
+            context.tenants.default_tenant().server().clone(),
+        ));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(
+        db_paths
+            .into_iter()
+            .map(|(id, db_path)| {
+                let tenant = context.tenants.get(id.as_bytes()).unwrap().clone();
+                (tenant, db_path)
+            })
+            .collect(),
+        db_key,
+    ));
+    #[cfg(not(unix))]
+    drop(db_paths);
+
+    #[cfg(feature = "quic")]
+    if let Some(config) = quic {
+        info!(
+            %addr,
+            ?transport,
+            max_concurrent_queries,
+            tenants = context.tenants.len(),
+            "Server started"
+        );
+        quic::serve(addr, config, context, shutdown_timeout).await?;
+        info!("Server stopped");
+        return Ok(());
+    }
+
     // Bind the listener to the address
-    let addr = "127.0.0.1:6379";
-    let listener = TcpListener::bind(addr).await.unwrap();
-    println!("Server started. Listening on {}", addr);
+    let listener = TcpListener::bind(addr).await?;
+    info!(
+        %addr,
+        ?transport,
+        max_concurrent_queries,
+        tenants = context.tenants.len(),
+        "Server started"
+    );
 
+    if transport == Transport::Http {
+        http::serve(listener, context, shutdown_timeout).await?;
+        info!("Server stopped");
+        return Ok(());
+    }
+
+    let mut connections = JoinSet::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
     loop {
-        // The second item contains the IP and port of the new connection.
-        let (mut socket, _) = listener.accept().await.unwrap();
-        match process_query(socket, &server).await {
-            Ok(_) => {
-                println!("Request returned successfully!");
-                println!();
+        let (socket, peer) = tokio::select! {
+            _ = &mut signal => break,
+            // reap finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let context = context.clone();
+        let tls = tls.clone();
+        connections.spawn(
+            async move {
+                let result = match tls {
+                    Some(acceptor) => match acceptor.accept(socket).await {
+                        Ok(socket) => process_connection(socket, Some(peer.ip()), &context).await,
+                        Err(e) => Err(PsiError::Tls(format!("Handshake failed: {e}"))),
+                    },
+                    None => process_connection(socket, Some(peer.ip()), &context).await,
+                };
+                match result {
+                    Ok(_) => info!("Connection closed"),
+                    Err(e) => warn!("Connection failed with error: {e}"),
+                }
             }
+            .instrument(info_span!("connection", %peer)),
+        );
+    }
+
+    drop(listener);
+    info!(
+        connections = connections.len(),
+        "Shutting down. Waiting for connections to finish"
+    );
+    context.shutdown.send_replace(true);
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            connections = connections.len(),
+            "Aborting connections that did not finish within {} s",
+            shutdown_timeout.as_secs()
+        );
+        connections.shutdown().await;
+    }
+    info!("Server stopped");
+    Ok(())
+}
+
+/// Starts coordinator that fans requests of clients out to shards at `shard_addrs` with `ShardCoordinator`. Shards
+/// must serve dbs preprocessed with `psi_params`. Clients connect to the coordinator as they would to a single server.
+/// Shards authenticate clients, thus `tokens` is set on shards instead.
+///
+/// On SIGINT or SIGTERM coordinator stops accepting connections and exits.
+async fn start_coordinator(
+    shard_addrs: Vec<SocketAddr>,
+    psi_params: &PsiParams,
+    options: ServeOptions,
+) -> Result<(), PsiError> {
+    if options.transport != Transport::Tcp {
+        return Err(PsiError::InvalidParams(
+            "Coordinator only supports TCP transport".to_string(),
+        ));
+    }
+    let coordinator = Arc::new(ShardCoordinator::new(shard_addrs, psi_params));
+    let listener = TcpListener::bind(options.addr).await?;
+    info!(
+        addr = %options.addr,
+        shards = coordinator.shard_addrs().len(),
+        "Coordinator started"
+    );
+
+    let mut connections = JoinSet::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        let (socket, peer) = tokio::select! {
+            _ = &mut signal => break,
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            accepted = listener.accept() => match accepted {
+                Ok(conn) => conn,
+                Err(e) => {
+                    warn!("Failed to accept connection: {e}");
+                    continue;
+                }
+            },
+        };
+
+        let coordinator = coordinator.clone();
+        let tls = options.tls.clone();
+        connections.spawn(
+            async move {
+                let result = match tls {
+                    Some(acceptor) => match acceptor.accept(socket).await {
+                        Ok(socket) => coordinator.serve_connection(socket).await,
+                        Err(e) => Err(PsiError::Tls(format!("Handshake failed: {e}"))),
+                    },
+                    None => coordinator.serve_connection(socket).await,
+                };
+                match result {
+                    Ok(_) => info!("Connection closed"),
+                    Err(e) => warn!("Connection failed with error: {e}"),
+                }
+            }
+            .instrument(info_span!("connection", %peer)),
+        );
+    }
+
+    info!("Coordinator stopped");
+    connections.shutdown().await;
+    Ok(())
+}
+
+/// Max. size of HTTP request head accepted by metrics endpoint
+const MAX_METRICS_REQUEST_BYTES: usize = 8 * 1024;
+
+/// Serves `Server::render_metrics` to HTTP GET requests for `/metrics` and `DbStats` of server's db as JSON to requests
+/// for `/stats` on `listener`. Each connection is answered once and closed, which is all Prometheus scrapers need.
+async fn serve_metrics(listener: TcpListener, server: Arc<Server>) {
+    loop {
+        let (mut socket, _) = match listener.accept().await {
+            Ok(conn) => conn,
             Err(e) => {
-                println!("Request failed with error: {e}");
-                println!();
+                warn!("Failed to accept metrics connection: {e}");
+                continue;
+            }
+        };
+
+        let server = server.clone();
+        tokio::spawn(async move {
+            // read request head
+            let mut request = vec![];
+            let mut buf = [0u8; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n")
+                && request.len() < MAX_METRICS_REQUEST_BYTES
+            {
+                match socket.read(&mut buf).await {
+                    Ok(0) | Err(_) => return,
+                    Ok(n) => request.extend_from_slice(&buf[..n]),
+                }
+            }
+
+            let (status, content_type, body) = if request.starts_with(b"GET /metrics ") {
+                (
+                    "200 OK",
+                    "text/plain; version=0.0.4",
+                    server.render_metrics(),
+                )
+            } else if request.starts_with(b"GET /stats ") {
+                let stats = serde_json::to_string(&server.snapshot().stats())
+                    .expect("Serializing db stats can't fail");
+                ("200 OK", "application/json", stats)
+            } else {
+                ("404 Not Found", "text/plain", String::new())
+            };
+            let response = format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+            let _ = socket.shutdown().await;
+        });
+    }
+}
+
+/// Max. no. of items in a single OPRF request
+const MAX_OPRF_ITEMS: usize = 1 << 20;
+
+/// How query responses are encoded, as negotiated in `MessageType::Hello`. Copied into queries when they are received,
+/// thus `Hello` sent while queries are in flight only applies to later queries.
+#[derive(Clone, Copy)]
+struct ResponseEncoding {
+    compression: bool,
+    metadata: bool,
+}
+
+/// State kept for the lifetime of a connection
+struct Session {
+    /// Tenant whose db requests are served by. Starts as `DEFAULT_TENANT`.
+    tenant: Arc<Tenant>,
+    /// Client and its evaluation key that the session is bound to after the first query or key upload. Holding the
+    /// key in session keeps it available for repeated queries even if it is evicted from the cache.
+    client: Option<(ClientId, Arc<EvaluationKey>)>,
+    queries_served: usize,
+    /// Queries and responses are zstd compressed. Enabled if client asks for it in `MessageType::Hello`.
+    compression: bool,
+    /// Responses carry `QueryMetadata`. Enabled if client asks for it in `MessageType::Hello`.
+    metadata: bool,
+    authenticated: bool,
+    /// Token client authenticated with. `None` if server does not require authentication.
+    token: Option<TokenId>,
+    /// IP address of client, which is rate limited by it unless it authenticates. `None` for in-memory connections.
+    peer: Option<IpAddr>,
+}
+
+impl Session {
+    fn new(tenant: Arc<Tenant>, peer: Option<IpAddr>) -> Session {
+        Session {
+            tenant,
+            client: None,
+            queries_served: 0,
+            compression: false,
+            metadata: false,
+            authenticated: false,
+            token: None,
+            peer,
+        }
+    }
+
+    fn response_encoding(&self) -> ResponseEncoding {
+        ResponseEncoding {
+            compression: self.compression,
+            metadata: self.metadata,
+        }
+    }
+
+    /// Key client is rate limited by
+    fn client_key(&self) -> Option<ClientKey> {
+        ClientKey::new(self.token.as_ref(), self.peer)
+    }
+
+    /// Returns evaluation key of `client_id`, looking it up in `key_cache` if session isn't bound to the client yet
+    fn evaluation_key(
+        &mut self,
+        client_id: &ClientId,
+        key_cache: &EvaluationKeyCache,
+    ) -> Option<Arc<EvaluationKey>> {
+        match &self.client {
+            Some((id, ek)) if id == client_id => Some(ek.clone()),
+            _ => {
+                let ek = key_cache.get(self.client_key(), client_id)?;
+                self.client = Some((*client_id, ek.clone()));
+                Some(ek)
+            }
+        }
+    }
+}
+
+/// Max. no. of segment responses of a connection queued for writing. Query threads wait for the connection to catch
+/// up once it is full, thus responses of slow clients aren't buffered in memory.
+const SEGMENT_QUEUE_CAPACITY: usize = 16;
+
+/// Response to a request along with no. of queries it served. Only borrows `ServerContext`, thus the connection keeps
+/// reading and serving other requests while it is pending.
+type PendingResponse<'a> =
+    Pin<Box<dyn Future<Output = Result<(Frame, usize), PsiError>> + Send + 'a>>;
+
+/// Response to a request that was processed right away
+fn ready<'a>(response: Result<Frame, PsiError>) -> PendingResponse<'a> {
+    Box::pin(std::future::ready(response.map(|frame| (frame, 0))))
+}
+
+/// Serves framed requests of client at `peer` on the connection until client closes it. Client can send any no. of
+/// queries in a single session. If a request fails, error is sent to client as `MessageType::Error` frame and the
+/// connection is closed, unless request is rejected by `QueryLimiter`, in which case `MessageType::Busy` is sent and
+/// the connection stays open.
+///
+/// Every frame of a response carries request id of its request (see `Frame::request_id`). Requests with id `0` are
+/// processed in order, one at a time, once requests in flight are responded to. Queries with other ids are processed
+/// concurrently and their responses, including segments of streamed responses, are interleaved as they complete.
+///
+/// Once server starts shutting down, connection is closed after its in-flight requests, if any, are responded to.
+async fn process_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    socket: S,
+    peer: Option<IpAddr>,
+    context: &ServerContext,
+) -> Result<(), PsiError> {
+    // tokens are checked by default tenant's server
+    let auth_server = context.tenants.default_tenant().server().clone();
+    let mut session = Session::new(context.tenants.default_tenant().clone(), peer);
+    let mut shutdown = context.shutdown.subscribe();
+
+    let (reader, mut writer) = tokio::io::split(socket);
+    let mut reader = FrameReader::new(reader);
+    // segments of streamed responses, written as soon as they are processed
+    let (segments, mut outgoing) = tokio::sync::mpsc::channel(SEGMENT_QUEUE_CAPACITY);
+    let mut in_flight: Vec<(u32, PendingResponse)> = vec![];
+    // request with id `0` received while other requests are in flight
+    let mut waiting: Option<Frame> = None;
+    // request with id `0` is in flight, thus no further requests are read until it is responded to
+    let mut sequential = false;
+    let mut disconnected = false;
+    let mut stopping = false;
+
+    loop {
+        if in_flight.is_empty() {
+            sequential = false;
+            if let Some(frame) = waiting.take() {
+                sequential = true;
+                in_flight.push((
+                    frame.request_id,
+                    dispatch_request(frame, context, &auth_server, &mut session, &segments),
+                ));
+            } else if disconnected {
+                info!(queries = session.queries_served, "Client disconnected");
+                return Ok(());
+            } else if stopping {
+                info!(
+                    queries = session.queries_served,
+                    "Closing connection for shutdown"
+                );
+                // flushes pending writes, including TLS close notify
+                writer.shutdown().await?;
+                return Ok(());
+            }
+        }
+        let reading = !(sequential || disconnected || stopping || waiting.is_some());
+        let server = session.tenant.server().clone();
+
+        // reading is cancel safe, thus requests are read while responses are written
+        tokio::select! {
+            frame = reader.read_with_limit(|message_type| {
+                server.query_validator().max_frame_bytes(message_type)
+            }), if reading => {
+                let frame = match frame {
+                    Ok(Some(frame)) => frame,
+                    Ok(None) => {
+                        // responds to requests in flight before closing
+                        disconnected = true;
+                        continue;
+                    }
+                    Err(e) => {
+                        // peer may have a different version. Tell it why before closing.
+                        let _ = write_frame(&mut writer, &Frame::error(&e.to_string())).await;
+                        return Err(e.into());
+                    }
+                };
+                if frame.request_id == 0 && !in_flight.is_empty() {
+                    waiting = Some(frame);
+                } else {
+                    sequential = frame.request_id == 0;
+                    in_flight.push((
+                        frame.request_id,
+                        dispatch_request(frame, context, &auth_server, &mut session, &segments),
+                    ));
+                }
+            }
+            Some(segment) = outgoing.recv() => write_frame(&mut writer, &segment).await?,
+            (request_id, response) = next_response(&mut in_flight) => {
+                // segments of a streamed response are sent before it resolves, thus precede its end
+                while let Ok(segment) = outgoing.try_recv() {
+                    write_frame(&mut writer, &segment).await?;
+                }
+                write_response(&mut writer, &mut session, request_id, response).await?;
+            }
+            // abandons request being read, if any
+            _ = shutdown.wait_for(|stop| *stop), if !stopping => stopping = true,
+        }
+    }
+}
+
+/// Resolves with response to whichever request in flight is responded to first and removes the request. Never
+/// resolves if no request is in flight.
+async fn next_response(
+    in_flight: &mut Vec<(u32, PendingResponse<'_>)>,
+) -> (u32, Result<(Frame, usize), PsiError>) {
+    std::future::poll_fn(|cx| {
+        for index in 0..in_flight.len() {
+            if let Poll::Ready(response) = in_flight[index].1.as_mut().poll(cx) {
+                let (request_id, _) = in_flight.swap_remove(index);
+                return Poll::Ready((request_id, response));
             }
         }
+        Poll::Pending
+    })
+    .await
+}
+
+/// Processes request in `frame`. Queries are decoded right away and evaluated once the returned response is polled,
+/// whereas other requests are processed right away. Segments of streamed responses are sent to `segments`.
+fn dispatch_request<'a>(
+    frame: Frame,
+    context: &'a ServerContext,
+    auth_server: &Server,
+    session: &mut Session,
+    segments: &Sender<Frame>,
+) -> PendingResponse<'a> {
+    let tenant = session.tenant.clone();
+    let server = tenant.server();
+    let requires_auth = auth_server.token_store().is_some()
+        && !session.authenticated
+        && !matches!(frame.message_type, MessageType::Hello | MessageType::Auth);
+    let response = match frame.message_type {
+        _ if requires_auth => Err(AuthError::NotAuthenticated.into()),
+        MessageType::Query => process_query(&frame.payload, context, session),
+        MessageType::QueryBatch => process_query_batch(&frame.payload, context, session),
+        MessageType::StreamedQuery => {
+            let segments = segments.clone();
+            process_streamed_query(&frame.payload, context, session, frame.request_id, segments)
+        }
+        MessageType::Hello => process_hello(&frame.payload, session).map(ready),
+        MessageType::Auth => process_auth(&frame.payload, auth_server, session).map(ready),
+        MessageType::Tenant => process_tenant(&frame.payload, &context.tenants, session).map(ready),
+        MessageType::OprfRequest => process_oprf_request(&frame.payload, server).map(ready),
+        MessageType::EvaluationKey => {
+            process_evaluation_key(&frame.payload, server, session, tenant.key_cache()).map(ready)
+        }
+        message_type => Err(PsiError::Protocol(ProtocolError::InvalidMessage(format!(
+            "Server does not accept {message_type:?} messages"
+        )))),
+    };
+    response.unwrap_or_else(|e| ready(Err(e)))
+}
+
+/// Writes response to request `request_id`. If request failed, error is written instead and returned, after which the
+/// connection is closed, unless request is rejected by `QueryLimiter`.
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    session: &mut Session,
+    request_id: u32,
+    response: Result<(Frame, usize), PsiError>,
+) -> Result<(), PsiError> {
+    match response {
+        Ok((frame, queries)) => {
+            session.queries_served += queries;
+            write_frame(writer, &frame.with_request_id(request_id)).await?
+        }
+        Err(PsiError::Protocol(ProtocolError::Busy {
+            reason,
+            retry_after,
+        })) => {
+            warn!(%reason, "Rejected request of busy client");
+            let busy = Frame::busy(reason, retry_after).with_request_id(request_id);
+            write_frame(writer, &busy).await?
+        }
+        Err(e) => {
+            write_frame(
+                writer,
+                &Frame::error(&e.to_string()).with_request_id(request_id),
+            )
+            .await?;
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Caches evaluation key uploaded by client under its client id, bound to the session's `ClientKey`, and binds the
+/// session to the client
+fn process_evaluation_key(
+    payload: &[u8],
+    server: &Server,
+    session: &mut Session,
+    key_cache: &EvaluationKeyCache,
+) -> Result<Frame, PsiError> {
+    let (client_id, ek) = decode_evaluation_key(payload, server)?;
+    key_cache.insert(session.client_key(), client_id, ek.clone());
+    session.client = Some((client_id, ek));
+
+    Ok(Frame::new(MessageType::Ack, vec![]))
+}
+
+/// Decodes evaluation key, prefixed with id of the client it belongs to, uploaded by client
+fn decode_evaluation_key(
+    payload: &[u8],
+    server: &Server,
+) -> Result<(ClientId, Arc<EvaluationKey>), PsiError> {
+    let (client_id, ek_bytes) = ClientId::split_prefix(payload)?;
+
+    debug!("Deserializing client evaluation key");
+    let ek = deserialize_evaluation_key(ek_bytes, server.evaluator())?;
+    Ok((client_id, Arc::new(ek)))
+}
+
+/// Switches session to tenant with id in `payload`. Session is unbound from its client, since evaluation keys are
+/// cached per tenant.
+fn process_tenant(
+    payload: &[u8],
+    tenants: &Tenants,
+    session: &mut Session,
+) -> Result<Frame, PsiError> {
+    let tenant = tenants.get(payload)?;
+    info!(tenant = tenant.id(), "Client selected tenant");
+    session.tenant = tenant.clone();
+    session.client = None;
+    Ok(Frame::new(MessageType::Ack, vec![]))
+}
+
+/// Enables capabilities requested by client that server supports and responds with enabled capabilities
+fn process_hello(payload: &[u8], session: &mut Session) -> Result<Frame, PsiError> {
+    if payload.len() != 1 {
+        return Err(PsiError::Protocol(ProtocolError::InvalidMessage(
+            "Malformed hello".to_string(),
+        )));
+    }
+    let flags = payload[0] & (CAPABILITY_ZSTD | CAPABILITY_METADATA | CAPABILITY_DB_STATS);
+    session.compression = flags & CAPABILITY_ZSTD != 0;
+    session.metadata = flags & CAPABILITY_METADATA != 0;
+
+    let mut response = vec![flags];
+    if flags & CAPABILITY_DB_STATS != 0 {
+        let db_stats = session.tenant.server().snapshot().stats();
+        response.extend(bincode::serialize(&db_stats)?);
+    }
+    Ok(Frame::new(MessageType::Hello, response))
+}
+
+/// Authenticates client with API token in `payload`
+fn process_auth(payload: &[u8], server: &Server, session: &mut Session) -> Result<Frame, PsiError> {
+    let token = std::str::from_utf8(payload).map_err(|_| AuthError::InvalidToken)?;
+    session.token = server.authenticate(token)?;
+    session.authenticated = true;
+    Ok(Frame::new(MessageType::Ack, vec![]))
+}
+
+/// Evaluates blinded items sent by client with server's OPRF key
+fn process_oprf_request(payload: &[u8], server: &Server) -> Result<Frame, PsiError> {
+    info!("Received new OPRF request");
+
+    let count = payload.len() / OPRF_POINT_BYTES;
+    if count > MAX_OPRF_ITEMS {
+        return Err(PsiError::ParamsMismatch(format!(
+            "OPRF request with {count} items exceeds limit {MAX_OPRF_ITEMS}"
+        )));
+    }
+
+    let response = OprfRequest::from_bytes(payload)
+        .and_then(|request| server.oprf_evaluate(&request))
+        .ok_or(PsiError::Protocol(ProtocolError::InvalidMessage(
+            "OPRF is disabled or request is malformed".to_string(),
+        )))?;
+
+    Ok(Frame::new(MessageType::OprfResponse, response.to_bytes()))
+}
+
+/// Decodes query sent by client along with client's evaluation key and time spent deserializing the query. Returns
+/// `None` if server does not have the evaluation key of the client.
+fn decode_query(
+    payload: &[u8],
+    server: &Server,
+    session: &mut Session,
+    key_cache: &EvaluationKeyCache,
+) -> Result<Option<(Query, Arc<EvaluationKey>, Duration)>, PsiError> {
+    let (client_id, payload) = ClientId::split_prefix(payload)?;
+    let client_evaluation_key = match session.evaluation_key(&client_id, key_cache) {
+        Some(ek) => ek,
+        None => {
+            info!("Evaluation key of client is not cached. Requesting upload");
+            return Ok(None);
+        }
+    };
+
+    // decompressed query is limited to max. query size, same as uncompressed query
+    let decompressed;
+    let payload = if session.compression {
+        decompressed = decompress(payload, server.query_validator().max_query_bytes())?;
+        &decompressed[..]
+    } else {
+        payload
+    };
+    let (query, deserialize_time) = deserialize_client_query(payload, server)?;
+    Ok(Some((
+        query.with_client_id(client_id),
+        client_evaluation_key,
+        deserialize_time,
+    )))
+}
+
+/// Validates and deserializes uncompressed query. Returns query along with time spent deserializing it.
+fn deserialize_client_query(
+    payload: &[u8],
+    server: &Server,
+) -> Result<(Query, Duration), PsiError> {
+    server.query_validator().validate_query_bytes(payload)?;
+
+    debug!("Deserializing query");
+    let now = std::time::Instant::now();
+    let query = deserialize_query(payload, server.psi_params(), server.evaluator())?;
+    let deserialize_time = now.elapsed();
+    server
+        .metrics()
+        .observe(QueryStage::Deserialize, deserialize_time);
+    Ok((query, deserialize_time))
+}
+
+/// Returns token that cancels a query received now once `ServerContext::query_timeout` elapses
+fn query_cancellation(context: &ServerContext) -> CancellationToken {
+    match context.query_timeout {
+        Some(timeout) => CancellationToken::with_timeout(timeout),
+        None => CancellationToken::new(),
     }
 }
 
-async fn process_query(mut socket: TcpStream, server: &Server) -> Result<()> {
-    socket.readable().await?;
+/// Waits until less than max. concurrent queries are being processed, or until deadline of `cancellation`
+async fn acquire_query_permit<'a>(
+    context: &'a ServerContext,
+    cancellation: &CancellationToken,
+) -> Result<SemaphorePermit<'a>, PsiError> {
+    match cancellation.deadline() {
+        Some(deadline) => tokio::time::timeout_at(deadline.into(), context.limiter.acquire())
+            .await
+            .map_err(|_| {
+                PsiError::Cancelled("Timed out waiting for other queries to finish".to_string())
+            })?,
+        None => context.limiter.acquire().await,
+    }
+}
+
+/// Decodes query and returns its response, which processes query on a blocking thread once a query permit is available
+fn process_query<'a>(
+    payload: &[u8],
+    context: &'a ServerContext,
+    session: &mut Session,
+) -> Result<PendingResponse<'a>, PsiError> {
+    info!("Received new query");
+    let cancellation = query_cancellation(context);
+
+    let tenant = session.tenant.clone();
+    let server = tenant.server();
+    let (query, client_evaluation_key, deserialize_time) =
+        match decode_query(payload, server, session, tenant.key_cache())? {
+            Some(decoded) => decoded,
+            None => {
+                return Ok(ready(Ok(Frame::new(
+                    MessageType::EvaluationKeyRequired,
+                    vec![],
+                ))))
+            }
+        };
+    context.limiter.check_rate(session.client_key().as_ref())?;
+    context
+        .tenants
+        .default_tenant()
+        .server()
+        .record_query(session.token.as_ref())?;
+
+    let server = server.clone();
+    let encoding = session.response_encoding();
+    Ok(Box::pin(async move {
+        let (query_response, metadata) =
+            evaluate_query(context, &server, query, client_evaluation_key, cancellation).await?;
+        let response_bytes = serialize_client_response(
+            &server,
+            encoding,
+            &query_response,
+            metadata,
+            deserialize_time,
+        )?;
+        Ok((Frame::new(MessageType::QueryResponse, response_bytes), 1))
+    }))
+}
 
-    println!("Received New Query");
+/// Serializes `query_response` as payload of `MessageType::QueryResponse`, with `metadata` if enabled by `encoding`
+fn serialize_client_response(
+    server: &Server,
+    encoding: ResponseEncoding,
+    query_response: &QueryResponse,
+    mut metadata: QueryMetadata,
+    deserialize_time: Duration,
+) -> Result<Vec<u8>, PsiError> {
+    let now = std::time::Instant::now();
+    let mut serialized_query_response =
+        serialize_query_response(query_response, server.evaluator().params());
+    if encoding.metadata {
+        metadata.deserialize_time = deserialize_time;
+        metadata.serialize_time = now.elapsed();
+        serialized_query_response = serialized_query_response.with_metadata(metadata);
+    }
 
-    // read query into buffer
-    let expected_bytes = expected_query_bytes(server.evaluator(), server.psi_params());
-    let mut query_buffer = vec![0; expected_bytes];
-    socket.read_exact(&mut query_buffer).await?;
+    let response_bytes = if encoding.compression {
+        serialized_query_response.compressed()?
+    } else {
+        bincode::serialize(&serialized_query_response)?
+    };
+    server
+        .metrics()
+        .observe(QueryStage::Serialize, now.elapsed());
+    server.metrics().record_response_bytes(response_bytes.len());
+    Ok(response_bytes)
+}
 
-    // deserialize query
-    println!("Deserializing Query...");
-    let query = deserialize_query(&query_buffer, server.psi_params(), server.evaluator());
+/// Decodes `QueryBatch` and returns its response, which processes each query of the batch one after another on a
+/// blocking thread once a single query permit is available. Evaluation key is looked up once for the whole batch,
+/// whereas each query counts towards client's rate limit and token's quota. Responds with
+/// `MessageType::QueryBatchResponse`.
+fn process_query_batch<'a>(
+    payload: &[u8],
+    context: &'a ServerContext,
+    session: &mut Session,
+) -> Result<PendingResponse<'a>, PsiError> {
+    info!("Received new query batch");
+    let cancellation = query_cancellation(context);
 
-    // read client's evaluation key
-    println!("Deserializing Client Evaluation Key...");
-    let client_evaluation_key = read_client_evaluation_key(server)?;
+    let tenant = session.tenant.clone();
+    let server = tenant.server();
+    let (client_id, payload) = ClientId::split_prefix(payload)?;
+    let client_evaluation_key = match session.evaluation_key(&client_id, tenant.key_cache()) {
+        Some(ek) => ek,
+        None => {
+            info!("Evaluation key of client is not cached. Requesting upload");
+            return Ok(ready(Ok(Frame::new(
+                MessageType::EvaluationKeyRequired,
+                vec![],
+            ))));
+        }
+    };
 
-    // Start processing Query
-    println!("Processing Query...");
+    // decompressed batch is limited to max. batch size, same as uncompressed batch
+    let decompressed;
+    let payload = if session.compression {
+        decompressed = decompress(payload, server.query_validator().max_batch_bytes())?;
+        &decompressed[..]
+    } else {
+        payload
+    };
+    server.query_validator().validate_batch_bytes(payload)?;
+    debug!("Deserializing query batch");
     let now = std::time::Instant::now();
-    let query_response = server.query(&query, &client_evaluation_key);
-    println!("Query Processing Time: {} ms", now.elapsed().as_millis());
+    let batch = deserialize_query_batch(payload, server.psi_params(), server.evaluator())?
+        .with_client_id(client_id);
+    let deserialize_time = now.elapsed();
+    server
+        .metrics()
+        .observe(QueryStage::Deserialize, deserialize_time);
+    server.query_validator().validate_batch(&batch)?;
+
+    for _ in batch.queries() {
+        context.limiter.check_rate(session.client_key().as_ref())?;
+        context
+            .tenants
+            .default_tenant()
+            .server()
+            .record_query(session.token.as_ref())?;
+    }
 
-    // serialize response
-    let serialized_query_response =
-        serialize_query_response(&query_response, server.evaluator().params());
+    let server = server.clone();
+    let encoding = session.response_encoding();
+    Ok(Box::pin(async move {
+        let query_server = server.clone();
+        let evaluated = evaluate_on_query_thread(context, cancellation, move |cancellation| {
+            query_server.query_batch(&batch, &client_evaluation_key, cancellation)
+        })
+        .await?;
+        info!(queries = evaluated.len(), "Query batch processed");
 
-    let response_bytes = bincode::serialize(&serialized_query_response).unwrap();
+        let payloads = evaluated
+            .into_iter()
+            .map(|(query_response, metadata)| {
+                serialize_client_response(
+                    &server,
+                    encoding,
+                    &query_response,
+                    metadata,
+                    deserialize_time,
+                )
+            })
+            .collect::<Result<Vec<_>, PsiError>>()?;
+        let queries = payloads.len();
+        Ok((
+            Frame::new(
+                MessageType::QueryBatchResponse,
+                bincode::serialize(&payloads)?,
+            ),
+            queries,
+        ))
+    }))
+}
 
-    socket.writable().await?;
+/// Evaluates query on a blocking thread once a query permit is available. Query is cancelled once `cancellation` is
+/// cancelled, including when the returned future is dropped.
+async fn evaluate_query(
+    context: &ServerContext,
+    server: &Arc<Server>,
+    query: Query,
+    client_evaluation_key: Arc<EvaluationKey>,
+    cancellation: CancellationToken,
+) -> Result<(QueryResponse, QueryMetadata), PsiError> {
+    let query_server = server.clone();
+    evaluate_on_query_thread(context, cancellation, move |cancellation| {
+        query_server.query_with_metadata(&query, &client_evaluation_key, cancellation)
+    })
+    .await
+}
 
-    socket.write_all(&response_bytes).await?;
+/// Runs `evaluate` on a blocking thread once a query permit is available. `evaluate` is passed `cancellation`, which
+/// is cancelled once the returned future is dropped.
+async fn evaluate_on_query_thread<T: Send + 'static>(
+    context: &ServerContext,
+    cancellation: CancellationToken,
+    evaluate: impl FnOnce(&CancellationToken) -> Result<T, PsiError> + Send + 'static,
+) -> Result<T, PsiError> {
+    let _cancel_on_drop = CancelOnDrop(cancellation.clone());
+    cancellation.check()?;
+    let _permit = acquire_query_permit(context, &cancellation).await?;
 
-    Ok(())
+    debug!("Processing query");
+    let now = std::time::Instant::now();
+    // blocking thread does not inherit connection's span
+    let span = tracing::Span::current();
+    let evaluated = tokio::task::spawn_blocking(move || {
+        let _entered = span.enter();
+        evaluate(&cancellation)
+    })
+    .await
+    .map_err(|e| PsiError::Io(format!("Query task failed: {e}")))??;
+    info!(
+        elapsed_ms = now.elapsed().as_millis() as u64,
+        "Query processed"
+    );
+    Ok(evaluated)
+}
+
+/// Decodes query and returns its response, which processes query on a blocking thread once a query permit is available
+/// and sends response of each segment to `segments`, tagged with `request_id`, as soon as it is processed and there is
+/// room in the queue. Response resolves to `MessageType::QueryResponseEnd` frame once all segments are sent.
+fn process_streamed_query<'a>(
+    payload: &[u8],
+    context: &'a ServerContext,
+    session: &mut Session,
+    request_id: u32,
+    segments: Sender<Frame>,
+) -> Result<PendingResponse<'a>, PsiError> {
+    info!("Received new streamed query");
+    let cancellation = query_cancellation(context);
+
+    let tenant = session.tenant.clone();
+    let server = tenant.server();
+    let (query, client_evaluation_key, deserialize_time) =
+        match decode_query(payload, server, session, tenant.key_cache())? {
+            Some(decoded) => decoded,
+            None => {
+                return Ok(ready(Ok(Frame::new(
+                    MessageType::EvaluationKeyRequired,
+                    vec![],
+                ))))
+            }
+        };
+    context.limiter.check_rate(session.client_key().as_ref())?;
+    context
+        .tenants
+        .default_tenant()
+        .server()
+        .record_query(session.token.as_ref())?;
+
+    let query_server = server.clone();
+    let encoding = session.response_encoding();
+    Ok(Box::pin(async move {
+        let (mut metadata, serialize_time) =
+            evaluate_on_query_thread(context, cancellation, move |cancellation| {
+                // summed across segments
+                let serialize_time = Mutex::new(Duration::ZERO);
+                let metadata = query_server.query_streamed(
+                    &query,
+                    &client_evaluation_key,
+                    cancellation,
+                    |segment_response| {
+                        let now = std::time::Instant::now();
+                        let mut bytes = serialize_segment_response(
+                            &segment_response,
+                            query_server.evaluator().params(),
+                        );
+                        if encoding.compression {
+                            bytes = compress(&bytes);
+                        }
+                        let elapsed = now.elapsed();
+                        *serialize_time.lock().unwrap() += elapsed;
+                        let metrics = query_server.metrics();
+                        metrics.observe(QueryStage::Serialize, elapsed);
+                        metrics.record_response_bytes(bytes.len());
+                        // waits while connection's queue is full. Receiver is dropped only if writing to client
+                        // failed, which cancels the query.
+                        let _ = segments.blocking_send(
+                            Frame::new(MessageType::QueryResponseSegment, bytes)
+                                .with_request_id(request_id),
+                        );
+                    },
+                )?;
+                Ok((metadata, serialize_time.into_inner().unwrap()))
+            })
+            .await?;
+
+        let metadata_bytes = if encoding.metadata {
+            metadata.deserialize_time = deserialize_time;
+            metadata.serialize_time = serialize_time;
+            bincode::serialize(&metadata)?
+        } else {
+            vec![]
+        };
+        Ok((Frame::new(MessageType::QueryResponseEnd, metadata_bytes), 1))
+    }))
 }
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    // #[arg(short, long)]
-    // debug: u8,
+    /// Address server listens on
+    #[arg(long, global = true, default_value = "127.0.0.1")]
+    bind: IpAddr,
+    /// Port server listens on
+    #[arg(long, global = true, default_value_t = 6379)]
+    port: u16,
+    /// Transport clients connect over. `http` serves `POST /keys`, `POST /query` and `GET /params` instead of the
+    /// framed TCP protocol, and does not support TLS. `quic` (requires `quic` feature) serves the framed protocol over
+    /// QUIC on UDP port `port`.
+    #[arg(long, global = true, value_enum, default_value_t = Transport::Tcp)]
+    transport: Transport,
+    /// Directory under which data of each server set size is stored, ie `data-dir`/{set_size}
+    #[arg(long, global = true, default_value = "./../data")]
+    data_dir: PathBuf,
+    /// `PsiParams` config file (.toml, .json or bincode .bin). Defaults to `PsiParams::default`. Must be the same for
+    /// preprocessing and starting the server.
+    #[arg(long, global = true, visible_alias = "params-file")]
+    config: Option<PathBuf>,
+    /// PEM encoded certificate chain. Connections are wrapped in TLS if set, which requires `tls-key`.
+    #[arg(long, global = true, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    /// PEM encoded private key of `tls-cert`
+    #[arg(long, global = true, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// File with API tokens of authorized clients, one per line optionally followed by max. no. of queries allowed
+    /// with the token. Clients must authenticate if set.
+    #[arg(long, global = true)]
+    tokens: Option<PathBuf>,
+    /// Port of HTTP endpoint serving Prometheus metrics at `/metrics`, on the same address as server. Disabled if not
+    /// set.
+    #[arg(long, global = true)]
+    metrics_port: Option<u16>,
+    /// Seconds to wait for in-flight requests to finish on SIGINT or SIGTERM before aborting them
+    #[arg(long, global = true, default_value_t = 30)]
+    shutdown_timeout: u64,
+    /// Max. no. of queries processed concurrently across all connections
+    #[arg(long, global = true, default_value_t = 2)]
+    max_concurrent_queries: usize,
+    /// Max. no. of queries waiting for one of `max-concurrent-queries` slots. Further queries are rejected with a busy
+    /// response telling the client when to retry. Unbounded if not set.
+    #[arg(long, global = true)]
+    max_queued_queries: Option<usize>,
+    /// Max. no. of queries a minute per API token, or per IP address if server does not require authentication.
+    /// Queries beyond the limit are rejected with a busy response. Unlimited if not set.
+    #[arg(long, global = true)]
+    queries_per_minute: Option<u32>,
+    /// Milliseconds within which a query must be deserialized and processed, counting time spent waiting for other
+    /// queries. Remaining work of queries that take longer is cancelled and client receives an error. Unlimited if not
+    /// set.
+    #[arg(long, global = true)]
+    query_timeout_ms: Option<u64>,
+    /// No. of threads preprocessing the db, including encoding of dbs reloaded on SIGHUP. Setting either this or
+    /// `query-threads` runs preprocessing and queries on separate pools, each of which defaults to one thread per CPU.
+    #[arg(long, global = true)]
+    preprocess_threads: Option<usize>,
+    /// No. of threads evaluating queries. See `preprocess-threads`.
+    #[arg(long, global = true)]
+    query_threads: Option<usize>,
+    /// Caches PS powers of this many most recently queried segments, thus clients retrying an identical query skip
+    /// calculating powers. Each query takes one entry per segment of each hash table. Disabled if not set.
+    #[arg(long, global = true)]
+    powers_cache_size: Option<usize>,
+    /// Only log warnings and errors. Overrides `RUST_LOG`.
+    #[arg(long, short, global = true)]
+    quiet: bool,
+    /// Seeds generation of random server and client sets, thus runs with the same seed are reproducible
+    #[arg(long, global = true)]
+    seed: Option<u64>,
     #[command(subcommand)]
     command: Commands,
 }
@@ -230,54 +1968,524 @@ enum Commands {
     },
     Preprocess {
         set_size: usize,
+        /// Reads server set from `sqlite:{path}` or `rocksdb:{path}` (requires `sqlite` or `rocksdb` feature) instead
+        /// of server_set.bin
+        #[arg(long)]
+        item_store: Option<String>,
+        /// Preprocesses only partition `i` of `n` partitions of InnerBoxes, given as `i/n`, and stores it at
+        /// server_db_partial_{i}_{n}.bin. Partial dbs of all partitions, preprocessed from the same server set and
+        /// `--config`, for ex. on different machines, are merged with `merge-db`.
+        #[arg(long = "shard", value_name = "i/n")]
+        partition: Option<Partition>,
+    },
+    /// Merges partial dbs of server set of `set_size`, preprocessed with `preprocess --shard`, into
+    /// server_db_preprocessed.bin
+    MergeDb {
+        set_size: usize,
+        /// Partial db file. Repeat for each partition. Defaults to all server_db_partial_*.bin files of `set_size`.
+        #[arg(long = "partial")]
+        partials: Vec<PathBuf>,
     },
     Start {
         set_size: usize,
+        /// Additional tenant served alongside the default one, as `ID=DIR[,CONFIG]`. Tenant's db is loaded from
+        /// DIR/server_db_preprocessed.bin with `PsiParams` in CONFIG, which defaults to `PsiParams::default`. Can be
+        /// repeated.
+        #[arg(long = "tenant")]
+        tenants: Vec<TenantArg>,
     },
     GenClientSet {
         server_set_size: usize,
         client_set_size: usize,
     },
+    /// Partitions server set of `set_size` into `shards` sets stored at `data-dir`/{set_size}/shards/{i}, each of
+    /// which is preprocessed and served by its own server
+    Shard {
+        set_size: usize,
+        shards: usize,
+    },
+    /// Starts coordinator that fans client queries out to all shards and returns their responses
+    Coordinate {
+        /// Address of shard server. Repeat for each shard.
+        #[arg(long = "shard", required = true)]
+        shards: Vec<SocketAddr>,
+    },
+    /// Imports server set from CSV (with header row) or JSONL dataset. Items longer than 32 bytes are hashed to 32
+    /// bytes with SHA-256.
+    Import {
+        input: PathBuf,
+        /// csv or jsonl
+        #[arg(long)]
+        format: ImportFormat,
+        /// Name of column (CSV) or field (JSONL) of items
+        #[arg(long)]
+        item_col: String,
+        /// Name of column (CSV) or field (JSONL) of labels. Labels are empty if not set.
+        #[arg(long)]
+        label_col: Option<String>,
+        /// Encoding of items and labels: hex or base64
+        #[arg(long, default_value = "hex")]
+        encoding: ValueEncoding,
+    },
+    /// Predicts db memory, preprocessing time, query and response sizes and query latency of server set of `set_size`
+    /// items without generating it. Uses params in `--config`, or preset for `set_size` if not set.
+    Estimate {
+        #[arg(long)]
+        set_size: u64,
+        /// Label size in bytes. Defaults to label size of params in `--config`, or 32 bytes.
+        #[arg(long)]
+        label_bytes: Option<u32>,
+    },
+    /// Sets up server with a random set of `set-size` items in-process and runs `clients` concurrent clients that each
+    /// query it `iterations` times. Prints setup and preprocess times, query latency percentiles and sizes as JSON.
+    /// Uses params in `--config`, or preset for `set-size` if not set.
+    Bench {
+        #[arg(long)]
+        set_size: usize,
+        #[arg(long, default_value_t = 1)]
+        clients: usize,
+        #[arg(long, default_value_t = 10)]
+        iterations: usize,
+    },
+    /// Rewrites preprocessed db of server set of `set_size`, stored by an earlier version, in current layout which
+    /// loads faster. Db is loaded with params in `--config`.
+    MigrateDb {
+        set_size: usize,
+    },
+    /// Applies inserts and removals in delta file, stored with `write_deltas`, to preprocessed db of server set of
+    /// `set_size`. Only rows touched by deltas are re-interpolated. Server set file isn't updated.
+    ApplyDelta {
+        set_size: usize,
+        deltas: PathBuf,
+    },
+    /// Removes items whose expiry has passed from preprocessed db of server set of `set_size`. Only rows of removed
+    /// items are re-interpolated.
+    PurgeExpired {
+        set_size: usize,
+    },
 }
 
-fn set_size_to_dir_path(set_size: usize) -> PathBuf {
-    let dir_path = PathBuf::from(&format!("./../data/{}", set_size));
-    dir_path
+/// Logs to stderr filtered by `RUST_LOG` (for ex. `RUST_LOG=psi=debug`), which defaults to `info`. `quiet` only logs
+/// warnings and errors.
+fn init_tracing(quiet: bool) {
+    let filter = if quiet {
+        EnvFilter::new("warn")
+    } else {
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+}
+
+fn set_size_to_dir_path(data_dir: &Path, set_size: usize) -> PathBuf {
+    data_dir.join(set_size.to_string())
 }
 
 #[tokio::main]
 async fn main() {
     let cli = Cli::parse();
+    init_tracing(cli.quiet);
 
-    match cli.command {
-        Commands::Start { set_size } => {
-            start_server_from_stored_db_state(&set_size_to_dir_path(set_size)).await;
+    let data_dir = cli.data_dir.as_path();
+    let (psi_params, options) = match load_psi_params(cli.config.as_deref())
+        .and_then(|psi_params| Ok((psi_params, ServeOptions::from_cli(&cli)?)))
+    {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
         }
-        Commands::SetupStart { set_size } => {
-            let dir_path = set_size_to_dir_path(set_size);
-            let psi_params = PsiParams::default();
-            generate_random_server_set(set_size);
-            let server = preprocess_and_store_dataset(&dir_path, &psi_params);
-            start_server(&server).await;
+    };
+
+    let progress = ProgressBarSink::new(cli.quiet);
+    let result = match cli.command {
+        Commands::Start { set_size, tenants } => {
+            start_server_from_stored_db_state(
+                &set_size_to_dir_path(data_dir, set_size),
+                &psi_params,
+                &tenants,
+                options,
+            )
+            .await
         }
-        Commands::Preprocess { set_size } => {
-            let psi_params = PsiParams::default();
-            preprocess_and_store_dataset(&set_size_to_dir_path(set_size), &psi_params);
+        Commands::SetupStart { set_size } => {
+            let dir_path = set_size_to_dir_path(data_dir, set_size);
+            match generate_random_server_set(set_size, &dir_path, cli.seed, &psi_params).and_then(
+                |_| {
+                    preprocess_and_store_dataset(
+                        &dir_path,
+                        None,
+                        &psi_params,
+                        options.db_key.as_ref(),
+                        options.thread_pools.as_ref(),
+                        &progress,
+                    )
+                },
+            ) {
+                Ok(server) => {
+                    let tenant = TenantServer {
+                        id: DEFAULT_TENANT.to_string(),
+                        server,
+                        db_path: dir_path.join("server_db_preprocessed.bin"),
+                    };
+                    start_server(vec![tenant], options).await
+                }
+                Err(e) => Err(e),
+            }
         }
+        Commands::Preprocess {
+            set_size,
+            item_store,
+            partition: None,
+        } => preprocess_and_store_dataset(
+            &set_size_to_dir_path(data_dir, set_size),
+            item_store.as_deref(),
+            &psi_params,
+            options.db_key.as_ref(),
+            options.thread_pools.as_ref(),
+            &progress,
+        )
+        .map(|_| ()),
+        Commands::Preprocess {
+            set_size,
+            item_store,
+            partition: Some(partition),
+        } => preprocess_and_store_partition(
+            &set_size_to_dir_path(data_dir, set_size),
+            item_store.as_deref(),
+            partition,
+            &psi_params,
+            options.db_key.as_ref(),
+            options.thread_pools.as_ref(),
+            &progress,
+        ),
+        Commands::MergeDb { set_size, partials } => merge_db(
+            &set_size_to_dir_path(data_dir, set_size),
+            partials,
+            &psi_params,
+            options.db_key.as_ref(),
+        ),
         Commands::Setup { set_size } => {
-            let dir_path = set_size_to_dir_path(set_size);
-            let psi_params = PsiParams::default();
-            generate_random_server_set(set_size);
-            preprocess_and_store_dataset(&dir_path, &psi_params);
+            let dir_path = set_size_to_dir_path(data_dir, set_size);
+            generate_random_server_set(set_size, &dir_path, cli.seed, &psi_params)
+                .and_then(|_| {
+                    preprocess_and_store_dataset(
+                        &dir_path,
+                        None,
+                        &psi_params,
+                        options.db_key.as_ref(),
+                        options.thread_pools.as_ref(),
+                        &progress,
+                    )
+                })
+                .map(|_| ())
         }
         Commands::GenClientSet {
             server_set_size,
             client_set_size,
+        } => generate_random_client_intersection_set(
+            client_set_size,
+            &set_size_to_dir_path(data_dir, server_set_size),
+            cli.seed,
+            &psi_params,
+        ),
+        Commands::Shard { set_size, shards } => shard_server_set(
+            &set_size_to_dir_path(data_dir, set_size),
+            shards,
+            &psi_params,
+        ),
+        Commands::Coordinate { shards } => start_coordinator(shards, &psi_params, options).await,
+        Commands::Import {
+            input,
+            format,
+            item_col,
+            label_col,
+            encoding,
         } => {
-            generate_random_client_intersection_set(
-                client_set_size,
-                &set_size_to_dir_path(server_set_size),
+            let mut options = ImportOptions::new(format, &item_col).with_encoding(encoding);
+            if let Some(label_col) = label_col {
+                options = options.with_label_col(&label_col);
+            }
+            import_server_set(&input, data_dir, &options, &psi_params)
+        }
+        Commands::Estimate {
+            set_size,
+            label_bytes,
+        } => estimate_set(
+            set_size,
+            label_bytes,
+            cli.config.is_some().then_some(psi_params),
+        ),
+        Commands::Bench {
+            set_size,
+            clients,
+            iterations,
+        } => bench(
+            set_size,
+            clients,
+            iterations,
+            cli.seed,
+            cli.config.is_some().then_some(psi_params),
+        ),
+        Commands::MigrateDb { set_size } => migrate_db(
+            &set_size_to_dir_path(data_dir, set_size),
+            &psi_params,
+            options.db_key.as_ref(),
+        ),
+        Commands::ApplyDelta { set_size, deltas } => apply_delta(
+            &set_size_to_dir_path(data_dir, set_size),
+            &deltas,
+            &psi_params,
+            options.db_key.as_ref(),
+            options.thread_pools.as_ref(),
+        ),
+        Commands::PurgeExpired { set_size } => purge_expired(
+            &set_size_to_dir_path(data_dir, set_size),
+            &psi_params,
+            options.db_key.as_ref(),
+            options.thread_pools.as_ref(),
+        ),
+    };
+
+    if let Err(e) = result {
+        error!("{e}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bfv::{EvaluationKeyProto, Evaluator, SecretKey};
+    use prost::Message;
+    use psi::{
+        gen_bfv_params, generate_evaluation_key, random_u256, BlockingPsiClient, InMemoryTransport,
+        PsiClient,
+    };
+    use rand::thread_rng;
+    use traits::TryFromWithParameters;
+
+    use super::*;
+
+    /// Serves a single connection on `server_end` with default tenant served by `server`
+    fn serve_in_memory(
+        server: Server,
+        server_end: InMemoryTransport,
+    ) -> tokio::task::JoinHandle<()> {
+        let context = ServerContext {
+            tenants: Tenants::new(server, EvaluationKeyCache::default()),
+            limiter: QueryLimiter::new(1),
+            query_timeout: None,
+            shutdown: watch::channel(false).0,
+        };
+        tokio::spawn(async move {
+            process_connection(server_end, None, &context)
+                .await
+                .unwrap();
+        })
+    }
+
+    #[test]
+    fn decode_evaluation_key_rejects_malformed_key() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let server = Server::new(&psi_params);
+        let client_id = ClientId::random(&mut rng);
+        let ek_bytes = |psi_params: &PsiParams| {
+            let evaluator = Evaluator::new(gen_bfv_params(psi_params));
+            let sk = SecretKey::random_with_params(evaluator.params(), &mut thread_rng());
+            let ek = generate_evaluation_key(psi_params, &evaluator, &sk, &mut thread_rng());
+            EvaluationKeyProto::try_from_with_parameters(&ek, evaluator.params()).encode_to_vec()
+        };
+
+        let valid = ek_bytes(&psi_params);
+        let (decoded_id, _) = decode_evaluation_key(&client_id.prefix(&valid), &server).unwrap();
+        assert_eq!(decoded_id, client_id);
+
+        // truncated key
+        assert!(matches!(
+            decode_evaluation_key(&client_id.prefix(&valid[..valid.len() / 2]), &server),
+            Err(PsiError::Serialization(_))
+        ));
+        // key generated with other BFV params
+        let other_params = PsiParams::default()
+            .with_bfv_degree(1 << 12)
+            .with_plaintext_modulus(8, 40961);
+        assert!(matches!(
+            decode_evaluation_key(&client_id.prefix(&ek_bytes(&other_params)), &server),
+            Err(PsiError::Serialization(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn query_over_in_memory_transport_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let (client_end, server_end) = InMemoryTransport::pair();
+        let connection = serve_in_memory(server, server_end);
+
+        let sk = SecretKey::random_with_params(&gen_bfv_params(&psi_params), &mut rng);
+        let mut client =
+            PsiClient::from_stream(client_end, &psi_params, sk, ClientId::random(&mut rng));
+        assert!(client.enable_compression().await.unwrap());
+        assert!(client.fetch_db_stats().await.unwrap().is_some());
+        assert!(client.expected_response_bytes().is_some());
+        // compression stays enabled
+        assert!(client.compression());
+
+        // half of the items are in server set
+        let items = item_labels[..10]
+            .iter()
+            .map(|item_label| *item_label.item())
+            .chain((0..10).map(|_| random_u256(&mut rng)))
+            .collect::<Vec<_>>();
+        let responses = client.query(&items).await.unwrap();
+        item_labels[..10].iter().for_each(|item_label| {
+            let response = responses
+                .iter()
+                .find(|response| response.item() == item_label.item())
+                .expect("Item at intersection is missing in response");
+            assert!(response.labels().contains(item_label.label()));
+        });
+
+        // server closes connection once client disconnects
+        drop(client);
+        connection.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn submitted_queries_are_answered_by_request_id() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+
+        let (client_end, server_end) = InMemoryTransport::pair();
+        let connection = serve_in_memory(server, server_end);
+
+        let sk = SecretKey::random_with_params(&gen_bfv_params(&psi_params), &mut rng);
+        let mut client =
+            PsiClient::from_stream(client_end, &psi_params, sk, ClientId::random(&mut rng));
+        client.upload_keys().await.unwrap();
+        let mut query_states = vec![];
+        for item_label in &item_labels[..2] {
+            query_states.extend(
+                client
+                    .construct_queries(&[*item_label.item()])
+                    .await
+                    .unwrap(),
             );
         }
+
+        let first = client.submit(&query_states[0]).await.unwrap();
+        let second = client.submit(&query_states[1]).await.unwrap();
+        // awaited in reverse order of submission
+        let second = second.await.unwrap();
+        let first = first.await.unwrap();
+        for (responses, item_label) in [first, second].iter().zip(&item_labels[..2]) {
+            let response = responses
+                .iter()
+                .find(|response| response.item() == item_label.item())
+                .expect("Item at intersection is missing in response");
+            assert!(response.labels().contains(item_label.label()));
+        }
+
+        drop(client);
+        connection.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rate_limited_client_is_told_to_retry() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+        let context = ServerContext {
+            tenants: Tenants::new(server, EvaluationKeyCache::default()),
+            limiter: QueryLimiter::new(1).with_max_per_minute(1),
+            query_timeout: None,
+            shutdown: watch::channel(false).0,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connection = tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            process_connection(socket, Some(peer.ip()), &context)
+                .await
+                .unwrap();
+        });
+
+        let sk = SecretKey::random_with_params(&gen_bfv_params(&psi_params), &mut rng);
+        let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let mut client =
+            PsiClient::from_stream(socket, &psi_params, sk, ClientId::random(&mut rng));
+        let items = [*item_labels[0].item()];
+        assert!(!client.query(&items).await.unwrap().is_empty());
+        match client.query(&items).await {
+            Err(PsiError::Protocol(ProtocolError::Busy {
+                reason: psi::BusyReason::RateLimited,
+                retry_after,
+            })) => assert!(retry_after > Duration::from_secs(50)),
+            result => panic!("Expected busy response but got {:?}", result.err()),
+        }
+
+        // connection stays open after busy response
+        assert!(client.enable_compression().await.unwrap());
+        drop(client);
+        connection.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn query_with_blocking_client_works() {
+        let mut rng = thread_rng();
+        let psi_params = PsiParams::default();
+        let item_labels = gen_random_item_labels(1000, None);
+        let mut server = Server::new(&psi_params);
+        server.setup(&item_labels).unwrap();
+        let context = ServerContext {
+            tenants: Tenants::new(server, EvaluationKeyCache::default()),
+            limiter: QueryLimiter::new(1),
+            query_timeout: None,
+            shutdown: watch::channel(false).0,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let connection = tokio::spawn(async move {
+            let (socket, peer) = listener.accept().await.unwrap();
+            process_connection(socket, Some(peer.ip()), &context)
+                .await
+                .unwrap();
+        });
+
+        let sk = SecretKey::random_with_params(&gen_bfv_params(&psi_params), &mut rng);
+        let client_id = ClientId::random(&mut rng);
+        let items = item_labels[..10]
+            .iter()
+            .map(|item_label| *item_label.item())
+            .collect::<Vec<_>>();
+        let responses = tokio::task::spawn_blocking(move || {
+            let mut client = BlockingPsiClient::connect(addr, &psi_params, sk, client_id).unwrap();
+            assert!(client.enable_compression().unwrap());
+            client.query(&items).unwrap()
+        })
+        .await
+        .unwrap();
+        item_labels[..10].iter().for_each(|item_label| {
+            let response = responses
+                .iter()
+                .find(|response| response.item() == item_label.item())
+                .expect("Item at intersection is missing in response");
+            assert!(response.labels().contains(item_label.label()));
+        });
+
+        // client is dropped with the blocking task
+        connection.await.unwrap();
     }
 }
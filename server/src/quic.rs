@@ -0,0 +1,112 @@
+//! QUIC transport of the server, for clients on lossy or mobile networks. Each bidirectional stream of a connection is
+//! served as a separate session of the framed protocol, same as a TCP connection, thus a client can run several
+//! sessions over a single connection without one large transfer holding up the others. Connections survive changes of
+//! client's address.
+
+use psi::{PsiError, QuicStream};
+use quinn::{ConnectionError, Endpoint};
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::task::JoinSet;
+use tracing::{info, info_span, warn, Instrument};
+
+use crate::{process_connection, shutdown_signal, ServerContext};
+
+/// Serves QUIC endpoint at `addr` with `config` until SIGINT or SIGTERM, after which in-flight requests are given
+/// upto `shutdown_timeout` to finish
+pub(crate) async fn serve(
+    addr: SocketAddr,
+    config: quinn::ServerConfig,
+    context: Arc<ServerContext>,
+    shutdown_timeout: Duration,
+) -> Result<(), PsiError> {
+    let endpoint = Endpoint::server(config, addr)?;
+
+    let mut connections = JoinSet::new();
+    let signal = shutdown_signal();
+    tokio::pin!(signal);
+    loop {
+        let connecting = tokio::select! {
+            _ = &mut signal => break,
+            // reap finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => continue,
+            connecting = endpoint.accept() => match connecting {
+                Some(connecting) => connecting,
+                None => break,
+            },
+        };
+
+        let peer = connecting.remote_address();
+        let context = context.clone();
+        connections.spawn(
+            async move {
+                match serve_connection(connecting, context).await {
+                    Ok(_) => info!("Connection closed"),
+                    Err(e) => warn!("Connection failed with error: {e}"),
+                }
+            }
+            .instrument(info_span!("connection", %peer)),
+        );
+    }
+
+    info!(
+        connections = connections.len(),
+        "Shutting down. Waiting for connections to finish"
+    );
+    context.shutdown.send_replace(true);
+    let drained = tokio::time::timeout(shutdown_timeout, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        warn!(
+            connections = connections.len(),
+            "Aborting connections that did not finish within {} s",
+            shutdown_timeout.as_secs()
+        );
+        connections.shutdown().await;
+    }
+    endpoint.close(0u32.into(), b"Server shutting down");
+    endpoint.wait_idle().await;
+    Ok(())
+}
+
+/// Serves each stream client opens on the connection on its own task until client closes the connection. Once server
+/// starts shutting down, no new streams are accepted and open streams close after their in-flight request.
+async fn serve_connection(
+    connecting: quinn::Connecting,
+    context: Arc<ServerContext>,
+) -> Result<(), PsiError> {
+    let connection = connecting
+        .await
+        .map_err(|e| PsiError::Tls(format!("Handshake failed: {e}")))?;
+    let mut shutdown = context.shutdown.subscribe();
+
+    let mut streams = JoinSet::new();
+    let result = loop {
+        let (send, recv) = tokio::select! {
+            _ = shutdown.wait_for(|stop| *stop) => break Ok(()),
+            Some(_) = streams.join_next(), if !streams.is_empty() => continue,
+            accepted = connection.accept_bi() => match accepted {
+                Ok(stream) => stream,
+                Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => break Ok(()),
+                Err(e) => break Err(PsiError::Io(format!("QUIC connection failed: {e}"))),
+            },
+        };
+
+        let stream_id = send.id();
+        let peer = connection.remote_address().ip();
+        let context = context.clone();
+        streams.spawn(
+            async move {
+                match process_connection(QuicStream::new(send, recv), Some(peer), &context).await {
+                    Ok(_) => info!("Stream closed"),
+                    Err(e) => warn!("Stream failed with error: {e}"),
+                }
+            }
+            .instrument(info_span!("stream", id = %stream_id)),
+        );
+    };
+
+    while streams.join_next().await.is_some() {}
+    result
+}